@@ -2,4 +2,44 @@
 fn main() {
     // trigger recompilation when a new migration is added
     println!("cargo:rerun-if-changed=db/migrations/");
-}
\ No newline at end of file
+    embed_assets();
+}
+
+/// Write the table of the files of `dist/` included in the binary by the `embed-assets` feature,
+/// empty without it, see src/assets.rs
+fn embed_assets() {
+    println!("cargo:rerun-if-changed=dist/");
+    println!("cargo:rerun-if-env-changed=CARGO_FEATURE_EMBED_ASSETS");
+    let dist = std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("dist");
+    let mut files = vec![];
+    if std::env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_some() {
+        list_files(&dist, &mut files);
+        files.sort();
+    }
+    let mut table = String::from("&[\n");
+    for file in &files {
+        let name = file
+            .strip_prefix(&dist)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        table.push_str(&format!("    ({:?}, include_bytes!({:?})),\n", name, file));
+    }
+    table.push(']');
+    let out = std::path::Path::new(&std::env::var("OUT_DIR").unwrap()).join("assets.rs");
+    std::fs::write(out, table).unwrap();
+}
+
+fn list_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            list_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,2 @@
+-- Low bitrate preview generated by the TranscodePreview task
+ALTER TABLE files ADD COLUMN preview_path TEXT;
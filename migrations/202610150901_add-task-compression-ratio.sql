@@ -0,0 +1,2 @@
+-- Output/input size ratio of archive tasks, updated while they run
+ALTER TABLE tasks ADD COLUMN compression_ratio REAL;
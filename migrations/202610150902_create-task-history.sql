@@ -0,0 +1,32 @@
+-- Summaries of finished tasks, kept once the tasks themselves are pruned
+CREATE TABLE task_history (
+    id TEXT PRIMARY KEY NOT NULL,
+    task_type TEXT NOT NULL,
+    status TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    started_at INTEGER,
+    finished_at INTEGER,
+    error TEXT
+);
+
+CREATE INDEX task_history_finished_at ON task_history (finished_at);
+
+-- Full-text index of the task errors
+CREATE VIRTUAL TABLE task_history_fts USING fts5(error, content='task_history', content_rowid='rowid');
+
+CREATE TRIGGER task_history_ai AFTER INSERT ON task_history BEGIN
+    INSERT INTO task_history_fts (rowid, error) VALUES (new.rowid, new.error);
+END;
+
+CREATE TRIGGER task_history_ad AFTER DELETE ON task_history BEGIN
+    INSERT INTO task_history_fts (task_history_fts, rowid, error) VALUES ('delete', old.rowid, old.error);
+END;
+
+CREATE TRIGGER task_history_au AFTER UPDATE ON task_history BEGIN
+    INSERT INTO task_history_fts (task_history_fts, rowid, error) VALUES ('delete', old.rowid, old.error);
+    INSERT INTO task_history_fts (rowid, error) VALUES (new.rowid, new.error);
+END;
+
+INSERT INTO task_history (id, task_type, status, created_at, started_at, finished_at, error)
+SELECT id, COALESCE(json_extract(input_data, '$.type'), task_type), status, created_at, started_at, finished_at, NULLIF(error, '')
+FROM tasks WHERE status IN ('completed', 'failed');
@@ -0,0 +1,2 @@
+-- Files of an encrypted share are ciphertext the server can't read, the key stays in the share URL fragment
+ALTER TABLE share_links ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Transient failures put the task back in the queue until max_retries is reached
+ALTER TABLE tasks ADD COLUMN retry_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tasks ADD COLUMN max_retries INTEGER NOT NULL DEFAULT 3;
+-- Unix timestamp of the next automatic attempt of a task waiting for its backoff
+ALTER TABLE tasks ADD COLUMN retry_at INTEGER;
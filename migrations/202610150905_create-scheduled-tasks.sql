@@ -0,0 +1,14 @@
+-- Recurring tasks, a task is created from input_data each time the cron expression fires
+CREATE TABLE IF NOT EXISTS scheduled_tasks (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    cron TEXT NOT NULL,
+    input_data TEXT NOT NULL,  -- JSON encoded task input
+    enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at INTEGER NOT NULL,
+    last_run_at INTEGER,
+    last_task_id TEXT,
+    next_run_at INTEGER        -- NULL once the expression has no future occurrence
+);
+
+CREATE INDEX idx_scheduled_tasks_next_run_at ON scheduled_tasks (next_run_at) WHERE enabled;
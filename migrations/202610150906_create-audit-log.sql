@@ -0,0 +1,13 @@
+-- Mutations made through the admin API and the webhook, newest rows have the highest id
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    actor TEXT NOT NULL,       -- API the action came through: admin or webhook
+    client_ip TEXT,            -- as reported by the reverse proxy
+    action TEXT NOT NULL,
+    target TEXT,               -- id of the share, task or schedule acted upon
+    details TEXT NOT NULL      -- JSON encoded parameters of the action
+);
+
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
+CREATE INDEX idx_audit_log_action ON audit_log (action);
@@ -0,0 +1,2 @@
+-- JSON encoded rules rewriting the file names served to clients, see src/filename_rules.rs
+ALTER TABLE share_links ADD COLUMN filename_rules TEXT NOT NULL DEFAULT '[]';
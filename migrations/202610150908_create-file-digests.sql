@@ -0,0 +1,10 @@
+-- Digests of the shared files, one row per hash algorithm. files.sha256 is kept for the SHA-256.
+CREATE TABLE IF NOT EXISTS file_digests (
+    file_id INTEGER NOT NULL,
+    algorithm TEXT NOT NULL,   -- sha256 or blake3
+    digest TEXT NOT NULL,      -- lowercase hex
+    PRIMARY KEY (file_id, algorithm)
+);
+
+INSERT INTO file_digests (file_id, algorithm, digest)
+SELECT id, 'sha256', sha256 FROM files WHERE sha256 IS NOT NULL AND sha256 != '';
@@ -0,0 +1,10 @@
+-- Long-lived keys for headless clients of the admin API, only the SHA-256 of the secret is stored.
+CREATE TABLE IF NOT EXISTS admin_api_keys (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scope TEXT NOT NULL,       -- read or full
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+);
@@ -0,0 +1,4 @@
+-- Argon2 PHC string of the password protecting a share, NULL for public shares
+ALTER TABLE share_links ADD COLUMN password_hash TEXT;
+-- Share defined in shares.toml, reconciled with the file and revoked once removed from it
+ALTER TABLE share_links ADD COLUMN managed BOOLEAN NOT NULL DEFAULT 0;
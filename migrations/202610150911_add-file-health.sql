@@ -0,0 +1,4 @@
+-- Result of the last VerifyFiles run on each file: ok, missing, size_mismatch or hash_mismatch,
+-- NULL until the file is checked
+ALTER TABLE files ADD COLUMN health TEXT;
+ALTER TABLE files ADD COLUMN health_checked_at INTEGER;
@@ -0,0 +1,4 @@
+-- A download can be made of several ranged requests, resumed after an interruption
+ALTER TABLE download ADD COLUMN bytes_sent INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE download ADD COLUMN bytes_covered INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE download ADD COLUMN segments INTEGER NOT NULL DEFAULT 1;
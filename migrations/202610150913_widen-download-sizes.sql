@@ -0,0 +1,22 @@
+-- Sizes of downloads are 64-bit, declared BIGINT like files.file_size. SQLite already stored them
+-- on 8 bytes, the table is rebuilt only to change the declared types.
+CREATE TABLE download_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    file_path TEXT,
+    ip_address TEXT,
+    transaction_id TEXT,
+    status TEXT,
+    file_size BIGINT,
+    started_at INT,
+    finished_at INT,
+    bytes_sent BIGINT NOT NULL DEFAULT 0,
+    bytes_covered BIGINT NOT NULL DEFAULT 0,
+    segments INTEGER NOT NULL DEFAULT 1
+);
+
+INSERT INTO download_new (id, file_path, ip_address, transaction_id, status, file_size, started_at, finished_at, bytes_sent, bytes_covered, segments)
+SELECT id, file_path, ip_address, transaction_id, status, file_size, started_at, finished_at, bytes_sent, bytes_covered, segments
+FROM download;
+
+DROP TABLE download;
+ALTER TABLE download_new RENAME TO download;
@@ -0,0 +1,3 @@
+-- Vanity slug serving a share at /s/<alias> besides /s/<id>
+ALTER TABLE share_links ADD COLUMN alias TEXT;
+CREATE UNIQUE INDEX share_links_alias ON share_links (alias);
@@ -0,0 +1,3 @@
+-- Opt-in to the email sent once every file of the share was downloaded
+ALTER TABLE share_links ADD COLUMN notify BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE share_links ADD COLUMN notified_at INTEGER;
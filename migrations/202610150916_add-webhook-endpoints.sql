@@ -0,0 +1,14 @@
+-- Outbound webhooks, see src/outgoing_webhooks.rs
+CREATE TABLE webhook_endpoints (
+    id TEXT PRIMARY KEY NOT NULL,
+    url TEXT NOT NULL,
+    -- Key of the HMAC signature, kept in clear to sign the deliveries
+    secret TEXT NOT NULL,
+    -- JSON list of the events sent
+    events TEXT NOT NULL,
+    format TEXT NOT NULL DEFAULT 'json',
+    created_at INTEGER NOT NULL,
+    last_delivery_at INTEGER,
+    -- `delivered`, or the error of the last attempt
+    last_status TEXT
+);
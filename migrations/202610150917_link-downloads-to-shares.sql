@@ -0,0 +1,11 @@
+-- Downloads are recorded with the share and file they were served from. Earlier downloads are
+-- linked when their path belongs to a single shared file.
+ALTER TABLE download ADD COLUMN share_id TEXT;
+ALTER TABLE download ADD COLUMN file_id INTEGER;
+
+UPDATE download SET
+    file_id = (SELECT share_link_files.file_id FROM files JOIN share_link_files ON share_link_files.file_id = files.id WHERE files.path = download.file_path),
+    share_id = (SELECT share_link_files.share_link_id FROM files JOIN share_link_files ON share_link_files.file_id = files.id WHERE files.path = download.file_path)
+WHERE (SELECT COUNT(*) FROM files JOIN share_link_files ON share_link_files.file_id = files.id WHERE files.path = download.file_path) = 1;
+
+CREATE INDEX download_share ON download (share_id, started_at);
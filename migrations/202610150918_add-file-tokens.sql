@@ -0,0 +1,11 @@
+-- Files are linked by a random token per file of a share rather than by their sequential id. The
+-- files shared before keep their id links, redirected to the token.
+ALTER TABLE share_link_files ADD COLUMN token TEXT;
+ALTER TABLE share_link_files ADD COLUMN legacy_id_link BOOLEAN NOT NULL DEFAULT 0;
+UPDATE share_link_files SET token = lower(hex(randomblob(8))), legacy_id_link = 1;
+CREATE UNIQUE INDEX share_link_files_token ON share_link_files (token);
+
+CREATE TRIGGER share_link_files_token AFTER INSERT ON share_link_files WHEN NEW.token IS NULL
+BEGIN
+    UPDATE share_link_files SET token = lower(hex(randomblob(8))) WHERE rowid = NEW.rowid;
+END;
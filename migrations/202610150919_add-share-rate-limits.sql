@@ -0,0 +1,2 @@
+-- Download rate of each file of the share, unlimited when NULL
+ALTER TABLE share_links ADD COLUMN max_bytes_per_sec INTEGER;
@@ -0,0 +1,5 @@
+-- Admin namespaces: keys with a namespace only manage the shares created in it, keys without one
+-- manage every share
+ALTER TABLE admin_api_keys ADD COLUMN namespace TEXT;
+ALTER TABLE share_links ADD COLUMN namespace TEXT;
+CREATE INDEX share_links_namespace ON share_links (namespace);
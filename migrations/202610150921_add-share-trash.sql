@@ -0,0 +1,3 @@
+-- Soft deletion: deleted shares are no longer served but can be restored until they are purged
+ALTER TABLE share_links ADD COLUMN deleted_at INTEGER;
+CREATE INDEX share_links_deleted_at ON share_links (deleted_at);
@@ -0,0 +1,2 @@
+-- Title, description, accent color and logo of the share page as JSON, the default look when NULL
+ALTER TABLE share_links ADD COLUMN branding TEXT;
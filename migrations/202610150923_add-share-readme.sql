@@ -0,0 +1,2 @@
+-- Markdown shown at the top of the share page, a shared README.md is shown when NULL
+ALTER TABLE share_links ADD COLUMN readme TEXT;
@@ -0,0 +1,11 @@
+-- Visits of the share pages, to know whether a recipient opened a link before downloading
+CREATE TABLE share_views (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    share_id TEXT NOT NULL,
+    viewed_at INTEGER NOT NULL,
+    ip_address TEXT,
+    user_agent TEXT,
+    referer TEXT
+);
+
+CREATE INDEX share_views_share ON share_views (share_id, viewed_at);
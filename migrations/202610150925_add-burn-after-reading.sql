@@ -0,0 +1,4 @@
+-- Shares served to their first visitor only, who claims them with a cookie whose SHA-256 is kept
+ALTER TABLE share_links ADD COLUMN burn_after_reading BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE share_links ADD COLUMN claim_hash TEXT;
+ALTER TABLE share_links ADD COLUMN claimed_at INTEGER;
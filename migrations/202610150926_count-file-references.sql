@@ -0,0 +1,21 @@
+-- Shares linking to each file, kept by triggers, so that a files row is deleted with its last link
+-- and rows with the same content can be merged
+ALTER TABLE files ADD COLUMN ref_count INTEGER NOT NULL DEFAULT 0;
+UPDATE files SET ref_count = (SELECT COUNT(*) FROM share_link_files WHERE file_id = files.id);
+CREATE INDEX files_sha256 ON files (sha256, file_size);
+
+CREATE TRIGGER share_link_files_ref_insert AFTER INSERT ON share_link_files
+BEGIN
+    UPDATE files SET ref_count = ref_count + 1 WHERE id = NEW.file_id;
+END;
+
+CREATE TRIGGER share_link_files_ref_delete AFTER DELETE ON share_link_files
+BEGIN
+    UPDATE files SET ref_count = ref_count - 1 WHERE id = OLD.file_id;
+END;
+
+CREATE TRIGGER share_link_files_ref_update AFTER UPDATE OF file_id ON share_link_files
+BEGIN
+    UPDATE files SET ref_count = ref_count - 1 WHERE id = OLD.file_id;
+    UPDATE files SET ref_count = ref_count + 1 WHERE id = NEW.file_id;
+END;
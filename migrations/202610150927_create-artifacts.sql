@@ -0,0 +1,20 @@
+-- Files generated by the tasks, deleted by the CleanupArtifacts task once no live share uses them
+CREATE TABLE artifacts (
+    path TEXT PRIMARY KEY NOT NULL,
+    task_type TEXT NOT NULL,
+    share_id TEXT,             -- share the file was generated for, when not linked to it
+    created_at INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO artifacts (path, task_type, share_id, created_at)
+SELECT json_extract(output_data, CASE json_extract(input_data, '$.type')
+        WHEN 'CreateArchive' THEN '$.archive_path'
+        WHEN 'ChecksumShare' THEN '$.sums_path'
+        WHEN 'DiscImage' THEN '$.image_path'
+        WHEN 'CreateTorrent' THEN '$.torrent_path'
+        WHEN 'TranscodePreview' THEN '$.preview_path'
+    END) AS path,
+    json_extract(input_data, '$.type'),
+    json_extract(input_data, '$.data.share_id'),
+    COALESCE(finished_at, created_at)
+FROM tasks WHERE status = 'completed' AND path IS NOT NULL;
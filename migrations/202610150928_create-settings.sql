@@ -0,0 +1,6 @@
+-- Server settings changed through the admin API, kept across restarts. `value` is JSON.
+CREATE TABLE settings (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
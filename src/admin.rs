@@ -0,0 +1,1378 @@
+use axum::extract::ws::WebSocket;
+use axum::extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade};
+use axum::http::{header, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::instrument;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::api_keys::{self, ApiKey, MintedKey, NewApiKey, Scope};
+use crate::audit::{self, Actor, AuditEntry, AuditQuery};
+use crate::error::{AppError, AppResult};
+use crate::file_dedup;
+use crate::file_indexer::{
+    DirListing, DirQuery, FileInfo, IndexStatus, ScanReport, SearchQuery, SearchResults,
+};
+use crate::filename_rules::{self, FilenameRule};
+use crate::listen::PeerAddr;
+use crate::maintenance::{MaintenanceStatus, MaintenanceUpdate};
+use crate::namespaces::{self, Namespace};
+use crate::notifications;
+use crate::outgoing_webhooks::{self, Endpoint, NewEndpoint, RegisteredEndpoint};
+use crate::progress::{self, DownloadQuery, DownloadRecord, ProgressSnapshot};
+use crate::proxy::Client;
+use crate::settings::{RuntimeSettings, SettingsPatch};
+use crate::share_alias;
+use crate::share_branding::{self, Branding};
+use crate::share_bundle::{self, ImportReport, ShareBundle};
+use crate::share_cache::CacheStats;
+use crate::share_profiles::{self, ShareProfile};
+use crate::share_readme::{self, Readme};
+use crate::share_stats::{self, ShareStats, StatsQuery};
+use crate::share_views::{self, ShareViews};
+use crate::top::{self, ServerStatus};
+use crate::validation::{ValidJson, Validate, Validator, MAX_PASSWORD_LEN};
+use crate::worker::health::{self, ShareDetails};
+use crate::worker::history::{HistoryQuery, TaskSummary};
+use crate::worker::schedules::{NewSchedule, Schedule};
+use crate::worker::trash;
+use crate::worker::{BackupDatabaseInput, Task, TaskInput, TaskList, TaskListQuery, TaskStatus};
+use crate::{publish_files, tus, App};
+
+/// Routes of the admin API, relative to the prefix they are mounted on. Keys with a namespace only
+/// reach the share routes, see [`namespaces`].
+pub fn router(app_state: App) -> Router<App> {
+    let owner_routes = Router::new()
+        .route("/tasks", get(list_tasks).post(create_task))
+        .route("/tasks/history", get(task_history))
+        .route("/tasks/history/export", get(export_task_history))
+        .route("/tasks/{task_id}", get(get_task_status).delete(delete_task))
+        .route("/tasks/{task_id}/retry", post(retry_task))
+        .route("/schedules", get(list_schedules).post(create_schedule))
+        .route("/schedules/{schedule_id}", delete(delete_schedule))
+        .route("/live_update", get(ws_handler))
+        .route("/progress/active", get(active_progress))
+        .route("/index/rescan", post(rescan_index))
+        .route("/cache/shares", get(share_cache_stats))
+        .route("/status", get(server_status))
+        .route("/audit", get(list_audit))
+        .route("/keys", get(list_api_keys).post(create_api_key))
+        .route("/keys/{key_id}", delete(revoke_api_key))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/{endpoint_id}", delete(delete_webhook))
+        .route("/maintenance", get(get_maintenance).put(set_maintenance))
+        .route("/settings", get(get_settings).patch(update_settings))
+        .route("/maintenance/backup", post(backup_database))
+        .route("/export", get(export_shares))
+        .route("/import", post(import_shares))
+        .merge(tus::router())
+        .route_layer(middleware::from_fn(namespaces::require_owner));
+    Router::new()
+        .route("/list_files", get(list_files))
+        .route("/create_shared_link", post(create_shared_link))
+        .route("/shares/{share_id}", get(get_share).delete(delete_share))
+        .route("/shares/{share_id}/restore", post(restore_share))
+        .route("/shares/{share_id}/filename_rules", put(set_filename_rules))
+        .route("/shares/{share_id}/notify", put(set_share_notify))
+        .route("/shares/{share_id}/branding", put(set_share_branding))
+        .route("/shares/{share_id}/readme", put(set_share_readme))
+        .route("/shares/{share_id}/stats", get(get_share_stats))
+        .route("/shares/{share_id}/views", get(get_share_views))
+        .route("/index/status", get(index_status))
+        .route("/files", get(list_dir))
+        .route("/files/search", get(search_files))
+        .route("/downloads", get(list_downloads))
+        .merge(owner_routes)
+        .layer(middleware::from_fn_with_state(
+            app_state,
+            api_keys::require_api_key,
+        ))
+}
+
+/// The routes served under `/admin` before the versioned API, kept for existing scripts
+pub fn legacy_router(app_state: App) -> Router<App> {
+    let owner_routes = Router::new()
+        .route("/tasks", post(create_task))
+        .route("/tasks/{task_id}", get(get_task_status))
+        .route("/live_update", get(ws_handler))
+        .route_layer(middleware::from_fn(namespaces::require_owner));
+    Router::new()
+        .route("/list_files", get(list_files))
+        .route("/create_shared_link", post(create_shared_link))
+        .merge(owner_routes)
+        .layer(middleware::from_fn_with_state(
+            app_state,
+            api_keys::require_api_key,
+        ))
+}
+
+/// Document of the admin API, relative to the prefix it is mounted on, see [`crate::openapi`]
+#[derive(OpenApi)]
+#[openapi(paths(
+    list_files,
+    list_dir,
+    create_shared_link,
+    get_share,
+    delete_share,
+    restore_share,
+    set_filename_rules,
+    set_share_notify,
+    set_share_branding,
+    set_share_readme,
+    get_share_stats,
+    get_share_views,
+    tus::list_uploads,
+    tus::get_upload,
+    tus::create_upload,
+    tus::upload_offset,
+    tus::append_upload,
+    tus::delete_upload,
+    index_status,
+    search_files,
+    list_downloads,
+    list_tasks,
+    create_task,
+    task_history,
+    export_task_history,
+    get_task_status,
+    delete_task,
+    retry_task,
+    list_schedules,
+    create_schedule,
+    delete_schedule,
+    ws_handler,
+    active_progress,
+    rescan_index,
+    share_cache_stats,
+    server_status,
+    list_audit,
+    list_api_keys,
+    create_api_key,
+    revoke_api_key,
+    list_webhooks,
+    create_webhook,
+    delete_webhook,
+    get_maintenance,
+    set_maintenance,
+    get_settings,
+    update_settings,
+    backup_database,
+    export_shares,
+    import_shares
+))]
+pub struct AdminApi;
+
+#[instrument(skip(app_state))]
+#[utoipa::path(
+    get,
+    path = "/list_files",
+    tag = "files",
+    responses((status = 200, body = Option<Vec<FileInfo>>))
+)]
+async fn list_files(State(app_state): State<App>) -> Json<Option<Vec<FileInfo>>> {
+    Json(app_state.indexer.tree())
+}
+
+/// Files to publish in a new share: their list, or an object with the list and the settings of
+/// the share
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+enum NewShareRequest {
+    Files(Vec<String>),
+    Share(ShareRequest),
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ShareRequest {
+    files: Vec<String>,
+    alias: Option<String>,
+    /// Profile of `profiles.toml` giving the settings not set here, see [`share_profiles`]
+    profile: Option<String>,
+    password: Option<String>,
+    /// Email once every file was downloaded, see [`crate::notifications`]
+    notify: Option<bool>,
+    /// Serve the share to its first visitor only, see [`crate::share_burn`]
+    #[serde(default)]
+    burn_after_reading: bool,
+}
+
+impl NewShareRequest {
+    fn into_share(self) -> ShareRequest {
+        match self {
+            NewShareRequest::Files(files) => ShareRequest {
+                files,
+                alias: None,
+                profile: None,
+                password: None,
+                notify: None,
+                burn_after_reading: false,
+            },
+            NewShareRequest::Share(share) => share,
+        }
+    }
+}
+
+impl Validate for NewShareRequest {
+    fn validate(&self, v: &mut Validator) {
+        let (files, share) = match self {
+            NewShareRequest::Files(files) => (files, None),
+            NewShareRequest::Share(share) => (&share.files, Some(share)),
+        };
+        v.check(!files.is_empty(), "files", "must not be empty");
+        for (i, file) in files.iter().enumerate() {
+            v.path(&format!("files[{}]", i), std::path::Path::new(file));
+        }
+        let Some(share) = share else {
+            return;
+        };
+        if let Some(alias) = &share.alias {
+            v.alias("alias", alias);
+        }
+        if let Some(password) = &share.password {
+            v.check(
+                !password.is_empty() && password.len() <= MAX_PASSWORD_LEN,
+                "password",
+                "must be between 1 and 256 characters",
+            );
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/create_shared_link",
+    tag = "shares",
+    request_body = NewShareRequest,
+    responses((status = 200, body = Option<String>, description = "URL of the share"))
+)]
+async fn create_shared_link(
+    State(app_state): State<App>,
+    actor: Actor,
+    client: Client,
+    namespace: Namespace,
+    ValidJson(request): ValidJson<NewShareRequest>,
+) -> AppResult<Json<Option<String>>> {
+    let request = request.into_share();
+    let config = &app_state.config;
+    let profile = match &request.profile {
+        Some(profile) => share_profiles::load(&config.data_dir, profile)?,
+        None => ShareProfile::default(),
+    };
+    let alias = request.alias;
+    let mut options = profile.share_options(request.password.as_deref())?;
+    options.alias = alias.clone();
+    options.notify = request.notify.unwrap_or(options.notify);
+    options.namespace = namespace.name().map(str::to_string);
+    options.burn_after_reading = request.burn_after_reading;
+    if options.expires_at.is_none() {
+        let now = chrono::offset::Utc::now().timestamp();
+        options.expires_at = app_state
+            .settings
+            .get()
+            .default_expiry_days
+            .map(|days| now + days * 24 * 3600);
+    }
+    let details = serde_json::json!({
+        "files": request.files,
+        "alias": alias,
+        "profile": request.profile,
+        "password": options.password_hash.is_some(),
+        "notify": options.notify,
+        "burn_after_reading": options.burn_after_reading,
+    });
+    let files = config
+        .shareable_roots()
+        .resolve_all("files", &request.files)?;
+    let link = publish_files(
+        files,
+        &client.host(&config.host),
+        &app_state.db_pool,
+        &app_state.plugins,
+        options,
+    )
+    .await?;
+    let share_id = match &alias {
+        Some(alias) => share_alias::resolve(&app_state.db_pool, alias).await?,
+        None => link.rsplit('/').next().map(str::to_string),
+    };
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_share",
+        share_id.as_deref(),
+        details,
+    )
+    .await;
+    Ok(Json(Some(link)))
+}
+
+/// A share with the health of its files, as found by the last `VerifyFiles` task
+#[utoipa::path(
+    get,
+    path = "/shares/{share_id}",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    responses((status = 200, body = ShareDetails))
+)]
+async fn get_share(
+    State(app_state): State<App>,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+) -> AppResult<Json<ShareDetails>> {
+    namespace
+        .check_share(&app_state.db_reader, &share_id)
+        .await?;
+    health::share_details(&app_state.db_reader, &share_id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("No share {}", share_id)))
+}
+
+/// Stop serving a share, it can be restored until it is purged, see [`trash`]
+#[utoipa::path(
+    delete,
+    path = "/shares/{share_id}",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    responses((status = 204))
+)]
+async fn delete_share(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let Some(id) = trash::delete_share(&app_state.db_pool, &share_id).await? else {
+        return Err(AppError::NotFound(format!("No share {}", share_id)));
+    };
+    app_state.share_cache.invalidate(&id);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "delete_share",
+        Some(&id),
+        serde_json::json!({}),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Serve a deleted share again
+#[utoipa::path(
+    post,
+    path = "/shares/{share_id}/restore",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    responses((status = 204))
+)]
+async fn restore_share(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let Some(id) = trash::restore_share(&app_state.db_pool, &share_id).await? else {
+        return Err(AppError::NotFound(format!("No deleted share {}", share_id)));
+    };
+    app_state.share_cache.invalidate(&id);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "restore_share",
+        Some(&id),
+        serde_json::json!({}),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Every share with its settings and file metadata, see [`share_bundle`]
+#[utoipa::path(
+    get,
+    path = "/export",
+    tag = "shares",
+    responses((status = 200, body = ShareBundle))
+)]
+async fn export_shares(State(app_state): State<App>, actor: Actor) -> AppResult<Json<ShareBundle>> {
+    let bundle = share_bundle::export(&app_state.db_reader).await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "export_shares",
+        None,
+        serde_json::json!({ "shares": bundle.shares.len() }),
+    )
+    .await;
+    Ok(Json(bundle))
+}
+
+/// Create the shares of a bundle missing from this server
+#[utoipa::path(
+    post,
+    path = "/import",
+    tag = "shares",
+    request_body = ShareBundle,
+    responses((status = 200, body = ImportReport))
+)]
+async fn import_shares(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(bundle): ValidJson<ShareBundle>,
+) -> AppResult<Json<ImportReport>> {
+    let roots = app_state.config.shareable_roots();
+    let report = share_bundle::import(&app_state.db_pool, &roots, &bundle).await?;
+    for share_id in &report.imported {
+        app_state.share_cache.invalidate(share_id);
+    }
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "import_shares",
+        None,
+        serde_json::json!({
+            "imported": report.imported,
+            "skipped": report.skipped.len(),
+            "errors": report.errors.len(),
+        }),
+    )
+    .await;
+    Ok(Json(report))
+}
+
+/// Downloads of a share per file and per day
+#[utoipa::path(
+    get,
+    path = "/shares/{share_id}/stats",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias"), StatsQuery),
+    responses((status = 200, body = ShareStats))
+)]
+async fn get_share_stats(
+    State(app_state): State<App>,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    Query(query): Query<StatsQuery>,
+) -> AppResult<Json<ShareStats>> {
+    namespace
+        .check_share(&app_state.db_reader, &share_id)
+        .await?;
+    share_stats::share_stats(&app_state.db_reader, &share_id, &query)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("No share {}", share_id)))
+}
+
+/// Visits of a share page per day, with the latest ones
+#[utoipa::path(
+    get,
+    path = "/shares/{share_id}/views",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias"), StatsQuery),
+    responses((status = 200, body = ShareViews))
+)]
+async fn get_share_views(
+    State(app_state): State<App>,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    Query(query): Query<StatsQuery>,
+) -> AppResult<Json<ShareViews>> {
+    namespace
+        .check_share(&app_state.db_reader, &share_id)
+        .await?;
+    share_views::share_views(&app_state.db_reader, &share_id, &query)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(format!("No share {}", share_id)))
+}
+
+/// Id of the share `share_id`, given by its id or its alias. The settings are saved and the
+/// cache invalidated under the id.
+async fn resolve_share(app_state: &App, share_id: &str) -> AppResult<String> {
+    share_alias::share_id(&app_state.db_pool, share_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("No share {}", share_id)))
+}
+
+/// Rules rewriting the file names served by a share
+#[derive(Deserialize, ToSchema)]
+#[serde(transparent)]
+struct FilenameRules(Vec<FilenameRule>);
+
+impl Validate for FilenameRules {
+    fn validate(&self, v: &mut Validator) {
+        filename_rules::validate(v, "filename_rules", &self.0);
+    }
+}
+
+/// Replace the filename rules of a share, an empty list serves the original names again
+#[utoipa::path(
+    put,
+    path = "/shares/{share_id}/filename_rules",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    request_body = FilenameRules,
+    responses((status = 204))
+)]
+async fn set_filename_rules(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    ValidJson(FilenameRules(rules)): ValidJson<FilenameRules>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let share_id = resolve_share(&app_state, &share_id).await?;
+    if !filename_rules::save(&app_state.db_pool, &share_id, &rules).await? {
+        return Err(AppError::NotFound(format!("No share {}", share_id)));
+    }
+    app_state.share_cache.invalidate(&share_id);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "set_filename_rules",
+        Some(&share_id),
+        serde_json::json!({ "filename_rules": rules }),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ShareNotify {
+    notify: bool,
+}
+
+/// Any boolean is valid, the payload only goes through [`ValidJson`] for its error envelope
+impl Validate for ShareNotify {
+    fn validate(&self, _v: &mut Validator) {}
+}
+
+/// Opt a share in or out of the email sent once all its files were downloaded
+#[utoipa::path(
+    put,
+    path = "/shares/{share_id}/notify",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    request_body = ShareNotify,
+    responses((status = 204))
+)]
+async fn set_share_notify(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    ValidJson(ShareNotify { notify }): ValidJson<ShareNotify>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let share_id = resolve_share(&app_state, &share_id).await?;
+    if !notifications::set_share_notify(&app_state.db_pool, &share_id, notify).await? {
+        return Err(AppError::NotFound(format!("No share {}", share_id)));
+    }
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "set_share_notify",
+        Some(&share_id),
+        serde_json::json!({ "notify": notify }),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replace the branding of the share page, an empty object restores the default look
+#[utoipa::path(
+    put,
+    path = "/shares/{share_id}/branding",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    request_body = Branding,
+    responses((status = 204))
+)]
+async fn set_share_branding(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    ValidJson(branding): ValidJson<Branding>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let share_id = resolve_share(&app_state, &share_id).await?;
+    if !share_branding::save(&app_state.db_pool, &share_id, &branding).await? {
+        return Err(AppError::NotFound(format!("No share {}", share_id)));
+    }
+    app_state.share_cache.invalidate(&share_id);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "set_share_branding",
+        Some(&share_id),
+        serde_json::json!({ "branding": branding }),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replace the Markdown shown at the top of the share page
+#[utoipa::path(
+    put,
+    path = "/shares/{share_id}/readme",
+    tag = "shares",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    request_body = Readme,
+    responses((status = 204))
+)]
+async fn set_share_readme(
+    State(app_state): State<App>,
+    actor: Actor,
+    namespace: Namespace,
+    Path(share_id): Path<String>,
+    ValidJson(Readme { markdown }): ValidJson<Readme>,
+) -> AppResult<StatusCode> {
+    namespace.check_share(&app_state.db_pool, &share_id).await?;
+    let share_id = resolve_share(&app_state, &share_id).await?;
+    if !share_readme::save(&app_state.db_pool, &share_id, markdown.as_deref()).await? {
+        return Err(AppError::NotFound(format!("No share {}", share_id)));
+    }
+    app_state.share_cache.invalidate(&share_id);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "set_share_readme",
+        Some(&share_id),
+        serde_json::json!({ "bytes": markdown.as_deref().map_or(0, str::len) }),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
+/// of websocket negotiation). After this completes, the actual switching from HTTP to
+/// websocket protocol will occur.
+/// This is the last point where we can extract TCP/IP metadata such as IP address of the client
+/// as well as things from HTTP headers such as user-agent of the browser etc.
+#[utoipa::path(
+    get,
+    path = "/live_update",
+    tag = "tasks",
+    responses((status = 101, description = "WebSocket of the task and download progress events"))
+)]
+async fn ws_handler(
+    State(app_state): State<App>,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<PeerAddr>,
+) -> impl IntoResponse {
+    // finalize the upgrade process by returning upgrade callback.
+    // we can customize the callback by sending additional info such as address.
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, app_state))
+}
+
+/// Downloads and tasks in progress, what the events of `/live_update` sent so far add up to
+#[utoipa::path(
+    get,
+    path = "/progress/active",
+    tag = "tasks",
+    responses((status = 200, body = ProgressSnapshot))
+)]
+async fn active_progress(State(app_state): State<App>) -> Json<ProgressSnapshot> {
+    Json(app_state.active_progress.snapshot())
+}
+
+async fn handle_socket(mut socket: WebSocket, who: PeerAddr, app_state: App) {
+    tracing::info!("Websocket connection from: {}", who);
+    let mut rx = app_state.progress_channel_sender.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match rx.recv().await {
+                Ok(msg) => {
+                    if let Err(err) = socket
+                        .send(axum::extract::ws::Message::Text(
+                            serde_json::json!(msg).to_string().into(),
+                        ))
+                        .await
+                    {
+                        tracing::error!("WS socket send error: {}", err);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("WS channel recv error: {}", err);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+#[utoipa::path(
+    post,
+    path = "/tasks",
+    tag = "tasks",
+    request_body = TaskInput,
+    responses((status = 200, body = String, description = "Id of the task"))
+)]
+async fn create_task(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(input): ValidJson<TaskInput>,
+) -> AppResult<Json<String>> {
+    let details = serde_json::json!(input);
+    let task_id = app_state
+        .task_manager
+        .create_task(input)
+        .await
+        .map_err(|e| AppError::Internal(e.context("Failed to create task")))?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_task",
+        Some(&task_id),
+        details,
+    )
+    .await;
+
+    Ok(Json(task_id))
+}
+
+/// Whether the public routes are down for maintenance, see [`crate::maintenance`]
+#[utoipa::path(
+    get,
+    path = "/maintenance",
+    tag = "maintenance",
+    responses((status = 200, body = MaintenanceStatus))
+)]
+async fn get_maintenance(State(app_state): State<App>) -> Json<MaintenanceStatus> {
+    Json(app_state.maintenance.status())
+}
+
+/// Turn the maintenance mode on or off, the public routes then answer 503 with the downtime page
+#[utoipa::path(
+    put,
+    path = "/maintenance",
+    tag = "maintenance",
+    request_body = MaintenanceUpdate,
+    responses((status = 200, body = MaintenanceStatus))
+)]
+async fn set_maintenance(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(update): ValidJson<MaintenanceUpdate>,
+) -> AppResult<Json<MaintenanceStatus>> {
+    let status = app_state
+        .maintenance
+        .set(&app_state.db_pool, &update)
+        .await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "set_maintenance",
+        None,
+        serde_json::json!({ "enabled": update.enabled, "message": update.message }),
+    )
+    .await;
+    Ok(Json(status))
+}
+
+/// Settings changed at runtime, see [`crate::settings`]
+#[utoipa::path(
+    get,
+    path = "/settings",
+    tag = "settings",
+    responses((status = 200, body = RuntimeSettings))
+)]
+async fn get_settings(State(app_state): State<App>) -> Json<RuntimeSettings> {
+    Json(app_state.settings.get())
+}
+
+/// Change some settings, applied at once and kept across restarts
+#[utoipa::path(
+    patch,
+    path = "/settings",
+    tag = "settings",
+    request_body(content = RuntimeSettings, description = "The settings to change, `null` lifting a limit"),
+    responses((status = 200, body = RuntimeSettings))
+)]
+async fn update_settings(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(patch): ValidJson<SettingsPatch>,
+) -> AppResult<Json<RuntimeSettings>> {
+    let settings = app_state
+        .settings
+        .update(&app_state.db_pool, &patch)
+        .await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "update_settings",
+        None,
+        serde_json::Value::Object(patch.0),
+    )
+    .await;
+    Ok(Json(settings))
+}
+
+/// Create a `BackupDatabase` task, its status tells where the backup was written
+#[utoipa::path(
+    post,
+    path = "/maintenance/backup",
+    tag = "tasks",
+    responses((status = 202, body = String, description = "Id of the BackupDatabase task"))
+)]
+async fn backup_database(
+    State(app_state): State<App>,
+    actor: Actor,
+) -> AppResult<(StatusCode, Json<String>)> {
+    let input = TaskInput::BackupDatabase(BackupDatabaseInput::default());
+    let task_id = app_state
+        .task_manager
+        .create_task(input)
+        .await
+        .map_err(|e| AppError::Internal(e.context("Failed to create task")))?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "backup_database",
+        Some(&task_id),
+        serde_json::json!({}),
+    )
+    .await;
+    Ok((StatusCode::ACCEPTED, Json(task_id)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/tasks/{task_id}",
+    tag = "tasks",
+    params(("task_id" = String, Path)),
+    responses((status = 200, body = Task))
+)]
+async fn get_task_status(
+    State(app_state): State<App>,
+    Path(task_id): Path<String>,
+) -> AppResult<Json<Task>> {
+    let task = app_state.task_manager.get_task_status(&task_id).await?;
+    Ok(Json(task))
+}
+
+/// Tasks filtered by status and type, most recent first
+#[utoipa::path(
+    get,
+    path = "/tasks",
+    tag = "tasks",
+    params(TaskListQuery),
+    responses((status = 200, body = TaskList))
+)]
+async fn list_tasks(
+    State(app_state): State<App>,
+    Query(query): Query<TaskListQuery>,
+) -> AppResult<Json<TaskList>> {
+    Ok(Json(app_state.task_manager.list_tasks(&query).await?))
+}
+
+/// Delete a completed or failed task, its history summary is kept
+#[utoipa::path(
+    delete,
+    path = "/tasks/{task_id}",
+    tag = "tasks",
+    params(("task_id" = String, Path)),
+    responses((status = 204))
+)]
+async fn delete_task(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(task_id): Path<String>,
+) -> AppResult<StatusCode> {
+    if app_state.task_manager.delete_task(&task_id).await? {
+        audit::record(
+            &app_state.db_pool,
+            &actor,
+            "delete_task",
+            Some(&task_id),
+            serde_json::json!({}),
+        )
+        .await;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    match app_state.task_manager.get_task_status(&task_id).await {
+        Ok(_) => Err(AppError::BadRequest(
+            "Only completed and failed tasks can be deleted".to_string(),
+        )),
+        Err(_) => Err(AppError::NotFound(format!("No task {}", task_id))),
+    }
+}
+
+/// Re-enqueue a failed task right away
+#[utoipa::path(
+    post,
+    path = "/tasks/{task_id}/retry",
+    tag = "tasks",
+    params(("task_id" = String, Path)),
+    responses((status = 202))
+)]
+async fn retry_task(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(task_id): Path<String>,
+) -> AppResult<StatusCode> {
+    if app_state.task_manager.retry_task(&task_id).await? {
+        audit::record(
+            &app_state.db_pool,
+            &actor,
+            "retry_task",
+            Some(&task_id),
+            serde_json::json!({}),
+        )
+        .await;
+        return Ok(StatusCode::ACCEPTED);
+    }
+    match app_state.task_manager.get_task_status(&task_id).await {
+        Ok(_) => Err(AppError::BadRequest(
+            "Only failed tasks can be retried".to_string(),
+        )),
+        Err(_) => Err(AppError::NotFound(format!("No task {}", task_id))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/schedules",
+    tag = "tasks",
+    responses((status = 200, body = Vec<Schedule>))
+)]
+async fn list_schedules(State(app_state): State<App>) -> AppResult<Json<Vec<Schedule>>> {
+    Ok(Json(app_state.task_manager.list_schedules().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/schedules",
+    tag = "tasks",
+    request_body = NewSchedule,
+    responses((status = 201, body = Schedule))
+)]
+async fn create_schedule(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(schedule): ValidJson<NewSchedule>,
+) -> AppResult<(StatusCode, Json<Schedule>)> {
+    let schedule = app_state.task_manager.create_schedule(&schedule).await?;
+    let details = serde_json::json!(schedule);
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_schedule",
+        Some(&schedule.id),
+        details,
+    )
+    .await;
+    Ok((StatusCode::CREATED, Json(schedule)))
+}
+
+/// Delete a schedule, the tasks it already created are kept
+#[utoipa::path(
+    delete,
+    path = "/schedules/{schedule_id}",
+    tag = "tasks",
+    params(("schedule_id" = String, Path)),
+    responses((status = 204))
+)]
+async fn delete_schedule(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(schedule_id): Path<String>,
+) -> AppResult<StatusCode> {
+    if app_state.task_manager.delete_schedule(&schedule_id).await? {
+        audit::record(
+            &app_state.db_pool,
+            &actor,
+            "delete_schedule",
+            Some(&schedule_id),
+            serde_json::json!({}),
+        )
+        .await;
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("No schedule {}", schedule_id)))
+    }
+}
+
+/// Summaries of the finished tasks, filtered by status, type and words of their error
+#[utoipa::path(
+    get,
+    path = "/tasks/history",
+    tag = "tasks",
+    params(HistoryQuery),
+    responses((status = 200, body = Vec<TaskSummary>))
+)]
+async fn task_history(
+    State(app_state): State<App>,
+    Query(query): Query<HistoryQuery>,
+) -> AppResult<Json<Vec<TaskSummary>>> {
+    Ok(Json(
+        app_state.task_manager.search_history(&query, true).await?,
+    ))
+}
+
+/// Whole matching task history as CSV
+#[utoipa::path(
+    get,
+    path = "/tasks/history/export",
+    tag = "tasks",
+    params(HistoryQuery),
+    responses((status = 200, body = String, content_type = "text/csv"))
+)]
+async fn export_task_history(
+    State(app_state): State<App>,
+    Query(query): Query<HistoryQuery>,
+) -> AppResult<Response> {
+    let csv = app_state.task_manager.export_history_csv(&query).await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"task-history.csv\"",
+            ),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+/// Metadata about the file indexer: watch/poll mode, last scan time and entry counts
+#[utoipa::path(
+    get,
+    path = "/index/status",
+    tag = "files",
+    responses((status = 200, body = IndexStatus))
+)]
+async fn index_status(State(app_state): State<App>) -> Json<IndexStatus> {
+    Json(app_state.indexer.status())
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RescanParams {
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Trigger a full rescan of the base path, `?wait=true` answers once the scan is done
+#[utoipa::path(
+    post,
+    path = "/index/rescan",
+    tag = "files",
+    params(RescanParams),
+    responses(
+        (status = 200, body = ScanReport, description = "Scan done, with `wait=true`"),
+        (status = 202, description = "Scan started")
+    )
+)]
+async fn rescan_index(
+    State(app_state): State<App>,
+    actor: Actor,
+    Query(params): Query<RescanParams>,
+) -> AppResult<Response> {
+    let done = app_state
+        .indexer
+        .rescan()
+        .map_err(|e| AppError::Unavailable(format!("Failed to trigger rescan: {}", e)))?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "rescan_index",
+        None,
+        serde_json::json!({}),
+    )
+    .await;
+
+    if !params.wait {
+        return Ok(StatusCode::ACCEPTED.into_response());
+    }
+
+    match done.await {
+        Ok(Ok(report)) => Ok(Json(report).into_response()),
+        Ok(Err(e)) => Err(AppError::Internal(anyhow::anyhow!("Rescan failed: {}", e))),
+        Err(_) => Err(AppError::Unavailable(
+            "File indexer stopped before completing the rescan".to_string(),
+        )),
+    }
+}
+
+/// One level of the file index, the subdirectories are listed by their `full_path`. The `ETag`
+/// changes with the entries of the page.
+#[utoipa::path(
+    get,
+    path = "/files",
+    tag = "files",
+    params(DirQuery),
+    responses(
+        (status = 200, body = DirListing),
+        (status = 304, description = "The entries didn't change since the `If-None-Match` ETag"),
+        (status = 404, description = "Not an indexed directory")
+    )
+)]
+async fn list_dir(
+    State(app_state): State<App>,
+    Query(query): Query<DirQuery>,
+    headers: header::HeaderMap,
+) -> AppResult<Response> {
+    let listing = app_state
+        .indexer
+        .list_dir(&query)
+        .ok_or_else(|| AppError::NotFound(format!("{} is not an indexed directory", query.path)))?;
+    let body = serde_json::to_vec(&listing).map_err(anyhow::Error::from)?;
+    let digest = format!("{:x}", Sha256::digest(&body));
+    let etag = format!("\"{}\"", &digest[..16]);
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "no-cache".to_string()),
+    ];
+    if crate::etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+    Ok((
+        cache_headers,
+        [(header::CONTENT_TYPE, "application/json".to_string())],
+        body,
+    )
+        .into_response())
+}
+
+/// Search the file index by name or glob pattern, with size filters, sorting and pagination
+#[utoipa::path(
+    get,
+    path = "/files/search",
+    tag = "files",
+    params(SearchQuery),
+    responses((status = 200, body = SearchResults))
+)]
+async fn search_files(
+    State(app_state): State<App>,
+    Query(query): Query<SearchQuery>,
+) -> AppResult<Json<SearchResults>> {
+    app_state
+        .indexer
+        .search(&query)
+        .map(Json)
+        .map_err(|e| AppError::BadRequest(format!("Invalid search pattern: {}", e)))
+}
+
+/// Hit/miss counters of the share page metadata cache
+#[utoipa::path(
+    get,
+    path = "/cache/shares",
+    tag = "server",
+    responses((status = 200, body = CacheStats))
+)]
+async fn share_cache_stats(State(app_state): State<App>) -> Json<CacheStats> {
+    Json(app_state.share_cache.stats())
+}
+
+/// Downloads, tasks, last failures, disks and deduplicated storage, as shown by `hardwire top`
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "server",
+    responses((status = 200, body = ServerStatus))
+)]
+async fn server_status(State(app_state): State<App>) -> AppResult<Json<ServerStatus>> {
+    let server_config = &app_state.config;
+    let recent_errors = HistoryQuery {
+        status: Some(TaskStatus::Failed),
+        limit: Some(top::RECENT_ERRORS),
+        ..Default::default()
+    };
+    Ok(Json(ServerStatus {
+        downloads: app_state.load.downloads(),
+        tasks: app_state.task_manager.count_by_status().await?,
+        recent_errors: app_state
+            .task_manager
+            .search_history(&recent_errors, true)
+            .await?,
+        disks: [
+            (
+                "base_path",
+                std::path::PathBuf::from(&server_config.base_path),
+            ),
+            ("data_dir", server_config.data_dir.clone()),
+        ]
+        .iter()
+        .filter_map(|(name, path)| top::disk_usage(name, path))
+        .collect(),
+        storage: file_dedup::storage_stats(&app_state.db_reader).await?,
+    }))
+}
+
+/// Stored downloads, the segments of a resumed download making up a single record
+#[utoipa::path(
+    get,
+    path = "/downloads",
+    tag = "server",
+    params(DownloadQuery),
+    responses((status = 200, body = Vec<DownloadRecord>))
+)]
+async fn list_downloads(
+    State(app_state): State<App>,
+    namespace: Namespace,
+    Query(query): Query<DownloadQuery>,
+) -> AppResult<Json<Vec<DownloadRecord>>> {
+    Ok(Json(
+        progress::recent_downloads(&app_state.db_reader, &query, namespace.name()).await?,
+    ))
+}
+
+/// Audit log entries filtered by action, actor, target and time range, most recent first
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "server",
+    params(AuditQuery),
+    responses((status = 200, body = Vec<AuditEntry>))
+)]
+async fn list_audit(
+    State(app_state): State<App>,
+    Query(query): Query<AuditQuery>,
+) -> AppResult<Json<Vec<AuditEntry>>> {
+    Ok(Json(audit::search(&app_state.db_reader, &query).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/keys",
+    tag = "keys",
+    responses((status = 200, body = Vec<ApiKey>))
+)]
+async fn list_api_keys(State(app_state): State<App>) -> AppResult<Json<Vec<ApiKey>>> {
+    Ok(Json(api_keys::list(&app_state.db_reader).await?))
+}
+
+/// Mint a key, the response holds the only copy of its secret
+#[utoipa::path(
+    post,
+    path = "/keys",
+    tag = "keys",
+    request_body = NewApiKey,
+    responses((status = 201, body = MintedKey))
+)]
+async fn create_api_key(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(new_key): ValidJson<NewApiKey>,
+) -> AppResult<(StatusCode, Json<MintedKey>)> {
+    // A first read or namespaced key would lock every mutation out, minting an owner key included
+    if (new_key.scope != Scope::Full || new_key.namespace.is_some())
+        && !api_keys::any_active(&app_state.db_pool).await?
+    {
+        return Err(AppError::BadRequest(
+            "The first API key must have the full scope and no namespace".to_string(),
+        ));
+    }
+    let minted = api_keys::mint(&app_state.db_pool, &new_key).await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_api_key",
+        Some(&minted.key.id),
+        serde_json::json!({
+            "name": minted.key.name,
+            "scope": minted.key.scope,
+            "namespace": minted.key.namespace,
+        }),
+    )
+    .await;
+    Ok((StatusCode::CREATED, Json(minted)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/keys/{key_id}",
+    tag = "keys",
+    params(("key_id" = String, Path)),
+    responses((status = 204))
+)]
+async fn revoke_api_key(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(key_id): Path<String>,
+) -> AppResult<StatusCode> {
+    if !api_keys::revoke(&app_state.db_pool, &key_id).await? {
+        return Err(AppError::NotFound(format!("No active API key {}", key_id)));
+    }
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "revoke_api_key",
+        Some(&key_id),
+        serde_json::json!({}),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses((status = 200, body = Vec<Endpoint>))
+)]
+async fn list_webhooks(State(app_state): State<App>) -> AppResult<Json<Vec<Endpoint>>> {
+    Ok(Json(outgoing_webhooks::list(&app_state.db_reader).await?))
+}
+
+/// Register an endpoint, the response holds the only copy of its secret
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = NewEndpoint,
+    responses((status = 201, body = RegisteredEndpoint))
+)]
+async fn create_webhook(
+    State(app_state): State<App>,
+    actor: Actor,
+    ValidJson(new_endpoint): ValidJson<NewEndpoint>,
+) -> AppResult<(StatusCode, Json<RegisteredEndpoint>)> {
+    let registered = outgoing_webhooks::register(&app_state.db_pool, &new_endpoint).await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_webhook",
+        Some(&registered.endpoint.id),
+        serde_json::json!({ "url": registered.endpoint.url, "events": registered.endpoint.events }),
+    )
+    .await;
+    Ok((StatusCode::CREATED, Json(registered)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{endpoint_id}",
+    tag = "webhooks",
+    params(("endpoint_id" = String, Path)),
+    responses((status = 204))
+)]
+async fn delete_webhook(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(endpoint_id): Path<String>,
+) -> AppResult<StatusCode> {
+    if !outgoing_webhooks::remove(&app_state.db_pool, &endpoint_id).await? {
+        return Err(AppError::NotFound(format!("No webhook {}", endpoint_id)));
+    }
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "delete_webhook",
+        Some(&endpoint_id),
+        serde_json::json!({}),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT)
+}
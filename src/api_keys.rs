@@ -0,0 +1,333 @@
+//! Long-lived API keys for the CLI and scripts using the admin API.
+//!
+//! Keys are sent as `Authorization: Bearer hw_...` and only their SHA-256 is stored. The admin API
+//! stays open while no key is active, so the first key can be minted; once one exists every
+//! request must carry a key. Read keys are limited to `GET` and `HEAD` requests, and keys with a
+//! namespace to the shares of their namespace, see [`crate::namespaces`].
+
+use anyhow::Result;
+use axum::extract::{OriginalUri, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+use crate::namespaces::Namespace;
+use crate::progress::{AuthAttempt, Event};
+use crate::proxy::client_ip;
+use crate::validation::{Validate, Validator};
+use crate::App;
+
+pub const KEY_PREFIX: &str = "hw_";
+const KEY_SECRET_LEN: usize = 40;
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// `GET` and `HEAD` requests only
+    Read,
+    Full,
+}
+
+impl Scope {
+    fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Full => "full",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "full" => Some(Scope::Full),
+            _ => None,
+        }
+    }
+
+    pub fn allows(&self, method: &Method) -> bool {
+        match self {
+            Scope::Read => method == Method::GET || method == Method::HEAD,
+            Scope::Full => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    /// Owner keys have none
+    pub namespace: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+/// A freshly minted key, the only time its secret is known
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MintedKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scope: Scope,
+    /// The key only manages the shares of this namespace, every share without
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+impl Validate for NewApiKey {
+    fn validate(&self, v: &mut Validator) {
+        v.check(!self.name.trim().is_empty(), "name", "must not be empty");
+        v.check(
+            self.name.len() <= MAX_NAME_LEN,
+            "name",
+            "must not be longer than 100 characters",
+        );
+        if let Some(namespace) = &self.namespace {
+            v.share_id("namespace", namespace);
+        }
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Create a key, its secret is returned once and can't be retrieved later
+pub async fn mint(db: &SqlitePool, new_key: &NewApiKey) -> Result<MintedKey> {
+    let key = ApiKey {
+        id: nanoid::nanoid!(10),
+        name: new_key.name.trim().to_string(),
+        scope: new_key.scope,
+        namespace: new_key.namespace.clone(),
+        created_at: chrono::offset::Utc::now().timestamp(),
+        last_used_at: None,
+        revoked_at: None,
+    };
+    let secret = format!("{}{}", KEY_PREFIX, nanoid::nanoid!(KEY_SECRET_LEN));
+    let key_hash = hash_secret(&secret);
+    let scope = key.scope.name();
+    sqlx::query!(
+        r#"INSERT INTO admin_api_keys (id, name, key_hash, scope, namespace, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+        key.id,
+        key.name,
+        key_hash,
+        scope,
+        key.namespace,
+        key.created_at
+    )
+    .execute(db)
+    .await?;
+    Ok(MintedKey { key, secret })
+}
+
+/// Revoke a key, `false` when it does not exist or was already revoked
+pub async fn revoke(db: &SqlitePool, key_id: &str) -> Result<bool> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let result = sqlx::query!(
+        "UPDATE admin_api_keys SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+        now,
+        key_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Every key, revoked ones included, most recent first
+pub async fn list(db: &SqlitePool) -> Result<Vec<ApiKey>> {
+    let rows = sqlx::query!(
+        r#"SELECT id AS "id!", name, scope, namespace, created_at, last_used_at, revoked_at
+        FROM admin_api_keys ORDER BY created_at DESC, id"#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(ApiKey {
+                id: row.id,
+                name: row.name,
+                scope: Scope::from_name(&row.scope)?,
+                namespace: row.namespace,
+                created_at: row.created_at,
+                last_used_at: row.last_used_at,
+                revoked_at: row.revoked_at,
+            })
+        })
+        .collect())
+}
+
+/// Whether the admin API requires a key
+pub async fn any_active(db: &SqlitePool) -> Result<bool> {
+    let active = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM admin_api_keys WHERE revoked_at IS NULL) AS "active!: bool""#
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(active)
+}
+
+/// Scope and namespace of the active key with `secret`, its last use is updated
+pub async fn authenticate(db: &SqlitePool, secret: &str) -> Result<Option<(Scope, Namespace)>> {
+    if !secret.starts_with(KEY_PREFIX) {
+        return Ok(None);
+    }
+    let key_hash = hash_secret(secret);
+    let now = chrono::offset::Utc::now().timestamp();
+    let key = sqlx::query!(
+        r#"UPDATE admin_api_keys SET last_used_at = ?
+        WHERE key_hash = ? AND revoked_at IS NULL
+        RETURNING scope, namespace"#,
+        now,
+        key_hash
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(key.and_then(|key| {
+        Some((
+            Scope::from_name(&key.scope)?,
+            Namespace::from_name(key.namespace),
+        ))
+    }))
+}
+
+/// Reject admin API requests without a key allowing them once a key is active, and give the others
+/// the [`Namespace`] of their key
+pub async fn require_api_key(
+    State(app_state): State<App>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    match any_active(&app_state.db_reader).await {
+        Ok(false) => {
+            request.extensions_mut().insert(Namespace::Owner);
+            return next.run(request).await;
+        }
+        Ok(true) => {}
+        Err(e) => return AppError::Internal(e).into_response(),
+    }
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let (scope, namespace) = match given {
+        Some(given) => match authenticate(&app_state.db_pool, given).await {
+            Ok(Some((scope, namespace))) => (Some(scope), Some(namespace)),
+            Ok(None) => (None, None),
+            Err(e) => return AppError::Internal(e).into_response(),
+        },
+        None => (None, None),
+    };
+    let reason = match (given, scope) {
+        (None, _) => Some("Missing bearer token"),
+        (Some(_), None) => Some("Invalid or revoked API key"),
+        (Some(_), Some(scope)) if !scope.allows(request.method()) => {
+            Some("Read-only API key used for a mutation")
+        }
+        _ => None,
+    };
+    // Nobody listening is not an error for the request itself
+    let _ = app_state
+        .progress_channel_sender
+        .send(Event::Auth(AuthAttempt {
+            realm: "admin",
+            success: reason.is_none(),
+            client_ip: client_ip(request.extensions()),
+            path: request
+                .extensions()
+                .get::<OriginalUri>()
+                .map_or(request.uri().path(), |uri| uri.path())
+                .to_string(),
+            reason: reason.map(str::to_string),
+        }));
+    match (reason, scope) {
+        (None, _) => {
+            if let Some(namespace) = namespace {
+                request.extensions_mut().insert(namespace);
+            }
+            next.run(request).await
+        }
+        (Some(reason), Some(_)) => AppError::Forbidden(reason.to_string()).into_response(),
+        (Some(_), None) => {
+            AppError::Unauthorized("Missing or invalid API key".to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_mint_authenticate_and_revoke() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        assert!(!any_active(&db).await?);
+
+        let read = mint(
+            &db,
+            &NewApiKey {
+                name: "backup script".to_string(),
+                scope: Scope::Read,
+                namespace: None,
+            },
+        )
+        .await?;
+        assert!(read.secret.starts_with(KEY_PREFIX));
+        assert!(any_active(&db).await?);
+        assert_eq!(
+            authenticate(&db, &read.secret).await?,
+            Some((Scope::Read, Namespace::Owner))
+        );
+        assert_eq!(authenticate(&db, "hw_unknown").await?, None);
+        assert!(!Scope::Read.allows(&Method::POST));
+        assert!(Scope::Full.allows(&Method::DELETE));
+
+        let family = mint(
+            &db,
+            &NewApiKey {
+                name: "alice".to_string(),
+                scope: Scope::Full,
+                namespace: Some("alice".to_string()),
+            },
+        )
+        .await?;
+        assert_eq!(
+            authenticate(&db, &family.secret).await?,
+            Some((Scope::Full, Namespace::Member("alice".to_string())))
+        );
+        assert!(revoke(&db, &family.key.id).await?);
+
+        let keys = list(&db).await?;
+        assert_eq!(keys.len(), 2);
+        let key = keys.iter().find(|key| key.id == read.key.id).unwrap();
+        assert_eq!(key.namespace, None);
+        assert!(key.last_used_at.is_some());
+
+        assert!(revoke(&db, &read.key.id).await?);
+        assert!(!revoke(&db, &read.key.id).await?);
+        assert_eq!(authenticate(&db, &read.secret).await?, None);
+        assert!(!any_active(&db).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Versioning of the HTTP APIs.
+//!
+//! Clients may ask for a version with the `X-Hardwire-Api-Version` header, every versioned
+//! response carries the version which served it. Legacy mounts of an API are tagged with
+//! `Deprecation`/`Link` headers pointing to their versioned successor.
+
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+pub const API_VERSION_HEADER: HeaderName = HeaderName::from_static("x-hardwire-api-version");
+pub const DEPRECATION_HEADER: HeaderName = HeaderName::from_static("deprecation");
+pub const SUNSET_HEADER: HeaderName = HeaderName::from_static("sunset");
+
+pub const CURRENT_VERSION: u32 = 1;
+pub const SUPPORTED_VERSIONS: &[u32] = &[1];
+
+/// Reject requests asking for a version we don't serve and tag responses with the served one
+pub async fn negotiate_version(request: Request, next: Next) -> Response {
+    if let Some(requested) = request.headers().get(&API_VERSION_HEADER) {
+        let version = requested
+            .to_str()
+            .ok()
+            .map(|v| v.trim().trim_start_matches('v'))
+            .and_then(|v| v.parse::<u32>().ok());
+        if !version.is_some_and(|v| SUPPORTED_VERSIONS.contains(&v)) {
+            let supported: Vec<String> = SUPPORTED_VERSIONS.iter().map(u32::to_string).collect();
+            return (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Unsupported API version, supported versions: {}",
+                    supported.join(", ")
+                ),
+            )
+                .into_response();
+        }
+    }
+
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(API_VERSION_HEADER, HeaderValue::from(CURRENT_VERSION));
+    response
+}
+
+/// Where a deprecated mount of an API moved to
+#[derive(Clone, Debug)]
+pub struct Deprecation {
+    pub successor_prefix: &'static str,
+    /// HTTP date after which the deprecated mount may be removed
+    pub sunset: Option<&'static str>,
+}
+
+/// Tag responses of a deprecated mount, to be layered on the router before nesting it
+pub async fn deprecated(
+    State(deprecation): State<Deprecation>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let successor = format!("{}{}", deprecation.successor_prefix, request.uri().path());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION_HEADER, HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+    {
+        headers.insert(axum::http::header::LINK, link);
+    }
+    if let Some(sunset) = deprecation.sunset {
+        headers.insert(SUNSET_HEADER, HeaderValue::from_static(sunset));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        let api = Router::new().route("/tasks", get(|| async { "OK" }));
+        Router::new()
+            .nest(
+                "/api/v1",
+                api.clone()
+                    .layer(axum::middleware::from_fn(negotiate_version)),
+            )
+            .nest(
+                "/legacy",
+                api.layer(axum::middleware::from_fn_with_state(
+                    Deprecation {
+                        successor_prefix: "/api/v1",
+                        sunset: None,
+                    },
+                    deprecated,
+                )),
+            )
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_version() {
+        let response = app()
+            .oneshot(Request::get("/api/v1/tasks").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[API_VERSION_HEADER], "1");
+
+        let response = app()
+            .oneshot(
+                Request::get("/api/v1/tasks")
+                    .header(API_VERSION_HEADER, "2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_deprecated_mount_links_successor() {
+        let response = app()
+            .oneshot(Request::get("/legacy/tasks").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[DEPRECATION_HEADER], "true");
+        assert_eq!(
+            response.headers()[axum::http::header::LINK],
+            "</api/v1/tasks>; rel=\"successor-version\""
+        );
+    }
+}
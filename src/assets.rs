@@ -0,0 +1,118 @@
+//! Assets of the pages: the CSS built by `make css` and the images of `dist/`.
+//!
+//! By default they are served from `dist/` under the working directory. Built with the
+//! `embed-assets` feature, the binary includes them and runs from anywhere, answering with an ETag
+//! so browsers revalidate instead of downloading them again. `HARDWIRE_ASSETS_DIR` serves a
+//! directory instead of either, to try changes to the CSS without rebuilding. The templates don't
+//! need either: askama compiles them into the binary.
+
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG};
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::LazyLock;
+use tower_http::services::ServeDir;
+
+/// Directory served without the `embed-assets` feature or `HARDWIRE_ASSETS_DIR`
+pub const STD_ASSETS_DIR: &str = "dist/";
+
+/// Files of `dist/` by path, written by build.rs, empty without the `embed-assets` feature
+static EMBEDDED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+static ASSETS: LazyLock<HashMap<&'static str, Asset>> = LazyLock::new(|| index(EMBEDDED));
+
+struct Asset {
+    content: &'static [u8],
+    content_type: String,
+    etag: String,
+}
+
+fn index(files: &'static [(&'static str, &'static [u8])]) -> HashMap<&'static str, Asset> {
+    files
+        .iter()
+        .map(|(path, content)| {
+            let asset = Asset {
+                content,
+                content_type: mime_guess::from_path(path)
+                    .first_or_octet_stream()
+                    .to_string(),
+                etag: format!("\"{}\"", &blake3::hash(content).to_hex()[..16]),
+            };
+            (*path, asset)
+        })
+        .collect()
+}
+
+/// Service of `/assets`, from `dir` when given
+pub fn router(dir: Option<&Path>) -> Router {
+    match dir {
+        Some(dir) => {
+            log::info!("Serving the assets from {}", dir.display());
+            Router::new().fallback_service(ServeDir::new(dir))
+        }
+        None if cfg!(feature = "embed-assets") => {
+            log::info!(
+                "Serving the {} assets embedded in the binary",
+                EMBEDDED.len()
+            );
+            Router::new().fallback(embedded)
+        }
+        None => Router::new().fallback_service(ServeDir::new(STD_ASSETS_DIR)),
+    }
+}
+
+async fn embedded(uri: Uri, headers: HeaderMap) -> Response {
+    serve(&ASSETS, uri.path(), &headers)
+}
+
+fn serve(assets: &HashMap<&'static str, Asset>, path: &str, headers: &HeaderMap) -> Response {
+    let Some(asset) = assets.get(path.trim_start_matches('/')) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let cache_headers = [
+        (ETAG, asset.etag.clone()),
+        (CACHE_CONTROL, "no-cache".to_string()),
+    ];
+    if crate::etag_matches(headers, &asset.etag) {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (
+        cache_headers,
+        [(CONTENT_TYPE, asset.content_type.clone())],
+        asset.content,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::IF_NONE_MATCH;
+
+    static FILES: &[(&str, &[u8])] = &[
+        ("css/404.css", b"body { color: red; }"),
+        ("images/background.jpg", b"\xff\xd8\xff"),
+    ];
+
+    #[test]
+    fn test_serve() {
+        let assets = index(FILES);
+        let response = serve(&assets, "/css/404.css", &HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/css");
+        let etag = response.headers()[ETAG].clone();
+        let response = serve(&assets, "/images/background.jpg", &HeaderMap::new());
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/jpeg");
+        assert_ne!(response.headers()[ETAG], etag);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, etag);
+        let response = serve(&assets, "/css/404.css", &headers);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = serve(&assets, "/css/output.css", &HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,245 @@
+//! Audit log of the mutations made through the admin API and the webhook.
+//!
+//! Each action is stored in `audit_log` and emitted as a structured tracing event with the
+//! `audit` target, so it can be browsed with `GET /admin/api/audit` or shipped with the logs.
+//! Failing to record an action is logged but does not fail the request which performed it.
+
+use anyhow::Result;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::convert::Infallible;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::proxy::Client;
+
+/// Who performed an action: the API it came through and the client address
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub name: &'static str,
+    pub client_ip: Option<String>,
+}
+
+impl Actor {
+    pub fn new(name: &'static str, client: &Client) -> Self {
+        Actor {
+            name,
+            client_ip: client.ip.map(|ip| ip.to_string()),
+        }
+    }
+}
+
+/// Extracts the [`Actor`] of an admin API request
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let client = parts
+            .extensions
+            .get::<Client>()
+            .cloned()
+            .unwrap_or_default();
+        Ok(Actor::new("admin", &client))
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: i64,
+    pub actor: String,
+    pub client_ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: serde_json::Value,
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    id: i64,
+    created_at: i64,
+    actor: String,
+    client_ip: Option<String>,
+    action: String,
+    target: Option<String>,
+    details: String,
+}
+
+impl AuditRow {
+    fn into_entry(self) -> Result<AuditEntry> {
+        Ok(AuditEntry {
+            id: self.id,
+            created_at: self.created_at,
+            actor: self.actor,
+            client_ip: self.client_ip,
+            action: self.action,
+            target: self.target,
+            details: serde_json::from_str(&self.details)?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    /// Unix timestamps bounding `created_at`, both inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl AuditQuery {
+    pub const DEFAULT_LIMIT: i64 = 100;
+    pub const MAX_LIMIT: i64 = 1000;
+}
+
+/// Store and trace `action` performed by `actor` on `target`
+pub async fn record(
+    db: &SqlitePool,
+    actor: &Actor,
+    action: &str,
+    target: Option<&str>,
+    details: serde_json::Value,
+) {
+    tracing::info!(
+        target: "audit",
+        actor = actor.name,
+        client_ip = actor.client_ip.as_deref(),
+        action,
+        target,
+        details = %details,
+        "{} {}",
+        actor.name,
+        action
+    );
+    let now = chrono::offset::Utc::now().timestamp();
+    let details = details.to_string();
+    if let Err(e) = sqlx::query!(
+        r#"INSERT INTO audit_log (created_at, actor, client_ip, action, target, details)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+        now,
+        actor.name,
+        actor.client_ip,
+        action,
+        target,
+        details
+    )
+    .execute(db)
+    .await
+    {
+        tracing::error!("Failed to record {} in the audit log: {}", action, e);
+    }
+}
+
+/// Most recent entries first
+pub async fn search(db: &SqlitePool, query: &AuditQuery) -> Result<Vec<AuditEntry>> {
+    let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+        "SELECT id, created_at, actor, client_ip, action, target, details FROM audit_log WHERE 1 = 1",
+    );
+    if let Some(action) = &query.action {
+        builder.push(" AND action = ").push_bind(action);
+    }
+    if let Some(actor) = &query.actor {
+        builder.push(" AND actor = ").push_bind(actor);
+    }
+    if let Some(target) = &query.target {
+        builder.push(" AND target = ").push_bind(target);
+    }
+    if let Some(since) = query.since {
+        builder.push(" AND created_at >= ").push_bind(since);
+    }
+    if let Some(until) = query.until {
+        builder.push(" AND created_at <= ").push_bind(until);
+    }
+    builder
+        .push(" ORDER BY id DESC LIMIT ")
+        .push_bind(
+            query
+                .limit
+                .unwrap_or(AuditQuery::DEFAULT_LIMIT)
+                .clamp(1, AuditQuery::MAX_LIMIT),
+        )
+        .push(" OFFSET ")
+        .push_bind(query.offset.unwrap_or(0).max(0));
+    builder
+        .build_query_as::<AuditRow>()
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(AuditRow::into_entry)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_record_and_search() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let admin = Actor {
+            name: "admin",
+            client_ip: Some("192.0.2.1".to_string()),
+        };
+        let webhook = Actor {
+            name: "webhook",
+            client_ip: None,
+        };
+        record(
+            &db,
+            &admin,
+            "create_share",
+            Some("abc"),
+            serde_json::json!({ "files": ["/a"] }),
+        )
+        .await;
+        record(
+            &db,
+            &webhook,
+            "create_task",
+            Some("t1"),
+            serde_json::json!({}),
+        )
+        .await;
+        record(
+            &db,
+            &admin,
+            "delete_task",
+            Some("t1"),
+            serde_json::json!({}),
+        )
+        .await;
+
+        let all = search(&db, &AuditQuery::default()).await?;
+        let actions: Vec<&str> = all.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, vec!["delete_task", "create_task", "create_share"]);
+        assert_eq!(all[2].client_ip.as_deref(), Some("192.0.2.1"));
+        assert_eq!(all[2].details, serde_json::json!({ "files": ["/a"] }));
+
+        let query = AuditQuery {
+            actor: Some("admin".to_string()),
+            target: Some("t1".to_string()),
+            ..Default::default()
+        };
+        let entries = search(&db, &query).await?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "delete_task");
+
+        let query = AuditQuery {
+            since: Some(chrono::offset::Utc::now().timestamp() + 60),
+            ..Default::default()
+        };
+        assert!(search(&db, &query).await?.is_empty());
+        Ok(())
+    }
+}
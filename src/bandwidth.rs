@@ -0,0 +1,319 @@
+//! Download option suggested to the recipients of a share.
+//!
+//! With `HARDWIRE_BANDWIDTH_PROBE` enabled, the share page times the download of a small probe and
+//! asks the server how the files are best fetched: one by one, through an archive already in the
+//! share, or in ranged volumes when a single file would take too long to download in one go. The
+//! server divides its uplink, when known, between the downloads in progress.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use utoipa::ToSchema;
+
+use crate::instrumented::ByteSink;
+
+/// Size of the probe, large enough for a meaningful timing on fast links
+pub const PROBE_SIZE: usize = 256 * 1024;
+/// Time lost per file downloaded separately: request, save dialog...
+const PER_FILE_OVERHEAD_SECS: f64 = 1.0;
+/// Transfers longer than this are split, an interruption only loses the current volume
+const LONG_TRANSFER_SECS: f64 = 3600.0;
+/// Target duration of a volume
+const VOLUME_SECS: f64 = 600.0;
+const MIN_VOLUME_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_VOLUME_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// From this many files an archive is worth it
+const MANY_FILES: usize = 10;
+const ARCHIVE_EXTENSIONS: &[&str] = &[".zip", ".7z", ".tar", ".tar.gz", ".tar.zst", ".tgz"];
+
+/// Incompressible bytes, so proxies can't shrink the probe and skew the timing
+pub fn probe_payload() -> &'static [u8] {
+    static PAYLOAD: OnceLock<Vec<u8>> = OnceLock::new();
+    PAYLOAD.get_or_init(|| {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        (0..PROBE_SIZE)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    })
+}
+
+/// A download being served
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ServedDownload {
+    pub id: u64,
+    pub file_path: String,
+    pub client_ip: Option<String>,
+    pub started_at: i64,
+    pub read_bytes: u64,
+    pub total_bytes: u64,
+    /// Average since the download started
+    pub bytes_per_sec: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    file_path: String,
+    client_ip: Option<String>,
+    started_at: i64,
+    started: Instant,
+    total_bytes: u64,
+    read_bytes: Arc<AtomicU64>,
+}
+
+/// Downloads being served
+#[derive(Clone, Debug, Default)]
+pub struct ServerLoad {
+    next_id: Arc<AtomicU64>,
+    downloads: Arc<Mutex<HashMap<u64, Tracked>>>,
+}
+
+impl ServerLoad {
+    /// Count a download until the returned sink is dropped
+    pub fn track(
+        &self,
+        file_path: &str,
+        client_ip: Option<String>,
+        total_bytes: u64,
+    ) -> ActiveDownload {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let read_bytes = Arc::new(AtomicU64::new(0));
+        self.downloads.lock().unwrap().insert(
+            id,
+            Tracked {
+                file_path: file_path.to_string(),
+                client_ip,
+                started_at: chrono::offset::Utc::now().timestamp(),
+                started: Instant::now(),
+                total_bytes,
+                read_bytes: Arc::clone(&read_bytes),
+            },
+        );
+        ActiveDownload {
+            id,
+            read_bytes,
+            downloads: Arc::clone(&self.downloads),
+        }
+    }
+
+    pub fn active_downloads(&self) -> usize {
+        self.downloads.lock().unwrap().len()
+    }
+
+    /// Downloads in progress, oldest first
+    pub fn downloads(&self) -> Vec<ServedDownload> {
+        let mut downloads: Vec<ServedDownload> = self
+            .downloads
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, tracked)| {
+                let read_bytes = tracked.read_bytes.load(Ordering::Relaxed);
+                let elapsed = tracked.started.elapsed().as_secs_f64().max(0.001);
+                ServedDownload {
+                    id: *id,
+                    file_path: tracked.file_path.clone(),
+                    client_ip: tracked.client_ip.clone(),
+                    started_at: tracked.started_at,
+                    read_bytes,
+                    total_bytes: tracked.total_bytes,
+                    bytes_per_sec: (read_bytes as f64 / elapsed) as u64,
+                }
+            })
+            .collect();
+        downloads.sort_by_key(|download| download.id);
+        downloads
+    }
+}
+
+/// Sink of a download tracked by [`ServerLoad`], which forgets it with the response body
+pub struct ActiveDownload {
+    id: u64,
+    read_bytes: Arc<AtomicU64>,
+    downloads: Arc<Mutex<HashMap<u64, Tracked>>>,
+}
+
+impl ByteSink for ActiveDownload {
+    fn on_bytes(&mut self, read: u64, _total_read: u64) {
+        self.read_bytes.fetch_add(read, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        self.downloads.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuggestionQuery {
+    /// Bytes per second measured by the recipient
+    pub bandwidth: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadOption {
+    /// Download the files one by one
+    Direct,
+    /// Download the archive of the share
+    Archive,
+    /// Download the largest files in ranges of `volume_size` bytes
+    SplitVolumes,
+}
+
+/// A file of a share as seen by [`suggest`]
+#[derive(Debug, Clone)]
+pub struct ShareFile {
+    /// Token the file is served at
+    pub link: String,
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Suggestion {
+    pub option: DownloadOption,
+    pub reason: String,
+    /// Bytes per second expected for the recipient, after sharing the uplink
+    pub bandwidth: u64,
+    pub total_size: u64,
+    pub file_count: usize,
+    pub active_downloads: usize,
+    pub estimated_seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive_link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_size: Option<u64>,
+}
+
+fn is_archive(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ARCHIVE_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Best way to download `files` at `client_bandwidth` bytes per second while `active_downloads`
+/// share the `uplink` of the server
+pub fn suggest(
+    files: &[ShareFile],
+    client_bandwidth: u64,
+    active_downloads: usize,
+    uplink: Option<u64>,
+) -> Suggestion {
+    let bandwidth = uplink
+        .map_or(client_bandwidth, |uplink| {
+            client_bandwidth.min(uplink / (active_downloads as u64 + 1))
+        })
+        .max(1);
+    let total_size: u64 = files.iter().map(|f| f.size).sum();
+    let largest = files.iter().map(|f| f.size).max().unwrap_or(0);
+    let transfer_secs = |size: u64| size as f64 / bandwidth as f64;
+    let archive = files.iter().find(|f| is_archive(&f.name));
+
+    let mut suggestion = Suggestion {
+        option: DownloadOption::Direct,
+        reason: String::new(),
+        bandwidth,
+        total_size,
+        file_count: files.len(),
+        active_downloads,
+        estimated_seconds: 0,
+        archive_link: None,
+        volume_size: None,
+    };
+    if transfer_secs(largest) > LONG_TRANSFER_SECS {
+        let volume_size = ((bandwidth as f64 * VOLUME_SECS) as u64)
+            .clamp(MIN_VOLUME_SIZE, MAX_VOLUME_SIZE)
+            / (1024 * 1024)
+            * (1024 * 1024);
+        suggestion.option = DownloadOption::SplitVolumes;
+        suggestion.volume_size = Some(volume_size);
+        suggestion.estimated_seconds = transfer_secs(total_size) as u64;
+        suggestion.reason = format!(
+            "A single file would take more than an hour, download it in {} MiB ranges so an interruption only loses one of them",
+            volume_size / (1024 * 1024)
+        );
+    } else if let Some(archive) = archive.filter(|_| files.len() >= MANY_FILES) {
+        suggestion.option = DownloadOption::Archive;
+        suggestion.archive_link = Some(archive.link.clone());
+        suggestion.estimated_seconds =
+            (transfer_secs(archive.size) + PER_FILE_OVERHEAD_SECS) as u64;
+        suggestion.reason = format!(
+            "{} files are quicker to get through the {} archive",
+            files.len(),
+            archive.name
+        );
+    } else {
+        suggestion.estimated_seconds =
+            (transfer_secs(total_size) + files.len() as f64 * PER_FILE_OVERHEAD_SECS) as u64;
+        suggestion.reason = "Download the files directly".to_string();
+    }
+    suggestion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(sizes: &[(&str, u64)]) -> Vec<ShareFile> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, (name, size))| ShareFile {
+                link: format!("f{}", i),
+                name: name.to_string(),
+                size: *size,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_suggest() {
+        const MB: u64 = 1_000_000;
+        let few = files(&[("a.mkv", 500 * MB), ("b.mkv", 500 * MB)]);
+        let suggestion = suggest(&few, 10 * MB, 0, None);
+        assert_eq!(suggestion.option, DownloadOption::Direct);
+        assert_eq!(suggestion.estimated_seconds, 102);
+
+        let mut many = files(&[("photos.zip", 90 * MB)]);
+        many.extend(files(&[("photo.jpg", 10 * MB); 10]));
+        let suggestion = suggest(&many, 10 * MB, 0, None);
+        assert_eq!(suggestion.option, DownloadOption::Archive);
+        assert_eq!(suggestion.archive_link.as_deref(), Some("f0"));
+
+        // 100 MB/s on the client side but the uplink is shared with 9 other downloads
+        let suggestion = suggest(&few, 100 * MB, 9, Some(1000 * MB / 8));
+        assert_eq!(suggestion.bandwidth, 12_500_000);
+
+        let huge = files(&[("disk.img", 100_000 * MB)]);
+        let suggestion = suggest(&huge, 10 * MB, 0, None);
+        assert_eq!(suggestion.option, DownloadOption::SplitVolumes);
+        assert_eq!(suggestion.volume_size, Some(MAX_VOLUME_SIZE));
+        let suggestion = suggest(&huge, MB, 0, None);
+        assert_eq!(suggestion.volume_size, Some(572 * 1024 * 1024));
+        assert_eq!(probe_payload().len(), PROBE_SIZE);
+    }
+
+    #[test]
+    fn test_server_load() {
+        let load = ServerLoad::default();
+        let mut first = load.track("/a.mkv", None, 100);
+        let second = load.track("/b.mkv", Some("192.0.2.1".to_string()), 50);
+        first.on_bytes(40, 40);
+        let downloads = load.downloads();
+        assert_eq!(load.active_downloads(), 2);
+        assert_eq!(downloads[0].file_path, "/a.mkv");
+        assert_eq!(downloads[0].read_bytes, 40);
+
+        drop(first);
+        drop(second);
+        assert_eq!(load.active_downloads(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+//! Fault injection for resilience testing.
+//!
+//! Built with the `chaos` feature, `HARDWIRE_CHAOS=slow_reads=0.1,db_errors=0.05` makes each
+//! injection point fail with the given probability. Without the feature [`Chaos`] never injects
+//! anything, the unit tests configure it directly to assert the server degrades gracefully.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+
+#[cfg(feature = "chaos")]
+const CHAOS_ENV_VAR: &str = "HARDWIRE_CHAOS";
+const SLOW_READ_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Fault {
+    /// Served files pause before a read
+    SlowRead,
+    /// Task inputs and download records fail to be read or written
+    DbError,
+    /// Download progress updates are lost, as when a broadcast receiver lags
+    DroppedEvent,
+    /// The task worker panics while processing a task
+    WorkerPanic,
+    /// The file indexer panics while storing a scan, holding the lock of the tree
+    IndexerPanic,
+}
+
+impl Fault {
+    #[cfg(feature = "chaos")]
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "slow_reads" => Some(Fault::SlowRead),
+            "db_errors" => Some(Fault::DbError),
+            "dropped_events" => Some(Fault::DroppedEvent),
+            "worker_panics" => Some(Fault::WorkerPanic),
+            "indexer_panics" => Some(Fault::IndexerPanic),
+            _ => None,
+        }
+    }
+}
+
+/// Probability of each fault, shared by the injection points
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    faults: Option<Arc<HashMap<Fault, f64>>>,
+}
+
+impl Chaos {
+    #[cfg(any(test, feature = "chaos"))]
+    pub fn new(faults: &[(Fault, f64)]) -> Self {
+        Chaos {
+            faults: Some(Arc::new(faults.iter().copied().collect())),
+        }
+    }
+
+    /// Faults of `HARDWIRE_CHAOS`, ignored without the `chaos` feature
+    pub fn from_env() -> Self {
+        #[cfg(feature = "chaos")]
+        if let Ok(spec) = std::env::var(CHAOS_ENV_VAR) {
+            let faults: Vec<(Fault, f64)> = spec
+                .split(',')
+                .filter(|part| !part.trim().is_empty())
+                .map(|part| {
+                    let (name, probability) = part.split_once('=').unwrap_or((part, "1"));
+                    let fault = Fault::from_name(name.trim())
+                        .unwrap_or_else(|| panic!("Unknown fault {} in {}", name, CHAOS_ENV_VAR));
+                    (fault, probability.trim().parse::<f64>().unwrap())
+                })
+                .collect();
+            tracing::warn!("Fault injection enabled: {:?}", faults);
+            return Chaos::new(&faults);
+        }
+        Chaos::default()
+    }
+
+    /// Whether `fault` should happen now
+    pub fn inject(&self, fault: Fault) -> bool {
+        let Some(probability) = self.faults.as_ref().and_then(|f| f.get(&fault).copied()) else {
+            return false;
+        };
+        #[cfg(feature = "chaos")]
+        return rand::random::<f64>() < probability;
+        #[cfg(not(feature = "chaos"))]
+        return probability >= 1.0;
+    }
+
+    /// Error of a failed database access
+    pub fn db_error(&self) -> Result<(), sqlx::Error> {
+        if self.inject(Fault::DbError) {
+            return Err(sqlx::Error::PoolTimedOut);
+        }
+        Ok(())
+    }
+}
+
+/// Reader pausing before the reads picked by [`Fault::SlowRead`]
+pub struct SlowReader<R> {
+    inner: R,
+    chaos: Chaos,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<R> SlowReader<R> {
+    pub fn new(inner: R, chaos: Chaos) -> Self {
+        SlowReader {
+            inner,
+            chaos,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SlowReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.delay.is_none() && self.chaos.inject(Fault::SlowRead) {
+            self.delay = Some(Box::pin(tokio::time::sleep(SLOW_READ_DELAY)));
+        }
+        if let Some(delay) = self.delay.as_mut() {
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.delay = None;
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
@@ -0,0 +1,219 @@
+//! Origins allowed to call the APIs from a browser.
+//!
+//! `HARDWIRE_CORS_ORIGINS` lists origins such as `https://admin.example.com`. The host may start
+//! with `*.` to allow any subdomain, not the domain itself, and the port may be `*` to allow any
+//! port, e.g. `http://localhost:*` for a development server. Without a port only the default one of
+//! the scheme matches.
+
+use anyhow::{anyhow, Context};
+use axum::http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderValue, Method};
+use http::request::Parts as RequestParts;
+use std::str::FromStr;
+use std::sync::Arc;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{api_version, tus};
+
+/// The admin SPA and local development servers
+pub const STD_CORS_ORIGINS: &str =
+    "https://*.pestel.me,http://*.pestel.me,http://localhost:*,https://localhost:*";
+
+/// Scheme, host and port of an origin, lowercase
+fn split(origin: &str) -> Option<(String, String, Option<&str>)> {
+    let (scheme, authority) = origin.split_once("://")?;
+    if scheme.is_empty() || authority.is_empty() || authority.contains(['/', '@', '?', '#']) {
+        return None;
+    }
+    // The colons of an IPv6 address are within brackets
+    let (host, port) = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => (&authority[..i], Some(&authority[i + 1..])),
+        _ => (authority, None),
+    };
+    if host.is_empty() || port == Some("") {
+        return None;
+    }
+    Some((scheme.to_ascii_lowercase(), host.to_ascii_lowercase(), port))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Port {
+    Default,
+    Any,
+    Number(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct OriginPattern {
+    scheme: String,
+    /// Subdomains of the host only, for `*.` hosts
+    subdomains: bool,
+    host: String,
+    port: Port,
+}
+
+impl OriginPattern {
+    fn matches(&self, origin: &str) -> bool {
+        let Some((scheme, host, port)) = split(origin) else {
+            return false;
+        };
+        let host_matches = if self.subdomains {
+            host.strip_suffix(&self.host)
+                .is_some_and(|subdomain| !subdomain.is_empty())
+        } else {
+            host == self.host
+        };
+        let port = match port.map(str::parse::<u16>) {
+            None => None,
+            Some(Ok(port)) => Some(port),
+            Some(Err(_)) => return false,
+        };
+        let port_matches = match (&self.port, port) {
+            (Port::Any, _) | (Port::Default, None) => true,
+            (Port::Number(expected), Some(port)) => port == *expected,
+            _ => false,
+        };
+        scheme == self.scheme && host_matches && port_matches
+    }
+}
+
+impl FromStr for OriginPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (scheme, host, port) = split(s)
+            .ok_or_else(|| anyhow!("Invalid origin {}, expected scheme://host[:port]", s))?;
+        let (subdomains, host) = match host.strip_prefix("*.") {
+            Some(domain) => (true, format!(".{}", domain)),
+            None => (false, host),
+        };
+        if host.contains('*') {
+            anyhow::bail!(
+                "Invalid origin {}, only a leading *. is allowed in the host",
+                s
+            );
+        }
+        let port = match port {
+            None => Port::Default,
+            Some("*") => Port::Any,
+            Some(port) => Port::Number(
+                port.parse()
+                    .with_context(|| format!("Invalid port in origin {}", s))?,
+            ),
+        };
+        Ok(OriginPattern {
+            scheme,
+            subdomains,
+            host,
+            port,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AllowedOrigins(Vec<OriginPattern>);
+
+impl AllowedOrigins {
+    pub fn allows(&self, origin: &HeaderValue) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        self.0.iter().any(|pattern| pattern.matches(origin))
+    }
+
+    /// CORS of the APIs, with credentials for the allowed origins
+    pub fn layer(self) -> CorsLayer {
+        let origins = Arc::new(self);
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::predicate(
+                move |origin: &HeaderValue, _request_parts: &RequestParts| origins.allows(origin),
+            ))
+            .allow_headers([
+                AUTHORIZATION,
+                ACCEPT,
+                CONTENT_TYPE,
+                api_version::API_VERSION_HEADER,
+                tus::TUS_RESUMABLE,
+                tus::UPLOAD_LENGTH,
+                tus::UPLOAD_OFFSET,
+                tus::UPLOAD_METADATA,
+                tus::UPLOAD_CHECKSUM,
+            ])
+            .allow_methods([
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            // Read by tus clients to resume an upload
+            .expose_headers([
+                LOCATION,
+                tus::TUS_RESUMABLE,
+                tus::TUS_VERSION_HEADER,
+                tus::TUS_EXTENSION,
+                tus::UPLOAD_LENGTH,
+                tus::UPLOAD_OFFSET,
+                tus::UPLOAD_EXPIRES,
+            ])
+            .allow_credentials(true)
+    }
+}
+
+impl FromStr for AllowedOrigins {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<_>>()
+            .map(AllowedOrigins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(origins: &AllowedOrigins, origin: &[u8]) -> bool {
+        origins.allows(&HeaderValue::from_bytes(origin).unwrap())
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let origins: AllowedOrigins = "https://admin.example.com, https://*.example.org, http://localhost:*, http://[::1]:5173"
+            .parse()
+            .unwrap();
+        assert!(allows(&origins, b"https://admin.example.com"));
+        assert!(allows(&origins, b"https://Admin.Example.com"));
+        assert!(!allows(&origins, b"http://admin.example.com"));
+        assert!(!allows(&origins, b"https://admin.example.com:8443"));
+        assert!(!allows(&origins, b"https://evil-admin.example.com"));
+        assert!(allows(&origins, b"https://files.example.org"));
+        assert!(!allows(&origins, b"https://example.org"));
+        assert!(!allows(&origins, b"https://evilexample.org"));
+        assert!(allows(&origins, b"http://localhost:5173"));
+        assert!(allows(&origins, b"http://localhost"));
+        assert!(allows(&origins, b"http://[::1]:5173"));
+        assert!(!allows(&origins, b"http://[::1]:5174"));
+
+        assert!(!allows(&origins, b"null"));
+        assert!(!allows(&origins, b"http://localhost:*"));
+        // Origins which used to panic the predicate
+        assert!(!allows(&origins, b"file://"));
+        assert!(!allows(&origins, b"mailto:admin@example.com"));
+        assert!(!allows(&origins, b"https://admin.example.com\xff"));
+
+        assert!(AllowedOrigins::from_str("").unwrap().0.is_empty());
+        assert!("admin.example.com".parse::<AllowedOrigins>().is_err());
+        assert!("https://admin.*.com".parse::<AllowedOrigins>().is_err());
+        assert!("https://example.com/admin"
+            .parse::<AllowedOrigins>()
+            .is_err());
+        assert!(STD_CORS_ORIGINS.parse::<AllowedOrigins>().is_ok());
+    }
+}
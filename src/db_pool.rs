@@ -0,0 +1,156 @@
+//! Pools of connections to the database.
+//!
+//! SQLite only allows one writer at a time, so the writer pool holds a single connection:
+//! concurrent writes wait for it instead of failing with SQLITE_BUSY, while WAL lets the readers run
+//! alongside. `busy_timeout` covers the other processes, such as `hardwire export` or a backup,
+//! holding the lock. With WAL, `synchronous=NORMAL` only syncs at checkpoints, a power loss may lose
+//! the last transactions but never corrupts the database.
+
+use anyhow::{Context, Result};
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseConfig {
+    /// Connections of the reader pool
+    pub max_connections: u32,
+    /// Connections kept open in each pool, at most one for the writer
+    pub min_connections: u32,
+    /// Wait for a connection of a pool before failing the query
+    pub acquire_timeout: Duration,
+    /// Wait for the lock of another connection or process before failing with SQLITE_BUSY
+    pub busy_timeout: Duration,
+}
+
+impl DatabaseConfig {
+    pub fn new(
+        max_connections: &str,
+        min_connections: &str,
+        acquire_timeout_secs: &str,
+        busy_timeout_ms: &str,
+    ) -> Result<Self> {
+        let parse = |value: &str, name: &str| {
+            value
+                .parse::<u32>()
+                .with_context(|| format!("Invalid {} {}", name, value))
+        };
+        let config = DatabaseConfig {
+            max_connections: parse(max_connections, "HARDWIRE_DB_MAX_CONNECTIONS")?,
+            min_connections: parse(min_connections, "HARDWIRE_DB_MIN_CONNECTIONS")?,
+            acquire_timeout: Duration::from_secs(
+                parse(acquire_timeout_secs, "HARDWIRE_DB_ACQUIRE_TIMEOUT_SECS")?.into(),
+            ),
+            busy_timeout: Duration::from_millis(
+                parse(busy_timeout_ms, "HARDWIRE_DB_BUSY_TIMEOUT_MS")?.into(),
+            ),
+        };
+        anyhow::ensure!(
+            config.max_connections > 0,
+            "HARDWIRE_DB_MAX_CONNECTIONS must be at least 1"
+        );
+        anyhow::ensure!(
+            config.min_connections <= config.max_connections,
+            "HARDWIRE_DB_MIN_CONNECTIONS {} is above HARDWIRE_DB_MAX_CONNECTIONS {}",
+            config.min_connections,
+            config.max_connections
+        );
+        Ok(config)
+    }
+
+    fn connect_options(&self, path: &Path) -> SqliteConnectOptions {
+        SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(self.busy_timeout)
+    }
+
+    /// Open the writer and reader pools of the database at `path`, creating it if needed
+    pub async fn open(&self, path: &Path) -> Result<(SqlitePool, SqlitePool)> {
+        let options = self.connect_options(path);
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(self.min_connections.min(1))
+            .acquire_timeout(self.acquire_timeout)
+            .connect_with(options.clone())
+            .await
+            .with_context(|| format!("Failed to open the database {}", path.display()))?;
+        let reader = SqlitePoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .connect_with(options.read_only(true))
+            .await
+            .with_context(|| format!("Failed to open the database {}", path.display()))?;
+        let pragmas = pragmas(&writer).await?;
+        log::info!(
+            "Opened {} with {}, 1 writer and {} readers, {} kept open, acquire timeout {}s",
+            path.display(),
+            pragmas,
+            self.max_connections,
+            self.min_connections,
+            self.acquire_timeout.as_secs()
+        );
+        Ok((writer, reader))
+    }
+}
+
+/// The pragmas the pools set, as applied by SQLite
+async fn pragmas(db: &SqlitePool) -> Result<String> {
+    let mut conn = db.acquire().await?;
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+        .fetch_one(&mut *conn)
+        .await?;
+    let synchronous: i64 = sqlx::query_scalar("PRAGMA synchronous")
+        .fetch_one(&mut *conn)
+        .await?;
+    let busy_timeout: i64 = sqlx::query_scalar("PRAGMA busy_timeout")
+        .fetch_one(&mut *conn)
+        .await?;
+    let synchronous = match synchronous {
+        0 => "off",
+        1 => "normal",
+        2 => "full",
+        _ => "extra",
+    };
+    Ok(format!(
+        "journal_mode={} synchronous={} busy_timeout={}ms",
+        journal_mode, synchronous, busy_timeout
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_open() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = DatabaseConfig::new("3", "1", "2", "1500")?;
+        let (writer, reader) = config.open(&dir.path().join("db.sqlite")).await?;
+        assert_eq!(
+            pragmas(&writer).await?,
+            "journal_mode=wal synchronous=normal busy_timeout=1500ms"
+        );
+        assert_eq!(
+            pragmas(&reader).await?,
+            "journal_mode=wal synchronous=normal busy_timeout=1500ms"
+        );
+        assert_eq!(reader.options().get_max_connections(), 3);
+        assert_eq!(reader.options().get_min_connections(), 1);
+        assert_eq!(writer.options().get_max_connections(), 1);
+        assert_eq!(
+            writer.options().get_acquire_timeout(),
+            Duration::from_secs(2)
+        );
+
+        assert!(DatabaseConfig::new("0", "0", "30", "5000").is_err());
+        assert!(DatabaseConfig::new("2", "3", "30", "5000").is_err());
+        assert!(DatabaseConfig::new("4", "0", "soon", "5000").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+//! Schema of the database.
+//!
+//! The migrations of `migrations/` are embedded in the binary and applied when the database is
+//! opened, unless `--no-migrate` is given, which only warns about the pending ones. The versions
+//! already applied are checked against the embedded ones first: a database migrated by a newer
+//! build, a migration edited after being applied or one which failed partway refuse to start with
+//! the versions involved, rather than failing on the first query. So does a database whose tables
+//! were created without the history of the migrations, unless `--no-migrate` is given.
+
+use anyhow::Result;
+use sqlx::migrate::{AppliedMigration, Migrate, Migration, Migrator};
+use sqlx::SqlitePool;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Why the database doesn't match the migrations of this build
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaDrift {
+    /// Applied by a newer build, or a migration since removed
+    UnknownVersion { version: i64, latest: i64 },
+    /// Applied with another content than the embedded one
+    ChecksumMismatch { version: i64, description: String },
+    /// Failed partway, the schema is in between two versions
+    Dirty { version: i64 },
+    /// Tables without the `_sqlx_migrations` history
+    Untracked,
+}
+
+impl std::fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaDrift::UnknownVersion { version, latest } => write!(
+                f,
+                "The database has migration {} applied, unknown to this build whose latest is {}: \
+                 upgrade hardwire or restore a backup",
+                version, latest
+            ),
+            SchemaDrift::ChecksumMismatch {
+                version,
+                description,
+            } => write!(
+                f,
+                "Migration {} ({}) was changed since it was applied to the database",
+                version, description
+            ),
+            SchemaDrift::Dirty { version } => write!(
+                f,
+                "Migration {} failed partway, restore a backup of the database",
+                version
+            ),
+            SchemaDrift::Untracked => write!(
+                f,
+                "The tables of the database were created without the history of the migrations, \
+                 start with --no-migrate to use them as they are"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SchemaDrift {}
+
+fn migrations() -> impl Iterator<Item = &'static Migration> {
+    MIGRATOR
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+}
+
+/// Check the `applied` migrations against the embedded ones, and list those still to apply
+fn pending(applied: &[AppliedMigration]) -> Result<Vec<&'static Migration>, SchemaDrift> {
+    let latest = migrations().map(|m| m.version).max().unwrap_or_default();
+    for applied in applied {
+        match migrations().find(|m| m.version == applied.version) {
+            None => {
+                return Err(SchemaDrift::UnknownVersion {
+                    version: applied.version,
+                    latest,
+                })
+            }
+            Some(migration) if migration.checksum != applied.checksum => {
+                return Err(SchemaDrift::ChecksumMismatch {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(migrations()
+        .filter(|m| !applied.iter().any(|applied| applied.version == m.version))
+        .collect())
+}
+
+/// Check the schema of `db` and apply the pending migrations unless `no_migrate`. Returns the
+/// versions applied.
+pub async fn prepare(db: &SqlitePool, no_migrate: bool) -> Result<Vec<i64>> {
+    let mut conn = db.acquire().await?;
+    let tables: Vec<String> = sqlx::query_scalar(
+        "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut applied = vec![];
+    if tables.iter().any(|table| table == "_sqlx_migrations") {
+        if let Some(version) = conn.dirty_version().await? {
+            return Err(SchemaDrift::Dirty { version }.into());
+        }
+        applied = conn.list_applied_migrations().await?;
+    }
+    // The history may be there, left by a failed first migration
+    let untracked = applied.is_empty() && tables.iter().any(|table| table != "_sqlx_migrations");
+    if untracked && !no_migrate {
+        return Err(SchemaDrift::Untracked.into());
+    }
+    let pending = pending(&applied)?;
+    let versions: Vec<i64> = pending.iter().map(|m| m.version).collect();
+    if no_migrate {
+        if !untracked && !versions.is_empty() {
+            log::warn!(
+                "{} migrations are not applied to the database, the latest being {}",
+                versions.len(),
+                versions[versions.len() - 1]
+            );
+        }
+        return Ok(vec![]);
+    } else if !pending.is_empty() {
+        MIGRATOR.run(&mut *conn).await?;
+        for migration in &pending {
+            log::info!(
+                "Applied migration {} {}",
+                migration.version,
+                migration.description
+            );
+        }
+    }
+    if let Some(version) = migrations().map(|m| m.version).max() {
+        log::info!("Database schema at version {}", version);
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_prepare() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        let latest = migrations().map(|m| m.version).max().unwrap();
+        assert!(prepare(&db, true).await?.is_empty());
+        sqlx::query("CREATE TABLE share_links (id TEXT)")
+            .execute(&db)
+            .await?;
+        let error = prepare(&db, false).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SchemaDrift>(),
+            Some(&SchemaDrift::Untracked)
+        );
+        assert!(prepare(&db, true).await?.is_empty());
+        db.acquire().await?.ensure_migrations_table().await?;
+        let error = prepare(&db, false).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SchemaDrift>(),
+            Some(&SchemaDrift::Untracked)
+        );
+        sqlx::query("DROP TABLE share_links").execute(&db).await?;
+
+        let applied = prepare(&db, false).await?;
+        assert_eq!(applied.len(), migrations().count());
+        assert_eq!(applied.last(), Some(&latest));
+        assert!(prepare(&db, false).await?.is_empty());
+        assert!(prepare(&db, true).await?.is_empty());
+
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = x'00' WHERE version = ?")
+            .bind(latest)
+            .execute(&db)
+            .await?;
+        let error = prepare(&db, false).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<SchemaDrift>(),
+            Some(SchemaDrift::ChecksumMismatch { version, .. }) if *version == latest
+        ));
+        let checksum = migrations().last().unwrap().checksum.to_vec();
+        sqlx::query("UPDATE _sqlx_migrations SET checksum = ? WHERE version = ?")
+            .bind(checksum)
+            .bind(latest)
+            .execute(&db)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (?, 'from a newer build', TRUE, x'00', 0)",
+        )
+        .bind(latest + 1)
+        .execute(&db)
+        .await?;
+        let error = prepare(&db, false).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SchemaDrift>(),
+            Some(&SchemaDrift::UnknownVersion {
+                version: latest + 1,
+                latest
+            })
+        );
+
+        sqlx::query("UPDATE _sqlx_migrations SET success = FALSE WHERE version = ?")
+            .bind(latest + 1)
+            .execute(&db)
+            .await?;
+        let error = prepare(&db, true).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SchemaDrift>(),
+            Some(&SchemaDrift::Dirty {
+                version: latest + 1
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+//! Free space checks before writing large files.
+//!
+//! Archives, disc images and uploads are checked against the space left on their file system
+//! before being written, `HARDWIRE_DISK_RESERVE_MB` being kept free for the database and the logs.
+//! A file which would not fit fails at once with [`InsufficientSpace`], a 413 for the admin API,
+//! rather than with ENOSPC once the disk is full.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use crate::top;
+
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub dir: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+    pub reserve: u64,
+}
+
+impl std::fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Not enough space in {}: {} needed, {} available of which {} are kept free",
+            self.dir.display(),
+            top::format_bytes(self.needed),
+            top::format_bytes(self.available),
+            top::format_bytes(self.reserve)
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// Fail unless `needed` bytes can be written at `path`, a file or directory which may not exist
+/// yet, and still leave `reserve` bytes free. Passes when the free space can't be known.
+pub fn ensure_space(path: &Path, needed: u64, reserve: u64) -> Result<()> {
+    let dir = path
+        .ancestors()
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("."));
+    let Some(usage) = top::disk_usage("", dir) else {
+        return Ok(());
+    };
+    if usage.available_bytes < needed.saturating_add(reserve) {
+        return Err(InsufficientSpace {
+            dir: dir.to_path_buf(),
+            needed,
+            available: usage.available_bytes,
+            reserve,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensure_space() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("archives").join("a.zip");
+        ensure_space(&output, 1, 0)?;
+        let error = ensure_space(&output, u64::MAX / 2, 0).unwrap_err();
+        let insufficient = error.downcast_ref::<InsufficientSpace>().unwrap();
+        assert_eq!(insufficient.dir, dir.path());
+        assert!(ensure_space(&output, 1, u64::MAX / 2).is_err());
+        Ok(())
+    }
+}
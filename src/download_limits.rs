@@ -0,0 +1,253 @@
+//! Concurrent downloads per client and per file.
+//!
+//! Download accelerators split a file into many ranges fetched at once, each one a stream reading
+//! the disk and taking a share of the uplink. A client address may only have
+//! `HARDWIRE_MAX_DOWNLOADS_PER_CLIENT` downloads in progress, and a file is read by at most
+//! `HARDWIRE_MAX_READERS_PER_FILE` downloads; requests beyond that get a 429 until one ends. `0`
+//! lifts a limit. Clients without an address, on the Unix socket, are only limited per file. The
+//! limits can be changed at runtime, see [`crate::settings`]: the downloads in progress then keep
+//! their slots of the former limits, and only the new ones count against the new limits.
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::instrumented::ByteSink;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LimitsConfig {
+    /// Downloads in progress of a client address, `None` without limit
+    pub per_client: Option<usize>,
+    /// Downloads in progress of a file, `None` without limit
+    pub per_file: Option<usize>,
+}
+
+impl LimitsConfig {
+    pub fn new(per_client: &str, per_file: &str) -> Result<Self> {
+        let parse = |value: &str, name: &str| {
+            value
+                .parse::<usize>()
+                .map(|limit| (limit > 0).then_some(limit))
+                .with_context(|| format!("Invalid {} {}", name, value))
+        };
+        Ok(LimitsConfig {
+            per_client: parse(per_client, "HARDWIRE_MAX_DOWNLOADS_PER_CLIENT")?,
+            per_file: parse(per_file, "HARDWIRE_MAX_READERS_PER_FILE")?,
+        })
+    }
+}
+
+/// Semaphores of the keys with a download in progress
+#[derive(Debug)]
+struct Slots<K> {
+    limit: usize,
+    semaphores: Mutex<HashMap<K, Arc<Semaphore>>>,
+}
+
+impl<K: Eq + Hash + Clone> Slots<K> {
+    fn new(limit: usize) -> Self {
+        Slots {
+            limit,
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn try_acquire(&self, key: &K) -> Option<OwnedSemaphorePermit> {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        let semaphore = semaphores
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)));
+        Arc::clone(semaphore).try_acquire_owned().ok()
+    }
+
+    /// Forget the semaphore of `key` once no download holds it
+    fn release(&self, key: &K, permit: Option<OwnedSemaphorePermit>) {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        drop(permit);
+        if semaphores
+            .get(key)
+            .is_some_and(|semaphore| semaphore.available_permits() == self.limit)
+        {
+            semaphores.remove(key);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limits {
+    per_client: Option<Arc<Slots<IpAddr>>>,
+    per_file: Option<Arc<Slots<String>>>,
+}
+
+impl Limits {
+    fn new(config: &LimitsConfig) -> Self {
+        Limits {
+            per_client: config.per_client.map(|limit| Arc::new(Slots::new(limit))),
+            per_file: config.per_file.map(|limit| Arc::new(Slots::new(limit))),
+        }
+    }
+}
+
+/// Cheap to clone, the clones share the slots and the limits
+#[derive(Debug, Clone, Default)]
+pub struct DownloadLimiter {
+    limits: Arc<RwLock<Limits>>,
+}
+
+/// Which limit refused a download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Client,
+    File,
+}
+
+impl Limited {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Limited::Client => {
+                "Too many downloads in progress from your address, retry once one of them ends"
+            }
+            Limited::File => "Too many downloads of this file in progress, retry later",
+        }
+    }
+}
+
+impl DownloadLimiter {
+    pub fn new(config: &LimitsConfig) -> Self {
+        DownloadLimiter {
+            limits: Arc::new(RwLock::new(Limits::new(config))),
+        }
+    }
+
+    /// Apply `config` to the downloads starting from now
+    pub fn reconfigure(&self, config: &LimitsConfig) {
+        *self.limits.write().unwrap() = Limits::new(config);
+    }
+
+    /// Take a slot of `client_ip` and of `file_path`, held until the returned sink is dropped
+    /// with the response body
+    pub fn try_start(
+        &self,
+        client_ip: Option<IpAddr>,
+        file_path: &str,
+    ) -> Result<DownloadSlot, Limited> {
+        let (per_client, per_file) = {
+            let limits = self.limits.read().unwrap();
+            (limits.per_client.clone(), limits.per_file.clone())
+        };
+        let mut slot = DownloadSlot::default();
+        if let (Some(slots), Some(ip)) = (&per_client, client_ip) {
+            let permit = slots.try_acquire(&ip);
+            let acquired = permit.is_some();
+            slot.client = Some((Arc::clone(slots), ip, permit));
+            if !acquired {
+                return Err(Limited::Client);
+            }
+        }
+        if let Some(slots) = &per_file {
+            let path = file_path.to_string();
+            let permit = slots.try_acquire(&path);
+            let acquired = permit.is_some();
+            slot.file = Some((Arc::clone(slots), path, permit));
+            if !acquired {
+                return Err(Limited::File);
+            }
+        }
+        Ok(slot)
+    }
+}
+
+/// Sink of a download allowed by [`DownloadLimiter`], which frees its slots when dropped
+#[derive(Default)]
+pub struct DownloadSlot {
+    client: Option<(Arc<Slots<IpAddr>>, IpAddr, Option<OwnedSemaphorePermit>)>,
+    file: Option<(Arc<Slots<String>>, String, Option<OwnedSemaphorePermit>)>,
+}
+
+impl Drop for DownloadSlot {
+    fn drop(&mut self) {
+        if let Some((slots, ip, permit)) = self.client.take() {
+            slots.release(&ip, permit);
+        }
+        if let Some((slots, path, permit)) = self.file.take() {
+            slots.release(&path, permit);
+        }
+    }
+}
+
+impl ByteSink for DownloadSlot {
+    fn on_bytes(&mut self, _read: u64, _total_read: u64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let config = LimitsConfig::new("4", "0")?;
+        assert_eq!((config.per_client, config.per_file), (Some(4), None));
+        assert!(LimitsConfig::new("-1", "8").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_limiter() {
+        let limiter = DownloadLimiter::new(&LimitsConfig {
+            per_client: Some(2),
+            per_file: Some(3),
+        });
+        let (alice, bob): (IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+
+        let first = limiter.try_start(Some(alice), "/a.iso").unwrap();
+        let second = limiter.try_start(Some(alice), "/b.iso").unwrap();
+        assert_eq!(
+            limiter.try_start(Some(alice), "/c.iso").err(),
+            Some(Limited::Client)
+        );
+        drop(first);
+        let third = limiter.try_start(Some(alice), "/b.iso").unwrap();
+
+        let other = limiter.try_start(Some(bob), "/b.iso").unwrap();
+        assert_eq!(limiter.try_start(None, "/b.iso").err(), Some(Limited::File));
+        // The refused downloads took no slot
+        let local = limiter.try_start(None, "/a.iso").unwrap();
+
+        drop((second, third, other, local));
+        {
+            let limits = limiter.limits.read().unwrap();
+            let (per_client, per_file) = (
+                limits.per_client.as_ref().unwrap(),
+                limits.per_file.as_ref().unwrap(),
+            );
+            assert_eq!(
+                (
+                    per_client.semaphores.lock().unwrap().len(),
+                    per_file.semaphores.lock().unwrap().len()
+                ),
+                (0, 0)
+            );
+        }
+
+        // The download in progress keeps its slot, only the new ones count
+        let held = limiter.try_start(Some(alice), "/a.iso").unwrap();
+        limiter.reconfigure(&LimitsConfig {
+            per_client: Some(1),
+            per_file: None,
+        });
+        let first = limiter.try_start(Some(alice), "/a.iso").unwrap();
+        assert_eq!(
+            limiter.try_start(Some(alice), "/b.iso").err(),
+            Some(Limited::Client)
+        );
+        drop((held, first));
+        limiter.reconfigure(&LimitsConfig::default());
+        let _unlimited: Vec<DownloadSlot> = (0..4)
+            .map(|_| limiter.try_start(Some(alice), "/a.iso").unwrap())
+            .collect();
+    }
+}
@@ -0,0 +1,194 @@
+//! Command line downloads of a whole share, for transfers between servers.
+//!
+//! `GET /s/{share_id}/manifest.txt` lists the URLs of the files, one per line, for
+//! `wget -c --content-disposition -i manifest.txt`. `GET /s/{share_id}/download.sh` is a bash
+//! script downloading them with curl into the current directory under their names, with the
+//! filename rules of the share applied. It resumes partial files, skips the complete ones, and checks
+//! the SHA-256 of the files hashed by the `ChecksumShare` task, so it can run again until
+//! everything is there. Encrypted shares have neither, their files are only readable with the key.
+
+use askama::Template;
+use axum::extract::{Path, State};
+use axum::http::header::{CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS};
+use axum::response::{IntoResponse, Response};
+use std::collections::HashSet;
+
+use crate::{filename_rules, not_found, proxy, share_zip, App};
+
+struct ShareFile {
+    /// Unique in the share, see [`share_zip::unique_name`]
+    name: String,
+    url: String,
+    /// Unknown for files shared before sizes were recorded
+    size: Option<i64>,
+    sha256: Option<String>,
+}
+
+/// The files of a share, sorted by path, `None` when it can't be downloaded
+async fn share_files(app_state: &App, share_id: &str, host: &str) -> Option<Vec<ShareFile>> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let rows = sqlx::query!(
+        r#"SELECT share_link_files.token as "token!", files.path, files.file_size, share_links.filename_rules,
+        file_digests.digest as "sha256?"
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        LEFT JOIN file_digests ON file_digests.file_id=files.id AND file_digests.algorithm='sha256'
+        WHERE share_link_files.share_link_id=$1 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $2) AND share_links.deleted_at IS NULL
+        ORDER BY files.path"#,
+        share_id,
+        now
+    )
+    .fetch_all(&app_state.db_reader)
+    .await
+    .ok()?;
+    if rows.is_empty() {
+        return None;
+    }
+
+    let today = chrono::offset::Utc::now().date_naive();
+    let mut names = HashSet::new();
+    let files = rows
+        .into_iter()
+        .map(|row| {
+            let rules: Vec<filename_rules::FilenameRule> =
+                serde_json::from_str(&row.filename_rules).unwrap_or_default();
+            let name = std::path::Path::new(&row.path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            ShareFile {
+                name: share_zip::unique_name(
+                    &mut names,
+                    &filename_rules::rewrite(&name, &rules, today),
+                ),
+                url: format!("{}/s/{}/{}", host, share_id, row.token),
+                size: row.file_size,
+                sha256: row.sha256,
+            }
+        })
+        .collect();
+    Some(files)
+}
+
+/// `text` in single quotes for the shell
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+#[derive(Template)]
+#[template(path = "download.sh", escape = "none")]
+struct DownloadScript {
+    share_url: String,
+    files: Vec<ScriptFile>,
+}
+
+/// A file of [`DownloadScript`], quoted for the shell
+struct ScriptFile {
+    name: String,
+    url: String,
+    /// Empty when unknown
+    size: String,
+    sha256: String,
+}
+
+fn render_script(share_url: &str, files: &[ShareFile]) -> String {
+    let script = DownloadScript {
+        share_url: share_url.to_string(),
+        files: files
+            .iter()
+            .map(|file| ScriptFile {
+                name: shell_quote(&file.name),
+                url: shell_quote(&file.url),
+                size: file.size.map_or_else(String::new, |size| size.to_string()),
+                sha256: file
+                    .sha256
+                    .as_deref()
+                    .filter(|digest| digest.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .unwrap_or_default()
+                    .to_ascii_lowercase(),
+            })
+            .collect(),
+    }
+    .render()
+    .unwrap();
+    // askama drops the last newline of the template
+    script + "\n"
+}
+
+/// Plain text shown by browsers, so a script can be read before it runs
+fn text(body: String) -> Response {
+    (
+        [
+            (CONTENT_TYPE, "text/plain; charset=utf-8"),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// The URLs of the files of a share, see the module documentation
+pub async fn manifest(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+    client: proxy::Client,
+) -> Response {
+    let host = client.host(&app_state.config.host);
+    let Some(files) = share_files(&app_state, &share_id, &host).await else {
+        return not_found().await.into_response();
+    };
+    let urls: String = files.iter().map(|file| format!("{}\n", file.url)).collect();
+    text(urls)
+}
+
+/// Script downloading the files of a share, see the module documentation
+pub async fn download_script(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+    client: proxy::Client,
+) -> Response {
+    let host = client.host(&app_state.config.host);
+    let Some(files) = share_files(&app_state, &share_id, &host).await else {
+        return not_found().await.into_response();
+    };
+    text(render_script(&format!("{}/s/{}", host, share_id), &files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("a b.txt"), "'a b.txt'");
+        assert_eq!(shell_quote("it's $HOME"), r"'it'\''s $HOME'");
+    }
+
+    #[test]
+    fn test_render_script() {
+        let files = [
+            ShareFile {
+                name: "Holidays 'final'.mp4".to_string(),
+                url: "https://files.example.com/s/V1StGXR8_Z/k3Jx9".to_string(),
+                size: Some(48213),
+                sha256: Some("AB12".to_string()),
+            },
+            ShareFile {
+                name: "notes.txt".to_string(),
+                url: "https://files.example.com/s/V1StGXR8_Z/pQ7wz".to_string(),
+                size: None,
+                sha256: None,
+            },
+        ];
+        let script = render_script("https://files.example.com/s/V1StGXR8_Z", &files);
+        assert!(script.starts_with("#!/usr/bin/env bash\n"));
+        assert!(script.contains(
+            r"fetch 'Holidays '\''final'\''.mp4' 'https://files.example.com/s/V1StGXR8_Z/k3Jx9' '48213' 'ab12'"
+        ));
+        assert!(script
+            .contains("fetch 'notes.txt' 'https://files.example.com/s/V1StGXR8_Z/pQ7wz' '' ''\n"));
+        assert!(script.ends_with("echo \"Downloaded 2 files\"\n"));
+    }
+}
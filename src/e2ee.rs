@@ -0,0 +1,188 @@
+//! End-to-end encrypted shares.
+//!
+//! The CLI encrypts the files before publishing them, the key only travels in the fragment of
+//! the share URL which browsers never send to the server. The share page decrypts the files with
+//! WebCrypto, see `templates/encrypted_share.html`.
+//!
+//! File format, all integers big endian:
+//!
+//! ```text
+//! "HWE1" | nonce prefix (8 bytes) | metadata length (u32) | metadata | chunk 1 | chunk 2 | ...
+//! ```
+//!
+//! The metadata (JSON with the original name and size) and each chunk of `CHUNK_SIZE` plaintext
+//! bytes are sealed with AES-256-GCM. The nonce of block `i` is the prefix followed by `i` as an
+//! u32, the metadata being block 0. The associated data flags the metadata and the last chunk so
+//! blocks can't be reordered nor the file truncated.
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+pub const MAGIC: &[u8; 4] = b"HWE1";
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+const AAD_CHUNK: &[u8] = &[0];
+const AAD_LAST_CHUNK: &[u8] = &[1];
+const AAD_METADATA: &[u8] = &[2];
+
+/// Plaintext metadata of an encrypted file
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub name: String,
+    pub size: u64,
+}
+
+pub struct ShareKey(Key<Aes256Gcm>);
+
+impl ShareKey {
+    pub fn generate() -> Self {
+        Self(Aes256Gcm::generate_key(OsRng))
+    }
+
+    /// Encoding of the key in the share URL fragment
+    pub fn to_fragment(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.0)
+    }
+}
+
+fn nonce(prefix: &[u8; 8], block: u32) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&block.to_be_bytes());
+    nonce.into()
+}
+
+fn seal(
+    cipher: &Aes256Gcm,
+    prefix: &[u8; 8],
+    block: u32,
+    aad: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>> {
+    cipher
+        .encrypt(&nonce(prefix, block), Payload { msg, aad })
+        .map_err(|_| anyhow!("Failed to encrypt block {}", block))
+}
+
+/// Encrypt `source` into `dest`. The chunks are not padded, the size of `dest` reveals the exact
+/// size of the file and the length of its metadata
+pub fn encrypt_file(source: &Path, dest: &Path, key: &ShareKey) -> Result<()> {
+    let cipher = Aes256Gcm::new(&key.0);
+    let mut prefix = [0u8; 8];
+    OsRng.fill_bytes(&mut prefix);
+
+    let mut input = File::open(source)?;
+    let size = input.metadata()?.len();
+    let metadata = FileMetadata {
+        name: source
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size,
+    };
+    let sealed_metadata = seal(
+        &cipher,
+        &prefix,
+        0,
+        AAD_METADATA,
+        &serde_json::to_vec(&metadata)?,
+    )?;
+
+    let mut output = BufWriter::new(File::create(dest)?);
+    output.write_all(MAGIC)?;
+    output.write_all(&prefix)?;
+    output.write_all(&(sealed_metadata.len() as u32).to_be_bytes())?;
+    output.write_all(&sealed_metadata)?;
+
+    // An empty file still has an empty last chunk
+    let chunks = size.div_ceil(CHUNK_SIZE as u64).max(1);
+    let block_count = u32::try_from(chunks).map_err(|_| anyhow!("File is too large"))?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining = size;
+    for block in 1..=block_count {
+        let len = remaining.min(CHUNK_SIZE as u64) as usize;
+        input.read_exact(&mut buf[..len])?;
+        remaining -= len as u64;
+        let aad = if block == block_count {
+            AAD_LAST_CHUNK
+        } else {
+            AAD_CHUNK
+        };
+        output.write_all(&seal(&cipher, &prefix, block, aad, &buf[..len])?)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn open(cipher: &Aes256Gcm, prefix: &[u8; 8], block: u32, aad: &[u8], msg: &[u8]) -> Vec<u8> {
+        cipher
+            .decrypt(&nonce(prefix, block), Payload { msg, aad })
+            .unwrap()
+    }
+
+    /// Same steps as the share page decryption
+    fn decrypt(data: &[u8], key: &ShareKey) -> (FileMetadata, Vec<u8>) {
+        let cipher = Aes256Gcm::new(&key.0);
+        assert_eq!(&data[..4], MAGIC);
+        let prefix: [u8; 8] = data[4..12].try_into().unwrap();
+        let metadata_len = u32::from_be_bytes(data[12..16].try_into().unwrap()) as usize;
+        let metadata = open(
+            &cipher,
+            &prefix,
+            0,
+            AAD_METADATA,
+            &data[16..16 + metadata_len],
+        );
+
+        let chunks: Vec<&[u8]> = data[16 + metadata_len..].chunks(CHUNK_SIZE + 16).collect();
+        let mut content = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            let aad = if i + 1 == chunks.len() {
+                AAD_LAST_CHUNK
+            } else {
+                AAD_CHUNK
+            };
+            content.extend(open(&cipher, &prefix, i as u32 + 1, aad, chunk));
+        }
+        (serde_json::from_slice(&metadata).unwrap(), content)
+    }
+
+    #[test]
+    fn test_encrypt_file_roundtrip() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let key = ShareKey::generate();
+        assert_eq!(key.to_fragment().len(), 43);
+
+        for size in [0, 14, CHUNK_SIZE, 2 * CHUNK_SIZE + 3] {
+            let source = temp_dir.path().join("test1.txt");
+            let content: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            std::fs::write(&source, &content)?;
+            let dest = temp_dir.path().join("test1.hwe");
+            encrypt_file(&source, &dest, &key)?;
+
+            let (metadata, decrypted) = decrypt(&std::fs::read(&dest)?, &key);
+            assert_eq!(
+                metadata,
+                FileMetadata {
+                    name: "test1.txt".to_string(),
+                    size: size as u64
+                }
+            );
+            assert_eq!(decrypted, content);
+        }
+        Ok(())
+    }
+}
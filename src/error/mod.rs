@@ -0,0 +1,216 @@
+//! Errors returned by the HTTP handlers.
+//!
+//! They are rendered as a JSON envelope:
+//! `{"error": {"code": "validation_failed", "message": "...", "details": [{"field": "...", "message": "..."}]}}`
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::disk_space::InsufficientSpace;
+use crate::plugins::Veto;
+use crate::share_alias::AliasTaken;
+use crate::share_profiles::{PasswordRequired, UnknownProfile, PROFILES_FILE_NAME};
+
+/// Error on a single field of a payload, `field` is a dotted path such as `data.files[0]`
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum AppError {
+    /// The payload was well formed but some fields are invalid
+    Validation(Vec<FieldError>),
+    BadRequest(String),
+    Unauthorized(String),
+    /// Authenticated but not allowed to perform the request
+    Forbidden(String),
+    NotFound(String),
+    /// Existed but is not served anymore
+    Gone(String),
+    /// The request does not apply to the current state of its target
+    Conflict(String),
+    /// What should be written does not fit on the disk
+    PayloadTooLarge(String),
+    /// A component the request needs is not running, retrying later may succeed
+    Unavailable(String),
+    Internal(anyhow::Error),
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    details: Vec<FieldError>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Gone(_) => StatusCode::GONE,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Validation(_) => "validation_failed",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::NotFound(_) => "not_found",
+            AppError::Gone(_) => "gone",
+            AppError::Conflict(_) => "conflict",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::Unavailable(_) => "unavailable",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Validation(errors) => {
+                write!(f, "Invalid payload: ")?;
+                let fields: Vec<String> = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(f, "{}", fields.join(", "))
+            }
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::NotFound(message)
+            | AppError::Gone(message)
+            | AppError::Conflict(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::Unavailable(message) => write!(f, "{}", message),
+            AppError::Internal(e) => write!(f, "Something went wrong: {}", e),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::Internal(e) = &self {
+            tracing::error!("{:#}", e);
+        }
+        let status = self.status();
+        let envelope = ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                details: match self {
+                    AppError::Validation(errors) => errors,
+                    _ => vec![],
+                },
+            },
+        };
+        (status, Json(envelope)).into_response()
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        if err.is::<Veto>() {
+            // The reason is only logged, it may tell more about the server than clients should know
+            return AppError::Forbidden("Refused by the server policy".to_string());
+        }
+        if err.is::<AliasTaken>() {
+            return AppError::Validation(vec![FieldError {
+                field: "alias".to_string(),
+                message: "is already taken".to_string(),
+            }]);
+        }
+        if err.is::<UnknownProfile>() {
+            return AppError::Validation(vec![FieldError {
+                field: "profile".to_string(),
+                message: format!("is not defined in {}", PROFILES_FILE_NAME),
+            }]);
+        }
+        if err.is::<PasswordRequired>() {
+            return AppError::Validation(vec![FieldError {
+                field: "password".to_string(),
+                message: "is required by the profile".to_string(),
+            }]);
+        }
+        if let Some(insufficient) = err.downcast_ref::<InsufficientSpace>() {
+            return AppError::PayloadTooLarge(insufficient.to_string());
+        }
+        if matches!(
+            err.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::RowNotFound)
+        ) {
+            return AppError::NotFound("Not found".to_string());
+        }
+        AppError::Internal(err)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => AppError::NotFound("Not found".to_string()),
+            err => AppError::Internal(err.into()),
+        }
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        AppError::Internal(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow() {
+        let missing = AppError::from(anyhow::Error::from(sqlx::Error::RowNotFound));
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let failed = AppError::from(anyhow::anyhow!("disk full"));
+        assert_eq!(
+            (failed.status(), failed.code()),
+            (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+        );
+        assert_eq!(failed.to_string(), "Something went wrong: disk full");
+        let full = AppError::from(anyhow::Error::from(InsufficientSpace {
+            dir: "/data".into(),
+            needed: 2_000_000,
+            available: 1_000_000,
+            reserve: 0,
+        }));
+        assert_eq!(
+            (full.status(), full.code()),
+            (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large")
+        );
+        assert_eq!(
+            full.to_string(),
+            "Not enough space in /data: 2.0 MB needed, 1.0 MB available of which 0 B are kept free"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Files given on the command line.
+//!
+//! `hardwire --files` and `hardwire publish` take paths, globs such as `"photos/**/*.jpg"`, quoted
+//! so the shell leaves them to hardwire, and directories, whose files are published recursively or,
+//! with `--no-recursive`, only the ones directly in them. Globs matching nothing, empty directories
+//! and missing paths are reported and left out, `--strict` makes them fail the command instead.
+//! With `--remote`, the paths are the server's and are sent as they are.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// An argument which gave no file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub arg: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.arg, self.reason)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Expansion {
+    /// In the order of the arguments, each once
+    pub files: Vec<String>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Expansion {
+    fn add(&mut self, path: &Path) {
+        let path = path.to_string_lossy().into_owned();
+        if !self.files.contains(&path) {
+            self.files.push(path);
+        }
+    }
+
+    fn skip(&mut self, arg: &str, reason: impl Into<String>) {
+        self.skipped.push(Skipped {
+            arg: arg.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    /// The files of a directory, sorted
+    fn add_dir(&mut self, arg: &str, dir: &Path, recursive: bool) {
+        let before = self.files.len();
+        let walk = WalkDir::new(dir)
+            .min_depth(1)
+            .max_depth(if recursive { usize::MAX } else { 1 })
+            .sort_by_file_name();
+        for entry in walk {
+            match entry {
+                Ok(entry) if entry.path().is_file() => self.add(entry.path()),
+                Ok(_) => {}
+                Err(e) => self.skip(arg, format!("is partly unreadable: {}", e)),
+            }
+        }
+        if self.files.len() == before {
+            self.skip(arg, "contains no file");
+        }
+    }
+
+    fn add_glob(&mut self, arg: &str, recursive: bool) {
+        let paths = match glob::glob(arg) {
+            Ok(paths) => paths,
+            Err(e) => return self.skip(arg, format!("is an invalid pattern: {}", e)),
+        };
+        let before = self.files.len();
+        for path in paths {
+            match path {
+                Ok(path) if path.is_dir() => self.add_dir(arg, &path, recursive),
+                Ok(path) => self.add(&path),
+                Err(e) => self.skip(arg, format!("is partly unreadable: {}", e)),
+            }
+        }
+        if self.files.len() == before {
+            self.skip(arg, "matches no file");
+        }
+    }
+
+    /// The files, after reporting the skipped arguments. Fails when one was skipped and `strict`,
+    /// or when none of the arguments gave a file.
+    pub fn into_files(self, strict: bool) -> Result<Vec<String>> {
+        for skipped in &self.skipped {
+            eprintln!("Skipped {}", skipped);
+        }
+        if strict && !self.skipped.is_empty() {
+            return Err(anyhow!(
+                "{} arguments gave no file, see above",
+                self.skipped.len()
+            ));
+        }
+        if self.files.is_empty() && !self.skipped.is_empty() {
+            return Err(anyhow!("No file to publish"));
+        }
+        Ok(self.files)
+    }
+}
+
+fn is_glob(arg: &str) -> bool {
+    arg.contains(['*', '?', '['])
+}
+
+/// The files of `args`, see the module documentation
+pub fn expand(args: &[String], recursive: bool) -> Expansion {
+    let mut expansion = Expansion::default();
+    for arg in args {
+        let path = PathBuf::from(arg);
+        // A file may be named like a pattern
+        if is_glob(arg) && !path.exists() {
+            expansion.add_glob(arg, recursive);
+        } else if path.is_dir() {
+            expansion.add_dir(arg, &path, recursive);
+        } else if path.exists() {
+            expansion.add(&path);
+        } else {
+            expansion.skip(arg, "does not exist");
+        }
+    }
+    expansion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("photos/2024/summer"))?;
+        std::fs::create_dir_all(root.join("empty"))?;
+        for name in [
+            "photos/a.jpg",
+            "photos/notes.txt",
+            "photos/2024/b.jpg",
+            "photos/2024/summer/c.jpg",
+        ] {
+            std::fs::write(root.join(name), name)?;
+        }
+        let arg = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+        let expansion = expand(&[arg("photos/**/*.jpg"), arg("photos/a.jpg")], true);
+        assert_eq!(
+            expansion.files,
+            [
+                arg("photos/2024/b.jpg"),
+                arg("photos/2024/summer/c.jpg"),
+                arg("photos/a.jpg")
+            ]
+        );
+        assert!(expansion.skipped.is_empty());
+
+        let expansion = expand(&[arg("photos")], true);
+        assert_eq!(expansion.files.len(), 4);
+        assert_eq!(expansion.files[0], arg("photos/2024/b.jpg"));
+        let expansion = expand(&[arg("photos")], false);
+        assert_eq!(
+            expansion.files,
+            [arg("photos/a.jpg"), arg("photos/notes.txt")]
+        );
+
+        let expansion = expand(
+            &[
+                arg("photos/notes.txt"),
+                arg("missing.txt"),
+                arg("*.png"),
+                arg("empty"),
+            ],
+            true,
+        );
+        assert_eq!(expansion.files, [arg("photos/notes.txt")]);
+        let reasons: Vec<&str> = expansion
+            .skipped
+            .iter()
+            .map(|skipped| skipped.reason.as_str())
+            .collect();
+        assert_eq!(
+            reasons,
+            ["does not exist", "matches no file", "contains no file"]
+        );
+        assert!(expansion.into_files(true).is_err());
+
+        let expansion = expand(&[arg("missing.txt"), arg("photos/a.jpg")], true);
+        assert_eq!(expansion.into_files(false)?, [arg("photos/a.jpg")]);
+        assert!(expand(&[arg("missing.txt")], true)
+            .into_files(false)
+            .is_err());
+        assert!(expand(&[], true).into_files(true)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! Deduplication of the shared files.
+//!
+//! Publishing a file inserts a `files` row for each share, its content is only known once hashed.
+//! When the SHA-256 of a file is stored, [`merge`] looks for a row with the same digest, size and
+//! name and moves the links of the shares to it, so one row holds the digests, health and preview
+//! of the content. `files.ref_count`, kept by triggers on `share_link_files`, tells when the last
+//! share of a row is gone and the row can be deleted, see [`delete_unreferenced`].
+//!
+//! The files the tasks generate in `HARDWIRE_DATA_DIR` (archives, manifests, torrents) are replaced
+//! in place by their next run, they are never merged. Uploads are.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use std::path::Path;
+use utoipa::ToSchema;
+
+use crate::tus;
+
+/// Space the shares take in `files`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct StorageStats {
+    /// Files linked by at least one share
+    pub files: i64,
+    /// Links of the shares to these files
+    pub links: i64,
+    /// Size of the files, each counted once
+    pub stored_bytes: i64,
+    /// Size of the files, counted once per link
+    pub shared_bytes: i64,
+    /// What a copy of the file per link would have taken on top of `stored_bytes`
+    pub saved_bytes: i64,
+}
+
+/// A duplicate merged into another row
+#[derive(Debug, PartialEq, Eq)]
+pub struct Merged {
+    /// Row the links now point to
+    pub file_id: i64,
+    /// Shares whose links were moved
+    pub share_ids: Vec<String>,
+}
+
+/// Whether `path` was generated by a task in `data_dir`, uploads excepted
+pub fn is_generated(data_dir: &Path, path: &Path) -> bool {
+    let generated_in =
+        |dir: &Path| path.starts_with(dir) && !path.starts_with(dir.join(tus::COMPLETE_DIR));
+    generated_in(data_dir) || std::fs::canonicalize(data_dir).is_ok_and(|dir| generated_in(&dir))
+}
+
+/// Move the links to `file_id` to the oldest row with the same SHA-256, size and file name, and
+/// delete `file_id`. `None` when the file isn't hashed or has no such duplicate.
+pub async fn merge(db: &SqlitePool, data_dir: &Path, file_id: i64) -> Result<Option<Merged>> {
+    let mut tx = db.begin().await?;
+    let Some(file) = sqlx::query!(
+        "SELECT sha256, file_size, path FROM files WHERE id = ?",
+        file_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let sha256 = file.sha256.unwrap_or_default();
+    let path = Path::new(&file.path);
+    if sha256.is_empty() || is_generated(data_dir, path) {
+        return Ok(None);
+    }
+    let candidates = sqlx::query!(
+        r#"SELECT id AS "id!", path FROM files
+        WHERE sha256 = ? AND file_size = ? AND id != ? ORDER BY id"#,
+        sha256,
+        file.file_size,
+        file_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let Some(canonical) = candidates.into_iter().find(|candidate| {
+        let candidate_path = Path::new(&candidate.path);
+        candidate_path.file_name() == path.file_name() && !is_generated(data_dir, candidate_path)
+    }) else {
+        return Ok(None);
+    };
+
+    let share_ids = sqlx::query_scalar!(
+        r#"SELECT DISTINCT share_link_id AS "share_link_id!" FROM share_link_files
+        WHERE file_id = ? AND share_link_id IS NOT NULL"#,
+        file_id
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE share_link_files SET file_id = ? WHERE file_id = ?",
+        canonical.id,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE download SET file_id = ? WHERE file_id = ?",
+        canonical.id,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"INSERT OR IGNORE INTO file_digests (file_id, algorithm, digest)
+        SELECT ?, algorithm, digest FROM file_digests WHERE file_id = ?"#,
+        canonical.id,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE files SET preview_path = COALESCE(preview_path, (SELECT preview_path FROM files WHERE id = ?2))
+        WHERE id = ?1"#,
+        canonical.id,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    delete_unreferenced(&mut tx, &[file_id]).await?;
+    tx.commit().await?;
+    Ok(Some(Merged {
+        file_id: canonical.id,
+        share_ids,
+    }))
+}
+
+/// Delete the rows of `file_ids` no share links to anymore, with their digests
+pub async fn delete_unreferenced(conn: &mut SqliteConnection, file_ids: &[i64]) -> Result<u64> {
+    let file_ids = serde_json::to_string(file_ids)?;
+    let deleted = sqlx::query!(
+        "DELETE FROM files WHERE ref_count <= 0 AND id IN (SELECT value FROM json_each(?))",
+        file_ids
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        r#"DELETE FROM file_digests WHERE file_id IN (SELECT value FROM json_each(?))
+        AND file_id NOT IN (SELECT id FROM files)"#,
+        file_ids
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(deleted)
+}
+
+pub async fn storage_stats(db: &SqlitePool) -> Result<StorageStats> {
+    let row = sqlx::query!(
+        r#"SELECT COUNT(*) AS "files!: i64",
+            COALESCE(SUM(ref_count), 0) AS "links!: i64",
+            COALESCE(SUM(file_size), 0) AS "stored_bytes!: i64",
+            COALESCE(SUM(file_size * ref_count), 0) AS "shared_bytes!: i64"
+        FROM files WHERE ref_count > 0"#
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(StorageStats {
+        files: row.files,
+        links: row.links,
+        stored_bytes: row.stored_bytes,
+        shared_bytes: row.shared_bytes,
+        saved_bytes: row.shared_bytes - row.stored_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_merge() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at) VALUES ('s1', -1, 0), ('s2', -1, 0), ('s3', -1, 0);
+            INSERT INTO files (id, sha256, path, file_size) VALUES
+                (1, 'abcd', '/data/a.iso', 100), (2, 'abcd', '/data/copy/a.iso', 100),
+                (3, 'abcd', '/data/b.iso', 100), (4, 'abcd', '/srv/hardwire/archives/a.iso', 100),
+                (5, '', '/data/a.iso', 100);
+            INSERT INTO share_link_files (share_link_id, file_id) VALUES ('s1', 1), ('s2', 2), ('s3', 2), ('s3', 3), ('s3', 4), ('s3', 5);
+            INSERT INTO file_digests (file_id, algorithm, digest) VALUES (2, 'sha256', 'abcd'), (2, 'blake3', 'ef01');
+            INSERT INTO download (file_path, share_id, file_id, status, file_size, started_at) VALUES ('/data/copy/a.iso', 's2', 2, 'complete', 100, 0);",
+        )
+        .execute(&db)
+        .await?;
+        let data_dir = Path::new("/srv/hardwire");
+        let stats = storage_stats(&db).await?;
+        assert_eq!((stats.files, stats.links, stats.saved_bytes), (5, 6, 100));
+
+        let merged = merge(&db, data_dir, 2).await?.unwrap();
+        assert_eq!(merged.file_id, 1);
+        assert_eq!(merged.share_ids, ["s2", "s3"]);
+        let ref_count: i64 = sqlx::query_scalar("SELECT ref_count FROM files WHERE id = 1")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(ref_count, 3);
+        let digests: Vec<String> =
+            sqlx::query_scalar("SELECT algorithm FROM file_digests WHERE file_id = 1")
+                .fetch_all(&db)
+                .await?;
+        assert_eq!(digests, ["blake3", "sha256"]);
+        let download_file: i64 = sqlx::query_scalar("SELECT file_id FROM download")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(download_file, 1);
+        let stats = storage_stats(&db).await?;
+        assert_eq!((stats.files, stats.links), (4, 6));
+        assert_eq!((stats.stored_bytes, stats.saved_bytes), (400, 200));
+
+        // Another name, a generated file, no digest
+        assert_eq!(merge(&db, data_dir, 3).await?, None);
+        assert_eq!(merge(&db, data_dir, 4).await?, None);
+        assert_eq!(merge(&db, data_dir, 5).await?, None);
+        assert_eq!(merge(&db, data_dir, 2).await?, None);
+
+        let mut conn = db.acquire().await?;
+        sqlx::query("DELETE FROM share_link_files WHERE share_link_id = 's3'")
+            .execute(&mut *conn)
+            .await?;
+        assert_eq!(delete_unreferenced(&mut conn, &[1, 3, 4]).await?, 2);
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM files ORDER BY id")
+            .fetch_all(&mut *conn)
+            .await?;
+        assert_eq!(ids, [1, 5]);
+        Ok(())
+    }
+}
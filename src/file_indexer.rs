@@ -1,110 +1,1271 @@
+//! Index of the files under `HARDWIRE_BASE_PATH`.
+//!
+//! A tokio task keeps the tree up to date from the filesystem notifications, or rescans it
+//! periodically when watching fails. The walks run on the blocking pool. They stop at
+//! `HARDWIRE_INDEX_MAX_DEPTH`, skip what matches `HARDWIRE_INDEX_EXCLUDE` or the patterns of the
+//! `.hardwireignore` files, and don't enter a symlinked directory which is one of its own
+//! ancestors.
+
 use chrono::Utc;
-use serde::Serialize;
+use futures::FutureExt;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc;
-use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
-use std::thread;
-use std::time::Duration;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::chaos::{Chaos, Fault};
 
-#[derive(Serialize, Debug, Clone)]
+/// Glob patterns, one per line, of the entries left out of the index in the directory of the file
+/// and below. Patterns with a `/` match the path relative to that directory, the others the name.
+pub const IGNORE_FILE: &str = ".hardwireignore";
+
+/// What the indexer walks
+#[derive(Debug, Clone, Default)]
+pub struct IndexOptions {
+    /// Directories this many levels below the base path are listed without their content
+    pub max_depth: Option<usize>,
+    /// Patterns of [`IGNORE_FILE`] applying to the whole tree
+    pub exclude: Vec<String>,
+    /// Leave out the entries whose name starts with a dot
+    pub hide_dotfiles: bool,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
 pub struct FileInfo {
     name: String,
     full_path: String,
     is_dir: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<u64>,
+    /// Unix timestamp of the last modification
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_at: Option<i64>,
+    /// Its metadata or, for a directory, its content could not be read
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unreadable: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(no_recursion)]
     children: Option<Vec<FileInfo>>,
 }
 
+/// How the indexer keeps the tree up to date
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexMode {
+    /// Filesystem notifications update the tree incrementally
+    Watch,
+    /// Watching failed, the whole tree is rescanned periodically
+    #[default]
+    Poll,
+}
+
+/// Metadata about the last scans, exposed to the admin API
+#[derive(Serialize, Debug, Clone, Default, ToSchema)]
+pub struct IndexStatus {
+    pub mode: IndexMode,
+    pub base_path: String,
+    pub last_full_scan_at: Option<i64>,
+    pub last_full_scan_duration_ms: Option<u64>,
+    pub last_update_at: Option<i64>,
+    pub file_count: usize,
+    pub dir_count: usize,
+    /// Entries indexed without their metadata or content, see [`FileInfo`]
+    pub unreadable_count: usize,
+    pub last_error: Option<String>,
+    /// Times the indexer task panicked and was restarted
+    pub restarts: u32,
+}
+
+/// Outcome of a full scan
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ScanReport {
+    pub file_count: usize,
+    pub dir_count: usize,
+    pub elapsed_ms: u64,
+}
+
+pub enum IndexerMessage {
+    /// Full rescan, the optional sender is notified once it completes
+    Rescan(Option<oneshot::Sender<Result<ScanReport, String>>>),
+    FsEvent(notify::Result<Event>),
+}
+
+/// Flattened view of an indexed entry, kept alongside the tree for searches
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct IndexedEntry {
+    pub name: String,
+    pub full_path: String,
+    pub is_dir: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<i64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unreadable: bool,
+    #[serde(skip)]
+    name_lowercase: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Dir,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Path,
+    Size,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Search in the index. `q` is a case insensitive substring of the name, or a glob pattern if it
+/// contains `*`, `?` or `[`, matched against the relative path when it contains a `/`.
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub kind: Option<EntryKind>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct SearchResults {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub entries: Vec<IndexedEntry>,
+}
+
+impl SearchQuery {
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+}
+
+/// A directory of the index, the base path when `path` is empty
+#[derive(Deserialize, Debug, Clone, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DirQuery {
+    #[serde(default)]
+    pub path: String,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// An entry of a directory, without the content of its subdirectories
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct DirEntry {
+    pub name: String,
+    /// Relative to the base path, the `path` listing the entries of a directory
+    pub full_path: String,
+    pub is_dir: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<i64>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unreadable: bool,
+    /// Entries of a directory, `None` when its content isn't indexed: it is unreadable or beyond
+    /// the maximum depth
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub child_count: Option<usize>,
+}
+
+/// A page of the entries of a directory, the directories first, then by name
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DirListing {
+    pub path: String,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub entries: Vec<DirEntry>,
+}
+
+/// Delay before the indexer task is restarted after a panic
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Filesystem events arriving this long after the first one are applied with it, the flattened
+/// index is rebuilt once per batch
+const EVENT_BATCH_WINDOW: Duration = Duration::from_millis(100);
+
+/// The locks are taken through [`lock`], which ignores the poisoning by a panic
 #[derive(Clone, Debug)]
 pub struct FileIndexer {
-    pub files: Arc<Mutex<Option<Vec<FileInfo>>>>,
-    pub _signal_index_updater: Sender<()>,
+    files: Arc<Mutex<Option<Vec<FileInfo>>>>,
+    entries: Arc<Mutex<Vec<IndexedEntry>>>,
+    status: Arc<Mutex<IndexStatus>>,
+    pub signal_index_updater: mpsc::UnboundedSender<IndexerMessage>,
+    walker: Arc<Walker>,
+    /// Faults injected in the scans, see [`crate::chaos`]
+    chaos: Chaos,
+    /// Cleared when the indexer task stops, even by a panic
+    running: Arc<AtomicBool>,
+}
+
+/// Lock `mutex` even when a panic poisoned it: the tree may miss the change being applied, until
+/// the rescan of the restarted indexer
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Clears the running flag of the indexer when its task ends
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
 }
 
 impl FileIndexer {
-    pub fn new(base_path: &Path, update_interval: u64) -> FileIndexer {
-        let (tx, rx) = mpsc::channel();
-        let rescan_tx = tx.clone();
-        let base_path: Arc<PathBuf> = Arc::new(base_path.to_path_buf());
+    pub fn new(
+        base_path: &Path,
+        update_interval: u64,
+        options: IndexOptions,
+        chaos: Chaos,
+    ) -> FileIndexer {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Mutex::new(IndexStatus {
+            base_path: base_path.to_string_lossy().into_owned(),
+            ..Default::default()
+        }));
 
-        let files: Arc<Mutex<Option<Vec<FileInfo>>>> = Arc::new(Mutex::new(Some(vec![])));
-        // Spawn a thread to run the scan periodically
-        let files_clone = Arc::clone(&files);
-        let base_path_clone = Arc::clone(&base_path);
+        let indexer = FileIndexer {
+            files: Arc::new(Mutex::new(Some(vec![]))),
+            entries: Arc::new(Mutex::new(vec![])),
+            status,
+            signal_index_updater: tx,
+            walker: Arc::new(Walker::new(base_path, &options)),
+            chaos,
+            running: Arc::new(AtomicBool::new(true)),
+        };
+        let indexer_clone = indexer.clone();
 
-        thread::spawn(move || {
+        let running = RunningGuard(Arc::clone(&indexer.running));
+        tokio::spawn(async move {
+            let _running = running;
+            // A panic loses the watcher, not the messages, and the next run rescans the tree
             loop {
-                match rec_scan_dir(&base_path_clone, &base_path_clone) {
-                    Ok(dir_structure) => {
-                        let mut output = files_clone.lock().unwrap();
-                        *output = Some(dir_structure);
+                let run = AssertUnwindSafe(indexer_clone.run(&mut rx, update_interval))
+                    .catch_unwind()
+                    .await;
+                let Err(panic) = run else {
+                    break;
+                };
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                log::error!("File indexer panicked, restarting it: {}", message);
+                {
+                    let mut status = lock(&indexer_clone.status);
+                    status.restarts += 1;
+                    status.last_error = Some(format!("Indexer panicked: {}", message));
+                }
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
+        });
+
+        indexer
+    }
+
+    /// Watch the tree, or rescan it every `update_interval` seconds, until the runtime shuts down
+    async fn run(&self, rx: &mut mpsc::UnboundedReceiver<IndexerMessage>, update_interval: u64) {
+        let base_path = &self.walker.base_path;
+        // Events carry absolute paths, while the tree is relative to the configured base path
+        let watch_root = fs::canonicalize(base_path).unwrap_or_else(|_| base_path.to_path_buf());
+
+        // The watcher must outlive the loop, dropping it stops notifications
+        let tx = self.signal_index_updater.clone();
+        let watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(IndexerMessage::FsEvent(res));
+        })
+        .and_then(|mut w| {
+            w.watch(&watch_root, RecursiveMode::Recursive)?;
+            Ok(w)
+        });
+
+        let mode = match &watcher {
+            Ok(_) => IndexMode::Watch,
+            Err(e) => {
+                log::warn!(
+                    "Failed to watch {}, falling back to periodic scans: {}",
+                    base_path.display(),
+                    e
+                );
+                lock(&self.status).last_error = Some(e.to_string());
+                IndexMode::Poll
+            }
+        };
+        lock(&self.status).mode = mode;
+
+        let _ = self.full_scan().await;
+
+        // A message received while batching events, handled next
+        let mut pending = None;
+        loop {
+            let msg = match pending.take() {
+                Some(msg) => msg,
+                None => match mode {
+                    IndexMode::Watch => match rx.recv().await {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    // Wait for either the update interval or a manual rescan signal
+                    IndexMode::Poll => {
+                        match tokio::time::timeout(Duration::from_secs(update_interval), rx.recv())
+                            .await
+                        {
+                            Ok(Some(msg)) => msg,
+                            Err(_) => IndexerMessage::Rescan(None),
+                            Ok(None) => break,
+                        }
+                    }
+                },
+            };
+
+            match msg {
+                IndexerMessage::Rescan(done) => {
+                    log::info!("Rescan of {} at {}", base_path.display(), Utc::now());
+                    let report = self.full_scan().await;
+                    if let Some(done) = done {
+                        let _ = done.send(report);
                     }
-                    Err(e) => eprintln!("Error scanning directory: {}", e),
                 }
+                IndexerMessage::FsEvent(Ok(event)) => {
+                    let mut events = vec![event];
+                    let deadline = tokio::time::Instant::now() + EVENT_BATCH_WINDOW;
+                    while let Ok(msg) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                        match msg {
+                            Some(IndexerMessage::FsEvent(Ok(event))) => events.push(event),
+                            msg => {
+                                pending = msg;
+                                break;
+                            }
+                        }
+                    }
 
-                // Wait for either a minute or a manual rescan signal
-                let res = rx.recv_timeout(Duration::from_secs(update_interval));
-                if res.is_ok() {
-                    println!("Manual rescan signal received at {}", Utc::now());
+                    // New ignore rules may hide or reveal anything below their directory
+                    let ignore_changed = events
+                        .iter()
+                        .flat_map(|event| &event.paths)
+                        .any(|path| path.file_name() == Some(IGNORE_FILE.as_ref()));
+                    if events.iter().any(Event::need_rescan) || ignore_changed {
+                        let _ = self.full_scan().await;
+                    } else {
+                        let indexer = self.clone();
+                        let watch_root = watch_root.clone();
+                        let applied = tokio::task::spawn_blocking(move || {
+                            indexer.apply_events(&watch_root, &events)
+                        })
+                        .await;
+                        match applied {
+                            Ok(()) => {}
+                            // The runtime is shutting down
+                            Err(e) if e.is_cancelled() => break,
+                            Err(e) => log::error!("File event handling failed: {}", e),
+                        }
+                    }
+                }
+                IndexerMessage::FsEvent(Err(e)) => {
+                    log::error!("File watcher error: {}", e);
+                    lock(&self.status).last_error = Some(e.to_string());
                 }
             }
-        });
+        }
+        drop(watcher);
+    }
+
+    /// The whole tree, `None` before the first scan
+    pub fn tree(&self) -> Option<Vec<FileInfo>> {
+        lock(&self.files).clone()
+    }
+
+    pub fn status(&self) -> IndexStatus {
+        lock(&self.status).clone()
+    }
+
+    /// Whether the indexer task still keeps the tree up to date
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Ask the indexer task for a full rescan, the receiver resolves once it is done
+    pub fn rescan(&self) -> anyhow::Result<oneshot::Receiver<Result<ScanReport, String>>> {
+        let (tx, rx) = oneshot::channel();
+        self.signal_index_updater
+            .send(IndexerMessage::Rescan(Some(tx)))
+            .map_err(|_| anyhow::anyhow!("file indexer task is not running"))?;
+        Ok(rx)
+    }
+
+    /// One level of the tree, `None` when `query.path` isn't an indexed directory
+    pub fn list_dir(&self, query: &DirQuery) -> Option<DirListing> {
+        let limit = query
+            .limit
+            .unwrap_or(SearchQuery::DEFAULT_LIMIT)
+            .min(SearchQuery::MAX_LIMIT);
+        let files = lock(&self.files);
+        let mut children = files.as_deref()?;
+        for component in query.path.split('/').filter(|c| !c.is_empty()) {
+            children = children
+                .iter()
+                .find(|f| f.is_dir && f.name == component)?
+                .children
+                .as_deref()?;
+        }
+
+        let mut entries: Vec<&FileInfo> = children.iter().collect();
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        Some(DirListing {
+            path: query.path.trim_matches('/').to_string(),
+            total: entries.len(),
+            offset: query.offset,
+            limit,
+            entries: entries
+                .into_iter()
+                .skip(query.offset)
+                .take(limit)
+                .map(|f| DirEntry {
+                    name: f.name.clone(),
+                    full_path: f.full_path.clone(),
+                    is_dir: f.is_dir,
+                    size: f.size,
+                    modified_at: f.modified_at,
+                    unreadable: f.unreadable,
+                    child_count: f.children.as_ref().map(Vec::len),
+                })
+                .collect(),
+        })
+    }
+
+    /// Search the flattened index, see [`SearchQuery`]
+    pub fn search(&self, query: &SearchQuery) -> Result<SearchResults, glob::PatternError> {
+        let matcher = query.q.as_deref().map(NameMatcher::new).transpose()?;
+        let limit = query
+            .limit
+            .unwrap_or(SearchQuery::DEFAULT_LIMIT)
+            .min(SearchQuery::MAX_LIMIT);
+
+        let entries = lock(&self.entries);
+        let mut matches: Vec<&IndexedEntry> = entries
+            .iter()
+            .filter(|e| match query.kind {
+                Some(EntryKind::File) => !e.is_dir,
+                Some(EntryKind::Dir) => e.is_dir,
+                None => true,
+            })
+            .filter(|e| {
+                query
+                    .min_size
+                    .is_none_or(|min| e.size.is_some_and(|s| s >= min))
+            })
+            .filter(|e| {
+                query
+                    .max_size
+                    .is_none_or(|max| e.size.is_some_and(|s| s <= max))
+            })
+            .filter(|e| matcher.as_ref().is_none_or(|m| m.matches(e)))
+            .collect();
+
+        match query.sort {
+            SortKey::Name => matches.sort_by(|a, b| a.name_lowercase.cmp(&b.name_lowercase)),
+            SortKey::Path => matches.sort_by(|a, b| a.full_path.cmp(&b.full_path)),
+            SortKey::Size => matches.sort_by_key(|e| e.size.unwrap_or(0)),
+        }
+        if let SortOrder::Desc = query.order {
+            matches.reverse();
+        }
 
-        FileIndexer {
-            files,
-            _signal_index_updater: rescan_tx.clone(),
+        Ok(SearchResults {
+            total: matches.len(),
+            offset: query.offset,
+            limit,
+            entries: matches
+                .into_iter()
+                .skip(query.offset)
+                .take(limit)
+                .cloned()
+                .collect(),
+        })
+    }
+
+    #[instrument(skip(self), fields(base_path = %self.walker.base_path.display(), file_count, dir_count), err)]
+    async fn full_scan(&self) -> Result<ScanReport, String> {
+        let started = Instant::now();
+        let walker = Arc::clone(&self.walker);
+        let scanned = tokio::task::spawn_blocking(move || walker.scan_tree())
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        match scanned {
+            Ok(dir_structure) => {
+                let (file_count, dir_count, unreadable_count) = self.reindex(&dir_structure);
+                let mut files = lock(&self.files);
+                // Poisons the lock of the tree
+                if self.chaos.inject(Fault::IndexerPanic) {
+                    panic!("Injected indexer panic");
+                }
+                *files = Some(dir_structure);
+                drop(files);
+
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                let mut status = lock(&self.status);
+                let now = Utc::now().timestamp();
+                status.last_full_scan_at = Some(now);
+                status.last_full_scan_duration_ms = Some(elapsed_ms);
+                status.last_update_at = Some(now);
+                status.file_count = file_count;
+                status.dir_count = dir_count;
+                status.unreadable_count = unreadable_count;
+                let span = tracing::Span::current();
+                span.record("file_count", file_count);
+                span.record("dir_count", dir_count);
+                Ok(ScanReport {
+                    file_count,
+                    dir_count,
+                    elapsed_ms,
+                })
+            }
+            Err(e) => {
+                log::error!("Error scanning directory: {}", e);
+                lock(&self.status).last_error = Some(e.to_string());
+                Err(e.to_string())
+            }
+        }
+    }
+
+    /// Refresh the paths of a batch of events in the tree, then rebuild the flattened index once
+    #[instrument(level = "debug", skip_all, fields(events = events.len()))]
+    fn apply_events(&self, watch_root: &Path, events: &[Event]) {
+        let mut paths: Vec<&PathBuf> = events
+            .iter()
+            .filter(|event| !matches!(event.kind, EventKind::Access(_)))
+            .flat_map(|event| &event.paths)
+            .collect();
+        if paths.is_empty() {
+            return;
         }
+        paths.sort();
+        paths.dedup();
+
+        let mut guard = lock(&self.files);
+        let tree = guard.get_or_insert_with(Vec::new);
+        for path in paths {
+            if let Err(e) = self.walker.refresh_path(watch_root, tree, path) {
+                log::error!("Error indexing {}: {}", path.display(), e);
+                lock(&self.status).last_error = Some(e.to_string());
+            }
+        }
+
+        let (file_count, dir_count, unreadable_count) = self.reindex(tree);
+        let mut status = lock(&self.status);
+        status.last_update_at = Some(Utc::now().timestamp());
+        status.file_count = file_count;
+        status.dir_count = dir_count;
+        status.unreadable_count = unreadable_count;
+    }
+
+    /// Rebuild the flattened index from the tree, returns the file, directory and unreadable entry
+    /// counts
+    fn reindex(&self, tree: &[FileInfo]) -> (usize, usize, usize) {
+        let mut entries = Vec::new();
+        flatten(tree, &mut entries);
+        let dir_count = entries.iter().filter(|e| e.is_dir).count();
+        let unreadable_count = entries.iter().filter(|e| e.unreadable).count();
+        let counts = (entries.len() - dir_count, dir_count, unreadable_count);
+        *lock(&self.entries) = entries;
+        counts
     }
 }
 
-fn rec_scan_dir(base_path: &Path, path: &Path) -> io::Result<Vec<FileInfo>> {
-    let mut files_info = Vec::new();
+enum NameMatcher {
+    Substring(String),
+    Name(glob::Pattern),
+    Path(glob::Pattern),
+}
 
-    if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
+impl NameMatcher {
+    const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+        case_sensitive: false,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
 
-            let metadata = fs::metadata(&path)?;
-            let size = if path.is_file() {
-                Some(metadata.len())
-            } else {
+    fn new(q: &str) -> Result<Self, glob::PatternError> {
+        if !q.contains(['*', '?', '[']) {
+            Ok(NameMatcher::Substring(q.to_lowercase()))
+        } else if q.contains('/') {
+            Ok(NameMatcher::Path(glob::Pattern::new(q)?))
+        } else {
+            Ok(NameMatcher::Name(glob::Pattern::new(q)?))
+        }
+    }
+
+    fn matches(&self, entry: &IndexedEntry) -> bool {
+        match self {
+            NameMatcher::Substring(q) => entry.name_lowercase.contains(q.as_str()),
+            NameMatcher::Name(p) => p.matches_with(&entry.name, Self::GLOB_OPTIONS),
+            NameMatcher::Path(p) => p.matches_with(&entry.full_path, Self::GLOB_OPTIONS),
+        }
+    }
+}
+
+fn flatten(files: &[FileInfo], entries: &mut Vec<IndexedEntry>) {
+    for f in files {
+        entries.push(IndexedEntry {
+            name: f.name.clone(),
+            full_path: f.full_path.clone(),
+            is_dir: f.is_dir,
+            size: f.size,
+            modified_at: f.modified_at,
+            unreadable: f.unreadable,
+            name_lowercase: f.name.to_lowercase(),
+        });
+        if let Some(children) = &f.children {
+            flatten(children, entries);
+        }
+    }
+}
+
+/// Pattern of the configuration or of an [`IGNORE_FILE`]
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    /// Directory the pattern applies to, relative to the base path
+    dir: PathBuf,
+    pattern: glob::Pattern,
+    /// Matched against the path relative to `dir` rather than the name
+    anchored: bool,
+}
+
+impl IgnoreRule {
+    const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+
+    /// `None` for blank lines and comments
+    fn parse(dir: &Path, line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        match glob::Pattern::new(line.trim_start_matches('/')) {
+            Ok(pattern) => Some(IgnoreRule {
+                dir: dir.to_path_buf(),
+                pattern,
+                anchored,
+            }),
+            Err(e) => {
+                log::warn!("Ignoring the index exclude pattern {}: {}", line, e);
                 None
-            };
+            }
+        }
+    }
+
+    fn matches(&self, relative: &Path) -> bool {
+        let Ok(below) = relative.strip_prefix(&self.dir) else {
+            return false;
+        };
+        if self.anchored {
+            self.pattern.matches_path_with(below, Self::GLOB_OPTIONS)
+        } else {
+            below.file_name().is_some_and(|name| {
+                self.pattern
+                    .matches_with(&name.to_string_lossy(), Self::GLOB_OPTIONS)
+            })
+        }
+    }
+}
+
+/// Walks the tree under the base path, see [`IndexOptions`]
+#[derive(Debug)]
+struct Walker {
+    base_path: PathBuf,
+    max_depth: Option<usize>,
+    exclude: Vec<IgnoreRule>,
+    hide_dotfiles: bool,
+}
+
+/// State of a walk down to a directory
+#[derive(Debug, Clone, Default)]
+struct WalkScope {
+    rules: Vec<IgnoreRule>,
+    /// Canonical paths of the directories walked through, a symlink back to one would loop
+    ancestors: Vec<PathBuf>,
+}
+
+impl Walker {
+    fn new(base_path: &Path, options: &IndexOptions) -> Self {
+        Walker {
+            base_path: base_path.to_path_buf(),
+            max_depth: options.max_depth,
+            exclude: options
+                .exclude
+                .iter()
+                .filter_map(|pattern| IgnoreRule::parse(Path::new(""), pattern))
+                .collect(),
+            hide_dotfiles: options.hide_dotfiles,
+        }
+    }
+
+    fn scan_tree(&self) -> io::Result<Vec<FileInfo>> {
+        let scope = WalkScope {
+            rules: self.exclude.clone(),
+            ancestors: vec![],
+        };
+        self.scan_dir(&self.base_path, &scope)
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.base_path).unwrap_or(path)
+    }
+
+    /// The scope of the entries of `dir`, `None` when it is a symlink to one of its ancestors
+    fn enter(&self, dir: &Path, scope: &WalkScope) -> Option<WalkScope> {
+        let mut scope = scope.clone();
+        if let Ok(canonical) = fs::canonicalize(dir) {
+            if scope.ancestors.contains(&canonical) {
+                log::warn!(
+                    "Not indexing {} again, it links to its ancestor",
+                    dir.display()
+                );
+                return None;
+            }
+            scope.ancestors.push(canonical);
+        }
+        if let Ok(rules) = fs::read_to_string(dir.join(IGNORE_FILE)) {
+            let relative = self.relative(dir);
+            scope.rules.extend(
+                rules
+                    .lines()
+                    .filter_map(|line| IgnoreRule::parse(relative, line)),
+            );
+        }
+        Some(scope)
+    }
+
+    fn is_ignored(&self, path: &Path, scope: &WalkScope) -> bool {
+        let relative = self.relative(path);
+        let name = path.file_name().unwrap_or_default();
+        name == IGNORE_FILE
+            || (self.hide_dotfiles && name.as_encoded_bytes().starts_with(b"."))
+            || scope.rules.iter().any(|rule| rule.matches(relative))
+    }
+
+    fn scan_dir(&self, path: &Path, scope: &WalkScope) -> io::Result<Vec<FileInfo>> {
+        let mut files_info = Vec::new();
+
+        if let Some(scope) = path.is_dir().then(|| self.enter(path, scope)).flatten() {
+            for entry in fs::read_dir(path)? {
+                let path = entry?.path();
+                if !self.is_ignored(&path, &scope) {
+                    files_info.push(self.scan_entry(&path, &scope));
+                }
+            }
+        }
+
+        Ok(files_info)
+    }
+
+    /// Flagged unreadable rather than failing when its metadata or content can't be read, e.g. a
+    /// dangling link or a directory of another user
+    fn scan_entry(&self, path: &Path, scope: &WalkScope) -> FileInfo {
+        let metadata = fs::metadata(path);
+        if let Err(e) = &metadata {
+            log::debug!("Failed to read the metadata of {}: {}", path.display(), e);
+        }
+        let is_dir = metadata.as_ref().is_ok_and(|m| m.is_dir());
+        let size = metadata
+            .as_ref()
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|m| m.len());
+        let modified_at = metadata
+            .as_ref()
+            .ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs() as i64);
+        let mut unreadable = metadata.is_err();
+
+        let name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+
+        let relative = self.relative(path);
+        let full_path = relative.to_string_lossy().into_owned();
 
-            let name = path
-                .file_name()
-                .unwrap_or_else(|| path.as_os_str())
-                .to_string_lossy()
-                .into_owned();
+        let depth = relative.components().count();
+        let children = if is_dir && self.max_depth.is_none_or(|max| depth < max) {
+            match self.scan_dir(path, scope) {
+                Ok(children) => Some(children),
+                Err(e) => {
+                    log::debug!("Failed to list {}: {}", path.display(), e);
+                    unreadable = true;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        FileInfo {
+            name,
+            full_path,
+            is_dir,
+            size,
+            modified_at,
+            unreadable,
+            children,
+        }
+    }
+
+    /// The scope of the entries of the directory `relative` to the base path
+    fn scope_of(&self, relative: &Path) -> Option<WalkScope> {
+        let mut scope = WalkScope {
+            rules: self.exclude.clone(),
+            ancestors: vec![],
+        };
+        let mut dir = self.base_path.clone();
+        scope = self.enter(&dir, &scope)?;
+        for component in relative.components() {
+            dir.push(component);
+            scope = self.enter(&dir, &scope)?;
+        }
+        Some(scope)
+    }
+
+    /// Replace (or remove) the node of `path` in the tree, rescanning its subtree
+    fn refresh_path(
+        &self,
+        watch_root: &Path,
+        tree: &mut Vec<FileInfo>,
+        path: &Path,
+    ) -> io::Result<()> {
+        let relative = match path.strip_prefix(watch_root) {
+            Ok(relative) => relative,
+            Err(_) => return Ok(()),
+        };
+        let components: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if components.is_empty() {
+            *tree = self.scan_tree()?;
+            return Ok(());
+        }
 
-            let full_path = path
-                .strip_prefix(base_path)
-                .unwrap_or(&path)
-                .to_string_lossy()
-                .into_owned();
+        // An ancestor which is not indexed yet gets indexed as a whole
+        let depth = indexed_depth(tree, &components[..components.len() - 1]);
+        let components = &components[..=depth];
+        let target: PathBuf = self.base_path.join(components.iter().collect::<PathBuf>());
+        let (name, parents) = components.split_last().unwrap();
+        // A directory linking to its ancestor
+        let Some(scope) = self.scope_of(&parents.iter().collect::<PathBuf>()) else {
+            return Ok(());
+        };
 
-            let children = if path.is_dir() {
-                Some(rec_scan_dir(base_path, &path)?)
+        let mut children = tree;
+        for component in parents {
+            match children
+                .iter_mut()
+                .find(|f| f.is_dir && &f.name == component)
+                .and_then(|f| f.children.as_mut())
+            {
+                Some(parent_children) => children = parent_children,
+                None => return Ok(()),
+            }
+        }
+
+        children.retain(|f| &f.name != name);
+        if fs::symlink_metadata(&target).is_ok() && !self.is_ignored(&target, &scope) {
+            children.push(self.scan_entry(&target, &scope));
+        }
+        Ok(())
+    }
+}
+
+fn indexed_depth(tree: &[FileInfo], parents: &[String]) -> usize {
+    let mut children = tree;
+    let mut depth = 0;
+    for component in parents {
+        match children
+            .iter()
+            .find(|f| f.is_dir && &f.name == component)
+            .and_then(|f| f.children.as_deref())
+        {
+            Some(parent_children) => {
+                children = parent_children;
+                depth += 1;
+            }
+            None => break,
+        }
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn count_entries(files: &[FileInfo]) -> (usize, usize) {
+        files.iter().fold((0, 0), |(file_count, dir_count), f| {
+            let (sub_files, sub_dirs) = f.children.as_deref().map(count_entries).unwrap_or((0, 0));
+            if f.is_dir {
+                (file_count + sub_files, dir_count + sub_dirs + 1)
             } else {
-                None
+                (file_count + 1, dir_count)
+            }
+        })
+    }
+
+    #[test]
+    fn test_refresh_path_adds_and_removes_entries() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base = temp_dir.path();
+        fs::write(base.join("test1.txt"), b"Test content 1")?;
+
+        let walker = Walker::new(base, &IndexOptions::default());
+        let mut tree = walker.scan_tree()?;
+        assert_eq!(count_entries(&tree), (1, 0));
+
+        // New file in a directory which is not indexed yet
+        fs::create_dir_all(base.join("sub/dir"))?;
+        fs::write(base.join("sub/dir/test2.txt"), b"Test content 2")?;
+        walker.refresh_path(base, &mut tree, &base.join("sub/dir/test2.txt"))?;
+        assert_eq!(count_entries(&tree), (2, 2));
+
+        fs::remove_file(base.join("test1.txt"))?;
+        walker.refresh_path(base, &mut tree, &base.join("test1.txt"))?;
+        assert_eq!(count_entries(&tree), (1, 2));
+        assert!(tree.iter().all(|f| f.name != "test1.txt"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh_path_ignores_paths_outside_root() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base = temp_dir.path().join("root");
+        fs::create_dir(&base)?;
+        fs::write(temp_dir.path().join("outside.txt"), b"Test content")?;
+
+        let walker = Walker::new(&base, &IndexOptions::default());
+        let mut tree = walker.scan_tree()?;
+        walker.refresh_path(&base, &mut tree, &temp_dir.path().join("outside.txt"))?;
+        assert!(tree.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_limits() -> io::Result<()> {
+        let temp_dir = tempdir()?;
+        let base = temp_dir.path();
+        fs::create_dir_all(base.join("app/node_modules/lib"))?;
+        fs::create_dir_all(base.join("app/build/out"))?;
+        fs::create_dir_all(base.join("deep/a/b/c"))?;
+        fs::write(base.join("app/main.rs"), b"fn main() {}")?;
+        fs::write(base.join("app/notes.tmp"), b"")?;
+        fs::write(base.join("app/node_modules/lib/index.js"), b"")?;
+        fs::write(base.join("app/build/out/app"), b"")?;
+        fs::write(
+            base.join("app/.hardwireignore"),
+            "# Generated\n/build/out\n*.tmp\n",
+        )?;
+        fs::write(base.join("deep/a/b/c/file.txt"), b"")?;
+        std::os::unix::fs::symlink(base.join("app"), base.join("app/build/up"))?;
+
+        let walker = Walker::new(
+            base,
+            &IndexOptions {
+                max_depth: Some(3),
+                exclude: vec!["node_modules".to_string()],
+                hide_dotfiles: false,
+            },
+        );
+        let mut tree = walker.scan_tree()?;
+        let mut entries = vec![];
+        flatten(&tree, &mut entries);
+        let mut paths: Vec<&str> = entries.iter().map(|e| e.full_path.as_str()).collect();
+        paths.sort();
+        // `deep/a/b` is listed without its content, `up` links back to `app`
+        assert_eq!(
+            paths,
+            [
+                "app",
+                "app/build",
+                "app/build/up",
+                "app/main.rs",
+                "deep",
+                "deep/a",
+                "deep/a/b"
+            ]
+        );
+
+        // Events in ignored directories are ignored as well
+        fs::write(base.join("app/node_modules/lib/other.js"), b"")?;
+        walker.refresh_path(base, &mut tree, &base.join("app/node_modules/lib/other.js"))?;
+        fs::write(base.join("app/build/out/other"), b"")?;
+        walker.refresh_path(base, &mut tree, &base.join("app/build/out/other"))?;
+        fs::write(base.join("app/lib.rs"), b"")?;
+        walker.refresh_path(base, &mut tree, &base.join("app/lib.rs"))?;
+        assert_eq!(count_entries(&tree), (2, 6));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_and_hidden_entries() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir()?;
+        let base = temp_dir.path();
+        fs::create_dir_all(base.join("locked/inside"))?;
+        fs::create_dir(base.join(".git"))?;
+        fs::write(base.join(".env"), b"SECRET=1")?;
+        fs::write(base.join("a.txt"), b"a")?;
+        std::os::unix::fs::symlink(base.join("missing"), base.join("dangling"))?;
+        fs::set_permissions(base.join("locked"), fs::Permissions::from_mode(0o000))?;
+        // Root reads it anyway
+        let locked = fs::read_dir(base.join("locked")).is_err();
+
+        let options = IndexOptions {
+            hide_dotfiles: true,
+            ..Default::default()
+        };
+        let tree = Walker::new(base, &options).scan_tree();
+        fs::set_permissions(base.join("locked"), fs::Permissions::from_mode(0o755))?;
+        let mut entries = vec![];
+        flatten(&tree?, &mut entries);
+        entries.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+        let paths: Vec<&str> = entries.iter().map(|e| e.full_path.as_str()).collect();
+        if locked {
+            assert_eq!(paths, ["a.txt", "dangling", "locked"]);
+            assert!(entries[2].unreadable);
+        } else {
+            assert_eq!(paths, ["a.txt", "dangling", "locked", "locked/inside"]);
+        }
+        assert!(!entries[0].unreadable);
+        assert!(entries[0].modified_at.is_some());
+        assert!(entries[1].unreadable);
+        assert_eq!(entries[1].modified_at, None);
+
+        let tree = Walker::new(base, &IndexOptions::default()).scan_tree()?;
+        assert_eq!(count_entries(&tree), (3, 3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_list_dir() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        fs::create_dir_all(temp_dir.path().join("photos/2024/summer"))?;
+        fs::write(temp_dir.path().join("photos/b.jpg"), b"bb")?;
+        fs::write(temp_dir.path().join("photos/a.jpg"), b"a")?;
+        fs::write(temp_dir.path().join("notes.txt"), b"n")?;
+
+        let options = IndexOptions {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let indexer = FileIndexer::new(temp_dir.path(), 60, options, Chaos::default());
+        indexer.rescan()?.await?.map_err(anyhow::Error::msg)?;
+
+        let root = indexer.list_dir(&DirQuery::default()).unwrap();
+        let names: Vec<&str> = root.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["photos", "notes.txt"]);
+        assert_eq!(root.entries[0].child_count, Some(3));
+        assert_eq!(root.entries[1].child_count, None);
+
+        let photos = indexer
+            .list_dir(&DirQuery {
+                path: "photos/".to_string(),
+                limit: Some(2),
+                offset: 1,
+            })
+            .unwrap();
+        assert_eq!(photos.path, "photos");
+        assert_eq!(photos.total, 3);
+        let paths: Vec<&str> = photos
+            .entries
+            .iter()
+            .map(|e| e.full_path.as_str())
+            .collect();
+        assert_eq!(paths, ["photos/a.jpg", "photos/b.jpg"]);
+
+        // Beyond the maximum depth, a file, outside of the base path
+        for path in ["photos/2024", "notes.txt", "..", "missing"] {
+            let query = DirQuery {
+                path: path.to_string(),
+                ..Default::default()
             };
+            assert!(indexer.list_dir(&query).is_none(), "{}", path);
+        }
+        Ok(())
+    }
 
-            files_info.push(FileInfo {
-                name,
-                full_path,
-                is_dir: path.is_dir(),
-                size,
-                children,
-            });
+    #[tokio::test]
+    async fn test_restart_after_panic() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        fs::write(temp_dir.path().join("a.txt"), b"a")?;
+        let chaos = Chaos::new(&[(Fault::IndexerPanic, 1.0)]);
+        let indexer = FileIndexer::new(temp_dir.path(), 60, IndexOptions::default(), chaos);
+        for _ in 0..100 {
+            if indexer.status().restarts > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        let status = indexer.status();
+        assert_eq!(status.restarts, 1);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("Indexer panicked: Injected indexer panic")
+        );
+        assert!(indexer.files.is_poisoned());
+        assert!(indexer.is_running());
+        // Still served from the poisoned lock
+        assert_eq!(indexer.tree().map(|tree| tree.len()), Some(0));
+        assert!(indexer.list_dir(&DirQuery::default()).is_some());
+        Ok(())
     }
 
-    Ok(files_info)
+    #[tokio::test]
+    async fn test_rescan_reports_counts() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        fs::create_dir(temp_dir.path().join("sub"))?;
+        fs::write(temp_dir.path().join("sub/test1.txt"), b"Test content 1")?;
+        fs::write(temp_dir.path().join("test2.txt"), b"Test content 2")?;
+
+        let indexer = FileIndexer::new(
+            temp_dir.path(),
+            60,
+            IndexOptions::default(),
+            Chaos::default(),
+        );
+        let report = indexer.rescan()?.await?.map_err(anyhow::Error::msg)?;
+        assert_eq!(report.file_count, 2);
+        assert_eq!(report.dir_count, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_events_batch() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let indexer = FileIndexer::new(
+            temp_dir.path(),
+            60,
+            IndexOptions::default(),
+            Chaos::default(),
+        );
+        indexer.rescan()?.await?.map_err(anyhow::Error::msg)?;
+
+        let watch_root = fs::canonicalize(temp_dir.path())?;
+        fs::create_dir(watch_root.join("photos"))?;
+        fs::write(watch_root.join("photos/a.jpg"), b"aa")?;
+        fs::write(watch_root.join("b.txt"), b"b")?;
+        let created = |path: &str| {
+            Event::new(EventKind::Create(notify::event::CreateKind::Any))
+                .add_path(watch_root.join(path))
+        };
+        let events = [
+            created("photos"),
+            created("photos/a.jpg"),
+            created("b.txt"),
+            Event::new(EventKind::Modify(notify::event::ModifyKind::Any))
+                .add_path(watch_root.join("b.txt")),
+        ];
+        indexer.apply_events(&watch_root, &events);
+
+        let status = indexer.status();
+        assert_eq!((status.file_count, status.dir_count), (2, 1));
+        let results = indexer.search(&SearchQuery {
+            q: Some("a.jpg".to_string()),
+            ..Default::default()
+        })?;
+        assert_eq!(results.entries[0].full_path, "photos/a.jpg");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_search() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        fs::create_dir(temp_dir.path().join("photos"))?;
+        fs::write(temp_dir.path().join("photos/a.jpg"), b"aa")?;
+        fs::write(temp_dir.path().join("photos/B.JPG"), b"bbbb")?;
+        fs::write(temp_dir.path().join("notes.txt"), b"n")?;
+
+        let indexer = FileIndexer::new(
+            temp_dir.path(),
+            60,
+            IndexOptions::default(),
+            Chaos::default(),
+        );
+        indexer.rescan()?.await?.map_err(anyhow::Error::msg)?;
+
+        let results = indexer.search(&SearchQuery {
+            q: Some("*.jpg".to_string()),
+            sort: SortKey::Size,
+            order: SortOrder::Desc,
+            ..Default::default()
+        })?;
+        assert_eq!(results.total, 2);
+        assert_eq!(results.entries[0].name, "B.JPG");
+
+        let results = indexer.search(&SearchQuery {
+            q: Some("photos/*".to_string()),
+            limit: Some(1),
+            offset: 1,
+            ..Default::default()
+        })?;
+        assert_eq!(results.total, 2);
+        assert_eq!(results.entries.len(), 1);
+        assert_eq!(results.entries[0].name, "B.JPG");
+
+        let results = indexer.search(&SearchQuery {
+            q: Some("o".to_string()),
+            kind: Some(EntryKind::Dir),
+            ..Default::default()
+        })?;
+        assert_eq!(results.total, 1);
+        assert_eq!(results.entries[0].full_path, "photos");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,97 @@
+//! Links to the files of a share.
+//!
+//! Each file of a share is served at `/s/<share_id>/<token>`, the token being random and stored in
+//! `share_link_files.token`, so the links to the files of one share say nothing of those of
+//! another. Files shared before tokens were served at their sequential `files.id`:
+//! [`redirect_legacy_links`] keeps those links working by redirecting them to the token, new
+//! shares have no such links.
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Redirect, Response};
+use sqlx::SqlitePool;
+
+use crate::proxy::Client;
+use crate::App;
+
+/// Share id, file id and what follows it of a `/s/<share_id>/<file_id>/...` path
+fn legacy_link(path: &str) -> Option<(&str, u32, &str)> {
+    let (share_id, rest) = path.strip_prefix("/s/")?.split_once('/')?;
+    let (file_id, tail) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    // Tokens are 16 characters, too long for an id even when they only have digits
+    let file_id = file_id.parse().ok()?;
+    Some((share_id, file_id, tail))
+}
+
+/// Token replacing the id link of a file shared before tokens
+async fn legacy_token(
+    db: &SqlitePool,
+    share_id: &str,
+    file_id: u32,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT token AS "token!" FROM share_link_files
+        WHERE share_link_id = ? AND file_id = ? AND legacy_id_link"#,
+        share_id,
+        file_id
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Permanently redirect `/s/<share_id>/<file_id>/...` to `/s/<share_id>/<token>/...`. Other
+/// requests, including ids of files shared since, go on to the share routes.
+pub async fn redirect_legacy_links(
+    State(app_state): State<App>,
+    client: Client,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((share_id, file_id, tail)) = legacy_link(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let token = match legacy_token(&app_state.db_reader, share_id, file_id).await {
+        Ok(Some(token)) => token,
+        Ok(None) => return next.run(request).await,
+        Err(e) => {
+            tracing::error!("Failed to find the token of file {}: {}", file_id, e);
+            return next.run(request).await;
+        }
+    };
+    let mut location = format!(
+        "{}/s/{}/{}{}",
+        client.host(&app_state.config.host),
+        share_id,
+        token,
+        tail
+    );
+    if let Some(query) = request.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Redirect::permanent(&location).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_link() {
+        assert_eq!(
+            legacy_link("/s/V1StGXR8_Z/3/inline"),
+            Some(("V1StGXR8_Z", 3, "/inline"))
+        );
+        assert_eq!(
+            legacy_link("/s/V1StGXR8_Z/42"),
+            Some(("V1StGXR8_Z", 42, ""))
+        );
+        assert_eq!(legacy_link("/s/V1StGXR8_Z/9f86d081884c7d65"), None);
+        assert_eq!(legacy_link("/s/V1StGXR8_Z/1234567890123456"), None);
+        assert_eq!(legacy_link("/s/V1StGXR8_Z/qr.png"), None);
+        assert_eq!(legacy_link("/s/V1StGXR8_Z"), None);
+    }
+}
@@ -0,0 +1,205 @@
+//! Rewriting of the file names served to clients.
+//!
+//! A share can carry rules turning internal names such as `projX_final_v7_FINAL2.mp4` into the
+//! name the client sees, in the share page, the `Content-Disposition` of downloads and the
+//! entries of the archives created with rules. Rules apply in order to the file name only, the
+//! extension is kept aside for the prefix and suffix rules.
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::validation::Validator;
+
+pub const MAX_RULES: usize = 20;
+const MAX_RULE_TEXT_LEN: usize = 255;
+/// Replaced by the current UTC date, `2026-10-15`, in the added prefixes and suffixes
+const DATE_PLACEHOLDER: &str = "{date}";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum FilenameRule {
+    /// Remove `prefix` from the start of the name
+    StripPrefix { prefix: String },
+    /// Replace every occurrence of `from` by `to`
+    Replace { from: String, to: String },
+    /// Add `prefix` before the name, e.g. the client name
+    AddPrefix { prefix: String },
+    /// Add `suffix` before the extension, e.g. `_{date}`
+    AddSuffix { suffix: String },
+}
+
+impl FilenameRule {
+    fn texts(&self) -> Vec<(&'static str, &str)> {
+        match self {
+            FilenameRule::StripPrefix { prefix } => vec![("prefix", prefix)],
+            FilenameRule::Replace { from, to } => vec![("from", from), ("to", to)],
+            FilenameRule::AddPrefix { prefix } => vec![("prefix", prefix)],
+            FilenameRule::AddSuffix { suffix } => vec![("suffix", suffix)],
+        }
+    }
+}
+
+/// Check `rules` given in the `field` of a payload
+pub fn validate(v: &mut Validator, field: &str, rules: &[FilenameRule]) {
+    v.check(
+        rules.len() <= MAX_RULES,
+        field,
+        "must not contain more than 20 rules",
+    );
+    for (i, rule) in rules.iter().enumerate() {
+        for (name, text) in rule.texts() {
+            let field = format!("{}[{}].{}", field, i, name);
+            if text.len() > MAX_RULE_TEXT_LEN {
+                v.error(field, "must not be longer than 255 characters");
+            } else if text.is_empty() && name != "to" {
+                v.error(field, "must not be empty");
+            } else if text.contains(['/', '\\']) || text.chars().any(char::is_control) {
+                v.error(
+                    field,
+                    "must not contain path separators or control characters",
+                );
+            }
+        }
+    }
+}
+
+/// `name` rewritten by `rules`, the original name when they would leave it empty
+pub fn rewrite(name: &str, rules: &[FilenameRule], today: NaiveDate) -> String {
+    let date = today.format("%Y-%m-%d").to_string();
+    let extension = name
+        .rfind('.')
+        .filter(|&dot| dot > 0)
+        .map_or("", |dot| &name[dot..]);
+    let mut rewritten = name.to_string();
+    for rule in rules {
+        rewritten = match rule {
+            FilenameRule::StripPrefix { prefix } => rewritten
+                .strip_prefix(prefix.as_str())
+                .map_or(rewritten.clone(), str::to_string),
+            FilenameRule::Replace { from, to } => rewritten.replace(from.as_str(), to),
+            FilenameRule::AddPrefix { prefix } => {
+                format!("{}{}", prefix.replace(DATE_PLACEHOLDER, &date), rewritten)
+            }
+            FilenameRule::AddSuffix { suffix } => {
+                let suffix = suffix.replace(DATE_PLACEHOLDER, &date);
+                match rewritten
+                    .strip_suffix(extension)
+                    .filter(|_| !extension.is_empty())
+                {
+                    Some(stem) => format!("{}{}{}", stem, suffix, extension),
+                    None => format!("{}{}", rewritten, suffix),
+                }
+            }
+        };
+    }
+    let rewritten = rewritten.trim();
+    if rewritten.is_empty() || rewritten == "." || rewritten == ".." {
+        name.to_string()
+    } else {
+        rewritten.to_string()
+    }
+}
+
+/// `Content-Disposition` value making the client save the download as `name`
+pub fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| {
+            if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String =
+        name.bytes()
+            .map(|b| match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (b as char).to_string(),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Replace the rules of a share, `false` when it does not exist
+pub async fn save(db: &SqlitePool, share_id: &str, rules: &[FilenameRule]) -> Result<bool> {
+    let rules = serde_json::to_string(rules)?;
+    let result = sqlx::query!(
+        "UPDATE share_links SET filename_rules = ? WHERE id = ?",
+        rules,
+        share_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(value: serde_json::Value) -> Vec<FilenameRule> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 15).unwrap();
+        let rules = rules(serde_json::json!([
+            { "rule": "strip_prefix", "prefix": "projX_" },
+            { "rule": "replace", "from": "_v7_FINAL2", "to": "" },
+            { "rule": "add_prefix", "prefix": "ACME " },
+            { "rule": "add_suffix", "suffix": " {date}" }
+        ]));
+        assert_eq!(
+            rewrite("projX_final_v7_FINAL2.mp4", &rules, today),
+            "ACME final 2026-10-15.mp4"
+        );
+        assert_eq!(rewrite(".bashrc", &rules, today), "ACME .bashrc 2026-10-15");
+
+        let erase = vec![FilenameRule::Replace {
+            from: "notes.txt".to_string(),
+            to: String::new(),
+        }];
+        assert_eq!(rewrite("notes.txt", &erase, today), "notes.txt");
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut v = Validator::default();
+        validate(
+            &mut v,
+            "filename_rules",
+            &rules(serde_json::json!([
+                { "rule": "strip_prefix", "prefix": "" },
+                { "rule": "replace", "from": "_", "to": "" },
+                { "rule": "add_suffix", "suffix": "/../x" }
+            ])),
+        );
+        let Err(crate::error::AppError::Validation(errors)) = v.finish() else {
+            panic!("Expected validation errors");
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec!["filename_rules[0].prefix", "filename_rules[2].suffix"]
+        );
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("été \"2026\".mp4"),
+            "attachment; filename=\"_t_ _2026_.mp4\"; filename*=UTF-8''%C3%A9t%C3%A9%20%222026%22.mp4"
+        );
+    }
+}
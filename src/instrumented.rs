@@ -0,0 +1,192 @@
+//! Byte counting wrapper for readers and writers.
+//!
+//! [`InstrumentedStream`] wraps a sync or async reader, or a writer, and reports the bytes going
+//! through it to a [`ByteSink`]: progress events on a broadcast channel, shared counters or logs.
+//! Sinks can be combined with a tuple, `(CounterSink, LogSink)` reports to both.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Receives the byte counts of an [`InstrumentedStream`]
+pub trait ByteSink {
+    /// `read` bytes were just read, `total_read` since the stream was created
+    fn on_bytes(&mut self, read: u64, total_read: u64);
+
+    /// The inner reader reached its end
+    fn on_eof(&mut self, _total_read: u64) {}
+
+    fn on_error(&mut self, _err: &io::Error) {}
+}
+
+impl<A: ByteSink, B: ByteSink> ByteSink for (A, B) {
+    fn on_bytes(&mut self, read: u64, total_read: u64) {
+        self.0.on_bytes(read, total_read);
+        self.1.on_bytes(read, total_read);
+    }
+
+    fn on_eof(&mut self, total_read: u64) {
+        self.0.on_eof(total_read);
+        self.1.on_eof(total_read);
+    }
+
+    fn on_error(&mut self, err: &io::Error) {
+        self.0.on_error(err);
+        self.1.on_error(err);
+    }
+}
+
+/// Adds the bytes read to a counter shared with other threads
+#[derive(Clone, Debug, Default)]
+pub struct CounterSink(pub Arc<AtomicU64>);
+
+impl ByteSink for CounterSink {
+    fn on_bytes(&mut self, read: u64, _total_read: u64) {
+        self.0.fetch_add(read, Ordering::Relaxed);
+    }
+}
+
+/// Logs the outcome of the stream
+#[derive(Clone, Debug)]
+pub struct LogSink {
+    pub label: String,
+}
+
+impl ByteSink for LogSink {
+    fn on_bytes(&mut self, _read: u64, _total_read: u64) {}
+
+    fn on_eof(&mut self, total_read: u64) {
+        tracing::debug!("{}: {} bytes read", self.label, total_read);
+    }
+
+    fn on_error(&mut self, err: &io::Error) {
+        tracing::warn!("{}: read error: {}", self.label, err);
+    }
+}
+
+pub struct InstrumentedStream<R, S> {
+    inner: R,
+    sink: S,
+    read_bytes: u64,
+}
+
+impl<R, S: ByteSink> InstrumentedStream<R, S> {
+    pub fn new(inner: R, sink: S) -> Self {
+        Self {
+            inner,
+            sink,
+            read_bytes: 0,
+        }
+    }
+
+    fn record(&mut self, result: &io::Result<usize>) {
+        match result {
+            Ok(0) => self.sink.on_eof(self.read_bytes),
+            Ok(n) => {
+                self.read_bytes += *n as u64;
+                self.sink.on_bytes(*n as u64, self.read_bytes);
+            }
+            Err(e) => self.sink.on_error(e),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin, S: ByteSink + Unpin> AsyncRead for InstrumentedStream<R, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let read_poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(result) = &read_poll {
+            let result = match result {
+                Ok(()) => Ok(buf.filled().len() - filled_before),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            self.record(&result);
+        }
+        read_poll
+    }
+}
+
+impl<R: Read, S: ByteSink> Read for InstrumentedStream<R, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.record(&result);
+        result
+    }
+}
+
+impl<W: Write, S: ByteSink> Write for InstrumentedStream<W, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        match &result {
+            Ok(0) => {}
+            result => self.record(result),
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Seek, S> Seek for InstrumentedStream<T, S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        chunks: Vec<(u64, u64)>,
+        eof: Option<u64>,
+    }
+
+    impl ByteSink for &mut RecordingSink {
+        fn on_bytes(&mut self, read: u64, total_read: u64) {
+            self.chunks.push((read, total_read));
+        }
+
+        fn on_eof(&mut self, total_read: u64) {
+            self.eof = Some(total_read);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_stream_counts_bytes() -> io::Result<()> {
+        let mut sink = RecordingSink::default();
+        let mut stream = InstrumentedStream::new(&b"Test content 1"[..], &mut sink);
+        let mut buf = [0u8; 10];
+        AsyncReadExt::read_exact(&mut stream, &mut buf).await?;
+        let mut rest = vec![];
+        AsyncReadExt::read_to_end(&mut stream, &mut rest).await?;
+
+        assert_eq!(sink.chunks, vec![(10, 10), (4, 14)]);
+        assert_eq!(sink.eof, Some(14));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_stream_fans_out_to_sinks() -> io::Result<()> {
+        let counter = CounterSink::default();
+        let mut sink = RecordingSink::default();
+        let mut stream =
+            InstrumentedStream::new(&b"Test content 2"[..], (counter.clone(), &mut sink));
+        io::copy(&mut stream, &mut io::sink())?;
+
+        assert_eq!(counter.0.load(Ordering::Relaxed), 14);
+        assert_eq!(sink.eof, Some(14));
+        Ok(())
+    }
+}
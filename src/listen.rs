@@ -0,0 +1,347 @@
+//! Sockets the server accepts connections on.
+//!
+//! `HARDWIRE_LISTEN` takes comma-separated `tcp://0.0.0.0:8090`, `unix:///run/hardwire.sock` for a
+//! reverse proxy on the same host, or `fd://` for the sockets passed by systemd socket activation
+//! (`sd_listen_fds`). Without it the server listens on `HARDWIRE_PORT` of each address of
+//! `HARDWIRE_BIND_ADDR`. Unix sockets are created with `HARDWIRE_SOCKET_MODE`, `660` by default so
+//! that a proxy in the group of the server can connect.
+
+use anyhow::{Context, Result};
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_util::either::Either;
+
+/// First file descriptor passed by systemd, after stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Sockets inherited from systemd
+    Fd,
+}
+
+impl FromStr for ListenAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            let address = address
+                .parse::<SocketAddr>()
+                .with_context(|| format!("Invalid TCP address {}", address))?;
+            Ok(ListenAddress::Tcp(address))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            if !path.starts_with('/') {
+                anyhow::bail!("Unix socket path {} must be absolute", path);
+            }
+            Ok(ListenAddress::Unix(PathBuf::from(path)))
+        } else if s == "fd://" {
+            Ok(ListenAddress::Fd)
+        } else {
+            anyhow::bail!("{} is neither tcp://, unix:// nor fd://", s)
+        }
+    }
+}
+
+/// IP address to bind, IPv6 ones possibly in brackets as in URLs
+pub fn parse_ip(s: &str) -> Result<IpAddr> {
+    let ip = s.trim();
+    let ip = ip
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(ip);
+    ip.parse()
+        .with_context(|| format!("Invalid IP address {}", s))
+}
+
+/// Addresses which can all be bound together
+pub fn validate(addresses: &[ListenAddress]) -> Result<()> {
+    if addresses.is_empty() {
+        anyhow::bail!("No address to listen on");
+    }
+    for (i, address) in addresses.iter().enumerate() {
+        for other in &addresses[i + 1..] {
+            match (address, other) {
+                (a, b) if a == b => anyhow::bail!("{} is listed twice", a),
+                // Linux binds both IPv6 and IPv4 on [::] unless net.ipv6.bindv6only is set
+                (ListenAddress::Tcp(a), ListenAddress::Tcp(b))
+                    if a.port() == b.port()
+                        && a.is_ipv6() != b.is_ipv6()
+                        && (a.ip() == IpAddr::from([0u16; 8])
+                            || b.ip() == IpAddr::from([0u16; 8])) =>
+                {
+                    anyhow::bail!(
+                        "{} and {} can't both be bound, [::] also accepts IPv4 connections",
+                        a,
+                        b
+                    )
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(address) => write!(f, "tcp://{}", address),
+            ListenAddress::Unix(path) => write!(f, "unix://{}", path.display()),
+            ListenAddress::Fd => write!(f, "fd://"),
+        }
+    }
+}
+
+/// Address of a connected client
+#[derive(Debug, Clone, Copy)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Client on the same host, a reverse proxy most likely
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => addr.fmt(f),
+            PeerAddr::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+pub type Stream = Either<TcpStream, UnixStream>;
+
+/// A bound socket
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Socket {
+    async fn bind(address: &ListenAddress, socket_mode: u32) -> Result<Vec<Self>> {
+        match address {
+            ListenAddress::Tcp(address) => Ok(vec![Socket::Tcp(
+                TcpListener::bind(address)
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", address))?,
+            )]),
+            ListenAddress::Unix(path) => {
+                // Left behind by a previous run, connecting to it would fail
+                if std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen on {}", path.display()))?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(socket_mode))?;
+                Ok(vec![Socket::Unix(listener)])
+            }
+            ListenAddress::Fd => Self::from_systemd(),
+        }
+    }
+
+    /// Sockets of `LISTEN_FDS`, when `LISTEN_PID` is the server
+    fn from_systemd() -> Result<Vec<Self>> {
+        let for_us =
+            std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+        let fds = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|fds| fds.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        if !for_us || fds == 0 {
+            anyhow::bail!("No socket passed by systemd, LISTEN_PID or LISTEN_FDS is missing");
+        }
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+            .map(|fd| {
+                let family = getsockname::<SockaddrStorage>(fd)
+                    .with_context(|| format!("File descriptor {} is not a socket", fd))?
+                    .family();
+                match family {
+                    Some(AddressFamily::Unix) => {
+                        // Safety: systemd passes the sockets from file descriptor 3, only taken
+                        // here once
+                        let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                        listener.set_nonblocking(true)?;
+                        Ok(Socket::Unix(UnixListener::from_std(listener)?))
+                    }
+                    _ => {
+                        // Safety: as above
+                        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                        listener.set_nonblocking(true)?;
+                        Ok(Socket::Tcp(TcpListener::from_std(listener)?))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn local_addr(&self) -> io::Result<PeerAddr> {
+        match self {
+            Socket::Tcp(listener) => listener.local_addr().map(PeerAddr::Tcp),
+            Socket::Unix(_) => Ok(PeerAddr::Unix),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<(Stream, PeerAddr)> {
+        match self {
+            Socket::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Either::Left(stream), PeerAddr::Tcp(addr)))
+            }
+            Socket::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Either::Right(stream), PeerAddr::Unix))
+            }
+        }
+    }
+}
+
+/// Connections of all the sockets of the server
+pub struct Listener {
+    local_addr: PeerAddr,
+    connections: mpsc::Receiver<(Stream, PeerAddr)>,
+}
+
+impl Listener {
+    pub async fn bind(addresses: &[ListenAddress], socket_mode: u32) -> Result<Self> {
+        let mut sockets = vec![];
+        for address in addresses {
+            sockets.extend(Socket::bind(address, socket_mode).await?);
+        }
+        let local_addr = sockets
+            .first()
+            .context("No address to listen on")?
+            .local_addr()?;
+        let (sender, connections) = mpsc::channel(64);
+        for socket in sockets {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                // Stops with the first connection accepted once the listener is dropped
+                while !sender.is_closed() {
+                    match socket.accept().await {
+                        Ok(connection) => {
+                            let _ = sender.send(connection).await;
+                        }
+                        Err(e) => {
+                            // Out of file descriptors most likely, let connections close
+                            tracing::error!("Failed to accept a connection: {}", e);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            });
+        }
+        Ok(Listener {
+            local_addr,
+            connections,
+        })
+    }
+}
+
+impl axum::serve::Listener for Listener {
+    type Io = Stream;
+    type Addr = PeerAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // The accepting tasks only stop once the receiver is dropped
+        self.connections.recv().await.expect("Accept loops stopped")
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Connected<IncomingStream<'_, Listener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, Listener>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "tcp://[::]:8090".parse::<ListenAddress>().unwrap(),
+            ListenAddress::Tcp("[::]:8090".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:///run/hardwire.sock"
+                .parse::<ListenAddress>()
+                .unwrap(),
+            ListenAddress::Unix(PathBuf::from("/run/hardwire.sock"))
+        );
+        assert_eq!("fd://".parse::<ListenAddress>().unwrap(), ListenAddress::Fd);
+        assert!("tcp://localhost".parse::<ListenAddress>().is_err());
+        assert!("unix://hardwire.sock".parse::<ListenAddress>().is_err());
+        assert!("0.0.0.0:8090".parse::<ListenAddress>().is_err());
+
+        assert_eq!(
+            parse_ip("[::1]").unwrap(),
+            IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1u16])
+        );
+        assert_eq!(
+            parse_ip(" 127.0.0.1").unwrap(),
+            IpAddr::from([127, 0, 0, 1])
+        );
+        assert!(parse_ip("localhost").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let addresses = |list: &[&str]| -> Vec<ListenAddress> {
+            list.iter()
+                .map(|address| address.parse().unwrap())
+                .collect()
+        };
+        assert!(validate(&addresses(&["tcp://127.0.0.1:8090", "tcp://[::1]:8090"])).is_ok());
+        assert!(validate(&addresses(&["tcp://0.0.0.0:8090", "tcp://[::]:8091"])).is_ok());
+        assert!(validate(&addresses(&["tcp://0.0.0.0:8090", "tcp://[::]:8090"])).is_err());
+        assert!(validate(&addresses(&["tcp://[::]:8090", "tcp://10.0.0.1:8090"])).is_err());
+        assert!(validate(&addresses(&["fd://", "fd://"])).is_err());
+        assert!(validate(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("hardwire.sock");
+        let address = ListenAddress::Unix(path.clone());
+        // A stale socket is replaced
+        drop(Socket::bind(&address, 0o660).await?);
+        let listener = Listener::bind(&[address], 0o660).await?;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o660
+        );
+
+        let app = axum::Router::new().route("/healthcheck", axum::routing::get(|| async { "OK" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let mut stream = UnixStream::connect(&path).await?;
+        stream
+            .write_all(b"GET /healthcheck HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        Ok(())
+    }
+}
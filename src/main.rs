@@ -1,34 +1,35 @@
-use axum::extract::ws::WebSocket;
-
-use axum::http::header::{ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use axum::http::header::{
+    ACCEPT, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE, REFERER, USER_AGENT,
+    X_CONTENT_TYPE_OPTIONS,
+};
 use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::{Html, IntoResponse, Response};
-use axum::Json;
 
 use url::Url;
 
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use file_indexer::FileInfo;
-use http::request::Parts as RequestParts;
 
 // use qbittorrent::{data::Torrent, traits::TorrentData, Api};
 use tokio::sync::broadcast;
 use tokio_util::codec::{BytesCodec, FramedRead};
-use tower_http::services::ServeDir;
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use tower_http::services::ServeFile;
 use tracing::instrument;
 
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, Parser, Subcommand};
 
 use sqlx::{Pool, Sqlite, SqlitePool};
 
-use tower_http::cors::{AllowOrigin, CorsLayer};
-
 use std::fs::File;
+use std::future::IntoFuture;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow,Result};
+use anyhow::{anyhow, Context, Result};
 use std::env;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
 use askama::Template;
@@ -38,16 +39,78 @@ extern crate chrono;
 
 type Db = sqlx::SqlitePool;
 
-use axum::routing::{get, head, post};
-use axum::extract::{ConnectInfo, Path, State, WebSocketUpgrade};
-
-
+use axum::extract::{Path, Query, Request, State};
+use axum::middleware;
+use axum::routing::{get, head};
+use axum::Json;
+use tower::Layer;
+
+mod admin;
+mod api_keys;
+mod api_version;
+mod assets;
+mod audit;
+mod bandwidth;
+mod chaos;
+mod cors;
+mod db_pool;
+mod db_schema;
+mod disk_space;
+mod download_limits;
+mod download_script;
+mod e2ee;
+mod error;
+mod file_args;
+mod file_dedup;
 mod file_indexer;
+mod file_tokens;
+mod filename_rules;
+mod instrumented;
+mod listen;
+mod maintenance;
+mod namespaces;
+mod notifications;
+mod observability;
+mod openapi;
+mod outgoing_webhooks;
+mod plugins;
+mod probes;
 mod progress;
+mod proxy;
+mod public_api;
+mod publish_plan;
+mod remote;
+mod settings;
+mod share_alias;
+mod share_branding;
+mod share_bundle;
+mod share_burn;
+mod share_cache;
+mod share_page;
+mod share_password;
+mod share_profiles;
+mod share_readme;
+mod share_roots;
+mod share_stats;
+mod share_views;
+mod share_zip;
+mod shares_file;
+mod siem;
+mod text_viewer;
+mod throttle;
+mod thumbnails;
+mod tls;
+mod top;
+mod tus;
+mod validation;
+mod virus_scan;
+mod webhook;
 mod worker;
-use progress::ProgressReader;
+use error::{AppError, AppResult};
+use instrumented::{InstrumentedStream, LogSink};
+use progress::{DownloadProgressSink, FileDownload};
 use tracing_opentelemetry_instrumentation_sdk::find_current_trace_id;
-use worker::{Task, TaskInput, TaskManager, tasks::TaskWorker};
+use worker::{tasks::TaskWorker, TaskManager};
 
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
@@ -56,83 +119,227 @@ struct Cli {
     #[arg(short, long)]
     server: bool,
 
-    /// Files to publish
+    /// Files, directories or quoted globs to publish
     #[arg(short, long, num_args=1.., value_names = ["LIST OF FILES"])]
     files: Vec<String>,
-}
 
-// Make our own error that wraps `anyhow::Error`.
-struct AppError(anyhow::Error);
+    /// Encrypt the files before publishing them, the key is only part of the printed link
+    #[arg(short, long, requires = "files")]
+    encrypt: bool,
 
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
-    }
+    /// Addresses the server listens on, HARDWIRE_BIND_ADDR or all interfaces by default
+    #[arg(long, value_name = "ADDRESS", value_delimiter = ',', value_parser = listen::parse_ip, requires = "server")]
+    bind: Vec<IpAddr>,
+
+    /// Use the database as it is, without applying the pending migrations
+    #[arg(long)]
+    no_migrate: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Live view of a running server: downloads, tasks, recent errors and disk usage
+    Top {
+        /// Server to watch, HARDWIRE_HOST by default
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Key for the admin API, when it requires one
+        #[arg(long, env = "HARDWIRE_API_KEY", hide_env_values = true)]
+        api_key: Option<String>,
+
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        interval: u64,
+    },
+    /// Publish files and print the link of the share
+    Publish {
+        /// Files, directories or quoted globs such as "photos/**/*.jpg" to publish, paths on the
+        /// server with --remote
+        #[arg(required = true, value_names = ["FILES"])]
+        files: Vec<String>,
+
+        /// Only publish the files directly in the given directories
+        #[arg(long, conflicts_with = "remote")]
+        no_recursive: bool,
+
+        /// Fail when a path is missing or a glob or directory gives no file, instead of skipping it
+        #[arg(long, conflicts_with = "remote")]
+        strict: bool,
+
+        /// Encrypt the files before publishing them, the key is only part of the printed link
+        #[arg(short, long, conflicts_with = "remote")]
+        encrypt: bool,
+
+        /// Alias the share is also served at, e.g. `vacation-2024` for `/s/vacation-2024`
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Profile of `profiles.toml` setting the expiration, password and rate of the share
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// Password asked before serving the share
+        #[arg(long, env = "HARDWIRE_SHARE_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+
+        /// Serve the share to its first visitor only, until they downloaded every file
+        #[arg(long)]
+        burn_after_reading: bool,
+
+        /// Publish through the admin API of the server at this URL instead of its database
+        #[arg(long)]
+        remote: Option<String>,
+
+        /// Key for the admin API, when it requires one
+        #[arg(long, env = "HARDWIRE_API_KEY", hide_env_values = true)]
+        api_key: Option<String>,
+
+        /// List the files which would be published with their size, without creating the share
+        #[arg(long, conflicts_with = "remote")]
+        dry_run: bool,
+
+        /// Print the share, or the files of --dry-run, as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Write every share with its settings and file metadata to a JSON bundle
+    Export {
+        /// File the bundle is written to, standard output by default
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Create the shares of a bundle written by `export`, their files must be in the share roots
+    Import {
+        /// Bundle to import
+        file: PathBuf,
+    },
 }
 
 /// App holds the state of the application
 #[derive(Clone, Debug)]
 struct App {
+    /// Single connection pool, every write goes through it
     db_pool: Pool<Sqlite>,
+    /// Read-only connections for listings and lookups
+    db_reader: Pool<Sqlite>,
     progress_channel_sender: broadcast::Sender<progress::Event>,
+    /// Downloads and tasks in progress, kept by the [`progress::Manager`]
+    active_progress: progress::ActiveProgress,
     task_manager: Arc<TaskManager>,
     indexer: file_indexer::FileIndexer,
+    share_cache: share_cache::ShareCache,
+    /// Faults injected in the downloads, see [`chaos`]
+    chaos: chaos::Chaos,
+    load: bandwidth::ServerLoad,
+    /// Concurrent downloads of a client and of a file, see [`download_limits`]
+    limits: download_limits::DownloadLimiter,
+    /// Bytes read at once by the unthrottled downloads
+    download_buffer: usize,
+    /// Extension hooks, shared with the task manager
+    plugins: plugins::Plugins,
+    /// Resumable uploads of the admin API, see [`tus`]
+    uploads: tus::Uploads,
+    /// Downtime of the public routes, see [`maintenance`]
+    maintenance: maintenance::Maintenance,
+    /// Knobs changed at runtime, see [`settings`]
+    settings: settings::Settings,
+    /// Read from the environment once, when the state is built
+    config: Arc<ServerConfig>,
 }
 
 impl App {
     fn new(
         pool: Pool<Sqlite>,
+        reader: Pool<Sqlite>,
         progress_channel_sender: broadcast::Sender<progress::Event>,
         task_manager: Arc<TaskManager>,
         indexer: file_indexer::FileIndexer,
+        share_cache: share_cache::ShareCache,
+        chaos: chaos::Chaos,
     ) -> Self {
         App {
             db_pool: pool,
+            db_reader: reader,
             progress_channel_sender,
+            active_progress: progress::ActiveProgress::default(),
+            plugins: task_manager.plugins.clone(),
             task_manager,
             indexer,
+            share_cache,
+            chaos,
+            load: bandwidth::ServerLoad::default(),
+            limits: download_limits::DownloadLimiter::default(),
+            download_buffer: ServerConfig::STD_DOWNLOAD_BUFFER_KB * 1024,
+            uploads: tus::Uploads::new(
+                &ServerConfig::data_dir_from_env(),
+                tus::Uploads::STD_EXPIRY,
+            ),
+            maintenance: maintenance::Maintenance::default(),
+            settings: settings::Settings::default(),
+            config: Arc::new(ServerConfig::new()),
         }
     }
-}
 
-impl App {}
+    fn with_active_progress(mut self, active_progress: progress::ActiveProgress) -> Self {
+        self.active_progress = active_progress;
+        self
+    }
+
+    fn with_uploads(mut self, uploads: tus::Uploads) -> Self {
+        self.uploads = uploads;
+        self
+    }
 
-async fn init_db(data_dir: PathBuf) -> Db {
-    let mut sqlite_path = data_dir.clone();
-    sqlite_path.push("db.sqlite");
+    fn with_maintenance(mut self, maintenance: maintenance::Maintenance) -> Self {
+        self.maintenance = maintenance;
+        self
+    }
 
-    let opts = sqlx::sqlite::SqliteConnectOptions::new()
-        .filename(sqlite_path)
-        .create_if_missing(true);
+    /// The download limits follow the settings
+    fn with_settings(mut self, settings: settings::Settings) -> Self {
+        self.limits = download_limits::DownloadLimiter::new(&settings.get().limits());
+        settings.spawn_limits_updater(self.limits.clone());
+        self.settings = settings;
+        self
+    }
 
-    // opts.disable_statement_logging();
-    match Db::connect_with(opts).await {
-        Ok(db) => db,
-        Err(e) => {
-            panic!("Failed to connect to SQLx database: {}", e);
-        }
-    } 
+    fn with_download_buffer(mut self, bytes: usize) -> Self {
+        self.download_buffer = bytes;
+        self
+    }
+}
+
+impl App {}
+
+/// Open the writer and reader pools, see [`db_pool`], then check and migrate the schema, see
+/// [`db_schema`].
+async fn init_db(
+    data_dir: PathBuf,
+    config: &db_pool::DatabaseConfig,
+    no_migrate: bool,
+) -> Result<(Db, Db)> {
+    let (writer, reader) = config.open(&data_dir.join("db.sqlite")).await?;
+    db_schema::prepare(&writer, no_migrate).await?;
+    Ok((writer, reader))
 }
 
 struct ShareLink {
-    link: i64,
+    /// Token of the file in the share, see [`file_tokens`]
+    link: String,
     short_filename: String,
+    has_preview: bool,
+    unavailable: bool,
+    /// Human readable, empty when unknown
+    size: String,
+    icon: &'static str,
+    /// See [`share_page::FileKind::preview`]
+    inline_preview: &'static str,
+    /// Readable in the browser, see [`text_viewer`]
+    viewable: bool,
 }
 
 #[derive(Template)] // this will generate the code...
@@ -155,207 +362,878 @@ struct DownloadFilesTemplate {
     share_id: String,
     hardwire_host: String,
     first_filename: String,
+    /// See [`share_branding`], the default look when empty
+    title: String,
+    description: String,
+    accent_color: String,
+    logo_url: String,
+    /// Sanitized HTML of the readme, see [`share_readme`]
+    readme: String,
+    /// Time a probe download with JavaScript to suggest how to fetch the files
+    bandwidth_probe: bool,
+    /// Link the .torrent built by the CreateTorrent task
+    torrent: bool,
+    /// See [`share_page::page_files`]
+    page: usize,
+    total_pages: usize,
+    sort_links: Vec<SortLink>,
+    /// Query strings of the neighbour pages, empty on the first and last ones
+    prev_href: String,
+    next_href: String,
+    /// See [`share_page::Layout`]
+    gallery: bool,
+    /// Link to the other layout, empty for shares without images or videos
+    layout_label: &'static str,
+    layout_href: String,
+}
+
+struct SortLink {
+    label: &'static str,
+    href: String,
+    /// The listing is sorted by this key, `▲` or `▼`, empty otherwise
+    current: &'static str,
+}
+
+/// JSON variant of the share page
+#[derive(serde::Serialize)]
+struct ShareListing {
+    share_id: String,
+    files: Vec<ListedFile>,
+    page: usize,
+    per_page: usize,
+    total_pages: usize,
+    total_files: usize,
+}
+
+#[derive(serde::Serialize)]
+struct ListedFile {
+    name: String,
+    url: String,
+    /// Bytes, unknown for files shared before sizes were recorded
+    size: Option<i64>,
+    #[serde(rename = "type")]
+    kind: share_page::FileKind,
+    unavailable: bool,
+}
+
+/// Share page of an end-to-end encrypted share, the file names are only known once decrypted
+/// by the browser so they can't be rendered by the server
+#[derive(Template)]
+#[template(path = "encrypted_share.html")]
+struct EncryptedShareTemplate {
+    file_links: Vec<String>,
+    share_id: String,
+    hardwire_host: String,
 }
 
+/// The share page, or its JSON variant at `/s/{share_id}.json` and for `Accept: application/json`
 async fn list_shared_files(
     State(app_state): State<App>,
     Path(share_id): Path<String>,
-) -> Response {
-    let result = async move {
-        let shared_links: Vec<(String, i64, String)> = sqlx::query_as(
-            r#"SELECT files.path AS "filename!", files.id AS "link!", substr(files.path, instr(files.path, '/') + 1) AS "short_filename!"
-        FROM share_links JOIN share_link_files ON share_links.id=share_link_files.share_link_id
-        JOIN files ON share_link_files.file_id=files.id
-        WHERE share_links.id = ?"#
-        )
-        .bind(share_id.clone())
-        .fetch_all(&app_state.db_pool)
-        .await?;
-        let server = ServerConfig::new();
-        
-        if !shared_links.is_empty() {
-            let t = DownloadFilesTemplate {
-                files: shared_links
-                    .iter()
-                    .map(|r| ShareLink {
-                        link: r.1,
-                        short_filename: r.2.clone(),
-                    })
-                    .collect(),
-                share_id: share_id.to_string(),
-                hardwire_host: server.host,
-                first_filename: shared_links.first().unwrap().2.clone(),
-            };
-
-            Ok::<_, anyhow::Error>((StatusCode::OK, Html(t.render().unwrap())))
-        } else {
-            Ok::<_, anyhow::Error>(not_found().await)
+    Query(query): Query<share_page::ListQuery>,
+    client: proxy::Client,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let (share_id, json) = match share_id.strip_suffix(".json") {
+        Some(share_id) => (share_id.to_string(), true),
+        None => {
+            let accept = headers
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .unwrap_or_default();
+            (share_id, accept.contains("application/json"))
         }
+    };
+    let share = match app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+    {
+        Some(share) => share,
+        None if json => return Err(AppError::NotFound("Share not found".to_string())),
+        None => return Ok(not_found().await.into_response()),
+    };
+    let server = ServerConfig::new();
+    let hardwire_host = client.host(&server.host);
+
+    if json {
+        let listed = share_page::page_files(&share.files, &query);
+        let listing = ShareListing {
+            files: listed
+                .files
+                .iter()
+                .map(|f| ListedFile {
+                    name: f.short_filename.clone(),
+                    url: format!("{}/s/{}/{}", hardwire_host, share_id, f.link),
+                    size: f.size,
+                    kind: share_page::FileKind::from_name(&f.short_filename),
+                    unavailable: f.unavailable,
+                })
+                .collect(),
+            share_id,
+            page: listed.page,
+            per_page: query.per_page(),
+            total_pages: listed.total_pages,
+            total_files: listed.total_files,
+        };
+        return Ok(Json(listing).into_response());
     }
-    .await;
 
-    match result {
-        Ok(response) => response.into_response(),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {}", e)).into_response(),
+    let view = share_views::ShareView {
+        viewed_at: chrono::offset::Utc::now().timestamp(),
+        ip_address: client.ip.map(|ip| ip.to_string()),
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        referer: headers
+            .get(REFERER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+    };
+    share_views::record(&app_state.db_pool, &share_id, view).await;
+
+    if share.encrypted {
+        let t = EncryptedShareTemplate {
+            file_links: share.files.iter().map(|f| f.link.clone()).collect(),
+            share_id: share_id.to_string(),
+            hardwire_host,
+        };
+        return Ok((StatusCode::OK, Html(t.render().unwrap())).into_response());
     }
-}
 
-async fn healthcheck() -> impl IntoResponse {
-    "OK"
+    use share_page::{SortKey, SortOrder};
+    let listed = share_page::page_files(&share.files, &query);
+    let sort_links = [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Type, "Type"),
+    ]
+    .into_iter()
+    .map(|(sort, label)| {
+        let current = sort == query.sort;
+        // Sorting again by the current key reverses the order
+        let order = if current && query.order == SortOrder::Asc {
+            SortOrder::Desc
+        } else {
+            SortOrder::Asc
+        };
+        SortLink {
+            label,
+            href: query.href(sort, order, 1),
+            current: match (current, query.order) {
+                (false, _) => "",
+                (true, SortOrder::Asc) => "▲",
+                (true, SortOrder::Desc) => "▼",
+            },
+        }
+    })
+    .collect();
+    let page_href = |page: usize| query.href(query.sort, query.order, page);
+    let layout = query
+        .layout
+        .unwrap_or_else(|| share_page::Layout::of(&share.files));
+    let (layout_label, layout_href) = if share
+        .files
+        .iter()
+        .any(|f| share_page::is_media(&f.short_filename))
+    {
+        let (label, other) = match layout {
+            share_page::Layout::List => ("Show as a gallery", share_page::Layout::Gallery),
+            share_page::Layout::Gallery => ("Show as a list", share_page::Layout::List),
+        };
+        let switched = share_page::ListQuery {
+            layout: Some(other),
+            ..query.clone()
+        };
+        (label, switched.href(query.sort, query.order, listed.page))
+    } else {
+        ("", String::new())
+    };
+    let t = DownloadFilesTemplate {
+        prev_href: if listed.page > 1 {
+            page_href(listed.page - 1)
+        } else {
+            String::new()
+        },
+        next_href: if listed.page < listed.total_pages {
+            page_href(listed.page + 1)
+        } else {
+            String::new()
+        },
+        page: listed.page,
+        total_pages: listed.total_pages,
+        sort_links,
+        gallery: layout == share_page::Layout::Gallery,
+        layout_label,
+        layout_href,
+        files: listed
+            .files
+            .iter()
+            .map(|f| {
+                let kind = share_page::FileKind::from_name(&f.short_filename);
+                ShareLink {
+                    link: f.link.clone(),
+                    short_filename: f.short_filename.clone(),
+                    has_preview: f.has_preview,
+                    unavailable: f.unavailable,
+                    size: f
+                        .size
+                        .map_or_else(String::new, |size| top::format_bytes(size.max(0) as u64)),
+                    icon: kind.icon(),
+                    inline_preview: if f.unavailable { "" } else { kind.preview() },
+                    viewable: !f.unavailable && text_viewer::is_viewable(&f.short_filename, f.size),
+                }
+            })
+            .collect(),
+        share_id: share_id.to_string(),
+        hardwire_host,
+        first_filename: share.files[0].short_filename.clone(),
+        title: share
+            .branding
+            .title
+            .clone()
+            .unwrap_or_else(|| "HardWire".to_string()),
+        description: share.branding.description.clone().unwrap_or_default(),
+        accent_color: share.branding.accent_color.clone().unwrap_or_default(),
+        logo_url: share.branding.logo_url.clone().unwrap_or_default(),
+        readme: share.readme.clone().unwrap_or_default(),
+        bandwidth_probe: server.bandwidth_probe,
+        torrent: worker::torrent::torrent_path(&server.data_dir, &share_id).exists(),
+    };
+
+    Ok((StatusCode::OK, Html(t.render().unwrap())).into_response())
 }
 
-async fn head_file(
+/// Serve the preview generated for a shared video by the TranscodePreview task
+async fn preview_file(
     State(app_state): State<App>,
-    Path((share_id, file_id)): Path<(String, u32)>,
-) -> impl IntoResponse {
-    let file_path = match sqlx::query!(
-        r#"SELECT path as file_path
+    Path((share_id, token)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    let now = chrono::offset::Utc::now().timestamp();
+    let preview_path = match sqlx::query!(
+        r#"SELECT preview_path as "preview_path!"
         FROM files JOIN share_link_files ON share_link_files.file_id=files.id
-        WHERE files.id=$1 AND share_link_files.share_link_id=$2"#,
-        file_id,
-        share_id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.token=$1 AND share_link_files.share_link_id=$2 AND preview_path IS NOT NULL
+        AND (share_links.expiration < 0 OR share_links.expiration > $3) AND share_links.deleted_at IS NULL"#,
+        token,
+        share_id,
+        now
     )
-    .fetch_one(&app_state.db_pool)
+    .fetch_one(&app_state.db_reader)
     .await
     {
-        Ok(row) => row.file_path,
-        Err(_) => return Err(not_found().await),
+        Ok(row) => row.preview_path,
+        Err(_) => return not_found().await.into_response(),
     };
 
-    let file = match tokio::fs::File::open(file_path.clone()).await {
-        Ok(file) => file,
-        Err(_) => return Err(not_found().await),
+    match ServeFile::new(preview_path).try_call(request).await {
+        Ok(response) => response.map(Body::new).into_response(),
+        Err(_) => not_found().await.into_response(),
+    }
+}
+
+/// Serve a shared image, video, audio file or PDF for the share page to display it, see
+/// [`share_page`]. Range requests are supported for seeking, previews are not recorded as downloads.
+async fn inline_file(
+    State(app_state): State<App>,
+    Path((share_id, token)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    let now = chrono::offset::Utc::now().timestamp();
+    let (file_id, file_path) = match sqlx::query!(
+        r#"SELECT files.id as "id!", path
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.token=$1 AND share_link_files.share_link_id=$2 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $3) AND share_links.deleted_at IS NULL"#,
+        token,
+        share_id,
+        now
+    )
+    .fetch_one(&app_state.db_reader)
+    .await
+    {
+        Ok(row) => (row.id, row.path),
+        Err(_) => return not_found().await.into_response(),
+    };
+    let file_name = std::path::Path::new(&file_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    if share_page::FileKind::from_name(&file_name)
+        .preview()
+        .is_empty()
+    {
+        return not_found().await.into_response();
+    }
+    let mut download = plugins::DownloadRequest {
+        share_id,
+        file_id,
+        path: file_path.clone(),
+        client_ip: proxy::client_ip(request.extensions()),
+        download_name: file_name,
     };
-    let file_size = file.metadata().await.unwrap().len();
+    if app_state.plugins.before_download(&mut download).is_err() {
+        return (StatusCode::FORBIDDEN, Html("Download refused".to_string())).into_response();
+    }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_LENGTH, file_size.to_string().parse().unwrap());
-    Ok(headers)
+    let content_type = share_page::content_type(&file_path);
+    match ServeFile::new_with_mime(file_path, &content_type)
+        .try_call(request)
+        .await
+    {
+        Ok(response) => {
+            let mut response = response.map(Body::new).into_response();
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("inline"));
+            headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+            response
+        }
+        Err(_) => not_found().await.into_response(),
+    }
 }
 
-#[instrument(skip(app_state))]
-async fn download_file(
+/// QR code of the share page, to open it from a phone. Links to encrypted shares carry their key
+/// in the fragment, which the server never sees, so they have none.
+async fn share_qr_code(
     State(app_state): State<App>,
-    Path((share_id, file_id)): Path<(String, u32)>,
+    Path(share_id): Path<String>,
+    client: proxy::Client,
+) -> AppResult<Response> {
+    match app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+    {
+        Some(share) if !share.encrypted => {}
+        _ => return Ok(not_found().await.into_response()),
+    }
+    let png = share_page::qr_png(&format!(
+        "{}/s/{}",
+        client.host(&app_state.config.host),
+        share_id
+    ))?;
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png"),
+            (CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        png,
+    )
+        .into_response())
+}
+
+/// .torrent built by the CreateTorrent task, see [`worker::torrent`]
+async fn share_torrent(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+) -> AppResult<Response> {
+    match app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+    {
+        Some(share) if !share.encrypted => {}
+        _ => return Ok(not_found().await.into_response()),
+    }
+    let torrent_path = worker::torrent::torrent_path(&app_state.config.data_dir, &share_id);
+    let metainfo = match tokio::fs::read(&torrent_path).await {
+        Ok(metainfo) => metainfo,
+        Err(_) => return Ok(not_found().await.into_response()),
+    };
+    Ok((
+        [
+            (CONTENT_TYPE, "application/x-bittorrent".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.torrent\"", share_id),
+            ),
+        ],
+        metainfo,
+    )
+        .into_response())
+}
+
+/// Web seed of the share torrents: BitTorrent clients append the torrent name and the file name
+/// to the seed URL, the file is found by its name and served as a regular download
+async fn web_seed(
+    State(app_state): State<App>,
+    Path((share_id, path)): Path<(String, String)>,
+    client: proxy::Client,
     headers: HeaderMap,
-) -> impl IntoResponse {
-    let file_path = match sqlx::query!(
-        r#"SELECT path as file_path
+) -> Response {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let now = chrono::offset::Utc::now().timestamp();
+    let files = sqlx::query!(
+        r#"SELECT share_link_files.token as "token!", files.path
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.share_link_id=$1 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $2) AND share_links.deleted_at IS NULL"#,
+        share_id,
+        now
+    )
+    .fetch_all(&app_state.db_reader)
+    .await
+    .unwrap_or_default();
+    let token = files.into_iter().find_map(|file| {
+        (std::path::Path::new(&file.path)
+            .file_name()
+            .is_some_and(|file_name| file_name == name))
+        .then_some(file.token)
+    });
+    match token {
+        Some(token) => download_file(State(app_state), Path((share_id, token)), client, headers)
+            .await
+            .into_response(),
+        None => not_found().await.into_response(),
+    }
+}
+
+/// Incompressible bytes timed by the share page to measure the bandwidth of the recipient
+async fn bandwidth_probe(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+) -> AppResult<Response> {
+    if app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+        .is_none()
+    {
+        return Ok(not_found().await.into_response());
+    }
+    Ok((
+        [
+            (CONTENT_TYPE, "application/octet-stream"),
+            (CACHE_CONTROL, "no-store"),
+        ],
+        bandwidth::probe_payload(),
+    )
+        .into_response())
+}
+
+/// How the files of a share are best downloaded at the bandwidth measured by the recipient
+async fn download_suggestion(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+    Query(query): Query<bandwidth::SuggestionQuery>,
+) -> AppResult<Response> {
+    let Some(share) = app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+    else {
+        return Ok(not_found().await.into_response());
+    };
+    let files: Vec<bandwidth::ShareFile> = share
+        .files
+        .iter()
+        .map(|file| bandwidth::ShareFile {
+            link: file.link.clone(),
+            name: file.short_filename.clone(),
+            size: file.size.map_or(0, |size| size.max(0) as u64),
+        })
+        .collect();
+    Ok(Json(bandwidth::suggest(
+        &files,
+        query.bandwidth,
+        app_state.load.active_downloads(),
+        app_state.config.uplink,
+    ))
+    .into_response())
+}
+
+async fn healthcheck() -> impl IntoResponse {
+    "OK"
+}
+
+/// A shared file resolved from its link, with what both `HEAD` and `GET` answer
+struct ShareFile {
+    file: tokio::fs::File,
+    file_size: u64,
+    /// Strong validator of the file content, from its size and modification time
+    etag: String,
+    max_bytes_per_sec: Option<i64>,
+    download: plugins::DownloadRequest,
+}
+
+/// Look up the file of a share link, open it and let the plugins rename or refuse it
+async fn resolve_share_file(
+    app_state: &App,
+    share_id: String,
+    token: String,
+    client: &proxy::Client,
+) -> Result<ShareFile, (StatusCode, Html<String>)> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let (file_id, file_path, filename_rules, max_bytes_per_sec) = match sqlx::query!(
+        r#"SELECT files.id as "id!", path as file_path, share_links.filename_rules, share_links.max_bytes_per_sec
     FROM files JOIN share_link_files ON share_link_files.file_id=files.id
-    WHERE files.id=$1 AND share_link_files.share_link_id=$2"#,
-        file_id,
-        share_id
+    JOIN share_links ON share_links.id=share_link_files.share_link_id
+    WHERE share_link_files.token=$1 AND share_link_files.share_link_id=$2
+    AND (share_links.expiration < 0 OR share_links.expiration > $3) AND share_links.deleted_at IS NULL"#,
+        token,
+        share_id,
+        now
     )
-    .fetch_one(&app_state.db_pool)
+    .fetch_one(&app_state.db_reader)
     .await
     {
-        Ok(row) => row.file_path,
+        Ok(row) => (row.id, row.file_path, row.filename_rules, row.max_bytes_per_sec),
         Err(_) => return Err(not_found().await),
     };
+    let filename_rules: Vec<filename_rules::FilenameRule> =
+        serde_json::from_str(&filename_rules).unwrap_or_default();
+    let download_name = filename_rules::rewrite(
+        &std::path::Path::new(&file_path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy(),
+        &filename_rules,
+        chrono::offset::Utc::now().date_naive(),
+    );
+    let mut download = plugins::DownloadRequest {
+        share_id,
+        file_id,
+        path: file_path.clone(),
+        client_ip: client.ip.map(|ip| ip.to_string()),
+        download_name,
+    };
+    if app_state.plugins.before_download(&mut download).is_err() {
+        return Err((StatusCode::FORBIDDEN, Html("Download refused".to_string())));
+    }
 
-    let mut file = match tokio::fs::File::open(file_path.clone()).await {
+    let file = match tokio::fs::File::open(file_path).await {
         Ok(file) => file,
         Err(_) => return Err(not_found().await),
     };
-    let file_size = file.metadata().await.unwrap().len();
-    let transaction_id = find_current_trace_id().unwrap();
-
-    // Handle range request
-    let (start, end) = if let Some(range) = headers.get(RANGE) {
-        if let Ok(range_str) = range.to_str() {
-            if let Some(range_val) = range_str.strip_prefix("bytes=") {
-                let ranges: Vec<&str> = range_val.split('-').collect();
-                if ranges.len() == 2 {
-                    let start = ranges[0].parse::<u64>().unwrap_or(0);
-                    let end = ranges[1].parse::<u64>().unwrap_or(file_size - 1).min(file_size - 1);
-                    if start <= end {
-                        (start, end)
-                    } else {
-                        (0, file_size - 1)
-                    }
-                } else {
-                    (0, file_size - 1)
-                }
-            } else {
-                (0, file_size - 1)
-            }
-        } else {
-            (0, file_size - 1)
-        }
-    } else {
-        (0, file_size - 1)
+    let metadata = file.metadata().await.unwrap();
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    Ok(ShareFile {
+        file,
+        file_size: metadata.len(),
+        etag: format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len()),
+        max_bytes_per_sec,
+        download,
+    })
+}
+
+/// Bytes of the file sent for the request, `None` when its `Range` starts past the end of the file.
+/// The whole file without a single valid range, or when the `If-Range` validator no longer matches
+/// the file.
+fn requested_range(
+    headers: &HeaderMap,
+    file_size: u64,
+    etag: &str,
+) -> Option<std::ops::Range<u64>> {
+    let whole = Some(0..file_size);
+    if headers
+        .get(IF_RANGE)
+        .is_some_and(|if_range| if_range.as_bytes() != etag.as_bytes())
+    {
+        return whole;
+    }
+    let Some(spec) = headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+    else {
+        return whole;
     };
+    // Several ranges would need a multipart response, the whole file is sent instead
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return whole;
+    };
+    let range = match (start.trim(), end.trim()) {
+        // The last bytes, e.g. `bytes=-500`, where players look for the index of a video
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return None,
+            Ok(suffix) => file_size.saturating_sub(suffix)..file_size,
+            Err(_) => return whole,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(start) => start..file_size,
+            Err(_) => return whole,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(file_size),
+            _ => return whole,
+        },
+    };
+    (range.start < file_size).then_some(range)
+}
 
-    // Seek to the start position if it's not 0
-    if start > 0 {
-        use tokio::io::AsyncSeekExt;
-        if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
-            return Ok((StatusCode::INTERNAL_SERVER_ERROR, format!("Something went wrong: {}", e)).into_response());
+/// Whether the `If-None-Match` of the request lists `etag`, the client then already has the response
+fn etag_matches(request: &HeaderMap, etag: &str) -> bool {
+    request
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            })
+        })
+}
+
+impl ShareFile {
+    /// Status and headers of the response sending `range`, a 304 when the client already has the
+    /// file or a 416 for a range past its end, see [`requested_range`]
+    async fn response_head(
+        &self,
+        db: &SqlitePool,
+        request: &HeaderMap,
+        range: Option<std::ops::Range<u64>>,
+    ) -> (StatusCode, HeaderMap) {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, self.etag.parse().unwrap());
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if etag_matches(request, &self.etag) {
+            return (StatusCode::NOT_MODIFIED, headers);
         }
-    }
+        let Some(range) = range else {
+            headers.insert(
+                CONTENT_RANGE,
+                format!("bytes */{}", self.file_size).parse().unwrap(),
+            );
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers);
+        };
 
-    let content_length = end - start + 1;
-    let progress_reader = ProgressReader::new(
-        file,
-        content_length as u32,
-        transaction_id,
-        file_path,
-        app_state.progress_channel_sender,
-        start,
-    );
-    let frame_reader = FramedRead::new(progress_reader, BytesCodec::new());
-    // let body_stream = http_body_util::BodyStream::new(frame_reader);
-    let body = Body::from_stream(frame_reader);
-
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_LENGTH, content_length.to_string().parse().unwrap());
-    
-    if start != 0 || end != file_size - 1 {
         headers.insert(
-            CONTENT_RANGE,
-            format!("bytes {}-{}/{}", start, end, file_size).parse().unwrap(),
+            CONTENT_LENGTH,
+            (range.end - range.start).to_string().parse().unwrap(),
         );
-        headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
-        Ok((StatusCode::PARTIAL_CONTENT, headers, body).into_response())
-    } else {
-        headers.insert(ACCEPT_RANGES, "bytes".parse().unwrap());
-        Ok((headers, body).into_response())
+        headers.insert(
+            CONTENT_TYPE,
+            share_page::content_type(&self.download.path)
+                .as_ref()
+                .parse()
+                .unwrap(),
+        );
+        headers.insert(
+            CONTENT_DISPOSITION,
+            filename_rules::content_disposition(&self.download.download_name)
+                .parse()
+                .unwrap(),
+        );
+        insert_digest_headers(&mut headers, db, self.download.file_id).await;
+        if range != (0..self.file_size) {
+            headers.insert(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.end - 1, self.file_size)
+                    .parse()
+                    .unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, headers)
+        } else {
+            (StatusCode::OK, headers)
+        }
     }
 }
 
-#[instrument(skip(app_state))]
-async fn list_files(State(app_state): State<App>) -> Json<Option<Vec<FileInfo>>> {
-    let files = app_state.indexer.files.lock().unwrap().clone();
-    Json(files)
+/// The headers of [`download_file`] without the body, download managers check the size and the
+/// range support before opening their connections
+async fn head_file(
+    State(app_state): State<App>,
+    Path((share_id, token)): Path<(String, String)>,
+    client: proxy::Client,
+    headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap), (StatusCode, Html<String>)> {
+    let share_file = resolve_share_file(&app_state, share_id, token, &client).await?;
+    let range = requested_range(&headers, share_file.file_size, &share_file.etag);
+    Ok(share_file
+        .response_head(&app_state.db_reader, &headers, range)
+        .await)
+}
 
-    // json!(*app_state.indexer.files.lock().unwrap());
+/// `Repr-Digest` and `Digest` of the whole file, once the ChecksumShare task hashed it
+async fn insert_digest_headers(headers: &mut HeaderMap, db: &SqlitePool, file_id: i64) {
+    let digests = match worker::hashing::file_digests(db, file_id).await {
+        Ok(digests) => digests,
+        Err(e) => {
+            tracing::error!("Failed to load the digests of file {}: {}", file_id, e);
+            return;
+        }
+    };
+    if let Some((repr_digest, digest)) = worker::hashing::digest_headers(&digests) {
+        headers.insert("repr-digest", repr_digest.parse().unwrap());
+        headers.insert("digest", digest.parse().unwrap());
+    }
 }
 
-async fn create_shared_link(
+/// Progress events of an unthrottled download, besides the first and last ones
+const FAST_PATH_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[instrument(skip(app_state))]
+async fn download_file(
     State(app_state): State<App>,
-    Json(files): Json<Vec<String>>,
-) -> Json<Option<String>> {
-    // Validate input
-    for file in &files {
-        if file.contains("..") || file.contains("\0") {
-            return Json(None);
+    Path((share_id, token)): Path<(String, String)>,
+    client: proxy::Client,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let share_file = resolve_share_file(&app_state, share_id, token, &client).await?;
+    let range = requested_range(&headers, share_file.file_size, &share_file.etag);
+    let (status, response_headers) = share_file
+        .response_head(&app_state.db_reader, &headers, range.clone())
+        .await;
+    let Some(range) = range.filter(|_| status != StatusCode::NOT_MODIFIED) else {
+        return Ok((status, response_headers).into_response());
+    };
+    let ShareFile {
+        mut file,
+        file_size,
+        max_bytes_per_sec,
+        download,
+        ..
+    } = share_file;
+    // The lower of the rates of the share and of the settings
+    let max_bytes_per_sec = match (
+        max_bytes_per_sec.map(|rate| rate as u64),
+        app_state.settings.get().max_bytes_per_sec,
+    ) {
+        (Some(share), Some(server)) => Some(share.min(server)),
+        (share, server) => share.or(server),
+    };
+    let file_path = download.path.clone();
+    let slot = match app_state.limits.try_start(client.ip, &file_path) {
+        Ok(slot) => slot,
+        Err(limited) => {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                Html(limited.message().to_string()),
+            ))
+        }
+    };
+    // no trace context when OpenTelemetry isn't initialized
+    let transaction_id = find_current_trace_id().unwrap_or_else(|| nanoid::nanoid!());
+
+    // Seek to the start position if it's not 0
+    if range.start > 0 {
+        use tokio::io::AsyncSeekExt;
+        if let Err(e) = file.seek(std::io::SeekFrom::Start(range.start)).await {
+            return Ok(AppError::from(e).into_response());
         }
     }
 
-    match publish_files(files, &ServerConfig::new().host, &app_state.db_pool).await {
-        Ok(link) => Json(Some(link)),
-        Err(_) => Json(None),
+    let content_length = range.end - range.start;
+    let log_sink = LogSink {
+        label: format!("Download {} ({})", file_path, transaction_id),
+    };
+    let served = app_state
+        .load
+        .track(&file_path, download.client_ip.clone(), content_length);
+    let after_download = plugins::AfterDownloadSink::new(app_state.plugins.clone(), &download);
+    let progress = FileDownload::new(
+        transaction_id,
+        file_path,
+        download.client_ip.clone(),
+        file_size,
+        range,
+    )
+    .with_share(download.share_id.clone(), download.file_id)
+    .with_user_agent(
+        headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+    );
+    let mut progress_sink = DownloadProgressSink::new(
+        progress,
+        app_state.progress_channel_sender,
+        app_state.chaos.clone(),
+    );
+    // Unthrottled downloads read large buffers handed to hyper without copy, and report their
+    // progress less often than each of them
+    let unthrottled = max_bytes_per_sec.is_none();
+    if unthrottled {
+        progress_sink = progress_sink.with_min_interval(FAST_PATH_PROGRESS_INTERVAL);
     }
+    // Stop at the end of the range so only the bytes sent are counted
+    use tokio::io::AsyncReadExt;
+    let progress_reader = InstrumentedStream::new(
+        chaos::SlowReader::new(
+            throttle::Throttled::new(file.take(content_length), max_bytes_per_sec),
+            app_state.chaos.clone(),
+        ),
+        (progress_sink, (log_sink, (served, (after_download, slot)))),
+    );
+    let body = if unthrottled {
+        Body::from_stream(ReaderStream::with_capacity(
+            progress_reader,
+            app_state.download_buffer,
+        ))
+    } else {
+        // Small chunks keep the throttled rate smooth
+        Body::from_stream(FramedRead::new(progress_reader, BytesCodec::new()))
+    };
+
+    Ok((status, response_headers, body).into_response())
+}
+
+/// Settings of a new share, see [`publish_files`] and [`share_profiles`]
+#[derive(Debug, Default)]
+struct ShareOptions {
+    /// Unix timestamp after which the share is no longer served, `None` for a share which never
+    /// expires
+    expires_at: Option<i64>,
+    /// Files encrypted client-side, see [`e2ee`]
+    encrypted: bool,
+    alias: Option<String>,
+    /// See [`share_password`]
+    password_hash: Option<String>,
+    /// Email once every file was downloaded, see [`notifications`]
+    notify: bool,
+    /// Download rate of each file, see [`throttle`]
+    max_bytes_per_sec: Option<i64>,
+    /// Admins managing the share besides the owner, see [`namespaces`]
+    namespace: Option<String>,
+    /// Served to its first visitor only, see [`share_burn`]
+    burn_after_reading: bool,
 }
 
+/// Register `files` in a new share and return its URL. The URL uses the alias of `options` when
+/// given, which fails with [`share_alias::AliasTaken`] when another share uses it.
 async fn publish_files(
-    files: Vec<String>,
+    files: Vec<share_roots::ShareableFile>,
     base_url: &String,
     db_pool: &SqlitePool,
+    plugins: &plugins::Plugins,
+    options: ShareOptions,
 ) -> Result<String> {
     let mut files_id: Vec<i64> = vec![];
-    let share_id = nanoid::nanoid!(10);
+    let mut share = plugins::NewShare {
+        share_id: nanoid::nanoid!(10),
+        files: files
+            .into_iter()
+            .map(share_roots::ShareableFile::into_string)
+            .collect(),
+        expires_at: options.expires_at,
+        encrypted: options.encrypted,
+        alias: options.alias,
+    };
+    plugins.share_created(&mut share)?;
+    let plugins::NewShare {
+        share_id,
+        files,
+        expires_at,
+        encrypted,
+        alias,
+    } = share;
+    if let Some(alias) = &alias {
+        if !share_alias::is_available(db_pool, alias).await? {
+            return Err(share_alias::AliasTaken(alias.clone()).into());
+        }
+    }
 
+    let mut stored_files = vec![];
     for filename in files {
         if std::path::Path::new(&filename).exists() {
             let file = File::open(&filename)?;
@@ -373,15 +1251,25 @@ async fn publish_files(
                 Ok(row) => files_id.push(row.last_insert_rowid()),
                 Err(e) => return Err(anyhow!("failed to create share link: {:?}", e)),
             };
+            stored_files.push(filename);
         }
     }
     if !files_id.is_empty() {
         let now = chrono::offset::Utc::now().timestamp();
+        let expiration = expires_at.unwrap_or(-1);
         match sqlx::query!(
-            "INSERT INTO share_links (id, expiration, created_at) VALUES ($1, $2, $3)",
+            "INSERT INTO share_links (id, expiration, created_at, encrypted, alias, password_hash, notify, max_bytes_per_sec, namespace, burn_after_reading)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
             share_id,
-            -1,
-            now
+            expiration,
+            now,
+            encrypted,
+            alias,
+            options.password_hash,
+            options.notify,
+            options.max_bytes_per_sec,
+            options.namespace,
+            options.burn_after_reading
         )
         .execute(db_pool)
         .await
@@ -396,7 +1284,19 @@ async fn publish_files(
                     .execute(db_pool)
                     .await?;
                 }
-                return Ok(format!("{}/s/{}", base_url, share_id));
+                let link = format!("{}/s/{}", base_url, alias.as_deref().unwrap_or(&share_id));
+                plugins.share_stored(&plugins::NewShare {
+                    share_id,
+                    files: stored_files,
+                    expires_at,
+                    encrypted,
+                    alias,
+                });
+                return Ok(link);
+            }
+            // Taken between the check and the insert
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() && alias.is_some() => {
+                return Err(share_alias::AliasTaken(alias.unwrap_or_default()).into());
             }
             Err(e) => {
                 log::error!("{}", e);
@@ -407,11 +1307,77 @@ async fn publish_files(
     Err(anyhow::Error::msg("failed to create share link"))
 }
 
+#[derive(Debug)]
 pub struct ServerConfig {
     pub port: u16,
+    /// Sockets accepting the connections, TCP on `port` of `bind_addrs` when empty
+    pub listen: Vec<listen::ListenAddress>,
+    /// Addresses of the TCP sockets on `port`, `0.0.0.0` when empty
+    pub bind_addrs: Vec<IpAddr>,
+    /// Permissions of a Unix socket
+    pub socket_mode: u32,
     pub base_path: String,
+    /// Directories files may be shared from, `base_path` when empty
+    pub share_roots: Vec<PathBuf>,
     pub host: String,
     pub data_dir: PathBuf,
+    pub ffmpeg_path: String,
+    pub mkisofs_path: String,
+    pub webhook_token: Option<String>,
+    /// Days finished tasks are kept before only their history summary remains
+    pub task_retention_days: Option<u64>,
+    /// Days deleted shares stay in the trash, `None` to keep them
+    pub trash_retention_days: Option<u64>,
+    /// Days the files generated by the tasks are kept once no live share uses them, `None` to keep
+    /// them
+    pub artifact_retention_days: Option<u64>,
+    /// Time given to the downloads and the running task to finish on shutdown
+    pub shutdown_grace: Duration,
+    /// Hours between two backups of the database, `None` to only back up on demand
+    pub backup_hours: Option<u64>,
+    /// Database backups kept by the rotation
+    pub backup_keep: usize,
+    /// Time after which an upload without activity is deleted
+    pub upload_expiry: Duration,
+    /// Bytes left free by the archives, disc images and uploads
+    pub disk_reserve: u64,
+    /// Scanners the assembled uploads go through before being shared, none by default
+    pub scanners: Vec<virus_scan::Scanner>,
+    /// Forwarding of download and auth events, disabled without `HARDWIRE_SIEM_URL`
+    pub siem: Option<siem::SiemConfig>,
+    /// Export of the traces, format of the logs and tokio console
+    pub observability: observability::ObservabilityConfig,
+    /// Files hashed at the same time by the ChecksumShare task, overall and per file system
+    pub hash_concurrency: usize,
+    pub hash_concurrency_per_root: usize,
+    /// Suggest a download option from the bandwidth measured by the share page
+    pub bandwidth_probe: bool,
+    /// Upload capacity in bytes per second shared by the downloads, unknown when unset
+    pub uplink: Option<u64>,
+    /// Hours between two verifications of the shared files
+    pub health_check_hours: Option<u64>,
+    /// Concurrent downloads of a client and of a file
+    pub limits: download_limits::LimitsConfig,
+    /// Bytes read at once by the unthrottled downloads
+    pub download_buffer: usize,
+    /// Emails on share and task events, disabled without `HARDWIRE_SMTP_URL`
+    pub notifications: Option<notifications::NotificationConfig>,
+    /// Announce URLs of the torrents built by the CreateTorrent task
+    pub torrent_trackers: Vec<String>,
+    /// HTTPS served directly, disabled without a certificate or ACME domains
+    pub tls: Option<tls::TlsConfig>,
+    /// Reverse proxies whose forwarded headers are believed
+    pub trusted_proxies: proxy::TrustedProxies,
+    /// Browser origins allowed to call the APIs
+    pub cors_origins: cors::AllowedOrigins,
+    /// Serve Swagger UI at `/admin/api/docs`, see [`openapi`]
+    pub swagger_ui: bool,
+    /// Depth and exclude patterns of the file index
+    pub index: file_indexer::IndexOptions,
+    /// Pools of connections and pragmas of SQLite
+    pub database: db_pool::DatabaseConfig,
+    /// Directory of the assets, instead of `dist/` or the ones embedded in the binary
+    pub assets_dir: Option<PathBuf>,
 }
 
 impl ServerConfig {
@@ -419,17 +1385,137 @@ impl ServerConfig {
     const STD_BASE_PATH: &'static str = ".";
     const STD_HOST: &'static str = "http://localhost:8090";
     const PORT_ENV_VAR: &'static str = "HARDWIRE_PORT";
+    const LISTEN_ENV_VAR: &'static str = "HARDWIRE_LISTEN";
+    const BIND_ADDR_ENV_VAR: &'static str = "HARDWIRE_BIND_ADDR";
+    const STD_SOCKET_MODE: u32 = 0o660;
+    const SOCKET_MODE_ENV_VAR: &'static str = "HARDWIRE_SOCKET_MODE";
     const BASE_PATH_ENV_VAR: &'static str = "HARDWIRE_BASE_PATH";
+    const SHARE_ROOTS_ENV_VAR: &'static str = "HARDWIRE_SHARE_ROOTS";
     const HOST_ENV_VAR: &'static str = "HARDWIRE_HOST";
     const STD_HARDWIRE_DATA_DIR: &'static str = ".";
     const HARDWIRE_DATA_DIR_ENV_VAR: &'static str = "HARDWIRE_DATA_DIR";
+    const STD_FFMPEG_PATH: &'static str = "ffmpeg";
+    const FFMPEG_PATH_ENV_VAR: &'static str = "HARDWIRE_FFMPEG_PATH";
+    const STD_MKISOFS_PATH: &'static str = "mkisofs";
+    const MKISOFS_PATH_ENV_VAR: &'static str = "HARDWIRE_MKISOFS_PATH";
+    const WEBHOOK_TOKEN_ENV_VAR: &'static str = "HARDWIRE_WEBHOOK_TOKEN";
+    const STD_TASK_RETENTION_DAYS: u64 = 30;
+    const TASK_RETENTION_DAYS_ENV_VAR: &'static str = "HARDWIRE_TASK_RETENTION_DAYS";
+    const STD_TRASH_RETENTION_DAYS: u64 = 30;
+    const TRASH_RETENTION_DAYS_ENV_VAR: &'static str = "HARDWIRE_TRASH_RETENTION_DAYS";
+    const STD_ARTIFACT_RETENTION_DAYS: u64 = 30;
+    const ARTIFACT_RETENTION_DAYS_ENV_VAR: &'static str = "HARDWIRE_ARTIFACT_RETENTION_DAYS";
+    const STD_SHUTDOWN_GRACE_SECS: u64 = 30;
+    const SHUTDOWN_GRACE_SECS_ENV_VAR: &'static str = "HARDWIRE_SHUTDOWN_GRACE_SECS";
+    const STD_BACKUP_HOURS: u64 = 24;
+    const BACKUP_HOURS_ENV_VAR: &'static str = "HARDWIRE_BACKUP_HOURS";
+    const STD_BACKUP_KEEP: usize = 7;
+    const BACKUP_KEEP_ENV_VAR: &'static str = "HARDWIRE_BACKUP_KEEP";
+    const STD_UPLOAD_EXPIRY_HOURS: u64 = 24;
+    const UPLOAD_EXPIRY_HOURS_ENV_VAR: &'static str = "HARDWIRE_UPLOAD_EXPIRY_HOURS";
+    const STD_DISK_RESERVE_MB: u64 = 1000;
+    const DISK_RESERVE_MB_ENV_VAR: &'static str = "HARDWIRE_DISK_RESERVE_MB";
+    #[cfg(feature = "clamav")]
+    const CLAMD_ADDR_ENV_VAR: &'static str = "HARDWIRE_CLAMD_ADDR";
+    const SCAN_COMMAND_ENV_VAR: &'static str = "HARDWIRE_SCAN_COMMAND";
+    const SIEM_URL_ENV_VAR: &'static str = "HARDWIRE_SIEM_URL";
+    const STD_SIEM_FORMAT: &'static str = "syslog";
+    const SIEM_FORMAT_ENV_VAR: &'static str = "HARDWIRE_SIEM_FORMAT";
+    const STD_SIEM_FACILITY: &'static str = "local0";
+    const SIEM_FACILITY_ENV_VAR: &'static str = "HARDWIRE_SIEM_FACILITY";
+    const SIEM_SEVERITIES_ENV_VAR: &'static str = "HARDWIRE_SIEM_SEVERITIES";
+    const OTLP_ENV_VAR: &'static str = "HARDWIRE_OTLP";
+    const LOG_FORMAT_ENV_VAR: &'static str = "HARDWIRE_LOG_FORMAT";
+    const STD_TRACE_SAMPLE_RATIO: &'static str = "1";
+    const TRACE_SAMPLE_RATIO_ENV_VAR: &'static str = "HARDWIRE_TRACE_SAMPLE_RATIO";
+    const TOKIO_CONSOLE_ENV_VAR: &'static str = "HARDWIRE_TOKIO_CONSOLE";
+    const HASH_CONCURRENCY_ENV_VAR: &'static str = "HARDWIRE_HASH_CONCURRENCY";
+    const STD_HASH_CONCURRENCY_PER_ROOT: usize = 2;
+    const HASH_CONCURRENCY_PER_ROOT_ENV_VAR: &'static str = "HARDWIRE_HASH_CONCURRENCY_PER_ROOT";
+    const BANDWIDTH_PROBE_ENV_VAR: &'static str = "HARDWIRE_BANDWIDTH_PROBE";
+    const UPLINK_MBPS_ENV_VAR: &'static str = "HARDWIRE_UPLINK_MBPS";
+    const STD_HEALTH_CHECK_HOURS: u64 = 6;
+    const HEALTH_CHECK_HOURS_ENV_VAR: &'static str = "HARDWIRE_HEALTH_CHECK_HOURS";
+    const STD_MAX_DOWNLOADS_PER_CLIENT: &'static str = "4";
+    const MAX_DOWNLOADS_PER_CLIENT_ENV_VAR: &'static str = "HARDWIRE_MAX_DOWNLOADS_PER_CLIENT";
+    const STD_MAX_READERS_PER_FILE: &'static str = "32";
+    const MAX_READERS_PER_FILE_ENV_VAR: &'static str = "HARDWIRE_MAX_READERS_PER_FILE";
+    const STD_DOWNLOAD_BUFFER_KB: usize = 256;
+    const DOWNLOAD_BUFFER_KB_ENV_VAR: &'static str = "HARDWIRE_DOWNLOAD_BUFFER_KB";
+    const SMTP_URL_ENV_VAR: &'static str = "HARDWIRE_SMTP_URL";
+    const NOTIFY_FROM_ENV_VAR: &'static str = "HARDWIRE_NOTIFY_FROM";
+    const NOTIFY_TO_ENV_VAR: &'static str = "HARDWIRE_NOTIFY_TO";
+    const STD_NOTIFY_EVENTS: &'static str = "share_downloaded,task_failed";
+    const NOTIFY_EVENTS_ENV_VAR: &'static str = "HARDWIRE_NOTIFY_EVENTS";
+    const TORRENT_TRACKERS_ENV_VAR: &'static str = "HARDWIRE_TORRENT_TRACKERS";
+    const TLS_CERT_ENV_VAR: &'static str = "HARDWIRE_TLS_CERT";
+    const TLS_KEY_ENV_VAR: &'static str = "HARDWIRE_TLS_KEY";
+    const HTTP_PORT_ENV_VAR: &'static str = "HARDWIRE_HTTP_PORT";
+    const TRUSTED_PROXIES_ENV_VAR: &'static str = "HARDWIRE_TRUSTED_PROXIES";
+    const CORS_ORIGINS_ENV_VAR: &'static str = "HARDWIRE_CORS_ORIGINS";
+    const SWAGGER_UI_ENV_VAR: &'static str = "HARDWIRE_SWAGGER_UI";
+    const STD_INDEX_MAX_DEPTH: usize = 32;
+    const INDEX_MAX_DEPTH_ENV_VAR: &'static str = "HARDWIRE_INDEX_MAX_DEPTH";
+    const INDEX_EXCLUDE_ENV_VAR: &'static str = "HARDWIRE_INDEX_EXCLUDE";
+    const INDEX_HIDE_DOTFILES_ENV_VAR: &'static str = "HARDWIRE_INDEX_HIDE_DOTFILES";
+    const STD_DB_MAX_CONNECTIONS: u32 = 4;
+    const DB_MAX_CONNECTIONS_ENV_VAR: &'static str = "HARDWIRE_DB_MAX_CONNECTIONS";
+    const STD_DB_MIN_CONNECTIONS: u32 = 0;
+    const DB_MIN_CONNECTIONS_ENV_VAR: &'static str = "HARDWIRE_DB_MIN_CONNECTIONS";
+    const STD_DB_ACQUIRE_TIMEOUT_SECS: u32 = 30;
+    const DB_ACQUIRE_TIMEOUT_SECS_ENV_VAR: &'static str = "HARDWIRE_DB_ACQUIRE_TIMEOUT_SECS";
+    const STD_DB_BUSY_TIMEOUT_MS: u32 = 5000;
+    const DB_BUSY_TIMEOUT_MS_ENV_VAR: &'static str = "HARDWIRE_DB_BUSY_TIMEOUT_MS";
+    const ASSETS_DIR_ENV_VAR: &'static str = "HARDWIRE_ASSETS_DIR";
+    #[cfg(feature = "acme")]
+    const STD_ACME_HTTP_PORT: u16 = 80;
+    #[cfg(feature = "acme")]
+    const ACME_DOMAINS_ENV_VAR: &'static str = "HARDWIRE_ACME_DOMAINS";
+    #[cfg(feature = "acme")]
+    const ACME_EMAIL_ENV_VAR: &'static str = "HARDWIRE_ACME_EMAIL";
+    #[cfg(feature = "acme")]
+    const ACME_DIRECTORY_ENV_VAR: &'static str = "HARDWIRE_ACME_DIRECTORY";
 
     fn new() -> ServerConfig {
         ServerConfig {
             port: Self::port_from_env(),
+            listen: Self::listen_from_env(),
+            bind_addrs: Self::bind_addrs_from_env(),
+            socket_mode: Self::socket_mode_from_env(),
             base_path: Self::base_path_from_env(),
+            share_roots: Self::share_roots_from_env(),
             host: Self::host_from_env(),
             data_dir: Self::data_dir_from_env(),
+            ffmpeg_path: Self::ffmpeg_path_from_env(),
+            mkisofs_path: Self::mkisofs_path_from_env(),
+            webhook_token: Self::webhook_token_from_env(),
+            task_retention_days: Self::task_retention_days_from_env(),
+            trash_retention_days: Self::trash_retention_days_from_env(),
+            artifact_retention_days: Self::artifact_retention_days_from_env(),
+            shutdown_grace: Self::shutdown_grace_from_env(),
+            backup_hours: Self::backup_hours_from_env(),
+            backup_keep: Self::backup_keep_from_env(),
+            upload_expiry: Self::upload_expiry_from_env(),
+            disk_reserve: Self::disk_reserve_from_env(),
+            scanners: Self::scanners_from_env(),
+            siem: Self::siem_from_env(),
+            observability: Self::observability_from_env(),
+            hash_concurrency: Self::hash_concurrency_from_env(),
+            hash_concurrency_per_root: Self::hash_concurrency_per_root_from_env(),
+            bandwidth_probe: Self::bandwidth_probe_from_env(),
+            uplink: Self::uplink_from_env(),
+            health_check_hours: Self::health_check_hours_from_env(),
+            limits: Self::limits_from_env(),
+            download_buffer: Self::download_buffer_from_env(),
+            notifications: Self::notifications_from_env(),
+            torrent_trackers: Self::torrent_trackers_from_env(),
+            tls: Self::tls_from_env(),
+            trusted_proxies: Self::trusted_proxies_from_env(),
+            cors_origins: Self::cors_origins_from_env(),
+            swagger_ui: Self::swagger_ui_from_env(),
+            index: Self::index_from_env(),
+            database: Self::database_from_env(),
+            assets_dir: Self::assets_dir_from_env(),
         }
     }
 
@@ -441,145 +1527,901 @@ impl ServerConfig {
             .unwrap()
     }
 
-    fn base_path_from_env() -> String {
-        env::var(ServerConfig::BASE_PATH_ENV_VAR).unwrap_or(ServerConfig::STD_BASE_PATH.to_string())
+    fn listen_from_env() -> Vec<listen::ListenAddress> {
+        env::var(ServerConfig::LISTEN_ENV_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|address| !address.trim().is_empty())
+            .map(|address| address.trim().parse().unwrap())
+            .collect()
     }
 
-    fn host_from_env() -> String {
-        env::var(ServerConfig::HOST_ENV_VAR).unwrap_or(ServerConfig::STD_HOST.to_string())
+    fn bind_addrs_from_env() -> Vec<IpAddr> {
+        env::var(ServerConfig::BIND_ADDR_ENV_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|ip| !ip.trim().is_empty())
+            .map(|ip| listen::parse_ip(ip).unwrap())
+            .collect()
     }
 
-    fn data_dir_from_env() -> PathBuf {
-        PathBuf::from(
-            env::var(ServerConfig::HARDWIRE_DATA_DIR_ENV_VAR)
-                .unwrap_or(ServerConfig::STD_HARDWIRE_DATA_DIR.to_string()),
-        )
+    /// Addresses of the TCP sockets, on all interfaces by default
+    fn bind_ips(&self) -> Vec<IpAddr> {
+        if self.bind_addrs.is_empty() {
+            vec![IpAddr::from([0, 0, 0, 0])]
+        } else {
+            self.bind_addrs.clone()
+        }
     }
-}
 
-async fn not_found() -> (StatusCode, Html<String>) {
-    let t = T404 {};
-    (StatusCode::NOT_FOUND, Html(t.render().unwrap()))
-}
+    fn listen_addresses(&self) -> Vec<listen::ListenAddress> {
+        if !self.listen.is_empty() {
+            return self.listen.clone();
+        }
+        self.bind_ips()
+            .into_iter()
+            .map(|ip| listen::ListenAddress::Tcp(SocketAddr::new(ip, self.port)))
+            .collect()
+    }
 
-/// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
-/// of websocket negotiation). After this completes, the actual switching from HTTP to
-/// websocket protocol will occur.
-/// This is the last point where we can extract TCP/IP metadata such as IP address of the client
-/// as well as things from HTTP headers such as user-agent of the browser etc.
-async fn ws_handler(
-    State(app_state): State<App>,
-    ws: WebSocketUpgrade,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
-    // finalize the upgrade process by returning upgrade callback.
-    // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, app_state))
-}
-
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr, app_state: App) {
-    tracing::info!("Websocket connection from: {}", who);
-    let mut rx = app_state.progress_channel_sender.subscribe();
-    tokio::spawn(async move {
-        loop {
-            match rx.recv().await {
-                Ok(msg) => {
-                    if let Err(err) = socket
-                        .send(axum::extract::ws::Message::Text(
-                            serde_json::json!(msg).to_string().into(),
-                        ))
-                        .await
-                    {
-                        tracing::error!("WS socket send error: {}", err);
-                        break;
-                    }
-                }
-                Err(err) => {
-                    tracing::error!("WS channel recv error: {}", err);
-                    break;
-                }
+    /// Settings which parse but can't work together, checked before the server starts
+    fn validate(&self) -> Result<()> {
+        if !self.listen.is_empty() && !self.bind_addrs.is_empty() {
+            anyhow::bail!(
+                "{} and {} can't be used together, list tcp:// addresses in {}",
+                ServerConfig::LISTEN_ENV_VAR,
+                ServerConfig::BIND_ADDR_ENV_VAR,
+                ServerConfig::LISTEN_ENV_VAR
+            );
+        }
+        let addresses = self.listen_addresses();
+        listen::validate(&addresses)?;
+        if let Some(http_port) = self.tls.as_ref().and_then(|tls| tls.http_port) {
+            let https_ports = addresses.iter().filter_map(|address| match address {
+                listen::ListenAddress::Tcp(addr) => Some(addr.port()),
+                _ => None,
+            });
+            if https_ports.into_iter().any(|port| port == http_port) {
+                anyhow::bail!("HTTPS and the HTTP redirection both use port {}", http_port);
             }
         }
-    });
-}
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    pretty_env_logger::init();
+        Ok(())
+    }
 
-    let cli = Cli::parse();
-    let server_config = ServerConfig::new();
-    let db_pool = init_db(server_config.data_dir).await;
+    /// Octal, as taken by chmod
+    fn socket_mode_from_env() -> u32 {
+        env::var(ServerConfig::SOCKET_MODE_ENV_VAR)
+            .map(|val| u32::from_str_radix(&val, 8))
+            .unwrap_or(Ok(ServerConfig::STD_SOCKET_MODE))
+            .unwrap()
+    }
 
-    if cli.files.is_empty() && !cli.server {
+    fn base_path_from_env() -> String {
+        env::var(ServerConfig::BASE_PATH_ENV_VAR).unwrap_or(ServerConfig::STD_BASE_PATH.to_string())
+    }
+
+    /// Separated like `PATH`
+    fn share_roots_from_env() -> Vec<PathBuf> {
+        env::var_os(ServerConfig::SHARE_ROOTS_ENV_VAR)
+            .map(|roots| {
+                env::split_paths(&roots)
+                    .filter(|root| !root.as_os_str().is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Where published files must be, the files encrypted by `--encrypt` and the uploads included
+    fn shareable_roots(&self) -> share_roots::ShareRoots {
+        let roots = if self.share_roots.is_empty() {
+            vec![PathBuf::from(&self.base_path)]
+        } else {
+            self.share_roots.clone()
+        };
+        share_roots::ShareRoots::new(roots.into_iter().chain([
+            self.data_dir.join("encrypted"),
+            self.data_dir.join(tus::COMPLETE_DIR),
+        ]))
+    }
+
+    fn host_from_env() -> String {
+        env::var(ServerConfig::HOST_ENV_VAR).unwrap_or(ServerConfig::STD_HOST.to_string())
+    }
+
+    fn ffmpeg_path_from_env() -> String {
+        env::var(ServerConfig::FFMPEG_PATH_ENV_VAR)
+            .unwrap_or(ServerConfig::STD_FFMPEG_PATH.to_string())
+    }
+
+    fn mkisofs_path_from_env() -> String {
+        env::var(ServerConfig::MKISOFS_PATH_ENV_VAR)
+            .unwrap_or(ServerConfig::STD_MKISOFS_PATH.to_string())
+    }
+
+    fn webhook_token_from_env() -> Option<String> {
+        env::var(ServerConfig::WEBHOOK_TOKEN_ENV_VAR)
+            .ok()
+            .filter(|token| !token.is_empty())
+    }
+
+    /// `0` keeps the finished tasks forever
+    fn task_retention_days_from_env() -> Option<u64> {
+        let days = env::var(ServerConfig::TASK_RETENTION_DAYS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_TASK_RETENTION_DAYS))
+            .unwrap();
+        (days > 0).then_some(days)
+    }
+
+    /// `0` keeps the deleted shares forever
+    fn trash_retention_days_from_env() -> Option<u64> {
+        let days = env::var(ServerConfig::TRASH_RETENTION_DAYS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_TRASH_RETENTION_DAYS))
+            .unwrap();
+        (days > 0).then_some(days)
+    }
+
+    fn artifact_retention_days_from_env() -> Option<u64> {
+        let days = env::var(ServerConfig::ARTIFACT_RETENTION_DAYS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_ARTIFACT_RETENTION_DAYS))
+            .unwrap();
+        (days > 0).then_some(days)
+    }
+
+    fn shutdown_grace_from_env() -> Duration {
+        let secs = env::var(ServerConfig::SHUTDOWN_GRACE_SECS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_SHUTDOWN_GRACE_SECS))
+            .unwrap();
+        Duration::from_secs(secs)
+    }
+
+    /// `0` disables the periodic backups
+    fn backup_hours_from_env() -> Option<u64> {
+        let hours = env::var(ServerConfig::BACKUP_HOURS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_BACKUP_HOURS))
+            .unwrap();
+        (hours > 0).then_some(hours)
+    }
+
+    /// At least one
+    fn backup_keep_from_env() -> usize {
+        env::var(ServerConfig::BACKUP_KEEP_ENV_VAR)
+            .map(|val| val.parse::<usize>())
+            .unwrap_or(Ok(ServerConfig::STD_BACKUP_KEEP))
+            .unwrap()
+            .max(1)
+    }
+
+    fn upload_expiry_from_env() -> Duration {
+        let hours = env::var(ServerConfig::UPLOAD_EXPIRY_HOURS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_UPLOAD_EXPIRY_HOURS))
+            .unwrap();
+        Duration::from_secs(hours * 3600)
+    }
+
+    fn disk_reserve_from_env() -> u64 {
+        let megabytes = env::var(ServerConfig::DISK_RESERVE_MB_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_DISK_RESERVE_MB))
+            .unwrap();
+        megabytes * 1_000_000
+    }
+
+    /// clamd first, then the command
+    fn scanners_from_env() -> Vec<virus_scan::Scanner> {
+        let mut scanners = vec![];
+        #[cfg(feature = "clamav")]
+        if let Ok(addr) = env::var(ServerConfig::CLAMD_ADDR_ENV_VAR) {
+            if !addr.is_empty() {
+                scanners.push(virus_scan::Scanner::Clamd(addr));
+            }
+        }
+        if let Ok(command) = env::var(ServerConfig::SCAN_COMMAND_ENV_VAR) {
+            scanners.extend(virus_scan::Scanner::command(&command));
+        }
+        scanners
+    }
+
+    /// `0` disables the periodic verification
+    fn health_check_hours_from_env() -> Option<u64> {
+        let hours = env::var(ServerConfig::HEALTH_CHECK_HOURS_ENV_VAR)
+            .map(|val| val.parse::<u64>())
+            .unwrap_or(Ok(ServerConfig::STD_HEALTH_CHECK_HOURS))
+            .unwrap();
+        (hours > 0).then_some(hours)
+    }
+
+    /// `0` for no depth limit, the exclude patterns are comma-separated
+    fn index_from_env() -> file_indexer::IndexOptions {
+        let max_depth = env::var(ServerConfig::INDEX_MAX_DEPTH_ENV_VAR)
+            .map(|val| val.parse::<usize>())
+            .unwrap_or(Ok(ServerConfig::STD_INDEX_MAX_DEPTH))
+            .unwrap();
+        file_indexer::IndexOptions {
+            max_depth: (max_depth > 0).then_some(max_depth),
+            exclude: env::var(ServerConfig::INDEX_EXCLUDE_ENV_VAR)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_string)
+                .collect(),
+            hide_dotfiles: env::var(ServerConfig::INDEX_HIDE_DOTFILES_ENV_VAR)
+                .map(|val| val.parse::<bool>())
+                .unwrap_or(Ok(false))
+                .unwrap(),
+        }
+    }
+
+    fn database_from_env() -> db_pool::DatabaseConfig {
+        db_pool::DatabaseConfig::new(
+            &env::var(ServerConfig::DB_MAX_CONNECTIONS_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_DB_MAX_CONNECTIONS.to_string()),
+            &env::var(ServerConfig::DB_MIN_CONNECTIONS_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_DB_MIN_CONNECTIONS.to_string()),
+            &env::var(ServerConfig::DB_ACQUIRE_TIMEOUT_SECS_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_DB_ACQUIRE_TIMEOUT_SECS.to_string()),
+            &env::var(ServerConfig::DB_BUSY_TIMEOUT_MS_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_DB_BUSY_TIMEOUT_MS.to_string()),
+        )
+        .unwrap()
+    }
+
+    fn assets_dir_from_env() -> Option<PathBuf> {
+        env::var_os(ServerConfig::ASSETS_DIR_ENV_VAR)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    }
+
+    /// Comma-separated, none by default: the server is the web seed
+    fn torrent_trackers_from_env() -> Vec<String> {
+        env::var(ServerConfig::TORRENT_TRACKERS_ENV_VAR)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|tracker| !tracker.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Loopback by default, an empty value trusts no proxy
+    fn trusted_proxies_from_env() -> proxy::TrustedProxies {
+        env::var(ServerConfig::TRUSTED_PROXIES_ENV_VAR)
+            .unwrap_or(proxy::STD_TRUSTED_PROXIES.to_string())
+            .parse()
+            .unwrap()
+    }
+
+    /// An empty value allows no other origin
+    fn cors_origins_from_env() -> cors::AllowedOrigins {
+        env::var(ServerConfig::CORS_ORIGINS_ENV_VAR)
+            .unwrap_or(cors::STD_CORS_ORIGINS.to_string())
+            .parse()
+            .unwrap()
+    }
+
+    fn swagger_ui_from_env() -> bool {
+        env::var(ServerConfig::SWAGGER_UI_ENV_VAR)
+            .map(|val| val.parse::<bool>())
+            .unwrap_or(Ok(false))
+            .unwrap()
+    }
+
+    /// ACME domains take precedence over certificate files
+    fn tls_from_env() -> Option<tls::TlsConfig> {
+        let var = |name: &str| env::var(name).ok().filter(|val| !val.is_empty());
+        let http_port =
+            var(ServerConfig::HTTP_PORT_ENV_VAR).map(|port| port.parse::<u16>().unwrap());
+        #[cfg(feature = "acme")]
+        if let Some(domains) = var(ServerConfig::ACME_DOMAINS_ENV_VAR) {
+            let acme_config = tls::acme::AcmeConfig::new(
+                &domains,
+                var(ServerConfig::ACME_EMAIL_ENV_VAR),
+                var(ServerConfig::ACME_DIRECTORY_ENV_VAR),
+                &Self::data_dir_from_env(),
+            )
+            .unwrap();
+            return Some(tls::TlsConfig {
+                source: tls::CertSource::Acme(acme_config),
+                http_port: Some(http_port.unwrap_or(ServerConfig::STD_ACME_HTTP_PORT)),
+            });
+        }
+        match (
+            var(ServerConfig::TLS_CERT_ENV_VAR),
+            var(ServerConfig::TLS_KEY_ENV_VAR),
+        ) {
+            (Some(cert), Some(key)) => Some(tls::TlsConfig {
+                source: tls::CertSource::Files {
+                    cert: PathBuf::from(cert),
+                    key: PathBuf::from(key),
+                },
+                http_port,
+            }),
+            (None, None) => None,
+            _ => panic!(
+                "{} and {} must be set together",
+                ServerConfig::TLS_CERT_ENV_VAR,
+                ServerConfig::TLS_KEY_ENV_VAR
+            ),
+        }
+    }
+
+    fn siem_from_env() -> Option<siem::SiemConfig> {
+        let url = env::var(ServerConfig::SIEM_URL_ENV_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let host = Self::host_from_env();
+        let hostname = Url::parse(&host)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        Some(
+            siem::SiemConfig::new(
+                &url,
+                &env::var(ServerConfig::SIEM_FORMAT_ENV_VAR)
+                    .unwrap_or(ServerConfig::STD_SIEM_FORMAT.to_string()),
+                &env::var(ServerConfig::SIEM_FACILITY_ENV_VAR)
+                    .unwrap_or(ServerConfig::STD_SIEM_FACILITY.to_string()),
+                &env::var(ServerConfig::SIEM_SEVERITIES_ENV_VAR).unwrap_or_default(),
+                &hostname,
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Pretty logs in debug builds, JSON lines in release builds
+    fn observability_from_env() -> observability::ObservabilityConfig {
+        let std_log_format = if cfg!(debug_assertions) {
+            "pretty"
+        } else {
+            "json"
+        };
+        observability::ObservabilityConfig::new(
+            &env::var(ServerConfig::OTLP_ENV_VAR).unwrap_or("true".to_string()),
+            &env::var(ServerConfig::LOG_FORMAT_ENV_VAR).unwrap_or(std_log_format.to_string()),
+            &env::var(ServerConfig::TRACE_SAMPLE_RATIO_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_TRACE_SAMPLE_RATIO.to_string()),
+            &env::var(ServerConfig::TOKIO_CONSOLE_ENV_VAR).unwrap_or("false".to_string()),
+        )
+        .unwrap()
+    }
+
+    /// At least 8 KiB
+    fn download_buffer_from_env() -> usize {
+        env::var(ServerConfig::DOWNLOAD_BUFFER_KB_ENV_VAR)
+            .map(|val| val.parse::<usize>())
+            .unwrap_or(Ok(ServerConfig::STD_DOWNLOAD_BUFFER_KB))
+            .unwrap()
+            .max(8)
+            * 1024
+    }
+
+    fn limits_from_env() -> download_limits::LimitsConfig {
+        download_limits::LimitsConfig::new(
+            &env::var(ServerConfig::MAX_DOWNLOADS_PER_CLIENT_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_MAX_DOWNLOADS_PER_CLIENT.to_string()),
+            &env::var(ServerConfig::MAX_READERS_PER_FILE_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_MAX_READERS_PER_FILE.to_string()),
+        )
+        .unwrap()
+    }
+
+    fn notifications_from_env() -> Option<notifications::NotificationConfig> {
+        let url = env::var(ServerConfig::SMTP_URL_ENV_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        Some(
+            notifications::NotificationConfig::new(
+                &url,
+                &env::var(ServerConfig::NOTIFY_FROM_ENV_VAR).unwrap_or_default(),
+                &env::var(ServerConfig::NOTIFY_TO_ENV_VAR).unwrap_or_default(),
+                &env::var(ServerConfig::NOTIFY_EVENTS_ENV_VAR)
+                    .unwrap_or(ServerConfig::STD_NOTIFY_EVENTS.to_string()),
+                &Self::host_from_env(),
+            )
+            .unwrap(),
+        )
+    }
+
+    /// One file per CPU by default
+    fn hash_concurrency_from_env() -> usize {
+        env::var(ServerConfig::HASH_CONCURRENCY_ENV_VAR)
+            .map(|val| val.parse::<usize>())
+            .unwrap_or_else(|_| Ok(std::thread::available_parallelism().map_or(1, |n| n.get())))
+            .unwrap()
+    }
+
+    fn hash_concurrency_per_root_from_env() -> usize {
+        env::var(ServerConfig::HASH_CONCURRENCY_PER_ROOT_ENV_VAR)
+            .map(|val| val.parse::<usize>())
+            .unwrap_or(Ok(ServerConfig::STD_HASH_CONCURRENCY_PER_ROOT))
+            .unwrap()
+    }
+
+    fn bandwidth_probe_from_env() -> bool {
+        env::var(ServerConfig::BANDWIDTH_PROBE_ENV_VAR)
+            .map(|val| val.parse::<bool>())
+            .unwrap_or(Ok(false))
+            .unwrap()
+    }
+
+    /// Given in megabits per second
+    fn uplink_from_env() -> Option<u64> {
+        env::var(ServerConfig::UPLINK_MBPS_ENV_VAR)
+            .ok()
+            .map(|val| val.parse::<u64>().unwrap() * 125_000)
+    }
+
+    fn data_dir_from_env() -> PathBuf {
+        PathBuf::from(
+            env::var(ServerConfig::HARDWIRE_DATA_DIR_ENV_VAR)
+                .unwrap_or(ServerConfig::STD_HARDWIRE_DATA_DIR.to_string()),
+        )
+    }
+}
+
+/// Public share pages and downloads. Every file of a share must stay downloadable from the plain
+/// links of its page, without JavaScript.
+fn share_routes(app_state: App) -> axum::Router<App> {
+    axum::Router::new()
+        .route("/s/{share_id}", get(list_shared_files))
+        .route("/s/{share_id}/{token}", head(head_file).get(download_file))
+        .route("/s/{share_id}/{token}/preview", get(preview_file))
+        .route("/s/{share_id}/{token}/inline", get(inline_file))
+        .route("/s/{share_id}/{token}/view", get(text_viewer::view_file))
+        .route("/s/{share_id}/{token}/thumb", get(thumbnails::thumb_file))
+        .route("/s/{share_id}/qr.png", get(share_qr_code))
+        .route("/s/{share_id}/share.torrent", get(share_torrent))
+        .route("/s/{share_id}/zip", get(share_zip::share_zip))
+        .route("/s/{share_id}/manifest.txt", get(download_script::manifest))
+        .route(
+            "/s/{share_id}/download.sh",
+            get(download_script::download_script),
+        )
+        .route("/s/{share_id}/seed/{*path}", get(web_seed))
+        .route("/s/{share_id}/probe", get(bandwidth_probe))
+        .route("/s/{share_id}/suggestion", get(download_suggestion))
+        .nest("/api/v1", public_api::router())
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            file_tokens::redirect_legacy_links,
+        ))
+        // Only visitors who gave the password claim a burn-after-reading share
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            share_burn::require_claim,
+        ))
+        // Checked before redirecting, the redirect discloses the token
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            share_password::require_share_password,
+        ))
+        // Before anything reads the share
+        .route_layer(middleware::from_fn_with_state(
+            app_state,
+            maintenance::downtime,
+        ))
+}
+
+async fn not_found() -> (StatusCode, Html<String>) {
+    let t = T404 {};
+    (StatusCode::NOT_FOUND, Html(t.render().unwrap()))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    pretty_env_logger::init();
+
+    let cli = Cli::parse();
+    let mut server_config = ServerConfig::new();
+    if !cli.bind.is_empty() {
+        server_config.bind_addrs = cli.bind.clone();
+    }
+    if let Some(Command::Publish {
+        name: Some(name), ..
+    }) = &cli.command
+    {
+        share_alias::check(name).map_err(|e| anyhow!("Invalid name {}: {}", name, e))?;
+    }
+    let (files, encrypt, options, json) = match cli.command {
+        Some(Command::Top {
+            url,
+            api_key,
+            interval,
+        }) => {
+            let remote =
+                remote::Remote::new(&url.unwrap_or(server_config.host), api_key.as_deref())?;
+            return top::run(&remote, Duration::from_secs(interval)).await;
+        }
+        Some(Command::Export { out }) => {
+            let (db_pool, _) = init_db(
+                server_config.data_dir.clone(),
+                &server_config.database,
+                cli.no_migrate,
+            )
+            .await?;
+            let bundle = serde_json::to_string_pretty(&share_bundle::export(&db_pool).await?)?;
+            match out {
+                Some(out) => std::fs::write(&out, bundle)
+                    .with_context(|| format!("Failed to write {}", out.display()))?,
+                None => println!("{}", bundle),
+            }
+            return Ok(());
+        }
+        Some(Command::Import { file }) => {
+            let content = std::fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            let bundle = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", file.display()))?;
+            let (db_pool, _) = init_db(
+                server_config.data_dir.clone(),
+                &server_config.database,
+                cli.no_migrate,
+            )
+            .await?;
+            let report =
+                share_bundle::import(&db_pool, &server_config.shareable_roots(), &bundle).await?;
+            println!(
+                "Imported {} shares, skipped {} existing",
+                report.imported.len(),
+                report.skipped.len()
+            );
+            for error in &report.errors {
+                eprintln!("Not imported: {}", error);
+            }
+            return Ok(());
+        }
+        Some(Command::Publish {
+            files,
+            name,
+            profile,
+            password,
+            burn_after_reading,
+            remote: Some(url),
+            api_key,
+            json,
+            ..
+        }) => {
+            let request = remote::PublishRequest {
+                files,
+                alias: name,
+                profile,
+                password,
+                burn_after_reading,
+            };
+            let remote = remote::Remote::new(&url, api_key.as_deref())?;
+            let shared_link = remote.publish(&request).await?;
+            if json {
+                let share = publish_plan::PublishedShare::fetch(&remote, &shared_link).await?;
+                println!("{}", serde_json::to_string_pretty(&share)?);
+            } else {
+                println!("Shared link: {}", shared_link);
+            }
+            return Ok(());
+        }
+        Some(Command::Publish {
+            files,
+            encrypt,
+            name,
+            profile,
+            password,
+            burn_after_reading,
+            dry_run,
+            json,
+            no_recursive,
+            strict,
+            ..
+        }) => {
+            let files = file_args::expand(&files, !no_recursive).into_files(strict)?;
+            let profile = match profile {
+                Some(profile) => share_profiles::load(&server_config.data_dir, &profile)?,
+                None => share_profiles::ShareProfile::default(),
+            };
+            let options = ShareOptions {
+                alias: name,
+                burn_after_reading,
+                ..profile.share_options(password.as_deref())?
+            };
+            if dry_run {
+                let plan =
+                    publish_plan::PublishPlan::check(&server_config.shareable_roots(), &files);
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&plan)?);
+                } else {
+                    println!("{}", plan);
+                }
+                return plan.result();
+            }
+            (files, encrypt, options, json)
+        }
+        None => (
+            file_args::expand(&cli.files, true).into_files(false)?,
+            cli.encrypt,
+            ShareOptions::default(),
+            false,
+        ),
+    };
+    let (db_pool, db_reader) = init_db(
+        server_config.data_dir.clone(),
+        &server_config.database,
+        cli.no_migrate,
+    )
+    .await?;
+    let plugins = plugins::registry();
+
+    if files.is_empty() && !cli.server {
         // let out = std::io::stdout();
         Cli::command().print_long_help()?;
     }
 
-    if !cli.files.is_empty() {
-        let shared_link = publish_files(cli.files, &server_config.host, &db_pool).await?;
-        println!("Shared link: {}", shared_link);
+    if !files.is_empty() && encrypt {
+        let key = e2ee::ShareKey::generate();
+        let encrypted_dir = server_config.data_dir.join("encrypted");
+        std::fs::create_dir_all(&encrypted_dir)?;
+        let roots = server_config.shareable_roots();
+        let mut encrypted_files = vec![];
+        for filename in &files {
+            let file = roots.resolve(std::path::Path::new(filename))?;
+            let dest = encrypted_dir.join(format!("{}.hwe", nanoid::nanoid!(10)));
+            e2ee::encrypt_file(file.path(), &dest, &key)?;
+            encrypted_files.push(roots.resolve(&dest)?);
+        }
+        let shared_link = publish_files(
+            encrypted_files,
+            &server_config.host,
+            &db_pool,
+            &plugins,
+            ShareOptions {
+                encrypted: true,
+                ..options
+            },
+        )
+        .await?;
+        if json {
+            let mut share = publish_plan::PublishedShare::load(&db_pool, &shared_link).await?;
+            share.url = format!("{}#{}", shared_link, key.to_fragment());
+            println!("{}", serde_json::to_string_pretty(&share)?);
+        } else {
+            println!("Shared link: {}#{}", shared_link, key.to_fragment());
+        }
+    } else if !files.is_empty() {
+        let roots = server_config.shareable_roots();
+        let files = files
+            .iter()
+            .map(|file| roots.resolve(std::path::Path::new(file)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let shared_link =
+            publish_files(files, &server_config.host, &db_pool, &plugins, options).await?;
+        if json {
+            let share = publish_plan::PublishedShare::load(&db_pool, &shared_link).await?;
+            println!("{}", serde_json::to_string_pretty(&share)?);
+        } else {
+            println!("Shared link: {}", shared_link);
+        }
     }
 
     if cli.server {
-        let _ = init_tracing_opentelemetry::tracing_subscriber_ext::init_subscribers()?;
-        let mut progress_manager = progress::Manager::new(db_pool.clone());
+        server_config.validate()?;
+        let addresses = server_config.listen_addresses();
+        let bind_ips = server_config.bind_ips();
+        let _tracing = observability::init(&server_config.observability)?;
+        let chaos = chaos::Chaos::from_env();
+        let mut progress_manager =
+            progress::Manager::new(db_pool.clone()).with_chaos(chaos.clone());
+        let mut plugins = plugins;
+        if let Some(notifications_config) = server_config.notifications {
+            let notifier = notifications::Notifier::new(notifications_config)?;
+            progress_manager = progress_manager.with_notifier(notifier.clone());
+            plugins = plugins.with(notifier);
+        }
+        let webhooks = outgoing_webhooks::Webhooks::new(db_pool.clone(), &server_config.host)?;
+        progress_manager = progress_manager.with_webhooks(webhooks.clone());
+        plugins = plugins.with(webhooks);
         // let base_path = "/mnt";
-        let indexer =
-            file_indexer::FileIndexer::new(&PathBuf::from(&server_config.base_path.as_str()), 60);
+        let indexer = file_indexer::FileIndexer::new(
+            &PathBuf::from(&server_config.base_path.as_str()),
+            60,
+            server_config.index.clone(),
+            chaos.clone(),
+        );
 
+        // Cancelled by the shutdown signal, the server and the task worker then get the grace
+        // period to finish. The progress manager is flushed once they are done.
+        let shutdown = CancellationToken::new();
+        let progress_flush = CancellationToken::new();
+        let mut progress_manager = progress_manager.with_shutdown(progress_flush.clone());
         let progress_channel_sender = progress_manager.sender.clone();
-        progress_manager.start_recv_thread().await;
+        let progress_stopped = progress_manager.start_recv_thread().await;
+        if let Some(siem_config) = server_config.siem {
+            siem::spawn(siem_config, progress_channel_sender.subscribe())?;
+        }
 
         // Initialize task manager
-        let (task_manager, task_receiver) = TaskManager::new(db_pool.clone());
-        let task_manager = Arc::new(task_manager);
-        
+        let share_cache = share_cache::ShareCache::new();
+        let (task_manager, task_receiver) = TaskManager::new(db_pool.clone(), share_cache.clone());
+        let task_manager = Arc::new(
+            task_manager
+                .with_chaos(chaos.clone())
+                .with_plugins(plugins.clone())
+                .with_progress(progress_channel_sender.clone()),
+        );
+
         // Start task worker
         let worker_task_manager = Arc::clone(&task_manager);
-        tokio::spawn(async move {
-            let mut worker = TaskWorker::new((*worker_task_manager).clone(), task_receiver);
-            worker.run().await;
+        let worker_shutdown = shutdown.clone();
+        let grace = server_config.shutdown_grace;
+        let worker = tokio::spawn(async move {
+            let mut worker = TaskWorker::new((*worker_task_manager).clone(), task_receiver)
+                .with_shutdown(worker_shutdown, grace);
+            worker.run().await
         });
+        match task_manager.resume_tasks().await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!("Resumed {} tasks of the previous run", count),
+            Err(e) => tracing::error!("Failed to resume the tasks of the previous run: {}", e),
+        }
+        if let Some(days) = server_config.task_retention_days {
+            task_manager.spawn_pruning(Duration::from_secs(days * 24 * 3600));
+        }
+        if let Some(days) = server_config.trash_retention_days {
+            task_manager.spawn_trash_purge(Duration::from_secs(days * 24 * 3600));
+        }
+        if let Some(days) = server_config.artifact_retention_days {
+            task_manager.spawn_artifact_cleanup(Duration::from_secs(days * 24 * 3600));
+        }
+        if let Some(hours) = server_config.backup_hours {
+            task_manager.spawn_backups(Duration::from_secs(hours * 3600));
+        }
+        task_manager.spawn_scheduler();
+        if let Some(hours) = server_config.health_check_hours {
+            task_manager.spawn_health_checks(Duration::from_secs(hours * 3600));
+        }
+        shares_file::spawn_watch(
+            db_pool.clone(),
+            share_cache.clone(),
+            PathBuf::from(&server_config.base_path),
+            &server_config.data_dir,
+        );
 
-        let app_state = App::new(db_pool, progress_channel_sender, task_manager, indexer);
+        let uploads = tus::Uploads::new(&server_config.data_dir, server_config.upload_expiry)
+            .with_reserve(server_config.disk_reserve);
+        uploads.spawn_cleanup();
 
-        let app = axum::Router::new()
-            .route("/s/{share_id}", get(list_shared_files))
-            .route("/s/{share_id}/{file_id}", head(head_file).get(download_file))
-            .route("/admin/tasks", post(create_task))
-            .route("/admin/tasks/{task_id}", get(get_task_status))
+        let maintenance = maintenance::Maintenance::load(&db_pool).await?;
+        let settings = settings::Settings::load(
+            &db_pool,
+            settings::RuntimeSettings::new(&server_config.limits),
+        )
+        .await?;
+        let app_state = App::new(
+            db_pool,
+            db_reader,
+            progress_channel_sender,
+            task_manager,
+            indexer,
+            share_cache,
+            chaos,
+        )
+        .with_download_buffer(server_config.download_buffer)
+        .with_active_progress(progress_manager.active())
+        .with_uploads(uploads)
+        .with_maintenance(maintenance)
+        .with_settings(settings);
+
+        let app = share_routes(app_state.clone())
             .route("/healthcheck", get(healthcheck))
-            .nest_service("/assets", ServeDir::new("dist/"))
-            .route("/admin/live_update", get(ws_handler))
-            .route("/admin/list_files", get(list_files))
-            .route("/admin/create_shared_link", post(create_shared_link))
-            .with_state(app_state)
+            .route("/healthz", get(probes::healthz))
+            .route("/readyz", get(probes::readyz))
+            .nest_service(
+                "/assets",
+                assets::router(server_config.assets_dir.as_deref()),
+            )
+            .nest("/hooks", webhook::router(app_state.clone()))
+            .merge(openapi::router(server_config.swagger_ui))
+            .nest(
+                "/admin/api/v1",
+                admin::router(app_state.clone())
+                    .layer(middleware::from_fn(api_version::negotiate_version)),
+            )
+            // unversioned paths kept for existing scripts and the SPA
+            .nest(
+                "/admin/api",
+                admin::router(app_state.clone()).layer(middleware::from_fn_with_state(
+                    api_version::Deprecation {
+                        successor_prefix: "/admin/api/v1",
+                        sunset: None,
+                    },
+                    api_version::deprecated,
+                )),
+            )
+            .nest(
+                "/admin",
+                admin::legacy_router(app_state.clone()).layer(middleware::from_fn_with_state(
+                    api_version::Deprecation {
+                        successor_prefix: "/admin/api/v1",
+                        sunset: None,
+                    },
+                    api_version::deprecated,
+                )),
+            )
+            .with_state(app_state.clone())
             // include trace context as header into the response
             .layer(OtelInResponseLayer)
             //start OpenTelemetry trace on incoming request
             .layer(OtelAxumLayer::default())
-            .layer(
-                CorsLayer::new()
-                    .allow_origin(AllowOrigin::predicate(
-                        |origin: &HeaderValue, _request_parts: &RequestParts| {
-                            origin.as_bytes().ends_with(b".pestel.me")
-                                || match Url::parse(std::str::from_utf8(origin.as_ref()).unwrap()) {
-                                    Ok(url) => url.host_str().unwrap().eq("localhost"),
-                                    Err(_) => false,
-                                }
-                        },
-                    ))
-                    .allow_headers([AUTHORIZATION, ACCEPT])
-                    .allow_credentials(true),
-            );
-
-        let bind_adress = format!("0.0.0.0:{}", server_config.port);
-        let listener = tokio::net::TcpListener::bind(bind_adress).await.unwrap();
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_signal())
+            .layer(server_config.cors_origins.layer())
+            .layer(middleware::from_fn(tus::discovery));
+
+        // Layers of the router run after routing, aliases must be rewritten before
+        let app = middleware::from_fn_with_state(app_state.clone(), share_alias::resolve_aliases)
+            .layer(app);
+        // The client is identified before anything logs it
+        let app =
+            middleware::from_fn_with_state(Arc::clone(&app_state.config), proxy::resolve_client)
+                .layer(app);
+
+        let listener = listen::Listener::bind(&addresses, server_config.socket_mode).await?;
+        for address in &addresses {
+            tracing::info!("Listening on {}", address);
+        }
+        let signal = {
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown_signal().await;
+                shutdown.cancel();
+            }
+        };
+        let mut server = if let Some(tls_config) = server_config.tls {
+            let listener =
+                tls::listen(listener, tls_config, &server_config.host, &bind_ips).await?;
+            tokio::spawn(
+                axum::serve(
+                    listener,
+                    axum::ServiceExt::<Request>::into_make_service_with_connect_info::<
+                        listen::PeerAddr,
+                    >(app),
+                )
+                .with_graceful_shutdown(signal)
+                .into_future(),
+            )
+        } else {
+            tokio::spawn(
+                axum::serve(
+                    listener,
+                    axum::ServiceExt::<Request>::into_make_service_with_connect_info::<
+                        listen::PeerAddr,
+                    >(app),
+                )
+                .with_graceful_shutdown(signal)
+                .into_future(),
+            )
+        };
+        tokio::select! {
+            result = &mut server => result??,
+            _ = shutdown.cancelled() => {
+                match tokio::time::timeout(server_config.shutdown_grace, &mut server).await {
+                    Ok(result) => result??,
+                    Err(_) => tracing::warn!("Cutting the downloads still running after the grace period"),
+                }
+            }
+        }
+        let interrupted = worker.await.unwrap_or(false);
+        progress_flush.cancel();
+        if tokio::time::timeout(Duration::from_secs(5), progress_stopped)
             .await
-            .unwrap();
+            .is_err()
+        {
+            tracing::error!("Failed to record the downloads in progress");
+        }
+        if interrupted {
+            // The blocking work of the interrupted task would keep the runtime from stopping
+            std::process::exit(0);
+        }
     }
     Ok(())
 }
@@ -611,40 +2453,956 @@ async fn shutdown_signal() {
     opentelemetry::global::shutdown_tracer_provider();
 }
 
-async fn create_task(
-    State(app_state): State<App>,
-    Json(input): Json<TaskInput>,
-) -> Result<Json<String>, Response> {
-    let task_id = app_state
-        .task_manager
-        .create_task(input)
-        .await
-        .map_err(|e| {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use tower::ServiceExt;
+
+    /// `path` checked against its own directory as the only share root
+    fn shareable(path: impl AsRef<std::path::Path>) -> share_roots::ShareableFile {
+        let path = path.as_ref();
+        share_roots::ShareRoots::new([path.parent().unwrap().to_path_buf()])
+            .resolve(path)
+            .unwrap()
+    }
+
+    async fn test_app(base_path: &std::path::Path, chaos: chaos::Chaos) -> Result<App> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let mut progress_manager = progress::Manager::new(db.clone()).with_chaos(chaos.clone());
+        progress_manager.start_recv_thread().await;
+        let share_cache = share_cache::ShareCache::new();
+        let (task_manager, _) = TaskManager::new(db.clone(), share_cache.clone());
+        Ok(App::new(
+            db.clone(),
+            db,
+            progress_manager.sender.clone(),
+            Arc::new(task_manager.with_chaos(chaos.clone())),
+            file_indexer::FileIndexer::new(base_path, 3600, Default::default(), chaos.clone()),
+            share_cache,
+            chaos,
+        ))
+    }
+
+    async fn get_body(app: &axum::Router, uri: &str) -> Result<(StatusCode, Vec<u8>)> {
+        let response = app
+            .clone()
+            .oneshot(Request::get(uri).body(Body::empty())?)
+            .await?;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        Ok((status, body.to_vec()))
+    }
+
+    /// Crawl a share page as a browser with JavaScript disabled would: only the server-rendered
+    /// links can be followed, and they must lead to every file of the share
+    #[tokio::test]
+    async fn test_share_page_works_without_javascript() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut files = vec![];
+        for (name, content) in [
+            ("a.txt", "hello"),
+            ("b c.txt", "world"),
+            ("d.bin", "\0\x01"),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            files.push((path.to_string_lossy().into_owned(), content));
+        }
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            files.iter().map(|(path, _)| shareable(path)).collect(),
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+        let (status, page) = get_body(&app, share_path).await?;
+        assert_eq!(status, StatusCode::OK);
+        let page = String::from_utf8(page)?;
+        assert!(!page.contains("<script"));
+        assert!(page.contains(r#"<html class="dark" lang="en">"#));
+
+        let links: Vec<&str> = page
+            .split(r#"href=""#)
+            .skip(1)
+            .filter_map(|s| s.split('"').next())
+            .filter_map(|href| href.strip_prefix(host.as_str()))
+            .filter(|href| {
+                href.starts_with(share_path)
+                    && !href.ends_with("/preview")
+                    && !href.ends_with("/view")
+            })
+            .collect();
+        assert_eq!(links.len(), files.len());
+        let mut contents = vec![];
+        for link in links {
+            let (status, body) = get_body(&app, link).await?;
+            assert_eq!(status, StatusCode::OK);
+            contents.push(String::from_utf8(body)?);
+        }
+        contents.sort();
+        let mut expected: Vec<&str> = files.iter().map(|(_, content)| *content).collect();
+        expected.sort();
+        assert_eq!(contents, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_downtime() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            vec![shareable(&path)],
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state.clone());
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+        let share_id = share_path.trim_start_matches("/s/");
+        let update = |enabled| maintenance::MaintenanceUpdate {
+            enabled,
+            message: Some("Replacing a disk".to_string()),
+            retry_after_secs: Some(120),
+        };
+
+        app_state
+            .maintenance
+            .set(&app_state.db_pool, &update(true))
+            .await?;
+        let response = app
+            .clone()
+            .oneshot(Request::get(share_path).body(Body::empty())?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[axum::http::header::RETRY_AFTER], "120");
+        let page = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let page = String::from_utf8(page.to_vec())?;
+        assert!(page.contains("Replacing a disk"));
+        assert!(page.contains("Back in about 2 minutes"));
+        let (status, body) = get_body(&app, &format!("/api/v1/shares/{}", share_id)).await?;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let error: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(error["error"]["message"], "The server is under maintenance");
+
+        app_state
+            .maintenance
+            .set(&app_state.db_pool, &update(false))
+            .await?;
+        let (status, _) = get_body(&app, share_path).await?;
+        assert_eq!(status, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_page_pages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = vec![];
+        for (name, size) in [("a.txt", 3), ("b.iso", 1), ("c.mkv", 2)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "x".repeat(size))?;
+            paths.push(shareable(&path));
+        }
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            paths,
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+
+        let (status, page) = get_body(
+            &app,
+            &format!("{}?sort=size&order=desc&per_page=2", share_path),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        let page = String::from_utf8(page)?;
+        assert!(page.contains("Page 1 of 2"));
+        assert!(page.find("a.txt").unwrap() < page.find("c.mkv").unwrap());
+        assert!(!page.contains("b.iso"));
+        assert!(page.contains("href=\"?sort=size&amp;order=desc&amp;page=2&amp;per_page=2\""));
+
+        let (status, body) = get_body(
+            &app,
+            &format!("{}.json?sort=size&page=2&per_page=2", share_path),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        let listing: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(
             (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to create task: {}", e),
+                listing["total_files"].as_u64(),
+                listing["total_pages"].as_u64()
+            ),
+            (Some(3), Some(2))
+        );
+        assert!(listing["files"][0]["name"]
+            .as_str()
+            .unwrap()
+            .ends_with("a.txt"));
+        assert_eq!(listing["files"][0]["size"], 3);
+        assert_eq!(listing["files"][0]["type"], "text");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(share_path)
+                    .header(ACCEPT, "application/json")
+                    .body(Body::empty())?,
             )
-                .into_response()
-        })?;
+            .await?;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        let (status, _) = get_body(&app, "/s/missing.json").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
 
-    Ok(Json(task_id))
-}
+    #[tokio::test]
+    async fn test_public_share_api() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.iso");
+        std::fs::write(&path, "iso")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        for (alias, password) in [("holidays", None), ("private", Some("s3cret"))] {
+            publish_files(
+                vec![shareable(&path)],
+                &host,
+                &app_state.db_pool,
+                &app_state.plugins,
+                ShareOptions {
+                    alias: Some(alias.to_string()),
+                    password_hash: password
+                        .map(|password| share_password::hash_password(password).unwrap()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        }
+        let (share_id, token): (String, String) = sqlx::query_as(
+            "SELECT id, token FROM share_links JOIN share_link_files ON share_link_id = id WHERE alias = 'holidays'",
+        )
+        .fetch_one(&app_state.db_pool)
+        .await?;
+        sqlx::query("INSERT INTO file_digests (file_id, algorithm, digest) SELECT file_id, 'sha256', 'abcd' FROM share_link_files WHERE token = ?")
+            .bind(&token)
+            .execute(&app_state.db_pool)
+            .await?;
+        let app = axum::Router::new().fallback_service(
+            middleware::from_fn_with_state(app_state.clone(), share_alias::resolve_aliases)
+                .layer(share_routes(app_state.clone()).with_state(app_state)),
+        );
 
-async fn get_task_status(
-    State(app_state): State<App>,
-    Path(task_id): Path<String>,
-) -> Result<Json<Task>, Response> {
-    let task = app_state
-        .task_manager
-        .get_task_status(&task_id)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to get task status: {}", e),
+        let (status, body) = get_body(&app, "/api/v1/shares/holidays").await?;
+        assert_eq!(status, StatusCode::OK);
+        let share: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(share["schema_version"], public_api::SCHEMA_VERSION);
+        assert_eq!(share["share_id"], share_id.as_str());
+        assert!(share["expires_at"].is_null());
+        let file = &share["files"][0];
+        assert_eq!(
+            (file["size"].as_i64(), file["type"].as_str()),
+            (Some(3), Some("other"))
+        );
+        assert_eq!(file["digests"]["sha256"], "abcd");
+        assert_eq!(
+            file["url"],
+            format!("{}/s/{}/{}", host, share_id, token).as_str()
+        );
+
+        let (status, _) = get_body(&app, "/api/v1/shares/private").await?;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = get_body(&app, "/api/v1/shares/missing").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    /// The admin routes of a share take its alias as well as its id
+    #[tokio::test]
+    async fn test_share_settings_by_alias() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.iso");
+        std::fs::write(&path, "iso")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let options = ShareOptions {
+            alias: Some("holidays".to_string()),
+            ..Default::default()
+        };
+        publish_files(
+            vec![shareable(&path)],
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            options,
+        )
+        .await?;
+        let share_id: String = sqlx::query_scalar("SELECT id FROM share_links")
+            .fetch_one(&app_state.db_pool)
+            .await?;
+        let app = admin::router(app_state.clone()).with_state(app_state.clone());
+        let put = |uri: &str, body: serde_json::Value| {
+            let request = Request::put(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()));
+            app.clone().oneshot(request.unwrap())
+        };
+        let cached = || async {
+            app_state
+                .share_cache
+                .get(&share_id, &app_state.db_reader)
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        assert!(cached().await.files[0].short_filename.ends_with("/a.iso"));
+
+        let rules = serde_json::json!([{"rule": "add_prefix", "prefix": "ACME "}]);
+        let response = put("/shares/holidays/filename_rules", rules).await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(cached().await.files[0].short_filename, "ACME a.iso");
+
+        let response = put(
+            "/shares/holidays/notify",
+            serde_json::json!({"notify": true}),
+        )
+        .await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let notify: bool = sqlx::query_scalar("SELECT notify FROM share_links")
+            .fetch_one(&app_state.db_pool)
+            .await?;
+        assert!(notify);
+
+        let response = put(
+            "/shares/holidays/branding",
+            serde_json::json!({"title": "Holidays"}),
+        )
+        .await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(cached().await.branding.title.as_deref(), Some("Holidays"));
+
+        let response = put(
+            "/shares/holidays/readme",
+            serde_json::json!({"markdown": "Photos of the trip"}),
+        )
+        .await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(cached().await.readme.is_some());
+
+        let response = put(
+            "/shares/missing/notify",
+            serde_json::json!({"notify": true}),
+        )
+        .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    /// Only the routes of the baseline are served under the unversioned `/admin`
+    #[tokio::test]
+    async fn test_legacy_admin_routes() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let app = admin::legacy_router(app_state.clone()).with_state(app_state);
+        let (status, _) = get_body(&app, "/list_files").await?;
+        assert_eq!(status, StatusCode::OK);
+        for uri in ["/index/status", "/keys", "/cache/shares"] {
+            let (status, _) = get_body(&app, uri).await?;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_page_previews() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = vec![];
+        for (name, content) in [
+            ("photo.png", "\u{89}PNG"),
+            ("page.html", "<script></script>"),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            paths.iter().map(shareable).collect(),
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let tokens: Vec<String> =
+            sqlx::query_scalar("SELECT token FROM share_link_files ORDER BY file_id")
+                .fetch_all(&app_state.db_pool)
+                .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+        let (_, page) = get_body(&app, share_path).await?;
+        let page = String::from_utf8(page)?;
+        assert!(page.contains(&format!(
+            r#"<img src="{}{}/{}/thumb?w=320""#,
+            host, share_path, tokens[0]
+        )));
+        assert!(!page.contains(&format!("{}/inline", tokens[1])));
+        // Only images have thumbnails
+        let (status, _) = get_body(&app, &format!("{}/{}/thumb", share_path, tokens[1])).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(page.contains("17 B"));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(format!("{}/{}/inline", share_path, tokens[0])).body(Body::empty())?,
             )
-                .into_response()
-        })?;
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[CONTENT_DISPOSITION], "inline");
+        // HTML would run scripts on the origin of the server
+        let (status, _) = get_body(&app, &format!("{}/{}/inline", share_path, tokens[1])).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        // It is read escaped in the text view instead
+        assert!(page.contains(&format!("{}/view", tokens[1])));
+        assert!(!page.contains(&format!("{}/view", tokens[0])));
+        let (status, view) = get_body(&app, &format!("{}/{}/view", share_path, tokens[1])).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(String::from_utf8(view)?.contains("&lt;script&gt;&lt;/script&gt;"));
+        let (status, _) = get_body(&app, &format!("{}/{}/view", share_path, tokens[0])).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, qr_code) = get_body(&app, &format!("{}/qr.png", share_path)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(qr_code.starts_with(b"\x89PNG"));
+        Ok(())
+    }
 
-    Ok(Json(task))
+    #[tokio::test]
+    async fn test_share_page_gallery() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = vec![];
+        for (name, content) in [
+            ("a.jpg", "first"),
+            ("b.mp4", "second"),
+            ("notes.txt", "third"),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            paths.iter().map(shareable).collect(),
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let tokens: Vec<String> =
+            sqlx::query_scalar("SELECT token FROM share_link_files ORDER BY file_id")
+                .fetch_all(&app_state.db_pool)
+                .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+        let (_, page) = get_body(&app, share_path).await?;
+        let page = String::from_utf8(page)?;
+        assert!(page.contains(&format!(r#"action="{}{}/zip""#, host, share_path)));
+        assert!(page.contains(&format!(r#"value="{}""#, tokens[2])));
+        assert!(page.contains(&format!("{}/thumb?w=320", tokens[0])));
+        assert!(
+            page.contains("?sort=name&amp;order=asc&amp;page=1&amp;per_page=50&amp;layout=list")
+        );
+        let (_, page) = get_body(&app, &format!("{}?layout=list", share_path)).await?;
+        assert!(!String::from_utf8(page)?.contains("/zip"));
+
+        let (status, zip) = get_body(
+            &app,
+            &format!("{}/zip?file={}&file={}", share_path, tokens[0], tokens[2]),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zip))?;
+        let mut names = archive.file_names().collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        assert_eq!(names, ["a.jpg", "notes.txt"]);
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("notes.txt")?, &mut content)?;
+        assert_eq!(content, "third");
+        let (_, zip) = get_body(&app, &format!("{}/zip", share_path)).await?;
+        assert_eq!(zip::ZipArchive::new(std::io::Cursor::new(zip))?.len(), 3);
+        let (status, _) = get_body(&app, &format!("{}/zip?file=unknown", share_path)).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    /// Files are served at their token, the id links of the files shared before tokens redirect
+    #[tokio::test]
+    async fn test_file_tokens() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let mut share_paths = vec![];
+        for _ in 0..2 {
+            let shared_link = publish_files(
+                vec![shareable(&path)],
+                &host,
+                &app_state.db_pool,
+                &app_state.plugins,
+                ShareOptions::default(),
+            )
+            .await?;
+            share_paths.push(shared_link.strip_prefix(&host).unwrap().to_string());
+        }
+        let links: Vec<(i64, String)> =
+            sqlx::query_as("SELECT file_id, token FROM share_link_files ORDER BY rowid")
+                .fetch_all(&app_state.db_pool)
+                .await?;
+        assert_eq!(links[0].1.len(), 16);
+        assert_ne!(links[0].1, links[1].1);
+        let app = share_routes(app_state.clone()).with_state(app_state.clone());
+
+        let (status, body) = get_body(&app, &format!("{}/{}", share_paths[0], links[0].1)).await?;
+        assert_eq!((status, body), (StatusCode::OK, b"hello".to_vec()));
+        // The token of a file only works in its share
+        let (status, _) = get_body(&app, &format!("{}/{}", share_paths[1], links[0].1)).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get_body(&app, &format!("{}/{}", share_paths[0], links[0].0)).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        sqlx::query("UPDATE share_link_files SET legacy_id_link = 1 WHERE token = ?")
+            .bind(&links[0].1)
+            .execute(&app_state.db_pool)
+            .await?;
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get(format!("{}/{}/inline?x=1", share_paths[0], links[0].0))
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[axum::http::header::LOCATION],
+            format!("{}{}/{}/inline?x=1", host, share_paths[0], links[0].1).as_str()
+        );
+        Ok(())
+    }
+
+    /// Throughput of the unthrottled downloads against the small chunks of the rate limited ones,
+    /// measured without a rate limit on both. Run with
+    /// `cargo test --release bench_download_paths -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn bench_download_paths() -> Result<()> {
+        use futures::StreamExt;
+        const SIZE: u64 = 1 << 30;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("big.bin");
+        std::fs::File::create(&path)?.set_len(SIZE)?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let mut share_paths = vec![];
+        // A limit no client reaches still takes the throttled path
+        for max_bytes_per_sec in [None, Some(i64::MAX)] {
+            let options = ShareOptions {
+                max_bytes_per_sec,
+                ..ShareOptions::default()
+            };
+            let shared_link = publish_files(
+                vec![shareable(&path)],
+                &host,
+                &app_state.db_pool,
+                &app_state.plugins,
+                options,
+            )
+            .await?;
+            share_paths.push(shared_link.strip_prefix(&host).unwrap().to_string());
+        }
+        let tokens: Vec<String> =
+            sqlx::query_scalar("SELECT token FROM share_link_files ORDER BY rowid")
+                .fetch_all(&app_state.db_pool)
+                .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        for (label, (share_path, token)) in ["large buffers", "small chunks"]
+            .into_iter()
+            .zip(share_paths.iter().zip(&tokens))
+        {
+            let start = std::time::Instant::now();
+            let response = app
+                .clone()
+                .oneshot(Request::get(format!("{}/{}", share_path, token)).body(Body::empty())?)
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            let mut body = response.into_body().into_data_stream();
+            let mut received = 0;
+            while let Some(chunk) = body.next().await {
+                received += chunk?.len() as u64;
+            }
+            assert_eq!(received, SIZE);
+            let elapsed = start.elapsed().as_secs_f64();
+            println!("{}: {:.2} GB/s", label, SIZE as f64 / elapsed / 1e9);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_alias() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello")?;
+        let path = path.to_string_lossy().into_owned();
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let publish = |alias: &str| {
+            publish_files(
+                vec![shareable(&path)],
+                &host,
+                &app_state.db_pool,
+                &app_state.plugins,
+                ShareOptions {
+                    alias: Some(alias.to_string()),
+                    ..ShareOptions::default()
+                },
+            )
+        };
+        let shared_link = publish("vacation-2024").await?;
+        assert_eq!(shared_link, format!("{}/s/vacation-2024", host));
+        assert!(publish("vacation-2024")
+            .await
+            .unwrap_err()
+            .is::<share_alias::AliasTaken>());
+
+        let app = axum::Router::new().fallback_service(
+            middleware::from_fn_with_state(app_state.clone(), share_alias::resolve_aliases)
+                .layer(share_routes(app_state.clone()).with_state(app_state.clone())),
+        );
+        let (status, page) = get_body(&app, "/s/vacation-2024").await?;
+        assert_eq!(status, StatusCode::OK);
+        let token: String = sqlx::query_scalar("SELECT token FROM share_link_files")
+            .fetch_one(&app_state.db_pool)
+            .await?;
+        assert!(String::from_utf8(page)?.contains(&format!("/{}", token)));
+        let (status, body) = get_body(&app, &format!("/s/vacation-2024/{}", token)).await?;
+        assert_eq!((status, body), (StatusCode::OK, b"hello".to_vec()));
+        let (status, _) = get_body(&app, "/s/vacation-2025").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    /// Slow reads and lost progress updates delay a download but neither corrupt it nor its record
+    #[tokio::test]
+    async fn test_download_under_injected_faults() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("big.bin");
+        let content: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &content)?;
+        let chaos = chaos::Chaos::new(&[
+            (chaos::Fault::SlowRead, 1.0),
+            (chaos::Fault::DroppedEvent, 1.0),
+        ]);
+        let app_state = test_app(dir.path(), chaos).await?;
+        let db = app_state.db_pool.clone();
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            vec![shareable(&path)],
+            &host,
+            &db,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let token: String = sqlx::query_scalar("SELECT token FROM share_link_files")
+            .fetch_one(&db)
+            .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let started = std::time::Instant::now();
+        let link = format!("{}/{}", shared_link.strip_prefix(&host).unwrap(), token);
+        let (status, body) = get_body(&app, &link).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, content);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let mut records = vec![];
+        for _ in 0..50 {
+            records = sqlx::query_as::<_, (String, i64)>("SELECT status, file_size FROM download")
+                .fetch_all(&db)
+                .await?;
+            if !records.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(
+            records,
+            vec![("complete".to_string(), content.len() as i64)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_mirrors_get() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, "hello")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            vec![shareable(&path)],
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let token: String = sqlx::query_scalar("SELECT token FROM share_link_files")
+            .fetch_one(&app_state.db_pool)
+            .await?;
+        let uri = format!("{}/{}", shared_link.strip_prefix(&host).unwrap(), token);
+        let app = share_routes(app_state.clone()).with_state(app_state.clone());
+        let send = |method: &str, headers: &[(&str, &str)]| {
+            let mut request = Request::builder().method(method).uri(&uri);
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for range in [&[][..], &[("range", "bytes=1-3")][..]] {
+            let head = send("HEAD", range).await?;
+            let get = send("GET", range).await?;
+            assert_eq!(head.status(), get.status());
+            assert_eq!(head.headers(), get.headers());
+        }
+        let get = send("GET", &[]).await?;
+        let etag = get.headers()[ETAG].to_str()?.to_string();
+        assert_eq!(get.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(get.headers()[ACCEPT_RANGES], "bytes");
+
+        for method in ["HEAD", "GET"] {
+            let response = send(method, &[("if-none-match", &etag)]).await?;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        }
+        // A range of another version of the file is not resumed
+        let response = send("GET", &[("range", "bytes=1-3"), ("if-range", "\"other\"")]).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("GET", &[("range", "bytes=1-3"), ("if-range", &etag)]).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], b"ell");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_script() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = vec![];
+        for name in ["b.iso", "a.iso"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, name)?;
+            paths.push(path.to_string_lossy().into_owned());
+        }
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            paths.iter().map(shareable).collect(),
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let tokens: Vec<String> =
+            sqlx::query_scalar("SELECT token FROM share_link_files ORDER BY file_id DESC")
+                .fetch_all(&app_state.db_pool)
+                .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let share_path = shared_link.strip_prefix(&host).unwrap();
+        let (status, manifest) = get_body(&app, &format!("{}/manifest.txt", share_path)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let urls: Vec<String> = tokens
+            .iter()
+            .map(|token| format!("{}/{}\n", shared_link, token))
+            .collect();
+        assert_eq!(String::from_utf8(manifest)?, urls.concat());
+        let (status, script) = get_body(&app, &format!("{}/download.sh", share_path)).await?;
+        assert_eq!(status, StatusCode::OK);
+        let script = String::from_utf8(script)?;
+        assert!(script.contains(&format!(
+            "fetch 'a.iso' '{}/{}' '5' ''",
+            shared_link, tokens[0]
+        )));
+        let (status, _) = get_body(&app, "/s/unknown/download.sh").await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn test_requested_range() {
+        let range = |value: &str, size| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RANGE, value.parse().unwrap());
+            requested_range(&headers, size, "\"etag\"")
+        };
+        assert_eq!(
+            requested_range(&HeaderMap::new(), 10, "\"etag\""),
+            Some(0..10)
+        );
+        assert_eq!(range("bytes=2-4", 10), Some(2..5));
+        assert_eq!(range("bytes=2-", 10), Some(2..10));
+        assert_eq!(range("bytes=2-99", 10), Some(2..10));
+        assert_eq!(range("bytes=-4", 10), Some(6..10));
+        assert_eq!(range("bytes=-99", 10), Some(0..10));
+        assert_eq!(range("bytes=10-", 10), None);
+        assert_eq!(range("bytes=-0", 10), None);
+        assert_eq!(range("bytes=0-", 0), None);
+        // Ignored
+        assert_eq!(range("bytes=4-2", 10), Some(0..10));
+        assert_eq!(range("bytes=0-1,4-5", 10), Some(0..10));
+        assert_eq!(range("items=0-1", 10), Some(0..10));
+        assert_eq!(range("bytes=a-", 0), Some(0..0));
+    }
+
+    #[tokio::test]
+    async fn test_media_seeking() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("Film.MP4");
+        std::fs::write(&path, "0123456789")?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            vec![shareable(&path)],
+            &host,
+            &app_state.db_pool,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let token: String = sqlx::query_scalar("SELECT token FROM share_link_files")
+            .fetch_one(&app_state.db_pool)
+            .await?;
+        let uri = format!("{}/{}", shared_link.strip_prefix(&host).unwrap(), token);
+        let app = share_routes(app_state.clone()).with_state(app_state);
+        let get = |uri: String, range: Option<&str>| {
+            let mut request = Request::get(uri);
+            if let Some(range) = range {
+                request = request.header(RANGE, range);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // Players only seek in files announcing ranges from the first response
+        let response = get(uri.clone(), None).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/mp4");
+        assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+        assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+        // The index at the end of an MP4
+        let response = get(uri.clone(), Some("bytes=-3")).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 7-9/10");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], b"789");
+        let response = get(uri.clone(), Some("bytes=4-")).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 4-9/10");
+        let response = get(uri.clone(), Some("bytes=10-")).await?;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = get(format!("{}/inline", uri), Some("bytes=0-1")).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/mp4");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ranged_download_over_4_gib() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("disk.img");
+        // Sparse, only the last bytes are written
+        let size: u64 = 5 * 1024 * 1024 * 1024 + 4;
+        let file = std::fs::File::create(&path)?;
+        file.set_len(size - 4)?;
+        drop(file);
+        std::io::Write::write_all(
+            &mut std::fs::OpenOptions::new().append(true).open(&path)?,
+            b"tail",
+        )?;
+        let app_state = test_app(dir.path(), chaos::Chaos::default()).await?;
+        let db = app_state.db_pool.clone();
+        let host = ServerConfig::new().host;
+        let shared_link = publish_files(
+            vec![shareable(&path)],
+            &host,
+            &db,
+            &app_state.plugins,
+            ShareOptions::default(),
+        )
+        .await?;
+        let token: String = sqlx::query_scalar("SELECT token FROM share_link_files")
+            .fetch_one(&db)
+            .await?;
+        let app = share_routes(app_state.clone()).with_state(app_state);
+
+        let link = format!("{}/{}", shared_link.strip_prefix(&host).unwrap(), token);
+        let request = Request::get(link)
+            .header(RANGE, format!("bytes={}-", size - 6))
+            .body(Body::empty())?;
+        let response = app.oneshot(request).await?;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers()[CONTENT_RANGE],
+            format!("bytes {}-{}/{}", size - 6, size - 1, size).as_str()
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], b"\0\0tail");
+
+        let mut records = vec![];
+        for _ in 0..50 {
+            records = sqlx::query_as::<_, (String, i64, i64)>(
+                "SELECT status, file_size, bytes_covered FROM download",
+            )
+            .fetch_all(&db)
+            .await?;
+            if !records.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(records, vec![("partial".to_string(), size as i64, 6)]);
+        Ok(())
+    }
 }
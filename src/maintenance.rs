@@ -0,0 +1,227 @@
+//! Maintenance mode.
+//!
+//! While it is on, the public routes (share pages, downloads and the public API) answer 503 with
+//! `Retry-After`: the downtime page of `templates/maintenance.html`, or the JSON error envelope for
+//! the public API. The admin API, the health probes and the assets keep working, so the mode can be
+//! turned off again. It is stored in the `settings` table, a restart keeps it.
+
+use anyhow::Result;
+use askama::Template;
+use axum::extract::{Request, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Html, IntoResponse, Response};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::sync::{Arc, RwLock};
+use utoipa::ToSchema;
+
+use crate::error::AppError;
+use crate::validation::{Validate, Validator};
+use crate::App;
+
+const SETTING_KEY: &str = "maintenance";
+/// `Retry-After` when the update doesn't give one
+pub const STD_RETRY_AFTER_SECS: u64 = 600;
+const MAX_RETRY_AFTER_SECS: u64 = 7 * 24 * 3600;
+const MAX_MESSAGE_LEN: usize = 1000;
+
+/// The maintenance mode while it is on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MaintenanceMode {
+    /// Shown on the downtime page instead of the default one
+    pub message: Option<String>,
+    /// Seconds announced in `Retry-After`
+    pub retry_after_secs: u64,
+    /// When it was turned on
+    pub since: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    /// Absent while the server is up
+    #[serde(flatten)]
+    pub mode: Option<MaintenanceMode>,
+}
+
+/// Body of `PUT /maintenance`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MaintenanceUpdate {
+    pub enabled: bool,
+    pub message: Option<String>,
+    /// `STD_RETRY_AFTER_SECS` by default
+    pub retry_after_secs: Option<u64>,
+}
+
+impl Validate for MaintenanceUpdate {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(message) = &self.message {
+            v.check(
+                message.chars().count() <= MAX_MESSAGE_LEN,
+                "message",
+                "must be at most 1000 characters",
+            );
+        }
+        if let Some(secs) = self.retry_after_secs {
+            v.check(
+                (1..=MAX_RETRY_AFTER_SECS).contains(&secs),
+                "retry_after_secs",
+                "must be between 1 and 604800",
+            );
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "maintenance.html")]
+struct DowntimePage<'a> {
+    message: Option<&'a str>,
+    retry_after: String,
+}
+
+/// The mode as last set, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct Maintenance {
+    mode: Arc<RwLock<Option<MaintenanceMode>>>,
+}
+
+impl Maintenance {
+    /// The mode stored in `settings`, off when it never was set
+    pub async fn load(db: &SqlitePool) -> Result<Self> {
+        let value = sqlx::query_scalar!("SELECT value FROM settings WHERE key = ?", SETTING_KEY)
+            .fetch_optional(db)
+            .await?;
+        let mode = value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()?;
+        Ok(Maintenance {
+            mode: Arc::new(RwLock::new(mode)),
+        })
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        let mode = self.mode.read().unwrap().clone();
+        MaintenanceStatus {
+            enabled: mode.is_some(),
+            mode,
+        }
+    }
+
+    /// Turn the mode on or off, storing it before it applies
+    pub async fn set(
+        &self,
+        db: &SqlitePool,
+        update: &MaintenanceUpdate,
+    ) -> Result<MaintenanceStatus> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let mode = update.enabled.then(|| MaintenanceMode {
+            message: update.message.clone().filter(|message| !message.is_empty()),
+            retry_after_secs: update.retry_after_secs.unwrap_or(STD_RETRY_AFTER_SECS),
+            since: now,
+        });
+        match &mode {
+            Some(mode) => {
+                let value = serde_json::to_string(mode)?;
+                sqlx::query!(
+                    r#"INSERT INTO settings (key, value, updated_at) VALUES (?, ?, ?)
+                    ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at"#,
+                    SETTING_KEY,
+                    value,
+                    now
+                )
+                .execute(db)
+                .await?;
+            }
+            None => {
+                sqlx::query!("DELETE FROM settings WHERE key = ?", SETTING_KEY)
+                    .execute(db)
+                    .await?;
+            }
+        }
+        *self.mode.write().unwrap() = mode;
+        Ok(self.status())
+    }
+}
+
+/// "10 minutes", rounded up to the minute, or to the hour past two hours
+fn format_wait(secs: u64) -> String {
+    let minutes = secs.div_ceil(60).max(1);
+    match minutes {
+        1 => "a minute".to_string(),
+        2..=120 => format!("{} minutes", minutes),
+        _ => format!("{} hours", minutes.div_ceil(60)),
+    }
+}
+
+/// Answer the public routes with the downtime page while the mode is on
+pub async fn downtime(State(app_state): State<App>, request: Request, next: Next) -> Response {
+    let Some(mode) = app_state.maintenance.status().mode else {
+        return next.run(request).await;
+    };
+    let mut response = if request.uri().path().starts_with("/api/") {
+        AppError::Unavailable("The server is under maintenance".to_string()).into_response()
+    } else {
+        let page = DowntimePage {
+            message: mode.message.as_deref(),
+            retry_after: format_wait(mode.retry_after_secs),
+        };
+        match page.render() {
+            Ok(page) => (StatusCode::SERVICE_UNAVAILABLE, Html(page)).into_response(),
+            Err(e) => AppError::Internal(e.into()).into_response(),
+        }
+    };
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(mode.retry_after_secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_set_and_load() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let maintenance = Maintenance::load(&db).await?;
+        assert!(!maintenance.status().enabled);
+
+        let update = MaintenanceUpdate {
+            enabled: true,
+            message: Some("Moving to a new disk".to_string()),
+            retry_after_secs: None,
+        };
+        let status = maintenance.set(&db, &update).await?;
+        assert!(status.enabled);
+        let mode = status.mode.unwrap();
+        assert_eq!(mode.retry_after_secs, STD_RETRY_AFTER_SECS);
+        assert_eq!(
+            Maintenance::load(&db).await?.status().mode,
+            Some(mode.clone())
+        );
+        assert_eq!(
+            serde_json::to_value(maintenance.status())?["message"],
+            "Moving to a new disk"
+        );
+
+        let update = MaintenanceUpdate {
+            enabled: false,
+            message: None,
+            retry_after_secs: None,
+        };
+        assert!(!maintenance.set(&db, &update).await?.enabled);
+        assert!(!Maintenance::load(&db).await?.status().enabled);
+
+        assert_eq!(format_wait(30), "a minute");
+        assert_eq!(format_wait(600), "10 minutes");
+        assert_eq!(format_wait(5 * 3600), "5 hours");
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! Namespaces of the admin API, for a server shared by several admins.
+//!
+//! API keys minted with a `namespace` only see and manage the shares created with a key of the
+//! same namespace, and the downloads of those shares. The rest of the admin API (tasks, keys,
+//! webhooks, the audit log...) is reserved to owner keys, minted without a namespace, which see
+//! every share. Shares created by the CLI, the webhook or a task belong to no namespace.
+
+use anyhow::Result;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sqlx::SqlitePool;
+
+use crate::error::AppError;
+
+/// Shares an admin API request may see, set by [`crate::api_keys::require_api_key`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Namespace {
+    /// Every share, for owner keys and while the admin API requires no key
+    Owner,
+    Member(String),
+}
+
+impl Namespace {
+    pub fn from_name(name: Option<String>) -> Self {
+        match name {
+            Some(name) => Namespace::Member(name),
+            None => Namespace::Owner,
+        }
+    }
+
+    /// Namespace of the shares created by the request, none for the owner
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Namespace::Owner => None,
+            Namespace::Member(name) => Some(name),
+        }
+    }
+
+    /// Whether the share `share_id`, or the share with this alias, is visible
+    pub async fn sees_share(&self, db: &SqlitePool, share_id: &str) -> Result<bool> {
+        let Namespace::Member(name) = self else {
+            return Ok(true);
+        };
+        let visible = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM share_links WHERE (id = ?1 OR alias = ?1) AND namespace = ?2) AS "visible!: bool""#,
+            share_id,
+            name
+        )
+        .fetch_one(db)
+        .await?;
+        Ok(visible)
+    }
+
+    /// Answer as if the share did not exist when it isn't visible
+    pub async fn check_share(&self, db: &SqlitePool, share_id: &str) -> Result<(), AppError> {
+        if self.sees_share(db, share_id).await? {
+            Ok(())
+        } else {
+            Err(AppError::NotFound(format!("No share {}", share_id)))
+        }
+    }
+}
+
+/// Extracts the [`Namespace`] of an admin API request, refused without one
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Namespace>()
+            .cloned()
+            .ok_or_else(|| AppError::Forbidden("No namespace".to_string()))
+    }
+}
+
+/// Refuse the requests of namespaced keys
+pub async fn require_owner(namespace: Namespace, request: Request, next: Next) -> Response {
+    match namespace {
+        Namespace::Owner => next.run(request).await,
+        Namespace::Member(_) => {
+            AppError::Forbidden("Reserved to owner API keys".to_string()).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_sees_share() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, alias, namespace)
+            VALUES ('s1', -1, 0, 'holidays', 'alice'), ('s2', -1, 0, NULL, 'bob'), ('s3', -1, 0, NULL, NULL)",
+        )
+        .execute(&db)
+        .await?;
+
+        let alice = Namespace::from_name(Some("alice".to_string()));
+        assert!(alice.sees_share(&db, "s1").await?);
+        assert!(alice.sees_share(&db, "holidays").await?);
+        assert!(!alice.sees_share(&db, "s2").await?);
+        assert!(!alice.sees_share(&db, "s3").await?);
+        assert!(!alice.sees_share(&db, "s4").await?);
+        for share_id in ["s1", "s2", "s3"] {
+            assert!(Namespace::Owner.sees_share(&db, share_id).await?);
+        }
+        Ok(())
+    }
+}
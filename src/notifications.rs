@@ -0,0 +1,397 @@
+//! Email notifications.
+//!
+//! When `HARDWIRE_SMTP_URL` is set, the addresses of `HARDWIRE_NOTIFY_TO` get an email when every
+//! file of a share was downloaded, for the shares which opted in, and when a task failed. Each
+//! share is notified once, the first time all its files were downloaded after its creation. The
+//! messages are rendered from the `templates/email` templates.
+
+use anyhow::{anyhow, bail, Context, Result};
+use askama::Template;
+use chrono::{DateTime, Utc};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use sqlx::SqlitePool;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::plugins::{Plugin, TaskOutcome};
+use crate::worker::TaskStatus;
+
+/// Events emailed, each can be turned off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Events {
+    pub share_downloaded: bool,
+    pub task_failed: bool,
+}
+
+impl Events {
+    /// Comma separated event names such as `share_downloaded,task_failed`
+    fn parse(names: &str) -> Result<Self> {
+        let mut events = Events {
+            share_downloaded: false,
+            task_failed: false,
+        };
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "share_downloaded" => events.share_downloaded = true,
+                "task_failed" => events.task_failed = true,
+                other => bail!(
+                    "Unknown notification event {}, expected share_downloaded or task_failed",
+                    other
+                ),
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NotificationConfig {
+    /// `smtp://` upgraded with STARTTLS when offered, `?tls=required` to insist, or `smtps://`
+    pub smtp_url: String,
+    pub from: Mailbox,
+    pub to: Vec<Mailbox>,
+    pub events: Events,
+    /// Base URL of the links in the messages
+    pub host: String,
+}
+
+impl NotificationConfig {
+    pub fn new(smtp_url: &str, from: &str, to: &str, events: &str, host: &str) -> Result<Self> {
+        let to = to
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                address
+                    .parse::<Mailbox>()
+                    .with_context(|| format!("Invalid notification recipient {}", address))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if to.is_empty() {
+            bail!("Email notifications need at least one recipient");
+        }
+        // Sent by the first recipient when no sender is given
+        let from = if from.is_empty() {
+            to[0].clone()
+        } else {
+            from.parse::<Mailbox>()
+                .with_context(|| format!("Invalid notification sender {}", from))?
+        };
+        Ok(Self {
+            smtp_url: smtp_url.to_string(),
+            from,
+            to,
+            events: Events::parse(events)?,
+            host: host.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "email/share_downloaded.txt")]
+struct ShareDownloadedEmail<'a> {
+    link: &'a str,
+    files: &'a [String],
+    created_at: String,
+    expires_at: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "email/task_failed.txt")]
+struct TaskFailedEmail<'a> {
+    task_id: &'a str,
+    task_type: &'a str,
+    error: Option<&'a str>,
+    host: &'a str,
+}
+
+fn format_date(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .map(|date| date.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+#[derive(Clone)]
+enum Mailer {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    #[cfg(test)]
+    Stub(lettre::transport::stub::AsyncStubTransport),
+}
+
+/// Sends the notifications, cheap to clone
+#[derive(Clone)]
+pub struct Notifier {
+    config: Arc<NotificationConfig>,
+    mailer: Mailer,
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notifier")
+            .field("to", &self.config.to)
+            .field("events", &self.config.events)
+            .finish()
+    }
+}
+
+impl Notifier {
+    pub fn new(config: NotificationConfig) -> Result<Self> {
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::from_url(&config.smtp_url)
+            .map_err(|e| anyhow!("Invalid SMTP URL: {}", e))?
+            .build();
+        Ok(Self {
+            config: Arc::new(config),
+            mailer: Mailer::Smtp(mailer),
+        })
+    }
+
+    async fn send(&self, subject: &str, body: String) -> Result<()> {
+        let mut builder = Message::builder()
+            .from(self.config.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.config.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder.body(body)?;
+        match &self.mailer {
+            Mailer::Smtp(mailer) => {
+                mailer.send(message).await?;
+            }
+            #[cfg(test)]
+            Mailer::Stub(mailer) => mailer.send(message).await?,
+        }
+        Ok(())
+    }
+
+    /// Notify the opted-in shares containing `file_path` whose files are now all downloaded.
+    /// Called once a download of the file is complete.
+    pub async fn file_downloaded(&self, db: &SqlitePool, file_path: &str) -> Result<()> {
+        if !self.config.events.share_downloaded {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp();
+        // Marked in the same statement so concurrent downloads can't send it twice
+        let shares = sqlx::query!(
+            r#"UPDATE share_links SET notified_at = ?1
+            WHERE notify AND notified_at IS NULL
+            AND id IN (SELECT share_link_id FROM share_link_files JOIN files ON files.id = share_link_files.file_id WHERE files.path = ?2)
+            AND NOT EXISTS (
+                SELECT 1 FROM share_link_files JOIN files ON files.id = share_link_files.file_id
+                WHERE share_link_files.share_link_id = share_links.id
+                AND NOT EXISTS (
+                    SELECT 1 FROM download WHERE download.file_path = files.path
+                    AND download.status = 'complete' AND download.started_at >= share_links.created_at
+                )
+            )
+            RETURNING id AS "id!", alias, created_at AS "created_at!", expiration AS "expiration!""#,
+            now,
+            file_path
+        )
+        .fetch_all(db)
+        .await?;
+
+        for share in shares {
+            let files = sqlx::query_scalar!(
+                r#"SELECT files.path FROM files JOIN share_link_files ON share_link_files.file_id = files.id
+                WHERE share_link_files.share_link_id = ? ORDER BY files.id"#,
+                share.id
+            )
+            .fetch_all(db)
+            .await?;
+            let link = format!(
+                "{}/s/{}",
+                self.config.host,
+                share.alias.as_deref().unwrap_or(&share.id)
+            );
+            let body = ShareDownloadedEmail {
+                link: &link,
+                files: &files,
+                created_at: format_date(share.created_at),
+                expires_at: (share.expiration >= 0).then(|| format_date(share.expiration)),
+            }
+            .render()?;
+            self.send(&format!("Share {} fully downloaded", link), body)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn task_failed(&self, task: &TaskOutcome) -> Result<()> {
+        let body = TaskFailedEmail {
+            task_id: &task.task_id,
+            task_type: &task.task_type,
+            error: task.error.as_deref(),
+            host: &self.config.host,
+        }
+        .render()?;
+        self.send(&format!("Task {} failed", task.task_type), body)
+            .await
+    }
+}
+
+impl Plugin for Notifier {
+    fn name(&self) -> &'static str {
+        "notifications"
+    }
+
+    fn task_completed(&self, task: &TaskOutcome) {
+        if !self.config.events.task_failed || !matches!(task.status, TaskStatus::Failed) {
+            return;
+        }
+        let notifier = self.clone();
+        let task = task.clone();
+        tokio::spawn(async move {
+            if let Err(e) = notifier.task_failed(&task).await {
+                tracing::warn!(
+                    "Failed to email the failure of task {}: {}",
+                    task.task_id,
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Opt the share in or out of the email sent once all its files were downloaded, `false` when
+/// the share does not exist
+pub async fn set_share_notify(db: &SqlitePool, share_id: &str, notify: bool) -> Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE share_links SET notify = ?, notified_at = NULL WHERE id = ?",
+        notify,
+        share_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::transport::stub::AsyncStubTransport;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn config(events: &str) -> NotificationConfig {
+        NotificationConfig::new(
+            "smtp://localhost:2525",
+            "",
+            "me@example.com, Backup <backup@example.com>",
+            events,
+            "https://files.example.com/",
+        )
+        .unwrap()
+    }
+
+    fn stub_notifier(config: NotificationConfig) -> (Notifier, AsyncStubTransport) {
+        let stub = AsyncStubTransport::new_ok();
+        let notifier = Notifier {
+            config: Arc::new(config),
+            mailer: Mailer::Stub(stub.clone()),
+        };
+        (notifier, stub)
+    }
+
+    #[test]
+    fn test_config() {
+        let config = config("share_downloaded");
+        assert_eq!(config.to.len(), 2);
+        assert_eq!(config.from.email.to_string(), "me@example.com");
+        assert_eq!(config.host, "https://files.example.com");
+        assert!(config.events.share_downloaded && !config.events.task_failed);
+        assert!(NotificationConfig::new("smtp://localhost", "", "", "", "").is_err());
+        assert!(
+            NotificationConfig::new("smtp://localhost", "", "me@example.com", "upload", "")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_task_failed() -> Result<()> {
+        let (notifier, stub) = stub_notifier(config("task_failed"));
+        let task = TaskOutcome {
+            task_id: "t1".to_string(),
+            task_type: "ChecksumShare".to_string(),
+            status: TaskStatus::Failed,
+            output: None,
+            error: Some("No such file".to_string()),
+        };
+        notifier.task_failed(&task).await?;
+        let messages = stub.messages().await;
+        assert_eq!(messages.len(), 1);
+        let (envelope, message) = &messages[0];
+        assert_eq!(envelope.to().len(), 2);
+        assert!(message.contains("Subject: Task ChecksumShare failed"));
+        assert!(message.contains("No such file"));
+        assert!(message.contains("https://files.example.com/admin/api/v1/tasks/t1"));
+        Ok(())
+    }
+
+    async fn download(db: &SqlitePool, path: &str, started_at: i64) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO download (file_path, status, started_at) VALUES (?, 'complete', ?)",
+            path,
+            started_at
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_share_downloaded_once() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query!(
+            "INSERT INTO share_links (id, expiration, created_at, alias) VALUES ('share', -1, 100, 'holidays')"
+        )
+        .execute(&db)
+        .await?;
+        for (id, path) in [(1, "/data/a.txt"), (2, "/data/b.txt")] {
+            sqlx::query!(
+                "INSERT INTO files (id, path, file_size, sha256) VALUES (?, ?, 5, '')",
+                id,
+                path
+            )
+            .execute(&db)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO share_link_files (share_link_id, file_id) VALUES ('share', ?)",
+                id
+            )
+            .execute(&db)
+            .await?;
+        }
+        let (notifier, stub) = stub_notifier(config("share_downloaded"));
+        // Not opted in
+        download(&db, "/data/a.txt", 200).await?;
+        download(&db, "/data/b.txt", 200).await?;
+        notifier.file_downloaded(&db, "/data/b.txt").await?;
+        assert!(stub.messages().await.is_empty());
+
+        assert!(set_share_notify(&db, "share", true).await?);
+        assert!(!set_share_notify(&db, "unknown", true).await?);
+        sqlx::query!("DELETE FROM download").execute(&db).await?;
+        // Downloaded before the share was created
+        download(&db, "/data/a.txt", 50).await?;
+        download(&db, "/data/b.txt", 200).await?;
+        notifier.file_downloaded(&db, "/data/b.txt").await?;
+        assert!(stub.messages().await.is_empty());
+
+        download(&db, "/data/a.txt", 300).await?;
+        notifier.file_downloaded(&db, "/data/a.txt").await?;
+        notifier.file_downloaded(&db, "/data/a.txt").await?;
+        let messages = stub.messages().await;
+        assert_eq!(messages.len(), 1);
+        let message = &messages[0].1;
+        assert!(message.contains("https://files.example.com/s/holidays"));
+        assert!(message.contains("- /data/a.txt"));
+        assert!(message.contains("- /data/b.txt"));
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Logs and traces of the server.
+//!
+//! Spans are exported with OTLP, configured by the standard `OTEL_*` variables, unless
+//! `HARDWIRE_OTLP` is `false`; an exporter failing to start only disables the export. Logs are
+//! written to stdout as pretty, compact text or JSON lines. `HARDWIRE_TRACE_SAMPLE_RATIO` keeps a
+//! share of the traces, those started by a sampled upstream service being kept regardless, and
+//! `HARDWIRE_TOKIO_CONSOLE` serves the tokio console on `127.0.0.1:6669` (the task details need a
+//! build with `RUSTFLAGS="--cfg tokio_unstable"`).
+
+use anyhow::{bail, Context, Result};
+use init_tracing_opentelemetry::tracing_subscriber_ext::build_loglevel_filter_layer;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Multi-line, with the span events
+    Pretty,
+    /// One line per event
+    Text,
+    /// One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservabilityConfig {
+    pub otlp: bool,
+    pub log_format: LogFormat,
+    /// Share of the traces started here which are exported, from 0 to 1
+    pub sample_ratio: f64,
+    pub enable_console_subscriber: bool,
+}
+
+impl ObservabilityConfig {
+    pub fn new(otlp: &str, log_format: &str, sample_ratio: &str, console: &str) -> Result<Self> {
+        let log_format = match log_format {
+            "pretty" => LogFormat::Pretty,
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            other => bail!(
+                "Unknown log format {}, expected pretty, text or json",
+                other
+            ),
+        };
+        let sample_ratio = sample_ratio
+            .parse::<f64>()
+            .ok()
+            .filter(|ratio| (0.0..=1.0).contains(ratio))
+            .with_context(|| {
+                format!(
+                    "Invalid trace sample ratio {}, expected 0 to 1",
+                    sample_ratio
+                )
+            })?;
+        Ok(ObservabilityConfig {
+            otlp: otlp
+                .parse()
+                .with_context(|| format!("Invalid HARDWIRE_OTLP {}", otlp))?,
+            log_format,
+            sample_ratio,
+            enable_console_subscriber: console
+                .parse()
+                .with_context(|| format!("Invalid HARDWIRE_TOKIO_CONSOLE {}", console))?,
+        })
+    }
+}
+
+/// Exports the spans still buffered when dropped
+pub struct TracingGuard(TracerProvider);
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        for result in self.0.force_flush() {
+            if let Err(e) = result {
+                eprintln!("Failed to export the last spans: {}", e);
+            }
+        }
+    }
+}
+
+fn log_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync + 'static>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use tracing_subscriber::fmt::format::FmtSpan;
+    let layer =
+        tracing_subscriber::fmt::layer().with_timer(tracing_subscriber::fmt::time::uptime());
+    match format {
+        LogFormat::Pretty => Box::new(
+            layer
+                .pretty()
+                .with_line_number(true)
+                .with_thread_names(true)
+                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE),
+        ),
+        LogFormat::Text => Box::new(layer.compact()),
+        LogFormat::Json => Box::new(layer.json()),
+    }
+}
+
+/// Install the subscriber of `config`. The returned guard flushes the spans not exported yet
+/// when dropped, `None` without OTLP.
+pub fn init(config: &ObservabilityConfig) -> Result<Option<TracingGuard>> {
+    // Logs the setup of the exporter
+    let setup = tracing_subscriber::registry()
+        .with(build_loglevel_filter_layer())
+        .with(log_layer(config.log_format));
+    let otlp = tracing::subscriber::with_default(setup, || {
+        if !config.otlp {
+            return None;
+        }
+        let sampler =
+            Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+        let provider = init_tracing_opentelemetry::otlp::init_tracerprovider(
+            init_tracing_opentelemetry::resource::DetectResource::default().build(),
+            |builder| builder.with_sampler(sampler),
+        );
+        match provider.map_err(anyhow::Error::from).and_then(|provider| {
+            init_tracing_opentelemetry::init_propagator()?;
+            Ok(provider)
+        }) {
+            Ok(provider) => Some(provider),
+            Err(e) => {
+                tracing::error!(
+                    "Failed to start the OTLP exporter, spans are not exported: {}",
+                    e
+                );
+                None
+            }
+        }
+    });
+
+    let (otel_layer, guard) = match otlp {
+        Some(provider) => {
+            use opentelemetry::trace::TracerProvider as _;
+            let layer = tracing_opentelemetry::layer()
+                .with_error_records_to_exceptions(true)
+                .with_tracer(provider.tracer(""));
+            opentelemetry::global::set_tracer_provider(provider.clone());
+            (
+                Some(layer.with_filter(build_loglevel_filter_layer())),
+                Some(TracingGuard(provider)),
+            )
+        }
+        None => (None, None),
+    };
+    let console = config.enable_console_subscriber.then(|| {
+        console_subscriber::ConsoleLayer::builder()
+            .with_default_env()
+            .spawn()
+    });
+    // Filtered by layer, the console needs the tokio spans which `RUST_LOG` leaves out
+    let subscriber = tracing_subscriber::registry()
+        .with(console)
+        .with(otel_layer)
+        .with(log_layer(config.log_format).with_filter(build_loglevel_filter_layer()));
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let config = ObservabilityConfig::new("false", "json", "0.25", "true")?;
+        assert!(!config.otlp && config.enable_console_subscriber);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.sample_ratio, 0.25);
+
+        assert!(ObservabilityConfig::new("true", "xml", "1", "false").is_err());
+        assert!(ObservabilityConfig::new("true", "text", "1.5", "false").is_err());
+        assert!(ObservabilityConfig::new("yes", "text", "1", "false").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! OpenAPI 3 document of the admin API and of the public share API.
+//!
+//! The document is derived from the handlers and their payloads, and served without an API key at
+//! `GET /admin/api/openapi.json` since it describes no data, so the frontend can generate its typed
+//! client from a running server. With `HARDWIRE_SWAGGER_UI=true`, `/admin/api/docs` renders it
+//! with Swagger UI, whose assets are loaded from a CDN by the browser.
+
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use utoipa::openapi::path::{Operation, PathItem};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{Content, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+use crate::error::ErrorEnvelope;
+use crate::{admin, public_api, App};
+
+const ADMIN_PREFIX: &str = "/admin/api/v1";
+const SECURITY_SCHEME: &str = "api_key";
+const SWAGGER_UI_VERSION: &str = "5.17.14";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "hardwire"),
+    nest(
+        (path = "/admin/api/v1", api = admin::AdminApi),
+        (path = "/api/v1", api = public_api::PublicApi)
+    ),
+    components(schemas(ErrorEnvelope)),
+    modifiers(&ErrorResponses, &AdminSecurity)
+)]
+pub struct ApiDoc;
+
+/// Operations of a path, the APIs only use these methods
+fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.head,
+        &mut item.patch,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Every operation may answer the error envelope of [`crate::error`]
+struct ErrorResponses;
+
+impl Modify for ErrorResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let error = ResponseBuilder::new()
+            .description("Error, see `error.code`")
+            .content(
+                "application/json",
+                Content::new(Some(Ref::from_schema_name("ErrorEnvelope"))),
+            )
+            .build();
+        for item in openapi.paths.paths.values_mut() {
+            for operation in operations(item) {
+                operation
+                    .responses
+                    .responses
+                    .insert("default".to_string(), error.clone().into());
+            }
+        }
+    }
+}
+
+/// The admin routes take an API key as `Authorization: Bearer hw_...`, see [`crate::api_keys`]
+struct AdminSecurity;
+
+impl Modify for AdminSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                SECURITY_SCHEME,
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("hw_...")
+                        .build(),
+                ),
+            );
+        }
+        let requirement = SecurityRequirement::new(SECURITY_SCHEME, Vec::<String>::new());
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with(ADMIN_PREFIX) {
+                continue;
+            }
+            for operation in operations(item) {
+                operation.security = Some(vec![requirement.clone()]);
+            }
+        }
+    }
+}
+
+pub fn router(swagger_ui: bool) -> Router<App> {
+    let router = Router::new().route("/admin/api/openapi.json", get(openapi_json));
+    if swagger_ui {
+        router.route("/admin/api/docs", get(swagger_ui_page))
+    } else {
+        router
+    }
+}
+
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+async fn swagger_ui_page() -> Html<String> {
+    Html(format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>hardwire API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@{version}/swagger-ui-bundle.js"></script>
+<script>
+SwaggerUIBundle({{ url: "openapi.json", dom_id: "#swagger-ui" }});
+</script>
+</body>
+</html>
+"##,
+        version = SWAGGER_UI_VERSION
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = doc["paths"].as_object().unwrap();
+        let share = &paths["/admin/api/v1/shares/{share_id}"];
+        assert!(share["get"].is_object() && share["delete"].is_object());
+        assert_eq!(
+            share["get"]["security"][0][SECURITY_SCHEME],
+            serde_json::json!([])
+        );
+        assert_eq!(
+            share["get"]["responses"]["default"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ErrorEnvelope"
+        );
+        let days = &paths["/admin/api/v1/shares/{share_id}/stats"]["get"]["parameters"][1];
+        assert_eq!(
+            (&days["name"], &days["in"], &days["required"]),
+            (&"days".into(), &"query".into(), &false.into())
+        );
+        assert_eq!(
+            paths["/admin/api/v1/tasks"]["post"]["requestBody"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            "#/components/schemas/TaskInput"
+        );
+
+        let public = &paths["/api/v1/shares/{share_id}"]["get"];
+        assert!(public.get("security").is_none());
+        assert_eq!(
+            public["responses"]["200"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/ShareInfo"
+        );
+        let schemas = doc["components"]["schemas"].as_object().unwrap();
+        for schema in [
+            "ShareInfo",
+            "SharedFileInfo",
+            "FileInfo",
+            "Task",
+            "ErrorEnvelope",
+        ] {
+            assert!(schemas.contains_key(schema), "missing {}", schema);
+        }
+    }
+}
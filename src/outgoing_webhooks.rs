@@ -0,0 +1,554 @@
+//! Outbound webhooks.
+//!
+//! Endpoints registered with `POST /admin/api/v1/webhooks` receive a `POST` for each event they
+//! subscribed to: a share created, a file downloaded completely, a task completed or failed. The
+//! body is signed with the secret of the endpoint in `X-Hardwire-Signature: sha256=<hex>`, the
+//! HMAC-SHA256 of the raw body. Failed deliveries are retried with an exponential backoff, and the
+//! outcome of the last one is kept with the endpoint. The `slack` and `discord` formats post the
+//! one-line summary of the event, as their incoming webhooks expect.
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::SqlitePool;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::plugins::{NewShare, Plugin, TaskOutcome};
+use crate::validation::{Validate, Validator};
+use crate::worker::TaskStatus;
+
+pub const SIGNATURE_HEADER: &str = "X-Hardwire-Signature";
+pub const EVENT_HEADER: &str = "X-Hardwire-Event";
+pub const DELIVERY_HEADER: &str = "X-Hardwire-Delivery";
+const SECRET_LEN: usize = 32;
+const MAX_ATTEMPTS: u32 = 6;
+/// Doubled after each failed attempt: 2s, 4s, 8s... about a minute overall
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(2);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ShareCreated,
+    /// Every byte of a file was sent, over one or more requests
+    DownloadCompleted,
+    TaskCompleted,
+    /// A task failed without further retries
+    TaskFailed,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::ShareCreated => "share_created",
+            EventKind::DownloadCompleted => "download_completed",
+            EventKind::TaskCompleted => "task_completed",
+            EventKind::TaskFailed => "task_failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The event with its data
+    #[default]
+    Json,
+    /// `{"text": summary}`
+    Slack,
+    /// `{"content": summary}`
+    Discord,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Slack => "slack",
+            Format::Discord => "discord",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Format::Json),
+            "slack" => Some(Format::Slack),
+            "discord" => Some(Format::Discord),
+            _ => None,
+        }
+    }
+
+    fn body(self, delivery_id: &str, event: &Event) -> serde_json::Value {
+        match self {
+            Format::Json => serde_json::json!({
+                "id": delivery_id,
+                "event": event.kind,
+                "timestamp": event.timestamp,
+                "text": event.summary,
+                "data": event.data,
+            }),
+            Format::Slack => serde_json::json!({ "text": event.summary }),
+            Format::Discord => serde_json::json!({ "content": event.summary }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Endpoint {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub format: Format,
+    pub created_at: i64,
+    pub last_delivery_at: Option<i64>,
+    /// `delivered`, or the error of the last attempt
+    pub last_status: Option<String>,
+}
+
+/// A freshly registered endpoint, the only time its secret is returned
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RegisteredEndpoint {
+    #[serde(flatten)]
+    pub endpoint: Endpoint,
+    pub secret: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewEndpoint {
+    pub url: String,
+    pub events: Vec<EventKind>,
+    #[serde(default)]
+    pub format: Format,
+    /// Generated when unset
+    pub secret: Option<String>,
+}
+
+impl Validate for NewEndpoint {
+    fn validate(&self, v: &mut Validator) {
+        let valid_url = url::Url::parse(&self.url)
+            .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+        v.check(valid_url, "url", "must be an http or https URL");
+        v.check(!self.events.is_empty(), "events", "must not be empty");
+        if let Some(secret) = &self.secret {
+            v.check(
+                secret.len() >= 16,
+                "secret",
+                "must be at least 16 characters",
+            );
+        }
+    }
+}
+
+/// Register an endpoint, its secret is returned once and can't be retrieved later
+pub async fn register(db: &SqlitePool, new_endpoint: &NewEndpoint) -> Result<RegisteredEndpoint> {
+    let mut events = vec![];
+    for event in &new_endpoint.events {
+        if !events.contains(event) {
+            events.push(*event);
+        }
+    }
+    let endpoint = Endpoint {
+        id: nanoid::nanoid!(10),
+        url: new_endpoint.url.clone(),
+        events,
+        format: new_endpoint.format,
+        created_at: chrono::offset::Utc::now().timestamp(),
+        last_delivery_at: None,
+        last_status: None,
+    };
+    let secret = new_endpoint
+        .secret
+        .clone()
+        .unwrap_or_else(|| nanoid::nanoid!(SECRET_LEN));
+    let events = serde_json::to_string(&endpoint.events)?;
+    let format = endpoint.format.name();
+    sqlx::query!(
+        r#"INSERT INTO webhook_endpoints (id, url, secret, events, format, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+        endpoint.id,
+        endpoint.url,
+        secret,
+        events,
+        format,
+        endpoint.created_at
+    )
+    .execute(db)
+    .await?;
+    Ok(RegisteredEndpoint { endpoint, secret })
+}
+
+/// Remove an endpoint, `false` when it does not exist
+pub async fn remove(db: &SqlitePool, endpoint_id: &str) -> Result<bool> {
+    let result = sqlx::query!("DELETE FROM webhook_endpoints WHERE id = ?", endpoint_id)
+        .execute(db)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Every endpoint with its secret, oldest first
+async fn load(db: &SqlitePool) -> Result<Vec<(Endpoint, String)>> {
+    let rows = sqlx::query!(
+        r#"SELECT id AS "id!", url, secret, events, format, created_at, last_delivery_at, last_status
+        FROM webhook_endpoints ORDER BY created_at, id"#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let endpoint = Endpoint {
+                id: row.id,
+                url: row.url,
+                events: serde_json::from_str(&row.events).ok()?,
+                format: Format::from_name(&row.format)?,
+                created_at: row.created_at,
+                last_delivery_at: row.last_delivery_at,
+                last_status: row.last_status,
+            };
+            Some((endpoint, row.secret))
+        })
+        .collect())
+}
+
+/// Every endpoint, without the secrets
+pub async fn list(db: &SqlitePool) -> Result<Vec<Endpoint>> {
+    Ok(load(db)
+        .await?
+        .into_iter()
+        .map(|(endpoint, _)| endpoint)
+        .collect())
+}
+
+/// Hex HMAC-SHA256 of `body` keyed by `secret`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Delay before the attempt following `attempt` failed ones
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub timestamp: i64,
+    /// One line for humans, what the chat formats post
+    pub summary: String,
+    pub data: serde_json::Value,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, summary: String, data: serde_json::Value) -> Self {
+        Event {
+            kind,
+            timestamp: chrono::offset::Utc::now().timestamp(),
+            summary,
+            data,
+        }
+    }
+}
+
+/// Sends the events to the endpoints subscribed to them, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    db: SqlitePool,
+    client: reqwest::Client,
+    /// Base URL of the share links
+    host: String,
+    base_retry_delay: Duration,
+}
+
+impl Webhooks {
+    pub fn new(db: SqlitePool, host: &str) -> Result<Self> {
+        Ok(Webhooks {
+            db,
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+            host: host.trim_end_matches('/').to_string(),
+            base_retry_delay: BASE_RETRY_DELAY,
+        })
+    }
+
+    /// Deliver `event` in the background
+    pub fn emit(&self, event: Event) {
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = webhooks.dispatch(&event).await {
+                tracing::error!(
+                    "Failed to dispatch the {} webhooks: {}",
+                    event.kind.name(),
+                    e
+                );
+            }
+        });
+    }
+
+    async fn dispatch(&self, event: &Event) -> Result<()> {
+        let endpoints = load(&self.db).await?;
+        let deliveries = endpoints
+            .iter()
+            .filter(|(endpoint, _)| endpoint.events.contains(&event.kind))
+            .map(|(endpoint, secret)| self.deliver(endpoint, secret, event));
+        futures::future::join_all(deliveries).await;
+        Ok(())
+    }
+
+    /// Post `event` to `endpoint` until it is accepted, refused or out of attempts, and store the
+    /// outcome
+    async fn deliver(&self, endpoint: &Endpoint, secret: &str, event: &Event) {
+        let delivery_id = uuid::Uuid::new_v4().to_string();
+        let body = endpoint.format.body(&delivery_id, event).to_string();
+        let signature = format!("sha256={}", sign(secret, body.as_bytes()));
+        let mut attempt = 0;
+        let status = loop {
+            attempt += 1;
+            let response = self
+                .client
+                .post(&endpoint.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.kind.name())
+                .header(DELIVERY_HEADER, &delivery_id)
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+            let (error, retryable) = match response {
+                Ok(response) if response.status().is_success() => break "delivered".to_string(),
+                Ok(response) => {
+                    let status = response.status();
+                    // Other client errors won't go away, e.g. a deleted Discord webhook
+                    let retryable = status.is_server_error()
+                        || status == StatusCode::REQUEST_TIMEOUT
+                        || status == StatusCode::TOO_MANY_REQUESTS;
+                    (format!("HTTP {}", status), retryable)
+                }
+                Err(e) => (e.to_string(), true),
+            };
+            if !retryable || attempt >= MAX_ATTEMPTS {
+                tracing::warn!(
+                    "Webhook {} not delivered to {} after {} attempts: {}",
+                    event.kind.name(),
+                    endpoint.url,
+                    attempt,
+                    error
+                );
+                break error;
+            }
+            tokio::time::sleep(retry_delay(self.base_retry_delay, attempt)).await;
+        };
+
+        let now = chrono::offset::Utc::now().timestamp();
+        if let Err(e) = sqlx::query!(
+            "UPDATE webhook_endpoints SET last_delivery_at = ?, last_status = ? WHERE id = ?",
+            now,
+            status,
+            endpoint.id
+        )
+        .execute(&self.db)
+        .await
+        {
+            tracing::error!("Failed to store the delivery to {}: {}", endpoint.url, e);
+        }
+    }
+}
+
+impl Plugin for Webhooks {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn share_stored(&self, share: &NewShare) {
+        let link = format!(
+            "{}/s/{}",
+            self.host,
+            share.alias.as_deref().unwrap_or(&share.share_id)
+        );
+        self.emit(Event::new(
+            EventKind::ShareCreated,
+            format!(
+                "New share {} with {} file{}",
+                link,
+                share.files.len(),
+                if share.files.len() == 1 { "" } else { "s" }
+            ),
+            serde_json::json!({
+                "share_id": share.share_id,
+                "alias": share.alias,
+                "link": link,
+                "files": share.files,
+                "expires_at": share.expires_at,
+                "encrypted": share.encrypted,
+            }),
+        ));
+    }
+
+    fn task_completed(&self, task: &TaskOutcome) {
+        let (kind, summary) = match task.status {
+            TaskStatus::Completed => (
+                EventKind::TaskCompleted,
+                format!("Task {} completed", task.task_type),
+            ),
+            TaskStatus::Failed => (
+                EventKind::TaskFailed,
+                format!(
+                    "Task {} failed: {}",
+                    task.task_type,
+                    task.error.as_deref().unwrap_or("unknown error")
+                ),
+            ),
+            TaskStatus::Pending | TaskStatus::Running => return,
+        };
+        self.emit(Event::new(
+            kind,
+            summary,
+            serde_json::json!({
+                "task_id": task.task_id,
+                "task_type": task.task_type,
+                "status": task.status,
+                "output": task.output,
+                "error": task.error,
+            }),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+    use axum::routing::post;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let delays: Vec<_> = (1..MAX_ATTEMPTS)
+            .map(|attempt| retry_delay(BASE_RETRY_DELAY, attempt).as_secs())
+            .collect();
+        assert_eq!(delays, [2, 4, 8, 16, 32]);
+    }
+
+    /// Receiver answering 503 to the first request, and the requests it got
+    async fn flaky_receiver() -> Result<(String, Arc<Mutex<Vec<(HeaderMap, String)>>>)> {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = Arc::clone(&requests);
+        let app = axum::Router::new()
+            .route(
+                "/ok",
+                post(move |headers: HeaderMap, body: String| async move {
+                    let mut requests = received.lock().unwrap();
+                    requests.push((headers, body));
+                    if requests.len() == 1 {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    } else {
+                        StatusCode::NO_CONTENT
+                    }
+                }),
+            )
+            .route("/gone", post(|| async { StatusCode::NOT_FOUND }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((url, requests))
+    }
+
+    #[tokio::test]
+    async fn test_delivery() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let (url, requests) = flaky_receiver().await?;
+        let mut webhooks = Webhooks::new(db.clone(), "https://files.example.com/")?;
+        webhooks.base_retry_delay = Duration::from_millis(10);
+
+        let subscribed = register(
+            &db,
+            &NewEndpoint {
+                url: format!("{}/ok", url),
+                events: vec![EventKind::ShareCreated, EventKind::TaskFailed],
+                format: Format::Json,
+                secret: None,
+            },
+        )
+        .await?;
+        let gone = register(
+            &db,
+            &NewEndpoint {
+                url: format!("{}/gone", url),
+                events: vec![EventKind::ShareCreated],
+                format: Format::Discord,
+                secret: Some("0123456789abcdef".to_string()),
+            },
+        )
+        .await?;
+        let unsubscribed = register(
+            &db,
+            &NewEndpoint {
+                url: format!("{}/ok", url),
+                events: vec![EventKind::DownloadCompleted],
+                format: Format::Slack,
+                secret: None,
+            },
+        )
+        .await?;
+
+        let event = Event::new(
+            EventKind::ShareCreated,
+            "New share".to_string(),
+            serde_json::json!({ "share_id": "V1StGXR8_Z" }),
+        );
+        webhooks.dispatch(&event).await?;
+
+        // Retried once after the 503, the other endpoints did not subscribe or refused it
+        let requests = requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        let (headers, body) = &requests[1];
+        assert_eq!(headers[EVENT_HEADER], "share_created");
+        assert_eq!(headers[DELIVERY_HEADER], requests[0].0[DELIVERY_HEADER]);
+        assert_eq!(
+            headers[SIGNATURE_HEADER],
+            format!("sha256={}", sign(&subscribed.secret, body.as_bytes()))
+        );
+        let body: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(body["event"], "share_created");
+        assert_eq!(body["data"]["share_id"], "V1StGXR8_Z");
+
+        let endpoints = list(&db).await?;
+        let status = |id: &str| {
+            endpoints
+                .iter()
+                .find(|endpoint| endpoint.id == id)
+                .and_then(|endpoint| endpoint.last_status.clone())
+        };
+        assert_eq!(
+            status(&subscribed.endpoint.id).as_deref(),
+            Some("delivered")
+        );
+        assert_eq!(
+            status(&gone.endpoint.id).as_deref(),
+            Some("HTTP 404 Not Found")
+        );
+        assert_eq!(status(&unsubscribed.endpoint.id), None);
+
+        assert!(remove(&db, &gone.endpoint.id).await?);
+        assert!(!remove(&db, &gone.endpoint.id).await?);
+        Ok(())
+    }
+}
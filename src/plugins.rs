@@ -0,0 +1,276 @@
+//! Compiled-in extension hooks.
+//!
+//! Forks add their business logic by implementing [`Plugin`] and listing it in [`registry`]
+//! instead of patching the handlers. Plugins run in registration order, synchronously, on the
+//! request or task which triggered the hook: slow side effects should be spawned. The first veto
+//! stops the following plugins.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::instrumented::ByteSink;
+use crate::worker::TaskStatus;
+
+/// Refusal of a share or download by a plugin, the reason is logged and not shown to clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Veto {
+    pub plugin: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for Veto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Refused by plugin {}: {}", self.plugin, self.reason)
+    }
+}
+
+impl std::error::Error for Veto {}
+
+/// A share about to be stored
+#[derive(Debug, Clone)]
+pub struct NewShare {
+    pub share_id: String,
+    pub files: Vec<String>,
+    /// Unix timestamp, the share never expires when unset
+    pub expires_at: Option<i64>,
+    pub encrypted: bool,
+    /// Vanity alias the share is also served at, see [`crate::share_alias`]
+    pub alias: Option<String>,
+}
+
+/// A file about to be served
+#[derive(Debug, Clone)]
+pub struct DownloadRequest {
+    pub share_id: String,
+    pub file_id: i64,
+    pub path: String,
+    pub client_ip: Option<String>,
+    /// Name of the `Content-Disposition` header
+    pub download_name: String,
+}
+
+/// How a download ended, the file is given by its [`DownloadRequest`]
+#[derive(Debug, Clone)]
+pub struct DownloadOutcome {
+    pub bytes_sent: u64,
+    /// The whole requested range was sent, `false` when the client went away
+    pub complete: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskOutcome {
+    pub task_id: String,
+    pub task_type: String,
+    pub status: TaskStatus,
+    pub output: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+pub trait Plugin: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Before a share is stored: change its files or expiry, or refuse it
+    fn share_created(&self, _share: &mut NewShare) -> Result<(), Veto> {
+        Ok(())
+    }
+
+    /// Once a share was stored, with the files which could be added to it
+    fn share_stored(&self, _share: &NewShare) {}
+
+    /// Before a file is served: rename it or refuse the download
+    fn before_download(&self, _download: &mut DownloadRequest) -> Result<(), Veto> {
+        Ok(())
+    }
+
+    /// Once the response body is dropped, whether or not it was sent entirely
+    fn after_download(&self, _download: &DownloadRequest, _outcome: &DownloadOutcome) {}
+
+    /// After a task completed, or failed without further retries
+    fn task_completed(&self, _task: &TaskOutcome) {}
+}
+
+/// Registered plugins, cheap to clone
+#[derive(Clone, Default)]
+pub struct Plugins(Arc<Vec<Arc<dyn Plugin>>>);
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|plugin| plugin.name()))
+            .finish()
+    }
+}
+
+impl Plugins {
+    pub fn with(mut self, plugin: impl Plugin + 'static) -> Self {
+        Arc::make_mut(&mut self.0).push(Arc::new(plugin));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn share_created(&self, share: &mut NewShare) -> Result<(), Veto> {
+        self.0
+            .iter()
+            .try_for_each(|plugin| plugin.share_created(share))
+            .inspect_err(|veto| tracing::warn!("Share {} not created: {}", share.share_id, veto))
+    }
+
+    pub fn share_stored(&self, share: &NewShare) {
+        for plugin in self.0.iter() {
+            plugin.share_stored(share);
+        }
+    }
+
+    pub fn before_download(&self, download: &mut DownloadRequest) -> Result<(), Veto> {
+        self.0
+            .iter()
+            .try_for_each(|plugin| plugin.before_download(download))
+            .inspect_err(|veto| tracing::warn!("Download of {} refused: {}", download.path, veto))
+    }
+
+    pub fn after_download(&self, download: &DownloadRequest, outcome: &DownloadOutcome) {
+        for plugin in self.0.iter() {
+            plugin.after_download(download, outcome);
+        }
+    }
+
+    pub fn task_completed(&self, task: &TaskOutcome) {
+        for plugin in self.0.iter() {
+            plugin.task_completed(task);
+        }
+    }
+}
+
+/// Plugins compiled into this build, forks register theirs here:
+/// `Plugins::default().with(MyPlugin::new())`
+pub fn registry() -> Plugins {
+    Plugins::default()
+}
+
+/// Runs the `after_download` hooks once the response body is dropped
+pub struct AfterDownloadSink {
+    plugins: Plugins,
+    download: DownloadRequest,
+    outcome: DownloadOutcome,
+}
+
+impl AfterDownloadSink {
+    pub fn new(plugins: Plugins, request: &DownloadRequest) -> Self {
+        AfterDownloadSink {
+            plugins,
+            download: request.clone(),
+            outcome: DownloadOutcome {
+                bytes_sent: 0,
+                complete: false,
+            },
+        }
+    }
+}
+
+impl ByteSink for AfterDownloadSink {
+    fn on_bytes(&mut self, _read: u64, total_read: u64) {
+        self.outcome.bytes_sent = total_read;
+    }
+
+    fn on_eof(&mut self, total_read: u64) {
+        self.outcome.bytes_sent = total_read;
+        self.outcome.complete = true;
+    }
+}
+
+impl Drop for AfterDownloadSink {
+    fn drop(&mut self) {
+        if !self.plugins.is_empty() {
+            self.plugins.after_download(&self.download, &self.outcome);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Caps the lifetime of shares, hides the user name of downloads and records the outcomes
+    #[derive(Default)]
+    struct Policy {
+        downloads: Arc<Mutex<Vec<(String, DownloadOutcome)>>>,
+    }
+
+    impl Plugin for Policy {
+        fn name(&self) -> &'static str {
+            "policy"
+        }
+
+        fn share_created(&self, share: &mut NewShare) -> Result<(), Veto> {
+            if share.files.iter().any(|file| file.ends_with(".key")) {
+                return Err(Veto {
+                    plugin: self.name(),
+                    reason: "keys can't be shared".to_string(),
+                });
+            }
+            share.expires_at = Some(share.expires_at.unwrap_or(i64::MAX).min(1_000));
+            Ok(())
+        }
+
+        fn before_download(&self, download: &mut DownloadRequest) -> Result<(), Veto> {
+            download.download_name = download.download_name.replace("alice", "user");
+            Ok(())
+        }
+
+        fn after_download(&self, download: &DownloadRequest, outcome: &DownloadOutcome) {
+            self.downloads
+                .lock()
+                .unwrap()
+                .push((download.download_name.clone(), outcome.clone()));
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let policy = Policy::default();
+        let downloads = Arc::clone(&policy.downloads);
+        let plugins = registry().with(policy);
+
+        let mut share = NewShare {
+            share_id: "abc".to_string(),
+            files: vec!["/data/report.pdf".to_string()],
+            expires_at: None,
+            encrypted: false,
+            alias: None,
+        };
+        assert_eq!(plugins.share_created(&mut share), Ok(()));
+        assert_eq!(share.expires_at, Some(1_000));
+        share.files.push("/data/server.key".to_string());
+        assert_eq!(
+            plugins.share_created(&mut share).unwrap_err().to_string(),
+            "Refused by plugin policy: keys can't be shared"
+        );
+
+        let mut request = DownloadRequest {
+            share_id: "abc".to_string(),
+            file_id: 1,
+            path: "/data/alice.pdf".to_string(),
+            client_ip: None,
+            download_name: "alice.pdf".to_string(),
+        };
+        plugins.before_download(&mut request).unwrap();
+        assert_eq!(request.download_name, "user.pdf");
+
+        let mut sink = AfterDownloadSink::new(plugins.clone(), &request);
+        sink.on_bytes(10, 10);
+        drop(sink);
+        let mut sink = AfterDownloadSink::new(plugins, &request);
+        sink.on_bytes(10, 10);
+        sink.on_eof(10);
+        drop(sink);
+        let downloads = downloads.lock().unwrap();
+        assert_eq!(downloads.len(), 2);
+        assert_eq!(downloads[0].0, "user.pdf");
+        assert!(!downloads[0].1.complete);
+        assert!(downloads[1].1.complete && downloads[1].1.bytes_sent == 10);
+    }
+}
@@ -0,0 +1,129 @@
+//! Liveness and readiness probes, e.g. for Kubernetes.
+//!
+//! `/healthz` answers as long as the server handles requests. `/readyz` also checks what serving
+//! shares and running tasks depend on: the database, a writable data directory, the file indexer
+//! thread and the task worker. It answers 503 when one of them fails, with the status of each
+//! component so the failing one shows in the probe logs.
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::App;
+
+/// Checks taking longer fail, a probe must answer before its own timeout
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+struct Component {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    elapsed_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    components: BTreeMap<&'static str, Component>,
+}
+
+/// Run `check` within [`CHECK_TIMEOUT`]
+async fn component<F>(check: F) -> Component
+where
+    F: std::future::Future<Output = Result<(), String>>,
+{
+    let start = Instant::now();
+    let result = tokio::time::timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| Err("timed out".to_string()));
+    Component {
+        ok: result.is_ok(),
+        error: result.err(),
+        elapsed_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Create and remove a file in `dir`
+async fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(".readyz");
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| format!("{} is not writable: {}", dir.display(), e))?;
+    tokio::fs::remove_file(&probe)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+pub async fn readyz(State(app_state): State<App>) -> Response {
+    let data_dir = &app_state.config.data_dir;
+    let (database, data_dir, indexer, worker) = tokio::join!(
+        component(async {
+            sqlx::query("SELECT 1")
+                .execute(&app_state.db_pool)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }),
+        component(check_writable(data_dir)),
+        component(async {
+            if app_state.indexer.is_running() {
+                Ok(())
+            } else {
+                Err("the file indexer task stopped".to_string())
+            }
+        }),
+        component(async {
+            match app_state.task_manager.worker_unavailable() {
+                None => Ok(()),
+                Some(reason) => Err(reason.to_string()),
+            }
+        }),
+    );
+    let components = BTreeMap::from([
+        ("database", database),
+        ("data_dir", data_dir),
+        ("indexer", indexer),
+        ("task_worker", worker),
+    ]);
+    let ready = components.values().all(|component| component.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Readiness { ready, components })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let writable = component(check_writable(dir.path())).await;
+        assert!(writable.ok && writable.error.is_none());
+        assert!(!dir.path().join(".readyz").exists());
+
+        let missing = component(check_writable(&dir.path().join("missing"))).await;
+        assert!(!missing.ok);
+        assert!(missing.error.unwrap().contains("is not writable"));
+
+        let slow = component(async {
+            tokio::time::sleep(CHECK_TIMEOUT * 2).await;
+            Ok(())
+        })
+        .await;
+        assert_eq!(slow.error.as_deref(), Some("timed out"));
+    }
+}
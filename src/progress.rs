@@ -1,100 +1,283 @@
 //use crossbeam::channel::{self, Sender};
 use sqlx::{Pool, Sqlite};
-use std::collections::HashMap;
-use std::io;
-use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, ReadBuf};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 
-use serde::Serialize;
+use crate::chaos::{Chaos, Fault};
+use crate::instrumented::ByteSink;
+use crate::notifications::Notifier;
+use crate::outgoing_webhooks::{self, EventKind, Webhooks};
 
-pub struct ProgressReader<R> {
-    inner: R,
-    total_bytes: u32,
-    read_bytes: usize,
-    transaction_id: String,
-    file_path: String,
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+/// Sends a download progress event for each chunk read from a served file, and a last one when
+/// the response body is dropped before the whole range was sent
+pub struct DownloadProgressSink {
+    download: FileDownload,
     channel_sender: broadcast::Sender<Event>,
-    start_offset: u64,
+    chaos: Chaos,
+    throughput: Throughput,
+    /// Chunks read sooner after the last event send none, the first and last ones always do
+    min_interval: Option<Duration>,
+    last_sent: Option<Instant>,
 }
 
-impl<R> ProgressReader<R> {
+impl DownloadProgressSink {
     pub fn new(
-        inner: R,
-        total_bytes: u32,
-        transaction_id: String,
-        file_path: String,
+        download: FileDownload,
         channel_sender: broadcast::Sender<Event>,
-        start_offset: u64,
+        chaos: Chaos,
     ) -> Self {
-        // CREATE TABLE downloads (
-        // id INTEGER PRIMARY KEY AUTOINCREMENT,
-        // ip_address TEXT,
-        // transaction_id TEXT,
-        // file_size INT,
-        // started_at INT,
-        // finished_at INT,
-        // );
-
         Self {
-            inner,
-            total_bytes,
-            read_bytes: 0,
-            transaction_id,
-            file_path,
+            download,
             channel_sender,
-            start_offset,
+            chaos,
+            throughput: Throughput::new(Instant::now()),
+            min_interval: None,
+            last_sent: None,
         }
     }
 
-    // pub fn progress(&self) -> f64 {
-    //     (self.read_bytes as f64 / self.total_bytes as f64) * 100.0
-    // }
+    /// Send at most one event per `interval` while the download is in progress
+    pub fn with_min_interval(mut self, interval: Duration) -> Self {
+        self.min_interval = Some(interval);
+        self
+    }
+
+    fn send(&mut self) {
+        self.last_sent = Some(Instant::now());
+        // Nobody listening is not an error for the download itself
+        let _ = self
+            .channel_sender
+            .send(Event::DownloadProgress(self.download.clone()));
+    }
 }
 
-impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<io::Result<()>> {
-        let read_poll = Pin::new(&mut self.as_mut().inner).poll_read(cx, buf);
-        if let Poll::Ready(Ok(_)) = read_poll {
-            self.read_bytes += buf.filled().len();
-            self.channel_sender
-                .send(Event::DownloadProgress(FileDownload {
-                    file_path: self.file_path.clone(),
-                    transaction_id: self.transaction_id.clone(),
-                    total_bytes: self.total_bytes,
-                    read_bytes: self.read_bytes,
-                    start_offset: self.start_offset,
-                }))
-                .unwrap();
+impl ByteSink for DownloadProgressSink {
+    fn on_bytes(&mut self, _read: u64, total_read: u64) {
+        self.download.read_bytes = total_read;
+        self.download.finished = self.download.is_complete();
+        self.download.rate =
+            self.throughput
+                .update(Instant::now(), total_read, self.download.total_bytes);
+        // A lagging receiver loses the oldest events, the last one always gets through
+        if !self.download.finished && self.chaos.inject(Fault::DroppedEvent) {
+            return;
         }
-        read_poll
+        let recent = match (self.min_interval, self.last_sent) {
+            (Some(interval), Some(last_sent)) => last_sent.elapsed() < interval,
+            _ => false,
+        };
+        if !self.download.finished && recent {
+            return;
+        }
+        self.send();
     }
 }
+
+impl Drop for DownloadProgressSink {
+    fn drop(&mut self) {
+        if !self.download.finished {
+            self.download.finished = true;
+            self.send();
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum DownloadStatus {
     Complete,
+    /// Some bytes of the file were never sent, the client may still resume
+    Partial,
 }
 
 impl DownloadStatus {
     pub fn to_str(self) -> String {
         match self {
             DownloadStatus::Complete => "complete".to_owned(),
+            DownloadStatus::Partial => "partial".to_owned(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// One request for a range of a file, the whole file being the range `0..file_size`
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct FileDownload {
-    total_bytes: u32,
-    read_bytes: usize,
-    transaction_id: String,
-    file_path: String,
-    start_offset: u64,
+    /// Length of the requested range
+    pub total_bytes: u64,
+    pub read_bytes: u64,
+    pub transaction_id: String,
+    pub file_path: String,
+    pub start_offset: u64,
+    pub file_size: u64,
+    pub client_ip: Option<String>,
+    /// Told apart two clients behind the same address, see [`FileDownload::with_user_agent`]
+    pub user_agent: Option<String>,
+    /// Share the file was served from, see [`FileDownload::with_share`]
+    pub share_id: Option<String>,
+    pub file_id: Option<i64>,
+    pub started_at: i64,
+    #[serde(flatten)]
+    pub rate: TransferRate,
+    /// Last event of the request, sent as well when the client went away mid-range
+    pub finished: bool,
+}
+
+impl FileDownload {
+    pub fn new(
+        transaction_id: String,
+        file_path: String,
+        client_ip: Option<String>,
+        file_size: u64,
+        range: Range<u64>,
+    ) -> Self {
+        FileDownload {
+            total_bytes: range.end - range.start,
+            read_bytes: 0,
+            transaction_id,
+            file_path,
+            start_offset: range.start,
+            file_size,
+            client_ip,
+            user_agent: None,
+            share_id: None,
+            file_id: None,
+            started_at: chrono::offset::Utc::now().timestamp(),
+            rate: TransferRate::default(),
+            finished: false,
+        }
+    }
+
+    /// Record the download in the statistics of the share
+    pub fn with_share(mut self, share_id: String, file_id: i64) -> Self {
+        self.share_id = Some(share_id);
+        self.file_id = Some(file_id);
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: Option<String>) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.read_bytes >= self.total_bytes
+    }
+
+    /// Requests of the same client for the same file make up a single download
+    fn download_key(&self) -> DownloadKey {
+        (
+            self.share_id.clone(),
+            self.file_path.clone(),
+            self.client_ip.clone(),
+            self.user_agent.clone(),
+        )
+    }
+
+    /// Bytes of the file sent so far
+    pub fn sent_range(&self) -> Range<u64> {
+        self.start_offset..self.start_offset + self.read_bytes.min(self.total_bytes)
+    }
+}
+
+/// Outcome of an authentication attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthAttempt {
+    /// What was authenticated against, e.g. `webhook`
+    pub realm: &'static str,
+    pub success: bool,
+    /// Address of the client, forwarded by a trusted proxy or of the connection
+    pub client_ip: Option<String>,
+    pub path: String,
+    pub reason: Option<String>,
+}
+
+/// Progress of a running task, sent every second by the tasks which report it
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct TaskProgress {
+    pub task_id: String,
+    /// `CreateArchive`, `AssembleUpload`...
+    pub task_type: String,
+    pub percent: i32,
+    /// Bytes processed so far, out of `total_bytes`
+    pub bytes: u64,
+    pub total_bytes: u64,
+    /// Bytes written for each byte read, for the archives
+    pub compression_ratio: Option<f64>,
+    #[serde(flatten)]
+    pub rate: TransferRate,
+    /// Last event of the task, whether it succeeded or not is in its status
+    pub finished: bool,
+}
+
+/// Speed of a download or task, and when it should end at that speed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, ToSchema)]
+pub struct TransferRate {
+    /// Over the last few seconds
+    pub bytes_per_sec: u64,
+    /// Since the start
+    pub avg_bytes_per_sec: u64,
+    /// Seconds left at `bytes_per_sec`, unknown while nothing moves
+    pub eta_secs: Option<u64>,
+}
+
+/// Samples of the bytes done over the last `THROUGHPUT_SAMPLES * SAMPLE_INTERVAL`
+const THROUGHPUT_SAMPLES: usize = 10;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Computes the [`TransferRate`] from a ring buffer of the bytes done at a few instants
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    started: Instant,
+    /// Oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    pub fn new(now: Instant) -> Self {
+        Throughput {
+            started: now,
+            samples: VecDeque::from([(now, 0)]),
+        }
+    }
+
+    /// Account `done` bytes out of `total` at `now`
+    pub fn update(&mut self, now: Instant, done: u64, total: u64) -> TransferRate {
+        let (oldest_at, oldest_done) = self.samples[0];
+        let (last_at, _) = self.samples[self.samples.len() - 1];
+        if now.duration_since(last_at) >= SAMPLE_INTERVAL {
+            if self.samples.len() == THROUGHPUT_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back((now, done));
+        }
+        let per_sec = |bytes: u64, since: Instant| {
+            let elapsed = now.duration_since(since).as_secs_f64();
+            if elapsed > 0.0 {
+                (bytes as f64 / elapsed) as u64
+            } else {
+                0
+            }
+        };
+        let bytes_per_sec = per_sec(done.saturating_sub(oldest_done), oldest_at);
+        let remaining = total.saturating_sub(done);
+        TransferRate {
+            bytes_per_sec,
+            avg_bytes_per_sec: per_sec(done, self.started),
+            eta_secs: match (remaining, bytes_per_sec) {
+                (0, _) => Some(0),
+                (_, 0) => None,
+                (remaining, rate) => Some(remaining.div_ceil(rate)),
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -102,12 +285,295 @@ pub struct FileDownload {
 #[serde(rename_all = "snake_case")]
 pub enum Event {
     DownloadProgress(FileDownload),
+    /// Sent by the [`Manager`], at most every second, for the downloads made of the requests of
+    /// the `download_progress` events
+    Download(LiveDownload),
+    Auth(AuthAttempt),
+    TaskProgress(TaskProgress),
+}
+
+/// A download in progress as seen by its client: the requests it sends at once for ranges of the
+/// file, like download accelerators do, or in a row to resume it
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct LiveDownload {
+    /// Transaction id of its first request
+    pub id: String,
+    pub file_path: String,
+    pub share_id: Option<String>,
+    pub file_id: Option<i64>,
+    pub client_ip: Option<String>,
+    pub file_size: u64,
+    pub started_at: i64,
+    /// Requests in progress
+    pub active_requests: usize,
+    pub requests: usize,
+    /// Bytes sent by all the requests
+    pub read_bytes: u64,
+    /// Combined rate of the requests since the download started
+    pub bytes_per_sec: u64,
+    /// No request is in progress anymore, another one may still resume the download
+    pub finished: bool,
+}
+
+/// Share, file path, client IP and user agent
+type DownloadKey = (Option<String>, String, Option<String>, Option<String>);
+
+/// Requests starting within this many seconds of the end of the last request of a download are
+/// part of it
+const LIVE_WINDOW: Duration = Duration::from_secs(10);
+/// Interval of the `download` events of a download in progress
+const LIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+struct LiveGroup {
+    download: LiveDownload,
+    /// Bytes sent by each request, by transaction id, and whether it finished
+    requests: HashMap<String, (u64, bool)>,
+    started: Instant,
+    last_seen: Instant,
+    last_sent: Option<Instant>,
+}
+
+impl LiveGroup {
+    fn new(pm: &FileDownload, now: Instant) -> Self {
+        LiveGroup {
+            download: LiveDownload {
+                id: pm.transaction_id.clone(),
+                file_path: pm.file_path.clone(),
+                share_id: pm.share_id.clone(),
+                file_id: pm.file_id,
+                client_ip: pm.client_ip.clone(),
+                file_size: pm.file_size,
+                started_at: pm.started_at,
+                active_requests: 0,
+                requests: 0,
+                read_bytes: 0,
+                bytes_per_sec: 0,
+                finished: false,
+            },
+            requests: HashMap::new(),
+            started: now,
+            last_seen: now,
+            last_sent: None,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.requests.values().any(|(_, finished)| !finished)
+    }
+
+    /// Account the progress of one of its requests, and tell whether to send the download
+    fn update(&mut self, pm: &FileDownload, now: Instant) -> bool {
+        let was_active = self.is_active();
+        self.requests
+            .insert(pm.transaction_id.clone(), (pm.read_bytes, pm.finished));
+        self.last_seen = now;
+        let download = &mut self.download;
+        download.requests = self.requests.len();
+        download.active_requests = self
+            .requests
+            .values()
+            .filter(|(_, finished)| !finished)
+            .count();
+        download.read_bytes = self.requests.values().map(|(read, _)| read).sum();
+        let elapsed = now.duration_since(self.started).as_secs_f64().max(0.001);
+        download.bytes_per_sec = (download.read_bytes as f64 / elapsed) as u64;
+        download.finished = download.active_requests == 0;
+
+        let due = self
+            .last_sent
+            .is_none_or(|last_sent| now.duration_since(last_sent) >= LIVE_INTERVAL);
+        if due || was_active != self.is_active() {
+            self.last_sent = Some(now);
+            return true;
+        }
+        false
+    }
 }
+/// Requests for the same file by the same client within this many seconds of each other are
+/// segments of a single download, e.g. a download manager resuming after a network failure
+const RESUME_WINDOW_SECS: i64 = 3600;
+
+/// A download made of one or more requests, stored as one `download` record
+#[derive(Debug, Clone)]
+struct DownloadSession {
+    record_id: i64,
+    file_size: u64,
+    /// Bytes of the file sent, sorted and disjoint
+    ranges: Vec<Range<u64>>,
+    /// Including the bytes sent more than once
+    bytes_sent: u64,
+    segments: i64,
+    last_seen: i64,
+}
+
+impl DownloadSession {
+    fn add_segment(&mut self, download: &FileDownload, now: i64) {
+        add_range(&mut self.ranges, download.sent_range());
+        self.bytes_sent += download.read_bytes;
+        self.segments += 1;
+        self.last_seen = now;
+    }
+
+    fn bytes_covered(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    fn status(&self) -> DownloadStatus {
+        if self.bytes_covered() >= self.file_size {
+            DownloadStatus::Complete
+        } else {
+            DownloadStatus::Partial
+        }
+    }
+}
+
+/// Insert `range` into the sorted and disjoint `ranges`, merging it with those it overlaps or
+/// touches
+fn add_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    let mut merged = range;
+    ranges.retain(|other| {
+        if other.start > merged.end || merged.start > other.end {
+            return true;
+        }
+        merged = merged.start.min(other.start)..merged.end.max(other.end);
+        false
+    });
+    let index = ranges.partition_point(|other| other.start < merged.start);
+    ranges.insert(index, merged);
+}
+
+/// A stored download, see [`recent_downloads`]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DownloadRecord {
+    pub id: i64,
+    pub file_path: Option<String>,
+    pub share_id: Option<String>,
+    pub file_id: Option<i64>,
+    pub client_ip: Option<String>,
+    /// `complete` once every byte of the file was sent, `partial` otherwise
+    pub status: Option<String>,
+    pub file_size: Option<i64>,
+    /// Bytes sent over all the segments, more than the file size when ranges were sent again
+    pub bytes_sent: i64,
+    /// Distinct bytes of the file sent
+    pub bytes_covered: i64,
+    /// Requests the download was made of, more than one when it was resumed
+    pub segments: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DownloadQuery {
+    pub file_path: Option<String>,
+    /// Only the downloads resumed at least once
+    #[serde(default)]
+    pub resumed: bool,
+    pub limit: Option<i64>,
+}
+
+impl DownloadQuery {
+    pub const DEFAULT_LIMIT: i64 = 100;
+    pub const MAX_LIMIT: i64 = 1000;
+}
+
+/// Stored downloads, most recent first, only those of the shares of `namespace` when given
+pub async fn recent_downloads(
+    db: &Pool<Sqlite>,
+    query: &DownloadQuery,
+    namespace: Option<&str>,
+) -> Result<Vec<DownloadRecord>, sqlx::Error> {
+    let min_segments = if query.resumed { 2 } else { 1 };
+    let limit = query
+        .limit
+        .unwrap_or(DownloadQuery::DEFAULT_LIMIT)
+        .clamp(1, DownloadQuery::MAX_LIMIT);
+    sqlx::query_as!(
+        DownloadRecord,
+        r#"SELECT id AS "id!", file_path, share_id, file_id, ip_address AS client_ip, status, file_size, bytes_sent, bytes_covered, segments, started_at, finished_at
+        FROM download
+        WHERE (?1 IS NULL OR file_path = ?1) AND segments >= ?2
+        AND (?4 IS NULL OR share_id IN (SELECT id FROM share_links WHERE namespace = ?4))
+        ORDER BY id DESC LIMIT ?3"#,
+        query.file_path,
+        min_segments,
+        limit,
+        namespace
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Downloads and tasks in progress, kept by the [`Manager`] and shared with the admin API for the
+/// dashboards connecting to the live feed midway
+#[derive(Clone, Debug, Default)]
+pub struct ActiveProgress {
+    /// Requests in flight, by transaction id
+    requests: Arc<Mutex<HashMap<String, FileDownload>>>,
+    /// Downloads of the live feed with a request in flight, by id
+    downloads: Arc<Mutex<HashMap<String, LiveDownload>>>,
+    /// Last progress of the running tasks, by id
+    tasks: Arc<Mutex<HashMap<String, TaskProgress>>>,
+}
+
+/// Downloads and tasks in progress, oldest first
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ProgressSnapshot {
+    pub downloads: Vec<LiveDownload>,
+    /// Requests the downloads are made of
+    pub requests: Vec<FileDownload>,
+    pub tasks: Vec<TaskProgress>,
+}
+
+impl ActiveProgress {
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let mut downloads: Vec<LiveDownload> =
+            self.downloads.lock().unwrap().values().cloned().collect();
+        downloads.sort_by(|a, b| (a.started_at, &a.id).cmp(&(b.started_at, &b.id)));
+        let mut requests: Vec<FileDownload> =
+            self.requests.lock().unwrap().values().cloned().collect();
+        requests.sort_by(|a, b| {
+            (a.started_at, &a.transaction_id).cmp(&(b.started_at, &b.transaction_id))
+        });
+        let mut tasks: Vec<TaskProgress> = self.tasks.lock().unwrap().values().cloned().collect();
+        tasks.sort_by(|a, b| a.task_id.cmp(&b.task_id));
+        ProgressSnapshot {
+            downloads,
+            requests,
+            tasks,
+        }
+    }
+
+    fn update_task(&self, progress: TaskProgress) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if progress.finished {
+            tasks.remove(&progress.task_id);
+        } else {
+            tasks.insert(progress.task_id.clone(), progress);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Manager {
     pub sender: broadcast::Sender<Event>,
     db_pool: Pool<Sqlite>,
-    ongoing_download: HashMap<String, FileDownload>,
+    active: ActiveProgress,
+    /// Downloads of the live feed
+    live: HashMap<DownloadKey, LiveGroup>,
+    sessions: HashMap<DownloadKey, DownloadSession>,
+    chaos: Chaos,
+    notifier: Option<Notifier>,
+    webhooks: Option<Webhooks>,
+    shutdown: CancellationToken,
 }
 
 impl Manager {
@@ -116,45 +582,598 @@ impl Manager {
         Manager {
             sender: send,
             db_pool,
-            ongoing_download: HashMap::new(),
+            active: ActiveProgress::default(),
+            live: HashMap::new(),
+            sessions: HashMap::new(),
+            chaos: Chaos::default(),
+            notifier: None,
+            webhooks: None,
+            shutdown: CancellationToken::new(),
         }
     }
 
-    pub async fn start_recv_thread(&mut self) {
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Email the opted-in shares once all their files were downloaded
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Send the completed downloads to the webhooks subscribed to them
+    pub fn with_webhooks(mut self, webhooks: Webhooks) -> Self {
+        self.webhooks = Some(webhooks);
+        self
+    }
+
+    /// Downloads and tasks in progress, updated as the events are received
+    pub fn active(&self) -> ActiveProgress {
+        self.active.clone()
+    }
+
+    /// Stop once `shutdown` is cancelled, after recording the events already sent and the
+    /// downloads still in progress, which were cut
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// The returned handle resolves once the manager stopped, see [`Manager::with_shutdown`]
+    pub async fn start_recv_thread(&mut self) -> JoinHandle<()> {
         let mut mgr = self.clone();
-        tokio::spawn(async move { mgr.process_message().await });
+        // Subscribed before returning so no event sent afterwards is missed
+        let receiver = self.sender.subscribe();
+        tokio::spawn(async move { mgr.process_message(receiver).await })
     }
 
-    async fn process_message(&mut self) {
-        let mut receiver = self.sender.subscribe();
+    async fn process_message(&mut self, mut receiver: broadcast::Receiver<Event>) {
         loop {
-            let m = receiver.recv().await;
+            let m = tokio::select! {
+                m = receiver.recv() => m,
+                _ = self.shutdown.cancelled() => {
+                    self.flush(&mut receiver).await;
+                    break;
+                }
+            };
             match m {
                 Ok(m) => match m {
                     Event::DownloadProgress(pm) => {
                         self.update_download_progress(pm).await;
                     }
+                    Event::TaskProgress(progress) => self.active.update_task(progress),
+                    Event::Download(_) | Event::Auth(_) => {}
                 },
-                Err(err) => tracing::error!("Progress queue receiver have been ended: {}", err),
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    tracing::warn!("Progress queue receiver missed {} events", count)
+                }
+                Err(err) => {
+                    tracing::error!("Progress queue receiver have been ended: {}", err);
+                    break;
+                }
             }
         }
     }
 
+    /// Record the events left in `receiver`, then the downloads still in progress as finished
+    #[instrument(skip_all, fields(ongoing = self.active.requests.lock().unwrap().len()))]
+    async fn flush(&mut self, receiver: &mut broadcast::Receiver<Event>) {
+        loop {
+            match receiver.try_recv() {
+                Ok(Event::DownloadProgress(pm)) => self.update_download_progress(pm).await,
+                Ok(Event::Download(_) | Event::Auth(_) | Event::TaskProgress(_))
+                | Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        let ongoing = std::mem::take(&mut *self.active.requests.lock().unwrap());
+        for (_, pm) in ongoing {
+            self.update_download_progress(FileDownload {
+                finished: true,
+                ..pm
+            })
+            .await;
+        }
+    }
+
+    /// Spans at the trace level, there is an event per chunk sent
+    #[instrument(level = "trace", skip_all, fields(transaction_id = %pm.transaction_id))]
     async fn update_download_progress(&mut self, pm: FileDownload) {
-        let transaction_id = pm.clone().transaction_id.clone();
+        let transaction_id = pm.transaction_id.clone();
+        self.update_live(&pm);
+
+        if pm.finished {
+            self.active.requests.lock().unwrap().remove(&transaction_id);
+            // Nothing was sent, e.g. the client only wanted the headers
+            if pm.read_bytes == 0 {
+                return;
+            }
+            if let Err(e) = self.record_download(&pm).await {
+                tracing::error!("Failed to record download {}: {}", transaction_id, e);
+            }
+            return;
+        }
+        self.active
+            .requests
+            .lock()
+            .unwrap()
+            .insert(transaction_id, pm);
+    }
+
+    /// Add the request to the download of the live feed it is part of, and send the download when
+    /// it changed enough
+    fn update_live(&mut self, pm: &FileDownload) {
+        let now = Instant::now();
+        self.live.retain(|_, group| {
+            group.is_active() || now.duration_since(group.last_seen) <= LIVE_WINDOW
+        });
+        let group = self
+            .live
+            .entry(pm.download_key())
+            .or_insert_with(|| LiveGroup::new(pm, now));
+        let send = group.update(pm, now);
+        let mut downloads = self.active.downloads.lock().unwrap();
+        if group.is_active() {
+            downloads.insert(group.download.id.clone(), group.download.clone());
+        } else {
+            downloads.remove(&group.download.id);
+        }
+        if send {
+            let _ = self.sender.send(Event::Download(group.download.clone()));
+        }
+    }
 
-        if pm.total_bytes == pm.read_bytes as u32 {
-            let download_status_str = DownloadStatus::Complete.to_str();
-            sqlx::query!(
-                "INSERT INTO download (file_path, transaction_id, status, file_size) VALUES ($1, $2, $3, $4)",
+    /// Add the request to the download it resumes, or start a new one
+    #[instrument(skip_all, fields(transaction_id = %pm.transaction_id, share_id = ?pm.share_id, file_id = ?pm.file_id, bytes = pm.read_bytes), err)]
+    async fn record_download(&mut self, pm: &FileDownload) -> Result<(), sqlx::Error> {
+        self.chaos.db_error()?;
+        let now = chrono::offset::Utc::now().timestamp();
+        self.sessions
+            .retain(|_, session| now - session.last_seen <= RESUME_WINDOW_SECS);
+
+        let key = pm.download_key();
+        let resumed = self.sessions.get(&key).is_some_and(|session| {
+            session.file_size == pm.file_size && matches!(session.status(), DownloadStatus::Partial)
+        });
+        if !resumed {
+            let file_size = pm.file_size as i64;
+            let record_id = sqlx::query_scalar!(
+                r#"INSERT INTO download (file_path, share_id, file_id, ip_address, transaction_id, file_size, started_at, segments)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, 0) RETURNING id AS "id!""#,
                 pm.file_path,
+                pm.share_id,
+                pm.file_id,
+                pm.client_ip,
                 pm.transaction_id,
-                download_status_str,
-                pm.total_bytes,
+                file_size,
+                pm.started_at,
+            )
+            .fetch_one(&self.db_pool)
+            .await?;
+            self.sessions.insert(
+                key.clone(),
+                DownloadSession {
+                    record_id,
+                    file_size: pm.file_size,
+                    ranges: vec![],
+                    bytes_sent: 0,
+                    segments: 0,
+                    last_seen: now,
+                },
+            );
+        }
+
+        let session = self.sessions.get_mut(&key).expect("session just stored");
+        session.add_segment(pm, now);
+        let completed = matches!(session.status(), DownloadStatus::Complete);
+        let status = session.status().to_str();
+        let bytes_sent = session.bytes_sent as i64;
+        let bytes_covered = session.bytes_covered() as i64;
+        sqlx::query!(
+            "UPDATE download SET status = $1, bytes_sent = $2, bytes_covered = $3, segments = $4, finished_at = $5 WHERE id = $6",
+            status,
+            bytes_sent,
+            bytes_covered,
+            session.segments,
+            now,
+            session.record_id,
+        )
+        .execute(&self.db_pool)
+        .await?;
+
+        if let Some(webhooks) = self.webhooks.as_ref().filter(|_| completed) {
+            let file_name = std::path::Path::new(&pm.file_path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            webhooks.emit(outgoing_webhooks::Event::new(
+                EventKind::DownloadCompleted,
+                format!("{} was downloaded", file_name),
+                serde_json::json!({
+                    "download_id": session.record_id,
+                    "file_path": pm.file_path,
+                    "file_size": session.file_size,
+                    "client_ip": pm.client_ip,
+                    "bytes_sent": session.bytes_sent,
+                    "segments": session.segments,
+                }),
+            ));
+        }
+        if let Some(notifier) = self.notifier.clone().filter(|_| completed) {
+            let db_pool = self.db_pool.clone();
+            let file_path = pm.file_path.clone();
+            tokio::spawn(async move {
+                if let Err(e) = notifier.file_downloaded(&db_pool, &file_path).await {
+                    tracing::warn!("Failed to notify the download of {}: {}", file_path, e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn test_db() -> anyhow::Result<Pool<Sqlite>> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        Ok(db)
+    }
+
+    /// A finished request which sent `sent` bytes of `range`
+    fn request(transaction_id: &str, range: Range<u64>, sent: u64) -> FileDownload {
+        FileDownload {
+            read_bytes: sent,
+            finished: true,
+            ..FileDownload::new(
+                transaction_id.to_string(),
+                "/data/a.iso".to_string(),
+                Some("192.0.2.1".to_string()),
+                100,
+                range,
             )
-            .execute(&self.db_pool)
-            .await.unwrap();
+            .with_share("s1".to_string(), 1)
+        }
+    }
+
+    #[test]
+    fn test_add_range() {
+        let mut ranges = vec![];
+        add_range(&mut ranges, 50..60);
+        add_range(&mut ranges, 0..10);
+        add_range(&mut ranges, 20..20);
+        assert_eq!(ranges, [0..10, 50..60]);
+        add_range(&mut ranges, 10..30);
+        add_range(&mut ranges, 25..55);
+        add_range(&mut ranges, 90..100);
+        assert_eq!(ranges, [0..60, 90..100]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_download_record_is_not_fatal() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        let mut manager = Manager::new(db.clone()).with_chaos(Chaos::new(&[(Fault::DbError, 1.0)]));
+        let download = FileDownload {
+            read_bytes: 4,
+            finished: false,
+            ..request("t1", 0..10, 4)
+        };
+        manager.update_download_progress(download.clone()).await;
+        assert!(manager.active.requests.lock().unwrap().contains_key("t1"));
+
+        manager
+            .update_download_progress(FileDownload {
+                read_bytes: 10,
+                finished: true,
+                ..download
+            })
+            .await;
+        assert!(manager.active.snapshot().requests.is_empty());
+        let records: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM download")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(records, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resumed_download() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        let mut manager = Manager::new(db.clone());
+        let query = DownloadQuery::default();
+
+        // Interrupted after 40 bytes, resumed from byte 30 to the end
+        manager
+            .update_download_progress(request("t1", 0..100, 40))
+            .await;
+        let records = recent_downloads(&db, &query, None).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].status.as_deref(), Some("partial"));
+        assert_eq!(records[0].bytes_covered, 40);
+
+        manager
+            .update_download_progress(request("t2", 30..100, 70))
+            .await;
+        let records = recent_downloads(&db, &query, None).await?;
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.status.as_deref(), Some("complete"));
+        assert_eq!(record.file_size, Some(100));
+        assert_eq!(record.bytes_sent, 110);
+        assert_eq!(record.bytes_covered, 100);
+        assert_eq!(record.segments, 2);
+        assert_eq!(record.share_id.as_deref(), Some("s1"));
+        assert_eq!(record.file_id, Some(1));
+
+        // Downloaded again once complete
+        manager
+            .update_download_progress(request("t5", 0..100, 100))
+            .await;
+        assert_eq!(recent_downloads(&db, &query, None).await?.len(), 2);
+
+        // Another client, and a request which sent nothing
+        manager
+            .update_download_progress(FileDownload {
+                client_ip: None,
+                ..request("t3", 0..100, 100)
+            })
+            .await;
+        manager
+            .update_download_progress(request("t4", 0..100, 0))
+            .await;
+        assert_eq!(recent_downloads(&db, &query, None).await?.len(), 3);
+        let resumed = DownloadQuery {
+            resumed: true,
+            ..Default::default()
+        };
+        assert_eq!(recent_downloads(&db, &resumed, None).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parallel_segments() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        let mut manager = Manager::new(db.clone());
+        let mut receiver = manager.sender.subscribe();
+        let segment =
+            |transaction_id: &str, range: Range<u64>, sent: u64, finished: bool| FileDownload {
+                finished,
+                ..request(transaction_id, range, sent).with_user_agent(Some("aria2/1.37".into()))
+            };
+
+        // A download accelerator fetching four ranges at once, and a browser on the same address
+        for (i, start) in [0, 25, 50, 75].into_iter().enumerate() {
+            let pm = segment(&format!("t{}", i), start..start + 25, 10, false);
+            manager.update_download_progress(pm).await;
+        }
+        manager
+            .update_download_progress(FileDownload {
+                finished: false,
+                user_agent: Some("Firefox".into()),
+                ..request("b1", 0..100, 5)
+            })
+            .await;
+        for (i, start) in [0, 25, 50, 75].into_iter().enumerate() {
+            let pm = segment(&format!("t{}", i), start..start + 25, 25, true);
+            manager.update_download_progress(pm).await;
+        }
+
+        let mut downloads = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            if let Event::Download(download) = event {
+                downloads.push(download);
+            }
+        }
+        let accelerated: Vec<&LiveDownload> = downloads
+            .iter()
+            .filter(|download| download.id == "t0")
+            .collect();
+        // Started, then finished
+        assert_eq!(accelerated.len(), 2);
+        assert_eq!(
+            (accelerated[0].active_requests, accelerated[0].read_bytes),
+            (1, 10)
+        );
+        let last = accelerated[1];
+        assert!(last.finished);
+        assert_eq!((last.requests, last.read_bytes), (4, 100));
+        assert!(downloads.iter().any(|download| download.id == "b1"));
+        assert_eq!(manager.live.len(), 2);
+
+        let records = recent_downloads(&db, &DownloadQuery::default(), None).await?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].status.as_deref(), Some("complete"));
+        assert_eq!(records[0].segments, 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_records_cut_downloads() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        let shutdown = CancellationToken::new();
+        let mut manager = Manager::new(db.clone()).with_shutdown(shutdown.clone());
+        let stopped = manager.start_recv_thread().await;
+        let ongoing = FileDownload {
+            finished: false,
+            ..request("t1", 0..100, 40)
+        };
+        manager.sender.send(Event::DownloadProgress(ongoing))?;
+        let other_client = FileDownload {
+            client_ip: Some("192.0.2.2".to_string()),
+            ..request("t2", 0..100, 100)
+        };
+        manager.sender.send(Event::DownloadProgress(other_client))?;
+        shutdown.cancel();
+        stopped.await?;
+
+        // Recorded as cut after 40 bytes
+        let mut records = recent_downloads(&db, &DownloadQuery::default(), None).await?;
+        records.sort_by_key(|record| record.bytes_covered);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].status.as_deref(), Some("partial"));
+        assert_eq!(records[0].bytes_covered, 40);
+        assert_eq!(records[1].status.as_deref(), Some("complete"));
+        Ok(())
+    }
+
+    /// The snapshot once the manager received the events of `tasks` running tasks
+    async fn wait_for_tasks(active: &ActiveProgress, tasks: usize) -> ProgressSnapshot {
+        for _ in 0..100 {
+            let snapshot = active.snapshot();
+            if snapshot.tasks.len() == tasks {
+                return snapshot;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Expected {} running tasks", tasks);
+    }
+
+    #[tokio::test]
+    async fn test_active_progress() -> anyhow::Result<()> {
+        let mut manager = Manager::new(test_db().await?);
+        manager.start_recv_thread().await;
+        let active = manager.active();
+        let download = FileDownload {
+            finished: false,
+            ..request("t1", 0..100, 40)
+        };
+        let task = TaskProgress {
+            task_id: "task1".to_string(),
+            task_type: "CreateArchive".to_string(),
+            percent: 50,
+            bytes: 10,
+            total_bytes: 20,
+            compression_ratio: None,
+            rate: TransferRate::default(),
+            finished: false,
+        };
+        manager
+            .sender
+            .send(Event::DownloadProgress(download.clone()))?;
+        manager.sender.send(Event::TaskProgress(task.clone()))?;
+        let snapshot = wait_for_tasks(&active, 1).await;
+        assert_eq!(snapshot.requests[0].read_bytes, 40);
+        assert_eq!(snapshot.downloads[0].id, "t1");
+        assert_eq!(snapshot.downloads[0].active_requests, 1);
+        assert_eq!(snapshot.tasks[0].percent, 50);
+
+        manager.sender.send(Event::DownloadProgress(FileDownload {
+            read_bytes: 100,
+            finished: true,
+            ..download
+        }))?;
+        manager.sender.send(Event::TaskProgress(TaskProgress {
+            finished: true,
+            ..task
+        }))?;
+        let snapshot = wait_for_tasks(&active, 0).await;
+        assert!(snapshot.requests.is_empty());
+        assert!(snapshot.downloads.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_files_over_4_gib() -> anyhow::Result<()> {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let db = test_db().await?;
+        let mut manager = Manager::new(db.clone());
+        let mut receiver = manager.sender.subscribe();
+
+        // The second half of a 6 GiB file, sent in chunks going past 4 GiB
+        let download = FileDownload::new(
+            "t1".to_string(),
+            "/data/disk.img".to_string(),
+            None,
+            6 * GIB,
+            3 * GIB..6 * GIB,
+        );
+        assert_eq!(download.total_bytes, 3 * GIB);
+        let mut sink =
+            DownloadProgressSink::new(download, manager.sender.clone(), Chaos::default());
+        sink.on_bytes(2 * GIB, 2 * GIB);
+        sink.on_bytes(GIB, 3 * GIB);
+        drop(sink);
+        let mut events = vec![];
+        while let Ok(Event::DownloadProgress(event)) = receiver.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 2);
+        assert!(events[1].finished && events[1].is_complete());
+        assert_eq!(events[1].sent_range(), 3 * GIB..6 * GIB);
+
+        manager.update_download_progress(events[1].clone()).await;
+        manager
+            .update_download_progress(FileDownload {
+                read_bytes: 5 * GIB,
+                finished: true,
+                ..FileDownload::new(
+                    "t2".to_string(),
+                    "/data/disk.img".to_string(),
+                    None,
+                    6 * GIB,
+                    0..6 * GIB,
+                )
+            })
+            .await;
+        let records = recent_downloads(&db, &DownloadQuery::default(), None).await?;
+        let record = &records[0];
+        assert_eq!(record.status.as_deref(), Some("complete"));
+        assert_eq!(record.file_size, Some(6 * GIB as i64));
+        assert_eq!(record.bytes_sent, 8 * GIB as i64);
+        assert_eq!(record.bytes_covered, 6 * GIB as i64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_min_interval() -> anyhow::Result<()> {
+        let manager = Manager::new(test_db().await?);
+        let mut receiver = manager.sender.subscribe();
+        let download =
+            FileDownload::new("t1".to_string(), "/data/a.iso".to_string(), None, 40, 0..40);
+        let mut sink =
+            DownloadProgressSink::new(download, manager.sender.clone(), Chaos::default())
+                .with_min_interval(Duration::from_secs(3600));
+        for total_read in [10, 20, 30, 40] {
+            sink.on_bytes(10, total_read);
+        }
+        drop(sink);
+        let mut events = vec![];
+        while let Ok(Event::DownloadProgress(event)) = receiver.try_recv() {
+            events.push(event.read_bytes);
+        }
+        // The first chunk and the last one
+        assert_eq!(events, vec![10, 40]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_throughput() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut throughput = Throughput::new(start);
+        assert_eq!(throughput.update(start, 0, 1000).eta_secs, None);
+        // 10 bytes per second for 10 seconds, then 40
+        for secs in 1..=10 {
+            throughput.update(at(secs), secs * 10, 1000);
+        }
+        let rate = throughput.update(at(10), 100, 1000);
+        assert_eq!((rate.bytes_per_sec, rate.avg_bytes_per_sec), (10, 10));
+        assert_eq!(rate.eta_secs, Some(90));
+        for secs in 11..=20 {
+            throughput.update(at(secs), 100 + (secs - 10) * 40, 1000);
         }
-        self.ongoing_download.insert(transaction_id, pm.clone());
+        // The samples of the first 10 seconds are gone
+        let rate = throughput.update(at(20), 500, 1000);
+        assert_eq!((rate.bytes_per_sec, rate.avg_bytes_per_sec), (40, 25));
+        assert_eq!(rate.eta_secs, Some(13));
+        assert_eq!(throughput.update(at(21), 1000, 1000).eta_secs, Some(0));
     }
 }
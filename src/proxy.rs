@@ -0,0 +1,288 @@
+//! Requests forwarded by a reverse proxy.
+//!
+//! Only the proxies of `HARDWIRE_TRUSTED_PROXIES`, a comma-separated list of addresses and CIDR
+//! networks, are believed: for their requests the client address is taken from `X-Forwarded-For`
+//! or `X-Real-IP`, and the links generated for the client from `X-Forwarded-Proto` and
+//! `X-Forwarded-Host`. Any other client is identified by its own address and gets links to
+//! `HARDWIRE_HOST`, whatever headers it sends. Connections through a Unix socket come from the
+//! host itself and are trusted.
+
+use anyhow::Context;
+use axum::extract::{ConnectInfo, FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::listen::PeerAddr;
+use crate::ServerConfig;
+
+/// Proxies on the same host
+pub const STD_TRUSTED_PROXIES: &str = "127.0.0.0/8,::1";
+
+/// Network of an address and a prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Bits which differ between the network and the address, for the prefix to cover
+        let (diff, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => ((u32::from(net) ^ u32::from(ip)) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(ip), 128),
+            _ => return false,
+        };
+        diff.leading_zeros() - (128 - bits) >= self.prefix as u32
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let addr = crate::listen::parse_ip(addr)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if prefix.is_empty() {
+            max
+        } else {
+            prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("Invalid prefix length in {}", s))?
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .map(str::parse)
+            .collect::<anyhow::Result<_>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// The client of a request, as seen through the trusted proxies
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    pub ip: Option<IpAddr>,
+    /// Scheme and host the client reached the server with, when a trusted proxy tells
+    pub base_url: Option<String>,
+}
+
+impl Client {
+    /// Host of the links generated for the client: `host`, the configured `HARDWIRE_HOST`,
+    /// unless a trusted proxy forwarded the request for another one
+    pub fn host(&self, host: &str) -> String {
+        let Some(base_url) = &self.base_url else {
+            return host.to_string();
+        };
+        // Keep the path under which the server is published
+        let path = url::Url::parse(host)
+            .map(|url| url.path().trim_end_matches('/').to_string())
+            .unwrap_or_default();
+        format!("{}{}", base_url, path)
+    }
+
+    fn resolve(
+        trusted: &TrustedProxies,
+        peer: Option<PeerAddr>,
+        headers: &HeaderMap,
+        host: &str,
+    ) -> Self {
+        let peer_trusted = match peer {
+            Some(PeerAddr::Unix) => true,
+            Some(PeerAddr::Tcp(addr)) => trusted.contains(addr.ip()),
+            None => false,
+        };
+        let peer_ip = match peer {
+            Some(PeerAddr::Tcp(addr)) => Some(addr.ip().to_canonical()),
+            _ => None,
+        };
+        if !peer_trusted {
+            return Client {
+                ip: peer_ip,
+                base_url: None,
+            };
+        }
+
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+        // Each proxy appends the address it got the request from, the client is the last one
+        // before the trusted proxies
+        let forwarded_for: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| crate::listen::parse_ip(ip).ok())
+            .map(|ip| ip.to_canonical())
+            .collect();
+        let ip = forwarded_for
+            .iter()
+            .rev()
+            .find(|ip| !trusted.contains(**ip))
+            .or(forwarded_for.first())
+            .copied()
+            .or_else(|| header("x-real-ip").and_then(|ip| crate::listen::parse_ip(ip).ok()))
+            .or(peer_ip);
+
+        let proto = header("x-forwarded-proto").and_then(|proto| proto.split(',').next());
+        let forwarded_host = header("x-forwarded-host").and_then(|host| host.split(',').next());
+        let base_url = if proto.is_some() || forwarded_host.is_some() {
+            let proto = proto
+                .map(str::trim)
+                .or_else(|| host.split_once("://").map(|(scheme, _)| scheme))
+                .filter(|proto| matches!(*proto, "http" | "https"));
+            let authority = forwarded_host
+                .or_else(|| header("host"))
+                .and_then(|host| host.trim().parse::<axum::http::uri::Authority>().ok())
+                .filter(|authority| !authority.as_str().contains('@'));
+            proto
+                .zip(authority)
+                .map(|(proto, authority)| format!("{}://{}", proto, authority))
+        } else {
+            None
+        };
+        Client { ip, base_url }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Client {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Client>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Address of the client, as recorded with the downloads and the audit log
+pub(crate) fn client_ip(extensions: &Extensions) -> Option<String> {
+    extensions
+        .get::<Client>()
+        .and_then(|client| client.ip)
+        .map(|ip| ip.to_string())
+}
+
+/// Identify the [`Client`] of every request, before any handler or log needs it
+pub async fn resolve_client(
+    State(config): State<Arc<ServerConfig>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<PeerAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    let client = Client::resolve(
+        &config.trusted_proxies,
+        peer,
+        request.headers(),
+        &config.host,
+    );
+    request.extensions_mut().insert(client);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn peer(addr: &str) -> Option<PeerAddr> {
+        Some(PeerAddr::Tcp(addr.parse::<SocketAddr>().unwrap()))
+    }
+
+    #[test]
+    fn test_cidr() {
+        let trusted: TrustedProxies = "10.0.0.0/8, 192.0.2.7, fd00::/8".parse().unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("192.0.2.7".parse().unwrap()));
+        assert!(!trusted.contains("192.0.2.8".parse().unwrap()));
+        assert!(trusted.contains("fd12::1".parse().unwrap()));
+        assert!(!trusted.contains("fe80::1".parse().unwrap()));
+        let all: TrustedProxies = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("203.0.113.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("proxy.lan".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let trusted: TrustedProxies = STD_TRUSTED_PROXIES.parse().unwrap();
+        let host = "https://files.example.com";
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.1, 203.0.113.9, 127.0.0.1".parse().unwrap(),
+        );
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("x-forwarded-host", "share.example.org".parse().unwrap());
+
+        // The client may have sent a spoofed X-Forwarded-For, only the entry of the proxy counts
+        let client = Client::resolve(&trusted, peer("127.0.0.1:41000"), &headers, host);
+        assert_eq!(client.ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(client.host(host), "https://share.example.org");
+        let client = Client::resolve(&trusted, Some(PeerAddr::Unix), &headers, host);
+        assert_eq!(client.ip, Some("203.0.113.9".parse().unwrap()));
+
+        // Headers of untrusted peers are ignored
+        let client = Client::resolve(&trusted, peer("203.0.113.50:41000"), &headers, host);
+        assert_eq!(client.ip, Some("203.0.113.50".parse().unwrap()));
+        assert_eq!(client.host(host), host);
+        assert_eq!(Client::resolve(&trusted, None, &headers, host).ip, None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "198.51.100.2".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("host", "localhost:8090".parse().unwrap());
+        let client = Client::resolve(
+            &trusted,
+            peer("[::1]:41000"),
+            &headers,
+            "https://x.example/hw/",
+        );
+        assert_eq!(client.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(
+            client.host("https://x.example/hw/"),
+            "http://localhost:8090/hw"
+        );
+
+        headers.insert("x-forwarded-host", "evil.example@x".parse().unwrap());
+        let client = Client::resolve(&trusted, peer("127.0.0.1:41000"), &headers, host);
+        assert_eq!(client.base_url, None);
+    }
+}
@@ -0,0 +1,114 @@
+//! Public JSON API of the shares, for clients which would otherwise scrape the share page.
+//!
+//! `GET /api/v1/shares/{share_id}` answers the metadata of a share: its expiry and, for each file,
+//! its size, digests and download URL. The share is found by its id or alias, and asks for its
+//! password like the share page. Responses carry `schema_version`: fields may be added within a
+//! version, never renamed or removed.
+
+use axum::extract::{Path, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::error::{AppError, AppResult};
+use crate::proxy::Client;
+use crate::share_page::FileKind;
+use crate::App;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ShareInfo {
+    pub schema_version: u32,
+    pub share_id: String,
+    /// Unix timestamp after which the share is not served anymore, `null` when it never expires
+    pub expires_at: Option<i64>,
+    /// Names and contents are end-to-end encrypted, the key is not known to the server
+    pub encrypted: bool,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = SharedFileInfo)]
+pub struct FileInfo {
+    pub name: String,
+    /// Bytes, `null` for files shared before sizes were recorded
+    pub size: Option<i64>,
+    #[serde(rename = "type")]
+    pub kind: FileKind,
+    pub url: String,
+    /// Hex digests by algorithm, once the ChecksumShare task hashed the file
+    pub digests: BTreeMap<String, String>,
+    /// Found missing or altered by the last verification of the shared files
+    pub unavailable: bool,
+}
+
+/// Document of the public API, relative to the prefix it is mounted on, see [`crate::openapi`]
+#[derive(OpenApi)]
+#[openapi(paths(get_share))]
+pub struct PublicApi;
+
+pub fn router() -> Router<App> {
+    Router::new().route("/shares/{share_id}", get(get_share))
+}
+
+/// Metadata of a share and of its files
+#[utoipa::path(
+    get,
+    path = "/shares/{share_id}",
+    tag = "public",
+    params(("share_id" = String, Path, description = "Share id or alias")),
+    responses(
+        (status = 200, body = ShareInfo),
+        (status = 401, description = "The share asks for its password")
+    )
+)]
+async fn get_share(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+    client: Client,
+) -> AppResult<Json<ShareInfo>> {
+    let share = app_state
+        .share_cache
+        .get(&share_id, &app_state.db_reader)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Share not found".to_string()))?;
+    let mut digests: HashMap<String, BTreeMap<String, String>> = HashMap::new();
+    for row in sqlx::query!(
+        r#"SELECT share_link_files.token AS "token!", file_digests.algorithm, file_digests.digest
+        FROM share_link_files JOIN file_digests ON file_digests.file_id = share_link_files.file_id
+        WHERE share_link_files.share_link_id = ?"#,
+        share_id
+    )
+    .fetch_all(&app_state.db_reader)
+    .await?
+    {
+        digests
+            .entry(row.token)
+            .or_default()
+            .insert(row.algorithm, row.digest);
+    }
+
+    let host = client.host(&app_state.config.host);
+    let files = share
+        .files
+        .iter()
+        .map(|file| FileInfo {
+            name: file.short_filename.clone(),
+            size: file.size,
+            kind: FileKind::from_name(&file.short_filename),
+            url: format!("{}/s/{}/{}", host, share_id, file.link),
+            digests: digests.remove(&file.link).unwrap_or_default(),
+            unavailable: file.unavailable,
+        })
+        .collect();
+    Ok(Json(ShareInfo {
+        schema_version: SCHEMA_VERSION,
+        expires_at: (share.expiration >= 0).then_some(share.expiration),
+        encrypted: share.encrypted,
+        files,
+        share_id,
+    }))
+}
@@ -0,0 +1,214 @@
+//! Output of `hardwire publish`.
+//!
+//! `--dry-run` checks the files against the share roots without touching the database: each file
+//! is listed with the path it would be registered with and its size, or the reason it can't be
+//! published, and the command fails when one can't. `--json` prints the plan, or the created share,
+//! as JSON for scripts instead of the `Shared link:` line.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+use crate::remote::Remote;
+use crate::share_roots::ShareRoots;
+use crate::top::format_bytes;
+use crate::worker::health;
+
+/// A file given to `publish --dry-run`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedFile {
+    /// As given on the command line
+    pub path: String,
+    /// Canonical path the file would be registered with
+    pub shared_path: Option<String>,
+    pub size: Option<u64>,
+    /// Why the file can't be published
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PublishPlan {
+    pub files: Vec<PlannedFile>,
+    /// Size of the files which can be published
+    pub total_bytes: u64,
+}
+
+impl PublishPlan {
+    /// Check each of `paths` as `publish` would, and read its size
+    pub fn check(roots: &ShareRoots, paths: &[String]) -> Self {
+        let files: Vec<PlannedFile> = paths
+            .iter()
+            .map(|path| {
+                let mut planned = PlannedFile {
+                    path: path.clone(),
+                    shared_path: None,
+                    size: None,
+                    error: None,
+                };
+                let file = match roots.resolve(Path::new(path)) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        planned.error = Some(e.to_string());
+                        return planned;
+                    }
+                };
+                planned.shared_path = Some(file.path().to_string_lossy().into_owned());
+                match File::open(file.path()).and_then(|file| file.metadata()) {
+                    Ok(metadata) => planned.size = Some(metadata.len()),
+                    Err(e) => planned.error = Some(format!("{} is not readable: {}", path, e)),
+                }
+                planned
+            })
+            .collect();
+        PublishPlan {
+            total_bytes: files.iter().filter_map(|file| file.size).sum(),
+            files,
+        }
+    }
+
+    /// Fails when a file can't be published
+    pub fn result(&self) -> Result<()> {
+        let failed = self
+            .files
+            .iter()
+            .filter(|file| file.error.is_some())
+            .count();
+        if failed > 0 {
+            return Err(anyhow!(
+                "{} of {} files can't be published",
+                failed,
+                self.files.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PublishPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            match (&file.shared_path, file.size, &file.error) {
+                (Some(shared_path), Some(size), None) => {
+                    writeln!(f, "{:>10}  {}", format_bytes(size), shared_path)?
+                }
+                (_, _, error) => writeln!(
+                    f,
+                    "{:>10}  {}",
+                    "error",
+                    error.as_deref().unwrap_or(&file.path)
+                )?,
+            }
+        }
+        let publishable = self
+            .files
+            .iter()
+            .filter(|file| file.error.is_none())
+            .count();
+        write!(
+            f,
+            "{} of {} files would be published, {} in total",
+            publishable,
+            self.files.len(),
+            format_bytes(self.total_bytes)
+        )
+    }
+}
+
+/// The share created by `publish`, printed by `--json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PublishedShare {
+    pub id: String,
+    /// Link of the share, with the key of an encrypted share
+    pub url: String,
+    /// Unix timestamp after which the share is not served anymore, `null` when it never expires
+    pub expires_at: Option<i64>,
+}
+
+/// Fields of `GET /shares/{share_id}` read back after publishing remotely
+#[derive(Deserialize)]
+struct RemoteShare {
+    id: String,
+    expiration: i64,
+}
+
+impl PublishedShare {
+    fn new(id: String, url: &str, expiration: i64) -> Self {
+        PublishedShare {
+            id,
+            url: url.to_string(),
+            expires_at: (expiration >= 0).then_some(expiration),
+        }
+    }
+
+    /// The share of `link`, as stored in `db`
+    pub async fn load(db: &SqlitePool, link: &str) -> Result<Self> {
+        let share_id = link_share_id(link);
+        let share = health::share_details(db, share_id)
+            .await?
+            .ok_or_else(|| anyhow!("No share {}", share_id))?;
+        Ok(PublishedShare::new(share.id, link, share.expiration))
+    }
+
+    /// The share of `link`, asked to the server which created it
+    pub async fn fetch(remote: &Remote, link: &str) -> Result<Self> {
+        let share: RemoteShare = remote
+            .get(&format!("/shares/{}", link_share_id(link)))
+            .await?;
+        Ok(PublishedShare::new(share.id, link, share.expiration))
+    }
+}
+
+/// Id or alias at the end of a share link
+fn link_share_id(link: &str) -> &str {
+    link.rsplit('/').next().unwrap_or(link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("movies"))?;
+        std::fs::write(root.join("a.txt"), "hello")?;
+        std::fs::write(root.join("movies/b.mkv"), "world!")?;
+        let roots = ShareRoots::new([root.clone()]);
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+        let plan = PublishPlan::check(&roots, &[path("a.txt"), path("movies/b.mkv")]);
+        assert_eq!(plan.total_bytes, 11);
+        assert_eq!(plan.files[0].size, Some(5));
+        assert!(plan.result().is_ok());
+        assert!(plan
+            .to_string()
+            .ends_with("2 of 2 files would be published, 11 B in total"));
+
+        let plan = PublishPlan::check(
+            &roots,
+            &[path("a.txt"), path("missing.txt"), path("movies")],
+        );
+        assert_eq!(plan.total_bytes, 5);
+        assert_eq!(
+            plan.files[1].error,
+            Some(format!("{} does not exist", path("missing.txt")))
+        );
+        assert_eq!(plan.files[2].shared_path, None);
+        assert_eq!(
+            plan.result().unwrap_err().to_string(),
+            "2 of 3 files can't be published"
+        );
+
+        let share = PublishedShare::new("abc".to_string(), "http://localhost/s/abc", -1);
+        assert_eq!(share.expires_at, None);
+        assert_eq!(
+            link_share_id("http://localhost/s/vacation-2024"),
+            "vacation-2024"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Client of the admin API, for the CLI commands working against a running server.
+//!
+//! Writing to the database directly only works next to the server; through the API the CLI also
+//! works when the server runs in a container or on another host. File paths are then the paths
+//! seen by the server.
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::{Method, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const API_PREFIX: &str = "/admin/api/v1";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    /// Includes the fields at fault of validation errors
+    message: String,
+}
+
+/// Body of `POST /create_shared_link`
+#[derive(Debug, Serialize)]
+pub struct PublishRequest {
+    /// Paths on the server
+    pub files: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// See [`crate::share_profiles`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// See [`crate::share_burn`]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub burn_after_reading: bool,
+}
+
+pub struct Remote {
+    client: reqwest::Client,
+    url: String,
+    api_key: Option<String>,
+}
+
+impl Remote {
+    /// Admin API of the server at `url`, e.g. `https://files.example.com`
+    pub fn new(url: &str, api_key: Option<&str>) -> Result<Self> {
+        Ok(Remote {
+            client: reqwest::Client::builder().timeout(TIMEOUT).build()?,
+            url: url.trim_end_matches('/').to_string(),
+            api_key: api_key.map(str::to_string),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}{}{}", self.url, API_PREFIX, path));
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = self.request(Method::GET, path).send().await;
+        self.decode(path, response).await
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        let response = self.request(Method::POST, path).json(body).send().await;
+        self.decode(path, response).await
+    }
+
+    async fn decode<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        response: reqwest::Result<Response>,
+    ) -> Result<T> {
+        let endpoint = format!("{}{}{}", self.url, API_PREFIX, path);
+        let response = response.with_context(|| format!("Failed to reach {}", endpoint))?;
+        let status = response.status();
+        if status.is_success() {
+            return response
+                .json()
+                .await
+                .with_context(|| format!("Unexpected answer from {}", endpoint));
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(anyhow!(
+            "{} answered {}: {}",
+            endpoint,
+            status,
+            error_message(&body)
+        ))
+    }
+
+    /// Share the files of `request` and return the link of the share
+    pub async fn publish(&self, request: &PublishRequest) -> Result<String> {
+        let link: Option<String> = match request {
+            // The plain list is understood by servers predating aliases
+            PublishRequest {
+                files,
+                alias: None,
+                profile: None,
+                password: None,
+                burn_after_reading: false,
+            } => self.post("/create_shared_link", files).await?,
+            request => self.post("/create_shared_link", request).await?,
+        };
+        link.ok_or_else(|| anyhow!("{} did not return a share link", self.url))
+    }
+}
+
+/// Message of an error answered by the API, the whole body when it isn't JSON
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<ErrorEnvelope>(body)
+        .map(|envelope| envelope.error.message)
+        .unwrap_or_else(|_| body.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_message() {
+        let body = r#"{"error":{"code":"unauthorized","message":"Missing or invalid API key"}}"#;
+        assert_eq!(error_message(body), "Missing or invalid API key");
+        assert_eq!(
+            error_message("Something went wrong: disk full\n"),
+            "Something went wrong: disk full"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Settings changed at runtime through the admin API.
+//!
+//! The values start from the environment, then the ones set with `PATCH /settings` and stored in
+//! the `settings` table override them, at once and after a restart. [`Settings`] keeps the current
+//! values in a `watch` channel: the handlers read them on each request, the components built from
+//! them, such as the [`DownloadLimiter`], subscribe to their changes.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::SqlitePool;
+use std::sync::Arc;
+use tokio::sync::watch;
+use utoipa::ToSchema;
+
+use crate::download_limits::{DownloadLimiter, LimitsConfig};
+use crate::validation::{Validate, Validator};
+
+/// Values of the settings, `null` for no limit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeSettings {
+    /// Downloads in progress of a client address, `HARDWIRE_MAX_DOWNLOADS_PER_CLIENT` by default
+    pub max_downloads_per_client: Option<usize>,
+    /// Downloads in progress of a file, `HARDWIRE_MAX_READERS_PER_FILE` by default
+    pub max_readers_per_file: Option<usize>,
+    /// Rate of each download in bytes per second, the lower of it and the rate of the share applies
+    pub max_bytes_per_sec: Option<u64>,
+    /// Expiry of the shares created through the admin API without one
+    pub default_expiry_days: Option<i64>,
+}
+
+impl RuntimeSettings {
+    /// The values given by the environment
+    pub fn new(limits: &LimitsConfig) -> Self {
+        RuntimeSettings {
+            max_downloads_per_client: limits.per_client,
+            max_readers_per_file: limits.per_file,
+            ..Default::default()
+        }
+    }
+
+    pub fn limits(&self) -> LimitsConfig {
+        LimitsConfig {
+            per_client: self.max_downloads_per_client,
+            per_file: self.max_readers_per_file,
+        }
+    }
+
+    /// `self` with the values of `patch`, which must only hold settings
+    fn merge(&self, patch: &Map<String, Value>) -> serde_json::Result<Self> {
+        let mut values = match serde_json::to_value(self)? {
+            Value::Object(values) => values,
+            _ => unreachable!("settings serialize to an object"),
+        };
+        values.extend(patch.clone());
+        serde_json::from_value(Value::Object(values))
+    }
+}
+
+impl Validate for RuntimeSettings {
+    fn validate(&self, v: &mut Validator) {
+        let at_least_one = "must be at least 1, null for no limit";
+        if let Some(limit) = self.max_downloads_per_client {
+            v.check(limit > 0, "max_downloads_per_client", at_least_one);
+        }
+        if let Some(limit) = self.max_readers_per_file {
+            v.check(limit > 0, "max_readers_per_file", at_least_one);
+        }
+        if let Some(rate) = self.max_bytes_per_sec {
+            v.check(rate > 0, "max_bytes_per_sec", at_least_one);
+        }
+        if let Some(days) = self.default_expiry_days {
+            v.expiry("default_expiry_days", days.saturating_mul(24 * 3600));
+        }
+    }
+}
+
+/// Body of `PATCH /settings`: the settings to change with their new value
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct SettingsPatch(pub Map<String, Value>);
+
+impl Validate for SettingsPatch {
+    fn validate(&self, v: &mut Validator) {
+        let defaults = RuntimeSettings::default();
+        for (key, value) in &self.0 {
+            let single = Map::from_iter([(key.clone(), value.clone())]);
+            match defaults.merge(&single) {
+                Ok(settings) => settings.validate(v),
+                Err(_) if !is_setting(key) => v.error(key, "is not a setting"),
+                Err(e) => v.error(key, e.to_string()),
+            }
+        }
+    }
+}
+
+fn is_setting(key: &str) -> bool {
+    match serde_json::to_value(RuntimeSettings::default()) {
+        Ok(Value::Object(values)) => values.contains_key(key),
+        _ => false,
+    }
+}
+
+/// The current settings, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Settings {
+    current: Arc<watch::Sender<RuntimeSettings>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::new(RuntimeSettings::default())
+    }
+}
+
+impl Settings {
+    pub fn new(values: RuntimeSettings) -> Self {
+        Settings {
+            current: Arc::new(watch::Sender::new(values)),
+        }
+    }
+
+    /// `defaults` overridden by the values stored in `settings`
+    pub async fn load(db: &SqlitePool, defaults: RuntimeSettings) -> Result<Self> {
+        let rows = sqlx::query!("SELECT key, value FROM settings")
+            .fetch_all(db)
+            .await?;
+        let mut stored = Map::new();
+        // The table also holds other state, such as the maintenance mode
+        for row in rows {
+            if is_setting(&row.key) {
+                stored.insert(row.key, serde_json::from_str(&row.value)?);
+            }
+        }
+        Ok(Settings::new(defaults.merge(&stored)?))
+    }
+
+    pub fn get(&self) -> RuntimeSettings {
+        self.current.borrow().clone()
+    }
+
+    /// Notified of each change
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeSettings> {
+        self.current.subscribe()
+    }
+
+    /// Store the values of a validated `patch` and apply them
+    pub async fn update(&self, db: &SqlitePool, patch: &SettingsPatch) -> Result<RuntimeSettings> {
+        let settings = self.get().merge(&patch.0)?;
+        let now = chrono::offset::Utc::now().timestamp();
+        let mut tx = db.begin().await?;
+        for (key, value) in &patch.0 {
+            let value = value.to_string();
+            sqlx::query!(
+                r#"INSERT INTO settings (key, value, updated_at) VALUES (?, ?, ?)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at"#,
+                key,
+                value,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        self.current.send_replace(settings.clone());
+        Ok(settings)
+    }
+
+    /// Apply the limits of the settings to `limiter` whenever they change
+    pub fn spawn_limits_updater(&self, limiter: DownloadLimiter) {
+        let mut changes = self.subscribe();
+        tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                let limits = changes.borrow_and_update().limits();
+                limiter.reconfigure(&limits);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    fn patch(value: Value) -> SettingsPatch {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn errors(patch: &SettingsPatch) -> Vec<String> {
+        let mut v = Validator::default();
+        patch.validate(&mut v);
+        match v.finish() {
+            Ok(()) => vec![],
+            Err(crate::error::AppError::Validation(errors)) => {
+                let mut fields: Vec<String> = errors.into_iter().map(|error| error.field).collect();
+                fields.sort();
+                fields
+            }
+            Err(e) => panic!("Unexpected {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_and_load() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let defaults = RuntimeSettings::new(&LimitsConfig {
+            per_client: Some(4),
+            per_file: None,
+        });
+        let settings = Settings::load(&db, defaults.clone()).await?;
+        assert_eq!(settings.get(), defaults);
+        let mut changes = settings.subscribe();
+
+        let update =
+            patch(serde_json::json!({"max_readers_per_file": 2, "max_downloads_per_client": null}));
+        assert!(errors(&update).is_empty());
+        let updated = settings.update(&db, &update).await?;
+        assert_eq!(
+            (
+                updated.max_downloads_per_client,
+                updated.max_readers_per_file
+            ),
+            (None, Some(2))
+        );
+        assert!(changes.has_changed()?);
+        assert_eq!(*changes.borrow_and_update(), updated);
+
+        // The stored values override the environment, the others follow it
+        let defaults = RuntimeSettings {
+            default_expiry_days: Some(30),
+            ..defaults
+        };
+        let reloaded = Settings::load(&db, defaults).await?.get();
+        assert_eq!(
+            reloaded,
+            RuntimeSettings {
+                default_expiry_days: Some(30),
+                ..updated
+            }
+        );
+
+        let invalid = patch(serde_json::json!({
+            "max_bytes_per_sec": 0,
+            "default_expiry_days": "soon",
+            "colour": "blue",
+        }));
+        assert_eq!(
+            errors(&invalid),
+            ["colour", "default_expiry_days", "max_bytes_per_sec"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Vanity aliases of shares.
+//!
+//! A share may be given a readable slug when it is created, e.g. `vacation-2024`, stored in
+//! `share_links.alias` next to its generated id. [`resolve_aliases`] rewrites `/s/<alias>/...` to
+//! `/s/<id>/...`, and `/api/v1/shares/<alias>` likewise, before routing, so every share route
+//! accepts both.
+
+use axum::extract::{Request, State};
+use axum::http::Uri;
+use axum::middleware::Next;
+use axum::response::Response;
+use sqlx::SqlitePool;
+use std::fmt;
+
+use crate::App;
+
+/// Paths of a share: its page and files, and its metadata in the public API
+const SHARE_PREFIXES: [&str; 2] = ["/s/", "/api/v1/shares/"];
+
+pub const MIN_ALIAS_LEN: usize = 3;
+pub const MAX_ALIAS_LEN: usize = 64;
+/// Words kept for the server, whether or not it serves them yet
+const RESERVED: &[&str] = &[
+    "admin",
+    "api",
+    "assets",
+    "healthcheck",
+    "hooks",
+    "inline",
+    "login",
+    "logout",
+    "new",
+    "preview",
+    "probe",
+    "qr",
+    "s",
+    "share",
+    "shares",
+    "static",
+    "suggestion",
+    "upload",
+    "uploads",
+];
+
+/// The alias, or a share id equal to it, is already used by another share
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasTaken(pub String);
+
+impl fmt::Display for AliasTaken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The alias {} is already taken", self.0)
+    }
+}
+
+impl std::error::Error for AliasTaken {}
+
+/// Why `alias` can't be used, whether or not it is taken
+pub fn check(alias: &str) -> Result<(), String> {
+    if !(MIN_ALIAS_LEN..=MAX_ALIAS_LEN).contains(&alias.len()) {
+        return Err(format!(
+            "must be between {} and {} characters",
+            MIN_ALIAS_LEN, MAX_ALIAS_LEN
+        ));
+    }
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err("must only contain lowercase letters, digits and '-'".to_string());
+    }
+    if alias.starts_with('-') || alias.ends_with('-') {
+        return Err("must not start or end with '-'".to_string());
+    }
+    if RESERVED.contains(&alias) {
+        return Err("is reserved".to_string());
+    }
+    Ok(())
+}
+
+/// Whether no share uses `alias` as its alias or id
+pub async fn is_available(db: &SqlitePool, alias: &str) -> Result<bool, sqlx::Error> {
+    let taken = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM share_links WHERE alias = ?1 OR id = ?1) AS "taken!: bool""#,
+        alias
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(!taken)
+}
+
+/// Id of the share aliased `alias`
+pub async fn resolve(db: &SqlitePool, alias: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT id AS "id!" FROM share_links WHERE alias = ?"#,
+        alias
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Id of the share `id_or_alias`, given by its id or its alias
+pub async fn share_id(db: &SqlitePool, id_or_alias: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT id AS "id!" FROM share_links WHERE id = ?1 OR alias = ?1"#,
+        id_or_alias
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// Serve `/s/<alias>/...` as `/s/<id>/...`. Runs before routing, unknown segments are left as
+/// they are for the share routes to answer 404.
+pub async fn resolve_aliases(
+    State(app_state): State<App>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(uri) = aliased_uri(&app_state.db_reader, request.uri()).await {
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+/// Prefix, share id or alias and rest of the path of a share, the rest being empty, `/<tail>` or
+/// the `.json` of the JSON share page
+pub fn split_share_path(path: &str) -> Option<(&'static str, &str, &str)> {
+    let (prefix, rest) = SHARE_PREFIXES
+        .iter()
+        .find_map(|prefix| Some((*prefix, path.strip_prefix(prefix)?)))?;
+    let mut end = rest.find('/').unwrap_or(rest.len());
+    if end == rest.len() && rest.ends_with(".json") {
+        end -= ".json".len();
+    }
+    Some((prefix, &rest[..end], &rest[end..]))
+}
+
+async fn aliased_uri(db: &SqlitePool, uri: &Uri) -> Option<Uri> {
+    let (prefix, alias, tail) = split_share_path(uri.path())?;
+    check(alias).ok()?;
+    let share_id = resolve(db, alias)
+        .await
+        .inspect_err(|e| tracing::error!("Failed to resolve share alias {}: {}", alias, e))
+        .ok()??;
+    rewrite(uri, prefix, &share_id, tail)
+}
+
+/// `uri` pointing to `<prefix><share_id><tail>`, with the same query
+fn rewrite(uri: &Uri, prefix: &str, share_id: &str, tail: &str) -> Option<Uri> {
+    let mut path_and_query = format!("{}{}{}", prefix, share_id, tail);
+    if let Some(query) = uri.query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check("vacation-2024").is_ok());
+        assert!(check("ab").is_err());
+        assert!(check("Vacation").is_err());
+        assert!(check("-vacation").is_err());
+        assert!(check("admin").is_err());
+        assert!(check(&"a".repeat(MAX_ALIAS_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let uri: Uri = "/s/vacation-2024/3/inline?x=1".parse().unwrap();
+        let (prefix, alias, tail) = split_share_path(uri.path()).unwrap();
+        assert_eq!((alias, tail), ("vacation-2024", "/3/inline"));
+        assert_eq!(
+            rewrite(&uri, prefix, "V1StGXR8_Z", tail).unwrap(),
+            "/s/V1StGXR8_Z/3/inline?x=1"
+        );
+        let uri: Uri = "/s/vacation-2024.json".parse().unwrap();
+        let (prefix, alias, tail) = split_share_path(uri.path()).unwrap();
+        assert_eq!(alias, "vacation-2024");
+        assert_eq!(
+            rewrite(&uri, prefix, "V1StGXR8_Z", tail).unwrap(),
+            "/s/V1StGXR8_Z.json"
+        );
+        let uri: Uri = "/api/v1/shares/vacation-2024".parse().unwrap();
+        let (prefix, _, tail) = split_share_path(uri.path()).unwrap();
+        assert_eq!(
+            rewrite(&uri, prefix, "V1StGXR8_Z", tail).unwrap(),
+            "/api/v1/shares/V1StGXR8_Z"
+        );
+        assert!(split_share_path("/admin/api/v1/shares").is_none());
+    }
+}
@@ -0,0 +1,159 @@
+//! Branding of the share page.
+//!
+//! A share can replace the HardWire title of its page with its own title, description, logo and
+//! accent color, so deliveries to clients carry the sender's identity. The branding is stored as
+//! JSON in `share_links.branding`, `NULL` for the default look.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::validation::{Validate, Validator};
+
+const MAX_TITLE_LEN: usize = 100;
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_LOGO_URL_LEN: usize = 2048;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct Branding {
+    /// Heading and title of the page, `HardWire` when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Shown under the title, e.g. who the delivery is for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// `#rgb` or `#rrggbb` color of the download buttons
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accent_color: Option<String>,
+    /// HTTPS URL of an image shown above the title
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo_url: Option<String>,
+}
+
+impl Branding {
+    pub fn is_empty(&self) -> bool {
+        *self == Branding::default()
+    }
+
+    /// The branding stored in a `branding` column, the default look when unset or invalid
+    pub fn from_column(column: Option<&str>) -> Self {
+        column
+            .and_then(|branding| serde_json::from_str(branding).ok())
+            .unwrap_or_default()
+    }
+
+    /// Value of the `branding` column
+    pub fn to_column(&self) -> Result<Option<String>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_string(self)?))
+    }
+}
+
+fn is_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| matches!(hex.len(), 3 | 6) && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+impl Validate for Branding {
+    fn validate(&self, v: &mut Validator) {
+        let texts = [
+            ("title", &self.title, MAX_TITLE_LEN),
+            ("description", &self.description, MAX_DESCRIPTION_LEN),
+        ];
+        for (field, text, max_len) in texts {
+            if let Some(text) = text {
+                v.check(
+                    !text.trim().is_empty() && text.chars().count() <= max_len,
+                    field,
+                    &format!("must be between 1 and {} characters", max_len),
+                );
+            }
+        }
+        if let Some(color) = &self.accent_color {
+            v.check(is_color(color), "accent_color", "must be #rgb or #rrggbb");
+        }
+        if let Some(logo_url) = &self.logo_url {
+            let valid_url = url::Url::parse(logo_url)
+                .is_ok_and(|url| url.scheme() == "https" && url.has_host());
+            v.check(
+                valid_url && logo_url.len() <= MAX_LOGO_URL_LEN,
+                "logo_url",
+                "must be an https URL",
+            );
+        }
+    }
+}
+
+/// Replace the branding of a share, `false` when there is no such share
+pub async fn save(db: &SqlitePool, share_id: &str, branding: &Branding) -> Result<bool> {
+    let branding = branding.to_column()?;
+    let result = sqlx::query!(
+        "UPDATE share_links SET branding = ? WHERE id = ?",
+        branding,
+        share_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(branding: serde_json::Value) -> Vec<String> {
+        let branding: Branding = serde_json::from_value(branding).unwrap();
+        let mut v = Validator::default();
+        branding.validate(&mut v);
+        match v.finish() {
+            Ok(()) => vec![],
+            Err(crate::error::AppError::Validation(errors)) => {
+                errors.into_iter().map(|error| error.field).collect()
+            }
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = serde_json::json!({
+            "title": "Acme Studio",
+            "description": "Final cut for the client",
+            "accent_color": "#0a7",
+            "logo_url": "https://acme.example.com/logo.png",
+        });
+        assert!(errors(valid).is_empty());
+        assert!(errors(serde_json::json!({})).is_empty());
+        assert_eq!(
+            errors(serde_json::json!({
+                "title": " ",
+                "accent_color": "red",
+                "logo_url": "javascript:alert(1)",
+            })),
+            ["title", "accent_color", "logo_url"]
+        );
+        assert_eq!(
+            errors(serde_json::json!({ "accent_color": "#12345g" })),
+            ["accent_color"]
+        );
+    }
+
+    #[test]
+    fn test_column() -> Result<()> {
+        assert_eq!(Branding::default().to_column()?, None);
+        let branding = Branding {
+            title: Some("Acme".to_string()),
+            ..Default::default()
+        };
+        let column = branding.to_column()?;
+        assert_eq!(column.as_deref(), Some(r#"{"title":"Acme"}"#));
+        assert_eq!(Branding::from_column(column.as_deref()), branding);
+        assert_eq!(Branding::from_column(Some("not json")), Branding::default());
+        Ok(())
+    }
+}
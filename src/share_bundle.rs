@@ -0,0 +1,327 @@
+//! Export and import of the shares as a JSON bundle.
+//!
+//! The bundle holds every share with its settings, the trash included, and the metadata of its
+//! files: path, size, digests and link token, so the links keep working once imported on another
+//! host. Password hashes are exported as is, the bundle must be kept as private as the database.
+//! Files are not part of the bundle: they must be at the same paths on the importing host, in its
+//! share roots, and shares whose files are missing are reported rather than imported. Shares whose
+//! id already exists are left alone, importing a bundle twice changes nothing.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::path::Path;
+use utoipa::ToSchema;
+
+use crate::filename_rules::FilenameRule;
+use crate::share_branding::Branding;
+use crate::share_roots::ShareRoots;
+use crate::validation::{Validate, Validator};
+
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ShareBundle {
+    pub version: u32,
+    pub exported_at: i64,
+    pub shares: Vec<BundledShare>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BundledShare {
+    pub id: String,
+    pub alias: Option<String>,
+    pub created_at: i64,
+    /// -1 when the share never expires
+    pub expiration: i64,
+    pub encrypted: bool,
+    pub filename_rules: Vec<FilenameRule>,
+    /// Argon2 PHC string
+    pub password_hash: Option<String>,
+    /// Defined in shares.toml
+    pub managed: bool,
+    pub notify: bool,
+    pub notified_at: Option<i64>,
+    pub max_bytes_per_sec: Option<i64>,
+    pub namespace: Option<String>,
+    pub deleted_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Branding::is_empty")]
+    pub branding: Branding,
+    /// Markdown of the share page
+    #[serde(default)]
+    pub readme: Option<String>,
+    /// See [`crate::share_burn`], claims are not exported
+    #[serde(default)]
+    pub burn_after_reading: bool,
+    pub files: Vec<BundledFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BundledFile {
+    pub path: String,
+    pub file_size: Option<i64>,
+    /// Served at `/s/<share_id>/<token>`
+    pub token: String,
+    /// Hex digests by algorithm
+    #[serde(default)]
+    pub digests: BTreeMap<String, String>,
+}
+
+/// Shares of an import, by id
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    /// Already on this host
+    pub skipped: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl Validate for ShareBundle {
+    fn validate(&self, v: &mut Validator) {
+        v.check(
+            self.version == BUNDLE_VERSION,
+            "version",
+            &format!("must be {}", BUNDLE_VERSION),
+        );
+    }
+}
+
+/// Every share of the database
+pub async fn export(db: &SqlitePool) -> Result<ShareBundle> {
+    let shares = sqlx::query!(
+        r#"SELECT id AS "id!", alias, created_at, expiration, encrypted, filename_rules, password_hash,
+            managed, notify, notified_at, max_bytes_per_sec, namespace, deleted_at, branding, readme, burn_after_reading
+        FROM share_links ORDER BY created_at, id"#
+    )
+    .fetch_all(db)
+    .await?;
+    let files = sqlx::query!(
+        r#"SELECT share_link_files.share_link_id AS "share_id!", share_link_files.token AS "token!",
+            files.id AS "file_id!", files.path, files.file_size
+        FROM share_link_files JOIN files ON files.id = share_link_files.file_id
+        ORDER BY files.id"#
+    )
+    .fetch_all(db)
+    .await?;
+    let mut digests: BTreeMap<i64, BTreeMap<String, String>> = BTreeMap::new();
+    for digest in sqlx::query!("SELECT file_id, algorithm, digest FROM file_digests")
+        .fetch_all(db)
+        .await?
+    {
+        digests
+            .entry(digest.file_id)
+            .or_default()
+            .insert(digest.algorithm, digest.digest);
+    }
+
+    let mut files_by_share: BTreeMap<String, Vec<BundledFile>> = BTreeMap::new();
+    for file in files {
+        files_by_share
+            .entry(file.share_id)
+            .or_default()
+            .push(BundledFile {
+                path: file.path,
+                file_size: file.file_size,
+                token: file.token,
+                digests: digests.remove(&file.file_id).unwrap_or_default(),
+            });
+    }
+    let shares = shares
+        .into_iter()
+        .map(|share| {
+            Ok(BundledShare {
+                files: files_by_share.remove(&share.id).unwrap_or_default(),
+                filename_rules: serde_json::from_str(&share.filename_rules)
+                    .with_context(|| format!("Invalid filename rules of share {}", share.id))?,
+                id: share.id,
+                alias: share.alias,
+                created_at: share.created_at,
+                expiration: share.expiration,
+                encrypted: share.encrypted,
+                password_hash: share.password_hash,
+                managed: share.managed,
+                notify: share.notify,
+                notified_at: share.notified_at,
+                max_bytes_per_sec: share.max_bytes_per_sec,
+                namespace: share.namespace,
+                deleted_at: share.deleted_at,
+                branding: Branding::from_column(share.branding.as_deref()),
+                readme: share.readme,
+                burn_after_reading: share.burn_after_reading,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(ShareBundle {
+        version: BUNDLE_VERSION,
+        exported_at: chrono::offset::Utc::now().timestamp(),
+        shares,
+    })
+}
+
+/// Create the shares of `bundle` missing from the database, their files must be in `roots`
+pub async fn import(
+    db: &SqlitePool,
+    roots: &ShareRoots,
+    bundle: &ShareBundle,
+) -> Result<ImportReport> {
+    anyhow::ensure!(
+        bundle.version == BUNDLE_VERSION,
+        "Unsupported bundle version {}, expected {}",
+        bundle.version,
+        BUNDLE_VERSION
+    );
+    let mut report = ImportReport::default();
+    for share in &bundle.shares {
+        let exists = sqlx::query_scalar!("SELECT id FROM share_links WHERE id = ?", share.id)
+            .fetch_optional(db)
+            .await?;
+        if exists.is_some() {
+            report.skipped.push(share.id.clone());
+            continue;
+        }
+        match import_share(db, roots, share).await {
+            Ok(()) => report.imported.push(share.id.clone()),
+            Err(e) => report.errors.push(format!("{}: {:#}", share.id, e)),
+        }
+    }
+    Ok(report)
+}
+
+async fn import_share(db: &SqlitePool, roots: &ShareRoots, share: &BundledShare) -> Result<()> {
+    if let Some(alias) = &share.alias {
+        crate::share_alias::check(alias).map_err(|e| anyhow::anyhow!("Alias {} {}", alias, e))?;
+    }
+    let paths = share
+        .files
+        .iter()
+        .map(|file| roots.resolve(Path::new(&file.path)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let filename_rules = serde_json::to_string(&share.filename_rules)?;
+    let branding = share.branding.to_column()?;
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO share_links (id, alias, created_at, expiration, encrypted, filename_rules, password_hash,
+            managed, notify, notified_at, max_bytes_per_sec, namespace, deleted_at, branding, readme, burn_after_reading)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+        share.id,
+        share.alias,
+        share.created_at,
+        share.expiration,
+        share.encrypted,
+        filename_rules,
+        share.password_hash,
+        share.managed,
+        share.notify,
+        share.notified_at,
+        share.max_bytes_per_sec,
+        share.namespace,
+        share.deleted_at,
+        branding,
+        share.readme,
+        share.burn_after_reading
+    )
+    .execute(&mut *tx)
+    .await
+    .context("Alias already taken")?;
+    for (file, path) in share.files.iter().zip(paths) {
+        let path = path.into_string();
+        let file_id = sqlx::query!(
+            "INSERT INTO files (sha256, path, file_size) VALUES ('', ?, ?)",
+            path,
+            file.file_size
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        for (algorithm, digest) in &file.digests {
+            sqlx::query!(
+                "INSERT INTO file_digests (file_id, algorithm, digest) VALUES (?, ?, ?)",
+                file_id,
+                algorithm,
+                digest
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "INSERT INTO share_link_files (share_link_id, file_id, token) VALUES (?, ?, ?)",
+            share.id,
+            file_id,
+            file.token
+        )
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Token of {} already used", file.path))?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn test_db() -> Result<SqlitePool> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        Ok(db)
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = std::fs::canonicalize(dir.path())?.join("a.iso");
+        std::fs::write(&path, "iso")?;
+        let path = path.to_string_lossy().into_owned();
+        let source = test_db().await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, alias, password_hash, filename_rules, deleted_at, branding)
+            VALUES ('s1', -1, 10, 'holidays', '$argon2id$hash', '[{\"rule\":\"strip_prefix\",\"prefix\":\"x\"}]', NULL, '{\"title\":\"Acme\"}'),
+                ('s2', 99, 20, NULL, NULL, '[]', 30, NULL);
+            INSERT INTO files (id, sha256, path, file_size) VALUES (1, '', ?1, 3), (2, '', '/gone/b.iso', 5);
+            INSERT INTO file_digests (file_id, algorithm, digest) VALUES (1, 'sha256', 'abcd');
+            INSERT INTO share_link_files (share_link_id, file_id, token) VALUES ('s1', 1, 'tok1'), ('s2', 2, 'tok2');",
+        )
+        .bind(&path)
+        .execute(&source)
+        .await?;
+
+        let bundle = export(&source).await?;
+        assert_eq!(bundle.shares.len(), 2);
+        assert_eq!(bundle.shares[0].files[0].digests["sha256"], "abcd");
+        assert_eq!(bundle.shares[1].deleted_at, Some(30));
+        // Through JSON, as between two hosts
+        let bundle: ShareBundle = serde_json::from_str(&serde_json::to_string(&bundle)?)?;
+
+        let target = test_db().await?;
+        let roots = ShareRoots::new([dir.path().to_path_buf()]);
+        let report = import(&target, &roots, &bundle).await?;
+        assert_eq!(report.imported, vec!["s1"]);
+        assert_eq!(report.errors.len(), 1);
+        let imported = export(&target).await?;
+        let (share, expected) = (&imported.shares[0], &bundle.shares[0]);
+        assert_eq!(share.alias.as_deref(), Some("holidays"));
+        assert_eq!(share.password_hash, expected.password_hash);
+        assert_eq!(share.filename_rules, expected.filename_rules);
+        assert_eq!(share.branding.title.as_deref(), Some("Acme"));
+        assert_eq!(share.files[0].token, "tok1");
+        assert_eq!(share.files[0].digests, expected.files[0].digests);
+
+        let report = import(&target, &roots, &bundle).await?;
+        assert_eq!(report.skipped, vec!["s1"]);
+        assert!(report.imported.is_empty());
+
+        let future = ShareBundle {
+            version: BUNDLE_VERSION + 1,
+            ..bundle
+        };
+        assert!(import(&target, &roots, &future).await.is_err());
+        Ok(())
+    }
+}
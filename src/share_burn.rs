@@ -0,0 +1,241 @@
+//! Burn-after-reading shares.
+//!
+//! A share created with `burn_after_reading` is served to its first visitor only. The first
+//! request to the share claims it: a single `UPDATE` takes the claim, so of concurrent visitors
+//! exactly one wins, and the winner gets a cookie letting it view the page and download the files.
+//! Every other visitor gets a 410. The session ends once the holder downloaded every file in full,
+//! or `CLAIM_WINDOW` after the claim, then the share is burned: it expires and is not served to
+//! anyone anymore.
+
+use anyhow::Result;
+use axum::extract::{Request, State};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+use crate::error::AppError;
+use crate::share_alias::split_share_path;
+use crate::App;
+
+/// Time the holder of the claim has to download the files
+pub const CLAIM_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Outcome of a request to a burn-after-reading share
+#[derive(Debug, PartialEq, Eq)]
+pub enum Access {
+    /// First request, the share is now claimed by the holder of this token
+    Claimed(String),
+    /// Request of the holder of the claim during its session
+    Granted,
+    /// Claimed by another visitor whose session is still going on
+    Taken,
+    /// The session of the claim is over, the share just expired
+    Burned,
+}
+
+fn cookie_name(share_id: &str) -> String {
+    format!("hardwire_claim_{}", share_id)
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Token of the claim cookie of `share_id`
+fn claim_token(headers: &HeaderMap, share_id: &str) -> Option<String> {
+    let name = cookie_name(share_id);
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, token)| token.to_string())
+}
+
+/// Whether the files of `share_id` were all downloaded in full since `since`
+async fn all_downloaded(db: &SqlitePool, share_id: &str, since: i64) -> Result<bool> {
+    let missing = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "missing!: i64" FROM share_link_files
+        WHERE share_link_id = ?1 AND file_id NOT IN (
+            SELECT file_id FROM download WHERE share_id = ?1 AND status = 'complete' AND started_at >= ?2)"#,
+        share_id,
+        since
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(missing == 0)
+}
+
+/// Claim the share `share_id` for the request carrying `token`, or check its claim
+pub async fn access(
+    db: &SqlitePool,
+    share_id: &str,
+    token: Option<&str>,
+    now: i64,
+) -> Result<Access> {
+    let claim = sqlx::query!(
+        "SELECT claim_hash, claimed_at FROM share_links WHERE id = ?",
+        share_id
+    )
+    .fetch_optional(db)
+    .await?;
+    if let Some((Some(claim_hash), Some(claimed_at))) =
+        claim.map(|claim| (claim.claim_hash, claim.claimed_at))
+    {
+        let over = now >= claimed_at + CLAIM_WINDOW.as_secs() as i64
+            || all_downloaded(db, share_id, claimed_at).await?;
+        if over {
+            sqlx::query!(
+                "UPDATE share_links SET expiration = ? WHERE id = ?",
+                now,
+                share_id
+            )
+            .execute(db)
+            .await?;
+            return Ok(Access::Burned);
+        }
+        let holder = token.is_some_and(|token| hash_token(token) == claim_hash);
+        return Ok(if holder {
+            Access::Granted
+        } else {
+            Access::Taken
+        });
+    }
+
+    let token = nanoid::nanoid!(32);
+    let claim_hash = hash_token(&token);
+    let claimed = sqlx::query!(
+        "UPDATE share_links SET claim_hash = ?, claimed_at = ? WHERE id = ? AND claim_hash IS NULL",
+        claim_hash,
+        now,
+        share_id
+    )
+    .execute(db)
+    .await?
+    .rows_affected()
+        > 0;
+    Ok(if claimed {
+        Access::Claimed(token)
+    } else {
+        Access::Taken
+    })
+}
+
+/// Serve burn-after-reading shares to the holder of their claim only
+pub async fn require_claim(State(app_state): State<App>, request: Request, next: Next) -> Response {
+    let Some((_, share_id, _)) = split_share_path(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    // Missing and expired shares are answered by the handlers
+    match app_state
+        .share_cache
+        .get(share_id, &app_state.db_reader)
+        .await
+    {
+        Ok(Some(share)) if share.burn_after_reading => {}
+        Ok(_) => return next.run(request).await,
+        Err(e) => return AppError::from(e).into_response(),
+    }
+    let share_id = share_id.to_string();
+    let token = claim_token(request.headers(), &share_id);
+    let now = chrono::offset::Utc::now().timestamp();
+    match access(&app_state.db_pool, &share_id, token.as_deref(), now).await {
+        Ok(Access::Granted) => next.run(request).await,
+        Ok(Access::Claimed(token)) => {
+            let mut response = next.run(request).await;
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                cookie_name(&share_id),
+                token,
+                CLAIM_WINDOW.as_secs()
+            );
+            if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                response.headers_mut().append(SET_COOKIE, cookie);
+            }
+            response
+        }
+        Ok(Access::Taken) => {
+            AppError::Gone("This share was already opened by someone else".to_string())
+                .into_response()
+        }
+        Ok(Access::Burned) => {
+            app_state.share_cache.invalidate(&share_id);
+            AppError::Gone("This share is no longer available".to_string()).into_response()
+        }
+        Err(e) => AppError::from(e).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[test]
+    fn test_claim_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(claim_token(&headers, "s1"), None);
+        headers.insert(
+            COOKIE,
+            "theme=dark; hardwire_claim_s2=other; hardwire_claim_s1=abc"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(claim_token(&headers, "s1").as_deref(), Some("abc"));
+    }
+
+    #[tokio::test]
+    async fn test_access() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, burn_after_reading) VALUES ('s1', -1, 0, 1), ('s2', -1, 0, 1);
+            INSERT INTO files (id, path, file_size) VALUES (1, '/data/a.pdf', 10), (2, '/data/b.pdf', 10);
+            INSERT INTO share_link_files (share_link_id, file_id) VALUES ('s1', 1), ('s1', 2), ('s2', 1);",
+        )
+        .execute(&db)
+        .await?;
+
+        let Access::Claimed(token) = access(&db, "s1", None, 100).await? else {
+            panic!("the first visitor claims the share");
+        };
+        assert_eq!(access(&db, "s1", None, 101).await?, Access::Taken);
+        assert_eq!(access(&db, "s1", Some("guess"), 101).await?, Access::Taken);
+        assert_eq!(access(&db, "s1", Some(&token), 102).await?, Access::Granted);
+
+        // Burned once every file was downloaded in full during the session
+        sqlx::query(
+            "INSERT INTO download (file_path, share_id, file_id, status, file_size, started_at)
+            VALUES ('/data/a.pdf', 's1', 1, 'complete', 10, 103), ('/data/b.pdf', 's1', 2, 'partial', 10, 103)",
+        )
+        .execute(&db)
+        .await?;
+        assert_eq!(access(&db, "s1", Some(&token), 104).await?, Access::Granted);
+        sqlx::query("UPDATE download SET status = 'complete'")
+            .execute(&db)
+            .await?;
+        assert_eq!(access(&db, "s1", Some(&token), 105).await?, Access::Burned);
+        let expiration: i64 =
+            sqlx::query_scalar("SELECT expiration FROM share_links WHERE id = 's1'")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(expiration, 105);
+
+        // Or once the window is over, for everyone
+        let Access::Claimed(_) = access(&db, "s2", None, 100).await? else {
+            panic!("the first visitor claims the share");
+        };
+        let later = 100 + CLAIM_WINDOW.as_secs() as i64;
+        assert_eq!(access(&db, "s2", None, later).await?, Access::Burned);
+        Ok(())
+    }
+}
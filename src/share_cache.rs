@@ -0,0 +1,368 @@
+//! Cache of the share metadata rendered by the share page.
+//!
+//! Entries are served until `MAX_AGE`, past `REFRESH_AFTER` they are still served while a
+//! background task reloads them from the database. Expired shares are dropped on access and
+//! handlers editing a share must call [`ShareCache::invalidate`].
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::filename_rules::{self, FilenameRule};
+use crate::share_branding::Branding;
+use crate::share_readme;
+
+#[derive(Debug, Clone)]
+pub struct SharedFile {
+    /// Token the file is served at, see [`crate::file_tokens`]
+    pub link: String,
+    pub short_filename: String,
+    pub has_preview: bool,
+    /// Found missing or altered by the last verification of the shared files
+    pub unavailable: bool,
+    /// Bytes, unknown for files shared before sizes were recorded
+    pub size: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareMetadata {
+    pub files: Vec<SharedFile>,
+    /// Unix timestamp after which the share is not served anymore, -1 when it never expires
+    pub expiration: i64,
+    /// Files are end-to-end encrypted, the server only knows their ciphertext
+    pub encrypted: bool,
+    /// Argon2 hash of the password asked before serving the share
+    pub password_hash: Option<String>,
+    pub branding: Branding,
+    /// Sanitized HTML of the readme, see [`crate::share_readme`]
+    pub readme: Option<String>,
+    /// Served to its first visitor only, see [`crate::share_burn`]
+    pub burn_after_reading: bool,
+}
+
+impl ShareMetadata {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expiration >= 0 && self.expiration <= now
+    }
+}
+
+/// A shared file joined with its share, as selected by `load`
+#[derive(sqlx::FromRow)]
+struct ShareRow {
+    link: String,
+    short_filename: String,
+    expiration: i64,
+    has_preview: bool,
+    encrypted: bool,
+    filename_rules: String,
+    password_hash: Option<String>,
+    branding: Option<String>,
+    readme: Option<String>,
+    burn_after_reading: bool,
+    unavailable: bool,
+    file_size: Option<i64>,
+    path: String,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    share: Arc<ShareMetadata>,
+    loaded_at: Instant,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub refreshes: u64,
+    pub hit_ratio: f64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    refreshes: AtomicU64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShareCache {
+    inner: Arc<Inner>,
+}
+
+impl ShareCache {
+    const REFRESH_AFTER: Duration = Duration::from_secs(60);
+    const MAX_AGE: Duration = Duration::from_secs(600);
+    const MAX_ENTRIES: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Metadata of a share, `None` when it does not exist or has expired
+    pub async fn get(
+        &self,
+        share_id: &str,
+        db: &SqlitePool,
+    ) -> Result<Option<Arc<ShareMetadata>>, sqlx::Error> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let cached = self
+            .inner
+            .entries
+            .read()
+            .unwrap()
+            .get(share_id)
+            .map(|e| (Arc::clone(&e.share), e.loaded_at.elapsed()));
+
+        if let Some((share, age)) = cached {
+            if age < Self::MAX_AGE {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                if share.is_expired(now) {
+                    self.invalidate(share_id);
+                    return Ok(None);
+                }
+                if age >= Self::REFRESH_AFTER {
+                    self.refresh_in_background(share_id, db);
+                }
+                return Ok(Some(share));
+            }
+        }
+
+        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+        self.load(share_id, db).await
+    }
+
+    pub fn invalidate(&self, share_id: &str) {
+        self.inner.entries.write().unwrap().remove(share_id);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.inner.hits.load(Ordering::Relaxed);
+        let misses = self.inner.misses.load(Ordering::Relaxed);
+        CacheStats {
+            entries: self.inner.entries.read().unwrap().len(),
+            hits,
+            misses,
+            refreshes: self.inner.refreshes.load(Ordering::Relaxed),
+            hit_ratio: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+        }
+    }
+
+    fn refresh_in_background(&self, share_id: &str, db: &SqlitePool) {
+        // Reset the age first so concurrent requests don't spawn the same refresh
+        if let Some(entry) = self.inner.entries.write().unwrap().get_mut(share_id) {
+            entry.loaded_at = Instant::now();
+        }
+        self.inner.refreshes.fetch_add(1, Ordering::Relaxed);
+
+        let cache = self.clone();
+        let share_id = share_id.to_string();
+        let db = db.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cache.load(&share_id, &db).await {
+                tracing::error!("Failed to refresh share {} metadata: {}", share_id, e);
+            }
+        });
+    }
+
+    /// Rendered `README.md` among the files, the one closest to the root when there are several
+    async fn shared_readme(rows: &[ShareRow], encrypted: bool) -> Option<String> {
+        // Only the browser can decrypt the files of an encrypted share
+        if encrypted {
+            return None;
+        }
+        let path = rows
+            .iter()
+            .filter(|row| !row.unavailable && share_readme::is_readme(&row.path))
+            .map(|row| &row.path)
+            .min_by_key(|path| (path.matches('/').count(), path.len()))?;
+        Some(share_readme::render(&share_readme::read_file(path).await?))
+    }
+
+    async fn load(
+        &self,
+        share_id: &str,
+        db: &SqlitePool,
+    ) -> Result<Option<Arc<ShareMetadata>>, sqlx::Error> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let rows: Vec<ShareRow> = sqlx::query_as(
+            r#"SELECT share_link_files.token AS link, substr(files.path, instr(files.path, '/') + 1) AS short_filename, share_links.expiration,
+        files.preview_path IS NOT NULL AS has_preview, share_links.encrypted,
+        share_links.filename_rules, share_links.password_hash, share_links.branding,
+        share_links.readme, share_links.burn_after_reading, COALESCE(files.health, 'ok') != 'ok' AS unavailable, files.file_size, files.path
+        FROM share_links JOIN share_link_files ON share_links.id=share_link_files.share_link_id
+        JOIN files ON share_link_files.file_id=files.id
+        WHERE share_links.id = ? AND share_links.deleted_at IS NULL"#,
+        )
+        .bind(share_id)
+        .fetch_all(db)
+        .await?;
+
+        let share = match rows.first() {
+            Some(first) => {
+                let rules: Vec<FilenameRule> =
+                    serde_json::from_str(&first.filename_rules).unwrap_or_default();
+                let today = chrono::offset::Utc::now().date_naive();
+                ShareMetadata {
+                    expiration: first.expiration,
+                    encrypted: first.encrypted,
+                    password_hash: first.password_hash.clone(),
+                    branding: Branding::from_column(first.branding.as_deref()),
+                    files: rows
+                        .iter()
+                        .map(|row| SharedFile {
+                            link: row.link.clone(),
+                            short_filename: if rules.is_empty() {
+                                row.short_filename.clone()
+                            } else {
+                                let name =
+                                    row.short_filename.rsplit('/').next().unwrap_or_default();
+                                filename_rules::rewrite(name, &rules, today)
+                            },
+                            has_preview: row.has_preview,
+                            unavailable: row.unavailable,
+                            size: row.file_size,
+                        })
+                        .collect(),
+                    burn_after_reading: first.burn_after_reading,
+                    readme: match &first.readme {
+                        Some(markdown) => Some(share_readme::render(markdown)),
+                        None => Self::shared_readme(&rows, first.encrypted).await,
+                    },
+                }
+            }
+            None => {
+                self.invalidate(share_id);
+                return Ok(None);
+            }
+        };
+        if share.is_expired(now) {
+            self.invalidate(share_id);
+            return Ok(None);
+        }
+
+        let share = Arc::new(share);
+        let mut entries = self.inner.entries.write().unwrap();
+        if entries.len() >= Self::MAX_ENTRIES && !entries.contains_key(share_id) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, e)| e.loaded_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(
+            share_id.to_string(),
+            CacheEntry {
+                share: Arc::clone(&share),
+                loaded_at: Instant::now(),
+            },
+        );
+        Ok(Some(share))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    async fn test_db() -> anyhow::Result<SqlitePool> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        Ok(db)
+    }
+
+    async fn insert_share(db: &SqlitePool, share_id: &str, expiration: i64) -> anyhow::Result<()> {
+        let file_id = sqlx::query(
+            "INSERT INTO files (sha256, path, file_size) VALUES ('', 'dir/test1.txt', 14)",
+        )
+        .execute(db)
+        .await?
+        .last_insert_rowid();
+        sqlx::query("INSERT INTO share_links (id, expiration, created_at) VALUES (?, ?, 0)")
+            .bind(share_id)
+            .bind(expiration)
+            .execute(db)
+            .await?;
+        sqlx::query("INSERT INTO share_link_files (share_link_id, file_id) VALUES (?, ?)")
+            .bind(share_id)
+            .bind(file_id)
+            .execute(db)
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hits_and_invalidation() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        insert_share(&db, "share1", -1).await?;
+        let cache = ShareCache::new();
+
+        let share = cache.get("share1", &db).await?.unwrap();
+        assert_eq!(share.files[0].short_filename, "test1.txt");
+        cache.get("share1", &db).await?.unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        cache.invalidate("share1");
+        cache.get("share1", &db).await?.unwrap();
+        assert_eq!(cache.stats().misses, 2);
+
+        assert!(cache.get("missing", &db).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_shares_are_not_served() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        insert_share(&db, "expired", 1).await?;
+        let cache = ShareCache::new();
+
+        assert!(cache.get("expired", &db).await?.is_none());
+        assert_eq!(cache.stats().entries, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_readme() -> anyhow::Result<()> {
+        let db = test_db().await?;
+        insert_share(&db, "share1", -1).await?;
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("README.md");
+        std::fs::write(&path, "# Delivery")?;
+        let file_id = sqlx::query("INSERT INTO files (sha256, path) VALUES ('', ?)")
+            .bind(path.to_string_lossy())
+            .execute(&db)
+            .await?
+            .last_insert_rowid();
+        sqlx::query("INSERT INTO share_link_files (share_link_id, file_id) VALUES ('share1', ?)")
+            .bind(file_id)
+            .execute(&db)
+            .await?;
+        let cache = ShareCache::new();
+
+        let share = cache.get("share1", &db).await?.unwrap();
+        assert_eq!(share.readme.as_deref(), Some("<h1>Delivery</h1>\n"));
+
+        share_readme::save(&db, "share1", Some("*Final* cut")).await?;
+        cache.invalidate("share1");
+        let share = cache.get("share1", &db).await?.unwrap();
+        assert_eq!(share.readme.as_deref(), Some("<p><em>Final</em> cut</p>\n"));
+        Ok(())
+    }
+}
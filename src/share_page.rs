@@ -0,0 +1,377 @@
+//! What the share page shows next to each file: its size, an icon for its type and, for images,
+//! videos, audio and PDF documents, a preview served inline by
+//! `GET /s/{share_id}/{file_id}/inline`. The QR code of `GET /s/{share_id}/qr.png` opens the page
+//! from a phone.
+//!
+//! Large shares are listed by pages of `per_page` files, sorted by `sort` (`name`, `size` or
+//! `type`) in `order` (`asc` or `desc`), all given in the query string so the page works without
+//! JavaScript. Scripts get the same listing as JSON from `GET /s/{share_id}.json`, or with
+//! `Accept: application/json`.
+//!
+//! Shares of mostly photos and videos are shown as a grid of thumbnails instead, see [`Layout`].
+//!
+//! Only types a browser displays without running anything from the file are served inline: SVG
+//! images, HTML and text are offered as downloads only, so a shared file can't script the server
+//! origin.
+
+use anyhow::Result;
+use qrcode::{Color, QrCode};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::share_cache::SharedFile;
+
+/// Pixels per module of the QR code
+const QR_SCALE: usize = 6;
+/// Light modules around the code, 4 are required for readers to find it
+const QR_QUIET_ZONE: usize = 4;
+
+/// Files of a page when the query gives none
+pub const STD_PER_PAGE: usize = 50;
+pub const MAX_PER_PAGE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Image,
+    Video,
+    Audio,
+    Pdf,
+    Archive,
+    Text,
+    Other,
+}
+
+impl FileKind {
+    /// Kind of a file from the extension of its `name`
+    pub fn from_name(name: &str) -> Self {
+        let mime = mime_guess::from_path(name).first_or_octet_stream();
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("image", "svg") => FileKind::Text,
+            ("image", _) => FileKind::Image,
+            ("video", _) => FileKind::Video,
+            ("audio", _) => FileKind::Audio,
+            ("application", "pdf") => FileKind::Pdf,
+            (
+                "application",
+                "zip" | "gzip" | "x-tar" | "x-compressed" | "x-7z-compressed" | "x-bzip2"
+                | "x-rar-compressed",
+            ) => FileKind::Archive,
+            ("text", _) | ("application", "json" | "xml") => FileKind::Text,
+            _ => FileKind::Other,
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            FileKind::Image => "🖼️",
+            FileKind::Video => "🎞️",
+            FileKind::Audio => "🎵",
+            FileKind::Pdf => "📕",
+            FileKind::Archive => "📦",
+            FileKind::Text => "📝",
+            FileKind::Other => "📄",
+        }
+    }
+
+    /// Element of the share page previewing the file, empty when it is not served inline
+    pub fn preview(self) -> &'static str {
+        match self {
+            FileKind::Image => "image",
+            FileKind::Video => "video",
+            FileKind::Audio => "audio",
+            FileKind::Pdf => "pdf",
+            FileKind::Archive | FileKind::Text | FileKind::Other => "",
+        }
+    }
+}
+
+/// Content type of a file served from `path`. Audio and video get the types browsers check before
+/// playing them, where the registry of extensions has non-standard ones.
+pub fn content_type(path: &str) -> mime_guess::Mime {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    let standard = match extension.as_str() {
+        "m4a" | "m4b" => "audio/mp4",
+        "m4v" => "video/mp4",
+        "mka" => "audio/x-matroska",
+        "ts" => "video/mp2t",
+        _ => return mime_guess::from_path(path).first_or_octet_stream(),
+    };
+    standard.parse().unwrap()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    /// By [`FileKind`], then by name
+    Type,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How the share page shows the files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    /// One file per line, with their previews
+    List,
+    /// A grid of thumbnails opened in a lightbox, with checkboxes to download a selection of the
+    /// files as a zip, see [`crate::share_zip`]
+    Gallery,
+}
+
+impl Layout {
+    /// The gallery when more than half of the files are images or videos
+    pub fn of(files: &[SharedFile]) -> Self {
+        let media = files
+            .iter()
+            .filter(|file| is_media(&file.short_filename))
+            .count();
+        if media * 2 > files.len() {
+            Layout::Gallery
+        } else {
+            Layout::List
+        }
+    }
+}
+
+/// Shown by the gallery
+pub fn is_media(name: &str) -> bool {
+    matches!(FileKind::from_name(name), FileKind::Image | FileKind::Video)
+}
+
+/// Query string of the share page
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListQuery {
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// From 1
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    /// [`Layout::of`] the files when unset
+    pub layout: Option<Layout>,
+}
+
+impl ListQuery {
+    pub fn per_page(&self) -> usize {
+        self.per_page.unwrap_or(STD_PER_PAGE).clamp(1, MAX_PER_PAGE)
+    }
+
+    /// Query string of the same listing at `page`, sorted by `sort` in `order`
+    pub fn href(&self, sort: SortKey, order: SortOrder, page: usize) -> String {
+        let sort = match sort {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Type => "type",
+        };
+        let order = match order {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        };
+        let layout = match self.layout {
+            None => "",
+            Some(Layout::List) => "&layout=list",
+            Some(Layout::Gallery) => "&layout=gallery",
+        };
+        format!(
+            "?sort={}&order={}&page={}&per_page={}{}",
+            sort,
+            order,
+            page,
+            self.per_page(),
+            layout
+        )
+    }
+}
+
+/// A page of the files of a share
+#[derive(Debug)]
+pub struct FilePage<'a> {
+    pub files: Vec<&'a SharedFile>,
+    /// From 1, the last page when the query asked for one past it
+    pub page: usize,
+    pub total_pages: usize,
+    pub total_files: usize,
+}
+
+/// The files of `query.page`, sorted as asked. Files of unknown size sort as the smallest.
+pub fn page_files<'a>(files: &'a [SharedFile], query: &ListQuery) -> FilePage<'a> {
+    let mut sorted: Vec<&SharedFile> = files.iter().collect();
+    let name = |file: &&SharedFile| file.short_filename.to_lowercase();
+    match query.sort {
+        SortKey::Name => sorted.sort_by_key(name),
+        SortKey::Size => sorted.sort_by_key(|file| (file.size, name(file))),
+        SortKey::Type => {
+            sorted.sort_by_key(|file| (FileKind::from_name(&file.short_filename), name(file)))
+        }
+    }
+    if query.order == SortOrder::Desc {
+        sorted.reverse();
+    }
+    let per_page = query.per_page();
+    let total_pages = files.len().div_ceil(per_page).max(1);
+    let page = query.page.unwrap_or(1).clamp(1, total_pages);
+    FilePage {
+        files: sorted
+            .into_iter()
+            .skip((page - 1) * per_page)
+            .take(per_page)
+            .collect(),
+        page,
+        total_pages,
+        total_files: files.len(),
+    }
+}
+
+/// PNG of the QR code of `text`, black on white
+pub fn qr_png(text: &str) -> Result<Vec<u8>> {
+    let code = QrCode::new(text)?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let side = (modules + 2 * QR_QUIET_ZONE) * QR_SCALE;
+    let mut pixels = vec![u8::MAX; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let (x, y) = (i % modules + QR_QUIET_ZONE, i / modules + QR_QUIET_ZONE);
+        for row in y * QR_SCALE..(y + 1) * QR_SCALE {
+            pixels[row * side + x * QR_SCALE..row * side + (x + 1) * QR_SCALE].fill(0);
+        }
+    }
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_kind() {
+        assert_eq!(FileKind::from_name("holidays/beach.JPG"), FileKind::Image);
+        assert_eq!(FileKind::from_name("film.mkv"), FileKind::Video);
+        assert_eq!(FileKind::from_name("song.flac"), FileKind::Audio);
+        assert_eq!(FileKind::from_name("report.pdf"), FileKind::Pdf);
+        assert_eq!(FileKind::from_name("backup.zip"), FileKind::Archive);
+        assert_eq!(FileKind::from_name("logo.svg").preview(), "");
+        assert_eq!(FileKind::from_name("index.html").preview(), "");
+        assert_eq!(FileKind::from_name("README"), FileKind::Other);
+    }
+
+    #[test]
+    fn test_content_type() {
+        assert_eq!(content_type("/films/Holidays.MP4"), "video/mp4");
+        assert_eq!(content_type("clip.m4v"), "video/mp4");
+        assert_eq!(content_type("song.m4a"), "audio/mp4");
+        assert_eq!(content_type("song.mp3"), "audio/mpeg");
+        assert_eq!(content_type("film.webm"), "video/webm");
+        assert_eq!(content_type("README"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_page_files() {
+        let file = |name: &str, size| SharedFile {
+            link: name.to_string(),
+            short_filename: name.to_string(),
+            has_preview: false,
+            unavailable: false,
+            size,
+        };
+        let files = vec![
+            file("b.mkv", Some(30)),
+            file("A.txt", Some(10)),
+            file("c.jpg", None),
+            file("d.txt", Some(20)),
+            file("e.zip", Some(40)),
+        ];
+        let names = |page: FilePage| -> Vec<String> {
+            page.files
+                .iter()
+                .map(|file| file.short_filename.clone())
+                .collect()
+        };
+        let query = |sort, order, page| ListQuery {
+            sort,
+            order,
+            page,
+            per_page: Some(2),
+            layout: None,
+        };
+
+        let page = page_files(&files, &query(SortKey::Name, SortOrder::Asc, None));
+        assert_eq!((page.page, page.total_pages, page.total_files), (1, 3, 5));
+        assert_eq!(names(page), vec!["A.txt", "b.mkv"]);
+        let page = page_files(&files, &query(SortKey::Size, SortOrder::Desc, Some(3)));
+        assert_eq!(names(page), vec!["c.jpg"]);
+        let page = page_files(&files, &query(SortKey::Type, SortOrder::Asc, Some(1)));
+        assert_eq!(names(page), vec!["c.jpg", "b.mkv"]);
+        // Past the last page
+        let page = page_files(&files, &query(SortKey::Name, SortOrder::Asc, Some(9)));
+        assert_eq!((page.page, names(page)), (3, vec!["e.zip".to_string()]));
+    }
+
+    #[test]
+    fn test_layout() {
+        let files = |names: &[&str]| -> Vec<SharedFile> {
+            names
+                .iter()
+                .map(|name| SharedFile {
+                    link: name.to_string(),
+                    short_filename: name.to_string(),
+                    has_preview: false,
+                    unavailable: false,
+                    size: None,
+                })
+                .collect()
+        };
+        let layout = |names: &[&str]| Layout::of(&files(names));
+        assert_eq!(layout(&["a.jpg", "b.mp4", "notes.txt"]), Layout::Gallery);
+        assert_eq!(layout(&["a.jpg", "notes.txt"]), Layout::List);
+        assert_eq!(layout(&["logo.svg", "a.png", "b.zip"]), Layout::List);
+        let query = ListQuery {
+            layout: Some(Layout::List),
+            ..Default::default()
+        };
+        assert_eq!(
+            query.href(SortKey::Name, SortOrder::Asc, 1),
+            "?sort=name&order=asc&page=1&per_page=50&layout=list"
+        );
+    }
+
+    #[test]
+    fn test_qr_png() -> Result<()> {
+        let png = qr_png("https://files.example.com/s/V1StGXR8_Z")?;
+        let mut reader = png::Decoder::new(png.as_slice()).read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels)?;
+        assert_eq!(info.width, info.height);
+        assert_eq!(info.width as usize % QR_SCALE, 0);
+        let side = info.width as usize;
+        // White quiet zone, then the black corner of the top left finder pattern
+        assert_eq!(pixels[0], u8::MAX);
+        let corner = QR_QUIET_ZONE * QR_SCALE;
+        assert_eq!(pixels[corner * side + corner], 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Password protection of shares.
+//!
+//! Passwords are stored as Argon2 PHC strings. The share pages and downloads of a protected share
+//! ask for the password with HTTP Basic authentication, any user name is accepted, so browsers
+//! prompt for it without JavaScript and `curl -u :password` works. The share metadata of the public
+//! API is protected the same way.
+
+use anyhow::Result;
+use argon2::password_hash::phc::PasswordHash;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier};
+use argon2::Argon2;
+use axum::extract::{Request, State};
+use axum::http::header::{AUTHORIZATION, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+
+use crate::error::AppError;
+use crate::progress::{AuthAttempt, Event};
+use crate::proxy::client_ip;
+use crate::share_alias::split_share_path;
+use crate::App;
+
+const CHALLENGE: HeaderValue =
+    HeaderValue::from_static("Basic realm=\"hardwire\", charset=\"UTF-8\"");
+
+pub fn hash_password(password: &str) -> Result<String> {
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?
+        .to_string())
+}
+
+/// Whether `password` matches `hash`, `false` when the hash is malformed
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Password of the Basic `Authorization` header, the user name is ignored
+fn basic_password(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// Ask for the password of protected shares before serving their pages and files
+pub async fn require_share_password(
+    State(app_state): State<App>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((_, share_id, _)) = split_share_path(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    // Missing and expired shares are answered by the handlers
+    let share = match app_state
+        .share_cache
+        .get(share_id, &app_state.db_reader)
+        .await
+    {
+        Ok(Some(share)) => share,
+        Ok(None) => return next.run(request).await,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+    let Some(hash) = share.password_hash.clone() else {
+        return next.run(request).await;
+    };
+
+    let Some(password) = basic_password(request.headers()) else {
+        return challenge("Password required");
+    };
+    let valid = tokio::task::spawn_blocking(move || verify_password(&password, &hash))
+        .await
+        .unwrap_or(false);
+    // Nobody listening is not an error for the request itself
+    let _ = app_state
+        .progress_channel_sender
+        .send(Event::Auth(AuthAttempt {
+            realm: "share",
+            success: valid,
+            client_ip: client_ip(request.extensions()),
+            path: request.uri().path().to_string(),
+            reason: (!valid).then(|| "Invalid share password".to_string()),
+        }));
+    if !valid {
+        return challenge("Invalid password");
+    }
+    next.run(request).await
+}
+
+fn challenge(message: &str) -> Response {
+    let mut response = AppError::Unauthorized(message.to_string()).into_response();
+    response.headers_mut().insert(WWW_AUTHENTICATE, CHALLENGE);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("s3cret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("s3cret", &hash));
+        assert!(!verify_password("s3creT", &hash));
+        assert!(!verify_password("s3cret", "not a hash"));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(basic_password(&headers), None);
+        // curl -u :s3cret
+        headers.insert(AUTHORIZATION, "Basic OnMzY3JldA==".parse().unwrap());
+        assert_eq!(basic_password(&headers).as_deref(), Some("s3cret"));
+    }
+}
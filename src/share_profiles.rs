@@ -0,0 +1,182 @@
+//! Named share profiles defined in `profiles.toml`.
+//!
+//! A profile gathers the settings of a kind of share, e.g. deliveries to clients expiring after a
+//! week behind a password, so they needn't be repeated by every `hardwire publish --profile` or
+//! `create_shared_link` call. The file in the data directory is read whenever a profile is used,
+//! editing it needs no restart. Settings given along with the files take precedence.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::share_password;
+use crate::validation::{Validate, Validator, MAX_EXPIRY_SECS};
+use crate::ShareOptions;
+
+pub const PROFILES_FILE_NAME: &str = "profiles.toml";
+const MAX_EXPIRY_DAYS: i64 = MAX_EXPIRY_SECS / 86400;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    #[serde(default, rename = "profile")]
+    profiles: HashMap<String, ShareProfile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareProfile {
+    /// Days the shares are served, they never expire when unset
+    pub expires_in_days: Option<i64>,
+    /// Shares are refused without a password
+    #[serde(default)]
+    pub password_required: bool,
+    /// Email once every file was downloaded, see [`crate::notifications`]
+    #[serde(default)]
+    pub notify: bool,
+    /// Download rate of each file in Mbit/s, unlimited when unset
+    pub max_mbps: Option<f64>,
+}
+
+/// No profile of this name in `profiles.toml`
+#[derive(Debug)]
+pub struct UnknownProfile(pub String);
+
+impl fmt::Display for UnknownProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No share profile {} in {}", self.0, PROFILES_FILE_NAME)
+    }
+}
+
+impl std::error::Error for UnknownProfile {}
+
+/// The profile requires a password and none was given
+#[derive(Debug)]
+pub struct PasswordRequired;
+
+impl fmt::Display for PasswordRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The share profile requires a password")
+    }
+}
+
+impl std::error::Error for PasswordRequired {}
+
+impl Validate for ProfilesFile {
+    fn validate(&self, v: &mut Validator) {
+        for (name, profile) in &self.profiles {
+            let field = format!("profile.{}", name);
+            if let Some(days) = profile.expires_in_days {
+                v.check(
+                    (1..=MAX_EXPIRY_DAYS).contains(&days),
+                    &format!("{}.expires_in_days", field),
+                    &format!("must be between 1 and {}", MAX_EXPIRY_DAYS),
+                );
+            }
+            if let Some(mbps) = profile.max_mbps {
+                v.check(
+                    mbps.is_finite() && mbps > 0.0,
+                    &format!("{}.max_mbps", field),
+                    "must be a positive number",
+                );
+            }
+        }
+    }
+}
+
+/// The profile `name` of `data_dir/profiles.toml`
+pub fn load(data_dir: &Path, name: &str) -> Result<ShareProfile> {
+    let file = data_dir.join(PROFILES_FILE_NAME);
+    let content = match std::fs::read_to_string(&file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(UnknownProfile(name.to_string()).into())
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", file.display())),
+    };
+    let mut profiles: ProfilesFile =
+        toml::from_str(&content).with_context(|| format!("Failed to parse {}", file.display()))?;
+    let mut v = Validator::default();
+    profiles.validate(&mut v);
+    v.finish()
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {}", file.display(), e))?;
+    profiles
+        .profiles
+        .remove(name)
+        .ok_or_else(|| UnknownProfile(name.to_string()).into())
+}
+
+impl ShareProfile {
+    /// Options of a share created now with this profile, protected by `password` when given
+    pub fn share_options(&self, password: Option<&str>) -> Result<ShareOptions> {
+        if self.password_required && password.is_none() {
+            return Err(PasswordRequired.into());
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        Ok(ShareOptions {
+            expires_at: self.expires_in_days.map(|days| now + days * 86400),
+            password_hash: password.map(share_password::hash_password).transpose()?,
+            notify: self.notify,
+            max_bytes_per_sec: self.max_mbps.map(|mbps| (mbps * 125_000.0) as i64),
+            ..ShareOptions::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(load(dir.path(), "public-media")
+            .unwrap_err()
+            .is::<UnknownProfile>());
+
+        std::fs::write(
+            dir.path().join(PROFILES_FILE_NAME),
+            r#"
+            [profile.public-media]
+            max_mbps = 20
+
+            [profile.client-delivery]
+            expires_in_days = 7
+            password_required = true
+            notify = true
+            "#,
+        )?;
+        let profile = load(dir.path(), "public-media")?;
+        assert_eq!(profile.max_mbps, Some(20.0));
+        let options = profile.share_options(None)?;
+        assert_eq!(options.expires_at, None);
+        assert_eq!(options.max_bytes_per_sec, Some(2_500_000));
+        assert!(options.password_hash.is_none() && !options.notify);
+
+        let profile = load(dir.path(), "client-delivery")?;
+        assert!(profile
+            .share_options(None)
+            .unwrap_err()
+            .is::<PasswordRequired>());
+        let options = profile.share_options(Some("s3cret"))?;
+        let week = chrono::offset::Utc::now().timestamp() + 7 * 86400;
+        assert!((week - 5..=week).contains(&options.expires_at.unwrap()));
+        assert!(share_password::verify_password(
+            "s3cret",
+            options.password_hash.as_deref().unwrap()
+        ));
+        assert!(options.notify);
+        assert!(load(dir.path(), "archive")
+            .unwrap_err()
+            .is::<UnknownProfile>());
+
+        std::fs::write(
+            dir.path().join(PROFILES_FILE_NAME),
+            "[profile.slow]\nmax_mbps = 0\n",
+        )?;
+        assert!(load(dir.path(), "slow").is_err());
+        Ok(())
+    }
+}
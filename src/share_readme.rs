@@ -0,0 +1,102 @@
+//! Markdown readme of the share page.
+//!
+//! A share can carry a Markdown text explaining what its files are, set by the admin API, or else
+//! shows the `README.md` among its files. The Markdown is rendered to HTML then sanitized with
+//! ammonia, which only keeps formatting tags and safe links, so neither the text nor a shared
+//! file can run scripts in the page of the share.
+
+use anyhow::Result;
+use pulldown_cmark::{html, Options, Parser};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::Path;
+use utoipa::ToSchema;
+
+use crate::validation::{Validate, Validator};
+
+/// Bytes of Markdown rendered, larger `README.md` files are not shown
+pub const MAX_README_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Readme {
+    /// `null` or empty to show the shared `README.md` again, if any
+    pub markdown: Option<String>,
+}
+
+impl Validate for Readme {
+    fn validate(&self, v: &mut Validator) {
+        if let Some(markdown) = &self.markdown {
+            v.check(
+                markdown.len() <= MAX_README_LEN,
+                "markdown",
+                &format!("must be at most {} bytes", MAX_README_LEN),
+            );
+        }
+    }
+}
+
+/// Sanitized HTML of `markdown`
+pub fn render(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    ammonia::clean(&unsafe_html)
+}
+
+/// Whether the shared file at `path` is the readme of its share
+pub fn is_readme(path: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.eq_ignore_ascii_case("README.md"))
+}
+
+/// Markdown of a shared `README.md`, `None` when it is unreadable, too large or not UTF-8
+pub async fn read_file(path: &str) -> Option<String> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if metadata.len() > MAX_README_LEN as u64 {
+        return None;
+    }
+    String::from_utf8(tokio::fs::read(path).await.ok()?).ok()
+}
+
+/// Replace the readme of a share, `false` when there is no such share
+pub async fn save(db: &SqlitePool, share_id: &str, markdown: Option<&str>) -> Result<bool> {
+    let markdown = markdown.filter(|markdown| !markdown.trim().is_empty());
+    let result = sqlx::query!(
+        "UPDATE share_links SET readme = ? WHERE id = ?",
+        markdown,
+        share_id
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("# Delivery\n\nCheck with `sha256sum -c SHA256SUMS`"),
+            "<h1>Delivery</h1>\n<p>Check with <code>sha256sum -c SHA256SUMS</code></p>\n"
+        );
+        assert_eq!(render("<script>alert(1)</script>"), "");
+        assert_eq!(
+            render("[site](javascript:alert(1)) <img src=x onerror=alert(1)>"),
+            "<p><a rel=\"noopener noreferrer\">site</a> <img src=\"x\"></p>\n"
+        );
+        assert!(render("[docs](https://example.com)").contains(r#"rel="noopener noreferrer""#));
+    }
+
+    #[test]
+    fn test_is_readme() {
+        assert!(is_readme("/data/delivery/README.md"));
+        assert!(is_readme("/data/readme.MD"));
+        assert!(!is_readme("/data/README.md.bak"));
+        assert!(!is_readme("/data/notes.md"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Directories files may be shared from.
+//!
+//! `HARDWIRE_SHARE_ROOTS` lists them, separated by `:` like `PATH`, `HARDWIRE_BASE_PATH` being the
+//! only one by default. A file is shared by its canonical path, symbolic links resolved, which must
+//! be under one of the roots: neither `..` nor a link pointing out of a root can expose other files
+//! the server is able to read. [`crate::publish_files`] only takes the [`ShareableFile`]s checked
+//! here, along with the files encrypted by the CLI, their `encrypted` directory being a root too.
+
+use serde::Serialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{AppError, FieldError};
+
+/// A regular file under one of the share roots, by its canonical path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShareableFile(PathBuf);
+
+impl ShareableFile {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+/// Why a file can't be shared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotShareable {
+    NotFound(PathBuf),
+    NotAFile(PathBuf),
+    OutsideRoots(PathBuf),
+}
+
+impl NotShareable {
+    fn reason(&self) -> &'static str {
+        match self {
+            NotShareable::NotFound(_) => "does not exist",
+            NotShareable::NotAFile(_) => "is not a regular file",
+            NotShareable::OutsideRoots(_) => "is outside the share roots",
+        }
+    }
+}
+
+impl fmt::Display for NotShareable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (NotShareable::NotFound(path)
+        | NotShareable::NotAFile(path)
+        | NotShareable::OutsideRoots(path)) = self;
+        write!(f, "{} {}", path.display(), self.reason())
+    }
+}
+
+impl std::error::Error for NotShareable {}
+
+#[derive(Debug, Clone)]
+pub struct ShareRoots(Vec<PathBuf>);
+
+impl ShareRoots {
+    pub fn new(roots: impl IntoIterator<Item = PathBuf>) -> Self {
+        ShareRoots(roots.into_iter().collect())
+    }
+
+    /// Canonical paths of the roots, the missing ones left out. Resolved on each check so roots
+    /// created or moved after startup are followed.
+    fn canonical_roots(&self) -> Vec<PathBuf> {
+        self.0
+            .iter()
+            .filter_map(|root| std::fs::canonicalize(root).ok())
+            .collect()
+    }
+
+    pub fn resolve(&self, path: &Path) -> Result<ShareableFile, NotShareable> {
+        let canonical =
+            std::fs::canonicalize(path).map_err(|_| NotShareable::NotFound(path.to_path_buf()))?;
+        if !self
+            .canonical_roots()
+            .iter()
+            .any(|root| canonical.starts_with(root))
+        {
+            return Err(NotShareable::OutsideRoots(path.to_path_buf()));
+        }
+        if !canonical.is_file() {
+            return Err(NotShareable::NotAFile(path.to_path_buf()));
+        }
+        Ok(ShareableFile(canonical))
+    }
+
+    /// Check the `paths` of a payload, the errors reported on `field[i]`
+    pub fn resolve_all(
+        &self,
+        field: &str,
+        paths: &[String],
+    ) -> Result<Vec<ShareableFile>, AppError> {
+        let mut files = vec![];
+        let mut errors = vec![];
+        for (i, path) in paths.iter().enumerate() {
+            match self.resolve(Path::new(path)) {
+                Ok(file) => files.push(file),
+                Err(e) => errors.push(FieldError {
+                    field: format!("{}[{}]", field, i),
+                    message: e.reason().to_string(),
+                }),
+            }
+        }
+        if errors.is_empty() {
+            Ok(files)
+        } else {
+            Err(AppError::Validation(errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("shared");
+        std::fs::create_dir_all(root.join("movies"))?;
+        std::fs::write(root.join("movies/a.mkv"), "a")?;
+        std::fs::write(dir.path().join("secret"), "s")?;
+        std::os::unix::fs::symlink(dir.path().join("secret"), root.join("link"))?;
+        let roots = ShareRoots::new([root.clone(), dir.path().join("missing")]);
+
+        let file = roots.resolve(&root.join("movies/../movies/a.mkv"))?;
+        assert_eq!(
+            file.path(),
+            std::fs::canonicalize(root.join("movies/a.mkv"))?
+        );
+        assert_eq!(
+            roots.resolve(&root.join("../secret")),
+            Err(NotShareable::OutsideRoots(root.join("../secret")))
+        );
+        // The link is under the root, not the file it points to
+        assert_eq!(
+            roots.resolve(&root.join("link")),
+            Err(NotShareable::OutsideRoots(root.join("link")))
+        );
+        assert_eq!(
+            roots.resolve(&root.join("movies")),
+            Err(NotShareable::NotAFile(root.join("movies")))
+        );
+        assert_eq!(
+            roots.resolve(Path::new("/etc/shadow-missing")),
+            Err(NotShareable::NotFound("/etc/shadow-missing".into()))
+        );
+
+        let paths = [
+            root.join("movies/a.mkv").to_string_lossy().into_owned(),
+            "/etc/passwd".to_string(),
+        ];
+        match roots.resolve_all("files", &paths) {
+            Err(AppError::Validation(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].field, "files[1]");
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        Ok(())
+    }
+}
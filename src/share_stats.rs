@@ -0,0 +1,252 @@
+//! Download statistics of a share, from the `download` rows recorded with its id.
+
+use anyhow::Result;
+use chrono::{Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    /// Days of the daily series, today included
+    pub days: Option<u32>,
+}
+
+impl StatsQuery {
+    pub const DEFAULT_DAYS: u32 = 30;
+    pub const MAX_DAYS: u32 = 366;
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FileStats {
+    pub file_id: i64,
+    pub path: String,
+    pub downloads: i64,
+    /// Downloads which got every byte of the file
+    pub completed: i64,
+    pub unique_ips: i64,
+    pub bytes_sent: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DailyStats {
+    /// UTC day, `YYYY-MM-DD`
+    pub date: String,
+    pub downloads: i64,
+    pub completed: i64,
+    pub unique_ips: i64,
+    pub bytes_sent: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ShareStats {
+    pub share_id: String,
+    pub downloads: i64,
+    pub completed: i64,
+    /// Share of the downloads completed, none before the first download
+    pub completion_rate: Option<f64>,
+    /// Distinct client addresses over all the files
+    pub unique_ips: i64,
+    pub bytes_sent: i64,
+    /// Every file of the share, downloaded or not
+    pub files: Vec<FileStats>,
+    /// One entry per day, days without downloads included
+    pub daily: Vec<DailyStats>,
+}
+
+/// Statistics of the share `share_id`, or of its alias, none when there is no such share
+pub async fn share_stats(
+    db: &SqlitePool,
+    share_id: &str,
+    query: &StatsQuery,
+) -> Result<Option<ShareStats>> {
+    let Some(share_id) = sqlx::query_scalar!(
+        r#"SELECT id AS "id!" FROM share_links WHERE id = ?1 OR alias = ?1"#,
+        share_id
+    )
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let totals = sqlx::query!(
+        r#"SELECT COUNT(*) AS "downloads!: i64",
+            COALESCE(SUM(status = 'complete'), 0) AS "completed!: i64",
+            COUNT(DISTINCT ip_address) AS "unique_ips!: i64",
+            COALESCE(SUM(bytes_sent), 0) AS "bytes_sent!: i64"
+        FROM download WHERE share_id = ?"#,
+        share_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    let files = sqlx::query_as!(
+        FileStats,
+        r#"SELECT files.id AS "file_id!", files.path,
+            COUNT(download.id) AS "downloads!: i64",
+            COALESCE(SUM(download.status = 'complete'), 0) AS "completed!: i64",
+            COUNT(DISTINCT download.ip_address) AS "unique_ips!: i64",
+            COALESCE(SUM(download.bytes_sent), 0) AS "bytes_sent!: i64"
+        FROM share_link_files JOIN files ON files.id = share_link_files.file_id
+        LEFT JOIN download ON download.share_id = share_link_files.share_link_id AND download.file_id = files.id
+        WHERE share_link_files.share_link_id = ?
+        GROUP BY files.id ORDER BY files.id"#,
+        share_id
+    )
+    .fetch_all(db)
+    .await?;
+
+    let days = query
+        .days
+        .unwrap_or(StatsQuery::DEFAULT_DAYS)
+        .clamp(1, StatsQuery::MAX_DAYS);
+    let today = Utc::now().date_naive();
+    let first_day = today - Days::new(days as u64 - 1);
+    let since = first_day
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp();
+    let recorded = sqlx::query_as!(
+        DailyStats,
+        r#"SELECT date(started_at, 'unixepoch') AS "date!: String",
+            COUNT(*) AS "downloads!: i64",
+            COALESCE(SUM(status = 'complete'), 0) AS "completed!: i64",
+            COUNT(DISTINCT ip_address) AS "unique_ips!: i64",
+            COALESCE(SUM(bytes_sent), 0) AS "bytes_sent!: i64"
+        FROM download WHERE share_id = ? AND started_at >= ?
+        GROUP BY 1 ORDER BY 1"#,
+        share_id,
+        since
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(Some(ShareStats {
+        share_id,
+        downloads: totals.downloads,
+        completed: totals.completed,
+        completion_rate: (totals.downloads > 0)
+            .then(|| totals.completed as f64 / totals.downloads as f64),
+        unique_ips: totals.unique_ips,
+        bytes_sent: totals.bytes_sent,
+        files,
+        daily: fill_days(recorded, first_day, today),
+    }))
+}
+
+/// The days from `first_day` to `last_day`, with zeros for the ones missing from `recorded`
+fn fill_days(
+    recorded: Vec<DailyStats>,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+) -> Vec<DailyStats> {
+    let mut recorded = recorded.into_iter().peekable();
+    first_day
+        .iter_days()
+        .take_while(|day| *day <= last_day)
+        .map(|day| {
+            let date = day.format("%Y-%m-%d").to_string();
+            recorded
+                .next_if(|stats| stats.date == date)
+                .unwrap_or(DailyStats {
+                    date,
+                    downloads: 0,
+                    completed: 0,
+                    unique_ips: 0,
+                    bytes_sent: 0,
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_share_stats() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, alias) VALUES ('s1', -1, 0, 'holidays'), ('s2', -1, 0, NULL);
+            INSERT INTO files (id, path, file_size) VALUES (1, '/data/a.iso', 100), (2, '/data/b.iso', 10);
+            INSERT INTO share_link_files (share_link_id, file_id) VALUES ('s1', 1), ('s1', 2), ('s2', 1);",
+        )
+        .execute(&db)
+        .await?;
+
+        // The same file is also downloaded from another share
+        let now = Utc::now().timestamp();
+        sqlx::query(
+            "INSERT INTO download (file_path, share_id, file_id, ip_address, status, file_size, started_at, bytes_sent)
+            VALUES ('/data/a.iso', 's1', 1, '192.0.2.1', 'complete', 100, ?1, 100),
+                ('/data/a.iso', 's1', 1, '192.0.2.2', 'partial', 100, ?1, 40),
+                ('/data/a.iso', 's1', 1, '192.0.2.2', 'complete', 100, ?1, 100),
+                ('/data/a.iso', 's2', 1, '192.0.2.1', 'complete', 100, ?1, 100),
+                ('/data/a.iso', NULL, NULL, '192.0.2.3', 'complete', 100, ?1, 100)",
+        )
+        .bind(now)
+        .execute(&db)
+        .await?;
+
+        let stats = share_stats(&db, "holidays", &StatsQuery::default())
+            .await?
+            .unwrap();
+        assert_eq!(stats.share_id, "s1");
+        assert_eq!(stats.downloads, 3);
+        assert_eq!(stats.completed, 2);
+        assert_eq!(stats.completion_rate, Some(2.0 / 3.0));
+        assert_eq!(stats.unique_ips, 2);
+        assert_eq!(stats.bytes_sent, 240);
+        assert_eq!(stats.files.len(), 2);
+        assert_eq!(stats.files[0].downloads, 3);
+        assert_eq!(stats.files[1].downloads, 0);
+        assert_eq!(stats.files[1].path, "/data/b.iso");
+        assert_eq!(stats.daily.len(), StatsQuery::DEFAULT_DAYS as usize);
+        let today = stats.daily.last().unwrap();
+        assert_eq!(today.date, Utc::now().format("%Y-%m-%d").to_string());
+        assert_eq!((today.downloads, today.unique_ips), (3, 2));
+        assert_eq!(stats.daily[0].downloads, 0);
+
+        let stats = share_stats(&db, "s2", &StatsQuery { days: Some(1) })
+            .await?
+            .unwrap();
+        assert_eq!((stats.downloads, stats.completion_rate), (1, Some(1.0)));
+        assert_eq!(stats.daily.len(), 1);
+        assert!(share_stats(&db, "s3", &StatsQuery::default())
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_days() {
+        let day = |d| NaiveDate::from_ymd_opt(2024, 2, d).unwrap();
+        let recorded = vec![DailyStats {
+            date: "2024-02-28".to_string(),
+            downloads: 4,
+            completed: 3,
+            unique_ips: 2,
+            bytes_sent: 1000,
+        }];
+        let daily = fill_days(
+            recorded,
+            day(27),
+            NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+        );
+        let dates: Vec<&str> = daily.iter().map(|stats| stats.date.as_str()).collect();
+        assert_eq!(
+            dates,
+            ["2024-02-27", "2024-02-28", "2024-02-29", "2024-03-01"]
+        );
+        assert_eq!(daily[1].downloads, 4);
+        assert_eq!(daily[2].downloads, 0);
+    }
+}
@@ -0,0 +1,218 @@
+//! Visits of the share pages, recorded in `share_views` when `/s/{share_id}` is rendered.
+//!
+//! Downloads only tell that a recipient fetched a file, a view tells that they opened the link.
+//! The JSON listing of a share is not counted, it is fetched by scripts rather than people.
+
+use anyhow::Result;
+use chrono::{Days, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use utoipa::ToSchema;
+
+use crate::share_stats::StatsQuery;
+
+/// Views listed in [`ShareViews::recent`]
+const RECENT_VIEWS: i64 = 50;
+/// Characters kept of the user agent and referer sent by the client
+const MAX_HEADER_LEN: usize = 512;
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+pub struct ShareView {
+    pub viewed_at: i64,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DailyViews {
+    /// UTC day, `YYYY-MM-DD`
+    pub date: String,
+    pub views: i64,
+    pub unique_ips: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ShareViews {
+    pub share_id: String,
+    pub views: i64,
+    /// Distinct client addresses
+    pub unique_ips: i64,
+    pub first_viewed_at: Option<i64>,
+    pub last_viewed_at: Option<i64>,
+    /// One entry per day, days without views included
+    pub daily: Vec<DailyViews>,
+    /// Latest views first
+    pub recent: Vec<ShareView>,
+}
+
+fn truncate(header: Option<String>) -> Option<String> {
+    header.map(|header| header.chars().take(MAX_HEADER_LEN).collect())
+}
+
+/// Record a view of the share `share_id`, failures are only logged
+pub async fn record(db: &SqlitePool, share_id: &str, view: ShareView) {
+    let (user_agent, referer) = (truncate(view.user_agent), truncate(view.referer));
+    if let Err(e) = sqlx::query!(
+        "INSERT INTO share_views (share_id, viewed_at, ip_address, user_agent, referer) VALUES (?, ?, ?, ?, ?)",
+        share_id,
+        view.viewed_at,
+        view.ip_address,
+        user_agent,
+        referer
+    )
+    .execute(db)
+    .await
+    {
+        tracing::error!("Failed to record a view of share {}: {}", share_id, e);
+    }
+}
+
+/// Views of the share `share_id`, or of its alias, none when there is no such share
+pub async fn share_views(
+    db: &SqlitePool,
+    share_id: &str,
+    query: &StatsQuery,
+) -> Result<Option<ShareViews>> {
+    let Some(share_id) = sqlx::query_scalar!(
+        r#"SELECT id AS "id!" FROM share_links WHERE id = ?1 OR alias = ?1"#,
+        share_id
+    )
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let totals = sqlx::query!(
+        r#"SELECT COUNT(*) AS "views!: i64", COUNT(DISTINCT ip_address) AS "unique_ips!: i64",
+            MIN(viewed_at) AS "first_viewed_at: i64", MAX(viewed_at) AS "last_viewed_at: i64"
+        FROM share_views WHERE share_id = ?"#,
+        share_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    let days = query
+        .days
+        .unwrap_or(StatsQuery::DEFAULT_DAYS)
+        .clamp(1, StatsQuery::MAX_DAYS);
+    let today = Utc::now().date_naive();
+    let first_day = today - Days::new(days as u64 - 1);
+    let since = first_day
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        .and_utc()
+        .timestamp();
+    let mut recorded = sqlx::query_as!(
+        DailyViews,
+        r#"SELECT date(viewed_at, 'unixepoch') AS "date!: String", COUNT(*) AS "views!: i64",
+            COUNT(DISTINCT ip_address) AS "unique_ips!: i64"
+        FROM share_views WHERE share_id = ? AND viewed_at >= ?
+        GROUP BY 1 ORDER BY 1"#,
+        share_id,
+        since
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .peekable();
+    let daily = first_day
+        .iter_days()
+        .take_while(|day| *day <= today)
+        .map(|day| {
+            let date = day.format("%Y-%m-%d").to_string();
+            recorded
+                .next_if(|views| views.date == date)
+                .unwrap_or(DailyViews {
+                    date,
+                    views: 0,
+                    unique_ips: 0,
+                })
+        })
+        .collect();
+
+    let recent = sqlx::query_as!(
+        ShareView,
+        "SELECT viewed_at, ip_address, user_agent, referer FROM share_views
+        WHERE share_id = ? ORDER BY viewed_at DESC, id DESC LIMIT ?",
+        share_id,
+        RECENT_VIEWS
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(Some(ShareViews {
+        share_id,
+        views: totals.views,
+        unique_ips: totals.unique_ips,
+        first_viewed_at: totals.first_viewed_at,
+        last_viewed_at: totals.last_viewed_at,
+        daily,
+        recent,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_share_views() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, alias) VALUES ('s1', -1, 0, 'holidays'), ('s2', -1, 0, NULL)",
+        )
+        .execute(&db)
+        .await?;
+        let stats = share_views(&db, "s1", &StatsQuery::default())
+            .await?
+            .unwrap();
+        assert_eq!((stats.views, stats.last_viewed_at), (0, None));
+
+        let now = Utc::now().timestamp();
+        for (share_id, ip, viewed_at) in [
+            ("s1", "192.0.2.1", now - 10),
+            ("s1", "192.0.2.1", now),
+            ("s1", "192.0.2.2", now),
+            ("s2", "192.0.2.3", now),
+        ] {
+            let view = ShareView {
+                viewed_at,
+                ip_address: Some(ip.to_string()),
+                user_agent: Some("x".repeat(1000)),
+                referer: Some("https://mail.example.com/".to_string()),
+            };
+            record(&db, share_id, view).await;
+        }
+
+        let stats = share_views(&db, "holidays", &StatsQuery { days: Some(7) })
+            .await?
+            .unwrap();
+        assert_eq!(stats.share_id, "s1");
+        assert_eq!((stats.views, stats.unique_ips), (3, 2));
+        assert_eq!(
+            (stats.first_viewed_at, stats.last_viewed_at),
+            (Some(now - 10), Some(now))
+        );
+        assert_eq!(stats.daily.len(), 7);
+        let today = stats.daily.last().unwrap();
+        assert_eq!(today.date, Utc::now().format("%Y-%m-%d").to_string());
+        assert_eq!(stats.daily.iter().map(|day| day.views).sum::<i64>(), 3);
+        assert_eq!(stats.recent.len(), 3);
+        assert_eq!(stats.recent[2].viewed_at, now - 10);
+        assert_eq!(
+            stats.recent[0].user_agent.as_ref().map(String::len),
+            Some(MAX_HEADER_LEN)
+        );
+        assert!(share_views(&db, "s3", &StatsQuery::default())
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
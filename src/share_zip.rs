@@ -0,0 +1,241 @@
+//! Several files of a share in one zip, streamed while it is written.
+//!
+//! `GET /s/{share_id}/zip?file={token}&file={token}` sends the files of the tokens, or all the
+//! files of the share without any: it is what the form of the gallery layout of the share page
+//! submits, see [`crate::share_page::Layout`]. The files are stored uncompressed, photos and videos
+//! don't compress and the download starts right away whatever the size of the share. Each file goes
+//! through the plugins and the filename rules of the share like a single download, the files
+//! missing from the disk are left out. Encrypted shares have no zip, the server can't read them.
+
+use axum::body::Body;
+use axum::extract::{Path, RawQuery, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use zip::write::SimpleFileOptions;
+
+use crate::error::AppError;
+use crate::{filename_rules, not_found, plugins, proxy, App};
+
+/// Bytes sent to the client at once
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Chunks written ahead of the client
+const CHUNKS_AHEAD: usize = 8;
+
+struct ZipEntry {
+    /// Name in the zip, unique
+    name: String,
+    /// Opened when its turn comes, so the zip holds one file descriptor at a time
+    path: PathBuf,
+}
+
+/// `name`, or `name (2)`, `name (3)`... before its extension when `taken` has it
+pub fn unique_name(taken: &mut HashSet<String>, name: &str) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => name.split_at(dot),
+        _ => (name, ""),
+    };
+    let mut unique = name.to_string();
+    let mut n = 1;
+    while taken.contains(&unique) {
+        n += 1;
+        unique = format!("{} ({}){}", stem, n, extension);
+    }
+    taken.insert(unique.clone());
+    unique
+}
+
+/// Write the zip of `entries` to `writer`, which doesn't need to seek. The files gone since the
+/// zip started are left out.
+fn write_zip<W: Write>(writer: W, entries: Vec<ZipEntry>) -> zip::result::ZipResult<W> {
+    let mut archive = zip::ZipWriter::new_stream(writer);
+    for entry in entries {
+        let mut file = match std::fs::File::open(&entry.path) {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!("Left {} out of a zip: {}", entry.path.display(), e);
+                continue;
+            }
+        };
+        let size = file.metadata()?.len();
+        let options = SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .large_file(size >= u32::MAX as u64);
+        archive.start_file(entry.name, options)?;
+        io::copy(&mut file, &mut archive)?;
+    }
+    let mut writer = archive.finish()?.into_inner();
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Sends what is written to the response body, fails once the client is gone
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Zip of files of a share, see the module documentation
+pub async fn share_zip(
+    State(app_state): State<App>,
+    Path(share_id): Path<String>,
+    RawQuery(query): RawQuery,
+    client: proxy::Client,
+) -> Response {
+    let tokens: Vec<String> = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key == "file")
+        .map(|(_, token)| token.into_owned())
+        .collect();
+    let now = chrono::offset::Utc::now().timestamp();
+    let files = sqlx::query!(
+        r#"SELECT share_link_files.token as "token!", files.id as "id!", files.path, share_links.filename_rules
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.share_link_id=$1 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $2) AND share_links.deleted_at IS NULL
+        ORDER BY files.path"#,
+        share_id,
+        now
+    )
+    .fetch_all(&app_state.db_reader)
+    .await;
+    let files = match files {
+        Ok(files) => files,
+        Err(e) => return AppError::from(e).into_response(),
+    };
+
+    let today = chrono::offset::Utc::now().date_naive();
+    let mut names = HashSet::new();
+    let mut entries = vec![];
+    for file in files
+        .into_iter()
+        .filter(|file| tokens.is_empty() || tokens.contains(&file.token))
+    {
+        let rules: Vec<filename_rules::FilenameRule> =
+            serde_json::from_str(&file.filename_rules).unwrap_or_default();
+        let name = std::path::Path::new(&file.path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let mut download = plugins::DownloadRequest {
+            share_id: share_id.clone(),
+            file_id: file.id,
+            path: file.path.clone(),
+            client_ip: client.ip.map(|ip| ip.to_string()),
+            download_name: filename_rules::rewrite(&name, &rules, today),
+        };
+        if app_state.plugins.before_download(&mut download).is_err() {
+            return (StatusCode::FORBIDDEN, Html("Download refused".to_string())).into_response();
+        }
+        match tokio::fs::metadata(&file.path).await {
+            Ok(_) => entries.push(ZipEntry {
+                name: unique_name(&mut names, &download.download_name),
+                path: file.path.into(),
+            }),
+            Err(e) => tracing::warn!("Left {} out of the zip of {}: {}", file.path, share_id, e),
+        }
+    }
+    if entries.is_empty() {
+        return not_found().await.into_response();
+    }
+    // Counted as one download of the client
+    let slot = match app_state
+        .limits
+        .try_start(client.ip, &format!("/s/{}/zip", share_id))
+    {
+        Ok(slot) => slot,
+        Err(limited) => {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Html(limited.message().to_string()),
+            )
+                .into_response()
+        }
+    };
+
+    let (sender, receiver) = mpsc::channel(CHUNKS_AHEAD);
+    let writer = BufWriter::with_capacity(CHUNK_SIZE, ChannelWriter(sender.clone()));
+    let zip_name = share_id.clone();
+    tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        if let Err(e) = write_zip(writer, entries) {
+            tracing::warn!("Zip of {} interrupted: {}", zip_name, e);
+            let _ = sender.blocking_send(Err(io::Error::other(e)));
+        }
+    });
+    let body = Body::from_stream(futures::stream::unfold(
+        receiver,
+        |mut receiver| async move { receiver.recv().await.map(|chunk| (chunk, receiver)) },
+    ));
+    (
+        [
+            (CONTENT_TYPE, "application/zip".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                filename_rules::content_disposition(&format!("{}.zip", share_id)),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_unique_name() {
+        let mut taken = HashSet::new();
+        assert_eq!(unique_name(&mut taken, "photo.jpg"), "photo.jpg");
+        assert_eq!(unique_name(&mut taken, "photo.jpg"), "photo (2).jpg");
+        assert_eq!(unique_name(&mut taken, "photo.jpg"), "photo (3).jpg");
+        assert_eq!(unique_name(&mut taken, ".bashrc"), ".bashrc");
+        assert_eq!(unique_name(&mut taken, ".bashrc"), ".bashrc (2)");
+    }
+
+    #[test]
+    fn test_write_zip() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut entries = vec![];
+        for (name, content) in [("a.jpg", "first"), ("a (2).jpg", "second")] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            entries.push(ZipEntry {
+                name: name.to_string(),
+                path,
+            });
+        }
+        entries.push(ZipEntry {
+            name: "deleted.jpg".to_string(),
+            path: dir.path().join("deleted.jpg"),
+        });
+        let zip = write_zip(vec![], entries)?;
+
+        let mut archive = zip::ZipArchive::new(io::Cursor::new(zip))?;
+        assert_eq!(archive.len(), 2);
+        let mut content = String::new();
+        let mut file = archive.by_name("a (2).jpg")?;
+        assert_eq!(file.compression(), zip::CompressionMethod::Stored);
+        file.read_to_string(&mut content)?;
+        assert_eq!(content, "second");
+        Ok(())
+    }
+}
@@ -0,0 +1,535 @@
+//! Declarative shares defined in `shares.toml`.
+//!
+//! The file in the data directory lists long-lived shares by slug. On startup and whenever the
+//! file changes, the shares it defines are created or updated and those removed from it are
+//! moved to the trash, see [`crate::worker::trash`], so the shares can be managed from a git
+//! repository. Adding a share back restores it. Shares created through the API
+//! are never touched. An invalid file is reported and ignored, a missing one changes nothing.
+
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::audit::{self, Actor};
+use crate::file_dedup;
+use crate::share_cache::ShareCache;
+use crate::share_password;
+use crate::validation::{Validate, Validator, MAX_PASSWORD_LEN};
+use crate::worker::trash;
+
+pub const SHARES_FILE_NAME: &str = "shares.toml";
+/// Editors write a file in several steps, wait for them to settle before reading it
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+const ACTOR: Actor = Actor {
+    name: "shares_file",
+    client_ip: None,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SharesFile {
+    #[serde(default, rename = "share")]
+    shares: Vec<ShareDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShareDefinition {
+    /// Identifier of the share in its URL, `/s/<slug>`
+    slug: String,
+    /// Files relative to the base path
+    paths: Vec<PathBuf>,
+    /// `2026-12-31` or `2026-12-31T18:00:00Z`, the share never expires when unset
+    expires_at: Option<toml::value::Datetime>,
+    password: Option<String>,
+}
+
+impl ShareDefinition {
+    fn expiration(&self) -> Option<i64> {
+        let Some(expires_at) = &self.expires_at else {
+            return Some(-1);
+        };
+        let expires_at = expires_at.to_string();
+        chrono::DateTime::parse_from_rfc3339(&expires_at)
+            .map(|date| date.timestamp())
+            .or_else(|_| {
+                chrono::NaiveDateTime::parse_from_str(&expires_at, "%Y-%m-%dT%H:%M:%S%.f")
+                    .map(|date| date.and_utc().timestamp())
+            })
+            .or_else(|_| {
+                chrono::NaiveDate::parse_from_str(&expires_at, "%Y-%m-%d")
+                    .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp())
+            })
+            .ok()
+    }
+}
+
+impl Validate for SharesFile {
+    fn validate(&self, v: &mut Validator) {
+        let mut slugs = HashMap::new();
+        for (i, share) in self.shares.iter().enumerate() {
+            let field = format!("share[{}]", i);
+            v.share_id(&format!("{}.slug", field), &share.slug);
+            if let Some(first) = slugs.insert(share.slug.as_str(), i) {
+                v.error(
+                    format!("{}.slug", field),
+                    format!("is already used by share[{}]", first),
+                );
+            }
+            v.check(
+                !share.paths.is_empty(),
+                &format!("{}.paths", field),
+                "must not be empty",
+            );
+            for (j, path) in share.paths.iter().enumerate() {
+                let field = format!("{}.paths[{}]", field, j);
+                v.path(&field, path);
+                v.check(
+                    path.is_relative(),
+                    &field,
+                    "must be relative to the base path",
+                );
+            }
+            v.check(
+                share.expiration().is_some(),
+                &format!("{}.expires_at", field),
+                "must be a date or a date and time",
+            );
+            if let Some(password) = &share.password {
+                v.check(
+                    !password.is_empty() && password.len() <= MAX_PASSWORD_LEN,
+                    &format!("{}.password", field),
+                    "must be between 1 and 256 characters",
+                );
+            }
+        }
+    }
+}
+
+/// Changes made by a reconciliation, by slug
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub revoked: Vec<String>,
+    /// Shares left as they were, e.g. because one of their files is missing
+    pub errors: Vec<String>,
+}
+
+/// Share defined by a previous reconciliation
+struct ManagedShare {
+    expiration: i64,
+    password_hash: Option<String>,
+    deleted: bool,
+    paths: Vec<String>,
+}
+
+async fn managed_shares(db: &SqlitePool) -> Result<HashMap<String, ManagedShare>> {
+    let rows = sqlx::query!(
+        r#"SELECT id AS "id!", expiration, password_hash, deleted_at IS NOT NULL AS "deleted!: bool"
+        FROM share_links WHERE managed = 1"#
+    )
+    .fetch_all(db)
+    .await?;
+    let mut shares: HashMap<String, ManagedShare> = rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.id,
+                ManagedShare {
+                    expiration: row.expiration,
+                    password_hash: row.password_hash,
+                    deleted: row.deleted,
+                    paths: vec![],
+                },
+            )
+        })
+        .collect();
+    let files = sqlx::query!(
+        r#"SELECT share_link_files.share_link_id AS "share_id!", files.path
+        FROM share_link_files JOIN files ON files.id = share_link_files.file_id
+        JOIN share_links ON share_links.id = share_link_files.share_link_id
+        WHERE share_links.managed = 1 ORDER BY files.id"#
+    )
+    .fetch_all(db)
+    .await?;
+    for file in files {
+        if let Some(share) = shares.get_mut(&file.share_id) {
+            share.paths.push(file.path);
+        }
+    }
+    Ok(shares)
+}
+
+/// Absolute paths of the files of `share`, which must be in the base path
+fn resolve_paths(base_path: &Path, share: &ShareDefinition) -> Result<Vec<String>> {
+    share
+        .paths
+        .iter()
+        .map(|path| {
+            std::fs::canonicalize(base_path.join(path))
+                .ok()
+                .filter(|file| file.starts_with(base_path) && file.is_file())
+                .map(|file| file.to_string_lossy().into_owned())
+                .with_context(|| format!("No file {} in the base path", path.display()))
+        })
+        .collect()
+}
+
+/// Create or update the share `slug` with `paths`, replacing its files when `replace_files`
+async fn upsert_share(
+    db: &SqlitePool,
+    slug: &str,
+    paths: &[String],
+    expiration: i64,
+    password_hash: Option<&str>,
+    replace_files: bool,
+) -> Result<()> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        r#"INSERT INTO share_links (id, expiration, created_at, password_hash, managed)
+        VALUES (?1, ?2, ?3, ?4, 1)
+        ON CONFLICT (id) DO UPDATE SET expiration = ?2, password_hash = ?4, deleted_at = NULL"#,
+        slug,
+        expiration,
+        now,
+        password_hash
+    )
+    .execute(&mut *tx)
+    .await?;
+    if replace_files {
+        let replaced = sqlx::query_scalar!(
+            r#"DELETE FROM share_link_files WHERE share_link_id = ? RETURNING file_id AS "file_id!""#,
+            slug
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        file_dedup::delete_unreferenced(&mut tx, &replaced).await?;
+        for path in paths {
+            let file_size = i64::try_from(std::fs::metadata(path)?.len())?;
+            let file_id = sqlx::query!(
+                "INSERT INTO files (sha256, path, file_size) VALUES ('', ?, ?)",
+                path,
+                file_size
+            )
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+            sqlx::query!(
+                "INSERT INTO share_link_files (share_link_id, file_id) VALUES (?, ?)",
+                slug,
+                file_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Bring the shares defined by `file` in line with it
+pub async fn reconcile(
+    db: &SqlitePool,
+    share_cache: &ShareCache,
+    base_path: &Path,
+    file: &Path,
+) -> Result<Option<ReconcileReport>> {
+    let content = match std::fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let definitions: SharesFile =
+        toml::from_str(&content).with_context(|| format!("Failed to parse {}", file.display()))?;
+    let mut v = Validator::default();
+    definitions.validate(&mut v);
+    v.finish()
+        .map_err(|e| anyhow::anyhow!("Invalid {}: {}", file.display(), e))?;
+
+    let base_path = std::fs::canonicalize(base_path)?;
+    let now = chrono::offset::Utc::now().timestamp();
+    let mut managed = managed_shares(db).await?;
+    let mut report = ReconcileReport::default();
+    for share in &definitions.shares {
+        let slug = share.slug.as_str();
+        let existing = managed.remove(slug);
+        let paths = match resolve_paths(&base_path, share) {
+            Ok(paths) => paths,
+            Err(e) => {
+                report.errors.push(format!("{}: {:#}", slug, e));
+                continue;
+            }
+        };
+        // Checked by the validation
+        let expiration = share.expiration().unwrap_or(-1);
+
+        let (password_hash, password_changed) = match (&share.password, &existing) {
+            (None, _) => (
+                None,
+                existing.as_ref().is_some_and(|e| e.password_hash.is_some()),
+            ),
+            (
+                Some(password),
+                Some(ManagedShare {
+                    password_hash: Some(hash),
+                    ..
+                }),
+            ) if share_password::verify_password(password, hash) => (Some(hash.clone()), false),
+            (Some(password), _) => (Some(share_password::hash_password(password)?), true),
+        };
+        let files_changed = existing.as_ref().is_none_or(|e| e.paths != paths);
+        let changed = match &existing {
+            None => {
+                let taken = sqlx::query_scalar!("SELECT id FROM share_links WHERE id = ?", slug)
+                    .fetch_optional(db)
+                    .await?;
+                if taken.is_some() {
+                    report
+                        .errors
+                        .push(format!("{}: used by a share created through the API", slug));
+                    continue;
+                }
+                true
+            }
+            Some(existing) => {
+                files_changed
+                    || password_changed
+                    || existing.expiration != expiration
+                    || existing.deleted
+            }
+        };
+        if !changed {
+            continue;
+        }
+
+        if let Err(e) = upsert_share(
+            db,
+            slug,
+            &paths,
+            expiration,
+            password_hash.as_deref(),
+            files_changed,
+        )
+        .await
+        {
+            report.errors.push(format!("{}: {:#}", slug, e));
+            continue;
+        }
+        share_cache.invalidate(slug);
+        let action = if existing.is_none() {
+            report.created.push(slug.to_string());
+            "create_share"
+        } else {
+            report.updated.push(slug.to_string());
+            "update_share"
+        };
+        audit::record(
+            db,
+            &ACTOR,
+            action,
+            Some(slug),
+            serde_json::json!({
+                "files": paths,
+                "expiration": expiration,
+                "password": share.password.is_some(),
+            }),
+        )
+        .await;
+    }
+
+    // Shares left are not in the file anymore
+    for (slug, share) in managed {
+        if share.deleted || (share.expiration >= 0 && share.expiration <= now) {
+            continue;
+        }
+        if trash::delete_share(db, &slug).await?.is_none() {
+            continue;
+        }
+        share_cache.invalidate(&slug);
+        audit::record(
+            db,
+            &ACTOR,
+            "revoke_share",
+            Some(&slug),
+            serde_json::json!({}),
+        )
+        .await;
+        report.revoked.push(slug);
+    }
+    Ok(Some(report))
+}
+
+async fn reconcile_and_log(
+    db: &SqlitePool,
+    share_cache: &ShareCache,
+    base_path: &Path,
+    file: &Path,
+) {
+    match reconcile(db, share_cache, base_path, file).await {
+        Ok(Some(report)) => {
+            if !report.created.is_empty()
+                || !report.updated.is_empty()
+                || !report.revoked.is_empty()
+            {
+                tracing::info!(
+                    "Reconciled {}: {} created, {} updated, {} revoked",
+                    file.display(),
+                    report.created.len(),
+                    report.updated.len(),
+                    report.revoked.len()
+                );
+            }
+            for error in &report.errors {
+                tracing::error!("Share of {} left unchanged: {}", file.display(), error);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Shares left unchanged: {:#}", e),
+    }
+}
+
+/// Reconcile the shares with `data_dir/shares.toml` now and whenever it changes
+pub fn spawn_watch(db: SqlitePool, share_cache: ShareCache, base_path: PathBuf, data_dir: &Path) {
+    let file = data_dir.join(SHARES_FILE_NAME);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    // The directory is watched as editors and git replace the file rather than writing it
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Our own reads of the file are reported as accesses
+        if event.is_ok_and(|event| {
+            matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) && event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == Some(SHARES_FILE_NAME.as_ref()))
+        }) {
+            let _ = tx.send(());
+        }
+    })
+    .and_then(|mut watcher| {
+        watcher.watch(data_dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    });
+    if let Err(e) = &watcher {
+        tracing::error!(
+            "Failed to watch {}, it is only read on startup: {}",
+            file.display(),
+            e
+        );
+    }
+
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the notifications
+        let _watcher = watcher;
+        reconcile_and_log(&db, &share_cache, &base_path, &file).await;
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(DEBOUNCE).await;
+            while rx.try_recv().is_ok() {}
+            reconcile_and_log(&db, &share_cache, &base_path, &file).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    #[tokio::test]
+    async fn test_reconcile() -> Result<()> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        let cache = ShareCache::new();
+        let dir = tempfile::tempdir()?;
+        let base_path = dir.path().join("base");
+        std::fs::create_dir(&base_path)?;
+        for name in ["kit.zip", "logo.png"] {
+            std::fs::write(base_path.join(name), name)?;
+        }
+        let file = dir.path().join(SHARES_FILE_NAME);
+        assert!(reconcile(&db, &cache, &base_path, &file).await?.is_none());
+
+        std::fs::write(
+            &file,
+            r#"
+            [[share]]
+            slug = "press-kit"
+            paths = ["kit.zip"]
+            password = "s3cret"
+
+            [[share]]
+            slug = "logo"
+            paths = ["logo.png"]
+            expires_at = 2099-12-31
+            "#,
+        )?;
+        let report = reconcile(&db, &cache, &base_path, &file).await?.unwrap();
+        assert_eq!(report.created, vec!["press-kit", "logo"]);
+        let share = cache.get("press-kit", &db).await?.unwrap();
+        assert!(share_password::verify_password(
+            "s3cret",
+            share.password_hash.as_deref().unwrap()
+        ));
+        let share = cache.get("logo", &db).await?.unwrap();
+        assert_eq!(share.expiration, 4102358400);
+
+        // Unchanged definitions are left alone, the password is not hashed again
+        let report = reconcile(&db, &cache, &base_path, &file).await?.unwrap();
+        assert!(report.created.is_empty() && report.updated.is_empty());
+
+        std::fs::write(
+            &file,
+            r#"
+            [[share]]
+            slug = "press-kit"
+            paths = ["kit.zip", "logo.png"]
+
+            [[share]]
+            slug = "missing"
+            paths = ["missing.txt"]
+            "#,
+        )?;
+        let report = reconcile(&db, &cache, &base_path, &file).await?.unwrap();
+        assert_eq!(report.updated, vec!["press-kit"]);
+        assert_eq!(report.revoked, vec!["logo"]);
+        assert_eq!(report.errors.len(), 1);
+        let share = cache.get("press-kit", &db).await?.unwrap();
+        assert_eq!(share.files.len(), 2);
+        assert!(share.password_hash.is_none());
+        assert!(cache.get("logo", &db).await?.is_none());
+
+        // Adding it back restores it from the trash
+        std::fs::write(
+            &file,
+            r#"
+            [[share]]
+            slug = "press-kit"
+            paths = ["kit.zip", "logo.png"]
+
+            [[share]]
+            slug = "logo"
+            paths = ["logo.png"]
+            expires_at = 2099-12-31
+            "#,
+        )?;
+        let report = reconcile(&db, &cache, &base_path, &file).await?.unwrap();
+        assert_eq!(report.updated, vec!["logo"]);
+        assert!(cache.get("logo", &db).await?.is_some());
+
+        std::fs::write(&file, "[[share]]\nslug = \"../x\"\npaths = []\n")?;
+        assert!(reconcile(&db, &cache, &base_path, &file).await.is_err());
+        assert!(cache.get("press-kit", &db).await?.is_some());
+        Ok(())
+    }
+}
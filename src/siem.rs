@@ -0,0 +1,646 @@
+//! Forwarding of download and authentication events to a SIEM.
+//!
+//! When `HARDWIRE_SIEM_URL` is set, completed downloads and authentication attempts are sent to a
+//! syslog collector as RFC 5424 messages, or as CEF records behind a syslog header. `udp://` sends
+//! one datagram per event, `tcp://` and `tls://` (RFC 5425) frame them with octet counting. TLS
+//! trusts the system roots, `SSL_CERT_FILE` points to the certificate of a private CA.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{SecondsFormat, Utc};
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::progress::{AuthAttempt, Event, FileDownload};
+
+const APP_NAME: &str = "hardwire";
+/// Private enterprise number reserved for documentation (RFC 5612), it scopes our SD-IDs
+const ENTERPRISE_ID: u32 = 32473;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// RFC 5424 with the event fields as structured data
+    Syslog,
+    /// ArcSight Common Event Format
+    Cef,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    Informational,
+    Debug,
+}
+
+impl Severity {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "emerg" | "emergency" => Severity::Emergency,
+            "alert" => Severity::Alert,
+            "crit" | "critical" => Severity::Critical,
+            "err" | "error" => Severity::Error,
+            "warn" | "warning" => Severity::Warning,
+            "notice" => Severity::Notice,
+            "info" | "informational" => Severity::Informational,
+            "debug" => Severity::Debug,
+            _ => return None,
+        })
+    }
+
+    /// CEF severity, from 0 to 10
+    fn cef(self) -> u8 {
+        match self {
+            Severity::Emergency | Severity::Alert => 10,
+            Severity::Critical => 9,
+            Severity::Error => 7,
+            Severity::Warning => 5,
+            Severity::Notice => 3,
+            Severity::Informational => 1,
+            Severity::Debug => 0,
+        }
+    }
+}
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+/// Severity of each forwarded event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Severities {
+    pub download: Severity,
+    pub auth_success: Severity,
+    pub auth_failure: Severity,
+}
+
+impl Default for Severities {
+    fn default() -> Self {
+        Self {
+            download: Severity::Informational,
+            auth_success: Severity::Notice,
+            auth_failure: Severity::Warning,
+        }
+    }
+}
+
+impl Severities {
+    /// Overrides of the defaults such as `download=notice,auth_failure=err`
+    fn parse(mapping: &str) -> Result<Self> {
+        let mut severities = Self::default();
+        for entry in mapping.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (event, name) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected event=severity, got {}", entry))?;
+            let severity =
+                Severity::parse(name.trim()).ok_or_else(|| anyhow!("Unknown severity {}", name))?;
+            match event.trim() {
+                "download" => severities.download = severity,
+                "auth_success" => severities.auth_success = severity,
+                "auth_failure" => severities.auth_failure = severity,
+                other => bail!("Unknown event {}", other),
+            }
+        }
+        Ok(severities)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SiemConfig {
+    pub transport: Transport,
+    pub host: String,
+    pub port: u16,
+    pub format: Format,
+    pub facility: u8,
+    pub severities: Severities,
+    /// HOSTNAME of the syslog header
+    pub hostname: String,
+}
+
+impl SiemConfig {
+    pub fn new(
+        url: &str,
+        format: &str,
+        facility: &str,
+        severities: &str,
+        hostname: &str,
+    ) -> Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid SIEM URL {}", url))?;
+        let (transport, default_port) = match url.scheme() {
+            "udp" => (Transport::Udp, 514),
+            "tcp" => (Transport::Tcp, 601),
+            "tls" => (Transport::Tls, 6514),
+            other => bail!(
+                "Unsupported SIEM transport {}, expected udp, tcp or tls",
+                other
+            ),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("The SIEM URL has no host"))?
+            .to_string();
+        let format = match format {
+            "syslog" => Format::Syslog,
+            "cef" => Format::Cef,
+            other => bail!("Unknown SIEM format {}, expected syslog or cef", other),
+        };
+        let facility = facility
+            .parse::<u8>()
+            .ok()
+            .filter(|f| (*f as usize) < FACILITIES.len())
+            .or_else(|| {
+                FACILITIES
+                    .iter()
+                    .position(|f| *f == facility)
+                    .map(|f| f as u8)
+            })
+            .ok_or_else(|| anyhow!("Unknown syslog facility {}", facility))?;
+        // HOSTNAME is printable ASCII without spaces, NILVALUE otherwise
+        let hostname = if !hostname.is_empty() && hostname.bytes().all(|b| b.is_ascii_graphic()) {
+            hostname.to_string()
+        } else {
+            "-".to_string()
+        };
+
+        Ok(Self {
+            transport,
+            host,
+            port: url.port().unwrap_or(default_port),
+            format,
+            facility,
+            severities: Severities::parse(severities)?,
+            hostname,
+        })
+    }
+
+    /// Message forwarded for `event`, `None` for the events which aren't forwarded
+    fn format_event(&self, event: &Event, timestamp: &str) -> Option<String> {
+        let record = match event {
+            Event::DownloadProgress(download) if download.is_complete() => {
+                Record::download(download, self.severities.download)
+            }
+            Event::DownloadProgress(_) | Event::Download(_) | Event::TaskProgress(_) => {
+                return None
+            }
+            Event::Auth(attempt) => Record::auth(
+                attempt,
+                if attempt.success {
+                    self.severities.auth_success
+                } else {
+                    self.severities.auth_failure
+                },
+            ),
+        };
+
+        let header = format!(
+            "<{}>1 {} {} {} {} {}",
+            self.facility as u32 * 8 + record.severity as u32,
+            timestamp,
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            record.msg_id,
+        );
+        Some(match self.format {
+            Format::Syslog => {
+                let params: String = record
+                    .fields
+                    .iter()
+                    .map(|(name, value)| format!(" {}=\"{}\"", name.syslog, sd_escape(value)))
+                    .collect();
+                format!(
+                    "{} [{}@{}{}] {}",
+                    header, record.msg_id, ENTERPRISE_ID, params, record.message
+                )
+            }
+            Format::Cef => {
+                let extension: Vec<String> = record
+                    .fields
+                    .iter()
+                    .map(|(name, value)| {
+                        let label = name
+                            .cef_label
+                            .map(|label| format!("{}Label={} ", name.cef, label))
+                            .unwrap_or_default();
+                        format!("{}{}={}", label, name.cef, cef_value_escape(value))
+                    })
+                    .collect();
+                format!(
+                    "{} - CEF:0|Hardwire|{}|{}|{}|{}|{}|{}",
+                    header,
+                    APP_NAME,
+                    env!("CARGO_PKG_VERSION"),
+                    record.signature,
+                    cef_header_escape(&record.message),
+                    record.severity.cef(),
+                    extension.join(" ")
+                )
+            }
+        })
+    }
+}
+
+/// Name of a field in the syslog structured data and in the CEF extension
+struct FieldName {
+    syslog: &'static str,
+    cef: &'static str,
+    /// Label of the CEF custom fields
+    cef_label: Option<&'static str>,
+}
+
+const FILE_NAME: FieldName = FieldName {
+    syslog: "fileName",
+    cef: "fname",
+    cef_label: None,
+};
+const FILE_PATH: FieldName = FieldName {
+    syslog: "filePath",
+    cef: "filePath",
+    cef_label: None,
+};
+const FILE_SIZE: FieldName = FieldName {
+    syslog: "fileSize",
+    cef: "fsize",
+    cef_label: None,
+};
+const TRANSACTION_ID: FieldName = FieldName {
+    syslog: "transactionId",
+    cef: "externalId",
+    cef_label: None,
+};
+const REALM: FieldName = FieldName {
+    syslog: "realm",
+    cef: "cs1",
+    cef_label: Some("realm"),
+};
+const OUTCOME: FieldName = FieldName {
+    syslog: "outcome",
+    cef: "outcome",
+    cef_label: None,
+};
+const CLIENT_IP: FieldName = FieldName {
+    syslog: "src",
+    cef: "src",
+    cef_label: None,
+};
+const PATH: FieldName = FieldName {
+    syslog: "path",
+    cef: "request",
+    cef_label: None,
+};
+const REASON: FieldName = FieldName {
+    syslog: "reason",
+    cef: "reason",
+    cef_label: None,
+};
+
+struct Record {
+    msg_id: &'static str,
+    signature: &'static str,
+    message: String,
+    severity: Severity,
+    fields: Vec<(FieldName, String)>,
+}
+
+impl Record {
+    fn download(download: &FileDownload, severity: Severity) -> Self {
+        let file_name = std::path::Path::new(&download.file_path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Record {
+            msg_id: "download",
+            signature: "download",
+            message: format!("File {} downloaded", file_name),
+            severity,
+            fields: vec![
+                (FILE_NAME, file_name),
+                (FILE_PATH, download.file_path.clone()),
+                (FILE_SIZE, download.file_size.to_string()),
+                (TRANSACTION_ID, download.transaction_id.clone()),
+            ],
+        }
+    }
+
+    fn auth(attempt: &AuthAttempt, severity: Severity) -> Self {
+        let (signature, outcome) = if attempt.success {
+            ("auth_success", "success")
+        } else {
+            ("auth_failure", "failure")
+        };
+        let mut fields = vec![
+            (REALM, attempt.realm.to_string()),
+            (OUTCOME, outcome.to_string()),
+        ];
+        if let Some(client_ip) = &attempt.client_ip {
+            fields.push((CLIENT_IP, client_ip.clone()));
+        }
+        fields.push((PATH, attempt.path.clone()));
+        if let Some(reason) = &attempt.reason {
+            fields.push((REASON, reason.clone()));
+        }
+        Record {
+            msg_id: "auth",
+            signature,
+            message: format!("Authentication {} on {}", outcome, attempt.realm),
+            severity,
+            fields,
+        }
+    }
+}
+
+fn sd_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+fn cef_header_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+fn cef_value_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+type Stream = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+struct Forwarder {
+    config: SiemConfig,
+    tls: Option<TlsConnector>,
+    udp: Option<UdpSocket>,
+    stream: Option<Stream>,
+}
+
+impl Forwarder {
+    fn new(config: SiemConfig) -> Result<Self> {
+        let tls = if config.transport == Transport::Tls {
+            let mut roots = RootCertStore::empty();
+            let native = rustls_native_certs::load_native_certs();
+            for error in native.errors {
+                tracing::warn!("Failed to load a system certificate: {}", error);
+            }
+            roots.add_parsable_certificates(native.certs);
+            let client_config = ClientConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+            Some(TlsConnector::from(Arc::new(client_config)))
+        } else {
+            None
+        };
+        Ok(Self {
+            config,
+            tls,
+            udp: None,
+            stream: None,
+        })
+    }
+
+    async fn connect(&self) -> Result<Stream> {
+        let tcp = TcpStream::connect((self.config.host.as_str(), self.config.port)).await?;
+        match &self.tls {
+            Some(tls) => {
+                let server_name = ServerName::try_from(self.config.host.clone())?;
+                Ok(Box::new(tls.connect(server_name, tcp).await?))
+            }
+            None => Ok(Box::new(tcp)),
+        }
+    }
+
+    async fn send(&mut self, message: &str) -> Result<()> {
+        if self.config.transport == Transport::Udp {
+            if self.udp.is_none() {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket
+                    .connect((self.config.host.as_str(), self.config.port))
+                    .await?;
+                self.udp = Some(socket);
+            }
+            if let Some(socket) = &self.udp {
+                socket.send(message.as_bytes()).await?;
+            }
+            return Ok(());
+        }
+
+        // Octet counting framing, RFC 6587 section 3.4.1
+        let frame = format!("{} {}", message.len(), message);
+        // The collector may have closed an idle connection, reconnect once
+        for attempt in 0..2 {
+            let mut stream = match self.stream.take() {
+                Some(stream) => stream,
+                None => self.connect().await?,
+            };
+            match async {
+                stream.write_all(frame.as_bytes()).await?;
+                stream.flush().await
+            }
+            .await
+            {
+                Ok(()) => {
+                    self.stream = Some(stream);
+                    return Ok(());
+                }
+                Err(e) if attempt == 1 => return Err(e.into()),
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forward the download and authentication events sent on the progress channel
+pub fn spawn(config: SiemConfig, mut receiver: broadcast::Receiver<Event>) -> Result<()> {
+    let mut forwarder = Forwarder::new(config)?;
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                    if let Some(message) = forwarder.config.format_event(&event, &timestamp) {
+                        if let Err(e) = forwarder.send(&message).await {
+                            tracing::warn!("Failed to forward event to the SIEM: {}", e);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(count)) => {
+                    tracing::warn!("SIEM forwarder missed {} events", count)
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const TIMESTAMP: &str = "2026-10-15T09:00:00.000Z";
+
+    fn config(url: &str, format: &str) -> SiemConfig {
+        SiemConfig::new(url, format, "local0", "", "files.example.com").unwrap()
+    }
+
+    fn download() -> Event {
+        Event::DownloadProgress(FileDownload {
+            read_bytes: 14,
+            finished: true,
+            ..FileDownload::new(
+                "abc".to_string(),
+                "/data/dir/a=b.txt".to_string(),
+                None,
+                14,
+                0..14,
+            )
+        })
+    }
+
+    fn failed_auth() -> Event {
+        Event::Auth(AuthAttempt {
+            realm: "webhook",
+            success: false,
+            client_ip: Some("192.0.2.1".to_string()),
+            path: "/hooks".to_string(),
+            reason: Some("Invalid \"token\"]".to_string()),
+        })
+    }
+
+    #[test]
+    fn test_config() {
+        let siem = SiemConfig::new(
+            "tls://siem.example.com",
+            "cef",
+            "authpriv",
+            "download=notice, auth_failure=err",
+            "with space",
+        )
+        .unwrap();
+        assert_eq!(siem.transport, Transport::Tls);
+        assert_eq!(siem.port, 6514);
+        assert_eq!(siem.facility, 10);
+        assert_eq!(siem.hostname, "-");
+        assert_eq!(
+            siem.severities,
+            Severities {
+                download: Severity::Notice,
+                auth_success: Severity::Notice,
+                auth_failure: Severity::Error,
+            }
+        );
+
+        assert_eq!(config("udp://siem:5140", "syslog").port, 5140);
+        assert!(SiemConfig::new("http://siem", "syslog", "local0", "", "h").is_err());
+        assert!(SiemConfig::new("udp://siem", "syslog", "24", "", "h").is_err());
+        assert!(SiemConfig::new("udp://siem", "syslog", "local0", "upload=info", "h").is_err());
+    }
+
+    #[test]
+    fn test_syslog_format() {
+        let config = config("udp://siem", "syslog");
+        let pid = std::process::id();
+        assert_eq!(
+            config.format_event(&failed_auth(), TIMESTAMP).unwrap(),
+            format!(
+                "<132>1 {} files.example.com hardwire {} auth [auth@32473 realm=\"webhook\" outcome=\"failure\" src=\"192.0.2.1\" path=\"/hooks\" reason=\"Invalid \\\"token\\\"\\]\"] Authentication failure on webhook",
+                TIMESTAMP, pid
+            )
+        );
+        assert!(config
+            .format_event(&download(), TIMESTAMP)
+            .unwrap()
+            .starts_with("<134>1 "));
+
+        let Event::DownloadProgress(mut partial) = download() else {
+            unreachable!()
+        };
+        partial.read_bytes = 4;
+        assert!(config
+            .format_event(&Event::DownloadProgress(partial), TIMESTAMP)
+            .is_none());
+    }
+
+    #[test]
+    fn test_cef_format() {
+        let config = config("udp://siem", "cef");
+        assert_eq!(
+            config.format_event(&download(), TIMESTAMP).unwrap(),
+            format!(
+                "<134>1 {} files.example.com hardwire {} download - CEF:0|Hardwire|hardwire|{}|download|File a=b.txt downloaded|1|fname=a\\=b.txt filePath=/data/dir/a\\=b.txt fsize=14 externalId=abc",
+                TIMESTAMP,
+                std::process::id(),
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert!(config
+            .format_event(&failed_auth(), TIMESTAMP)
+            .unwrap()
+            .ends_with("|auth_failure|Authentication failure on webhook|5|cs1Label=realm cs1=webhook outcome=failure src=192.0.2.1 request=/hooks reason=Invalid \"token\"]"));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_octet_counting() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let mut forwarder = Forwarder::new(config(&format!("tcp://127.0.0.1:{}", port), "syslog"))?;
+
+        forwarder.send("first").await?;
+        forwarder.send("second event").await?;
+        drop(forwarder);
+
+        let (mut socket, _) = listener.accept().await?;
+        let mut received = String::new();
+        socket.read_to_string(&mut received).await?;
+        assert_eq!(received, "5 first12 second event");
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+//! Text files of a share read in the browser.
+//!
+//! `GET /s/{share_id}/{token}/view` renders a log, a config file or a script with line numbers
+//! and its syntax highlighted, so recipients can read it without downloading it. Only files of up
+//! to [`MAX_VIEW_BYTES`] with a known text extension are viewed, binary content is refused. The
+//! highlighting is a small lexer of comments, strings, numbers and keywords per language, its
+//! classes are Tailwind's, which is why `tailwind.config.js` scans this file. Views are not
+//! recorded as downloads.
+
+use askama::Template;
+use axum::extract::{Path, Request, State};
+use axum::http::header::X_CONTENT_TYPE_OPTIONS;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+use tokio::io::AsyncReadExt;
+
+use crate::share_page::FileKind;
+use crate::{not_found, plugins, proxy, App};
+
+/// Larger files are only downloaded
+pub const MAX_VIEW_BYTES: u64 = 1_000_000;
+
+const COMMENT: &str = "text-neutral-400 italic";
+const STRING: &str = "text-emerald-300";
+const NUMBER: &str = "text-amber-300";
+const KEYWORD: &str = "text-sky-300 font-semibold";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    /// Shown as it is: text, logs, Markdown
+    Plain,
+    Rust,
+    /// C, C++, Java, C#, Kotlin, Swift
+    C,
+    Go,
+    /// JavaScript and TypeScript
+    JavaScript,
+    Python,
+    Shell,
+    Sql,
+    /// TOML, INI, YAML
+    Config,
+    Json,
+    /// HTML, XML, SVG
+    Markup,
+}
+
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    keywords: &'static [&'static str],
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "else",
+    "enum",
+    "extends",
+    "false",
+    "final",
+    "for",
+    "if",
+    "import",
+    "include",
+    "interface",
+    "new",
+    "null",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "struct",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typedef",
+    "void",
+    "while",
+];
+
+impl Language {
+    /// Language of a file from its `name`, `None` when it isn't viewed as text
+    pub fn from_name(name: &str) -> Option<Self> {
+        let extension = std::path::Path::new(name)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let language = match extension.as_deref() {
+            Some("rs") => Language::Rust,
+            Some("c" | "h" | "cc" | "cpp" | "hpp" | "java" | "kt" | "cs" | "swift") => Language::C,
+            Some("go") => Language::Go,
+            Some("js" | "mjs" | "cjs" | "jsx" | "ts" | "tsx") => Language::JavaScript,
+            Some("py") => Language::Python,
+            Some("sh" | "bash" | "zsh") => Language::Shell,
+            Some("sql") => Language::Sql,
+            Some("toml" | "ini" | "cfg" | "conf" | "yaml" | "yml" | "env" | "properties") => {
+                Language::Config
+            }
+            Some("json") => Language::Json,
+            Some("html" | "htm" | "xml" | "svg") => Language::Markup,
+            Some("txt" | "log" | "md" | "csv" | "tsv") => Language::Plain,
+            None if matches!(name, "Makefile" | "Dockerfile") => Language::Shell,
+            _ if FileKind::from_name(name) == FileKind::Text => Language::Plain,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    fn syntax(self) -> Option<Syntax> {
+        let syntax = match self {
+            Language::Plain => return None,
+            Language::Rust => Syntax {
+                line_comments: &["//"],
+                block_comment: Some(("/*", "*/")),
+                // Lifetimes start with a quote
+                quotes: &['"'],
+                keywords: &[
+                    "as", "async", "await", "break", "const", "continue", "crate", "else", "enum",
+                    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+                    "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct",
+                    "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+                ],
+            },
+            Language::C => Syntax {
+                line_comments: &["//"],
+                block_comment: Some(("/*", "*/")),
+                quotes: &['"', '\''],
+                keywords: C_KEYWORDS,
+            },
+            Language::Go => Syntax {
+                line_comments: &["//"],
+                block_comment: Some(("/*", "*/")),
+                quotes: &['"', '\'', '`'],
+                keywords: &[
+                    "break",
+                    "case",
+                    "chan",
+                    "const",
+                    "continue",
+                    "default",
+                    "defer",
+                    "else",
+                    "false",
+                    "for",
+                    "func",
+                    "go",
+                    "if",
+                    "import",
+                    "interface",
+                    "map",
+                    "nil",
+                    "package",
+                    "range",
+                    "return",
+                    "select",
+                    "struct",
+                    "switch",
+                    "true",
+                    "type",
+                    "var",
+                ],
+            },
+            Language::JavaScript => Syntax {
+                line_comments: &["//"],
+                block_comment: Some(("/*", "*/")),
+                quotes: &['"', '\'', '`'],
+                keywords: &[
+                    "async",
+                    "await",
+                    "break",
+                    "case",
+                    "catch",
+                    "class",
+                    "const",
+                    "continue",
+                    "default",
+                    "else",
+                    "export",
+                    "extends",
+                    "false",
+                    "for",
+                    "from",
+                    "function",
+                    "if",
+                    "import",
+                    "interface",
+                    "let",
+                    "new",
+                    "null",
+                    "of",
+                    "return",
+                    "switch",
+                    "this",
+                    "throw",
+                    "true",
+                    "try",
+                    "type",
+                    "undefined",
+                    "var",
+                    "while",
+                ],
+            },
+            Language::Python => Syntax {
+                line_comments: &["#"],
+                block_comment: None,
+                quotes: &['"', '\''],
+                keywords: &[
+                    "and", "as", "async", "await", "break", "class", "continue", "def", "elif",
+                    "else", "except", "False", "finally", "for", "from", "if", "import", "in",
+                    "is", "lambda", "None", "not", "or", "pass", "raise", "return", "self", "True",
+                    "try", "while", "with", "yield",
+                ],
+            },
+            Language::Shell => Syntax {
+                line_comments: &["#"],
+                block_comment: None,
+                quotes: &['"', '\''],
+                keywords: &[
+                    "case", "do", "done", "elif", "else", "esac", "exit", "export", "fi", "for",
+                    "function", "if", "in", "local", "return", "then", "until", "while",
+                ],
+            },
+            Language::Sql => Syntax {
+                line_comments: &["--"],
+                block_comment: Some(("/*", "*/")),
+                quotes: &['\''],
+                keywords: &[
+                    "and", "as", "by", "create", "delete", "desc", "from", "group", "insert",
+                    "into", "join", "key", "not", "null", "on", "or", "order", "primary", "select",
+                    "set", "table", "update", "values", "where",
+                ],
+            },
+            Language::Config => Syntax {
+                line_comments: &["#"],
+                block_comment: None,
+                quotes: &['"', '\''],
+                keywords: &["true", "false", "yes", "no", "on", "off", "null"],
+            },
+            Language::Json => Syntax {
+                line_comments: &[],
+                block_comment: None,
+                quotes: &['"'],
+                keywords: &["true", "false", "null"],
+            },
+            Language::Markup => Syntax {
+                line_comments: &[],
+                block_comment: Some(("<!--", "-->")),
+                // Apostrophes are common in the text
+                quotes: &['"'],
+                keywords: &[],
+            },
+        };
+        Some(syntax)
+    }
+}
+
+/// Whether the share page links the file to its view
+pub fn is_viewable(name: &str, size: Option<i64>) -> bool {
+    Language::from_name(name).is_some() && size.is_none_or(|size| size as u64 <= MAX_VIEW_BYTES)
+}
+
+fn escape(text: &str, html: &mut String) {
+    for c in text.chars() {
+        match c {
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '&' => html.push_str("&amp;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
+
+/// HTML of each line, tokens spanning several lines being closed at the end of each
+#[derive(Default)]
+struct Lines {
+    lines: Vec<String>,
+    current: String,
+}
+
+impl Lines {
+    fn push(&mut self, class: Option<&str>, text: &str) {
+        for (i, segment) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.lines.push(std::mem::take(&mut self.current));
+            }
+            match class {
+                Some(class) if !segment.is_empty() => {
+                    self.current
+                        .push_str(&format!("<span class=\"{}\">", class));
+                    escape(segment, &mut self.current);
+                    self.current.push_str("</span>");
+                }
+                _ => escape(segment, &mut self.current),
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if !self.current.is_empty() {
+            self.lines.push(self.current);
+        }
+        self.lines
+    }
+}
+
+/// Length of the string starting `rest`, with its quotes. Only backquoted strings span lines.
+fn string_len(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '\n' if quote != '`' => return i,
+            c if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+/// Escaped and highlighted HTML of each line of `source`
+pub fn highlight(source: &str, language: Language) -> Vec<String> {
+    let mut lines = Lines::default();
+    let Some(syntax) = language.syntax() else {
+        lines.push(None, source);
+        return lines.finish();
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut rest = source;
+    while let Some(first) = rest.chars().next() {
+        let (class, len) = if syntax.line_comments.iter().any(|p| rest.starts_with(p)) {
+            (Some(COMMENT), rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some((open, close)) = syntax
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let len = rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |end| open.len() + end + close.len());
+            (Some(COMMENT), len)
+        } else if syntax.quotes.contains(&first) {
+            (Some(STRING), string_len(rest, first))
+        } else if first.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !is_word(c) && c != '.')
+                .unwrap_or(rest.len());
+            (Some(NUMBER), len)
+        } else if is_word(first) {
+            let len = rest.find(|c: char| !is_word(c)).unwrap_or(rest.len());
+            let word = &rest[..len];
+            let keyword = if language == Language::Sql {
+                syntax.keywords.contains(&word.to_lowercase().as_str())
+            } else {
+                syntax.keywords.contains(&word)
+            };
+            (keyword.then_some(KEYWORD), len)
+        } else {
+            (None, first.len_utf8())
+        };
+        lines.push(class, &rest[..len]);
+        rest = &rest[len..];
+    }
+    lines.finish()
+}
+
+#[derive(Template)]
+#[template(path = "text_view.html")]
+struct TextView {
+    file_name: String,
+    download_href: String,
+    share_href: String,
+    lines: Vec<String>,
+    /// Why the content isn't shown, empty when it is
+    notice: String,
+}
+
+/// Render a shared text file, see the module documentation
+pub async fn view_file(
+    State(app_state): State<App>,
+    Path((share_id, token)): Path<(String, String)>,
+    request: Request,
+) -> Response {
+    let now = chrono::offset::Utc::now().timestamp();
+    let (file_id, file_path) = match sqlx::query!(
+        r#"SELECT files.id as "id!", path
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.token=$1 AND share_link_files.share_link_id=$2 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $3) AND share_links.deleted_at IS NULL"#,
+        token,
+        share_id,
+        now
+    )
+    .fetch_one(&app_state.db_reader)
+    .await
+    {
+        Ok(row) => (row.id, row.path),
+        Err(_) => return not_found().await.into_response(),
+    };
+    let file_name = std::path::Path::new(&file_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let Some(language) = Language::from_name(&file_name) else {
+        return not_found().await.into_response();
+    };
+    let mut download = plugins::DownloadRequest {
+        share_id: share_id.clone(),
+        file_id,
+        path: file_path.clone(),
+        client_ip: proxy::client_ip(request.extensions()),
+        download_name: file_name.clone(),
+    };
+    if app_state.plugins.before_download(&mut download).is_err() {
+        return (StatusCode::FORBIDDEN, Html("Download refused".to_string())).into_response();
+    }
+
+    let mut content = vec![];
+    let read = match tokio::fs::File::open(&file_path).await {
+        Ok(file) => {
+            file.take(MAX_VIEW_BYTES + 1)
+                .read_to_end(&mut content)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if read.is_err() {
+        return not_found().await.into_response();
+    }
+    let mut view = TextView {
+        file_name,
+        download_href: format!("/s/{}/{}", share_id, token),
+        share_href: format!("/s/{}", share_id),
+        lines: vec![],
+        notice: String::new(),
+    };
+    if content.len() as u64 > MAX_VIEW_BYTES {
+        view.notice = format!(
+            "This file is larger than {}, download it to read it.",
+            crate::top::format_bytes(MAX_VIEW_BYTES)
+        );
+    } else if content.contains(&0) {
+        view.notice = "This file is not text, download it to open it.".to_string();
+    } else {
+        view.lines = highlight(&String::from_utf8_lossy(&content), language);
+    }
+    match view.render() {
+        Ok(page) => (
+            [(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))],
+            Html(page),
+        )
+            .into_response(),
+        Err(e) => crate::error::AppError::Internal(e.into()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(Language::from_name("server.LOG"), Some(Language::Plain));
+        assert_eq!(Language::from_name("deploy.sh"), Some(Language::Shell));
+        assert_eq!(Language::from_name("Makefile"), Some(Language::Shell));
+        assert_eq!(Language::from_name("movie.mkv"), None);
+        assert!(is_viewable("app.yaml", Some(4096)));
+        assert!(!is_viewable("huge.log", Some(MAX_VIEW_BYTES as i64 + 1)));
+
+        let lines = highlight(
+            "fn main() {\n    /* a <b>\n    c */ let x = \"1\\\"\"; // 42\n}\n",
+            Language::Rust,
+        );
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            format!("<span class=\"{}\">fn</span> main() {{", KEYWORD)
+        );
+        assert_eq!(
+            lines[1],
+            format!("    <span class=\"{}\">/* a &lt;b&gt;</span>", COMMENT)
+        );
+        assert_eq!(
+            lines[2],
+            format!(
+                "<span class=\"{c}\">    c */</span> <span class=\"{k}\">let</span> x = \
+                 <span class=\"{s}\">&quot;1\\&quot;&quot;</span>; <span class=\"{c}\">// 42</span>",
+                c = COMMENT,
+                k = KEYWORD,
+                s = STRING
+            )
+        );
+
+        let lines = highlight("SELECT 1 FROM t; -- it's", Language::Sql);
+        assert_eq!(
+            lines[0],
+            format!(
+                "<span class=\"{k}\">SELECT</span> <span class=\"{n}\">1</span> \
+                 <span class=\"{k}\">FROM</span> t; <span class=\"{c}\">-- it&#39;s</span>",
+                k = KEYWORD,
+                n = NUMBER,
+                c = COMMENT
+            )
+        );
+        assert_eq!(
+            highlight("<script>alert(1)</script>", Language::Plain),
+            ["&lt;script&gt;alert(1)&lt;/script&gt;"]
+        );
+    }
+}
@@ -0,0 +1,90 @@
+//! Download rate limit of the shares created with a capped profile, see [`crate::share_profiles`].
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::Instant;
+
+/// Reads per second at the limit, smaller reads smooth the rate
+const READS_PER_SEC: u64 = 10;
+
+/// Reader delivering at most `bytes_per_sec` on average, without limit when none is given
+pub struct Throttled<R> {
+    inner: R,
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    read: u64,
+    delay: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, bytes_per_sec: Option<u64>) -> Self {
+        Throttled {
+            inner,
+            bytes_per_sec: bytes_per_sec.filter(|rate| *rate > 0),
+            started: Instant::now(),
+            read: 0,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Throttled<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let Some(rate) = self.bytes_per_sec else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+        // Time at which the bytes already read are due
+        let due = self.started + Duration::from_secs_f64(self.read as f64 / rate as f64);
+        if due > Instant::now() {
+            let delay = self
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due)));
+            ready!(delay.as_mut().poll(cx));
+        }
+        self.delay = None;
+
+        let chunk = (rate / READS_PER_SEC).max(1) as usize;
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(buf.remaining().min(chunk)));
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+        let n = limited.filled().len();
+        buf.advance(n);
+        self.read += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_throttled() -> io::Result<()> {
+        let data = vec![7u8; 3000];
+        let started = Instant::now();
+        let mut read = vec![];
+        Throttled::new(&data[..], Some(10_000))
+            .read_to_end(&mut read)
+            .await?;
+        assert_eq!(read, data);
+        // 1000 bytes per read, the end of the data is reached once the 3000 bytes are due
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        let started = Instant::now();
+        let mut read = vec![];
+        Throttled::new(&data[..], None)
+            .read_to_end(&mut read)
+            .await?;
+        assert_eq!(read.len(), 3000);
+        assert!(started.elapsed() < Duration::from_millis(200));
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+//! Thumbnails of the shared images.
+//!
+//! `GET /s/{share_id}/{token}/thumb?w=320` serves a JPEG of the image scaled down to the width,
+//! rounded up to one of [`WIDTHS`] so a few sizes per image are kept. ffmpeg (`HARDWIRE_FFMPEG_PATH`)
+//! generates it on the first request into `data_dir/thumbnails`, where it stays until the image
+//! changes or the `CleanupArtifacts` task deletes it with the share. The `GenerateThumbnails` task
+//! generates those of a whole share ahead of its first visitors. The share page shows the
+//! thumbnails, and falls back to the image itself when one can't be generated.
+
+use anyhow::{anyhow, Result};
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::header::CACHE_CONTROL;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+use std::path::{Path as FsPath, PathBuf};
+use tokio::sync::Semaphore;
+use tower_http::services::ServeFile;
+
+use crate::share_page::FileKind;
+use crate::worker::artifacts;
+use crate::{not_found, plugins, proxy, App, ServerConfig};
+
+pub const THUMBNAILS_DIR: &str = "thumbnails";
+/// Widths generated, in pixels
+pub const WIDTHS: [u32; 4] = [160, 320, 640, 1280];
+pub const DEFAULT_WIDTH: u32 = 320;
+const TASK_TYPE: &str = "GenerateThumbnails";
+
+/// ffmpeg runs started at once, the others wait
+static GENERATING: Semaphore = Semaphore::const_new(2);
+
+/// The smallest of [`WIDTHS`] at least `requested`, the largest above them
+pub fn width(requested: Option<u32>) -> u32 {
+    let requested = requested.unwrap_or(DEFAULT_WIDTH);
+    WIDTHS
+        .into_iter()
+        .find(|width| *width >= requested)
+        .unwrap_or(WIDTHS[WIDTHS.len() - 1])
+}
+
+pub fn thumbnail_path(data_dir: &FsPath, file_id: i64, width: u32) -> PathBuf {
+    data_dir
+        .join(THUMBNAILS_DIR)
+        .join(format!("{}-{}.jpg", file_id, width))
+}
+
+/// Generated after the last change of `source`
+fn is_fresh(thumbnail: &FsPath, source: &FsPath) -> bool {
+    let modified = |path: &FsPath| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(thumbnail), modified(source)) {
+        (Some(thumbnail), Some(source)) => thumbnail >= source,
+        _ => false,
+    }
+}
+
+/// The thumbnail of the image `source`, the file `file_id` of `share_id`, generated unless it is
+/// up to date
+pub async fn get_or_create(
+    db: &SqlitePool,
+    config: &ServerConfig,
+    share_id: &str,
+    file_id: i64,
+    source: &FsPath,
+    width: u32,
+) -> Result<PathBuf> {
+    let path = thumbnail_path(&config.data_dir, file_id, width);
+    if is_fresh(&path, source) {
+        return Ok(path);
+    }
+    let _permit = GENERATING.acquire().await?;
+    // Generated by another request while this one waited
+    if is_fresh(&path, source) {
+        return Ok(path);
+    }
+    tokio::fs::create_dir_all(config.data_dir.join(THUMBNAILS_DIR)).await?;
+    // Renamed once complete, requests never serve a partial file
+    let partial = path.with_extension(format!("{}.jpg", nanoid::nanoid!(6)));
+    let result = run_ffmpeg(&config.ffmpeg_path, source, &partial, width).await;
+    if let Err(e) = result.and(tokio::fs::rename(&partial, &path).await.map_err(Into::into)) {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+    artifacts::record(db, &path, TASK_TYPE, Some(share_id)).await?;
+    Ok(path)
+}
+
+/// Scale `source` down to `width`, smaller images are not upscaled
+async fn run_ffmpeg(ffmpeg: &str, source: &FsPath, output: &FsPath, width: u32) -> Result<()> {
+    let result = tokio::process::Command::new(ffmpeg)
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(source)
+        .args(["-vf", &format!("scale='min({},iw)':-2", width)])
+        .args([
+            "-frames:v",
+            "1",
+            "-q:v",
+            "4",
+            "-f",
+            "image2",
+            "-c:v",
+            "mjpeg",
+        ])
+        .arg(output)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {}: {}", ffmpeg, e))?;
+    if !result.status.success() {
+        anyhow::bail!(
+            "ffmpeg failed ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbQuery {
+    /// Width in pixels, see [`width`]
+    w: Option<u32>,
+}
+
+/// Serve the thumbnail of a shared image, see the module documentation
+pub async fn thumb_file(
+    State(app_state): State<App>,
+    Path((share_id, token)): Path<(String, String)>,
+    Query(query): Query<ThumbQuery>,
+    request: Request,
+) -> Response {
+    let now = chrono::offset::Utc::now().timestamp();
+    let (file_id, file_path) = match sqlx::query!(
+        r#"SELECT files.id as "id!", path
+        FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+        JOIN share_links ON share_links.id=share_link_files.share_link_id
+        WHERE share_link_files.token=$1 AND share_link_files.share_link_id=$2 AND NOT share_links.encrypted
+        AND (share_links.expiration < 0 OR share_links.expiration > $3) AND share_links.deleted_at IS NULL"#,
+        token,
+        share_id,
+        now
+    )
+    .fetch_one(&app_state.db_reader)
+    .await
+    {
+        Ok(row) => (row.id, row.path),
+        Err(_) => return not_found().await.into_response(),
+    };
+    let file_name = FsPath::new(&file_path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    if FileKind::from_name(&file_name) != FileKind::Image {
+        return not_found().await.into_response();
+    }
+    let mut download = plugins::DownloadRequest {
+        share_id: share_id.clone(),
+        file_id,
+        path: file_path.clone(),
+        client_ip: proxy::client_ip(request.extensions()),
+        download_name: file_name,
+    };
+    if app_state.plugins.before_download(&mut download).is_err() {
+        return (StatusCode::FORBIDDEN, Html("Download refused".to_string())).into_response();
+    }
+
+    let width = width(query.w);
+    let source = PathBuf::from(&file_path);
+    let thumbnail = match get_or_create(
+        &app_state.db_pool,
+        &app_state.config,
+        &share_id,
+        file_id,
+        &source,
+        width,
+    )
+    .await
+    {
+        Ok(thumbnail) => thumbnail,
+        Err(e) => {
+            tracing::warn!("No thumbnail of file {}: {}", file_id, e);
+            let inline = format!("/s/{}/{}/inline", share_id, token);
+            return Redirect::temporary(&inline).into_response();
+        }
+    };
+    match ServeFile::new(thumbnail).try_call(request).await {
+        Ok(response) => {
+            let mut response = response.map(Body::new).into_response();
+            response.headers_mut().insert(
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=86400"),
+            );
+            response
+        }
+        Err(_) => not_found().await.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_width() {
+        assert_eq!(width(None), DEFAULT_WIDTH);
+        assert_eq!(width(Some(1)), 160);
+        assert_eq!(width(Some(320)), 320);
+        assert_eq!(width(Some(321)), 640);
+        assert_eq!(width(Some(10_000)), 1280);
+        assert_eq!(
+            thumbnail_path(FsPath::new("/data"), 42, 320),
+            FsPath::new("/data/thumbnails/42-320.jpg")
+        );
+    }
+
+    #[test]
+    fn test_is_fresh() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("photo.jpg");
+        let thumbnail = dir.path().join("thumb.jpg");
+        std::fs::write(&source, "image")?;
+        assert!(!is_fresh(&thumbnail, &source));
+        std::fs::write(&thumbnail, "thumbnail")?;
+        assert!(is_fresh(&thumbnail, &source));
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(&source)?
+            .set_modified(later)?;
+        assert!(!is_fresh(&thumbnail, &source));
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+//! Let's Encrypt certificates, built with the `acme` feature.
+//!
+//! The ACME account and the certificate are kept in `{data_dir}/acme`. A certificate is ordered
+//! when there is none and renewed once 60 days old, a month before Let's Encrypt certificates
+//! expire. The domains are validated by HTTP-01 challenges, answered on `HARDWIRE_HTTP_PORT` which
+//! must be reachable as port 80 of every domain.
+
+use anyhow::{anyhow, Context, Result};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{certified_key, CertStore, Challenges};
+
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 3600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    /// Email the ACME server warns about expiring certificates
+    pub contact: Option<String>,
+    pub directory_url: String,
+    pub cache_dir: PathBuf,
+}
+
+impl AcmeConfig {
+    /// `directory` is the URL of the ACME server, Let's Encrypt by default, `staging` being the
+    /// staging server of Let's Encrypt
+    pub fn new(
+        domains: &str,
+        contact: Option<String>,
+        directory: Option<String>,
+        data_dir: &Path,
+    ) -> Result<Self> {
+        let domains: Vec<String> = domains
+            .split(',')
+            .map(|domain| domain.trim().to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        if domains.is_empty() {
+            anyhow::bail!("No ACME domain");
+        }
+        // HTTP-01 can't validate wildcards
+        if let Some(domain) = domains.iter().find(|domain| domain.contains('*')) {
+            anyhow::bail!("Wildcard domain {} requires a DNS-01 challenge", domain);
+        }
+        let directory_url = match directory.as_deref() {
+            None => LetsEncrypt::Production.url().to_string(),
+            Some("staging") => LetsEncrypt::Staging.url().to_string(),
+            Some(url) => url.to_string(),
+        };
+        Ok(AcmeConfig {
+            domains,
+            contact,
+            directory_url,
+            cache_dir: data_dir.join("acme"),
+        })
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.cache_dir.join("cert.pem")
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.cache_dir.join("key.pem")
+    }
+}
+
+/// Serve the cached certificate, then order one whenever it is missing or due for renewal
+pub fn spawn(config: AcmeConfig, store: Arc<CertStore>, challenges: Challenges) {
+    tokio::spawn(async move {
+        if let Err(e) = load(&config, &store).await {
+            tracing::warn!("No cached certificate for {:?}: {}", config.domains, e);
+        }
+        loop {
+            let age = tokio::fs::metadata(config.cert_path())
+                .await
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default());
+            let due = store.is_empty() || age.map_or(true, |age| age >= RENEW_AFTER);
+            let wait = if !due {
+                CHECK_INTERVAL
+            } else {
+                match renew(&config, &store, &challenges).await {
+                    Ok(()) => {
+                        tracing::info!("Obtained a certificate for {:?}", config.domains);
+                        CHECK_INTERVAL
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to obtain a certificate for {:?}: {:#}",
+                            config.domains,
+                            e
+                        );
+                        RETRY_INTERVAL
+                    }
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+async fn load(config: &AcmeConfig, store: &CertStore) -> Result<()> {
+    let cert_pem = tokio::fs::read(config.cert_path()).await?;
+    let key_pem = tokio::fs::read(config.key_path()).await?;
+    store.set(certified_key(&cert_pem, &key_pem)?);
+    Ok(())
+}
+
+async fn renew(config: &AcmeConfig, store: &CertStore, challenges: &Challenges) -> Result<()> {
+    tokio::fs::create_dir_all(&config.cache_dir).await?;
+    let account = account(config).await?;
+    let (cert_pem, key_pem) = order(config, &account, challenges).await?;
+    store.set(certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?);
+    write_private(&config.key_path(), key_pem.as_bytes()).await?;
+    tokio::fs::write(config.cert_path(), cert_pem).await?;
+    Ok(())
+}
+
+/// Account of the cached credentials, registered on first use
+async fn account(config: &AcmeConfig) -> Result<Account> {
+    let path = config.cache_dir.join("account.json");
+    if let Ok(json) = tokio::fs::read(&path).await {
+        let credentials: AccountCredentials = serde_json::from_slice(&json)
+            .with_context(|| format!("Invalid ACME account {}", path.display()))?;
+        return Ok(Account::builder()?.from_credentials(credentials).await?);
+    }
+    let contact: Vec<String> = config
+        .contact
+        .iter()
+        .map(|email| format!("mailto:{}", email))
+        .collect();
+    let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+    let (account, credentials) = Account::builder()?
+        .create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            config.directory_url.clone(),
+            None,
+        )
+        .await?;
+    write_private(&path, &serde_json::to_vec(&credentials)?).await?;
+    Ok(account)
+}
+
+/// Certificate chain and private key of a new order, the tokens of its challenges being served
+/// until it completes
+async fn order(
+    config: &AcmeConfig,
+    account: &Account,
+    challenges: &Challenges,
+) -> Result<(String, String)> {
+    let identifiers: Vec<Identifier> = config
+        .domains
+        .iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect();
+    let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+    let mut tokens = vec![];
+    let result = async {
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            if authorization.status == AuthorizationStatus::Valid {
+                continue;
+            }
+            let mut challenge = authorization
+                .challenge(ChallengeType::Http01)
+                .ok_or_else(|| anyhow!("No HTTP-01 challenge offered"))?;
+            challenges.write().unwrap().insert(
+                challenge.token.clone(),
+                challenge.key_authorization().as_str().to_string(),
+            );
+            tokens.push(challenge.token.clone());
+            challenge.set_ready().await?;
+        }
+
+        let status = order.poll_ready(&RetryPolicy::default()).await?;
+        if status != OrderStatus::Ready {
+            anyhow::bail!("Order {:?}", status);
+        }
+        let key_pem = order.finalize().await?;
+        let cert_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+        Ok((cert_pem, key_pem))
+    }
+    .await;
+
+    let mut challenges = challenges.write().unwrap();
+    for token in tokens {
+        challenges.remove(&token);
+    }
+    result
+}
+
+/// Write a file only readable by the server user
+async fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    file.write_all(contents).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = AcmeConfig::new(
+            " Files.example.com,,www.example.com",
+            None,
+            None,
+            Path::new("/srv"),
+        )
+        .unwrap();
+        assert_eq!(config.domains, ["files.example.com", "www.example.com"]);
+        assert_eq!(config.directory_url, LetsEncrypt::Production.url());
+        assert_eq!(config.cert_path(), Path::new("/srv/acme/cert.pem"));
+        let config = AcmeConfig::new(
+            "files.example.com",
+            None,
+            Some("staging".to_string()),
+            Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(config.directory_url, LetsEncrypt::Staging.url());
+        assert!(AcmeConfig::new(" ,", None, None, Path::new(".")).is_err());
+        assert!(AcmeConfig::new("*.example.com", None, None, Path::new(".")).is_err());
+    }
+}
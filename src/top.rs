@@ -0,0 +1,314 @@
+//! `hardwire top`: live view of a running server.
+//!
+//! The server sums up its state at `GET /admin/api/v1/status`: downloads in progress, tasks per
+//! status, the last failures, the free space of its disks and the space saved by the
+//! deduplication of the shared files. `hardwire top` polls it and redraws
+//! the terminal, speeds are measured between two polls once a download has been seen twice.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+use crate::bandwidth::ServedDownload;
+use crate::file_dedup::StorageStats;
+use crate::remote::Remote;
+use crate::worker::history::TaskSummary;
+
+/// Failures listed by the status
+pub const RECENT_ERRORS: i64 = 5;
+const STATUS_PATH: &str = "/status";
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct DiskUsage {
+    /// What the disk holds: `base_path` or `data_dir`
+    pub name: String,
+    pub path: String,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ServerStatus {
+    pub downloads: Vec<ServedDownload>,
+    pub tasks: BTreeMap<String, i64>,
+    pub recent_errors: Vec<TaskSummary>,
+    pub disks: Vec<DiskUsage>,
+    #[serde(default)]
+    pub storage: StorageStats,
+}
+
+/// Size and free space of the file system holding `path`
+#[cfg(unix)]
+pub fn disk_usage(name: &str, path: &Path) -> Option<DiskUsage> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    let fragment_size = stat.fragment_size() as u64;
+    Some(DiskUsage {
+        name: name.to_string(),
+        path: path.to_string_lossy().into_owned(),
+        total_bytes: stat.blocks() as u64 * fragment_size,
+        available_bytes: stat.blocks_available() as u64 * fragment_size,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn disk_usage(_name: &str, _path: &Path) -> Option<DiskUsage> {
+    None
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(secs: i64) -> String {
+    let secs = secs.max(0);
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Keep the end of `text`, the most telling part of a path
+fn tail(text: &str, width: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= width {
+        text.to_string()
+    } else {
+        let kept: String = chars[chars.len() - (width - 1)..].iter().collect();
+        format!("…{}", kept)
+    }
+}
+
+fn head(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        text.to_string()
+    } else {
+        let kept: String = text.chars().take(width - 1).collect();
+        format!("{}…", kept)
+    }
+}
+
+/// Text of one refresh, `speeds` overrides the average speed of the downloads by id
+pub fn render(status: &ServerStatus, speeds: &HashMap<u64, u64>, now: i64, width: usize) -> String {
+    let width = width.max(60);
+    let mut out = String::new();
+
+    out.push_str(&format!("DOWNLOADS ({} active)\n", status.downloads.len()));
+    if status.downloads.is_empty() {
+        out.push_str("  none\n");
+    } else {
+        out.push_str(&format!(
+            "  {:>10}  {:>4}  {:>19}  {:>8}  {:<15}  FILE\n",
+            "SPEED", "DONE", "TRANSFERRED", "ELAPSED", "CLIENT"
+        ));
+        for download in &status.downloads {
+            let speed = speeds
+                .get(&download.id)
+                .copied()
+                .unwrap_or(download.bytes_per_sec);
+            let done = (download.read_bytes * 100)
+                .checked_div(download.total_bytes)
+                .unwrap_or(100);
+            let line = format!(
+                "  {:>8}/s  {:>3}%  {:>19}  {:>8}  {:<15}  ",
+                format_bytes(speed),
+                done,
+                format!(
+                    "{} / {}",
+                    format_bytes(download.read_bytes),
+                    format_bytes(download.total_bytes)
+                ),
+                format_duration(now - download.started_at),
+                download.client_ip.as_deref().unwrap_or("-"),
+            );
+            let room = width.saturating_sub(line.chars().count()).max(10);
+            out.push_str(&format!("{}{}\n", line, tail(&download.file_path, room)));
+        }
+    }
+
+    out.push_str("\nTASKS ");
+    for status_name in ["pending", "running", "completed", "failed"] {
+        out.push_str(&format!(
+            " {} {}",
+            status_name,
+            status.tasks.get(status_name).copied().unwrap_or(0)
+        ));
+    }
+    out.push('\n');
+
+    out.push_str("\nRECENT ERRORS\n");
+    if status.recent_errors.is_empty() {
+        out.push_str("  none\n");
+    }
+    for task in &status.recent_errors {
+        let finished = task
+            .finished_at
+            .and_then(|at| chrono::DateTime::from_timestamp(at, 0))
+            .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let line = format!(
+            "  {}  {:<16}  {}  ",
+            finished,
+            task.task_type,
+            head(&task.id, 8)
+        );
+        let room = width.saturating_sub(line.chars().count()).max(10);
+        out.push_str(&format!(
+            "{}{}\n",
+            line,
+            head(task.error.as_deref().unwrap_or("-"), room)
+        ));
+    }
+
+    out.push_str("\nDISKS\n");
+    for disk in &status.disks {
+        let used = (disk.total_bytes - disk.available_bytes.min(disk.total_bytes)) * 100;
+        out.push_str(&format!(
+            "  {:<9}  {:>9} free of {:>9} ({:>3}% used)  {}\n",
+            disk.name,
+            format_bytes(disk.available_bytes),
+            format_bytes(disk.total_bytes),
+            used.checked_div(disk.total_bytes).unwrap_or(0),
+            disk.path
+        ));
+    }
+    let storage = &status.storage;
+    out.push_str(&format!(
+        "  shared     {:>9} in {} files, {} saved by {} links\n",
+        format_bytes(storage.stored_bytes.max(0) as u64),
+        storage.files,
+        format_bytes(storage.saved_bytes.max(0) as u64),
+        storage.links
+    ));
+    out
+}
+
+/// Redraw the status of the server every `interval` until Ctrl-C
+pub async fn run(remote: &Remote, interval: Duration) -> Result<()> {
+    let width = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(120);
+    // Bytes read per download at the previous poll
+    let mut previous: Option<(Instant, HashMap<u64, u64>)> = None;
+
+    loop {
+        let polled_at = Instant::now();
+        let body = match remote.get::<ServerStatus>(STATUS_PATH).await {
+            Ok(status) => render_frame(status, &mut previous, polled_at, width),
+            Err(e) => format!("{:#}\n", e),
+        };
+
+        // Clear the screen and go back to the top left corner
+        print!(
+            "\x1b[2J\x1b[H{} {} (every {}s, Ctrl-C to quit)\n\n{}",
+            remote.url(),
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            interval.as_secs(),
+            body
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+fn render_frame(
+    status: ServerStatus,
+    previous: &mut Option<(Instant, HashMap<u64, u64>)>,
+    polled_at: Instant,
+    width: usize,
+) -> String {
+    let mut speeds = HashMap::new();
+    if let Some((previous_at, previous_bytes)) = previous.as_ref() {
+        let elapsed = polled_at.duration_since(*previous_at).as_secs_f64();
+        for download in &status.downloads {
+            if let Some(bytes) = previous_bytes.get(&download.id) {
+                let read = download.read_bytes.saturating_sub(*bytes);
+                speeds.insert(download.id, (read as f64 / elapsed.max(0.001)) as u64);
+            }
+        }
+    }
+    *previous = Some((
+        polled_at,
+        status
+            .downloads
+            .iter()
+            .map(|download| (download.id, download.read_bytes))
+            .collect(),
+    ));
+    render(
+        &status,
+        &speeds,
+        chrono::offset::Utc::now().timestamp(),
+        width,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::TaskStatus;
+
+    #[test]
+    fn test_render() {
+        let status = ServerStatus {
+            downloads: vec![ServedDownload {
+                id: 7,
+                file_path: "/mnt/videos/holidays.mkv".to_string(),
+                client_ip: Some("192.0.2.1".to_string()),
+                started_at: 1_000,
+                read_bytes: 250_000_000,
+                total_bytes: 1_000_000_000,
+                bytes_per_sec: 2_500_000,
+            }],
+            tasks: BTreeMap::from([("pending".to_string(), 3), ("failed".to_string(), 1)]),
+            recent_errors: vec![TaskSummary {
+                id: "0b9e7c1a-3f1d".to_string(),
+                task_type: "CreateArchive".to_string(),
+                status: TaskStatus::Failed,
+                created_at: 900,
+                started_at: Some(900),
+                finished_at: Some(950),
+                error: Some("No space left on device".to_string()),
+            }],
+            disks: vec![DiskUsage {
+                name: "base_path".to_string(),
+                path: "/mnt".to_string(),
+                total_bytes: 4_000_000_000_000,
+                available_bytes: 1_000_000_000_000,
+            }],
+            storage: StorageStats {
+                files: 2,
+                links: 3,
+                stored_bytes: 3_000_000_000,
+                shared_bytes: 4_000_000_000,
+                saved_bytes: 1_000_000_000,
+            },
+        };
+
+        let text = render(&status, &HashMap::from([(7, 12_300_000)]), 1_100, 120);
+        assert!(text.contains("DOWNLOADS (1 active)"));
+        assert!(text.contains("12.3 MB/s   25%    250.0 MB / 1.0 GB  00:01:40  192.0.2.1"));
+        assert!(text.contains("/mnt/videos/holidays.mkv"));
+        assert!(text.contains("pending 3 running 0 completed 0 failed 1"));
+        assert!(text.contains("CreateArchive     0b9e7c1…  No space left on device"));
+        assert!(text.contains("1.0 TB free of    4.0 TB ( 75% used)  /mnt"));
+        assert!(text.contains("shared        3.0 GB in 2 files, 1.0 GB saved by 3 links"));
+
+        let text = render(&status, &HashMap::new(), 1_100, 60);
+        assert!(text.contains("2.5 MB/s"));
+        assert!(text.contains("…"));
+    }
+}
@@ -0,0 +1,1199 @@
+//! Resumable uploads with the tus protocol, see <https://tus.io/protocols/resumable-upload>.
+//!
+//! `POST /admin/api/v1/uploads` creates an upload of `Upload-Length` bytes and answers its URL,
+//! `PATCH` appends bytes at `Upload-Offset` and `HEAD` tells how many bytes the server has, so an
+//! interrupted upload resumes where it stopped instead of starting over. The creation, expiration,
+//! checksum and termination extensions are supported.
+//!
+//! The body of each PATCH is kept as a chunk in `HARDWIRE_DATA_DIR/tus/<upload_id>/`. Once every
+//! byte is received, an `AssembleUpload` task concatenates the chunks into
+//! `HARDWIRE_DATA_DIR/staging/<upload_id>/<filename>`, out of the share roots, and checks the
+//! SHA-256 declared by the `sha256` metadata. Once the scanners found it clean, the file is moved to
+//! `HARDWIRE_DATA_DIR/uploads/<upload_id>/<filename>`, a share root, and added to the share of the
+//! `share_id` metadata, or to a new share. Uploads idle for `HARDWIRE_UPLOAD_EXPIRY_HOURS` are deleted by [`Uploads::spawn_cleanup`].
+
+use anyhow::{Context, Result};
+use axum::body::{Body, Bytes};
+use axum::extract::{OriginalUri, Path, Request, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::Engine;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::digest::DynDigest;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path as FsPath, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use crate::audit::{self, Actor};
+use crate::disk_space;
+use crate::error::{AppError, AppResult};
+use crate::virus_scan::{ScanResult, ScanStatus};
+use crate::worker::{AssembleUploadInput, TaskInput};
+use crate::App;
+
+pub const TUS_VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,expiration,checksum,termination";
+const CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+/// Status of a PATCH whose body does not match its `Upload-Checksum`
+const CHECKSUM_MISMATCH: u16 = 460;
+/// Directory of `HARDWIRE_DATA_DIR` the complete uploads are moved to
+pub const COMPLETE_DIR: &str = "uploads";
+
+pub const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+pub const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+pub const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+pub const TUS_CHECKSUM_ALGORITHM: HeaderName = HeaderName::from_static("tus-checksum-algorithm");
+pub const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+pub const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+pub const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+pub const UPLOAD_CHECKSUM: HeaderName = HeaderName::from_static("upload-checksum");
+pub const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+const UPLOAD_DEFER_LENGTH: HeaderName = HeaderName::from_static("upload-defer-length");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Upload {
+    pub id: String,
+    /// Bytes of the whole file
+    pub length: u64,
+    /// `Upload-Metadata` as sent on creation
+    pub metadata: Option<String>,
+    pub filename: String,
+    /// Lowercase hex SHA-256 of the file declared by the client, checked once assembled, or
+    /// computed then
+    pub sha256: Option<String>,
+    /// Share the file is added to once assembled, a new share when unset
+    pub share_id: Option<String>,
+    pub created_at: i64,
+    /// Where the chunks were assembled
+    #[schema(value_type = Option<String>)]
+    pub path: Option<PathBuf>,
+    /// Scan of the assembled file, when scanners are configured
+    #[serde(default)]
+    pub scan: Option<ScanResult>,
+}
+
+/// An upload and the bytes received so far
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UploadStatus {
+    #[serde(flatten)]
+    pub upload: Upload,
+    pub offset: u64,
+}
+
+/// An upload whose chunks were concatenated
+#[derive(Debug)]
+pub struct Assembled {
+    pub upload: Upload,
+    pub path: PathBuf,
+    /// Lowercase hex SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Uploads of the server, in progress and complete
+#[derive(Debug, Clone)]
+pub struct Uploads {
+    /// Directory of chunks and `.json` description of each upload
+    partial_dir: PathBuf,
+    /// One directory per assembled upload, until it is published
+    staging_dir: PathBuf,
+    /// One directory per published upload
+    complete_dir: PathBuf,
+    /// Infected files, out of the share roots
+    quarantine_dir: PathBuf,
+    /// Idle time after which an upload is deleted
+    expiry: Duration,
+    /// Bytes left free on the disk, see [`disk_space`]
+    reserve: u64,
+    /// Uploads receiving a PATCH or being assembled, which are not given another one
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Why a PATCH was refused
+#[derive(Debug)]
+pub enum PatchError {
+    Missing,
+    /// Another PATCH of the upload is in progress
+    Busy,
+    /// `Upload-Offset` is not the offset of the upload, or the upload is complete
+    Offset(u64),
+    /// The body goes beyond `Upload-Length`
+    TooLong,
+    Checksum,
+    /// The client went away, the bytes received are kept when there was no checksum
+    Interrupted(String),
+    Io(anyhow::Error),
+}
+
+impl From<std::io::Error> for PatchError {
+    fn from(e: std::io::Error) -> Self {
+        PatchError::Io(e.into())
+    }
+}
+
+impl From<anyhow::Error> for PatchError {
+    fn from(e: anyhow::Error) -> Self {
+        PatchError::Io(e)
+    }
+}
+
+impl IntoResponse for PatchError {
+    fn into_response(self) -> Response {
+        match self {
+            PatchError::Missing => AppError::NotFound("No such upload".to_string()).into_response(),
+            PatchError::Busy => {
+                AppError::Conflict("The upload is receiving another request".to_string())
+                    .into_response()
+            }
+            PatchError::Offset(offset) => {
+                AppError::Conflict(format!("Upload-Offset must be {}", offset)).into_response()
+            }
+            PatchError::TooLong => {
+                AppError::BadRequest("The body goes beyond Upload-Length".to_string())
+                    .into_response()
+            }
+            PatchError::Checksum => with_status(
+                AppError::BadRequest("Upload-Checksum does not match the body".to_string()),
+                CHECKSUM_MISMATCH,
+            ),
+            PatchError::Interrupted(e) => {
+                AppError::BadRequest(format!("Failed to read the body: {}", e)).into_response()
+            }
+            PatchError::Io(e) => AppError::Internal(e).into_response(),
+        }
+    }
+}
+
+/// The response of `error` with the status the protocol asks for
+fn with_status(error: AppError, status: u16) -> Response {
+    let mut response = error.into_response();
+    *response.status_mut() = StatusCode::from_u16(status).unwrap();
+    response
+}
+
+/// Marks an upload as receiving a PATCH until dropped
+struct BusyGuard {
+    busy: Arc<Mutex<HashSet<String>>>,
+    id: String,
+}
+
+impl BusyGuard {
+    fn take(busy: &Arc<Mutex<HashSet<String>>>, id: &str) -> Option<Self> {
+        busy.lock()
+            .unwrap()
+            .insert(id.to_string())
+            .then(|| BusyGuard {
+                busy: Arc::clone(busy),
+                id: id.to_string(),
+            })
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// `Upload-Checksum` of a PATCH: algorithm and digest of its body
+#[derive(Debug)]
+pub struct Checksum {
+    algorithm: String,
+    digest: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn parse(header: &str) -> AppResult<Self> {
+        let invalid = || AppError::BadRequest("Invalid Upload-Checksum".to_string());
+        let (algorithm, digest) = header.trim().split_once(' ').ok_or_else(invalid)?;
+        if !CHECKSUM_ALGORITHMS
+            .split(',')
+            .any(|known| known == algorithm)
+        {
+            return Err(AppError::BadRequest(format!(
+                "Unsupported checksum algorithm {}, expected one of {}",
+                algorithm, CHECKSUM_ALGORITHMS
+            )));
+        }
+        let digest = base64::engine::general_purpose::STANDARD
+            .decode(digest.trim())
+            .map_err(|_| invalid())?;
+        Ok(Checksum {
+            algorithm: algorithm.to_string(),
+            digest,
+        })
+    }
+
+    fn hasher(&self) -> Box<dyn DynDigest + Send> {
+        match self.algorithm.as_str() {
+            "sha1" => Box::new(sha1::Sha1::default()),
+            _ => Box::new(sha2::Sha256::default()),
+        }
+    }
+}
+
+/// Decoded pairs of `Upload-Metadata`, `key base64,key2 base64` where values are optional
+pub fn parse_metadata(header: &str) -> AppResult<BTreeMap<String, String>> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = base64::engine::general_purpose::STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok())
+                .ok_or_else(|| {
+                    AppError::BadRequest(format!("Invalid Upload-Metadata value of {}", key))
+                })?;
+            Ok((key.to_string(), value))
+        })
+        .collect()
+}
+
+/// Name the file of an upload is stored with: the last component of the name sent by the client
+fn file_name(metadata: &BTreeMap<String, String>) -> String {
+    let name = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))
+        .map(|name| name.replace('\\', "/"))
+        .unwrap_or_default();
+    let name: String = name
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(200)
+        .collect();
+    match name.trim() {
+        "" | "." | ".." => "upload".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// The `sha256` metadata, 64 hex digits
+fn declared_sha256(metadata: &BTreeMap<String, String>) -> AppResult<Option<String>> {
+    let Some(sha256) = metadata.get("sha256") else {
+        return Ok(None);
+    };
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AppError::BadRequest(
+            "The sha256 metadata must be 64 hex digits".to_string(),
+        ));
+    }
+    Ok(Some(sha256.to_ascii_lowercase()))
+}
+
+/// Write the chunks one after the other to `output`, the hex SHA-256 of the whole
+fn concatenate(chunks: &[PathBuf], output: &FsPath, processed: &AtomicU64) -> Result<String> {
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut hasher = sha2::Sha256::default();
+    let mut buffer = vec![0; 1 << 20];
+    for chunk in chunks {
+        let mut file = File::open(chunk)?;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            sha2::Digest::update(&mut hasher, &buffer[..read]);
+            writer.write_all(&buffer[..read])?;
+            processed.fetch_add(read as u64, Ordering::Relaxed);
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    Ok(format!("{:x}", sha2::Digest::finalize(hasher)))
+}
+
+/// Ids are generated by [`Uploads::create`], anything else could be a path
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+impl Uploads {
+    pub const STD_EXPIRY: Duration = Duration::from_secs(24 * 3600);
+
+    pub fn new(data_dir: &FsPath, expiry: Duration) -> Self {
+        Uploads {
+            partial_dir: data_dir.join("tus"),
+            staging_dir: data_dir.join("staging"),
+            complete_dir: data_dir.join(COMPLETE_DIR),
+            quarantine_dir: data_dir.join("quarantine"),
+            expiry,
+            reserve: 0,
+            busy: Arc::default(),
+        }
+    }
+
+    pub fn with_reserve(mut self, reserve: u64) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    fn chunks_dir(&self, id: &str) -> PathBuf {
+        self.partial_dir.join(id)
+    }
+
+    /// Chunks are named by their offset, padded so they sort in order
+    fn chunk_path(&self, id: &str, offset: u64) -> PathBuf {
+        self.chunks_dir(id).join(format!("{:020}", offset))
+    }
+
+    /// Path and size of the chunks received, in order
+    async fn chunks(&self, id: &str) -> Result<Vec<(PathBuf, u64)>> {
+        let mut entries = tokio::fs::read_dir(self.chunks_dir(id)).await?;
+        let mut chunks = BTreeMap::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let offset = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(offset) = offset {
+                let offset: u64 = offset;
+                chunks.insert(offset, (entry.path(), entry.metadata().await?.len()));
+            }
+        }
+        Ok(chunks.into_values().collect())
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.partial_dir.join(format!("{}.json", id))
+    }
+
+    /// `Upload-Expires` of an upload active now
+    pub fn expires(&self) -> String {
+        (chrono::offset::Utc::now() + self.expiry)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    async fn save(&self, upload: &Upload) -> Result<()> {
+        tokio::fs::write(self.info_path(&upload.id), serde_json::to_vec(upload)?)
+            .await
+            .with_context(|| format!("Failed to save upload {}", upload.id))
+    }
+
+    /// Create an empty upload of `length` bytes
+    pub async fn create(&self, length: u64, metadata: Option<&str>) -> AppResult<Upload> {
+        let decoded = metadata
+            .map(parse_metadata)
+            .transpose()?
+            .unwrap_or_default();
+        // The chunks, then the assembled file while the chunks are still there
+        disk_space::ensure_space(&self.partial_dir, length.saturating_mul(2), self.reserve)?;
+        let upload = Upload {
+            id: nanoid::nanoid!(16),
+            length,
+            metadata: metadata.map(str::to_string),
+            filename: file_name(&decoded),
+            sha256: declared_sha256(&decoded)?,
+            share_id: decoded.get("share_id").cloned(),
+            created_at: chrono::offset::Utc::now().timestamp(),
+            path: None,
+            scan: None,
+        };
+        tokio::fs::create_dir_all(self.chunks_dir(&upload.id))
+            .await
+            .context("Failed to create the upload")?;
+        self.save(&upload).await?;
+        Ok(upload)
+    }
+
+    /// An upload with the bytes received so far, `None` when there is no such upload
+    pub async fn get(&self, id: &str) -> Result<Option<(Upload, u64)>> {
+        if !valid_id(id) {
+            return Ok(None);
+        }
+        let info = match tokio::fs::read(self.info_path(id)).await {
+            Ok(info) => info,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let upload: Upload = serde_json::from_slice(&info)
+            .with_context(|| format!("Invalid description of upload {}", id))?;
+        let offset = match upload.path {
+            Some(_) => upload.length,
+            None => self.chunks(id).await?.iter().map(|(_, size)| size).sum(),
+        };
+        Ok(Some((upload, offset)))
+    }
+
+    /// Store `body` as the chunk of the upload at `offset`, the upload and its offset after it. A
+    /// body which does not match its checksum or goes beyond the length of the upload is discarded.
+    pub async fn append<S, E>(
+        &self,
+        id: &str,
+        offset: u64,
+        mut body: S,
+        checksum: Option<Checksum>,
+    ) -> Result<(Upload, u64), PatchError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::fmt::Display,
+    {
+        let _busy = BusyGuard::take(&self.busy, id).ok_or(PatchError::Busy)?;
+        let (upload, current) = self.get(id).await?.ok_or(PatchError::Missing)?;
+        if current == upload.length || offset != current {
+            return Err(PatchError::Offset(current));
+        }
+
+        let chunk_path = self.chunk_path(id, offset);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&chunk_path)
+            .await?;
+        let mut hasher = checksum.as_ref().map(Checksum::hasher);
+        let mut received = offset;
+        let mut failure = None;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    failure = Some(PatchError::Interrupted(e.to_string()));
+                    break;
+                }
+            };
+            if received + chunk.len() as u64 > upload.length {
+                failure = Some(PatchError::TooLong);
+                break;
+            }
+            file.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
+        }
+        file.flush().await?;
+        drop(file);
+        if let (None, Some(checksum), Some(hasher)) = (&failure, &checksum, hasher) {
+            if *hasher.finalize() != *checksum.digest {
+                failure = Some(PatchError::Checksum);
+            }
+        }
+        // Without a checksum the bytes of an interrupted request are good, the client resumes
+        // after them
+        let keep = match &failure {
+            None => true,
+            Some(PatchError::Interrupted(_)) => checksum.is_none(),
+            Some(_) => false,
+        };
+        if !keep || received == offset {
+            tokio::fs::remove_file(&chunk_path).await?;
+        }
+        match failure {
+            Some(failure) => Err(failure),
+            None => Ok((upload, received)),
+        }
+    }
+
+    /// Concatenate the chunks of a complete upload into its file in the staging directory, counting
+    /// the bytes written in `processed`. Fails when the SHA-256 of the file is not the declared one.
+    pub async fn assemble(&self, id: &str, processed: Arc<AtomicU64>) -> Result<Assembled> {
+        let _busy = BusyGuard::take(&self.busy, id)
+            .ok_or_else(|| anyhow::anyhow!("Upload {} is receiving a request", id))?;
+        let (mut upload, received) = self
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No upload {}", id))?;
+        // Assembled by a previous attempt of the task
+        if let (Some(path), Some(sha256)) = (&upload.path, &upload.sha256) {
+            return Ok(Assembled {
+                path: path.clone(),
+                sha256: sha256.clone(),
+                upload,
+            });
+        }
+        if received != upload.length {
+            anyhow::bail!(
+                "Upload {} has {} of its {} bytes",
+                id,
+                received,
+                upload.length
+            );
+        }
+
+        let chunks: Vec<PathBuf> = self
+            .chunks(id)
+            .await?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        disk_space::ensure_space(&self.staging_dir, upload.length, self.reserve)?;
+        let dir = self.staging_dir.join(id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(&upload.filename);
+        let output = path.clone();
+        let sha256 = tokio::task::spawn_blocking(move || concatenate(&chunks, &output, &processed))
+            .await??;
+        if let Some(declared) = &upload.sha256 {
+            if *declared != sha256 {
+                tokio::fs::remove_file(&path).await?;
+                anyhow::bail!(
+                    "SHA-256 of upload {} is {}, {} was declared",
+                    id,
+                    sha256,
+                    declared
+                );
+            }
+        }
+        upload.path = Some(path.clone());
+        upload.sha256 = Some(sha256.clone());
+        self.save(&upload).await?;
+        tokio::fs::remove_dir_all(self.chunks_dir(id)).await?;
+        Ok(Assembled {
+            upload,
+            path,
+            sha256,
+        })
+    }
+
+    /// Store the scan of an assembled upload, moving its file to the quarantine when infected
+    pub async fn record_scan(&self, id: &str, scan: ScanResult) -> Result<Upload> {
+        let (mut upload, _) = self
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No upload {}", id))?;
+        if let (ScanStatus::Infected, Some(path)) = (scan.status, &upload.path) {
+            let dir = self.quarantine_dir.join(id);
+            tokio::fs::create_dir_all(&dir).await?;
+            let quarantined = dir.join(&upload.filename);
+            tokio::fs::rename(path, &quarantined).await?;
+            if let Some(parent) = path.parent() {
+                let _ = tokio::fs::remove_dir(parent).await;
+            }
+            upload.path = Some(quarantined);
+        }
+        upload.scan = Some(scan);
+        self.save(&upload).await?;
+        Ok(upload)
+    }
+
+    /// Move the file of an assembled upload out of the staging directory into a share root, its path
+    /// there
+    pub async fn publish(&self, id: &str) -> Result<PathBuf> {
+        let (mut upload, _) = self
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No upload {}", id))?;
+        let path = upload
+            .path
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Upload {} is not assembled", id))?;
+        // Published by a previous attempt of the task
+        if !path.starts_with(&self.staging_dir) {
+            return Ok(path);
+        }
+        let dir = self.complete_dir.join(id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let published = dir.join(&upload.filename);
+        tokio::fs::rename(&path, &published).await?;
+        if let Some(parent) = path.parent() {
+            let _ = tokio::fs::remove_dir(parent).await;
+        }
+        upload.path = Some(published.clone());
+        self.save(&upload).await?;
+        Ok(published)
+    }
+
+    /// Uploads in progress and those assembled since the expiry time, most recent first
+    pub async fn list(&self) -> Result<Vec<UploadStatus>> {
+        let mut entries = match tokio::fs::read_dir(&self.partial_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut uploads = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(id) = name.to_str().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            if let Some((upload, offset)) = self.get(id).await? {
+                uploads.push(UploadStatus { upload, offset });
+            }
+        }
+        uploads.sort_by_key(|status| std::cmp::Reverse(status.upload.created_at));
+        Ok(uploads)
+    }
+
+    /// Forget an upload and delete its chunks, `false` when there is no such upload. Assembled
+    /// files stay, they may be shared already.
+    pub async fn remove(&self, id: &str) -> Result<bool, PatchError> {
+        let _busy = BusyGuard::take(&self.busy, id).ok_or(PatchError::Busy)?;
+        if self.get(id).await?.is_none() {
+            return Ok(false);
+        }
+        match tokio::fs::remove_dir_all(self.chunks_dir(id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        tokio::fs::remove_file(self.info_path(id)).await?;
+        Ok(true)
+    }
+
+    /// Delete the uploads without activity for the expiry time, their number
+    pub async fn cleanup(&self) -> Result<usize> {
+        let mut entries = match tokio::fs::read_dir(&self.partial_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(id) = name.to_str().and_then(|name| name.strip_suffix(".json")) else {
+                continue;
+            };
+            let chunks = self.chunks(id).await.unwrap_or_default();
+            let last_activity = [self.info_path(id), self.chunks_dir(id)]
+                .into_iter()
+                .chain(chunks.into_iter().map(|(path, _)| path))
+                .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                .max()
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let idle = last_activity.elapsed().unwrap_or_default();
+            if idle >= self.expiry && self.remove(id).await.is_ok_and(|removed| removed) {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Delete the expired uploads every hour
+    pub fn spawn_cleanup(&self) {
+        let uploads = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                match uploads.cleanup().await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Deleted {} expired uploads", count),
+                    Err(e) => tracing::error!("Failed to delete the expired uploads: {}", e),
+                }
+            }
+        });
+    }
+}
+
+pub fn router() -> Router<App> {
+    Router::new()
+        .route("/uploads", get(list_uploads).post(create_upload))
+        .route(
+            "/uploads/{upload_id}",
+            get(get_upload)
+                .head(upload_offset)
+                .patch(append_upload)
+                .delete(delete_upload),
+        )
+        .layer(middleware::from_fn(tus_resumable))
+}
+
+/// Refuse the requests of other protocol versions, and tell the version in every response. The
+/// GET routes are not part of the protocol.
+async fn tus_resumable(request: Request, next: Next) -> Response {
+    if request.method() == Method::GET {
+        return next.run(request).await;
+    }
+    let supported = request
+        .headers()
+        .get(&TUS_RESUMABLE)
+        .is_some_and(|version| version == TUS_VERSION);
+    let mut response = if supported {
+        next.run(request).await
+    } else {
+        let mut response = with_status(
+            AppError::BadRequest(format!("Tus-Resumable must be {}", TUS_VERSION)),
+            StatusCode::PRECONDITION_FAILED.as_u16(),
+        );
+        response
+            .headers_mut()
+            .insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+        response
+    };
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Versions and extensions of the server, answered to `OPTIONS /admin/api/v1/uploads`. The CORS
+/// layer answers every OPTIONS request as a preflight, so this wraps the whole app instead of being
+/// a route.
+pub async fn discovery(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let discovery = request.method() == Method::OPTIONS
+        && path.starts_with("/admin/")
+        && path.ends_with("/uploads");
+    let mut response = next.run(request).await;
+    if discovery {
+        let headers = response.headers_mut();
+        headers.insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+        headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+        headers.insert(TUS_EXTENSION, HeaderValue::from_static(EXTENSIONS));
+        headers.insert(
+            TUS_CHECKSUM_ALGORITHM,
+            HeaderValue::from_static(CHECKSUM_ALGORITHMS),
+        );
+    }
+    response
+}
+
+/// Create an upload, whose URL is answered in `Location`
+#[instrument(skip(app_state, headers))]
+#[utoipa::path(
+    post,
+    path = "/uploads",
+    tag = "uploads",
+    params(
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+        ("Upload-Length" = u64, Header, description = "Bytes of the file"),
+        ("Upload-Metadata" = Option<String>, Header, description = "`filename` names the file, `sha256` is its hex digest checked once assembled and `share_id` the share it is added to, a new one when unset")
+    ),
+    responses((status = 201, headers(("Location" = String), ("Upload-Expires" = String))))
+)]
+async fn create_upload(
+    State(app_state): State<App>,
+    actor: Actor,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    if headers.contains_key(&UPLOAD_DEFER_LENGTH) {
+        return Err(AppError::BadRequest(
+            "Upload-Defer-Length is not supported".to_string(),
+        ));
+    }
+    let length = header_u64(&headers, &UPLOAD_LENGTH)
+        .ok_or_else(|| AppError::BadRequest("Missing or invalid Upload-Length".to_string()))?;
+    let metadata = headers
+        .get(&UPLOAD_METADATA)
+        .map(|metadata| metadata.to_str())
+        .transpose()
+        .map_err(|_| AppError::BadRequest("Invalid Upload-Metadata".to_string()))?;
+    let share_id = metadata
+        .map(parse_metadata)
+        .transpose()?
+        .and_then(|mut metadata| metadata.remove("share_id"));
+    if let Some(share_id) = &share_id {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM share_links WHERE id = ? AND deleted_at IS NULL) AS "exists!: bool""#,
+            share_id
+        )
+        .fetch_one(&app_state.db_reader)
+        .await?;
+        if !exists {
+            return Err(AppError::NotFound(format!("No share {}", share_id)));
+        }
+    }
+    let upload = app_state.uploads.create(length, metadata).await?;
+    audit::record(
+        &app_state.db_pool,
+        &actor,
+        "create_upload",
+        Some(&upload.id),
+        serde_json::json!({ "filename": upload.filename, "length": length, "share_id": share_id }),
+    )
+    .await;
+    // Nothing to wait for
+    if length == 0 {
+        assemble_later(&app_state, &actor, &upload.id).await?;
+    }
+    let location = format!("{}/{}", uri.path().trim_end_matches('/'), upload.id);
+    Ok((
+        StatusCode::CREATED,
+        [
+            (LOCATION, location),
+            (UPLOAD_EXPIRES, app_state.uploads.expires()),
+        ],
+    )
+        .into_response())
+}
+
+/// Uploads in progress and those assembled since `HARDWIRE_UPLOAD_EXPIRY_HOURS`, with their scan
+#[utoipa::path(
+    get,
+    path = "/uploads",
+    tag = "uploads",
+    responses((status = 200, body = Vec<UploadStatus>))
+)]
+async fn list_uploads(State(app_state): State<App>) -> AppResult<Json<Vec<UploadStatus>>> {
+    Ok(Json(app_state.uploads.list().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/uploads/{upload_id}",
+    tag = "uploads",
+    params(("upload_id" = String, Path)),
+    responses((status = 200, body = UploadStatus))
+)]
+async fn get_upload(
+    State(app_state): State<App>,
+    Path(upload_id): Path<String>,
+) -> AppResult<Json<UploadStatus>> {
+    let (upload, offset) = app_state
+        .uploads
+        .get(&upload_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No such upload".to_string()))?;
+    Ok(Json(UploadStatus { upload, offset }))
+}
+
+/// Bytes of the upload received so far
+#[utoipa::path(
+    head,
+    path = "/uploads/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path),
+        ("Tus-Resumable" = String, Header, description = "1.0.0")
+    ),
+    responses((status = 200, headers(("Upload-Offset" = u64), ("Upload-Length" = u64))))
+)]
+async fn upload_offset(
+    State(app_state): State<App>,
+    Path(upload_id): Path<String>,
+) -> AppResult<Response> {
+    let (upload, offset) = app_state
+        .uploads
+        .get(&upload_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("No such upload".to_string()))?;
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, offset.into());
+    headers.insert(UPLOAD_LENGTH, upload.length.into());
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Some(metadata) = upload.metadata.and_then(|m| HeaderValue::from_str(&m).ok()) {
+        headers.insert(UPLOAD_METADATA, metadata);
+    }
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// Append the body to the upload at `Upload-Offset`
+#[instrument(skip(app_state, headers, body))]
+#[utoipa::path(
+    patch,
+    path = "/uploads/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path),
+        ("Tus-Resumable" = String, Header, description = "1.0.0"),
+        ("Upload-Offset" = u64, Header, description = "Bytes of the upload received so far"),
+        ("Upload-Checksum" = Option<String>, Header, description = "`sha1` or `sha256` and the base64 digest of the body")
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (status = 204, description = "Once the last byte is received, an AssembleUpload task is created", headers(("Upload-Offset" = u64), ("Upload-Expires" = String))),
+        (status = 409, description = "Upload-Offset is not the offset of the upload"),
+        (status = 460, description = "Upload-Checksum does not match the body")
+    )
+)]
+async fn append_upload(
+    State(app_state): State<App>,
+    actor: Actor,
+    Path(upload_id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if headers
+        .get(CONTENT_TYPE)
+        .is_none_or(|content_type| content_type != OFFSET_OCTET_STREAM)
+    {
+        return with_status(
+            AppError::BadRequest(format!("Content-Type must be {}", OFFSET_OCTET_STREAM)),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE.as_u16(),
+        );
+    }
+    let Some(offset) = header_u64(&headers, &UPLOAD_OFFSET) else {
+        return AppError::BadRequest("Missing or invalid Upload-Offset".to_string())
+            .into_response();
+    };
+    let checksum = match headers.get(&UPLOAD_CHECKSUM).map(|checksum| {
+        checksum
+            .to_str()
+            .map_err(|_| AppError::BadRequest("Invalid Upload-Checksum".to_string()))
+            .and_then(Checksum::parse)
+    }) {
+        Some(Err(e)) => return e.into_response(),
+        checksum => checksum.transpose().unwrap_or_default(),
+    };
+    let uploads = &app_state.uploads;
+    let (upload, offset) = match uploads
+        .append(&upload_id, offset, body.into_data_stream(), checksum)
+        .await
+    {
+        Ok(appended) => appended,
+        Err(e) => return e.into_response(),
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(UPLOAD_OFFSET, offset.into());
+    if offset == upload.length {
+        if let Err(e) = assemble_later(&app_state, &actor, &upload_id).await {
+            return e.into_response();
+        }
+    } else if let Ok(expires) = HeaderValue::from_str(&uploads.expires()) {
+        headers.insert(UPLOAD_EXPIRES, expires);
+    }
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+/// Queue the assembly of an upload whose bytes were all received
+async fn assemble_later(app_state: &App, actor: &Actor, upload_id: &str) -> AppResult<()> {
+    let task_id = app_state
+        .task_manager
+        .create_task(TaskInput::AssembleUpload(AssembleUploadInput {
+            upload_id: upload_id.to_string(),
+        }))
+        .await
+        .map_err(|e| AppError::Internal(e.context("Failed to queue the assembly")))?;
+    tracing::info!(
+        "Upload {} received, assembled by task {}",
+        upload_id,
+        task_id
+    );
+    audit::record(
+        &app_state.db_pool,
+        actor,
+        "complete_upload",
+        Some(upload_id),
+        serde_json::json!({ "task_id": task_id }),
+    )
+    .await;
+    Ok(())
+}
+
+/// Cancel an upload in progress
+#[utoipa::path(
+    delete,
+    path = "/uploads/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path),
+        ("Tus-Resumable" = String, Header, description = "1.0.0")
+    ),
+    responses((status = 204))
+)]
+async fn delete_upload(State(app_state): State<App>, Path(upload_id): Path<String>) -> Response {
+    match app_state.uploads.remove(&upload_id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => PatchError::Missing.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(chunks: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, String>> + Unpin {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn sha256(data: &[u8]) -> Checksum {
+        use sha2::Digest;
+        let digest = base64::engine::general_purpose::STANDARD.encode(sha2::Sha256::digest(data));
+        Checksum::parse(&format!("sha256 {}", digest)).unwrap()
+    }
+
+    #[test]
+    fn test_metadata() {
+        // filename world_domination_plan.pdf, is_confidential
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert_eq!(file_name(&metadata), "world_domination_plan.pdf");
+        assert!(parse_metadata("filename ???").is_err());
+
+        let name =
+            |name: &str| file_name(&BTreeMap::from([("name".to_string(), name.to_string())]));
+        assert_eq!(name("../../etc/passwd"), "passwd");
+        assert_eq!(name("C:\\Users\\me\\a.iso"), "a.iso");
+        assert_eq!(name(".."), "upload");
+        assert_eq!(file_name(&BTreeMap::new()), "upload");
+    }
+
+    #[tokio::test]
+    async fn test_resumed_upload() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), Uploads::STD_EXPIRY);
+        // filename a.txt
+        let upload = uploads.create(10, Some("filename YS50eHQ=")).await.unwrap();
+        assert!(uploads.get("../x").await?.is_none());
+
+        let (_, offset) = uploads
+            .append(&upload.id, 0, body(&[b"hel", b"lo"]), None)
+            .await
+            .unwrap();
+        assert_eq!(offset, 5);
+        assert!(matches!(
+            uploads.append(&upload.id, 3, body(&[b"lo"]), None).await,
+            Err(PatchError::Offset(5))
+        ));
+        assert!(matches!(
+            uploads
+                .append(&upload.id, 5, body(&[b" world!"]), None)
+                .await,
+            Err(PatchError::TooLong)
+        ));
+        assert!(matches!(
+            uploads
+                .append(&upload.id, 5, body(&[b"world"]), Some(sha256(b"wrong")))
+                .await,
+            Err(PatchError::Checksum)
+        ));
+        // Discarded, the client sends them again
+        assert_eq!(uploads.get(&upload.id).await?.unwrap().1, 5);
+
+        // What an interrupted request without checksum received is kept
+        let interrupted = futures::stream::iter(vec![Ok(Bytes::from_static(b"wo")), Err("reset")]);
+        assert!(matches!(
+            uploads.append(&upload.id, 5, interrupted, None).await,
+            Err(PatchError::Interrupted(_))
+        ));
+        let (_, offset) = uploads
+            .append(&upload.id, 7, body(&[b"rld"]), Some(sha256(b"rld")))
+            .await
+            .unwrap();
+        assert_eq!(offset, 10);
+        assert!(matches!(
+            uploads.append(&upload.id, 10, body(&[b"!"]), None).await,
+            Err(PatchError::Offset(10))
+        ));
+
+        let processed = Arc::new(AtomicU64::new(0));
+        let assembled = uploads.assemble(&upload.id, processed.clone()).await?;
+        assert_eq!(
+            assembled.path,
+            dir.path().join("staging").join(&upload.id).join("a.txt")
+        );
+        assert_eq!(std::fs::read(&assembled.path)?, b"helloworld");
+        assert_eq!(
+            assembled.sha256,
+            "936a185caaa266bb9cbe981e9e05cb78cd732b0b3280eb944412bb6f8f8f07af"
+        );
+        assert_eq!(processed.load(Ordering::Relaxed), 10);
+        assert!(!dir.path().join("tus").join(&upload.id).exists());
+        assert_eq!(uploads.get(&upload.id).await?.unwrap().1, 10);
+        // A retried task finds the file assembled
+        let again = uploads.assemble(&upload.id, processed).await?;
+        assert_eq!(again.path, assembled.path);
+        assert_eq!(again.sha256, assembled.sha256);
+
+        let scan = ScanResult {
+            status: ScanStatus::Infected,
+            detail: Some("Eicar".to_string()),
+            scanned_at: 0,
+        };
+        let quarantined = uploads.record_scan(&upload.id, scan.clone()).await?;
+        let path = dir.path().join("quarantine").join(&upload.id).join("a.txt");
+        assert_eq!(quarantined.path.as_ref(), Some(&path));
+        assert!(path.exists() && !assembled.path.exists());
+        let listed = uploads.list().await?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].upload.scan, Some(scan));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_declared_sha256() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), Uploads::STD_EXPIRY);
+        let metadata = |sha256: &str| {
+            format!(
+                "sha256 {},share_id c2hhcmU=",
+                base64::engine::general_purpose::STANDARD.encode(sha256)
+            )
+        };
+        assert!(uploads.create(5, Some(&metadata("abc"))).await.is_err());
+
+        // SHA-256 of "world"
+        let upload = uploads
+            .create(
+                5,
+                Some(&metadata(
+                    "486EA46224D1BB4FB680F34F7C9AD96A8F24EC88BE73EA8E5A6C65260E9CB8A7",
+                )),
+            )
+            .await
+            .unwrap();
+        assert_eq!(upload.share_id.as_deref(), Some("share"));
+        uploads
+            .append(&upload.id, 0, body(&[b"wor", b"ld"]), None)
+            .await
+            .unwrap();
+        assert!(uploads
+            .assemble(&upload.id, Arc::default())
+            .await
+            .is_ok_and(|assembled| assembled.sha256 == upload.sha256.unwrap()));
+        let published = dir.path().join("uploads").join(&upload.id).join("upload");
+        assert_eq!(uploads.publish(&upload.id).await?, published);
+        assert_eq!(std::fs::read(&published)?, b"world");
+        assert!(!dir.path().join("staging").join(&upload.id).exists());
+        // A retried task finds the file published
+        assert_eq!(uploads.publish(&upload.id).await?, published);
+
+        let upload = uploads
+            .create(5, Some(&metadata(&"0".repeat(64))))
+            .await
+            .unwrap();
+        uploads
+            .append(&upload.id, 0, body(&[b"world"]), None)
+            .await
+            .unwrap();
+        let error = uploads.assemble(&upload.id, Arc::default()).await;
+        assert!(error.unwrap_err().to_string().contains("was declared"));
+        assert!(!dir
+            .path()
+            .join("staging")
+            .join(&upload.id)
+            .join("upload")
+            .exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let uploads = Uploads::new(dir.path(), Uploads::STD_EXPIRY);
+        let upload = uploads.create(10, None).await.unwrap();
+        assert_eq!(uploads.cleanup().await?, 0);
+
+        let expired = Uploads::new(dir.path(), Duration::ZERO);
+        assert_eq!(expired.cleanup().await?, 1);
+        assert!(uploads.get(&upload.id).await?.is_none());
+        assert!(!dir.path().join("tus").join(&upload.id).exists());
+        Ok(())
+    }
+}
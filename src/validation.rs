@@ -0,0 +1,216 @@
+//! Validation of the admin payloads.
+//!
+//! Payload types implement [`Validate`] and handlers extract them with [`ValidJson`], which
+//! answers with the field-level errors of the error envelope before the handler runs.
+
+use axum::extract::{FromRequest, Request};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use std::path::{Component, Path};
+
+use crate::error::{AppError, FieldError};
+use crate::share_alias;
+
+/// Longest lifetime accepted for a share, ten years
+pub const MAX_EXPIRY_SECS: i64 = 10 * 365 * 24 * 3600;
+pub const MAX_PASSWORD_LEN: usize = 256;
+pub const MAX_SHARE_ID_LEN: usize = 64;
+
+pub trait Validate {
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Collects the field errors of a payload
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    pub fn check(&mut self, valid: bool, field: &str, message: &str) {
+        if !valid {
+            self.error(field, message);
+        }
+    }
+
+    /// A path given by an admin: not empty, without NUL bytes nor `..` components
+    pub fn path(&mut self, field: &str, path: &Path) {
+        let raw = path.to_string_lossy();
+        if raw.is_empty() {
+            self.error(field, "must not be empty");
+        } else if raw.contains('\0') {
+            self.error(field, "must not contain NUL bytes");
+        } else if path.components().any(|c| c == Component::ParentDir) {
+            self.error(field, "must not contain '..'");
+        }
+    }
+
+    /// An identifier as generated by nanoid
+    pub fn share_id(&mut self, field: &str, share_id: &str) {
+        if share_id.is_empty() || share_id.len() > MAX_SHARE_ID_LEN {
+            self.error(
+                field,
+                format!("must be between 1 and {} characters", MAX_SHARE_ID_LEN),
+            );
+        } else if !share_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.error(field, "must only contain letters, digits, '-' and '_'");
+        }
+    }
+
+    /// A vanity alias of a share, see [`share_alias::check`]
+    pub fn alias(&mut self, field: &str, alias: &str) {
+        if let Err(message) = share_alias::check(alias) {
+            self.error(field, message);
+        }
+    }
+
+    pub fn expiry(&mut self, field: &str, secs: i64) {
+        if !(1..=MAX_EXPIRY_SECS).contains(&secs) {
+            self.error(
+                field,
+                format!("must be between 1 and {} seconds", MAX_EXPIRY_SECS),
+            );
+        }
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(self.errors))
+        }
+    }
+}
+
+/// JSON body extractor which validates the payload
+pub struct ValidJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ValidJson<T>
+where
+    T: DeserializeOwned + Validate,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|rejection| AppError::BadRequest(rejection.body_text()))?;
+        let mut validator = Validator::default();
+        value.validate(&mut validator);
+        validator.finish()?;
+        Ok(ValidJson(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::TaskInput;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn field_errors(input: serde_json::Value) -> Vec<String> {
+        let input: TaskInput = serde_json::from_value(input).unwrap();
+        let mut v = Validator::default();
+        input.validate(&mut v);
+        v.errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn test_archive_input_validation() {
+        let errors = field_errors(serde_json::json!({
+            "type": "CreateArchive",
+            "data": {
+                "files": ["/data/ok.txt", "/data/../etc/shadow"],
+                "output_path": "",
+                "share_expires_in": 0
+            }
+        }));
+        assert_eq!(
+            errors,
+            vec![
+                "data.files[1]",
+                "data.output_path",
+                "data.share_expires_in",
+                "data.share_expires_in"
+            ]
+        );
+
+        let errors = field_errors(serde_json::json!({
+            "type": "CreateArchive",
+            "data": { "directory": "/data", "output_path": "/tmp/out.7z", "create_share": true, "share_expires_in": 3600 }
+        }));
+        assert!(errors.is_empty());
+
+        let errors = field_errors(serde_json::json!({
+            "type": "CreateArchive",
+            "data": { "directory": "/data", "output_path": "/tmp/out", "compression": { "method": "store", "level": 12 } }
+        }));
+        assert_eq!(
+            errors,
+            vec!["data.compression.level", "data.compression.method"]
+        );
+    }
+
+    #[test]
+    fn test_checksum_input_validation() {
+        let errors = field_errors(serde_json::json!({
+            "type": "ChecksumShare",
+            "data": { "share_id": "../x" }
+        }));
+        assert_eq!(errors, vec!["data.share_id"]);
+    }
+
+    #[test]
+    fn test_disc_image_input_validation() {
+        let errors = field_errors(serde_json::json!({
+            "type": "DiscImage",
+            "data": { "share_id": "abc", "media": "dvd", "volume_label": "My Holidays" }
+        }));
+        assert_eq!(errors, vec!["data.volume_label"]);
+
+        let errors = field_errors(serde_json::json!({
+            "type": "DiscImage",
+            "data": { "share_id": "abc", "media": "blu_ray_dual_layer" }
+        }));
+        assert!(errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_valid_json_rejects_with_envelope() -> anyhow::Result<()> {
+        let app = Router::new().route(
+            "/tasks",
+            post(|ValidJson(_): ValidJson<TaskInput>| async { "OK" }),
+        );
+        let response = app
+            .oneshot(
+                Request::post("/tasks")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        r#"{"type":"ChecksumShare","data":{"share_id":""}}"#,
+                    ))?,
+            )
+            .await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let body: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(body["error"]["code"], "validation_failed");
+        assert_eq!(body["error"]["details"][0]["field"], "data.share_id");
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! Scanning of the uploaded files before they are shared.
+//!
+//! Once an upload is assembled, the `AssembleUpload` task hands the file to each configured
+//! scanner: clamd over TCP (`HARDWIRE_CLAMD_ADDR`, `clamav` feature) with its `INSTREAM` command, and
+//! any program of `HARDWIRE_SCAN_COMMAND` given the path of the file as last argument, which exits
+//! with 0 when the file is clean and 1 when it is infected like `clamscan` does. An infected file is
+//! moved to `HARDWIRE_DATA_DIR/quarantine` and never shared. A scanner failing to answer fails the
+//! task, which is retried when clamd could not be reached.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scanner {
+    /// Address of clamd, `host:port`
+    #[cfg(feature = "clamav")]
+    Clamd(String),
+    /// Program and its first arguments
+    Command(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanStatus {
+    Clean,
+    /// Moved to the quarantine
+    Infected,
+}
+
+/// Outcome of the scan of an upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ScanResult {
+    pub status: ScanStatus,
+    /// What was found, e.g. the signature name reported by clamd
+    pub detail: Option<String>,
+    pub scanned_at: i64,
+}
+
+impl Scanner {
+    /// `HARDWIRE_SCAN_COMMAND`, split on whitespace
+    pub fn command(command: &str) -> Option<Self> {
+        let args: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+        (!args.is_empty()).then_some(Scanner::Command(args))
+    }
+
+    /// What the scanner found in the file, `None` when it is clean
+    pub async fn scan(&self, path: &Path) -> Result<Option<String>> {
+        match self {
+            #[cfg(feature = "clamav")]
+            Scanner::Clamd(addr) => clamd_scan(addr, path).await,
+            Scanner::Command(args) => command_scan(args, path).await,
+        }
+    }
+}
+
+/// Run every scanner on `path`, stopping at the first one finding something
+pub async fn scan(scanners: &[Scanner], path: &Path) -> Result<ScanResult> {
+    let mut result = ScanResult {
+        status: ScanStatus::Clean,
+        detail: None,
+        scanned_at: 0,
+    };
+    for scanner in scanners {
+        if let Some(detail) = scanner.scan(path).await? {
+            result.status = ScanStatus::Infected;
+            result.detail = Some(detail);
+            break;
+        }
+    }
+    result.scanned_at = chrono::offset::Utc::now().timestamp();
+    Ok(result)
+}
+
+async fn command_scan(args: &[String], path: &Path) -> Result<Option<String>> {
+    let output = tokio::process::Command::new(&args[0])
+        .args(&args[1..])
+        .arg(path)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {}", args[0]))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    match output.status.code() {
+        Some(0) => Ok(None),
+        Some(1) => Ok(Some(if stdout.is_empty() {
+            format!("{} found a threat", args[0])
+        } else {
+            stdout
+        })),
+        _ => anyhow::bail!(
+            "{} failed ({}): {}",
+            args[0],
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// The signature of a clamd reply such as `stream: Eicar-Signature FOUND`, `None` for `stream: OK`
+#[cfg(any(feature = "clamav", test))]
+fn parse_clamd_reply(reply: &str) -> Result<Option<String>> {
+    let reply = reply.trim_end_matches(['\0', '\n']);
+    let answer = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if answer == "OK" {
+        Ok(None)
+    } else if let Some(signature) = answer.strip_suffix(" FOUND") {
+        Ok(Some(signature.to_string()))
+    } else {
+        anyhow::bail!("clamd failed: {}", answer)
+    }
+}
+
+/// Stream the file to clamd with `zINSTREAM`: chunks prefixed by their big endian length, then an
+/// empty one
+#[cfg(feature = "clamav")]
+async fn clamd_scan(addr: &str, path: &Path) -> Result<Option<String>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut stream = tokio::net::TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect to clamd at {}", addr))?;
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        stream.write_all(&(read as u32).to_be_bytes()).await?;
+        if read == 0 {
+            break;
+        }
+        stream.write_all(&buffer[..read]).await?;
+    }
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    parse_clamd_reply(&reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), None);
+        assert_eq!(
+            parse_clamd_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0").unwrap(),
+            Some("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_command_scan() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let clean = dir.path().join("clean.txt");
+        let infected = dir.path().join("infected.txt");
+        std::fs::write(&clean, "hello")?;
+        std::fs::write(&infected, "X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR")?;
+        let script = dir.path().join("scan.sh");
+        std::fs::write(
+            &script,
+            "if grep -q EICAR \"$1\"; then echo Eicar; exit 1; fi",
+        )?;
+        let scanners = [Scanner::command(&format!("sh {}", script.display())).unwrap()];
+        assert_eq!(scan(&scanners, &clean).await?.status, ScanStatus::Clean);
+        let result = scan(&scanners, &infected).await?;
+        assert_eq!(result.status, ScanStatus::Infected);
+        assert_eq!(result.detail.as_deref(), Some("Eicar"));
+
+        std::fs::write(&script, "exit 2")?;
+        assert!(scan(&scanners, &clean).await.is_err());
+        assert_eq!(scan(&[], &infected).await?.status, ScanStatus::Clean);
+        Ok(())
+    }
+}
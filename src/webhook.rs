@@ -0,0 +1,229 @@
+//! Inbound webhook for external automations (n8n, Home Assistant, cron jobs...).
+//!
+//! `POST /hooks` with `Authorization: Bearer $HARDWIRE_WEBHOOK_TOKEN` runs one predefined
+//! action, the endpoint answers 404 while no token is configured. See the README for the payload
+//! of each action.
+
+use axum::extract::{OriginalUri, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+use crate::audit::{self, Actor};
+use crate::error::{AppError, AppResult};
+use crate::progress::{AuthAttempt, Event};
+use crate::proxy::{client_ip, Client};
+use crate::validation::{ValidJson, Validate, Validator};
+use crate::worker::{ArchiveInput, TaskInput};
+use crate::{publish_files, App, ShareOptions};
+
+pub fn router(app_state: App) -> Router<App> {
+    Router::new()
+        .route("/", post(run_action))
+        .layer(middleware::from_fn_with_state(app_state, require_token))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", content = "data", rename_all = "snake_case")]
+pub enum WebhookAction {
+    /// Share files of the base path
+    CreateShare(CreateShareAction),
+    /// Start a CreateArchive task
+    CreateArchive(ArchiveInput),
+    /// Rescan the base path
+    Rescan,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareAction {
+    /// Paths relative to the base path
+    pub paths: Vec<PathBuf>,
+    /// Lifetime of the share in seconds, it never expires when unset
+    pub expires_in: Option<i64>,
+}
+
+impl Validate for WebhookAction {
+    fn validate(&self, v: &mut Validator) {
+        match self {
+            WebhookAction::CreateShare(action) => {
+                v.check(!action.paths.is_empty(), "data.paths", "must not be empty");
+                for (i, path) in action.paths.iter().enumerate() {
+                    let field = format!("data.paths[{}]", i);
+                    v.path(&field, path);
+                    v.check(
+                        path.is_relative(),
+                        &field,
+                        "must be relative to the base path",
+                    );
+                }
+                if let Some(secs) = action.expires_in {
+                    v.expiry("data.expires_in", secs);
+                }
+            }
+            WebhookAction::CreateArchive(input) => input.validate(v),
+            WebhookAction::Rescan => {}
+        }
+    }
+}
+
+async fn require_token(State(app_state): State<App>, request: Request, next: Next) -> Response {
+    let Some(token) = &app_state.config.webhook_token else {
+        return AppError::NotFound("Webhooks are disabled".to_string()).into_response();
+    };
+    let given = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let reason = match given {
+        None => Some("Missing bearer token"),
+        Some(given) if !token_matches(token, given) => Some("Invalid token"),
+        Some(_) => None,
+    };
+    // Nobody listening is not an error for the request itself
+    let _ = app_state
+        .progress_channel_sender
+        .send(Event::Auth(AuthAttempt {
+            realm: "webhook",
+            success: reason.is_none(),
+            client_ip: client_ip(request.extensions()),
+            path: request
+                .extensions()
+                .get::<OriginalUri>()
+                .map_or(request.uri().path(), |uri| uri.path())
+                .to_string(),
+            reason: reason.map(str::to_string),
+        }));
+    if reason.is_some() {
+        return AppError::Unauthorized("Missing or invalid webhook token".to_string())
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Compare tokens in a time independent of the position of the first difference
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn run_action(
+    State(app_state): State<App>,
+    client: Client,
+    ValidJson(action): ValidJson<WebhookAction>,
+) -> AppResult<Response> {
+    tracing::info!("Webhook action: {:?}", action);
+    let actor = Actor::new("webhook", &client);
+    match action {
+        WebhookAction::CreateShare(action) => {
+            let config = &app_state.config;
+            let roots = config.shareable_roots();
+            let base_path = PathBuf::from(&config.base_path);
+            let mut files = vec![];
+            for path in action.paths {
+                let file = roots.resolve(&base_path.join(&path)).map_err(|_| {
+                    AppError::NotFound(format!("No file {} in the base path", path.display()))
+                })?;
+                files.push(file);
+            }
+            let expires_at = action
+                .expires_in
+                .map(|secs| chrono::offset::Utc::now().timestamp() + secs);
+            let details = serde_json::json!({ "files": files, "expires_at": expires_at });
+            let share_url = publish_files(
+                files,
+                &client.host(&config.host),
+                &app_state.db_pool,
+                &app_state.plugins,
+                ShareOptions {
+                    expires_at,
+                    ..Default::default()
+                },
+            )
+            .await?;
+            let share_id = share_url.rsplit('/').next();
+            audit::record(
+                &app_state.db_pool,
+                &actor,
+                "create_share",
+                share_id,
+                details,
+            )
+            .await;
+            Ok(Json(serde_json::json!({ "share_url": share_url })).into_response())
+        }
+        WebhookAction::CreateArchive(input) => {
+            let input = TaskInput::CreateArchive(input);
+            let details = serde_json::json!(input);
+            let task_id = app_state.task_manager.create_task(input).await?;
+            audit::record(
+                &app_state.db_pool,
+                &actor,
+                "create_task",
+                Some(&task_id),
+                details,
+            )
+            .await;
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(serde_json::json!({ "task_id": task_id })),
+            )
+                .into_response())
+        }
+        WebhookAction::Rescan => {
+            app_state.indexer.rescan()?;
+            audit::record(
+                &app_state.db_pool,
+                &actor,
+                "rescan_index",
+                None,
+                serde_json::json!({}),
+            )
+            .await;
+            Ok(StatusCode::ACCEPTED.into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3cre"));
+        assert!(!token_matches("s3cret", "s3creT"));
+    }
+
+    #[test]
+    fn test_action_validation() {
+        let action: WebhookAction = serde_json::from_value(serde_json::json!({
+            "action": "create_share",
+            "data": { "paths": ["movies/a.mkv", "/etc/passwd"], "expires_in": 0 }
+        }))
+        .unwrap();
+        let mut v = Validator::default();
+        action.validate(&mut v);
+        let Err(AppError::Validation(errors)) = v.finish() else {
+            panic!("Expected validation errors");
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["data.paths[1]", "data.expires_in"]);
+
+        let action: WebhookAction =
+            serde_json::from_value(serde_json::json!({ "action": "rescan" })).unwrap();
+        let mut v = Validator::default();
+        action.validate(&mut v);
+        assert!(v.finish().is_ok());
+    }
+}
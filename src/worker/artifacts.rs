@@ -0,0 +1,254 @@
+//! Cleanup of the files generated by the tasks.
+//!
+//! Archives, checksum manifests, video previews, disc images and torrents are recorded in
+//! `artifacts` when their task completes. The `CleanupArtifacts` task deletes those no live share
+//! uses anymore: every share linking to the file, or which it was generated for, expired, was
+//! deleted or purged, and the file was not downloaded since, for the retention period. The files
+//! the shares link to stay in `files` until the shares are purged.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::Path;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use super::{CleanupArtifactsInput, TaskInput, TaskManager};
+
+/// Record a file generated by a task of `task_type`, for `share_id` when given
+pub async fn record(
+    db: &SqlitePool,
+    path: &Path,
+    task_type: &str,
+    share_id: Option<&str>,
+) -> Result<()> {
+    let path = path.to_string_lossy();
+    let now = chrono::offset::Utc::now().timestamp();
+    sqlx::query!(
+        r#"INSERT INTO artifacts (path, task_type, share_id, created_at) VALUES (?, ?, ?, ?)
+        ON CONFLICT (path) DO UPDATE SET task_type = excluded.task_type,
+            share_id = excluded.share_id, created_at = excluded.created_at"#,
+        path,
+        task_type,
+        share_id,
+        now
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CleanedArtifact {
+    pub path: String,
+    pub task_type: String,
+    pub bytes: u64,
+    /// When its last share ended or it was last downloaded or generated
+    pub unused_since: i64,
+}
+
+/// Output of the `CleanupArtifacts` task
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CleanupReport {
+    pub dry_run: bool,
+    /// Files deleted, or which would be in a dry run
+    pub deleted: Vec<CleanedArtifact>,
+    pub freed_bytes: u64,
+    /// Files still used by a share or within the retention period
+    pub kept: usize,
+    /// Files already gone from the disk, forgotten unless in a dry run
+    pub missing: Vec<String>,
+}
+
+impl TaskManager {
+    /// When the artifact at `path` stopped being used, `None` while a live share uses it
+    async fn unused_since(
+        &self,
+        path: &str,
+        share_id: Option<&str>,
+        now: i64,
+    ) -> Result<Option<i64>> {
+        let shares = sqlx::query!(
+            r#"SELECT expiration, deleted_at FROM share_links
+            WHERE id = ?1 OR id IN (
+                SELECT share_link_files.share_link_id FROM share_link_files
+                JOIN files ON files.id = share_link_files.file_id
+                WHERE files.path = ?2 OR files.preview_path = ?2)"#,
+            share_id,
+            path
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let mut unused_since = None;
+        for share in shares {
+            let expired_at = (share.expiration >= 0).then_some(share.expiration);
+            let ended_at = match (expired_at.filter(|at| *at <= now), share.deleted_at) {
+                (None, None) => return Ok(None),
+                (expired_at, deleted_at) => expired_at.max(deleted_at),
+            };
+            unused_since = unused_since.max(ended_at);
+        }
+        let last_download = sqlx::query_scalar!(
+            r#"SELECT MAX(COALESCE(finished_at, started_at)) AS "at: i64" FROM download WHERE file_path = ?"#,
+            path
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(Some(unused_since.max(last_download).unwrap_or(0)))
+    }
+
+    /// Delete the artifacts unused for `retention`, or only list them with `dry_run`
+    pub async fn cleanup_artifacts(
+        &self,
+        retention: Duration,
+        dry_run: bool,
+    ) -> Result<CleanupReport> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let mut report = CleanupReport {
+            dry_run,
+            deleted: vec![],
+            freed_bytes: 0,
+            kept: 0,
+            missing: vec![],
+        };
+        let artifacts = sqlx::query!(
+            "SELECT path, task_type, share_id, created_at FROM artifacts ORDER BY path"
+        )
+        .fetch_all(&self.db)
+        .await?;
+        for artifact in artifacts {
+            let unused_since = match self
+                .unused_since(&artifact.path, artifact.share_id.as_deref(), now)
+                .await?
+            {
+                Some(at) => at.max(artifact.created_at),
+                None => {
+                    report.kept += 1;
+                    continue;
+                }
+            };
+            if now - unused_since < retention.as_secs() as i64 {
+                report.kept += 1;
+                continue;
+            }
+            let bytes = match tokio::fs::metadata(&artifact.path).await {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    if !dry_run {
+                        self.forget_artifact(&artifact.path).await?;
+                    }
+                    report.missing.push(artifact.path);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !dry_run {
+                tokio::fs::remove_file(&artifact.path).await?;
+                self.forget_artifact(&artifact.path).await?;
+                tracing::info!("Deleted {}, unused since {}", artifact.path, unused_since);
+            }
+            report.freed_bytes += bytes;
+            report.deleted.push(CleanedArtifact {
+                path: artifact.path,
+                task_type: artifact.task_type,
+                bytes,
+                unused_since,
+            });
+        }
+        Ok(report)
+    }
+
+    async fn forget_artifact(&self, path: &str) -> Result<()> {
+        sqlx::query!("DELETE FROM artifacts WHERE path = ?", path)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Create a `CleanupArtifacts` task every day
+    pub fn spawn_artifact_cleanup(&self, retention: Duration) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(24 * 3600));
+            loop {
+                interval.tick().await;
+                let input = TaskInput::CleanupArtifacts(CleanupArtifactsInput {
+                    older_than_days: (retention.as_secs() / (24 * 3600)) as u32,
+                    dry_run: false,
+                });
+                if let Err(e) = task_manager.create_task(input).await {
+                    tracing::error!("Failed to create the artifact cleanup task: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cleanup_artifacts() -> Result<()> {
+        let (task_manager, _receiver) = crate::worker::tests::test_task_manager().await?;
+        let db = task_manager.db.clone();
+        let dir = tempfile::tempdir()?;
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+        for name in [
+            "live.zip",
+            "expired.iso",
+            "downloaded.zip",
+            "orphan.torrent",
+        ] {
+            std::fs::write(dir.path().join(name), "12345")?;
+        }
+        let now = chrono::offset::Utc::now().timestamp();
+        let old = now - 10 * 24 * 3600;
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at) VALUES ('live', -1, 0), ('expired', ?1, 0);
+            INSERT INTO files (id, sha256, path, file_size) VALUES (1, '', ?2, 5), (2, '', ?3, 5);
+            INSERT INTO share_link_files (share_link_id, file_id) VALUES ('live', 1), ('expired', 2);
+            INSERT INTO download (file_path, status, file_size, started_at, finished_at) VALUES (?4, 'complete', 5, ?5, ?5);",
+        )
+        .bind(old)
+        .bind(path("live.zip"))
+        .bind(path("expired.iso"))
+        .bind(path("downloaded.zip"))
+        .bind(now - 3600)
+        .execute(&db)
+        .await?;
+        for (name, task_type, share_id) in [
+            ("live.zip", "CreateArchive", None),
+            ("expired.iso", "DiscImage", Some("expired")),
+            ("downloaded.zip", "CreateArchive", None),
+            ("orphan.torrent", "CreateTorrent", Some("purged")),
+            ("gone.zip", "CreateArchive", None),
+        ] {
+            record(&db, &dir.path().join(name), task_type, share_id).await?;
+        }
+        sqlx::query("UPDATE artifacts SET created_at = ?")
+            .bind(old)
+            .execute(&db)
+            .await?;
+
+        let retention = Duration::from_secs(7 * 24 * 3600);
+        let report = task_manager.cleanup_artifacts(retention, true).await?;
+        let deleted: Vec<&str> = report.deleted.iter().map(|a| a.path.as_str()).collect();
+        assert_eq!(deleted, [path("expired.iso"), path("orphan.torrent")]);
+        assert_eq!((report.freed_bytes, report.kept), (10, 2));
+        assert_eq!(report.missing, [path("gone.zip")]);
+        assert_eq!(report.deleted[0].unused_since, old);
+        assert!(dir.path().join("expired.iso").exists());
+
+        let report = task_manager.cleanup_artifacts(retention, false).await?;
+        assert_eq!(report.deleted.len(), 2);
+        assert!(!dir.path().join("expired.iso").exists());
+        assert!(!dir.path().join("orphan.torrent").exists());
+        assert!(dir.path().join("downloaded.zip").exists());
+        let left: Vec<String> = sqlx::query_scalar("SELECT path FROM artifacts ORDER BY path")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(left, [path("downloaded.zip"), path("live.zip")]);
+        Ok(())
+    }
+}
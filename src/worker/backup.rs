@@ -0,0 +1,140 @@
+//! Backups of the database.
+//!
+//! The `BackupDatabase` task copies the live database with `VACUUM INTO`, a consistent snapshot
+//! taken without stopping the server, to `data_dir/backups/db-<UTC time>.sqlite`. Only the `keep`
+//! most recent backups are kept, other files of the directory are left alone. A backup restores by
+//! replacing `db.sqlite` with it while the server is stopped.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{BackupDatabaseInput, TaskInput, TaskManager};
+
+pub const BACKUPS_DIR_NAME: &str = "backups";
+const PREFIX: &str = "db-";
+const EXTENSION: &str = ".sqlite";
+
+#[derive(Debug, Serialize)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Older backups deleted by the rotation
+    pub removed: Vec<PathBuf>,
+}
+
+/// Backup files of `dir`, oldest first
+fn list_backups(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut backups = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+            backups.push(dir.join(name));
+        }
+    }
+    // The UTC time of the names sorts chronologically
+    backups.sort();
+    Ok(backups)
+}
+
+/// Write a backup of `db` to `dir` and delete the backups beyond the `keep` most recent
+pub async fn backup_database(db: &SqlitePool, dir: &Path, keep: usize) -> Result<Backup> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let name = format!(
+        "{}{}{}",
+        PREFIX,
+        chrono::offset::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+        EXTENSION
+    );
+    let path = dir.join(&name);
+    // Renamed once complete, an interrupted backup is never mistaken for a good one
+    let partial = dir.join(format!(".{}.partial", name));
+    let partial_str = partial.to_string_lossy().into_owned();
+    sqlx::query!("VACUUM INTO ?", partial_str)
+        .execute(db)
+        .await
+        .context("Failed to back up the database")?;
+    std::fs::rename(&partial, &path)?;
+    let size = std::fs::metadata(&path)?.len();
+
+    let backups = list_backups(dir)?;
+    let mut removed = vec![];
+    for old in &backups[..backups.len().saturating_sub(keep)] {
+        std::fs::remove_file(old).with_context(|| format!("Failed to remove {}", old.display()))?;
+        removed.push(old.clone());
+    }
+    Ok(Backup {
+        path,
+        size,
+        removed,
+    })
+}
+
+impl TaskManager {
+    /// Create a `BackupDatabase` task every `interval`, the first one after `interval`
+    pub fn spawn_backups(&self, interval: Duration) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut interval = tokio::time::interval_at(start, interval);
+            loop {
+                interval.tick().await;
+                let input = TaskInput::BackupDatabase(BackupDatabaseInput::default());
+                if let Err(e) = task_manager.create_task(input).await {
+                    tracing::error!("Failed to create the database backup task: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    #[tokio::test]
+    async fn test_backup_database() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Not in memory, VACUUM INTO would write to the in-memory VFS
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(
+                SqliteConnectOptions::new()
+                    .filename(dir.path().join("db.sqlite"))
+                    .create_if_missing(true),
+            )
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        sqlx::query("INSERT INTO share_links (id, expiration, created_at) VALUES ('kept', -1, 0)")
+            .execute(&db)
+            .await?;
+        let backups_dir = dir.path().join(BACKUPS_DIR_NAME);
+        std::fs::create_dir_all(&backups_dir)?;
+        std::fs::write(backups_dir.join("db-20200101T000000Z.sqlite"), "old")?;
+        std::fs::write(backups_dir.join("notes.txt"), "not a backup")?;
+
+        let first = backup_database(&db, &backups_dir, 2).await?;
+        assert!(first.size > 0 && first.removed.is_empty());
+        let backup = SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}", first.path.display()))
+            .await?;
+        let shares: Vec<String> = sqlx::query_scalar("SELECT id FROM share_links")
+            .fetch_all(&backup)
+            .await?;
+        assert_eq!(shares, vec!["kept"]);
+
+        // Backups are named after the millisecond
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let second = backup_database(&db, &backups_dir, 2).await?;
+        assert_eq!(
+            second.removed,
+            vec![backups_dir.join("db-20200101T000000Z.sqlite")]
+        );
+        assert_eq!(list_backups(&backups_dir)?, vec![first.path, second.path]);
+        assert!(backups_dir.join("notes.txt").exists());
+        Ok(())
+    }
+}
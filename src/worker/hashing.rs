@@ -0,0 +1,261 @@
+//! Parallel hashing of shared files.
+//!
+//! Files are hashed on the blocking pool, at most `concurrency` at a time overall and at most
+//! `per_root` at a time on the same file system, so a slow disk is not thrashed by concurrent
+//! reads while files on other disks keep being hashed. BLAKE3 is offered as a faster alternative
+//! to SHA-256 on multi-TB trees.
+
+use anyhow::Result;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use utoipa::ToSchema;
+
+use crate::instrumented::{CounterSink, InstrumentedStream};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Name stored next to the digests
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Manifest attached to a share, in the format of `sha256sum` and `b3sum`
+    pub fn manifest_name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256SUMS",
+            HashAlgorithm::Blake3 => "B3SUMS",
+        }
+    }
+
+    /// Key of the algorithm in the `Repr-Digest` and `Digest` headers
+    fn header_key(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha-256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// Hex digest of everything read from `reader`
+    pub fn hash_reader<R: Read>(&self, mut reader: R) -> io::Result<String> {
+        Ok(match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                io::copy(&mut reader, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut reader, &mut hasher)?;
+                hasher.finalize().to_hex().to_string()
+            }
+        })
+    }
+}
+
+/// `Repr-Digest` (RFC 9530) and legacy `Digest` (RFC 3230) values of the hex `digests` of a file
+pub fn digest_headers(digests: &[(HashAlgorithm, String)]) -> Option<(String, String)> {
+    let encoded: Vec<(&str, String)> = digests
+        .iter()
+        .filter_map(|(algorithm, hex)| {
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some((
+                algorithm.header_key(),
+                base64::engine::general_purpose::STANDARD.encode(bytes),
+            ))
+        })
+        .collect();
+    if encoded.is_empty() {
+        return None;
+    }
+    let repr_digest = encoded
+        .iter()
+        .map(|(key, value)| format!("{}=:{}:", key, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let digest = encoded
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some((repr_digest, digest))
+}
+
+/// Digests stored for a file by the ChecksumShare task
+pub async fn file_digests(db: &SqlitePool, file_id: i64) -> Result<Vec<(HashAlgorithm, String)>> {
+    let rows = sqlx::query!(
+        "SELECT algorithm, digest FROM file_digests WHERE file_id = ? ORDER BY algorithm DESC",
+        file_id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| Some((HashAlgorithm::from_name(&row.algorithm)?, row.digest)))
+        .collect())
+}
+
+/// Bounds the files hashed at the same time, overall and per file system
+#[derive(Clone)]
+pub struct HashPool {
+    total: Arc<Semaphore>,
+    per_root: usize,
+    roots: Arc<Mutex<HashMap<u64, Arc<Semaphore>>>>,
+}
+
+impl HashPool {
+    pub fn new(concurrency: usize, per_root: usize) -> Self {
+        HashPool {
+            total: Arc::new(Semaphore::new(concurrency.max(1))),
+            per_root: per_root.max(1),
+            roots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn root_semaphore(&self, path: &Path) -> Arc<Semaphore> {
+        let root = root_id(path);
+        Arc::clone(
+            self.roots
+                .lock()
+                .unwrap()
+                .entry(root)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.per_root))),
+        )
+    }
+
+    /// Hex digests of `paths` in the same order, the bytes read are added to `processed`
+    pub async fn hash_files(
+        &self,
+        paths: Vec<PathBuf>,
+        algorithm: HashAlgorithm,
+        processed: Arc<AtomicU64>,
+    ) -> Result<Vec<String>> {
+        let mut set = JoinSet::new();
+        for (i, path) in paths.into_iter().enumerate() {
+            let root = self.root_semaphore(&path);
+            let total = Arc::clone(&self.total);
+            let processed = Arc::clone(&processed);
+            set.spawn(async move {
+                let _root = root.acquire_owned().await?;
+                let _total = total.acquire_owned().await?;
+                let hash = tokio::task::spawn_blocking(move || {
+                    let file = File::open(&path)?;
+                    let reader =
+                        InstrumentedStream::new(BufReader::new(file), CounterSink(processed));
+                    algorithm.hash_reader(reader)
+                })
+                .await??;
+                Ok::<_, anyhow::Error>((i, hash))
+            });
+        }
+
+        let mut hashes = vec![String::new(); set.len()];
+        while let Some(result) = set.join_next().await {
+            let (i, hash) = result??;
+            hashes[i] = hash;
+        }
+        Ok(hashes)
+    }
+}
+
+/// File system holding `path`, files which can't be inspected share the same root
+#[cfg(unix)]
+fn root_id(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).map_or(0, |metadata| metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn root_id(_path: &Path) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn test_hash_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = vec![];
+        for i in 0..5 {
+            let path = dir.path().join(format!("{}.txt", i));
+            std::fs::write(&path, format!("content {}", i).repeat(1000))?;
+            paths.push(path);
+        }
+        let processed = Arc::new(AtomicU64::new(0));
+        let pool = HashPool::new(3, 2);
+
+        let sha256 = pool
+            .hash_files(paths.clone(), HashAlgorithm::Sha256, processed.clone())
+            .await?;
+        let blake3 = pool
+            .hash_files(paths.clone(), HashAlgorithm::Blake3, processed.clone())
+            .await?;
+        for (i, path) in paths.iter().enumerate() {
+            let content = std::fs::read(path)?;
+            assert_eq!(sha256[i], format!("{:x}", Sha256::digest(&content)));
+            assert_eq!(blake3[i], blake3::hash(&content).to_hex().to_string());
+        }
+        assert_eq!(processed.load(Ordering::Relaxed), 2 * 5 * 9000);
+
+        assert!(pool
+            .hash_files(
+                vec![dir.path().join("missing")],
+                HashAlgorithm::Blake3,
+                processed
+            )
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_headers() {
+        let sha256 = HashAlgorithm::Sha256.hash_reader(&b"hello"[..]).unwrap();
+        let (repr_digest, digest) = digest_headers(&[(HashAlgorithm::Sha256, sha256)]).unwrap();
+        assert_eq!(
+            repr_digest,
+            "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:"
+        );
+        assert_eq!(
+            digest,
+            "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert_eq!(
+            digest_headers(&[(HashAlgorithm::Blake3, "zz".to_string())]),
+            None
+        );
+    }
+}
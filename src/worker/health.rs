@@ -0,0 +1,275 @@
+//! Health of the shared files.
+//!
+//! Files get moved or deleted on disk after they are published. The `VerifyFiles` task checks
+//! that every file of the live shares still exists with its recorded size, and with its recorded
+//! digest when `verify_hashes` is set, and stores the result in `files.health`. Share pages list
+//! the broken files as unavailable rather than linking to a 404.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::path::Path;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use super::hashing::{self, HashAlgorithm};
+use super::{TaskInput, TaskManager, VerifyFilesInput};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum FileHealth {
+    Ok,
+    /// Deleted, moved or unreadable
+    Missing,
+    SizeMismatch,
+    HashMismatch,
+}
+
+/// Health of the file at `path`, the digest is only compared when given
+pub async fn check_file(
+    path: &Path,
+    size: Option<i64>,
+    digest: Option<(HashAlgorithm, String)>,
+) -> FileHealth {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return FileHealth::Missing,
+    };
+    if size.is_some_and(|size| size != metadata.len() as i64) {
+        return FileHealth::SizeMismatch;
+    }
+    if let Some((algorithm, expected)) = digest {
+        let path = path.to_path_buf();
+        let actual =
+            tokio::task::spawn_blocking(move || algorithm.hash_reader(std::fs::File::open(path)?))
+                .await;
+        match actual {
+            Ok(Ok(actual)) if actual == expected => {}
+            Ok(Ok(_)) => return FileHealth::HashMismatch,
+            _ => return FileHealth::Missing,
+        }
+    }
+    FileHealth::Ok
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FileStatus {
+    pub id: i64,
+    pub path: String,
+    pub file_size: Option<i64>,
+    /// Unknown until the file is verified
+    pub health: Option<FileHealth>,
+    pub health_checked_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ShareDetails {
+    pub id: String,
+    pub alias: Option<String>,
+    pub created_at: i64,
+    /// Unix timestamp after which the share is not served anymore, -1 when it never expires
+    pub expiration: i64,
+    pub encrypted: bool,
+    pub password_protected: bool,
+    /// Defined in shares.toml
+    pub managed: bool,
+    /// When the share was moved to the trash, see [`super::trash`]
+    pub deleted_at: Option<i64>,
+    /// No file was found broken by the last verification
+    pub healthy: bool,
+    pub files: Vec<FileStatus>,
+}
+
+/// A share, by id or alias, with the health of its files, `None` when it does not exist
+pub async fn share_details(db: &SqlitePool, share_id: &str) -> Result<Option<ShareDetails>> {
+    let Some(share) = sqlx::query!(
+        r#"SELECT id AS "id!", alias, created_at, expiration, encrypted, password_hash IS NOT NULL AS "password_protected!: bool", managed, deleted_at
+        FROM share_links WHERE id = ?1 OR alias = ?1"#,
+        share_id
+    )
+    .fetch_optional(db)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let files = sqlx::query_as!(
+        FileStatus,
+        r#"SELECT files.id AS "id!", files.path, files.file_size, files.health AS "health: FileHealth", files.health_checked_at
+        FROM files JOIN share_link_files ON share_link_files.file_id = files.id
+        WHERE share_link_files.share_link_id = ? ORDER BY files.id"#,
+        share.id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(Some(ShareDetails {
+        id: share.id,
+        alias: share.alias,
+        created_at: share.created_at,
+        expiration: share.expiration,
+        encrypted: share.encrypted,
+        password_protected: share.password_protected,
+        managed: share.managed,
+        deleted_at: share.deleted_at,
+        healthy: files
+            .iter()
+            .all(|file| file.health.is_none_or(|health| health == FileHealth::Ok)),
+        files,
+    }))
+}
+
+impl TaskManager {
+    /// Check the files of the live shares, or of a single one, and store their health
+    pub async fn verify_files(&self, input: &VerifyFilesInput) -> Result<serde_json::Value> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let files = sqlx::query!(
+            r#"SELECT DISTINCT files.id AS "id!", files.path, files.file_size, files.health AS "health: FileHealth"
+            FROM files JOIN share_link_files ON share_link_files.file_id = files.id
+            JOIN share_links ON share_links.id = share_link_files.share_link_id
+            WHERE (share_links.expiration < 0 OR share_links.expiration > ?)
+            AND share_links.deleted_at IS NULL AND (?2 IS NULL OR share_links.id = ?2)
+            ORDER BY files.id"#,
+            now,
+            input.share_id
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut broken = vec![];
+        for file in &files {
+            let digest = if input.verify_hashes {
+                let digests = hashing::file_digests(&self.db, file.id).await?;
+                // BLAKE3 is much faster when both are known
+                digests
+                    .iter()
+                    .find(|(algorithm, _)| *algorithm == HashAlgorithm::Blake3)
+                    .or(digests.first())
+                    .cloned()
+            } else {
+                None
+            };
+            let health = check_file(Path::new(&file.path), file.file_size, digest).await;
+            let checked_at = chrono::offset::Utc::now().timestamp();
+            sqlx::query!(
+                "UPDATE files SET health = ?, health_checked_at = ? WHERE id = ?",
+                health,
+                checked_at,
+                file.id
+            )
+            .execute(&self.db)
+            .await?;
+
+            if file.health != Some(health) {
+                if health != FileHealth::Ok {
+                    tracing::warn!("Shared file {} is {:?}", file.path, health);
+                }
+                let share_ids = sqlx::query_scalar!(
+                    r#"SELECT share_link_id AS "share_link_id!" FROM share_link_files WHERE file_id = ?"#,
+                    file.id
+                )
+                .fetch_all(&self.db)
+                .await?;
+                for share_id in &share_ids {
+                    self.share_cache.invalidate(share_id);
+                }
+            }
+            if health != FileHealth::Ok {
+                broken.push(serde_json::json!({
+                    "file_id": file.id,
+                    "path": file.path,
+                    "health": health,
+                }));
+            }
+        }
+        Ok(serde_json::json!({ "checked": files.len(), "broken": broken }))
+    }
+
+    /// Verify the files of the live shares every `interval`
+    pub fn spawn_health_checks(&self, interval: Duration) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let input = TaskInput::VerifyFiles(VerifyFilesInput::default());
+                if let Err(e) = task_manager.create_task(input).await {
+                    tracing::error!("Failed to create the file verification task: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::tests::test_task_manager;
+
+    #[tokio::test]
+    async fn test_verify_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let kept = dir.path().join("kept.txt");
+        let truncated = dir.path().join("truncated.txt");
+        std::fs::write(&kept, "hello")?;
+        std::fs::write(&truncated, "hello")?;
+        let (task_manager, _receiver) = test_task_manager().await?;
+        let db = &task_manager.db;
+        for (id, path) in [
+            (1, &kept),
+            (2, &truncated),
+            (3, &dir.path().join("gone.txt")),
+        ] {
+            let path = path.to_string_lossy();
+            sqlx::query!(
+                "INSERT INTO files (id, path, file_size, sha256) VALUES (?, ?, 5, '')",
+                id,
+                path
+            )
+            .execute(db)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO share_link_files (share_link_id, file_id) VALUES ('share', ?)",
+                id
+            )
+            .execute(db)
+            .await?;
+        }
+        sqlx::query!(
+            "INSERT INTO share_links (id, expiration, created_at) VALUES ('share', -1, 0)"
+        )
+        .execute(db)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO file_digests (file_id, algorithm, digest) VALUES (1, 'sha256', 'bad')"
+        )
+        .execute(db)
+        .await?;
+        std::fs::write(&truncated, "hel")?;
+
+        let output = task_manager
+            .verify_files(&VerifyFilesInput::default())
+            .await?;
+        assert_eq!(output["checked"], 3);
+        let details = share_details(db, "share").await?.unwrap();
+        let health: Vec<_> = details.files.iter().map(|f| f.health).collect();
+        assert_eq!(
+            health,
+            [
+                Some(FileHealth::Ok),
+                Some(FileHealth::SizeMismatch),
+                Some(FileHealth::Missing)
+            ]
+        );
+        assert!(!details.healthy);
+
+        let input = VerifyFilesInput {
+            share_id: Some("share".to_string()),
+            verify_hashes: true,
+        };
+        task_manager.verify_files(&input).await?;
+        let details = share_details(db, "share").await?.unwrap();
+        assert_eq!(details.files[0].health, Some(FileHealth::HashMismatch));
+        assert!(share_details(db, "unknown").await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+//! History of the finished tasks.
+//!
+//! Every task reaching a final status gets a summary in `task_history`, without its input and
+//! output blobs. Finished tasks are pruned from `tasks` after the retention period while their
+//! summary stays searchable by error message and exportable as CSV.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{QueryBuilder, Sqlite};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::{IntoParams, ToSchema};
+
+use super::{TaskManager, TaskStatus};
+
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow, ToSchema)]
+pub struct TaskSummary {
+    pub id: String,
+    pub task_type: String,
+    pub status: TaskStatus,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Words which must all appear in the error message
+    pub q: Option<String>,
+    pub status: Option<TaskStatus>,
+    #[serde(rename = "type")]
+    pub task_type: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl HistoryQuery {
+    pub const DEFAULT_LIMIT: i64 = 100;
+    pub const MAX_LIMIT: i64 = 1000;
+}
+
+/// FTS5 query matching every word of `q`, quoted so user input can't use the query syntax
+fn fts_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl TaskManager {
+    /// Store the summary of a task which reached a final status
+    pub(crate) async fn record_history(&self, task_id: &str) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO task_history (id, task_type, status, created_at, started_at, finished_at, error)
+            SELECT id, COALESCE(json_extract(input_data, '$.type'), task_type), status, created_at, started_at, finished_at, NULLIF(error, '')
+            FROM tasks WHERE id = ?
+            ON CONFLICT (id) DO UPDATE SET status = excluded.status, started_at = excluded.started_at,
+                finished_at = excluded.finished_at, error = excluded.error"#,
+            task_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete the finished tasks older than `retention`, their summary stays in the history
+    pub async fn prune_finished_tasks(&self, retention: Duration) -> Result<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let before = now - retention.as_secs() as i64;
+        let result = sqlx::query!(
+            "DELETE FROM tasks WHERE status IN ('completed', 'failed') AND finished_at < ?",
+            before
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Prune the finished tasks every hour
+    pub fn spawn_pruning(&self, retention: Duration) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                match task_manager.prune_finished_tasks(retention).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Pruned {} finished tasks", count),
+                    Err(e) => tracing::error!("Failed to prune finished tasks: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Most recently finished tasks first, the whole matching history when `paginate` is false
+    pub async fn search_history(
+        &self,
+        query: &HistoryQuery,
+        paginate: bool,
+    ) -> Result<Vec<TaskSummary>> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "SELECT id, task_type, status, created_at, started_at, finished_at, error FROM task_history WHERE 1 = 1",
+        );
+        if let Some(fts) = query.q.as_deref().and_then(fts_query) {
+            builder
+                .push(" AND rowid IN (SELECT rowid FROM task_history_fts WHERE task_history_fts MATCH ")
+                .push_bind(fts)
+                .push(")");
+        }
+        if let Some(status) = &query.status {
+            builder.push(" AND status = ").push_bind(status.to_string());
+        }
+        if let Some(task_type) = &query.task_type {
+            builder
+                .push(" AND task_type = ")
+                .push_bind(task_type.clone());
+        }
+        builder.push(" ORDER BY finished_at DESC, id");
+        if paginate {
+            let limit = query
+                .limit
+                .unwrap_or(HistoryQuery::DEFAULT_LIMIT)
+                .clamp(1, HistoryQuery::MAX_LIMIT);
+            builder
+                .push(" LIMIT ")
+                .push_bind(limit)
+                .push(" OFFSET ")
+                .push_bind(query.offset.unwrap_or(0).max(0));
+        }
+
+        Ok(builder
+            .build_query_as::<TaskSummary>()
+            .fetch_all(&self.db)
+            .await?)
+    }
+
+    pub async fn export_history_csv(&self, query: &HistoryQuery) -> Result<String> {
+        let mut csv = String::from("id,type,status,created_at,started_at,finished_at,error\n");
+        for task in self.search_history(query, false).await? {
+            let optional = |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                csv_field(&task.id),
+                csv_field(&task.task_type),
+                task.status,
+                task.created_at,
+                optional(task.started_at),
+                optional(task.finished_at),
+                csv_field(task.error.as_deref().unwrap_or_default()),
+            ));
+        }
+        Ok(csv)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::worker::tests::test_task_manager;
+    use crate::worker::{ChecksumShareInput, TaskInput};
+
+    pub(crate) async fn finished_task(
+        task_manager: &TaskManager,
+        status: TaskStatus,
+        error: Option<&str>,
+    ) -> Result<String> {
+        let task_id = task_manager
+            .create_task(TaskInput::ChecksumShare(ChecksumShareInput {
+                share_id: "share1".to_string(),
+                algorithm: Default::default(),
+            }))
+            .await?;
+        task_manager
+            .update_task_status(&task_id, status, error.map(str::to_string), None)
+            .await?;
+        Ok(task_id)
+    }
+
+    #[tokio::test]
+    async fn test_search_and_export_history() -> Result<()> {
+        let (task_manager, _receiver) = test_task_manager().await?;
+        let failed = finished_task(
+            &task_manager,
+            TaskStatus::Failed,
+            Some("No such file, or directory"),
+        )
+        .await?;
+        finished_task(&task_manager, TaskStatus::Completed, None).await?;
+
+        let all = task_manager
+            .search_history(&HistoryQuery::default(), true)
+            .await?;
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().all(|t| t.task_type == "ChecksumShare"));
+
+        let query = HistoryQuery {
+            q: Some("directory \"no".to_string()),
+            ..Default::default()
+        };
+        let found = task_manager.search_history(&query, true).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, failed);
+
+        let csv = task_manager.export_history_csv(&query).await?;
+        assert!(csv.ends_with(",\"No such file, or directory\"\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pruning_keeps_history() -> Result<()> {
+        let (task_manager, _receiver) = test_task_manager().await?;
+        let task_id = finished_task(&task_manager, TaskStatus::Completed, None).await?;
+
+        assert_eq!(
+            task_manager
+                .prune_finished_tasks(Duration::from_secs(3600))
+                .await?,
+            0
+        );
+        assert_eq!(task_manager.prune_finished_tasks(Duration::ZERO).await?, 0);
+        sqlx::query!(
+            "UPDATE tasks SET finished_at = finished_at - 10 WHERE id = ?",
+            task_id
+        )
+        .execute(&task_manager.db)
+        .await?;
+        assert_eq!(task_manager.prune_finished_tasks(Duration::ZERO).await?, 1);
+
+        assert!(task_manager.get_task_status(&task_id).await.is_err());
+        let history = task_manager
+            .search_history(&HistoryQuery::default(), true)
+            .await?;
+        assert_eq!(history[0].id, task_id);
+        Ok(())
+    }
+}
@@ -1,29 +1,438 @@
+pub mod artifacts;
+pub mod backup;
+pub mod hashing;
+pub mod health;
+pub mod history;
+pub mod resume;
+pub mod retry;
+pub mod schedules;
 pub mod tasks;
+pub mod torrent;
+pub mod trash;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::chaos::Chaos;
+use crate::filename_rules::{self, FilenameRule};
+use crate::plugins::Plugins;
+use crate::progress;
+use crate::share_cache::ShareCache;
+use crate::validation::{Validate, Validator, MAX_PASSWORD_LEN};
+use hashing::HashAlgorithm;
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 #[serde(tag = "type", content = "data")]
 pub enum TaskInput {
     CreateArchive(ArchiveInput),
+    ChecksumShare(ChecksumShareInput),
+    TranscodePreview(TranscodePreviewInput),
+    DiscImage(DiscImageInput),
+    PurgeTasks(PurgeTasksInput),
+    /// Delete the expired shares, the files on disk are kept
+    PurgeExpiredShares,
+    PurgeDeletedShares(PurgeDeletedSharesInput),
+    BackupDatabase(BackupDatabaseInput),
+    VerifyFiles(VerifyFilesInput),
+    CreateTorrent(CreateTorrentInput),
+    AssembleUpload(AssembleUploadInput),
+    CleanupArtifacts(CleanupArtifactsInput),
+    GenerateThumbnails(GenerateThumbnailsInput),
     // Add other task types here
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct ArchiveInput {
+    #[schema(value_type = Option<Vec<String>>)]
     pub files: Option<Vec<PathBuf>>,
+    #[schema(value_type = Option<String>)]
     pub directory: Option<PathBuf>,
     pub password: Option<String>,
+    #[schema(value_type = String)]
     pub output_path: PathBuf,
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Register the created archive in a new share, its URL is stored in the task output
+    #[serde(default)]
+    pub create_share: bool,
+    /// Lifetime in seconds of the created share, it never expires when unset
+    pub share_expires_in: Option<i64>,
+    /// Rules rewriting the names of the archive entries
+    #[serde(default)]
+    pub filename_rules: Vec<FilenameRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+pub enum ArchiveFormat {
+    /// LZMA2 compressed 7z, AES encrypted with a password
+    #[default]
+    #[serde(rename = "7z")]
+    SevenZ,
+    /// Deflate compressed ZIP, already compressed media is stored as is. AES encrypted with a
+    /// password.
+    #[serde(rename = "zip")]
+    Zip,
+    #[serde(rename = "tar_gz")]
+    TarGz,
+    #[serde(rename = "tar_zstd")]
+    TarZstd,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, sqlx::Type)]
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::SevenZ => "7z",
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZstd => "tar.zst",
+        }
+    }
+
+    pub fn supports_password(self) -> bool {
+        matches!(self, ArchiveFormat::SevenZ | ArchiveFormat::Zip)
+    }
+
+    /// sevenz-rust can't write COPY entries yet and zstd has no store mode
+    pub fn supports_store(self) -> bool {
+        matches!(self, ArchiveFormat::Zip | ArchiveFormat::TarGz)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(default)]
+pub struct Compression {
+    pub method: CompressionMethod,
+    /// From 0 (fastest) to 9 (smallest)
+    pub level: u32,
+}
+
+impl Compression {
+    pub const MAX_LEVEL: u32 = 9;
+    /// Large media barely shrinks, favor speed by default
+    pub const DEFAULT_LEVEL: u32 = 1;
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            method: CompressionMethod::Compress,
+            level: Self::DEFAULT_LEVEL,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionMethod {
+    /// The compression of the archive format
+    #[default]
+    Compress,
+    /// Copy the files as is
+    Store,
+}
+
+/// Hash every file of a share and attach a SHA256SUMS or B3SUMS file to it
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ChecksumShareInput {
+    pub share_id: String,
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+}
+
+/// Transcode a shared video into a low bitrate MP4 stored next to the original.
+/// Requires the `transcode` feature and ffmpeg.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct TranscodePreviewInput {
+    pub share_id: String,
+    pub file_id: i64,
+    /// Height of the preview in pixels, smaller videos are not upscaled
+    pub max_height: Option<u32>,
+}
+
+impl TranscodePreviewInput {
+    pub const DEFAULT_MAX_HEIGHT: u32 = 480;
+}
+
+/// Delete the completed and failed tasks finished more than `older_than_days` ago, their
+/// history summary is kept
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct PurgeTasksInput {
+    pub older_than_days: u32,
+}
+
+/// Purge the shares deleted more than `older_than_days` ago, `0` empties the trash, see [`trash`]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct PurgeDeletedSharesInput {
+    pub older_than_days: u32,
+}
+
+/// Delete the files generated by the tasks which no live share used for `older_than_days`, or only
+/// report them with `dry_run`, see [`artifacts`]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct CleanupArtifactsInput {
+    pub older_than_days: u32,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Back up the database to `data_dir/backups`, keeping the `keep` most recent backups or
+/// `HARDWIRE_BACKUP_KEEP`, see [`backup`]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct BackupDatabaseInput {
+    pub keep: Option<u32>,
+}
+
+/// Check that the files of the live shares, or of `share_id`, still exist with their recorded
+/// size, and digest with `verify_hashes`, see [`health`]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, ToSchema)]
+pub struct VerifyFilesInput {
+    pub share_id: Option<String>,
+    /// Hash the files having a recorded digest, much slower than the size check
+    #[serde(default)]
+    pub verify_hashes: bool,
+}
+
+/// Build a .torrent of a share's files, web seeded by the server, see [`torrent`]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct CreateTorrentInput {
+    pub share_id: String,
+    /// Announce URLs, those of `HARDWIRE_TORRENT_TRACKERS` when unset
+    pub trackers: Option<Vec<String>>,
+    /// Bytes per piece, a power of two picked from the size of the share when unset
+    pub piece_length: Option<u64>,
+}
+
+/// Generate the thumbnails of the images of a share ahead of its first visitors, see
+/// [`crate::thumbnails`]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct GenerateThumbnailsInput {
+    pub share_id: String,
+    /// Among 160, 320, 640 and 1280 pixels, the 320 of the share page when unset
+    pub widths: Option<Vec<u32>>,
+}
+
+/// Concatenate the chunks of a complete upload, check its SHA-256 and add the file to its share,
+/// see [`crate::tus`]. Created when the last byte of the upload is received.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct AssembleUploadInput {
+    pub upload_id: String,
+}
+
+/// Build an ISO 9660/UDF image of a share's files with mkisofs and attach it to the share
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct DiscImageInput {
+    pub share_id: String,
+    /// Media the image must fit on
+    pub media: MediaPreset,
+    /// Volume name shown when the disc is mounted, defaults to the share id
+    pub volume_label: Option<String>,
+}
+
+impl DiscImageInput {
+    pub const MAX_VOLUME_LABEL_LEN: usize = 32;
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaPreset {
+    Cd,
+    Dvd,
+    DvdDualLayer,
+    BluRay,
+    BluRayDualLayer,
+}
+
+impl MediaPreset {
+    /// Writable bytes of a blank disc
+    pub fn capacity(self) -> u64 {
+        match self {
+            MediaPreset::Cd => 359_847 * 2048,
+            MediaPreset::Dvd => 2_295_104 * 2048,
+            MediaPreset::DvdDualLayer => 4_171_712 * 2048,
+            MediaPreset::BluRay => 12_219_392 * 2048,
+            MediaPreset::BluRayDualLayer => 24_438_784 * 2048,
+        }
+    }
+}
+
+impl std::fmt::Display for MediaPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaPreset::Cd => write!(f, "CD"),
+            MediaPreset::Dvd => write!(f, "DVD"),
+            MediaPreset::DvdDualLayer => write!(f, "dual layer DVD"),
+            MediaPreset::BluRay => write!(f, "Blu-ray"),
+            MediaPreset::BluRayDualLayer => write!(f, "dual layer Blu-ray"),
+        }
+    }
+}
+
+/// Field names are prefixed with `data.`, archive inputs being the data of a task or a webhook
+impl Validate for ArchiveInput {
+    fn validate(&self, v: &mut Validator) {
+        match (&self.files, &self.directory) {
+            (Some(files), None) => {
+                v.check(!files.is_empty(), "data.files", "must not be empty");
+                for (i, file) in files.iter().enumerate() {
+                    v.path(&format!("data.files[{}]", i), file);
+                }
+            }
+            (None, Some(directory)) => v.path("data.directory", directory),
+            _ => v.error(
+                "data",
+                "exactly one of directory or files must be specified",
+            ),
+        }
+        v.path("data.output_path", &self.output_path);
+        filename_rules::validate(v, "data.filename_rules", &self.filename_rules);
+        if let Some(password) = &self.password {
+            v.check(
+                !password.is_empty() && password.len() <= MAX_PASSWORD_LEN,
+                "data.password",
+                "must be between 1 and 256 characters",
+            );
+            v.check(
+                self.format.supports_password(),
+                "data.password",
+                "is only supported by the 7z and zip formats",
+            );
+        }
+        v.check(
+            self.compression.level <= Compression::MAX_LEVEL,
+            "data.compression.level",
+            "must be between 0 and 9",
+        );
+        if self.compression.method == CompressionMethod::Store {
+            v.check(
+                self.format.supports_store(),
+                "data.compression.method",
+                "store is only supported by the zip and tar_gz formats",
+            );
+        }
+        if let Some(secs) = self.share_expires_in {
+            v.check(
+                self.create_share,
+                "data.share_expires_in",
+                "requires create_share",
+            );
+            v.expiry("data.share_expires_in", secs);
+        }
+    }
+}
+
+impl Validate for TaskInput {
+    fn validate(&self, v: &mut Validator) {
+        match self {
+            TaskInput::CreateArchive(input) => input.validate(v),
+            TaskInput::ChecksumShare(input) => v.share_id("data.share_id", &input.share_id),
+            TaskInput::TranscodePreview(input) => {
+                v.share_id("data.share_id", &input.share_id);
+                v.check(input.file_id > 0, "data.file_id", "must be positive");
+                if let Some(max_height) = input.max_height {
+                    v.check(
+                        (144..=2160).contains(&max_height),
+                        "data.max_height",
+                        "must be between 144 and 2160",
+                    );
+                }
+            }
+            TaskInput::PurgeExpiredShares => {}
+            TaskInput::PurgeDeletedShares(input) => v.check(
+                input.older_than_days <= 3650,
+                "data.older_than_days",
+                "must be between 0 and 3650",
+            ),
+            TaskInput::CleanupArtifacts(input) => v.check(
+                input.older_than_days <= 3650,
+                "data.older_than_days",
+                "must be between 0 and 3650",
+            ),
+            TaskInput::BackupDatabase(input) => {
+                if let Some(keep) = input.keep {
+                    v.check(
+                        (1..=1000).contains(&keep),
+                        "data.keep",
+                        "must be between 1 and 1000",
+                    );
+                }
+            }
+            TaskInput::VerifyFiles(input) => {
+                if let Some(share_id) = &input.share_id {
+                    v.share_id("data.share_id", share_id);
+                }
+            }
+            TaskInput::PurgeTasks(input) => v.check(
+                (1..=3650).contains(&input.older_than_days),
+                "data.older_than_days",
+                "must be between 1 and 3650",
+            ),
+            TaskInput::CreateTorrent(input) => {
+                v.share_id("data.share_id", &input.share_id);
+                for (i, tracker) in input.trackers.iter().flatten().enumerate() {
+                    v.check(
+                        url::Url::parse(tracker).is_ok_and(|url| {
+                            matches!(url.scheme(), "http" | "https" | "udp") && url.has_host()
+                        }),
+                        &format!("data.trackers[{}]", i),
+                        "must be an http, https or udp URL",
+                    );
+                }
+                if let Some(piece_length) = input.piece_length {
+                    v.check(
+                        piece_length.is_power_of_two()
+                            && (torrent::MIN_PIECE_LENGTH..=torrent::MAX_PIECE_LENGTH)
+                                .contains(&piece_length),
+                        "data.piece_length",
+                        "must be a power of two between 16 KiB and 64 MiB",
+                    );
+                }
+            }
+            TaskInput::GenerateThumbnails(input) => {
+                v.share_id("data.share_id", &input.share_id);
+                if let Some(widths) = &input.widths {
+                    v.check(!widths.is_empty(), "data.widths", "must not be empty");
+                    for (i, width) in widths.iter().enumerate() {
+                        v.check(
+                            crate::thumbnails::WIDTHS.contains(width),
+                            &format!("data.widths[{}]", i),
+                            "must be 160, 320, 640 or 1280",
+                        );
+                    }
+                }
+            }
+            TaskInput::AssembleUpload(input) => v.check(
+                crate::tus::valid_id(&input.upload_id),
+                "data.upload_id",
+                "must be an upload id",
+            ),
+            TaskInput::DiscImage(input) => {
+                v.share_id("data.share_id", &input.share_id);
+                if let Some(label) = &input.volume_label {
+                    v.check(
+                        !label.is_empty()
+                            && label.len() <= DiscImageInput::MAX_VOLUME_LABEL_LEN
+                            && label
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                        "data.volume_label",
+                        "must be 1 to 32 ASCII letters, digits, '_' or '-'",
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::Type, ToSchema)]
 #[sqlx(rename_all = "snake_case")]
 pub enum TaskStatus {
     Pending,
@@ -43,35 +452,161 @@ impl std::fmt::Display for TaskStatus {
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct Task {
     pub id: String,
+    #[serde(rename = "type")]
+    pub task_type: String,
     pub status: TaskStatus,
     pub created_at: i64,
     pub started_at: Option<i64>,
     pub finished_at: Option<i64>,
     pub error: Option<String>,
     pub progress: i32,
+    pub elapsed_secs: Option<i64>,
+    /// Extrapolated from the progress of a running task
+    pub estimated_remaining_secs: Option<i64>,
+    /// Size of the written archive relative to the size of its files
+    pub compression_ratio: Option<f64>,
+    pub retry_count: i64,
+    pub max_retries: i64,
+    /// Unix timestamp of the next automatic attempt after a transient failure
+    pub retry_at: Option<i64>,
+    /// Result of a completed task, e.g. the path of a database backup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<serde_json::Value>,
+}
+
+#[derive(sqlx::FromRow)]
+struct TaskRow {
+    id: String,
+    task_type: String,
+    status: TaskStatus,
+    created_at: i64,
+    started_at: Option<i64>,
+    finished_at: Option<i64>,
+    error: Option<String>,
+    progress: i32,
+    compression_ratio: Option<f64>,
+    retry_count: i64,
+    max_retries: i64,
+    retry_at: Option<i64>,
+    output_data: Option<String>,
+}
+
+impl TaskRow {
+    const COLUMNS: &'static str = "id, COALESCE(json_extract(input_data, '$.type'), task_type) AS task_type, status, created_at, started_at, finished_at, error, COALESCE(progress, 0) AS progress, compression_ratio, retry_count, max_retries, retry_at, output_data";
+
+    fn into_task(self) -> Result<Task> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let elapsed_secs = self
+            .started_at
+            .map(|started_at| self.finished_at.unwrap_or(now) - started_at);
+        let estimated_remaining_secs = match (&self.status, elapsed_secs) {
+            (TaskStatus::Running, Some(elapsed)) if self.progress > 0 => {
+                Some(elapsed * (100 - self.progress as i64) / self.progress as i64)
+            }
+            _ => None,
+        };
+
+        Ok(Task {
+            id: self.id,
+            task_type: self.task_type,
+            status: self.status,
+            created_at: self.created_at,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            error: self.error,
+            progress: self.progress,
+            elapsed_secs,
+            estimated_remaining_secs,
+            compression_ratio: self.compression_ratio,
+            retry_count: self.retry_count,
+            max_retries: self.max_retries,
+            retry_at: self.retry_at,
+            output: self
+                .output_data
+                .and_then(|output| serde_json::from_str(&output).ok()),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TaskListQuery {
+    pub status: Option<TaskStatus>,
+    #[serde(rename = "type")]
+    pub task_type: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl TaskListQuery {
+    pub const DEFAULT_LIMIT: i64 = 100;
+    pub const MAX_LIMIT: i64 = 1000;
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TaskList {
+    /// Number of tasks matching the filters
+    pub total: i64,
+    pub tasks: Vec<Task>,
 }
 
 #[derive(Debug, Clone)]
 pub struct TaskManager {
     pub(crate) db: SqlitePool,
+    pub(crate) share_cache: ShareCache,
+    pub(crate) chaos: Chaos,
+    pub(crate) plugins: Plugins,
+    /// Live feed the progress of the tasks is sent to
+    pub(crate) progress: broadcast::Sender<progress::Event>,
     _task_sender: mpsc::Sender<String>, // Task ID
 }
 
 impl TaskManager {
-    pub fn new(db: SqlitePool) -> (Self, mpsc::Receiver<String>) {
+    pub fn new(db: SqlitePool, share_cache: ShareCache) -> (Self, mpsc::Receiver<String>) {
         let (tx, rx) = mpsc::channel(32);
         (
             Self {
                 db,
+                share_cache,
+                chaos: Chaos::default(),
+                plugins: Plugins::default(),
+                progress: broadcast::channel(1).0,
                 _task_sender: tx,
             },
             rx,
         )
     }
 
+    /// Why the worker can't take new tasks, `None` when it can. A long task doesn't make the worker
+    /// unready, the tasks are queued meanwhile.
+    pub fn worker_unavailable(&self) -> Option<&'static str> {
+        if self._task_sender.is_closed() {
+            Some("the task worker stopped")
+        } else if self._task_sender.capacity() == 0 {
+            Some("the task queue is full")
+        } else {
+            None
+        }
+    }
+
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    pub fn with_plugins(mut self, plugins: Plugins) -> Self {
+        self.plugins = plugins;
+        self
+    }
+
+    pub fn with_progress(mut self, sender: broadcast::Sender<progress::Event>) -> Self {
+        self.progress = sender;
+        self
+    }
+
     pub async fn create_task(&self, input: TaskInput) -> Result<String> {
         let task_id = Uuid::new_v4().to_string();
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
@@ -82,14 +617,15 @@ impl TaskManager {
 
         sqlx::query!(
             r#"
-            INSERT INTO tasks (id, task_type, status, created_at, input_data, progress)
-            VALUES (?, ?, ?, ?, ?, 0)
+            INSERT INTO tasks (id, task_type, status, created_at, input_data, progress, max_retries)
+            VALUES (?, ?, ?, ?, ?, 0, ?)
             "#,
             task_id,
             task_type,
             task_status,
             now,
             input_str,
+            retry::DEFAULT_MAX_RETRIES,
         )
         .execute(&self.db)
         .await?;
@@ -101,33 +637,90 @@ impl TaskManager {
     }
 
     pub async fn get_task_status(&self, task_id: &str) -> Result<Task> {
-        let task = sqlx::query!(
-            r#"
-            SELECT 
-                id,
-                status as "status: TaskStatus",
-                created_at,
-                started_at,
-                finished_at,
-                error,
-                COALESCE(progress, 0) as "progress!: i32"
-            FROM tasks
-            WHERE id = ?
-            "#,
+        let task: TaskRow = sqlx::query_as(&format!(
+            "SELECT {} FROM tasks WHERE id = ?",
+            TaskRow::COLUMNS
+        ))
+        .bind(task_id)
+        .fetch_one(&self.db)
+        .await?;
+        task.into_task()
+    }
+
+    /// Most recent tasks first
+    pub async fn list_tasks(&self, query: &TaskListQuery) -> Result<TaskList> {
+        let filter = |builder: &mut QueryBuilder<Sqlite>| {
+            builder.push(" FROM tasks WHERE 1 = 1");
+            if let Some(status) = &query.status {
+                builder.push(" AND status = ").push_bind(status.to_string());
+            }
+            if let Some(task_type) = &query.task_type {
+                builder
+                    .push(" AND json_extract(input_data, '$.type') = ")
+                    .push_bind(task_type.clone());
+            }
+        };
+
+        let mut count = QueryBuilder::new("SELECT COUNT(*)");
+        filter(&mut count);
+        let total: i64 = count.build_query_scalar().fetch_one(&self.db).await?;
+
+        let mut select = QueryBuilder::new(format!("SELECT {}", TaskRow::COLUMNS));
+        filter(&mut select);
+        select
+            .push(" ORDER BY created_at DESC, id LIMIT ")
+            .push_bind(
+                query
+                    .limit
+                    .unwrap_or(TaskListQuery::DEFAULT_LIMIT)
+                    .clamp(1, TaskListQuery::MAX_LIMIT),
+            )
+            .push(" OFFSET ")
+            .push_bind(query.offset.unwrap_or(0).max(0));
+        let tasks = select
+            .build_query_as::<TaskRow>()
+            .fetch_all(&self.db)
+            .await?
+            .into_iter()
+            .map(TaskRow::into_task)
+            .collect::<Result<_>>()?;
+
+        Ok(TaskList { total, tasks })
+    }
+
+    /// Number of tasks in each status, pruned tasks excluded
+    pub async fn count_by_status(&self) -> Result<BTreeMap<String, i64>> {
+        let rows = sqlx::query!(
+            r#"SELECT status AS "status!", COUNT(*) AS "count!: i64" FROM tasks GROUP BY status"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.status, row.count))
+            .collect())
+    }
+
+    /// Delete a finished task, `false` when it does not exist or is still pending or running
+    pub async fn delete_task(&self, task_id: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM tasks WHERE id = ? AND status IN ('completed', 'failed')",
             task_id
         )
-        .fetch_one(&self.db)
+        .execute(&self.db)
         .await?;
+        Ok(result.rows_affected() > 0)
+    }
 
-        Ok(Task {
-            id: task.id,
-            status: task.status,
-            created_at: task.created_at,
-            started_at: task.started_at,
-            finished_at: task.finished_at,
-            error: task.error,
-            progress: task.progress,
-        })
+    pub async fn update_task_compression_ratio(&self, task_id: &str, ratio: f64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE tasks SET compression_ratio = ? WHERE id = ?",
+            ratio,
+            task_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
     }
 
     pub async fn update_task_status(
@@ -150,7 +743,8 @@ impl TaskManager {
 
         match status {
             TaskStatus::Running => {
-                query.push_str(", started_at = ?");
+                // Progress updates keep the task running, they must not move its start
+                query.push_str(", started_at = COALESCE(started_at, ?), retry_at = NULL");
                 values.push(now.to_string());
             }
             TaskStatus::Completed | TaskStatus::Failed => {
@@ -170,6 +764,60 @@ impl TaskManager {
 
         q.execute(&self.db).await?;
 
+        if matches!(status, TaskStatus::Completed | TaskStatus::Failed) {
+            self.record_history(task_id).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::worker::history::tests::finished_task;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    pub(crate) async fn test_task_manager() -> Result<(TaskManager, mpsc::Receiver<String>)> {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+        sqlx::migrate!().run(&db).await?;
+        Ok(TaskManager::new(db, ShareCache::new()))
+    }
+
+    #[tokio::test]
+    async fn test_list_and_delete_tasks() -> Result<()> {
+        let (task_manager, _receiver) = test_task_manager().await?;
+        let failed = finished_task(&task_manager, TaskStatus::Failed, Some("Boom")).await?;
+        let pending = task_manager
+            .create_task(TaskInput::PurgeTasks(PurgeTasksInput {
+                older_than_days: 30,
+            }))
+            .await?;
+
+        let list = task_manager.list_tasks(&TaskListQuery::default()).await?;
+        assert_eq!(list.total, 2);
+        let list = task_manager
+            .list_tasks(&TaskListQuery {
+                task_type: Some("PurgeTasks".to_string()),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(list.total, 1);
+        assert_eq!(list.tasks[0].id, pending);
+        assert_eq!(list.tasks[0].task_type, "PurgeTasks");
+
+        assert!(!task_manager.delete_task(&pending).await?);
+        assert!(task_manager.delete_task(&failed).await?);
+        let list = task_manager
+            .list_tasks(&TaskListQuery {
+                status: Some(TaskStatus::Failed),
+                ..Default::default()
+            })
+            .await?;
+        assert_eq!(list.total, 0);
         Ok(())
     }
 }
@@ -0,0 +1,102 @@
+//! Tasks interrupted by a shutdown or a crash.
+//!
+//! On shutdown the worker takes no new task and lets the running one finish during the grace
+//! period. A task still running past it is put back to `pending`, its partial output being left
+//! under a `.partial` name, and is started over at the next startup along with the tasks which
+//! were still queued. Tasks found `running` at startup were cut by a crash and are resumed too.
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::TaskManager;
+
+pub const INTERRUPTED_ERROR: &str = "Interrupted by a shutdown";
+
+impl TaskManager {
+    /// Put the running task `task_id` back to `pending`, without counting a retry
+    pub async fn interrupt_task(&self, task_id: &str) -> Result<()> {
+        sqlx::query!(
+            r#"UPDATE tasks SET status = 'pending', error = ?, progress = 0, started_at = NULL
+            WHERE id = ? AND status = 'running'"#,
+            INTERRUPTED_ERROR,
+            task_id
+        )
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Enqueue the pending and interrupted tasks of a previous run, oldest first. Those waiting
+    /// for a retry are enqueued once it is due. The number of tasks resumed.
+    pub async fn resume_tasks(&self) -> Result<usize> {
+        sqlx::query!("UPDATE tasks SET status = 'pending', progress = 0, started_at = NULL WHERE status = 'running'")
+            .execute(&self.db)
+            .await?;
+        let tasks = sqlx::query!(
+            r#"SELECT id AS "id!", retry_at FROM tasks WHERE status = 'pending'
+            ORDER BY created_at, rowid"#
+        )
+        .fetch_all(&self.db)
+        .await?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        for task in &tasks {
+            let delay = task.retry_at.map_or(0, |retry_at| (retry_at - now).max(0));
+            let sender = self._task_sender.clone();
+            let task_id = task.id.clone();
+            // The queue is bounded, the worker empties it meanwhile
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(delay as u64)).await;
+                if let Err(e) = sender.send(task_id.clone()).await {
+                    tracing::error!("Failed to resume task {}: {}", task_id, e);
+                }
+            });
+        }
+        Ok(tasks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::tests::test_task_manager;
+    use crate::worker::{PurgeTasksInput, TaskInput, TaskStatus};
+
+    #[tokio::test]
+    async fn test_interrupt_and_resume() -> Result<()> {
+        let (task_manager, mut receiver) = test_task_manager().await?;
+        let input = || {
+            TaskInput::PurgeTasks(PurgeTasksInput {
+                older_than_days: 30,
+            })
+        };
+        let interrupted = task_manager.create_task(input()).await?;
+        let queued = task_manager.create_task(input()).await?;
+        let done = task_manager.create_task(input()).await?;
+        for _ in 0..3 {
+            receiver.recv().await;
+        }
+        task_manager
+            .update_task_status(&interrupted, TaskStatus::Running, None, Some(40))
+            .await?;
+        task_manager
+            .update_task_status(&done, TaskStatus::Completed, None, Some(100))
+            .await?;
+
+        task_manager.interrupt_task(&interrupted).await?;
+        let task = task_manager.get_task_status(&interrupted).await?;
+        assert!(matches!(task.status, TaskStatus::Pending));
+        assert_eq!(task.error.as_deref(), Some(INTERRUPTED_ERROR));
+        assert_eq!((task.progress, task.retry_count), (0, 0));
+
+        assert_eq!(task_manager.resume_tasks().await?, 2);
+        let mut resumed = vec![
+            receiver.recv().await.unwrap(),
+            receiver.recv().await.unwrap(),
+        ];
+        resumed.sort();
+        let mut expected = vec![interrupted, queued];
+        expected.sort();
+        assert_eq!(resumed, expected);
+        Ok(())
+    }
+}
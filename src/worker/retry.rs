@@ -0,0 +1,166 @@
+//! Retries of the failed tasks.
+//!
+//! A task failing with a transient error (an I/O error other than a missing file or a denied
+//! access) goes back to `pending` and is re-enqueued after an exponential backoff, until it has
+//! been retried `max_retries` times. Any failed task can also be retried by hand.
+
+use anyhow::Result;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::TaskManager;
+
+pub const DEFAULT_MAX_RETRIES: i64 = 3;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Errors which may not happen again on the next attempt
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            !matches!(
+                e.kind(),
+                ErrorKind::NotFound
+                    | ErrorKind::PermissionDenied
+                    | ErrorKind::InvalidInput
+                    | ErrorKind::InvalidData
+                    | ErrorKind::Unsupported
+            )
+        })
+}
+
+/// Delay before the attempt following `retry_count` retries: 30s, 1min, 2min... up to 1h
+pub fn retry_delay(retry_count: u32) -> Duration {
+    BASE_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(retry_count))
+        .min(MAX_RETRY_DELAY)
+}
+
+impl TaskManager {
+    /// Put a task which failed with `error` back in the queue after its backoff, `None` once it
+    /// has no retry left
+    pub async fn schedule_retry(&self, task_id: &str, error: &str) -> Result<Option<Duration>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let Some(retry_count) = sqlx::query_scalar!(
+            "SELECT retry_count FROM tasks WHERE id = ? AND retry_count < max_retries",
+            task_id
+        )
+        .fetch_optional(&self.db)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let delay = retry_delay(retry_count as u32);
+        let retry_at = now + delay.as_secs() as i64;
+        sqlx::query!(
+            r#"UPDATE tasks SET status = 'pending', retry_count = retry_count + 1, retry_at = ?,
+            error = ?, progress = 0, started_at = NULL WHERE id = ?"#,
+            retry_at,
+            error,
+            task_id
+        )
+        .execute(&self.db)
+        .await?;
+
+        let sender = self._task_sender.clone();
+        let task_id = task_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = sender.send(task_id.clone()).await {
+                tracing::error!("Failed to re-enqueue task {}: {}", task_id, e);
+            }
+        });
+        Ok(Some(delay))
+    }
+
+    /// Re-enqueue a failed task right away, `false` when it does not exist or has not failed
+    pub async fn retry_task(&self, task_id: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"UPDATE tasks SET status = 'pending', retry_count = retry_count + 1, retry_at = NULL,
+            error = NULL, progress = 0, started_at = NULL, finished_at = NULL, output_data = NULL,
+            compression_ratio = NULL
+            WHERE id = ? AND status = 'failed'"#,
+            task_id
+        )
+        .execute(&self.db)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self._task_sender.send(task_id.to_string()).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::history::tests::finished_task;
+    use crate::worker::tests::test_task_manager;
+    use crate::worker::{PurgeTasksInput, TaskInput, TaskStatus};
+
+    #[test]
+    fn test_transient_errors_and_delays() {
+        let io = |kind| anyhow::Error::from(std::io::Error::from(kind));
+        assert!(is_transient(&io(ErrorKind::TimedOut)));
+        assert!(is_transient(
+            &io(ErrorKind::StorageFull).context("Writing archive")
+        ));
+        assert!(!is_transient(&io(ErrorKind::NotFound)));
+        assert!(!is_transient(&anyhow::anyhow!("ffmpeg failed")));
+
+        assert_eq!(retry_delay(0), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(120));
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_retry_until_exhausted() -> Result<()> {
+        let (task_manager, mut receiver) = test_task_manager().await?;
+        let task_id = task_manager
+            .create_task(TaskInput::PurgeTasks(PurgeTasksInput {
+                older_than_days: 30,
+            }))
+            .await?;
+        assert_eq!(receiver.recv().await.as_deref(), Some(task_id.as_str()));
+
+        for retry in 0..DEFAULT_MAX_RETRIES as u32 {
+            let delay = task_manager.schedule_retry(&task_id, "Timed out").await?;
+            assert_eq!(delay, Some(retry_delay(retry)));
+        }
+        assert_eq!(
+            task_manager.schedule_retry(&task_id, "Timed out").await?,
+            None
+        );
+
+        let task = task_manager.get_task_status(&task_id).await?;
+        assert!(matches!(task.status, TaskStatus::Pending));
+        assert_eq!(task.retry_count, DEFAULT_MAX_RETRIES);
+        assert!(task.retry_at.is_some());
+        assert_eq!(task.error.as_deref(), Some("Timed out"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_manual_retry() -> Result<()> {
+        let (task_manager, mut receiver) = test_task_manager().await?;
+        let failed = finished_task(&task_manager, TaskStatus::Failed, Some("Boom")).await?;
+        let completed = finished_task(&task_manager, TaskStatus::Completed, None).await?;
+        while receiver.try_recv().is_ok() {}
+
+        assert!(!task_manager.retry_task(&completed).await?);
+        assert!(!task_manager.retry_task("missing").await?);
+        assert!(task_manager.retry_task(&failed).await?);
+        assert_eq!(receiver.recv().await.as_deref(), Some(failed.as_str()));
+
+        let task = task_manager.get_task_status(&failed).await?;
+        assert!(matches!(task.status, TaskStatus::Pending));
+        assert_eq!(task.retry_count, 1);
+        assert_eq!(task.error, None);
+        assert_eq!(task.finished_at, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+//! Recurring tasks.
+//!
+//! A schedule creates a task from its input each time its cron expression fires, in UTC. The
+//! scheduler looks for due schedules every 30 seconds, runs missed while the server was down are
+//! skipped rather than caught up.
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use super::{TaskInput, TaskManager};
+use crate::validation::{Validate, Validator};
+
+pub const MAX_NAME_LEN: usize = 100;
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Parse a cron expression: the five crontab fields, six or seven with leading seconds and
+/// trailing years, or a shortcut such as `@daily`
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule> {
+    let expression = expression.trim();
+    let normalized = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&normalized)
+        .with_context(|| format!("Invalid cron expression {}", expression))
+}
+
+/// First occurrence of `cron` strictly after the Unix timestamp `after`
+fn next_run(cron: &cron::Schedule, after: i64) -> Option<i64> {
+    let after = DateTime::from_timestamp(after, 0)?;
+    cron.after(&after).next().map(|t| t.timestamp())
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Schedule {
+    pub id: String,
+    pub name: String,
+    pub cron: String,
+    #[serde(flatten)]
+    pub input: TaskInput,
+    pub enabled: bool,
+    pub created_at: i64,
+    pub last_run_at: Option<i64>,
+    /// Task created by the last run
+    pub last_task_id: Option<String>,
+    pub next_run_at: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ScheduleRow {
+    id: String,
+    name: String,
+    cron: String,
+    input_data: String,
+    enabled: bool,
+    created_at: i64,
+    last_run_at: Option<i64>,
+    last_task_id: Option<String>,
+    next_run_at: Option<i64>,
+}
+
+impl ScheduleRow {
+    const COLUMNS: &'static str =
+        "id, name, cron, input_data, enabled, created_at, last_run_at, last_task_id, next_run_at";
+
+    fn into_schedule(self) -> Result<Schedule> {
+        Ok(Schedule {
+            id: self.id,
+            name: self.name,
+            cron: self.cron,
+            input: serde_json::from_str(&self.input_data)?,
+            enabled: self.enabled,
+            created_at: self.created_at,
+            last_run_at: self.last_run_at,
+            last_task_id: self.last_task_id,
+            next_run_at: self.next_run_at,
+        })
+    }
+}
+
+/// A schedule to create, the task input is given as in `POST /tasks`:
+/// `{"name": "...", "cron": "0 3 * * *", "type": "CreateArchive", "data": {...}}`
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewSchedule {
+    pub name: String,
+    pub cron: String,
+    #[serde(flatten)]
+    pub input: TaskInput,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Validate for NewSchedule {
+    fn validate(&self, v: &mut Validator) {
+        v.check(
+            !self.name.trim().is_empty() && self.name.len() <= MAX_NAME_LEN,
+            "name",
+            "must be between 1 and 100 characters",
+        );
+        match parse_cron(&self.cron) {
+            Ok(cron) => v.check(
+                cron.upcoming(chrono::Utc).next().is_some(),
+                "cron",
+                "never fires again",
+            ),
+            Err(_) => v.error("cron", "must be a valid cron expression"),
+        }
+        self.input.validate(v);
+    }
+}
+
+impl TaskManager {
+    pub async fn create_schedule(&self, new: &NewSchedule) -> Result<Schedule> {
+        let id = Uuid::new_v4().to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let next_run_at = next_run(&parse_cron(&new.cron)?, now);
+        let input_data = serde_json::to_string(&new.input)?;
+        sqlx::query!(
+            r#"INSERT INTO scheduled_tasks (id, name, cron, input_data, enabled, created_at, next_run_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+            id,
+            new.name,
+            new.cron,
+            input_data,
+            new.enabled,
+            now,
+            next_run_at
+        )
+        .execute(&self.db)
+        .await?;
+        self.get_schedule(&id).await
+    }
+
+    pub async fn get_schedule(&self, schedule_id: &str) -> Result<Schedule> {
+        let row: ScheduleRow = sqlx::query_as(&format!(
+            "SELECT {} FROM scheduled_tasks WHERE id = ?",
+            ScheduleRow::COLUMNS
+        ))
+        .bind(schedule_id)
+        .fetch_one(&self.db)
+        .await?;
+        row.into_schedule()
+    }
+
+    /// Schedules by name
+    pub async fn list_schedules(&self) -> Result<Vec<Schedule>> {
+        sqlx::query_as::<_, ScheduleRow>(&format!(
+            "SELECT {} FROM scheduled_tasks ORDER BY name, id",
+            ScheduleRow::COLUMNS
+        ))
+        .fetch_all(&self.db)
+        .await?
+        .into_iter()
+        .map(ScheduleRow::into_schedule)
+        .collect()
+    }
+
+    /// Delete a schedule, the tasks it created are kept. `false` when it does not exist.
+    pub async fn delete_schedule(&self, schedule_id: &str) -> Result<bool> {
+        let result = sqlx::query!("DELETE FROM scheduled_tasks WHERE id = ?", schedule_id)
+            .execute(&self.db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Create the tasks of the schedules due at `now` and return their ids
+    pub async fn run_due_schedules(&self, now: i64) -> Result<Vec<String>> {
+        let due: Vec<ScheduleRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM scheduled_tasks WHERE enabled AND next_run_at <= ?",
+            ScheduleRow::COLUMNS
+        ))
+        .bind(now)
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut task_ids = vec![];
+        for row in due {
+            let next_run_at = parse_cron(&row.cron)
+                .ok()
+                .and_then(|cron| next_run(&cron, now));
+            let task_id = match serde_json::from_str::<TaskInput>(&row.input_data) {
+                Ok(input) => match self.create_task(input).await {
+                    Ok(task_id) => Some(task_id),
+                    Err(e) => {
+                        tracing::error!("Schedule {} failed to create its task: {}", row.id, e);
+                        None
+                    }
+                },
+                Err(e) => {
+                    tracing::error!("Schedule {} has an invalid input: {}", row.id, e);
+                    None
+                }
+            };
+            sqlx::query!(
+                r#"UPDATE scheduled_tasks SET last_run_at = ?, last_task_id = COALESCE(?, last_task_id),
+                next_run_at = ? WHERE id = ?"#,
+                now,
+                task_id,
+                next_run_at,
+                row.id
+            )
+            .execute(&self.db)
+            .await?;
+            task_ids.extend(task_id);
+        }
+        Ok(task_ids)
+    }
+
+    /// Create the tasks of the due schedules every 30 seconds
+    pub fn spawn_scheduler(&self) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(now) => now.as_secs() as i64,
+                    Err(_) => continue,
+                };
+                match task_manager.run_due_schedules(now).await {
+                    Ok(task_ids) if task_ids.is_empty() => {}
+                    Ok(task_ids) => tracing::info!("Scheduled tasks created: {:?}", task_ids),
+                    Err(e) => tracing::error!("Failed to run the due schedules: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::tests::test_task_manager;
+
+    fn new_schedule(value: serde_json::Value) -> NewSchedule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        // 2026-10-15T09:00:00Z, a Thursday
+        let now = 1_792_054_800;
+        let nightly = parse_cron("30 2 * * *").unwrap();
+        assert_eq!(next_run(&nightly, now), Some(now + 17 * 3600 + 30 * 60));
+        let weekly = parse_cron("@weekly").unwrap();
+        assert_eq!(next_run(&weekly, now), Some(now - 9 * 3600 + 3 * 24 * 3600));
+        assert!(parse_cron("61 * * * *").is_err());
+        assert!(parse_cron("every day").is_err());
+    }
+
+    #[test]
+    fn test_new_schedule_validation() {
+        let schedule = new_schedule(serde_json::json!({
+            "name": "",
+            "cron": "0 0 0 1 1 * 2001",
+            "type": "PurgeTasks",
+            "data": { "older_than_days": 0 }
+        }));
+        let mut v = Validator::default();
+        schedule.validate(&mut v);
+        let Err(crate::error::AppError::Validation(errors)) = v.finish() else {
+            panic!("Expected validation errors");
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "cron", "data.older_than_days"]);
+    }
+
+    #[tokio::test]
+    async fn test_run_due_schedules() -> Result<()> {
+        let (task_manager, mut receiver) = test_task_manager().await?;
+        let schedule = task_manager
+            .create_schedule(&new_schedule(serde_json::json!({
+                "name": "Weekly cleanup",
+                "cron": "0 4 * * 1",
+                "type": "PurgeExpiredShares"
+            })))
+            .await?;
+        task_manager
+            .create_schedule(&new_schedule(serde_json::json!({
+                "name": "Disabled",
+                "cron": "* * * * *",
+                "enabled": false,
+                "type": "PurgeTasks",
+                "data": { "older_than_days": 30 }
+            })))
+            .await?;
+        let next_run_at = schedule.next_run_at.unwrap();
+
+        assert!(task_manager
+            .run_due_schedules(next_run_at - 1)
+            .await?
+            .is_empty());
+        let task_ids = task_manager.run_due_schedules(next_run_at).await?;
+        assert_eq!(task_ids.len(), 1);
+        assert_eq!(receiver.recv().await, Some(task_ids[0].clone()));
+        assert_eq!(
+            task_manager.get_task_status(&task_ids[0]).await?.task_type,
+            "PurgeExpiredShares"
+        );
+
+        let schedule = task_manager.get_schedule(&schedule.id).await?;
+        assert_eq!(schedule.last_run_at, Some(next_run_at));
+        assert_eq!(schedule.last_task_id, Some(task_ids[0].clone()));
+        assert_eq!(schedule.next_run_at, Some(next_run_at + 7 * 24 * 3600));
+        assert_eq!(task_manager.list_schedules().await?[0].name, "Disabled");
+
+        assert!(task_manager.delete_schedule(&schedule.id).await?);
+        assert!(!task_manager.delete_schedule(&schedule.id).await?);
+        Ok(())
+    }
+}
@@ -1,24 +1,51 @@
 use anyhow::Result;
+use futures::FutureExt;
 use sevenz_rust::{self, SevenZArchiveEntry};
+use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
-use tokio::sync::watch;
 use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
 use walkdir::WalkDir;
 
-use super::{TaskInput, TaskManager, TaskStatus};
+use super::artifacts;
+use super::backup;
+use super::hashing::{HashAlgorithm, HashPool};
+use super::torrent::{self, TorrentFile, TorrentSpec};
+use crate::chaos::Fault;
+use crate::disk_space;
+use crate::file_dedup;
+use crate::filename_rules::{self, FilenameRule};
+use crate::instrumented::{CounterSink, InstrumentedStream};
+use crate::plugins::TaskOutcome;
+use crate::progress::{Event, TaskProgress, Throughput};
+use crate::share_page::FileKind;
+use crate::thumbnails;
+use crate::tus;
+use crate::virus_scan::{self, ScanStatus};
+
+use super::{
+    retry, ArchiveFormat, ArchiveInput, AssembleUploadInput, ChecksumShareInput, Compression,
+    CompressionMethod, CreateTorrentInput, DiscImageInput, GenerateThumbnailsInput, TaskInput,
+    TaskManager, TaskStatus, TranscodePreviewInput,
+};
 
 pub struct TaskWorker {
     task_manager: TaskManager,
     task_receiver: mpsc::Receiver<String>,
+    shutdown: CancellationToken,
+    grace: time::Duration,
 }
 
 #[derive(Clone)]
 struct ArchiveProgress {
     total_bytes: std::sync::Arc<std::sync::atomic::AtomicU64>,
     processed_bytes: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    written_bytes: std::sync::Arc<std::sync::atomic::AtomicU64>,
     is_complete: std::sync::Arc<std::sync::atomic::AtomicBool>,
 }
 
@@ -27,6 +54,7 @@ impl ArchiveProgress {
         Self {
             total_bytes: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(total_bytes)),
             processed_bytes: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            written_bytes: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0)),
             is_complete: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
         }
     }
@@ -41,6 +69,17 @@ impl ArchiveProgress {
         }
         ((processed as f64 / total as f64) * 100.0) as i32
     }
+
+    /// Bytes written for each byte processed, once both are known
+    fn compression_ratio(&self) -> Option<f64> {
+        let processed = self
+            .processed_bytes
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let written = self
+            .written_bytes
+            .load(std::sync::atomic::Ordering::Relaxed);
+        (processed > 0 && written > 0).then(|| written as f64 / processed as f64)
+    }
 }
 
 impl TaskWorker {
@@ -48,174 +87,1163 @@ impl TaskWorker {
         Self {
             task_manager,
             task_receiver,
+            shutdown: CancellationToken::new(),
+            grace: time::Duration::ZERO,
         }
     }
 
-    pub async fn run(&mut self) {
-        while let Some(task_id) = self.task_receiver.recv().await {
-            if let Err(e) = self.process_task(&task_id).await {
+    /// Stop taking tasks once `shutdown` is cancelled, the running one being given `grace` to
+    /// finish, see [`resume`](super::resume)
+    pub fn with_shutdown(mut self, shutdown: CancellationToken, grace: time::Duration) -> Self {
+        self.shutdown = shutdown;
+        self.grace = grace;
+        self
+    }
+
+    /// Process the tasks until the shutdown. `true` when a task was interrupted, its blocking
+    /// work may still be running.
+    pub async fn run(&mut self) -> bool {
+        loop {
+            let task_id = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled() => return false,
+                task_id = self.task_receiver.recv() => match task_id {
+                    Some(task_id) => task_id,
+                    None => return false,
+                },
+            };
+            // A panicking task fails on its own instead of stopping the worker
+            let processing = AssertUnwindSafe(self.process_task(&task_id)).catch_unwind();
+            let deadline = async {
+                self.shutdown.cancelled().await;
+                time::sleep(self.grace).await;
+            };
+            let result = tokio::select! {
+                result = processing => result,
+                _ = deadline => {
+                    log::warn!("Task {} still running after the shutdown grace period", task_id);
+                    if let Err(e) = self.task_manager.interrupt_task(&task_id).await {
+                        log::error!("Failed to mark task {} as interrupted: {}", task_id, e);
+                    }
+                    return true;
+                }
+            };
+            let result = result.unwrap_or_else(|panic| {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                Err(anyhow::anyhow!("Task panicked: {}", message))
+            });
+            if let Err(e) = result {
+                if retry::is_transient(&e) {
+                    match self
+                        .task_manager
+                        .schedule_retry(&task_id, &e.to_string())
+                        .await
+                    {
+                        Ok(Some(delay)) => {
+                            log::warn!("Task {} failed, retrying in {:?}: {}", task_id, delay, e);
+                            continue;
+                        }
+                        Ok(None) => {}
+                        Err(retry_error) => {
+                            log::error!("Failed to retry task {}: {}", task_id, retry_error)
+                        }
+                    }
+                }
                 log::error!("Task {} failed: {}", task_id, e);
                 let _ = self
                     .task_manager
                     .update_task_status(&task_id, TaskStatus::Failed, Some(e.to_string()), None)
                     .await;
             }
+            self.task_completed(&task_id).await;
         }
     }
 
+    /// Run the `task_completed` hooks of the plugins on a task which reached a final status
+    #[instrument(skip(self))]
+    async fn task_completed(&self, task_id: &str) {
+        let plugins = &self.task_manager.plugins;
+        if plugins.is_empty() {
+            return;
+        }
+        let task = match sqlx::query!(
+            r#"SELECT COALESCE(json_extract(input_data, '$.type'), task_type) AS "task_type!: String",
+            status AS "status: TaskStatus", output_data, NULLIF(error, '') AS "error: String"
+            FROM tasks WHERE id = ?"#,
+            task_id
+        )
+        .fetch_one(&self.task_manager.db)
+        .await
+        {
+            Ok(task) => task,
+            Err(e) => {
+                log::error!("Failed to load task {} for the plugins: {}", task_id, e);
+                return;
+            }
+        };
+        plugins.task_completed(&TaskOutcome {
+            task_id: task_id.to_string(),
+            task_type: task.task_type,
+            status: task.status,
+            output: task
+                .output_data
+                .and_then(|output| serde_json::from_str(&output).ok()),
+            error: task.error,
+        });
+    }
+
+    #[instrument(skip(self), fields(task_type = tracing::field::Empty), err)]
     async fn process_task(&self, task_id: &str) -> Result<()> {
         // Mark task as running
         self.task_manager
             .update_task_status(task_id, TaskStatus::Running, None, Some(0))
             .await?;
 
+        if self.task_manager.chaos.inject(Fault::WorkerPanic) {
+            panic!("Injected worker panic");
+        }
+        self.task_manager.chaos.db_error()?;
+
         // Get task details
         let task_data = sqlx::query!("SELECT input_data FROM tasks WHERE id = ?", task_id)
             .fetch_one(&self.task_manager.db)
             .await?;
 
         let input: TaskInput = serde_json::from_str(&task_data.input_data)?;
+        if let Some(task_type) = serde_json::to_value(&input)?.get("type") {
+            tracing::Span::current().record("task_type", task_type.as_str());
+        }
 
-        match input {
+        let output_data = match input {
             TaskInput::CreateArchive(archive_input) => {
-                // Calculate total size of files to compress
-                let mut total_size = 0u64;
-                if let Some(dir) = &archive_input.directory {
-                    for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
-                        if entry.file_type().is_file() {
-                            if let Ok(metadata) = entry.metadata() {
-                                total_size += metadata.len();
-                            }
-                        }
-                    }
-                } else if let Some(files) = &archive_input.files {
-                    for file in files {
-                        if let Ok(metadata) = std::fs::metadata(file) {
-                            total_size += metadata.len();
-                        }
-                    }
-                }
+                self.create_archive(task_id, archive_input).await?
+            }
+            TaskInput::ChecksumShare(checksum_input) => {
+                self.checksum_share(task_id, checksum_input).await?
+            }
+            TaskInput::TranscodePreview(transcode_input) => {
+                self.transcode_preview(transcode_input).await?
+            }
+            TaskInput::DiscImage(image_input) => self.disc_image(task_id, image_input).await?,
+            TaskInput::CreateTorrent(torrent_input) => {
+                self.create_torrent(task_id, torrent_input).await?
+            }
+            TaskInput::AssembleUpload(upload_input) => {
+                self.assemble_upload(task_id, upload_input).await?
+            }
+            TaskInput::GenerateThumbnails(thumbnails_input) => {
+                self.generate_thumbnails(task_id, thumbnails_input).await?
+            }
+            TaskInput::PurgeTasks(purge_input) => {
+                let purged = self
+                    .task_manager
+                    .prune_finished_tasks(std::time::Duration::from_secs(
+                        purge_input.older_than_days as u64 * 24 * 3600,
+                    ))
+                    .await?;
+                serde_json::json!({ "purged": purged })
+            }
+            TaskInput::PurgeExpiredShares => self.purge_expired_shares().await?,
+            TaskInput::PurgeDeletedShares(purge_input) => {
+                let purged = self
+                    .task_manager
+                    .purge_deleted_shares(std::time::Duration::from_secs(
+                        purge_input.older_than_days as u64 * 24 * 3600,
+                    ))
+                    .await?;
+                serde_json::json!({ "purged": purged })
+            }
+            TaskInput::BackupDatabase(backup_input) => {
+                let config = crate::ServerConfig::new();
+                let keep = backup_input
+                    .keep
+                    .map_or(config.backup_keep, |keep| keep as usize);
+                let backups_dir = config.data_dir.join(backup::BACKUPS_DIR_NAME);
+                let backup =
+                    backup::backup_database(&self.task_manager.db, &backups_dir, keep).await?;
+                serde_json::to_value(backup)?
+            }
+            TaskInput::VerifyFiles(verify_input) => {
+                self.task_manager.verify_files(&verify_input).await?
+            }
+            TaskInput::CleanupArtifacts(cleanup_input) => {
+                let report = self
+                    .task_manager
+                    .cleanup_artifacts(
+                        std::time::Duration::from_secs(
+                            cleanup_input.older_than_days as u64 * 24 * 3600,
+                        ),
+                        cleanup_input.dry_run,
+                    )
+                    .await?;
+                serde_json::to_value(report)?
+            }
+        };
+
+        // Update task as completed
+        self.task_manager
+            .update_task_status(task_id, TaskStatus::Completed, None, Some(100))
+            .await?;
+
+        // Store output data
+        let output_data = output_data.to_string();
+        sqlx::query!(
+            "UPDATE tasks SET output_data = ? WHERE id = ?",
+            output_data,
+            task_id
+        )
+        .execute(&self.task_manager.db)
+        .await?;
+
+        Ok(())
+    }
 
-                // Create progress tracker
-                let progress = ArchiveProgress::new(total_size);
-                let progress_clone = progress.clone();
-
-                // Spawn progress monitoring task
-                let task_manager = self.task_manager.clone();
-                let task_id_clone = task_id.to_string();
-                tokio::spawn(async move {
-                    while !progress_clone
-                        .is_complete
-                        .load(std::sync::atomic::Ordering::Relaxed)
+    /// Periodically store the progress of a task and send it to the live feed until the returned
+    /// guard is dropped
+    fn monitor_progress(&self, task_id: &str, progress: &ArchiveProgress) -> ProgressGuard {
+        self.broadcast_progress(task_id, progress);
+        let progress_clone = progress.clone();
+        let task_manager = self.task_manager.clone();
+        let task_id_clone = task_id.to_string();
+        tokio::spawn(async move {
+            while !progress_clone
+                .is_complete
+                .load(std::sync::atomic::Ordering::Relaxed)
+            {
+                let progress_percentage = progress_clone.get_progress_percentage();
+                if let Err(e) = task_manager
+                    .update_task_status(
+                        &task_id_clone,
+                        TaskStatus::Running,
+                        None,
+                        Some(progress_percentage),
+                    )
+                    .await
+                {
+                    log::error!("Failed to update task progress: {}", e);
+                }
+                if let Some(ratio) = progress_clone.compression_ratio() {
+                    if let Err(e) = task_manager
+                        .update_task_compression_ratio(&task_id_clone, ratio)
+                        .await
                     {
-                        let progress_percentage = progress_clone.get_progress_percentage();
-                        if let Err(e) = task_manager
-                            .update_task_status(
-                                &task_id_clone,
-                                TaskStatus::Running,
-                                None,
-                                Some(progress_percentage),
-                            )
-                            .await
-                        {
-                            log::error!("Failed to update task progress: {}", e);
-                        }
-                        time::sleep(time::Duration::from_secs(10)).await;
+                        log::error!("Failed to update task compression ratio: {}", e);
                     }
-                });
-
-                let result = if let Some(dir) = archive_input.directory {
-                    create_7z_archive_with_progress(
-                        vec![dir],
-                        archive_input.output_path,
-                        archive_input.password,
-                        progress.clone(),
-                    )
-                    .await?
-                } else if let Some(files) = archive_input.files {
-                    create_7z_archive_with_progress(
-                        files,
-                        archive_input.output_path,
-                        archive_input.password,
-                        progress.clone(),
-                    )
-                    .await?
-                } else {
-                    anyhow::bail!("Either directory or files must be specified");
-                };
+                }
+                time::sleep(time::Duration::from_secs(10)).await;
+            }
+        });
+        ProgressGuard(progress.clone())
+    }
 
-                // Mark progress as complete
-                progress
+    /// Send the progress of a task to the live feed every second until the guard of `progress` is
+    /// dropped, then a last time
+    fn broadcast_progress(&self, task_id: &str, progress: &ArchiveProgress) {
+        let progress = progress.clone();
+        let task_manager = self.task_manager.clone();
+        let task_id = task_id.to_string();
+        tokio::spawn(async move {
+            let task_type = match task_manager.get_task_status(&task_id).await {
+                Ok(task) => task.task_type,
+                Err(e) => {
+                    tracing::error!("Failed to find task {}: {}", task_id, e);
+                    return;
+                }
+            };
+            let mut throughput = Throughput::new(std::time::Instant::now());
+            loop {
+                let complete = progress
                     .is_complete
-                    .store(true, std::sync::atomic::Ordering::Relaxed);
+                    .load(std::sync::atomic::Ordering::Relaxed);
+                let bytes = progress
+                    .processed_bytes
+                    .load(std::sync::atomic::Ordering::Relaxed);
+                let total_bytes = progress
+                    .total_bytes
+                    .load(std::sync::atomic::Ordering::Relaxed);
+                let _ = task_manager
+                    .progress
+                    .send(Event::TaskProgress(TaskProgress {
+                        task_id: task_id.clone(),
+                        task_type: task_type.clone(),
+                        percent: progress.get_progress_percentage(),
+                        bytes,
+                        total_bytes,
+                        compression_ratio: progress.compression_ratio(),
+                        rate: throughput.update(std::time::Instant::now(), bytes, total_bytes),
+                        finished: complete,
+                    }));
+                if complete {
+                    break;
+                }
+                time::sleep(time::Duration::from_secs(1)).await;
+            }
+        });
+    }
+
+    #[instrument(skip_all, fields(format = ?archive_input.format, total_size))]
+    async fn create_archive(
+        &self,
+        task_id: &str,
+        archive_input: ArchiveInput,
+    ) -> Result<serde_json::Value> {
+        // Calculate total size of files to compress
+        let mut total_size = 0u64;
+        if let Some(dir) = &archive_input.directory {
+            for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    if let Ok(metadata) = entry.metadata() {
+                        total_size += metadata.len();
+                    }
+                }
+            }
+        } else if let Some(files) = &archive_input.files {
+            for file in files {
+                if let Ok(metadata) = std::fs::metadata(file) {
+                    total_size += metadata.len();
+                }
+            }
+        }
+
+        tracing::Span::current().record("total_size", total_size);
+        // Stored entries take as much space as the files, compressed ones rarely much less
+        let config = crate::ServerConfig::new();
+        disk_space::ensure_space(&archive_input.output_path, total_size, config.disk_reserve)?;
+
+        // Create progress tracker
+        let progress = ArchiveProgress::new(total_size);
+        let _monitor = self.monitor_progress(task_id, &progress);
 
-                // Update task as completed
-                self.task_manager
-                    .update_task_status(task_id, TaskStatus::Completed, None, Some(100))
+        let result = if let Some(dir) = archive_input.directory {
+            create_archive_with_progress(
+                vec![dir],
+                archive_input.output_path,
+                archive_input.format,
+                archive_input.compression,
+                archive_input.password,
+                &archive_input.filename_rules,
+                progress.clone(),
+            )
+            .await?
+        } else if let Some(files) = archive_input.files {
+            create_archive_with_progress(
+                files,
+                archive_input.output_path,
+                archive_input.format,
+                archive_input.compression,
+                archive_input.password,
+                &archive_input.filename_rules,
+                progress.clone(),
+            )
+            .await?
+        } else {
+            anyhow::bail!("Either directory or files must be specified");
+        };
+
+        if total_size > 0 {
+            let archive_size = tokio::fs::metadata(&result).await?.len();
+            self.task_manager
+                .update_task_compression_ratio(task_id, archive_size as f64 / total_size as f64)
+                .await?;
+        }
+
+        let share_url = if archive_input.create_share {
+            let expires_at = archive_input
+                .share_expires_in
+                .map(|secs| chrono::offset::Utc::now().timestamp() + secs);
+            Some(
+                crate::publish_files(
+                    vec![config.shareable_roots().resolve(&result)?],
+                    &config.host,
+                    &self.task_manager.db,
+                    &self.task_manager.plugins,
+                    crate::ShareOptions {
+                        expires_at,
+                        ..Default::default()
+                    },
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        artifacts::record(&self.task_manager.db, &result, "CreateArchive", None).await?;
+
+        Ok(serde_json::json!({
+            "archive_path": result,
+            "share_url": share_url,
+        }))
+    }
+
+    /// Hash every file of a share in parallel, store the digests and attach a SHA256SUMS or B3SUMS
+    /// file to the share
+    #[instrument(skip_all, fields(share_id = %checksum_input.share_id))]
+    async fn checksum_share(
+        &self,
+        task_id: &str,
+        checksum_input: ChecksumShareInput,
+    ) -> Result<serde_json::Value> {
+        let share_id = checksum_input.share_id;
+        let algorithm = checksum_input.algorithm;
+        let config = crate::ServerConfig::new();
+        let sums_dir = config.data_dir.join("checksums").join(&share_id);
+        let sums_path = sums_dir.join(algorithm.manifest_name());
+        let sums_path_str = sums_path.to_string_lossy().into_owned();
+
+        // Previous manifests of the share are replaced or kept, not hashed
+        let manifests = [HashAlgorithm::Sha256, HashAlgorithm::Blake3].map(|a| {
+            sums_dir
+                .join(a.manifest_name())
+                .to_string_lossy()
+                .into_owned()
+        });
+        let files = sqlx::query!(
+            r#"SELECT files.id as "id!", files.path, files.file_size
+            FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+            WHERE share_link_files.share_link_id = ? AND files.path NOT IN (?, ?)"#,
+            share_id,
+            manifests[0],
+            manifests[1]
+        )
+        .fetch_all(&self.task_manager.db)
+        .await?;
+        if files.is_empty() {
+            anyhow::bail!("Share {} has no files", share_id);
+        }
+
+        let total_size = files
+            .iter()
+            .map(|f| f.file_size.unwrap_or(0).max(0) as u64)
+            .sum();
+        let progress = ArchiveProgress::new(total_size);
+        let _monitor = self.monitor_progress(task_id, &progress);
+
+        let pool = HashPool::new(config.hash_concurrency, config.hash_concurrency_per_root);
+        let hashes = pool
+            .hash_files(
+                files.iter().map(|f| PathBuf::from(&f.path)).collect(),
+                algorithm,
+                progress.processed_bytes.clone(),
+            )
+            .await?;
+
+        let mut sums = String::new();
+        let algorithm_name = algorithm.name();
+        for (file, hash) in files.iter().zip(&hashes) {
+            let path = PathBuf::from(&file.path);
+            sqlx::query!(
+                r#"INSERT INTO file_digests (file_id, algorithm, digest) VALUES (?, ?, ?)
+                ON CONFLICT (file_id, algorithm) DO UPDATE SET digest = excluded.digest"#,
+                file.id,
+                algorithm_name,
+                hash
+            )
+            .execute(&self.task_manager.db)
+            .await?;
+            if algorithm == HashAlgorithm::Sha256 {
+                sqlx::query!("UPDATE files SET sha256 = ? WHERE id = ?", hash, file.id)
+                    .execute(&self.task_manager.db)
                     .await?;
+                self.merge_duplicate(&config.data_dir, file.id).await?;
+            }
+
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| file.path.clone());
+            sums.push_str(&format!("{}  {}\n", hash, name));
+        }
+
+        if let Some(dir) = sums_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&sums_path, &sums).await?;
+        let sums_hash = hex_sha256(sums.as_bytes());
+        self.attach_to_share(&share_id, &sums_path_str, &sums_hash, sums.len() as i64)
+            .await?;
+        artifacts::record(
+            &self.task_manager.db,
+            &sums_path,
+            "ChecksumShare",
+            Some(&share_id),
+        )
+        .await?;
+
+        Ok(serde_json::json!({
+            "sums_path": sums_path,
+            "algorithm": algorithm,
+            "file_count": files.len(),
+        }))
+    }
+
+    /// Generate the preview of a shared video and attach it to the file
+    #[instrument(skip_all, fields(share_id = %transcode_input.share_id, file_id = transcode_input.file_id))]
+    async fn transcode_preview(
+        &self,
+        transcode_input: TranscodePreviewInput,
+    ) -> Result<serde_json::Value> {
+        let file = sqlx::query!(
+            r#"SELECT files.path, share_links.encrypted
+            FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+            JOIN share_links ON share_links.id=share_link_files.share_link_id
+            WHERE files.id = ? AND share_link_files.share_link_id = ?"#,
+            transcode_input.file_id,
+            transcode_input.share_id
+        )
+        .fetch_optional(&self.task_manager.db)
+        .await?
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "File {} is not part of share {}",
+                transcode_input.file_id,
+                transcode_input.share_id
+            )
+        })?;
+
+        if file.encrypted {
+            anyhow::bail!(
+                "Share {} is end-to-end encrypted, its files can't be transcoded",
+                transcode_input.share_id
+            );
+        }
+
+        let source = PathBuf::from(&file.path);
+        let preview_path = source.with_extension("preview.mp4");
+        let max_height = transcode_input
+            .max_height
+            .unwrap_or(TranscodePreviewInput::DEFAULT_MAX_HEIGHT);
+        run_ffmpeg(&source, &preview_path, max_height).await?;
+
+        let preview_path_str = preview_path.to_string_lossy().into_owned();
+        sqlx::query!(
+            "UPDATE files SET preview_path = ? WHERE id = ?",
+            preview_path_str,
+            transcode_input.file_id
+        )
+        .execute(&self.task_manager.db)
+        .await?;
+        self.task_manager
+            .share_cache
+            .invalidate(&transcode_input.share_id);
+        artifacts::record(
+            &self.task_manager.db,
+            &preview_path,
+            "TranscodePreview",
+            Some(&transcode_input.share_id),
+        )
+        .await?;
+
+        Ok(serde_json::json!({
+            "preview_path": preview_path,
+        }))
+    }
+
+    /// Generate the thumbnails of the images of a share, the failures are counted and logged
+    #[instrument(skip_all, fields(share_id = %thumbnails_input.share_id))]
+    async fn generate_thumbnails(
+        &self,
+        task_id: &str,
+        thumbnails_input: GenerateThumbnailsInput,
+    ) -> Result<serde_json::Value> {
+        let share_id = thumbnails_input.share_id;
+        let share = sqlx::query!("SELECT encrypted FROM share_links WHERE id = ?", share_id)
+            .fetch_optional(&self.task_manager.db)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No share {}", share_id))?;
+        if share.encrypted {
+            anyhow::bail!(
+                "Share {} is end-to-end encrypted, its images have no thumbnails",
+                share_id
+            );
+        }
+        let files = sqlx::query!(
+            r#"SELECT files.id AS "id!", files.path
+            FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+            WHERE share_link_files.share_link_id = ?"#,
+            share_id
+        )
+        .fetch_all(&self.task_manager.db)
+        .await?;
+        let images: Vec<_> = files
+            .into_iter()
+            .filter(|file| {
+                let name = Path::new(&file.path).file_name().unwrap_or_default();
+                FileKind::from_name(&name.to_string_lossy()) == FileKind::Image
+            })
+            .collect();
+        let widths = thumbnails_input
+            .widths
+            .unwrap_or(vec![thumbnails::DEFAULT_WIDTH]);
+
+        let config = crate::ServerConfig::new();
+        let (mut generated, mut failed) = (0, 0);
+        for (i, image) in images.iter().enumerate() {
+            for width in &widths {
+                match thumbnails::get_or_create(
+                    &self.task_manager.db,
+                    &config,
+                    &share_id,
+                    image.id,
+                    Path::new(&image.path),
+                    *width,
+                )
+                .await
+                {
+                    Ok(_) => generated += 1,
+                    Err(e) => {
+                        log::warn!("No thumbnail of file {}: {}", image.id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            let progress = ((i + 1) * 100 / images.len()) as i32;
+            self.task_manager
+                .update_task_status(task_id, TaskStatus::Running, None, Some(progress))
+                .await?;
+        }
+        Ok(serde_json::json!({
+            "images": images.len(),
+            "generated": generated,
+            "failed": failed,
+        }))
+    }
+
+    /// Build a disc image of a share's files and attach it to the share
+    #[instrument(skip_all, fields(share_id = %image_input.share_id, media = %image_input.media))]
+    async fn disc_image(
+        &self,
+        task_id: &str,
+        image_input: DiscImageInput,
+    ) -> Result<serde_json::Value> {
+        let share_id = image_input.share_id;
+        let config = crate::ServerConfig::new();
+        let image_path = config
+            .data_dir
+            .join("images")
+            .join(format!("{}.iso", share_id));
+        let image_path_str = image_path.to_string_lossy().into_owned();
+
+        // A previous image of the share is replaced, not included
+        let files = sqlx::query!(
+            r#"SELECT files.path, files.file_size
+            FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+            WHERE share_link_files.share_link_id = ? AND files.path != ?"#,
+            share_id,
+            image_path_str
+        )
+        .fetch_all(&self.task_manager.db)
+        .await?;
+        if files.is_empty() {
+            anyhow::bail!("Share {} has no files", share_id);
+        }
+
+        let media = image_input.media;
+        let total_size: u64 = files
+            .iter()
+            .map(|f| f.file_size.unwrap_or(0).max(0) as u64)
+            .sum();
+        if total_size > media.capacity() {
+            anyhow::bail!(
+                "Share {} files take {} bytes, more than a {} can hold ({} bytes)",
+                share_id,
+                total_size,
+                media,
+                media.capacity()
+            );
+        }
+
+        disk_space::ensure_space(&image_path, total_size, config.disk_reserve)?;
+        if let Some(dir) = image_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let volume_label = image_input
+            .volume_label
+            .unwrap_or_else(|| share_id.to_uppercase());
+        let sources: Vec<PathBuf> = files.iter().map(|f| PathBuf::from(&f.path)).collect();
+
+        let progress = ArchiveProgress::new(total_size);
+        let _monitor = self.monitor_progress(task_id, &progress);
+        run_mkisofs(
+            &config.mkisofs_path,
+            &sources,
+            &image_path,
+            &volume_label,
+            &progress,
+        )
+        .await?;
+
+        // The file system overhead may still push the image over the media capacity
+        let image_size = tokio::fs::metadata(&image_path).await?.len();
+        if image_size > media.capacity() {
+            tokio::fs::remove_file(&image_path).await?;
+            anyhow::bail!(
+                "Image of share {} takes {} bytes, more than a {} can hold ({} bytes)",
+                share_id,
+                image_size,
+                media,
+                media.capacity()
+            );
+        }
+
+        self.attach_to_share(&share_id, &image_path_str, "", image_size as i64)
+            .await?;
+        artifacts::record(
+            &self.task_manager.db,
+            &image_path,
+            "DiscImage",
+            Some(&share_id),
+        )
+        .await?;
+
+        Ok(serde_json::json!({
+            "image_path": image_path,
+            "image_size": image_size,
+            "media_capacity": media.capacity(),
+        }))
+    }
+
+    #[instrument(skip_all, fields(share_id = %torrent_input.share_id))]
+    async fn create_torrent(
+        &self,
+        task_id: &str,
+        torrent_input: CreateTorrentInput,
+    ) -> Result<serde_json::Value> {
+        let share_id = torrent_input.share_id;
+        let config = crate::ServerConfig::new();
+        let share = sqlx::query!(
+            "SELECT alias, encrypted FROM share_links WHERE id = ?",
+            share_id
+        )
+        .fetch_optional(&self.task_manager.db)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Share {} not found", share_id))?;
+        // Pieces of ciphertext would be useless without the key of the URL fragment
+        if share.encrypted {
+            anyhow::bail!("Share {} is encrypted", share_id);
+        }
+
+        let rows = sqlx::query!(
+            r#"SELECT files.path, files.file_size
+            FROM files JOIN share_link_files ON share_link_files.file_id=files.id
+            WHERE share_link_files.share_link_id = ?
+            ORDER BY files.id"#,
+            share_id
+        )
+        .fetch_all(&self.task_manager.db)
+        .await?;
+        if rows.is_empty() {
+            anyhow::bail!("Share {} has no files", share_id);
+        }
+        let mut files: Vec<TorrentFile> = Vec::with_capacity(rows.len());
+        for row in rows {
+            let path = PathBuf::from(&row.path);
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            // The web seed finds the files of the share by name
+            if files.iter().any(|file| file.name == name) {
+                anyhow::bail!("Share {} has several files named {}", share_id, name);
+            }
+            files.push(TorrentFile {
+                path,
+                name,
+                size: row.file_size.unwrap_or(0).max(0) as u64,
+            });
+        }
+
+        let slug = share.alias.unwrap_or_else(|| share_id.clone());
+        let name = match files.as_slice() {
+            [file] => file.name.clone(),
+            _ => slug.clone(),
+        };
+        let total_size: u64 = files.iter().map(|file| file.size).sum();
+        let piece_length = torrent_input
+            .piece_length
+            .unwrap_or_else(|| torrent::piece_length(total_size));
+        let spec = TorrentSpec {
+            name,
+            files,
+            piece_length,
+            trackers: torrent_input.trackers.unwrap_or(config.torrent_trackers),
+            web_seed: format!("{}/s/{}/seed/", config.host, share_id),
+            comment: format!("{}/s/{}", config.host, slug),
+        };
+
+        let progress = ArchiveProgress::new(total_size);
+        let _monitor = self.monitor_progress(task_id, &progress);
+        let processed = progress.processed_bytes.clone();
+        let built =
+            tokio::task::spawn_blocking(move || torrent::build(&spec, &processed)).await??;
+
+        let torrent_path = torrent::torrent_path(&config.data_dir, &share_id);
+        if let Some(dir) = torrent_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&torrent_path, &built.metainfo).await?;
+        artifacts::record(
+            &self.task_manager.db,
+            &torrent_path,
+            "CreateTorrent",
+            Some(&share_id),
+        )
+        .await?;
+
+        Ok(serde_json::json!({
+            "torrent_path": torrent_path,
+            "torrent_url": format!("{}/s/{}/share.torrent", config.host, share_id),
+            "info_hash": built.info_hash,
+            "piece_length": piece_length,
+            "pieces": built.pieces,
+        }))
+    }
+
+    /// Concatenate the chunks of an upload and add the file to the share of the upload, or to a
+    /// new share
+    #[instrument(skip_all, fields(upload_id = %upload_input.upload_id))]
+    async fn assemble_upload(
+        &self,
+        task_id: &str,
+        upload_input: AssembleUploadInput,
+    ) -> Result<serde_json::Value> {
+        let upload_id = upload_input.upload_id;
+        let config = crate::ServerConfig::new();
+        let uploads = tus::Uploads::new(&config.data_dir, config.upload_expiry)
+            .with_reserve(config.disk_reserve);
+        let (upload, _) = uploads
+            .get(&upload_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No upload {}", upload_id))?;
+        if let Some(share_id) = &upload.share_id {
+            sqlx::query!("SELECT id FROM share_links WHERE id = ?", share_id)
+                .fetch_optional(&self.task_manager.db)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Share {} not found", share_id))?;
+        }
 
-                // Store output data
-                let output_data = serde_json::json!({
-                    "archive_path": result
-                })
-                .to_string();
+        let progress = ArchiveProgress::new(upload.length);
+        let _monitor = self.monitor_progress(task_id, &progress);
+        let tus::Assembled {
+            upload,
+            path,
+            sha256,
+        } = uploads
+            .assemble(&upload_id, progress.processed_bytes.clone())
+            .await?;
+        let upload = match upload.scan {
+            None if !config.scanners.is_empty() => {
+                let scan = virus_scan::scan(&config.scanners, &path).await?;
+                uploads.record_scan(&upload_id, scan).await?
+            }
+            _ => upload,
+        };
+        if let Some(scan) = upload
+            .scan
+            .as_ref()
+            .filter(|scan| scan.status == ScanStatus::Infected)
+        {
+            anyhow::bail!(
+                "Upload {} is infected: {}, quarantined in {}",
+                upload_id,
+                scan.detail.as_deref().unwrap_or_default(),
+                upload.path.unwrap_or_default().display()
+            );
+        }
+        let path = uploads.publish(&upload_id).await?;
+        let file = config.shareable_roots().resolve(&path)?;
+        let path_str = file.path().to_string_lossy().into_owned();
 
-                sqlx::query!(
-                    "UPDATE tasks SET output_data = ? WHERE id = ?",
-                    output_data,
-                    task_id
+        let (file_id, share_url) = match &upload.share_id {
+            Some(share_id) => {
+                let file_id = self
+                    .attach_to_share(share_id, &path_str, &sha256, upload.length as i64)
+                    .await?;
+                (file_id, None)
+            }
+            None => {
+                let share_url = crate::publish_files(
+                    vec![file],
+                    &config.host,
+                    &self.task_manager.db,
+                    &self.task_manager.plugins,
+                    crate::ShareOptions::default(),
+                )
+                .await?;
+                let file_id = sqlx::query_scalar!(
+                    r#"UPDATE files SET sha256 = ? WHERE path = ? RETURNING id AS "id!""#,
+                    sha256,
+                    path_str
                 )
-                .execute(&self.task_manager.db)
+                .fetch_one(&self.task_manager.db)
                 .await?;
+                (file_id, Some(share_url))
             }
+        };
+        self.merge_duplicate(&config.data_dir, file_id).await?;
+
+        Ok(serde_json::json!({
+            "path": path_str,
+            "sha256": sha256,
+            "file_size": upload.length,
+            "share_id": upload.share_id,
+            "share_url": share_url,
+        }))
+    }
+
+    /// Delete the expired shares with their files rows which no other share links to
+    #[instrument(skip(self))]
+    async fn purge_expired_shares(&self) -> Result<serde_json::Value> {
+        let now = chrono::offset::Utc::now().timestamp();
+        let share_ids = sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM share_links WHERE expiration >= 0 AND expiration <= ?"#,
+            now
+        )
+        .fetch_all(&self.task_manager.db)
+        .await?;
+        self.task_manager.purge_shares(&share_ids).await?;
+
+        for share_id in &share_ids {
+            self.task_manager.share_cache.invalidate(share_id);
         }
+        Ok(serde_json::json!({ "purged": share_ids.len() }))
+    }
 
+    /// Merge a freshly hashed file into an identical files row, see [`file_dedup`]
+    async fn merge_duplicate(&self, data_dir: &Path, file_id: i64) -> Result<()> {
+        if let Some(merged) = file_dedup::merge(&self.task_manager.db, data_dir, file_id).await? {
+            tracing::info!("Merged file {} into file {}", file_id, merged.file_id);
+            for share_id in &merged.share_ids {
+                self.task_manager.share_cache.invalidate(share_id);
+            }
+        }
         Ok(())
     }
+
+    /// Add a generated file to a share, replacing the one previously generated at the same path.
+    /// The id of its files row.
+    async fn attach_to_share(
+        &self,
+        share_id: &str,
+        path: &str,
+        sha256: &str,
+        file_size: i64,
+    ) -> Result<i64> {
+        let mut tx = self.task_manager.db.begin().await?;
+        let replaced = sqlx::query_scalar!(
+            r#"DELETE FROM share_link_files WHERE share_link_id = ? AND file_id IN (SELECT id FROM files WHERE path = ?)
+            RETURNING file_id AS "file_id!""#,
+            share_id,
+            path
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        file_dedup::delete_unreferenced(&mut tx, &replaced).await?;
+        let file_id = sqlx::query!(
+            "INSERT INTO files (sha256, path, file_size) VALUES ($1, $2, $3)",
+            sha256,
+            path,
+            file_size
+        )
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        if !sha256.is_empty() {
+            sqlx::query!(
+                "INSERT INTO file_digests (file_id, algorithm, digest) VALUES (?, 'sha256', ?)",
+                file_id,
+                sha256
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query!(
+            "INSERT INTO share_link_files (share_link_id, file_id) VALUES ($1, $2)",
+            share_id,
+            file_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.task_manager.share_cache.invalidate(share_id);
+        Ok(file_id)
+    }
 }
 
-/// A reader that tracks the number of bytes read
-struct ProgressReader<R: Read> {
-    inner: R,
-    progress: ArchiveProgress,
+/// Write an ISO 9660 image with Joliet, Rock Ridge and UDF file systems holding `sources` at its
+/// root. The progress is the size of the image written so far.
+async fn run_mkisofs(
+    mkisofs: &str,
+    sources: &[PathBuf],
+    output: &Path,
+    volume_label: &str,
+    progress: &ArchiveProgress,
+) -> Result<()> {
+    let mut child = tokio::process::Command::new(mkisofs)
+        .args([
+            "-quiet",
+            "-iso-level",
+            "3",
+            "-J",
+            "-joliet-long",
+            "-R",
+            "-udf",
+        ])
+        .args(["-graft-points", "-V", volume_label, "-o"])
+        .arg(output)
+        .args(sources.iter().map(|source| graft_point(source)))
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", mkisofs, e))?;
+
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stderr = tokio::spawn(async move {
+        let mut output = String::new();
+        let _ = tokio::io::AsyncReadExt::read_to_string(&mut stderr, &mut output).await;
+        output
+    });
+
+    let mut interval = time::interval(time::Duration::from_secs(1));
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status?,
+            _ = interval.tick() => {
+                if let Ok(metadata) = tokio::fs::metadata(output).await {
+                    progress
+                        .processed_bytes
+                        .store(metadata.len(), std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        }
+    };
+
+    if !status.success() {
+        let _ = tokio::fs::remove_file(output).await;
+        anyhow::bail!(
+            "mkisofs failed ({}): {}",
+            status,
+            stderr.await.unwrap_or_default().trim()
+        );
+    }
+    Ok(())
+}
+
+/// mkisofs `name=path` argument placing `source` at the image root
+fn graft_point(source: &Path) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('=', "\\=");
+    let name = source
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}={}", escape(&name), escape(&source.to_string_lossy()))
 }
 
-impl<R: Read> ProgressReader<R> {
-    fn new(inner: R, progress: ArchiveProgress) -> Self {
-        Self { inner, progress }
+/// Transcode `source` into a small H.264/AAC MP4 which can start playing before it is fully
+/// downloaded
+#[cfg(feature = "transcode")]
+async fn run_ffmpeg(source: &Path, output: &Path, max_height: u32) -> Result<()> {
+    let ffmpeg = crate::ServerConfig::new().ffmpeg_path;
+    let result = tokio::process::Command::new(&ffmpeg)
+        .args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+        .arg(source)
+        .args(["-vf", &format!("scale=-2:'min({},ih)'", max_height)])
+        .args(["-c:v", "libx264", "-preset", "veryfast", "-crf", "30"])
+        .args(["-maxrate", "1M", "-bufsize", "2M"])
+        .args(["-c:a", "aac", "-b:a", "96k", "-movflags", "+faststart"])
+        .arg(output)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", ffmpeg, e))?;
+
+    if !result.status.success() {
+        anyhow::bail!(
+            "ffmpeg failed ({}): {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
     }
+    Ok(())
 }
 
-impl<R: Read> Read for ProgressReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let n = self.inner.read(buf)?;
-        if n > 0 {
-            self.progress
-                .processed_bytes
-                .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
-        }
-        Ok(n)
+#[cfg(not(feature = "transcode"))]
+async fn run_ffmpeg(_source: &Path, _output: &Path, _max_height: u32) -> Result<()> {
+    anyhow::bail!("hardwire was built without the transcode feature")
+}
+
+/// Marks the tracked progress as complete when dropped, which stops its monitoring task
+struct ProgressGuard(ArchiveProgress);
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        self.0
+            .is_complete
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+impl ArchiveProgress {
+    /// Wrap a reader so the bytes read from it count as processed
+    fn reader<R: Read>(&self, inner: R) -> InstrumentedStream<R, CounterSink> {
+        InstrumentedStream::new(inner, CounterSink(self.processed_bytes.clone()))
+    }
+
+    /// Wrap the output of an archive so the bytes written to it count as written
+    fn writer<W: Write>(&self, inner: W) -> InstrumentedStream<W, CounterSink> {
+        InstrumentedStream::new(inner, CounterSink(self.written_bytes.clone()))
+    }
+}
+
+/// Extensions of files which don't get smaller when compressed again
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "avi", "bz2", "flac", "gif", "gz", "jpeg", "jpg", "m4a", "m4v", "mkv", "mov",
+    "mp3", "mp4", "ogg", "opus", "png", "rar", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// `output_path` ending with the extension of `format`
+fn with_archive_extension(output_path: PathBuf, format: ArchiveFormat) -> PathBuf {
+    let extension = format.extension();
+    let has_extension = output_path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(&format!(".{}", extension)));
+    if has_extension {
+        output_path
+    } else {
+        output_path.with_extension(extension)
     }
 }
 
-/// Create a 7z archive with progress tracking
-async fn create_7z_archive_with_progress<P: AsRef<Path>>(
+/// Create an archive with progress tracking
+#[instrument(skip_all, fields(output_path = %output_path.display()))]
+async fn create_archive_with_progress<P: AsRef<Path>>(
     source: Vec<P>,
     output_path: PathBuf,
+    format: ArchiveFormat,
+    compression: Compression,
     password: Option<String>,
+    filename_rules: &[FilenameRule],
     progress: ArchiveProgress,
 ) -> Result<PathBuf> {
-    // Ensure output path has .7z extension
-    let output_path = if !output_path.extension().map_or(false, |ext| ext == "7z") {
-        output_path.with_extension("7z")
-    } else {
-        output_path
+    let output_path = with_archive_extension(output_path, format);
+    let today = chrono::offset::Utc::now().date_naive();
+    let rewrite = |name: &Path| -> PathBuf {
+        match name.file_name() {
+            Some(file_name) if !filename_rules.is_empty() => name.with_file_name(
+                filename_rules::rewrite(&file_name.to_string_lossy(), filename_rules, today),
+            ),
+            _ => name.to_path_buf(),
+        }
     };
 
-    // Create the output file
-    let output_file = File::create(&output_path)?;
-    let writer = BufWriter::new(output_file);
-
     // Collect all files to compress
     let mut files_to_compress = Vec::new();
     for path in source {
@@ -225,95 +1253,216 @@ async fn create_7z_archive_with_progress<P: AsRef<Path>>(
             for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
                 if entry.file_type().is_file() {
                     let relative_path = entry.path().strip_prefix(path)?;
-                    files_to_compress
-                        .push((entry.path().to_path_buf(), relative_path.to_path_buf()));
+                    files_to_compress.push((entry.path().to_path_buf(), rewrite(relative_path)));
                 }
             }
         } else if path.is_file() {
             // If it's a file, add it directly
-            files_to_compress.push((path.to_path_buf(), path.file_name().unwrap().into()));
+            let name = Path::new(path.file_name().unwrap());
+            files_to_compress.push((path.to_path_buf(), rewrite(name)));
         }
     }
 
     // Create archive with collected files
-    tokio::task::spawn_blocking(move || {
-        let mut archive = sevenz_rust::SevenZWriter::new(writer)?;
-
-        if let Some(pass) = password {
-            archive.set_content_methods(vec![sevenz_rust::AesEncoderOptions::new(
-                sevenz_rust::Password::from(pass.as_str()),
-            )
-            .into()]);
-        }
-
-        for (file_path, name) in files_to_compress {
-            let file = File::open(&file_path)?;
-            let reader = BufReader::new(file);
-            let progress_reader = ProgressReader::new(reader, progress.clone());
+    if compression.method == CompressionMethod::Store && !format.supports_store() {
+        anyhow::bail!("The {} format can't store files", format.extension());
+    }
+    // Renamed once complete, an archive cut by a shutdown or a crash is never mistaken for a good
+    // one
+    let mut partial_name = output_path.file_name().unwrap_or_default().to_os_string();
+    partial_name.push(".partial");
+    let partial_path = output_path.with_file_name(partial_name);
+    let output_file = File::create(&partial_path)?;
+    let writer = progress.writer(BufWriter::new(output_file));
 
-            archive.push_archive_entry(
-                SevenZArchiveEntry::from_path(&file_path, name.to_string_lossy().to_string()),
-                Some(progress_reader),
-            )?;
+    // The blocking pool doesn't inherit the span of the task
+    let span = tracing::Span::current();
+    let written = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        match format {
+            ArchiveFormat::SevenZ => {
+                write_7z(writer, files_to_compress, compression, password, &progress)
+            }
+            ArchiveFormat::Zip => {
+                write_zip(writer, files_to_compress, compression, password, &progress)
+            }
+            ArchiveFormat::TarGz => {
+                let level = match compression.method {
+                    CompressionMethod::Compress => flate2::Compression::new(compression.level),
+                    CompressionMethod::Store => flate2::Compression::none(),
+                };
+                let encoder = flate2::write::GzEncoder::new(writer, level);
+                write_tar(encoder, files_to_compress, &progress)?.finish()?;
+                Ok(())
+            }
+            ArchiveFormat::TarZstd => {
+                // zstd levels go from 1 to 19, 0 being its default of 3
+                let encoder = zstd::Encoder::new(writer, compression.level.max(1) as i32)?;
+                write_tar(encoder, files_to_compress, &progress)?.finish()?;
+                Ok(())
+            }
         }
-
-        archive.finish()?;
-        Ok::<_, anyhow::Error>(())
     })
-    .await??;
+    .await?;
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(e);
+    }
+    std::fs::rename(&partial_path, &output_path)?;
 
     Ok(output_path)
 }
 
-/// Create a 7z archive from a list of files or a directory
-///
-/// # Arguments
-/// * `source` - Either a directory path or a list of file paths to compress
-/// * `output_path` - Path where the 7z file should be created
-/// * `password` - Optional password to encrypt the archive
-pub async fn create_7z_archive<P: AsRef<Path>>(
-    source: Vec<P>,
-    output_path: PathBuf,
+fn write_7z<W: io::Write + io::Seek>(
+    writer: W,
+    files: Vec<(PathBuf, PathBuf)>,
+    compression: Compression,
     password: Option<String>,
-) -> Result<PathBuf> {
-    create_7z_archive_with_progress(source, output_path, password, ArchiveProgress::new(0)).await
+    progress: &ArchiveProgress,
+) -> Result<()> {
+    let mut archive = sevenz_rust::SevenZWriter::new(writer)?;
+
+    let mut methods = vec![];
+    if let Some(pass) = password {
+        methods.push(
+            sevenz_rust::AesEncoderOptions::new(sevenz_rust::Password::from(pass.as_str())).into(),
+        );
+    }
+    methods.push(sevenz_rust::lzma::LZMA2Options::with_preset(compression.level).into());
+    archive.set_content_methods(methods);
+
+    for (file_path, name) in files {
+        let file = File::open(&file_path)?;
+        let reader = BufReader::new(file);
+        let progress_reader = progress.reader(reader);
+
+        archive.push_archive_entry(
+            SevenZArchiveEntry::from_path(&file_path, name.to_string_lossy().to_string()),
+            Some(progress_reader),
+        )?;
+    }
+
+    archive.finish()?;
+    Ok(())
 }
 
-/// Create a 7z archive from a directory
-///
-/// # Arguments
-/// * `dir_path` - Path to the directory to compress
-/// * `output_path` - Path where the 7z file should be created
-/// * `password` - Optional password to encrypt the archive
-pub async fn create_7z_from_directory<P: AsRef<Path>>(
-    dir_path: P,
-    output_path: PathBuf,
+fn write_zip<W: io::Write + io::Seek>(
+    writer: W,
+    files: Vec<(PathBuf, PathBuf)>,
+    compression: Compression,
     password: Option<String>,
-) -> Result<PathBuf> {
-    create_7z_archive(vec![dir_path], output_path, password).await
+    progress: &ArchiveProgress,
+) -> Result<()> {
+    let mut archive = zip::ZipWriter::new(writer);
+
+    for (file_path, name) in files {
+        let already_compressed = file_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .is_some_and(|ext| COMPRESSED_EXTENSIONS.contains(&ext.as_str()));
+        let store = already_compressed || compression.method == CompressionMethod::Store;
+        let (method, level) = if store {
+            (zip::CompressionMethod::Stored, None)
+        } else {
+            (
+                zip::CompressionMethod::Deflated,
+                Some(compression.level as i64),
+            )
+        };
+        let file = File::open(&file_path)?;
+        let mut options = zip::write::SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+            .large_file(file.metadata()?.len() >= u32::MAX as u64);
+        if let Some(pass) = &password {
+            options = options.with_aes_encryption(zip::AesMode::Aes256, pass);
+        }
+
+        archive.start_file(name.to_string_lossy(), options)?;
+        io::copy(&mut progress.reader(BufReader::new(file)), &mut archive)?;
+    }
+
+    archive.finish()?;
+    Ok(())
 }
 
-/// Create a 7z archive from multiple files
-///
-/// # Arguments
-/// * `files` - List of file paths to compress
-/// * `output_path` - Path where the 7z file should be created
-/// * `password` - Optional password to encrypt the archive
-pub async fn create_7z_from_files<P: AsRef<Path>>(
-    files: Vec<P>,
-    output_path: PathBuf,
-    password: Option<String>,
-) -> Result<PathBuf> {
-    create_7z_archive(files, output_path, password).await
+/// Write the files to a tar stream, returning the inner writer to finish its compression
+fn write_tar<W: io::Write>(
+    writer: W,
+    files: Vec<(PathBuf, PathBuf)>,
+    progress: &ArchiveProgress,
+) -> Result<W> {
+    let mut archive = tar::Builder::new(writer);
+
+    for (file_path, name) in files {
+        let file = File::open(&file_path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&file.metadata()?);
+        archive.append_data(&mut header, name, progress.reader(BufReader::new(file)))?;
+    }
+
+    Ok(archive.into_inner()?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::worker::{PurgeTasksInput, Task};
     use tempfile::tempdir;
     use tokio::fs::File;
     use tokio::io::AsyncWriteExt;
 
+    /// Create a 7z archive from a list of files or a directory
+    ///
+    /// # Arguments
+    /// * `source` - Either a directory path or a list of file paths to compress
+    /// * `output_path` - Path where the 7z file should be created
+    /// * `password` - Optional password to encrypt the archive
+    async fn create_7z_archive<P: AsRef<Path>>(
+        source: Vec<P>,
+        output_path: PathBuf,
+        password: Option<String>,
+    ) -> Result<PathBuf> {
+        create_archive_with_progress(
+            source,
+            output_path,
+            ArchiveFormat::SevenZ,
+            Compression::default(),
+            password,
+            &[],
+            ArchiveProgress::new(0),
+        )
+        .await
+    }
+
+    /// Create a 7z archive from a directory
+    ///
+    /// # Arguments
+    /// * `dir_path` - Path to the directory to compress
+    /// * `output_path` - Path where the 7z file should be created
+    /// * `password` - Optional password to encrypt the archive
+    async fn create_7z_from_directory<P: AsRef<Path>>(
+        dir_path: P,
+        output_path: PathBuf,
+        password: Option<String>,
+    ) -> Result<PathBuf> {
+        create_7z_archive(vec![dir_path], output_path, password).await
+    }
+
+    /// Create a 7z archive from multiple files
+    ///
+    /// # Arguments
+    /// * `files` - List of file paths to compress
+    /// * `output_path` - Path where the 7z file should be created
+    /// * `password` - Optional password to encrypt the archive
+    async fn create_7z_from_files<P: AsRef<Path>>(
+        files: Vec<P>,
+        output_path: PathBuf,
+        password: Option<String>,
+    ) -> Result<PathBuf> {
+        create_7z_archive(files, output_path, password).await
+    }
+
     #[tokio::test]
     async fn test_create_7z_from_files() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -371,4 +1520,273 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_create_encrypted_7z() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let file_path = temp_dir.path().join("test1.txt");
+        File::create(&file_path)
+            .await?
+            .write_all(&b"Test content 1".repeat(100))
+            .await?;
+
+        let output_path = create_7z_from_files(
+            vec![file_path],
+            temp_dir.path().join("output.7z"),
+            Some("secret".to_string()),
+        )
+        .await?;
+        // Encrypted entries are still LZMA2 compressed
+        assert!(std::fs::metadata(&output_path)?.len() < 1400);
+
+        let extract_dir = temp_dir.path().join("extract");
+        let extract_dir_clone = extract_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            sevenz_rust::decompress_file_with_password(
+                output_path.as_path(),
+                extract_dir_clone.as_path(),
+                "secret".into(),
+            )
+        })
+        .await??;
+        assert_eq!(
+            std::fs::read(extract_dir.join("test1.txt"))?,
+            b"Test content 1".repeat(100)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_zip_and_tar_archives() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let file1_path = temp_dir.path().join("test1.txt");
+        let file2_path = temp_dir.path().join("test2.mp4");
+        File::create(&file1_path)
+            .await?
+            .write_all(b"Test content 1")
+            .await?;
+        File::create(&file2_path)
+            .await?
+            .write_all(b"Test content 2")
+            .await?;
+        let files = vec![file1_path, file2_path];
+
+        let zip_path = create_archive_with_progress(
+            files.clone(),
+            temp_dir.path().join("output"),
+            ArchiveFormat::Zip,
+            Compression::default(),
+            None,
+            &[],
+            ArchiveProgress::new(28),
+        )
+        .await?;
+        assert!(zip_path.ends_with("output.zip"));
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&zip_path)?)?;
+        let mut content = String::new();
+        zip.by_name("test1.txt")?.read_to_string(&mut content)?;
+        assert_eq!(content, "Test content 1");
+        assert_eq!(
+            zip.by_name("test2.mp4")?.compression(),
+            zip::CompressionMethod::Stored
+        );
+
+        let progress = ArchiveProgress::new(28);
+        let tar_path = create_archive_with_progress(
+            files,
+            temp_dir.path().join("output.tar.zst"),
+            ArchiveFormat::TarZstd,
+            Compression::default(),
+            None,
+            &[FilenameRule::AddSuffix {
+                suffix: "_v2".to_string(),
+            }],
+            progress.clone(),
+        )
+        .await?;
+        assert!(tar_path.ends_with("output.tar.zst"));
+        assert_eq!(progress.get_progress_percentage(), 100);
+        assert!(progress.compression_ratio().is_some());
+        let mut tar = tar::Archive::new(zstd::Decoder::new(std::fs::File::open(&tar_path)?)?);
+        let names: Vec<PathBuf> = tar
+            .entries()?
+            .map(|e| Ok(e?.path()?.into_owned()))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            names,
+            vec![PathBuf::from("test1_v2.txt"), PathBuf::from("test2_v2.mp4")]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hash_pool_progress() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let file_path = temp_dir.path().join("test1.txt");
+        let mut file = File::create(&file_path).await?;
+        file.write_all(b"Test content 1").await?;
+
+        let progress = ArchiveProgress::new(14);
+        let hashes = HashPool::new(2, 1)
+            .hash_files(
+                vec![file_path],
+                HashAlgorithm::Sha256,
+                progress.processed_bytes.clone(),
+            )
+            .await?;
+        assert_eq!(hashes, vec![hex_sha256(b"Test content 1")]);
+        assert_eq!(progress.get_progress_percentage(), 100);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_shares() -> Result<()> {
+        let (task_manager, receiver) = crate::worker::tests::test_task_manager().await?;
+        let db = task_manager.db.clone();
+        let now = chrono::offset::Utc::now().timestamp();
+        for (share_id, expiration) in [("expired", now - 10), ("live", -1)] {
+            sqlx::query("INSERT INTO share_links (id, expiration, created_at) VALUES (?, ?, ?)")
+                .bind(share_id)
+                .bind(expiration)
+                .bind(now)
+                .execute(&db)
+                .await?;
+        }
+        sqlx::query(
+            "INSERT INTO files (id, sha256, path, file_size) VALUES (1, '', '/data/only-expired', 1), (2, '', '/data/both', 1)",
+        )
+        .execute(&db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO share_link_files (share_link_id, file_id) VALUES ('expired', 1), ('expired', 2), ('live', 2)",
+        )
+        .execute(&db)
+        .await?;
+
+        let worker = TaskWorker::new(task_manager, receiver);
+        assert_eq!(
+            worker.purge_expired_shares().await?,
+            serde_json::json!({ "purged": 1 })
+        );
+        let shares: Vec<String> = sqlx::query_scalar("SELECT id FROM share_links")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(shares, vec!["live"]);
+        let files: Vec<i64> = sqlx::query_scalar("SELECT id FROM files")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(files, vec![2]);
+        let links: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM share_link_files")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(links, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_graft_point() {
+        assert_eq!(
+            graft_point(Path::new("/data/a=b.txt")),
+            "a\\=b.txt=/data/a\\=b.txt"
+        );
+    }
+
+    /// Run the worker with `fault` always injected and wait for two tasks to reach a final status
+    async fn tasks_under_fault(fault: Fault) -> Result<Vec<Task>> {
+        let (task_manager, receiver) = crate::worker::tests::test_task_manager().await?;
+        let task_manager = task_manager.with_chaos(crate::chaos::Chaos::new(&[(fault, 1.0)]));
+        let mut worker = TaskWorker::new(task_manager.clone(), receiver);
+        tokio::spawn(async move { worker.run().await });
+
+        let mut task_ids = vec![];
+        for _ in 0..2 {
+            task_ids.push(
+                task_manager
+                    .create_task(TaskInput::PurgeTasks(PurgeTasksInput {
+                        older_than_days: 30,
+                    }))
+                    .await?,
+            );
+        }
+        let mut tasks = vec![];
+        for task_id in task_ids {
+            for _ in 0..100 {
+                let task = task_manager.get_task_status(&task_id).await?;
+                if matches!(task.status, TaskStatus::Failed | TaskStatus::Completed) {
+                    tasks.push(task);
+                    break;
+                }
+                time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+        Ok(tasks)
+    }
+
+    #[tokio::test]
+    async fn test_archive_progress_events() -> Result<()> {
+        let temp_dir = tempdir()?;
+        std::fs::write(temp_dir.path().join("test.txt"), "Test content")?;
+        let (sender, mut events) = tokio::sync::broadcast::channel(16);
+        let (task_manager, receiver) = crate::worker::tests::test_task_manager().await?;
+        let task_manager = task_manager.with_progress(sender);
+        let mut worker = TaskWorker::new(task_manager.clone(), receiver);
+        tokio::spawn(async move { worker.run().await });
+
+        let task_id = task_manager
+            .create_task(TaskInput::CreateArchive(ArchiveInput {
+                files: Some(vec![temp_dir.path().join("test.txt")]),
+                directory: None,
+                password: None,
+                output_path: temp_dir.path().join("output.zip"),
+                format: ArchiveFormat::Zip,
+                compression: Compression::default(),
+                create_share: false,
+                share_expires_in: None,
+                filename_rules: vec![],
+            }))
+            .await?;
+        let last = time::timeout(std::time::Duration::from_secs(10), async {
+            loop {
+                if let Ok(Event::TaskProgress(progress)) = events.recv().await {
+                    if progress.finished {
+                        return progress;
+                    }
+                }
+            }
+        })
+        .await?;
+        assert_eq!(last.task_id, task_id);
+        assert_eq!(last.task_type, "CreateArchive");
+        assert_eq!((last.percent, last.bytes, last.total_bytes), (100, 12, 12));
+        assert!(last.compression_ratio.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_worker_survives_task_panics() -> Result<()> {
+        let tasks = tasks_under_fault(Fault::WorkerPanic).await?;
+        assert_eq!(tasks.len(), 2);
+        for task in tasks {
+            assert!(matches!(task.status, TaskStatus::Failed));
+            assert_eq!(
+                task.error.as_deref(),
+                Some("Task panicked: Injected worker panic")
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_database_errors_fail_tasks() -> Result<()> {
+        let tasks = tasks_under_fault(Fault::DbError).await?;
+        assert_eq!(tasks.len(), 2);
+        for task in tasks {
+            assert!(matches!(task.status, TaskStatus::Failed));
+            assert!(task.error.unwrap().contains("timed out"));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,283 @@
+//! .torrent files of shares.
+//!
+//! The `CreateTorrent` task hashes the files of a share into BitTorrent v1 pieces and writes a
+//! metainfo file served at `/s/{share_id}/share.torrent`. Besides the configured trackers, the
+//! server itself is listed as a web seed (BEP 19): clients fetch the pieces with ranged requests
+//! from `/s/{share_id}/seed/`, followed by the torrent name and file name, so a torrent works
+//! without any peer and recipients get a client which verifies and resumes every piece.
+
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Pieces of 256 KiB up to 16 MiB, about 1500 of them
+const MIN_AUTO_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_AUTO_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+const TARGET_PIECES: u64 = 1500;
+pub const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+pub const MAX_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// Where the torrent of a share is written
+pub fn torrent_path(data_dir: &Path, share_id: &str) -> PathBuf {
+    data_dir
+        .join("torrents")
+        .join(format!("{}.torrent", share_id))
+}
+
+/// Piece length for `total_size` bytes of files
+pub fn piece_length(total_size: u64) -> u64 {
+    (total_size / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_AUTO_PIECE_LENGTH, MAX_AUTO_PIECE_LENGTH)
+}
+
+/// A bencoded value
+#[derive(Debug, Clone)]
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Keys are sorted as bencoding requires
+    Dict(BTreeMap<&'static str, Value>),
+}
+
+impl Value {
+    fn str(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => out.extend_from_slice(format!("i{}e", i).as_bytes()),
+            Value::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Value::str(key).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    /// Name of the file in the torrent
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentSpec {
+    /// Name of the single file, or of the directory of several files
+    pub name: String,
+    pub files: Vec<TorrentFile>,
+    pub piece_length: u64,
+    pub trackers: Vec<String>,
+    /// URL the torrent name is appended to, ending with `/`
+    pub web_seed: String,
+    pub comment: String,
+}
+
+#[derive(Debug)]
+pub struct Torrent {
+    pub metainfo: Vec<u8>,
+    /// Hex SHA-1 of the info dictionary, identifying the torrent
+    pub info_hash: String,
+    pub pieces: usize,
+}
+
+/// SHA-1 of each piece of the concatenated `files`, adding the bytes read to `processed`
+fn hash_pieces(
+    files: &[TorrentFile],
+    piece_length: u64,
+    processed: &AtomicU64,
+) -> io::Result<Vec<u8>> {
+    let mut pieces = vec![];
+    let mut piece = Sha1::new();
+    let mut piece_filled = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    for file in files {
+        let mut reader = BufReader::new(File::open(&file.path)?).take(file.size);
+        let mut read_total = 0;
+        loop {
+            let wanted = buffer.len().min((piece_length - piece_filled) as usize);
+            let read = reader.read(&mut buffer[..wanted])?;
+            if read == 0 {
+                break;
+            }
+            piece.update(&buffer[..read]);
+            piece_filled += read as u64;
+            read_total += read as u64;
+            processed.fetch_add(read as u64, Ordering::Relaxed);
+            if piece_filled == piece_length {
+                pieces.extend_from_slice(&piece.finalize_reset());
+                piece_filled = 0;
+            }
+        }
+        // The pieces would be shifted for every following file
+        if read_total != file.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is shorter than recorded", file.path.display()),
+            ));
+        }
+    }
+    if piece_filled > 0 {
+        pieces.extend_from_slice(&piece.finalize());
+    }
+    Ok(pieces)
+}
+
+/// Hash the files and build the metainfo, reading the files on the current thread
+pub fn build(spec: &TorrentSpec, processed: &AtomicU64) -> io::Result<Torrent> {
+    let pieces = hash_pieces(&spec.files, spec.piece_length, processed)?;
+    let piece_count = pieces.len() / 20;
+
+    let mut info = BTreeMap::new();
+    info.insert("name", Value::str(&spec.name));
+    info.insert("piece length", Value::Int(spec.piece_length as i64));
+    info.insert("pieces", Value::Bytes(pieces));
+    match spec.files.as_slice() {
+        [file] if file.name == spec.name => {
+            info.insert("length", Value::Int(file.size as i64));
+        }
+        files => {
+            let files = files
+                .iter()
+                .map(|file| {
+                    Value::Dict(BTreeMap::from([
+                        ("length", Value::Int(file.size as i64)),
+                        ("path", Value::List(vec![Value::str(&file.name)])),
+                    ]))
+                })
+                .collect();
+            info.insert("files", Value::List(files));
+        }
+    }
+    let info = Value::Dict(info);
+    let info_hash = Sha1::digest(info.to_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let mut metainfo = BTreeMap::new();
+    if let Some(tracker) = spec.trackers.first() {
+        metainfo.insert("announce", Value::str(tracker));
+        // One tracker per tier, tried in order
+        let tiers = spec
+            .trackers
+            .iter()
+            .map(|tracker| Value::List(vec![Value::str(tracker)]))
+            .collect();
+        metainfo.insert("announce-list", Value::List(tiers));
+    }
+    metainfo.insert("comment", Value::str(&spec.comment));
+    metainfo.insert(
+        "created by",
+        Value::str(concat!("hardwire/", env!("CARGO_PKG_VERSION"))),
+    );
+    metainfo.insert(
+        "creation date",
+        Value::Int(chrono::offset::Utc::now().timestamp()),
+    );
+    metainfo.insert("info", info);
+    metainfo.insert("url-list", Value::List(vec![Value::str(&spec.web_seed)]));
+
+    Ok(Torrent {
+        metainfo: Value::Dict(metainfo).to_bytes(),
+        info_hash,
+        pieces: piece_count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bencode() {
+        let value = Value::Dict(BTreeMap::from([
+            ("spam", Value::List(vec![Value::str("a"), Value::Int(-3)])),
+            ("cow", Value::str("moo")),
+        ]));
+        assert_eq!(value.to_bytes(), b"d3:cow3:moo4:spaml1:ai-3eee");
+    }
+
+    #[test]
+    fn test_piece_length() {
+        assert_eq!(piece_length(0), MIN_AUTO_PIECE_LENGTH);
+        assert_eq!(piece_length(4 << 30), 4 << 20);
+        assert_eq!(piece_length(1 << 40), MAX_AUTO_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_pieces_span_files() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (a, b) = (dir.path().join("a.bin"), dir.path().join("b.bin"));
+        std::fs::write(&a, [1u8; 40_000])?;
+        std::fs::write(&b, [2u8; 30_000])?;
+        let files = vec![
+            TorrentFile {
+                path: a,
+                name: "a.bin".to_string(),
+                size: 40_000,
+            },
+            TorrentFile {
+                path: b,
+                name: "b.bin".to_string(),
+                size: 30_000,
+            },
+        ];
+        let spec = TorrentSpec {
+            name: "holidays".to_string(),
+            files,
+            piece_length: 32 * 1024,
+            trackers: vec!["udp://tracker.example.com:6969/announce".to_string()],
+            web_seed: "https://files.example.com/s/V1StGXR8_Z/seed/".to_string(),
+            comment: "https://files.example.com/s/V1StGXR8_Z".to_string(),
+        };
+        let processed = AtomicU64::new(0);
+        let torrent = build(&spec, &processed)?;
+        assert_eq!(processed.load(Ordering::Relaxed), 70_000);
+        assert_eq!(torrent.pieces, 3);
+
+        // The second piece is the end of a.bin followed by the start of b.bin
+        let mut second = vec![1u8; 40_000 - 32 * 1024];
+        second.resize(32 * 1024, 2);
+        let pieces = hash_pieces(&spec.files, spec.piece_length, &AtomicU64::new(0))?;
+        assert_eq!(&pieces[20..40], Sha1::digest(&second).as_slice());
+
+        let metainfo = String::from_utf8_lossy(&torrent.metainfo);
+        assert!(metainfo.contains("8:url-listl44:https://files.example.com/s/V1StGXR8_Z/seed/e"));
+        assert!(metainfo.contains("5:filesld6:lengthi40000e4:pathl5:a.binee"));
+        assert_eq!(torrent.info_hash.len(), 40);
+
+        let mut truncated = spec.clone();
+        truncated.files[1].size = 40_000;
+        assert!(build(&truncated, &AtomicU64::new(0)).is_err());
+        Ok(())
+    }
+}
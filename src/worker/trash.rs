@@ -0,0 +1,154 @@
+//! Trash of the deleted shares.
+//!
+//! Deleting a share, through the admin API or by removing it from `shares.toml`, only sets its
+//! `deleted_at`: the share is no longer served but can be restored as it was, its alias included.
+//! Deleted shares are purged with their files rows after the retention period, or on demand by
+//! the `PurgeDeletedShares` task. The files on disk are always kept.
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+use super::TaskManager;
+use crate::file_dedup;
+
+/// Move the share `share_id`, or the share with this alias, to the trash. Its id, `None` when
+/// there is no such share or it already is in the trash.
+pub async fn delete_share(db: &SqlitePool, share_id: &str) -> Result<Option<String>> {
+    let now = chrono::offset::Utc::now().timestamp();
+    let id = sqlx::query_scalar!(
+        r#"UPDATE share_links SET deleted_at = ?1
+        WHERE (id = ?2 OR alias = ?2) AND deleted_at IS NULL
+        RETURNING id AS "id!""#,
+        now,
+        share_id
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(id)
+}
+
+/// Take the share `share_id`, or the share with this alias, out of the trash. Its id, `None` when
+/// it isn't in the trash.
+pub async fn restore_share(db: &SqlitePool, share_id: &str) -> Result<Option<String>> {
+    let id = sqlx::query_scalar!(
+        r#"UPDATE share_links SET deleted_at = NULL
+        WHERE (id = ?1 OR alias = ?1) AND deleted_at IS NOT NULL
+        RETURNING id AS "id!""#,
+        share_id
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(id)
+}
+
+impl TaskManager {
+    /// Delete `share_ids` with their files rows which no other share links to
+    pub(crate) async fn purge_shares(&self, share_ids: &[String]) -> Result<()> {
+        let share_ids = serde_json::to_string(share_ids)?;
+        let mut tx = self.db.begin().await?;
+        let file_ids = sqlx::query_scalar!(
+            r#"SELECT file_id AS "file_id!" FROM share_link_files
+            WHERE share_link_id IN (SELECT value FROM json_each(?)) AND file_id IS NOT NULL"#,
+            share_ids
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM share_link_files WHERE share_link_id IN (SELECT value FROM json_each(?))",
+            share_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        file_dedup::delete_unreferenced(&mut tx, &file_ids).await?;
+        sqlx::query!(
+            "DELETE FROM share_views WHERE share_id IN (SELECT value FROM json_each(?))",
+            share_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM share_links WHERE id IN (SELECT value FROM json_each(?))",
+            share_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Purge the shares deleted more than `retention` ago
+    pub async fn purge_deleted_shares(&self, retention: Duration) -> Result<u64> {
+        let before = chrono::offset::Utc::now().timestamp() - retention.as_secs() as i64;
+        let share_ids = sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM share_links WHERE deleted_at <= ?"#,
+            before
+        )
+        .fetch_all(&self.db)
+        .await?;
+        self.purge_shares(&share_ids).await?;
+        for share_id in &share_ids {
+            self.share_cache.invalidate(share_id);
+        }
+        Ok(share_ids.len() as u64)
+    }
+
+    /// Purge the shares deleted more than `retention` ago every hour
+    pub fn spawn_trash_purge(&self, retention: Duration) {
+        let task_manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3600));
+            loop {
+                interval.tick().await;
+                match task_manager.purge_deleted_shares(retention).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("Purged {} deleted shares", count),
+                    Err(e) => tracing::error!("Failed to purge the deleted shares: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trash() -> Result<()> {
+        let (task_manager, _receiver) = crate::worker::tests::test_task_manager().await?;
+        let db = task_manager.db.clone();
+        sqlx::query(
+            "INSERT INTO share_links (id, expiration, created_at, alias) VALUES ('old', -1, 0, 'holidays'), ('recent', -1, 0, NULL), ('live', -1, 0, NULL);
+            INSERT INTO files (id, sha256, path, file_size) VALUES (1, '', '/data/old-only', 1), (2, '', '/data/old-and-recent', 1), (3, '', '/data/old-and-live', 1);
+            INSERT INTO share_link_files (share_link_id, file_id) VALUES ('old', 1), ('old', 2), ('old', 3), ('recent', 2), ('live', 3);",
+        )
+        .execute(&db)
+        .await?;
+
+        assert_eq!(delete_share(&db, "holidays").await?.as_deref(), Some("old"));
+        assert_eq!(delete_share(&db, "old").await?, None);
+        assert_eq!(restore_share(&db, "old").await?.as_deref(), Some("old"));
+        assert_eq!(restore_share(&db, "old").await?, None);
+        assert_eq!(restore_share(&db, "missing").await?, None);
+
+        delete_share(&db, "old").await?;
+        delete_share(&db, "recent").await?;
+        sqlx::query("UPDATE share_links SET deleted_at = deleted_at - 86400 WHERE id = 'old'")
+            .execute(&db)
+            .await?;
+        let retention = Duration::from_secs(3600);
+        assert_eq!(task_manager.purge_deleted_shares(retention).await?, 1);
+        let shares: Vec<String> = sqlx::query_scalar("SELECT id FROM share_links ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(shares, vec!["live", "recent"]);
+        // The files of the shares still in the trash are kept for their restoration
+        let files: Vec<i64> = sqlx::query_scalar("SELECT id FROM files ORDER BY id")
+            .fetch_all(&db)
+            .await?;
+        assert_eq!(files, vec![2, 3]);
+        assert_eq!(task_manager.purge_deleted_shares(retention).await?, 0);
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
 module.exports = {
-  content: ["./templates/**/*.{html,js}"],
+  content: ["./templates/**/*.{html,js}", "./src/text_viewer.rs"],
   theme: {
     extend: {},
   },
@@ -0,0 +1,48 @@
+#!/usr/bin/env bash
+# Download the files of {{ share_url }} into the current directory:
+#
+#     curl -fsSL {{ share_url }}/download.sh | bash
+#
+# Run it again after an interruption: complete files are skipped and partial ones resumed. The
+# files hashed on the server are checked against their SHA-256. For a share with a password:
+#
+#     curl -fsSL -u :password {{ share_url }}/download.sh | SHARE_PASSWORD=password bash
+set -euo pipefail
+
+auth=()
+if [ -n "${SHARE_PASSWORD:-}" ]; then
+    auth=(-u ":$SHARE_PASSWORD")
+fi
+
+sha256() {
+    if command -v sha256sum >/dev/null; then
+        sha256sum "$1" | cut -d ' ' -f 1
+    else
+        shasum -a 256 "$1" | cut -d ' ' -f 1
+    fi
+}
+
+# fetch NAME URL SIZE SHA256, the size and the digest are empty when unknown
+fetch() {
+    local name="$1" url="$2" size="$3" digest="$4" have=""
+    if [ -f "$name" ]; then
+        have="$(wc -c <"$name" | tr -d ' ')"
+    fi
+    if [ -n "$size" ] && [ "$have" = "$size" ]; then
+        echo "Already downloaded: $name"
+    elif [ -n "$size" ] && [ -n "$have" ] && [ "$have" -gt "$size" ]; then
+        echo "Larger than on the server: $name, delete it and run the script again" >&2
+        return 1
+    else
+        curl -fL --retry 5 --retry-delay 2 -C - ${auth[@]+"${auth[@]}"} -o "$name" "$url"
+    fi
+    if [ -n "$digest" ] && [ "$(sha256 "$name")" != "$digest" ]; then
+        echo "Checksum mismatch: $name, delete it and run the script again" >&2
+        return 1
+    fi
+}
+
+{% for file in files -%}
+fetch {{ file.name }} {{ file.url }} '{{ file.size }}' '{{ file.sha256 }}'
+{% endfor -%}
+echo "Downloaded {{ files.len() }} files"